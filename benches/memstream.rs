@@ -0,0 +1,36 @@
+use std::fs::File;
+use std::io::Write;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use octobuild::io::memstream::MemStream;
+
+// Size of typical preprocessed Unreal Engine translation unit.
+const STREAM_SIZE: usize = 64 * 1024 * 1024;
+
+fn write_to_file_benchmark(c: &mut Criterion) {
+    let mut stream = MemStream::new();
+    let line =
+        b"#line 1 \"c:\\\\work\\\\engine\\\\source\\\\runtime\\\\core\\\\public\\\\core.h\"\n";
+    while stream.len() < STREAM_SIZE {
+        stream.write_all(line).unwrap();
+    }
+    let temp = tempfile::tempdir().unwrap();
+    let path = temp.path().join("stream.i");
+
+    let mut group = c.benchmark_group("write_to_file");
+    group.sample_size(10);
+    group.bench_function("copy", |b| {
+        b.iter(|| {
+            let mut file = File::create(&path).unwrap();
+            stream.copy(&mut file).unwrap()
+        })
+    });
+    group.bench_function("write_to_file", |b| {
+        b.iter(|| stream.write_to_file(&path).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, write_to_file_benchmark);
+criterion_main!(benches);
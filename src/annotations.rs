@@ -0,0 +1,350 @@
+use std::collections::HashSet;
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use path_absolutize::Absolutize;
+
+use crate::compiler::OutputInfo;
+use crate::config::{AnnotationFormat, Config};
+use crate::diagnostics::{parse_output, Diagnostic, Severity};
+use crate::worker::{BuildAction, BuildResult};
+
+// Diagnostic with file path relative to repository root, as shown on pull request.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+struct Annotation {
+    severity: Severity,
+    file: Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+    code: Option<String>,
+    message: String,
+}
+
+#[derive(Default)]
+struct AnnotationState {
+    // Annotations already printed: headers are compiled by many tasks.
+    seen: HashSet<Annotation>,
+    warnings: usize,
+    errors: usize,
+    // Annotations not printed because of limit.
+    dropped_warnings: usize,
+    dropped_errors: usize,
+}
+
+// Prints compiler warnings and errors as CI annotations, so they surface on pull request
+// without log parsing. Cache hits replay stored compiler output and are annotated the same way.
+pub struct Annotator {
+    format: AnnotationFormat,
+    root: Option<PathBuf>,
+    // CI shows only a few annotations of every severity per step.
+    limit: usize,
+    state: Mutex<AnnotationState>,
+}
+
+impl Annotator {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        // Paths are relative to checkout directory of CI job unless root is specified.
+        let workspace = match config.annotations {
+            AnnotationFormat::Off => None,
+            AnnotationFormat::Azure => env::var_os("BUILD_SOURCESDIRECTORY"),
+            AnnotationFormat::Github => env::var_os("GITHUB_WORKSPACE"),
+        };
+        Annotator {
+            format: config.annotations,
+            root: config
+                .annotation_root
+                .clone()
+                .or_else(|| workspace.map(PathBuf::from)),
+            limit: config.annotation_limit,
+            state: Mutex::default(),
+        }
+    }
+
+    // Print annotations for diagnostics in output of completed task.
+    pub fn annotate(&self, out: &mut impl Write, result: &BuildResult) -> crate::Result<()> {
+        if self.format == AnnotationFormat::Off {
+            return Ok(());
+        }
+        let current_dir = match &result.task.action {
+            BuildAction::Empty => None,
+            BuildAction::Exec(command, _) => command.current_dir.as_deref(),
+            BuildAction::Compilation(_, task) => task.shared.command.current_dir.as_deref(),
+            BuildAction::Shadow(shadow) => shadow.command.current_dir.as_deref(),
+        };
+        match &result.result.output {
+            Ok(output) => self.annotate_output(out, output, current_dir),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn annotate_output(
+        &self,
+        out: &mut impl Write,
+        output: &OutputInfo,
+        current_dir: Option<&Path>,
+    ) -> crate::Result<()> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        for diagnostic in parse_output(output) {
+            let annotation = self.annotation(diagnostic, current_dir);
+            if state.seen.contains(&annotation) {
+                continue;
+            }
+            let (count, dropped) = match annotation.severity {
+                Severity::Warning => (&mut state.warnings, &mut state.dropped_warnings),
+                Severity::Error => (&mut state.errors, &mut state.dropped_errors),
+            };
+            if *count >= self.limit {
+                *dropped += 1;
+            } else {
+                *count += 1;
+                writeln!(out, "{}", self.format_annotation(&annotation))?;
+            }
+            state.seen.insert(annotation);
+        }
+        Ok(())
+    }
+
+    // Print note about annotations dropped because of limit.
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        let state = self.state.lock().unwrap();
+        if state.dropped_warnings == 0 && state.dropped_errors == 0 {
+            return Ok(());
+        }
+        let message = format!(
+            "{} more warning(s) and {} more error(s) are not annotated, limit is {} per severity",
+            state.dropped_warnings, state.dropped_errors, self.limit
+        );
+        match self.format {
+            AnnotationFormat::Off => {}
+            AnnotationFormat::Azure => writeln!(
+                out,
+                "##vso[task.logissue type=warning]{}",
+                azure_escape(&message)
+            )?,
+            AnnotationFormat::Github => writeln!(out, "::notice::{}", github_escape(&message))?,
+        }
+        Ok(())
+    }
+
+    fn annotation(&self, diagnostic: Diagnostic, current_dir: Option<&Path>) -> Annotation {
+        let path = match current_dir {
+            Some(dir) => Path::new(&diagnostic.file).absolutize_from(dir),
+            None => Path::new(&diagnostic.file).absolutize(),
+        }
+        .ok()
+        .map(|path| path.into_owned());
+        // Location of command line diagnostics like `cl : Command line warning` is not a file.
+        let path = path.filter(|path| diagnostic.line.is_some() || path.is_file());
+        let file = path.map(|path| {
+            let path = match &self.root {
+                Some(root) => path.strip_prefix(root).unwrap_or(&path),
+                None => &path,
+            };
+            path.to_string_lossy().replace('\\', "/")
+        });
+        // clang diagnostic without flag is identified by its message.
+        let code = (diagnostic.key != diagnostic.message).then_some(diagnostic.key);
+        Annotation {
+            severity: diagnostic.severity,
+            line: file.as_ref().and(diagnostic.line),
+            column: file.as_ref().and(diagnostic.column),
+            file,
+            code,
+            message: diagnostic.message,
+        }
+    }
+
+    fn format_annotation(&self, annotation: &Annotation) -> String {
+        let severity = match annotation.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.format {
+            AnnotationFormat::Off => String::new(),
+            AnnotationFormat::Azure => {
+                let mut properties = vec![format!("type={severity}")];
+                let mut add = |name: &str, value: Option<String>| {
+                    if let Some(value) = value {
+                        properties.push(format!("{name}={}", azure_escape_property(&value)));
+                    }
+                };
+                add("sourcepath", annotation.file.clone());
+                add("linenumber", annotation.line.map(|v| v.to_string()));
+                add("columnnumber", annotation.column.map(|v| v.to_string()));
+                add("code", annotation.code.clone());
+                format!(
+                    "##vso[task.logissue {};]{}",
+                    properties.join(";"),
+                    azure_escape(&annotation.message)
+                )
+            }
+            AnnotationFormat::Github => {
+                let mut properties = Vec::new();
+                let mut add = |name: &str, value: Option<String>| {
+                    if let Some(value) = value {
+                        properties.push(format!("{name}={}", github_escape_property(&value)));
+                    }
+                };
+                add("file", annotation.file.clone());
+                add("line", annotation.line.map(|v| v.to_string()));
+                add("col", annotation.column.map(|v| v.to_string()));
+                add("title", annotation.code.clone());
+                let separator = if properties.is_empty() { "" } else { " " };
+                format!(
+                    "::{severity}{separator}{}::{}",
+                    properties.join(","),
+                    github_escape(&annotation.message)
+                )
+            }
+        }
+    }
+}
+
+fn github_escape(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn github_escape_property(value: &str) -> String {
+    github_escape(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+fn azure_escape(value: &str) -> String {
+    value
+        .replace('%', "%AZP25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn azure_escape_property(value: &str) -> String {
+    azure_escape(value).replace(';', "%3B").replace(']', "%5D")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use crate::annotations::Annotator;
+    use crate::compiler::{OutputInfo, TaskStatus};
+    use crate::config::{AnnotationFormat, Config};
+    use crate::io::taskoutput::TaskOutput;
+
+    fn annotator(format: AnnotationFormat, root: &str, limit: usize) -> Annotator {
+        Annotator::new(&Config {
+            annotations: format,
+            annotation_root: Some(PathBuf::from(root)),
+            annotation_limit: limit,
+            ..Config::default()
+        })
+    }
+
+    fn output(stdout: &str, stderr: &str) -> OutputInfo {
+        OutputInfo {
+            status: TaskStatus::Code(0),
+            stdout: TaskOutput::from(stdout.as_bytes().to_vec()),
+            stderr: TaskOutput::from(stderr.as_bytes().to_vec()),
+        }
+    }
+
+    fn annotate(annotator: &Annotator, output: &OutputInfo, current_dir: &str) -> String {
+        let mut out = Vec::new();
+        annotator
+            .annotate_output(&mut out, output, Some(Path::new(current_dir)))
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_github_cl() {
+        let annotator = annotator(AnnotationFormat::Github, "/work/repo", 10);
+        let output = output(
+            "a.cpp\r
+/work/repo/src/a.cpp(10): warning C4996: 'strcpy': 100% unsafe, use strcpy_s\r
+/work/repo/src/a.h(12,5): error C2065: 'x': undeclared identifier\r
+cl : Command line warning D9025 : overriding '/W3' with '/W4'\r
+",
+            "",
+        );
+        assert_eq!(
+            annotate(&annotator, &output, "/work/repo/src"),
+            "::warning file=src/a.cpp,line=10,title=C4996::'strcpy': 100%25 unsafe, use strcpy_s
+::error file=src/a.h,line=12,col=5,title=C2065::'x': undeclared identifier
+::warning title=D9025::overriding '/W3' with '/W4'
+"
+        );
+        // The same diagnostics of another task are not repeated.
+        assert_eq!(annotate(&annotator, &output, "/work/repo/src"), "");
+    }
+
+    #[test]
+    fn test_azure_clang() {
+        let annotator = annotator(AnnotationFormat::Azure, "/work/repo", 10);
+        let output = output(
+            "",
+            "In file included from a.cpp:1:
+./inc/a.h:3:9: warning: unused variable 'a' [-Wunused-variable]
+    int a = 0;
+        ^
+a.cpp:5:1: error: use of undeclared identifier 'x;]'
+/usr/include/b.h:7: warning: 50% of [-Wother]
+2 warnings and 1 error generated.
+",
+        );
+        assert_eq!(
+            annotate(&annotator, &output, "/work/repo/src"),
+            "##vso[task.logissue type=warning;sourcepath=src/inc/a.h;linenumber=3;columnnumber=9;code=-Wunused-variable;]unused variable 'a'
+##vso[task.logissue type=error;sourcepath=src/a.cpp;linenumber=5;columnnumber=1;]use of undeclared identifier 'x;]'
+##vso[task.logissue type=warning;sourcepath=/usr/include/b.h;linenumber=7;code=-Wother;]50%AZP25 of
+"
+        );
+    }
+
+    #[test]
+    fn test_limit() {
+        for (format, summary) in [
+            (
+                AnnotationFormat::Github,
+                "::notice::2 more warning(s) and 0 more error(s) are not annotated, limit is 2 per severity\n",
+            ),
+            (
+                AnnotationFormat::Azure,
+                "##vso[task.logissue type=warning]2 more warning(s) and 0 more error(s) are not annotated, limit is 2 per severity\n",
+            ),
+        ] {
+            let annotator = annotator(format, "/work", 2);
+            let output = output(
+                "/work/a.cpp(1): warning C4996: a
+/work/a.cpp(2): warning C4996: b
+/work/a.cpp(3): error C2065: c
+/work/a.cpp(4): warning C4996: d
+/work/a.cpp(5): warning C4996: e
+",
+                "",
+            );
+            let annotations = annotate(&annotator, &output, "/work");
+            assert_eq!(annotations.lines().count(), 3);
+            assert!(!annotations.contains("line=4") && !annotations.contains("linenumber=4"));
+
+            let mut out = Vec::new();
+            annotator.print_summary(&mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), summary);
+        }
+
+        // Nothing is dropped: no summary.
+        let annotator = annotator(AnnotationFormat::Github, "/work", 2);
+        annotate(
+            &annotator,
+            &output("/work/a.cpp(1): warning C4996: a\n", ""),
+            "/work",
+        );
+        let mut out = Vec::new();
+        annotator.print_summary(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::config::DiagnosticStyle;
+use crate::diagnostics::{format_diagnostic, Location, Severity, CODE_BIGOBJ_RETRY};
+
+// Large generated sources fail with MSVC C1128 (number of sections exceeded object file format
+// limit) unless /bigobj is set. Such compilation is retried once with the flag, and retried
+// sources are reported, so the project can set the flag permanently.
+pub struct BigobjRetry {
+    enabled: bool,
+    retried: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl BigobjRetry {
+    #[must_use]
+    pub fn new(enabled: bool) -> Self {
+        BigobjRetry {
+            enabled,
+            retried: Mutex::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn remember(&self, source: &Path) {
+        if self.retried.lock().unwrap().insert(source.to_path_buf()) {
+            warn!(
+                "Source {} exceeded object file section limit, compiled with /bigobj",
+                source.display()
+            );
+        }
+    }
+
+    // Sources compiled with /bigobj added by retry.
+    #[must_use]
+    pub fn retried(&self) -> Vec<PathBuf> {
+        self.retried.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn print_warnings(
+        &self,
+        out: &mut impl Write,
+        style: DiagnosticStyle,
+    ) -> crate::Result<()> {
+        let retried = self.retried();
+        if retried.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "WARNING: Sources were compiled with /bigobj after C1128 failure, add the flag to the project:"
+        )?;
+        for source in retried {
+            writeln!(
+                out,
+                "{}",
+                format_diagnostic(
+                    style,
+                    Location::file(&source),
+                    Severity::Warning,
+                    CODE_BIGOBJ_RETRY,
+                    "Number of sections exceeded object file format limit, compile with /bigobj"
+                )
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,52 +1,134 @@
 #![allow(non_snake_case)]
 
+use std::collections::HashSet;
 use std::env;
 use std::fs::File;
 use std::io::{stderr, stdout, BufReader, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
 
+use path_absolutize::Absolutize;
 use petgraph::graph::NodeIndex;
 use petgraph::{EdgeDirection, Graph};
 
 use octobuild::cluster::client::RemoteCompiler;
 use octobuild::compiler::{CommandArgs, Compiler, SharedState};
-use octobuild::config::Config;
+use octobuild::config::{Config, DiagnosticStyle};
+use octobuild::console::{read_task_output, LogFollower};
+use octobuild::determinism::parse_percent;
+use octobuild::diagnostics::TaskOutcome;
+use octobuild::exitreport::ExitReport;
+use octobuild::prewarm::PrewarmStatistic;
+use octobuild::provenance::{audit_objects, ProvenanceLocation, PurgeFilter};
 use octobuild::simple::supported_compilers;
+use octobuild::slots::lower_own_priority;
+use octobuild::utils::escape_control;
 use octobuild::version;
-use octobuild::worker::execute_graph;
-use octobuild::worker::validate_graph;
+use octobuild::worker::{execute_graph, failure_exit_code};
+use octobuild::worker::{validate_graph, validate_pch_languages};
 use octobuild::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
 use octobuild::xg;
 use octobuild::xg::parser::{XgGraph, XgNode};
 
 pub fn main() -> octobuild::Result<()> {
+    let report = ExitReport::new();
+    let code = report.run(|| run(&report))?;
+    process::exit(code)
+}
+
+fn run(report: &ExitReport) -> octobuild::Result<i32> {
     writeln!(stdout(), "xgConsole ({}):", version::full())?;
     let args: Vec<String> = env::args().collect();
     for arg in &args {
-        writeln!(stdout(), "  {arg}")?;
+        writeln!(stdout(), "  {}", escape_control(arg))?;
     }
 
-    let config = Config::load()?;
+    let mut config = Config::load()?;
 
     if args.len() == 1 {
         config.print_help(&args[0], &mut stdout())?;
-        return Ok(());
+        return Ok(0);
     }
 
-    process::exit(match execute(&config, &args[1..]) {
+    let options = parse_options(&mut config, &args[1..])?;
+    report.set_path(config.diagnostics_file.as_deref());
+    Ok(match execute(&config, &options, report) {
         Ok(_) => 0,
+        Err(octobuild::Error::BuildFailed(failures)) => {
+            writeln!(
+                stderr(),
+                "ERROR: Build failed, {} task(s) failed:",
+                failures.len()
+            )?;
+            for failure in &failures {
+                writeln!(stderr(), "  {failure}")?;
+            }
+            failure_exit_code(&failures, config.exit_code_mode)
+        }
         Err(e) => {
             writeln!(stderr(), "ERROR: {e}")?;
+            report.record_error(&e);
             1
         }
     })
 }
 
-fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
-    let state = SharedState::new(config)?;
-    let compiler = RemoteCompiler::new(&config.coordinator, supported_compilers());
+// Command line options that are not part of configuration.
+struct ConsoleOptions {
+    files: Vec<String>,
+    // Globs of task captions for critical path recomputation as if they were cache hits.
+    what_if_hit: Vec<String>,
+}
+
+// Apply command line options to configuration and return remaining arguments.
+fn parse_options(config: &mut Config, args: &[String]) -> octobuild::Result<ConsoleOptions> {
+    let mut result = ConsoleOptions {
+        files: Vec::new(),
+        what_if_hit: Vec::new(),
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if let Some(value) = arg.strip_prefix("--exit-code-mode=") {
+            config.exit_code_mode = value.parse()?;
+        } else if let Some(value) = arg.strip_prefix("--annotations=") {
+            config.annotations = value.parse()?;
+        } else if let Some(value) = arg.strip_prefix("--diagnostics-file=") {
+            config.diagnostics_file = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--config-label=") {
+            config.config_label = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--verify-preprocess=") {
+            config.verify_preprocess = parse_percent(value)?;
+        } else if arg == "--deterministic-schedule" {
+            config.deterministic_schedule = true;
+        } else if arg == "--raw-output" {
+            config.raw_output = true;
+        } else if arg == "--explain" {
+            config.explain = true;
+        } else if arg == "--shadow" {
+            config.shadow = true;
+        } else if arg == "--what-if" {
+            let value = iter.next().ok_or("Option --what-if requires hit=<glob>")?;
+            let pattern = value.strip_prefix("hit=").ok_or_else(|| {
+                octobuild::Error::Generic(format!("Unknown --what-if scenario: {value}"))
+            })?;
+            result.what_if_hit.push(pattern.to_string());
+        } else {
+            result.files.push(arg.clone());
+        }
+    }
+    Ok(result)
+}
+
+fn execute(
+    config: &Config,
+    options: &ConsoleOptions,
+    report: &ExitReport,
+) -> octobuild::Result<()> {
+    let args = &options.files;
+    let mut state = SharedState::new(config)?;
+    let compiler = RemoteCompiler::new(config, supported_compilers(config));
 
     match args.first() {
         None => Err(octobuild::Error::NoTaskFiles),
@@ -60,22 +142,226 @@ fn execute(config: &Config, args: &[String]) -> octobuild::Result<()> {
                 _ = std::fs::remove_dir_all(&config.cache);
                 writeln!(stdout(), "Done!")?;
                 Ok(())
+            } else if arg.eq_ignore_ascii_case("/whence") {
+                let output = args.get(1).ok_or("Option /whence requires output file")?;
+                whence(&state, Path::new(output))
+            } else if arg.eq_ignore_ascii_case("/purge") {
+                let filter = parse_purge_filter(&args[1..])?;
+                let removed = state.provenance.purge(&state.cache, &filter)?;
+                writeln!(stdout(), "Removed {removed} cache entries")?;
+                Ok(())
+            } else if arg.eq_ignore_ascii_case("/reindex") {
+                let count = state.provenance.reindex(&state.cache)?;
+                writeln!(stdout(), "Indexed {count} cache entries")?;
+                Ok(())
+            } else if arg.eq_ignore_ascii_case("/audit-objects") {
+                let dir = args
+                    .get(1)
+                    .ok_or("Option /audit-objects requires directory")?;
+                audit(&compiler, Path::new(dir))
+            } else if arg.eq_ignore_ascii_case("/show-output") {
+                let task = args.get(1).ok_or("Option /show-output requires task id")?;
+                let task = task
+                    .parse()
+                    .map_err(|_| octobuild::Error::Generic(format!("Invalid task id: {task}")))?;
+                let dir = config
+                    .output_log_dir
+                    .as_ref()
+                    .ok_or("Option /show-output requires output_log_dir in configuration")?;
+                stdout().write_all(&read_task_output(dir, task)?)?;
+                Ok(())
+            } else if arg.eq_ignore_ascii_case("/attach") {
+                let from_start = match args.get(1).map(String::as_str) {
+                    None => false,
+                    Some("--from-start") => true,
+                    Some(arg) => {
+                        return Err(octobuild::Error::Generic(format!("Unknown option: {arg}")))
+                    }
+                };
+                let dir = config
+                    .output_log_dir
+                    .as_ref()
+                    .ok_or("Option /attach requires output_log_dir in configuration")?;
+                LogFollower::open(dir, from_start)?.follow(&mut stdout())
+            } else if arg.eq_ignore_ascii_case("/prewarm") {
+                let file = args.get(1).ok_or("Option /prewarm requires task file")?;
+                match args.get(2).map(String::as_str) {
+                    None => lower_own_priority(),
+                    Some("--normal-priority") => {}
+                    Some(arg) => {
+                        return Err(octobuild::Error::Generic(format!("Unknown option: {arg}")))
+                    }
+                }
+                state.prewarm = Some(PrewarmStatistic::new());
+                let result = build(&state, &compiler, config, report, Path::new(file));
+                if let Some(prewarm) = &state.prewarm {
+                    writeln!(stdout(), "{prewarm}")?;
+                }
+                result
             } else {
-                let mut graph = Graph::new();
-                let file = File::open(Path::new(&args[0]))?;
-                xg::parser::parse(&mut graph, BufReader::new(file))?;
-                let build_graph = prepare_graph(&compiler, validate_graph(graph)?, config)?;
-
-                let result =
-                    execute_graph(&state, build_graph, config.process_limit, print_task_result);
-                drop(state.cache.cleanup());
+                let result = build(&state, &compiler, config, report, Path::new(&args[0]));
                 writeln!(stdout(), "{}", state.statistic)?;
+                if state.schedule.is_deterministic() {
+                    writeln!(stdout(), "{}", state.schedule)?;
+                }
+                state.transforms.print_hits(&mut stdout())?;
+                state.temp.print_usage(&mut stdout())?;
+                if let Some(shadow) = &state.shadow {
+                    writeln!(stdout(), "{shadow}")?;
+                }
+                state
+                    .timeline
+                    .print_critical_path(&mut stdout(), &options.what_if_hit)?;
                 result
             }
         }
     }
 }
 
+fn build<C: Compiler>(
+    state: &SharedState,
+    compiler: &C,
+    config: &Config,
+    report: &ExitReport,
+    path: &Path,
+) -> octobuild::Result<()> {
+    let mut graph = Graph::new();
+    let file = File::open(path)?;
+    xg::parser::parse(&mut graph, BufReader::new(file))?;
+    let build_graph = prepare_graph(compiler, validate_graph(graph)?, config, state)?;
+
+    let result = execute_graph(state, build_graph, config.process_limit, |result| {
+        report.record_task(result);
+        print_task_result(state, result, config.diagnostic_style)?;
+        state.annotations.annotate(&mut stdout(), result)
+    });
+    drop(state.cache.cleanup());
+    state
+        .includes
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .outputs
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .bigobj
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .preprocess_check
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state.annotations.print_summary(&mut stdout())?;
+    state.console.print_summary(&mut stdout())?;
+    state.uncached.print_summary(&mut stdout())?;
+    state.preprocess_check.print_summary(&mut stdout())?;
+    state.warmup.print_summary(&mut stdout())?;
+    state.monitor.reaper.print_summary(&mut stdout())?;
+    result
+}
+
+fn parse_purge_filter(args: &[String]) -> octobuild::Result<PurgeFilter> {
+    let mut filter = PurgeFilter::default();
+    for arg in args {
+        if let Some(value) = arg.strip_prefix("--source-glob=") {
+            filter.source_glob = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--produced-after=") {
+            let seconds: u64 = value.parse().map_err(|_| {
+                octobuild::Error::Generic(format!("Invalid unix timestamp: {value}"))
+            })?;
+            filter.produced_after = Some(UNIX_EPOCH + Duration::from_secs(seconds));
+        } else {
+            return Err(octobuild::Error::Generic(format!("Unknown option: {arg}")));
+        }
+    }
+    if filter.source_glob.is_none() && filter.produced_after.is_none() {
+        return Err(octobuild::Error::from(
+            "Option /purge requires --source-glob or --produced-after",
+        ));
+    }
+    Ok(filter)
+}
+
+// Print where output file came from.
+fn whence(state: &SharedState, output: &Path) -> octobuild::Result<()> {
+    let output = output.absolutize()?;
+    let Some((location, provenance)) = state.provenance.find(&output)? else {
+        return Err(octobuild::Error::Generic(format!(
+            "No octobuild metadata found for {}",
+            output.display()
+        )));
+    };
+    match location {
+        ProvenanceLocation::Sidecar(path) => {
+            writeln!(stdout(), "Metadata file: {}", path.display())?;
+        }
+        ProvenanceLocation::Index(path) => {
+            writeln!(stdout(), "Cache index: {}", path.display())?;
+        }
+    }
+    writeln!(stdout(), "  key:       {}", provenance.key)?;
+    writeln!(
+        stdout(),
+        "  toolchain: {}",
+        provenance.toolchain.as_deref().unwrap_or("unknown")
+    )?;
+    writeln!(stdout(), "  source:    {}", provenance.source.display())?;
+    writeln!(stdout(), "  version:   {}", provenance.version)?;
+    writeln!(stdout(), "  outcome:   {:?}", provenance.outcome)?;
+    Ok(())
+}
+
+// Print origin of object files in directory and check them against available toolchains.
+fn audit<C: Compiler>(compiler: &C, dir: &Path) -> octobuild::Result<()> {
+    let toolchains: HashSet<String> = compiler
+        .discover_toolchains()
+        .iter()
+        .filter_map(|toolchain| toolchain.identifier())
+        .collect();
+    let (mut cached, mut compiled, mut other, mut mismatched) = (0, 0, 0, 0);
+    for audit in audit_objects(dir, &toolchains)? {
+        let origin = match &audit.stamp {
+            Ok(Some(stamp)) if stamp.provenance.outcome == TaskOutcome::Hit => {
+                cached += 1;
+                "cache"
+            }
+            Ok(Some(_)) => {
+                compiled += 1;
+                "compiled"
+            }
+            Ok(None) => {
+                other += 1;
+                "other"
+            }
+            Err(e) => {
+                other += 1;
+                writeln!(stdout(), "{:<9} {}: {e}", "invalid", audit.path.display())?;
+                continue;
+            }
+        };
+        write!(stdout(), "{origin:<9} {}", audit.path.display())?;
+        if let Ok(Some(stamp)) = &audit.stamp {
+            write!(stdout(), " (build {})", stamp.build)?;
+            if audit.toolchain_mismatch {
+                mismatched += 1;
+                write!(
+                    stdout(),
+                    " WARNING: toolchain not found: {}",
+                    stamp.provenance.toolchain.as_deref().unwrap_or_default()
+                )?;
+            }
+        }
+        writeln!(stdout())?;
+    }
+    writeln!(
+        stdout(),
+        "Objects: {cached} from cache, {compiled} compiled, {other} from other tools"
+    )?;
+    if mismatched > 0 {
+        return Err(octobuild::Error::Generic(format!(
+            "{mismatched} object(s) were produced by toolchain which is not available"
+        )));
+    }
+    Ok(())
+}
+
 fn env_resolver(name: &str) -> Option<String> {
     env::var(name).ok()
 }
@@ -84,6 +370,7 @@ fn prepare_graph<C: Compiler>(
     compiler: &C,
     graph: XgGraph,
     config: &Config,
+    state: &SharedState,
 ) -> octobuild::Result<BuildGraph> {
     let mut remap: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
     let mut depends: Vec<NodeIndex> = Vec::with_capacity(graph.node_count());
@@ -92,27 +379,43 @@ fn prepare_graph<C: Compiler>(
     for raw_node in graph.raw_nodes() {
         let node: &XgNode = &raw_node.weight;
         let raw_args: String = expand_arg(&node.raw_args, &env_resolver);
-        let command = node.command.clone();
+        let command = node
+            .command
+            .clone()
+            .remap(&config.tool_remap)
+            .with_config(config.config_label.as_deref());
 
-        let actions = BuildAction::create_tasks(
+        let create_tasks = if config.shadow {
+            BuildAction::create_shadow_tasks
+        } else {
+            BuildAction::create_tasks
+        };
+        let mut actions = create_tasks(
             compiler,
             command.clone(),
             CommandArgs::Raw(raw_args),
             &node.title,
             config.run_second_cpp,
+            config.split_link,
+            &state.uncached,
         );
+        state.warmup.start_actions(&actions);
+        if config.explain {
+            state.overrides.explain(&mut stdout(), &actions)?;
+        }
+        let link = BuildAction::take_link(&mut actions);
         let node_index = NodeIndex::new(remap.len());
-        if actions.len() == 1 {
+        if actions.len() == 1 && link.is_none() {
             depends.push(node_index);
             remap.push(result.add_node(Arc::new(BuildTask {
                 title: node.title.clone(),
                 action: actions.into_iter().next().unwrap(),
             })));
         } else {
-            // Add group node for tracking end of all task actions
+            // Add group node for tracking end of all task actions, link step runs there
             let group_node = result.add_node(Arc::new(BuildTask {
                 title: node.title.clone(),
-                action: BuildAction::Empty,
+                action: link.unwrap_or(BuildAction::Empty),
             }));
             depends.push(NodeIndex::end());
             // Add task actions
@@ -141,20 +444,25 @@ fn prepare_graph<C: Compiler>(
             result.add_edge(node_a, *node_b, ());
         }
     }
+    validate_pch_languages(&result)?;
     validate_graph(result)
 }
 
-fn print_task_result(result: &BuildResult) -> octobuild::Result<()> {
+fn print_task_result(
+    state: &SharedState,
+    result: &BuildResult,
+    style: DiagnosticStyle,
+) -> octobuild::Result<()> {
     writeln!(
         stdout(),
         "#{} {}/{}: {} @ {}s",
         result.worker,
         result.completed,
         result.total,
-        result.task.title,
+        escape_control(&result.task.title),
         result.result.duration.as_secs(),
     )?;
-    result.result.print_output()?;
+    state.console.print(result, style)?;
     Ok(())
 }
 
@@ -210,3 +518,14 @@ fn test_parse_vars() {
         "Afoo$(bar)$(none)B"
     );
 }
+
+#[test]
+fn test_parse_vars_percent() {
+    // Only $(name) is a variable reference, percent signs in paths are kept as is.
+    assert_eq!(
+        expand_arg("%PATH%/$(dir)/100%%/a^&!.cpp", &|name: &str| {
+            (name == "dir").then(|| "out%1%".to_string())
+        }),
+        "%PATH%/out%1%/100%%/a^&!.cpp"
+    );
+}
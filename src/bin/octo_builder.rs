@@ -1,15 +1,16 @@
+use std::cmp::max;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs;
 use std::fs::File;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
 
 use daemon::Daemon;
 use daemon::DaemonRunner;
@@ -20,31 +21,57 @@ use rouille::{router, try_or_400, Request, Response, Server};
 use sha2::digest::DynDigest;
 use sha2::{Digest, Sha256};
 
-use octobuild::cluster::builder::{CompileRequest, CompileResponse};
+use octobuild::cluster::builder::{ChunkQuery, CompileRequest, CompileResponse};
 use octobuild::cluster::common::{
-    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_TASK, RPC_BUILDER_UPDATE, RPC_BUILDER_UPLOAD,
+    BuilderInfo, BuilderInfoUpdate, RPC_BUILDER_CHUNKS, RPC_BUILDER_TASK, RPC_BUILDER_UPDATE,
+    RPC_BUILDER_UPLOAD,
 };
 use octobuild::compiler::CompileInput::Preprocessed;
 use octobuild::compiler::{
-    CompileStep, Compiler, CompilerOutput, PCHArgs, PCHUsage, SharedState, Toolchain,
+    CommandInfo, CompileStep, Compiler, CompilerOutput, PCHArgs, PCHUsage, SharedState, Toolchain,
 };
 use octobuild::config::Config;
+use octobuild::io::chunks::{ChunkParams, ChunkStore, CHUNK_STORE_LIMIT};
+use octobuild::io::prefix::{PrefixStore, STORE_LIMIT};
 use octobuild::io::tempfile::TempFile;
+use octobuild::service::{ServiceCommand, ServiceSpec};
 use octobuild::simple::supported_compilers;
 use octobuild::version;
 
+const SERVICE_NAME: &str = "octobuild_Builder";
+// How often stopping builder checks for running tasks.
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
 struct BuilderService {
     done: Arc<AtomicBool>,
     server: Option<(JoinHandle<()>, mpsc::Sender<()>)>,
     announcer: Option<JoinHandle<()>>,
+    state: Arc<BuilderState>,
+    drain: Duration,
 }
 
 struct BuilderState {
     name: String,
+    slots: usize,
+    max_payload: u64,
     shared: SharedState,
     precompiled_dir: PathBuf,
     toolchains: HashMap<String, Arc<dyn Toolchain>>,
     precompiled: Mutex<HashMap<String, Arc<PrecompiledFile>>>,
+    prefixes: PrefixStore,
+    chunks: ChunkStore,
+    // Tasks being compiled, stopping builder waits for them.
+    active: AtomicUsize,
+    draining: AtomicBool,
+}
+
+// Counts task as running while it is alive.
+struct ActiveTask<'a>(&'a AtomicUsize);
+
+impl Drop for ActiveTask<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 struct PrecompiledFile {
@@ -60,10 +87,16 @@ impl BuilderService {
 
         let state = Arc::new(BuilderState {
             name: hostname::get()?.into_string().unwrap(),
+            slots: max(config.process_limit, 1),
+            max_payload: config.builder_max_payload_kb * 1024,
             shared: SharedState::new(&config)?,
-            toolchains: BuilderService::discover_toolchains(),
+            toolchains: BuilderService::discover_toolchains(&config),
             precompiled_dir: config.cache,
             precompiled: Mutex::new(HashMap::new()),
+            prefixes: PrefixStore::new(STORE_LIMIT),
+            chunks: ChunkStore::new(CHUNK_STORE_LIMIT),
+            active: AtomicUsize::new(0),
+            draining: AtomicBool::new(false),
         });
         let worker_state = state.clone();
 
@@ -75,6 +108,9 @@ impl BuilderService {
                 (POST) [RPC_BUILDER_UPLOAD.to_string() + "/:hash"] => {
                     try_or_400!(handle_upload(worker_state.clone(), request))
                 },
+                (POST) [RPC_BUILDER_CHUNKS] => {
+                    try_or_400!(handle_chunks(&worker_state, request))
+                },
                 (POST) [RPC_BUILDER_TASK] => {
                     try_or_400!(handle_task(worker_state.clone(), request))
                 },
@@ -89,17 +125,31 @@ impl BuilderService {
         for toolchain in &state.toolchain_names() {
             info!("- {}", toolchain);
         }
+        // Advertised toolchains are probed before the first task arrives.
+        for toolchain in state.toolchains.values() {
+            if let Some(executable) = toolchain.executable() {
+                let command = CommandInfo::simple(executable.to_path_buf());
+                state.shared.warmup.start(toolchain, &command);
+            }
+        }
 
         let done = Arc::new(AtomicBool::new(false));
+        if config.coordinator.is_none() {
+            info!("Coordinator is not configured, builder is not announced");
+        }
         Ok(BuilderService {
-            announcer: Some(BuilderService::thread_announcer(
-                state,
-                config.coordinator.unwrap(),
-                done.clone(),
-                server.server_addr(),
-            )),
+            announcer: config.coordinator.map(|coordinator| {
+                BuilderService::thread_announcer(
+                    state.clone(),
+                    coordinator,
+                    done.clone(),
+                    server.server_addr(),
+                )
+            }),
             done,
             server: Some(server.stoppable()),
+            state,
+            drain: Duration::from_secs(config.builder_drain_sec),
         })
     }
 
@@ -115,6 +165,9 @@ impl BuilderService {
                 version: version::VERSION.to_owned(),
                 endpoint: endpoint.to_string(),
                 toolchains: state.toolchain_names(),
+                slots: state.slots,
+                max_payload: state.max_payload,
+                chunking: Some(ChunkParams::default()),
             });
 
             let client = reqwest::blocking::Client::new();
@@ -135,8 +188,8 @@ impl BuilderService {
     }
 
     #[must_use]
-    fn discover_toolchains() -> HashMap<String, Arc<dyn Toolchain>> {
-        let compiler = supported_compilers();
+    fn discover_toolchains(config: &Config) -> HashMap<String, Arc<dyn Toolchain>> {
+        let compiler = supported_compilers(config);
         compiler
             .discover_toolchains()
             .into_iter()
@@ -146,9 +199,18 @@ impl BuilderService {
 }
 
 fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result<Response> {
+    state.active.fetch_add(1, Ordering::SeqCst);
+    let _active = ActiveTask(&state.active);
+    // Stopping builder is no longer announced, clients compile rejected task elsewhere.
+    if state.draining.load(Ordering::SeqCst) {
+        return Ok(Response::text("Builder is stopping").with_status_code(503));
+    }
     // Receive compilation request.
     info!("Received task from: {}", &request.remote_addr());
     let request: CompileRequest = bincode::deserialize_from(request.data().unwrap())?;
+    if !request.stripped_args.is_empty() {
+        info!("Arguments stripped by client: {:?}", request.stripped_args);
+    }
     let pch_usage: PCHUsage = match request.precompiled_hash {
         Some(ref hash) => {
             if !is_valid_sha256(hash) {
@@ -170,16 +232,40 @@ fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result
                 path,
                 path_abs,
                 marker: None,
+                forced_marker: None,
             })
         }
         None => PCHUsage::None,
     };
+    let preprocessed = if let Some(chunks) = request.preprocessed_chunks {
+        let data = match state.chunks.assemble(chunks) {
+            Ok(data) => data,
+            Err(e) => return Ok(Response::text(e.to_string()).with_status_code(424)),
+        };
+        let usage = state.chunks.usage();
+        info!(
+            "Chunks: {} stored ({} bytes), reused {} bytes, received {} bytes",
+            usage.chunks, usage.size, usage.reused_bytes, usage.received_bytes
+        );
+        data
+    } else if request.preprocessed_prefix.is_empty() {
+        request.preprocessed_data
+    } else {
+        match state
+            .prefixes
+            .decode(request.preprocessed_prefix, request.preprocessed_data)
+        {
+            Ok(data) => data,
+            Err(e) => return Ok(Response::text(e.to_string()).with_status_code(424)),
+        }
+    };
     let compile_step = CompileStep {
         output_object: None,
         pch_usage,
         args: request.args.iter().map(OsString::from).collect(),
-        input: Preprocessed(CompilerOutput::Vec(request.preprocessed_data)),
+        input: Preprocessed(CompilerOutput::Vec(preprocessed)),
         run_second_cpp: false,
+        source: None,
     };
 
     let toolchain: Arc<dyn Toolchain> = state.toolchains.get(&request.toolchain).unwrap().clone();
@@ -188,6 +274,12 @@ fn handle_task(state: Arc<BuilderState>, request: &Request) -> octobuild::Result
     Ok(Response::from_data("application/octet-stream", payload))
 }
 
+fn handle_chunks(state: &BuilderState, request: &Request) -> octobuild::Result<Response> {
+    let query: ChunkQuery = bincode::deserialize_from(request.data().unwrap())?;
+    let payload = bincode::serialize(&state.chunks.missing(&query.hashes))?;
+    Ok(Response::from_data("application/octet-stream", payload))
+}
+
 fn handle_upload(state: Arc<BuilderState>, request: &Request) -> octobuild::Result<Response> {
     // Receive compilation request.
     let hash = match request.get_param("hash") {
@@ -285,6 +377,23 @@ impl BuilderState {
             })
             .clone()
     }
+
+    // Reject new tasks and wait for running ones.
+    fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        while self.active.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                info!(
+                    "Builder: {} task(s) still running after {}s drain",
+                    self.active.load(Ordering::SeqCst),
+                    timeout.as_secs()
+                );
+                return;
+            }
+            thread::sleep(DRAIN_POLL);
+        }
+    }
 }
 
 impl Drop for BuilderService {
@@ -293,6 +402,7 @@ impl Drop for BuilderService {
         if let Some(t) = self.announcer.take() {
             t.join().unwrap();
         }
+        self.state.drain(self.drain);
         if let Some((handle, sender)) = self.server.take() {
             sender.send(()).unwrap();
             handle.join().unwrap();
@@ -300,14 +410,47 @@ impl Drop for BuilderService {
     }
 }
 
+fn service_spec() -> octobuild::Result<ServiceSpec> {
+    Ok(ServiceSpec {
+        name: SERVICE_NAME.to_string(),
+        display_name: "Octobuild Builder".to_string(),
+        description: "Compiles tasks of remote octobuild clients".to_string(),
+        executable: env::current_exe()?,
+        drain: Duration::from_secs(Config::load()?.builder_drain_sec),
+    })
+}
+
+// Manage builder service with `install`, `uninstall`, `start` or `stop` command.
+fn run_service_command(command: ServiceCommand, spec: &ServiceSpec) -> ! {
+    match octobuild::service::run_command(command, spec) {
+        Ok(()) => {
+            println!("Service {}: {command:?} completed", spec.name);
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    }
+}
+
 fn main() {
+    match service_spec().and_then(|spec| spec.parse_args(env::args_os().skip(1))) {
+        Ok(Some((command, spec))) => run_service_command(command, &spec),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    }
+
     let daemon = Daemon {
-        name: "octobuild_Builder".to_string(),
+        name: SERVICE_NAME.to_string(),
     };
 
     daemon
         .run(move |rx: Receiver<State>| {
-            octobuild::utils::init_logger();
+            octobuild::service::init_logger(SERVICE_NAME);
 
             info!("Builder started.");
             let mut builder = None;
@@ -3,5 +3,7 @@ use octobuild::vs::compiler::VsCompiler;
 use std::process;
 
 fn main() -> std::io::Result<()> {
-    process::exit(simple_compile("cl.exe", |_| Ok(VsCompiler::default())))
+    process::exit(simple_compile("cl.exe", |config| {
+        Ok(VsCompiler::new(config))
+    }))
 }
@@ -0,0 +1,302 @@
+use std::env;
+use std::fs;
+use std::io::{stderr, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use petgraph::Graph;
+
+use octobuild::clang::compiler::ClangCompiler;
+use octobuild::cluster::client::RemoteCompiler;
+use octobuild::compiler::{CommandArgs, CommandInfo, Compiler, SharedState};
+use octobuild::config::{Config, DiagnosticStyle};
+use octobuild::determinism::parse_percent;
+use octobuild::exitreport::ExitReport;
+use octobuild::manifest::BuildManifest;
+use octobuild::run::{glob, output_path, RunCompiler};
+use octobuild::vs::compiler::VsCompiler;
+use octobuild::worker::{execute_graph, failure_exit_code};
+use octobuild::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
+
+struct RunOptions {
+    compiler: RunCompiler,
+    out_dir: Option<PathBuf>,
+    patterns: Vec<String>,
+    flags: Vec<String>,
+    // Build manifest written after the run.
+    manifest: Option<PathBuf>,
+    // Skip tasks recorded in manifest of the previous run as up-to-date.
+    incremental: bool,
+}
+
+// Manifest file name in output directory, used by incremental run without explicit manifest.
+const MANIFEST_FILE: &str = "octobuild-manifest.json";
+
+pub fn main() -> octobuild::Result<()> {
+    let report = ExitReport::new();
+    let code = report.run(|| run(&report))?;
+    process::exit(code)
+}
+
+fn run(report: &ExitReport) -> octobuild::Result<i32> {
+    let args: Vec<String> = env::args().collect();
+    let mut config = Config::load()?;
+
+    if args.len() == 1 {
+        print_help(&args[0])?;
+        return Ok(0);
+    }
+
+    let options = parse_options(&mut config, &args[1..])?;
+    report.set_path(config.diagnostics_file.as_deref());
+    Ok(match execute(&config, &options, report) {
+        Ok(_) => 0,
+        Err(octobuild::Error::BuildFailed(failures)) => {
+            writeln!(
+                stderr(),
+                "ERROR: Build failed, {} task(s) failed:",
+                failures.len()
+            )?;
+            for failure in &failures {
+                writeln!(stderr(), "  {failure}")?;
+            }
+            failure_exit_code(&failures, config.exit_code_mode)
+        }
+        Err(e) => {
+            writeln!(stderr(), "ERROR: {e}")?;
+            report.record_error(&e);
+            1
+        }
+    })
+}
+
+fn print_help(executable: &str) -> octobuild::Result<()> {
+    writeln!(stdout())?;
+    writeln!(stdout(), "Usage:")?;
+    writeln!(
+        stdout(),
+        "  {executable} [--compiler=cl|clang] [--out-dir=<dir>] [--exit-code-mode=<mode>] [--annotations=azure|github] [--config-label=<label>] [--deterministic-schedule] [--raw-output] [--explain] [--manifest=<file>] [--incremental] [--diagnostics-file=<file>] [--verify-preprocess=<N>%] --compile <pattern>... -- <flags>"
+    )?;
+    writeln!(stdout())?;
+    writeln!(stdout(), "Example:")?;
+    writeln!(
+        stdout(),
+        "  {executable} --compiler=cl --out-dir=out --compile \"src/**/*.cpp\" -- /c /O2 /Iinclude"
+    )?;
+    Ok(())
+}
+
+fn parse_options(config: &mut Config, args: &[String]) -> octobuild::Result<RunOptions> {
+    let mut options = RunOptions {
+        compiler: RunCompiler::default(),
+        out_dir: None,
+        patterns: Vec::new(),
+        flags: Vec::new(),
+        manifest: None,
+        incremental: false,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            options.flags = iter.cloned().collect();
+            break;
+        } else if arg == "--compile" {
+            let pattern = iter.next().ok_or("Option --compile requires pattern")?;
+            options.patterns.push(pattern.clone());
+        } else if let Some(value) = arg.strip_prefix("--compile=") {
+            options.patterns.push(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--compiler=") {
+            options.compiler = value.parse()?;
+        } else if let Some(value) = arg.strip_prefix("--out-dir=") {
+            options.out_dir = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--exit-code-mode=") {
+            config.exit_code_mode = value.parse()?;
+        } else if let Some(value) = arg.strip_prefix("--annotations=") {
+            config.annotations = value.parse()?;
+        } else if let Some(value) = arg.strip_prefix("--diagnostics-file=") {
+            config.diagnostics_file = Some(PathBuf::from(value));
+        } else if let Some(value) = arg.strip_prefix("--config-label=") {
+            config.config_label = Some(value.to_string());
+        } else if let Some(value) = arg.strip_prefix("--verify-preprocess=") {
+            config.verify_preprocess = parse_percent(value)?;
+        } else if arg == "--deterministic-schedule" {
+            config.deterministic_schedule = true;
+        } else if arg == "--raw-output" {
+            config.raw_output = true;
+        } else if arg == "--explain" {
+            config.explain = true;
+        } else if let Some(value) = arg.strip_prefix("--manifest=") {
+            options.manifest = Some(PathBuf::from(value));
+        } else if arg == "--incremental" {
+            options.incremental = true;
+        } else {
+            return Err(octobuild::Error::Generic(format!("Unknown option: {arg}")));
+        }
+    }
+    if options.patterns.is_empty() {
+        return Err(octobuild::Error::from("No source file patterns specified"));
+    }
+    Ok(options)
+}
+
+fn execute(config: &Config, options: &RunOptions, report: &ExitReport) -> octobuild::Result<()> {
+    let root = env::current_dir()?;
+    let mut sources = Vec::new();
+    for pattern in &options.patterns {
+        sources.extend(glob(&root, pattern)?);
+    }
+    sources.sort();
+    sources.dedup();
+    if sources.is_empty() {
+        return Err(octobuild::Error::Generic(format!(
+            "No files match patterns: {:?}",
+            options.patterns
+        )));
+    }
+
+    let mut state = SharedState::new(config)?;
+    let manifest = match (&options.manifest, options.incremental) {
+        (Some(path), _) => Some(root.join(path)),
+        (None, true) => Some(out_dir(options, &root).join(MANIFEST_FILE)),
+        (None, false) => None,
+    };
+    if let Some(path) = manifest {
+        state.manifest = Some(BuildManifest::new(path, options.incremental)?);
+    }
+    let build_graph = match options.compiler {
+        RunCompiler::Cl => prepare_graph(
+            &RemoteCompiler::new(config, VsCompiler::new(config)),
+            config,
+            options,
+            &root,
+            &sources,
+            &state,
+        )?,
+        RunCompiler::Clang => prepare_graph(
+            &RemoteCompiler::new(config, ClangCompiler::default()),
+            config,
+            options,
+            &root,
+            &sources,
+            &state,
+        )?,
+    };
+
+    let result = execute_graph(&state, build_graph, config.process_limit, |result| {
+        report.record_task(result);
+        print_task_result(&state, result, config.diagnostic_style)?;
+        state.annotations.annotate(&mut stdout(), result)
+    });
+    drop(state.cache.cleanup());
+    if let Some(manifest) = &state.manifest {
+        manifest.save()?;
+        if options.incremental {
+            writeln!(stdout(), "Up-to-date tasks: {}", manifest.up_to_date())?;
+        }
+    }
+    state
+        .includes
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .outputs
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .bigobj
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .preprocess_check
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state.annotations.print_summary(&mut stdout())?;
+    state.console.print_summary(&mut stdout())?;
+    state.uncached.print_summary(&mut stdout())?;
+    state.preprocess_check.print_summary(&mut stdout())?;
+    state.warmup.print_summary(&mut stdout())?;
+    state.monitor.reaper.print_summary(&mut stdout())?;
+    writeln!(stdout(), "{}", state.statistic)?;
+    if state.schedule.is_deterministic() {
+        writeln!(stdout(), "{}", state.schedule)?;
+    }
+    state.transforms.print_hits(&mut stdout())?;
+    state.temp.print_usage(&mut stdout())?;
+    result
+}
+
+fn prepare_graph<C: Compiler>(
+    compiler: &C,
+    config: &Config,
+    options: &RunOptions,
+    root: &Path,
+    sources: &[PathBuf],
+    state: &SharedState,
+) -> octobuild::Result<BuildGraph> {
+    let out_dir = out_dir(options, root);
+    let exec = options.compiler.executable();
+    let mut graph: BuildGraph = Graph::new();
+    for source in sources {
+        let output = output_path(root, &out_dir, source, options.compiler.object_extension());
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let args = options.compiler.task_args(&options.flags, source, &output);
+        let title = source.display().to_string();
+        let mut actions = BuildAction::create_tasks(
+            compiler,
+            CommandInfo::simple(PathBuf::from(exec))
+                .remap(&config.tool_remap)
+                .with_config(config.config_label.as_deref()),
+            CommandArgs::Regular(args),
+            &title,
+            config.run_second_cpp,
+            config.split_link,
+            &state.uncached,
+        );
+        state.warmup.start_actions(&actions);
+        if config.explain {
+            state.overrides.explain(&mut stdout(), &actions)?;
+        }
+        let link = BuildAction::take_link(&mut actions);
+        let mut compiled = Vec::new();
+        for action in actions {
+            compiled.push(graph.add_node(Arc::new(BuildTask {
+                title: title.clone(),
+                action,
+            })));
+        }
+        if let Some(action) = link {
+            let node = graph.add_node(Arc::new(BuildTask {
+                title: title.clone(),
+                action,
+            }));
+            for index in compiled {
+                graph.add_edge(node, index, ());
+            }
+        }
+    }
+    Ok(graph)
+}
+
+fn out_dir(options: &RunOptions, root: &Path) -> PathBuf {
+    options
+        .out_dir
+        .as_ref()
+        .map_or_else(|| root.to_path_buf(), |dir| root.join(dir))
+}
+
+fn print_task_result(
+    state: &SharedState,
+    result: &BuildResult,
+    style: DiagnosticStyle,
+) -> octobuild::Result<()> {
+    writeln!(
+        stdout(),
+        "#{} {}/{}: {} @ {}s",
+        result.worker,
+        result.completed,
+        result.total,
+        result.task.title,
+        result.result.duration.as_secs(),
+    )?;
+    state.console.print(result, style)?;
+    Ok(())
+}
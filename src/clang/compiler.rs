@@ -1,7 +1,6 @@
 use std::ffi::OsString;
-use std::fs::File;
 use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::process::{Command, Output, Stdio};
 use std::sync::{Arc, OnceLock};
 use std::{env, fs};
@@ -11,9 +10,13 @@ use regex::Regex;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::compiler::{
     Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OsCommandArgs,
-    OutputInfo, ParamForm, PreprocessResult, Scope, SharedState, Toolchain, ToolchainHolder,
+    OutputInfo, ParamForm, PreprocessResult, Scope, SharedState, TaskStatus, Toolchain,
+    ToolchainHolder,
 };
+use crate::io::taskoutput::TaskOutput;
+use crate::io::tempfile::write_atomic;
 use crate::lazy::Lazy;
+use crate::shim::ToolchainPath;
 use os_str_bytes::OsStrBytes;
 
 fn re_clang() -> &'static regex::bytes::Regex {
@@ -30,12 +33,12 @@ pub struct ClangCompiler {
 }
 
 struct ClangToolchain {
-    path: PathBuf,
+    path: ToolchainPath,
     identifier: Lazy<Option<String>>,
 }
 
 impl ClangToolchain {
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(path: ToolchainPath) -> Self {
         ClangToolchain {
             path,
             identifier: Lazy::default(),
@@ -65,7 +68,9 @@ impl Compiler for ClangCompiler {
             .flatten()
             .filter_map(|entry| entry.ok())
             .filter(|entry| re_clang().is_match(entry.file_name().to_string_lossy().as_bytes()))
-            .map(|entry| -> Arc<dyn Toolchain> { Arc::new(ClangToolchain::new(entry.path())) })
+            .map(|entry| -> Arc<dyn Toolchain> {
+                Arc::new(ClangToolchain::new(ToolchainPath::resolve(entry.path())))
+            })
             .collect()
     }
 }
@@ -155,19 +160,24 @@ impl Toolchain for ClangToolchain {
 
         let output = state.wrap_slow(|| -> crate::Result<Output> {
             let mut command = task.shared.command.to_command();
+            let temp = state.temp.place(Some(&task.output_object));
+            let task_temp = state.task_temp_dir(&mut command, &temp)?;
             let response_file =
-                state.do_response_file(OsCommandArgs::Regular(args), &mut command)?;
-            let output = command.output()?;
+                state.do_response_file(OsCommandArgs::Regular(args), &mut command, &temp)?;
+            let output = state.monitor.output(&mut command)?;
             drop(response_file);
+            drop(task_temp);
 
             if output.status.success() {
                 if let Some(ref deps_file) = task.shared.deps_file {
                     assert!(deps_file.is_absolute());
                     let data = fs::read_to_string(deps_file)?;
                     if let Some(end) = data.strip_prefix('-') {
-                        let mut f = File::create(deps_file)?;
-                        f.write_all(&task.output_object.to_raw_bytes())?;
-                        f.write_all(end.as_bytes())?;
+                        write_atomic(deps_file, |f| {
+                            f.write_all(&task.output_object.to_raw_bytes())?;
+                            f.write_all(end.as_bytes())?;
+                            Ok(())
+                        })?;
                     }
                 }
             }
@@ -176,15 +186,15 @@ impl Toolchain for ClangToolchain {
         })?;
 
         if output.status.success() {
-            Ok(PreprocessResult::Success(CompilerOutput::Vec(
-                output.stdout,
-            )))
+            Ok(PreprocessResult::Success(
+                CompilerOutput::Vec(output.stdout),
+                Vec::new(),
+            ))
         } else {
-            Ok(PreprocessResult::Failed(OutputInfo {
-                status: output.status.code(),
-                stdout: output.stdout,
-                stderr: output.stderr,
-            }))
+            Ok(PreprocessResult::Failed(OutputInfo::new(
+                output,
+                &state.output_limits,
+            )))
         }
     }
 
@@ -206,6 +216,15 @@ impl Toolchain for ClangToolchain {
         Ok(CompileStep::new(task, preprocessed, args))
     }
 
+    fn warmup_args(&self, source: &Path, object: &Path) -> Option<Vec<OsString>> {
+        Some(vec![
+            OsString::from("-c"),
+            OsString::from(source),
+            OsString::from("-o"),
+            OsString::from(object),
+        ])
+    }
+
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
         let mut args = task.args.clone();
         args.push(OsString::from("-c"));
@@ -215,7 +234,8 @@ impl Toolchain for ClangToolchain {
         };
 
         args.push(OsString::from("-o"));
-        match task.output_object {
+        let object_on_stdout = task.output_object.is_none();
+        match &task.output_object {
             None => args.push(OsString::from("-")),
             Some(v) => args.push(OsString::from(v)),
         };
@@ -224,12 +244,13 @@ impl Toolchain for ClangToolchain {
         state.wrap_slow(|| {
             // TODO: response file
 
-            let mut command = Command::new(&self.path);
+            let mut command = Command::new(&self.path.spawn);
             match &task.input {
                 Preprocessed(_) => {
                     command.env_clear();
                 }
                 Source(source) => {
+                    command.env_clear().envs(source.env.iter());
                     if let Some(dir) = &source.current_dir {
                         command.current_dir(dir);
                     }
@@ -243,18 +264,34 @@ impl Toolchain for ClangToolchain {
                 })
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped());
+            let temp = state.temp.place(task.output_object.as_deref());
+            let task_temp = state.task_temp_dir(&mut command, &temp)?;
 
             let response_file =
-                state.do_response_file(OsCommandArgs::Regular(args), &mut command)?;
+                state.do_response_file(OsCommandArgs::Regular(args), &mut command, &temp)?;
+            state.monitor.isolate(&mut command);
             let mut child = command.spawn()?;
+            let child_guard = state.monitor.track_child(&command, &child);
 
             if let Preprocessed(preprocessed) = task.input {
                 preprocessed.copy(child.stdin.as_mut().unwrap())?;
             }
 
-            let output = child.wait_with_output()?;
+            let output = child_guard.finish(child.wait_with_output()?);
             drop(response_file);
-            Ok(OutputInfo::new(output))
+            drop(task_temp);
+            if object_on_stdout {
+                // Object file for remote client must not be truncated.
+                let status = TaskStatus::from(output.status);
+                let mut stderr = output.stderr;
+                status.report_crash(&mut stderr);
+                return Ok(OutputInfo {
+                    status,
+                    stdout: TaskOutput::from(output.stdout),
+                    stderr: TaskOutput::new(stderr, &state.output_limits),
+                });
+            }
+            Ok(OutputInfo::new(output, &state.output_limits))
         })
     }
 }
@@ -272,20 +309,13 @@ fn clang_parse_version(base_name: &str, stdout: &str) -> Option<String> {
     Some(format!("{base_name} {version} {target}"))
 }
 
-fn clang_identifier(clang: &Path) -> Option<String> {
-    let filename = clang.file_name()?.to_string_lossy();
+fn clang_identifier(clang: &ToolchainPath) -> Option<String> {
+    let filename = clang.spawn.file_name()?.to_string_lossy();
     let cap: regex::bytes::Captures = re_clang().captures_iter(filename.as_bytes()).next()?;
     let base_name = String::from_utf8_lossy(cap.get(1)?.as_bytes()).into_owned();
-    let output = Command::new(clang.as_os_str())
-        .arg("--version")
-        .output()
-        .ok()?;
-
-    if !output.status.success() {
-        return None;
-    }
-
-    clang_parse_version(&base_name, &String::from_utf8_lossy(&output.stdout))
+    clang.probe(&["--version"], |output| {
+        clang_parse_version(&base_name, output)
+    })
 }
 
 #[cfg(test)]
@@ -374,4 +404,79 @@ InstalledDir: /usr/bin
             Some("prefix tags/RELEASE_380/final x86_64-pc-linux-gnu".to_string())
         )
     }
+
+    #[test]
+    fn test_remap_program() {
+        use crate::compiler::remap_program;
+        use crate::config::ToolRemap;
+        use std::path::{Path, PathBuf};
+
+        let rules = vec![
+            ToolRemap {
+                from: "/opt/llvm-13/bin/".to_string(),
+                to: "/opt/llvm-15/bin".to_string(),
+            },
+            ToolRemap {
+                from: "/usr/bin/clang".to_string(),
+                to: "/usr/bin/clang-15".to_string(),
+            },
+        ];
+        assert_eq!(
+            remap_program(&rules, Path::new("/opt/llvm-13/bin/clang++")),
+            Some(Path::new("/opt/llvm-15/bin").join("clang++"))
+        );
+        assert_eq!(
+            remap_program(&rules, Path::new("/usr/bin/clang")),
+            Some(PathBuf::from("/usr/bin/clang-15"))
+        );
+        // Exact rule doesn't match as prefix.
+        assert_eq!(remap_program(&rules, Path::new("/usr/bin/clang++")), None);
+        assert_eq!(remap_program(&rules, Path::new("/opt/llvm-13")), None);
+
+        // Remainder is cut from original path, even if separators or letter case differ.
+        let rules = vec![ToolRemap {
+            from: "C:/VS/\u{130}x/".to_string(),
+            to: "D:/VS".to_string(),
+        }];
+        let program = "C:\\VS\\\u{130}x\\Bin\\cl.exe";
+        assert_eq!(
+            remap_program(&rules, Path::new(program)),
+            Some(Path::new("D:/VS").join("Bin\\cl.exe"))
+        );
+        #[cfg(windows)]
+        assert_eq!(
+            remap_program(&rules, Path::new("c:\\vs\\i\u{307}x\\Bin\\cl.exe")),
+            Some(Path::new("D:/VS").join("Bin\\cl.exe"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remap_resolve_toolchain() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::compiler::{CommandInfo, Compiler};
+        use crate::config::ToolRemap;
+
+        let dir = tempfile::tempdir().unwrap();
+        let clang = dir.path().join("clang-15.0");
+        std::fs::write(&clang, b"").unwrap();
+        std::fs::set_permissions(&clang, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let compiler = super::ClangCompiler::default();
+        let command = CommandInfo::simple("/nonexistent/llvm/clang-15.0".into());
+        assert!(compiler.resolve_toolchain(&command).is_none());
+
+        let rules = vec![ToolRemap {
+            from: "/nonexistent/llvm/".to_string(),
+            to: dir.path().to_str().unwrap().to_string(),
+        }];
+        let command = command.remap(&rules);
+        assert_eq!(command.program, clang);
+        assert_eq!(
+            command.remapped_from.as_deref(),
+            Some(std::path::Path::new("/nonexistent/llvm/clang-15.0"))
+        );
+        assert!(compiler.resolve_toolchain(&command).is_some());
+    }
 }
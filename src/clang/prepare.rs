@@ -2,9 +2,10 @@ use std::path::{Path, PathBuf};
 use std::slice::Iter;
 use std::sync::Arc;
 
+use crate::cmd;
 use crate::compiler::{
     Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHUsage, ParamForm,
-    Scope,
+    Scope, TaskError,
 };
 use crate::utils::{expand_response_files, find_param, ParamValue};
 
@@ -13,7 +14,7 @@ pub fn create_tasks(
     args: &[String],
     run_second_cpp: bool,
 ) -> crate::Result<Vec<CompilationTask>> {
-    let expanded_args = expand_response_files(&command.current_dir, args)?;
+    let expanded_args = expand_response_files(&command.current_dir, args, cmd::native::parse)?;
 
     if expanded_args.iter().any(|v| v == "--analyze") {
         // Support only compilation steps
@@ -37,7 +38,7 @@ pub fn create_tasks(
         })
         .collect();
     if input_sources.is_empty() {
-        return Err(crate::Error::from("Can't find source file path."));
+        return Err(TaskError::MissingInput.into());
     }
     /*
     // Precompiled header file name.
@@ -79,16 +80,16 @@ pub fn create_tasks(
         ParamValue::None => None,
         ParamValue::Single(v) => {
             if input_sources.len() > 1 {
-                return Err(crate::Error::from(
-                    "Cannot specify -o when generating multiple output files",
-                ));
+                return Err(TaskError::MultipleInputsUnsupported.into());
             }
             Some(v)
         }
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many output object files: {v:?}"
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "output object files",
+                values: format!("{v:?}"),
+            }
+            .into());
         }
     }
     .map_or(Ok(None), |v| v.map(Some))?;
@@ -121,18 +122,19 @@ pub fn create_tasks(
                     return Ok(Vec::new());
                 }
                 _ => {
-                    return Err(crate::Error::from(format!(
-                        "Unknown source language type: {v}"
-                    )));
+                    return Err(TaskError::UnsupportedLanguage { language: v }.into());
                 }
             }
         }
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many output object files: {v:?}"
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "language options",
+                values: format!("{v:?}"),
+            }
+            .into());
         }
     };
+    let config = command.config.clone();
     let shared = Arc::new(CompilationArgs {
         command,
         args: parsed_args,
@@ -140,6 +142,9 @@ pub fn create_tasks(
         pch_usage: PCHUsage::None,
         deps_file,
         run_second_cpp,
+        notes: Vec::new(),
+        config,
+        link: None,
     });
     input_sources
         .into_iter()
@@ -161,11 +166,8 @@ pub fn create_tasks(
                         },
                         |lang| Some(lang.clone()),
                     )
-                    .ok_or_else(|| {
-                        format!(
-                            "Can't detect file language by extension: {}",
-                            source.as_os_str().to_string_lossy()
-                        )
+                    .ok_or_else(|| TaskError::UnknownLanguage {
+                        path: source.clone(),
                     })?,
                 output_object: output_object
                     .as_ref()
@@ -176,7 +178,7 @@ pub fn create_tasks(
         .collect()
 }
 
-fn parse_arguments(args: &[String]) -> Result<Vec<Arg>, String> {
+fn parse_arguments(args: &[String]) -> Result<Vec<Arg>, TaskError> {
     let mut result: Vec<Arg> = Vec::new();
     let mut errors: Vec<String> = Vec::new();
     let mut iter = args.iter();
@@ -191,7 +193,7 @@ fn parse_arguments(args: &[String]) -> Result<Vec<Arg>, String> {
         }
     }
     if !errors.is_empty() {
-        return Err(format!("Found unknown command line arguments: {errors:?}"));
+        return Err(TaskError::UnsupportedFlags { flags: errors });
     }
     Ok(result)
 }
@@ -1,13 +1,27 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::OutputInfo;
+use crate::io::chunks::ChunkPayload;
+use crate::io::prefix::PrefixChunk;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CompileRequest {
     pub toolchain: String,
     pub args: Vec<String>,
+    // Arguments removed by client before sending request (for debugging).
+    pub stripped_args: Vec<String>,
     pub preprocessed_data: Vec<u8>,
     pub precompiled_hash: Option<String>,
+    // Chunks of preprocessed file before preprocessed_data, empty without prefix deduplication.
+    pub preprocessed_prefix: Vec<PrefixChunk>,
+    // Chunks of preprocessed file instead of preprocessed_data, see RPC_BUILDER_CHUNKS.
+    pub preprocessed_chunks: Option<ChunkPayload>,
+}
+
+// Hashes of preprocessed file chunks, builder answers with indexes of chunks it lacks.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkQuery {
+    pub hashes: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1,24 +1,39 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
-use log::{trace, warn};
+use log::{info, trace, warn};
 use reqwest::blocking::Client;
 use reqwest::StatusCode;
 
-use crate::cache::FileHasher;
-use crate::cluster::builder::{CompileRequest, CompileResponse};
-use crate::cluster::common::{BuilderInfo, RPC_BUILDER_LIST, RPC_BUILDER_TASK, RPC_BUILDER_UPLOAD};
+use crate::cluster::builder::{ChunkQuery, CompileRequest, CompileResponse};
+use crate::cluster::common::{
+    BuilderInfo, RPC_BUILDER_CHUNKS, RPC_BUILDER_LIST, RPC_BUILDER_TASK, RPC_BUILDER_UPLOAD,
+};
+use crate::cluster::dispatch::{BuilderLoad, DispatchPolicy, PchAffinity, Placement};
 use crate::compiler::CompileInput::Preprocessed;
 use crate::compiler::{
-    CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo,
+    CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OutputInfo, PCHUsage,
     PreprocessResult, SharedState, Toolchain,
 };
+use crate::config::Config;
+use crate::io::chunks::{ChunkParams, ChunkPayload, ChunkedStream};
+use crate::io::prefix::{PrefixChunk, PrefixSender, CHECKPOINT_SIZE};
+use crate::io::statcache::FileHasher;
+use crate::io::taskoutput::TaskOutput;
+use crate::io::tempfile::write_atomic;
+use crate::watchdog::TaskPhase;
+
+// Prefix chunks, remaining data, content-defined chunks and bytes not sent.
+type EncodedPayload = (Vec<PrefixChunk>, Vec<u8>, Option<ChunkPayload>, usize);
 
 pub struct RemoteCompiler<C: Compiler> {
     shared: Arc<RemoteShared>,
@@ -35,6 +50,18 @@ struct RemoteShared {
     mutable: RwLock<RemoteSharedMut>,
     base_url: Option<reqwest::Url>,
     client: Client,
+    policy: DispatchPolicy,
+    // Running local compilations.
+    local_in_flight: AtomicUsize,
+    // Running remote compilations by builder endpoint.
+    remote_in_flight: Mutex<HashMap<String, usize>>,
+    // Preprocessed prefix chunks sent to builders, None if deduplication is disabled.
+    prefixes: Option<PrefixSender>,
+    // Send preprocessed files by chunks to builders supporting it.
+    chunk_dedup: bool,
+    // Compile consumers of precompiled headers remotely.
+    remote_pch: bool,
+    pch_affinity: PchAffinity,
 }
 
 struct RemoteToolchain {
@@ -43,15 +70,24 @@ struct RemoteToolchain {
 }
 
 impl<C: Compiler> RemoteCompiler<C> {
-    pub fn new(base_url: &Option<reqwest::Url>, compiler: C) -> Self {
+    pub fn new(config: &Config, compiler: C) -> Self {
         RemoteCompiler {
             shared: Arc::new(RemoteShared {
                 mutable: RwLock::new(RemoteSharedMut {
                     cooldown: Instant::now(),
                     builders: Arc::new(Vec::new()),
                 }),
-                base_url: base_url.as_ref().cloned(),
+                base_url: config.coordinator.clone(),
                 client: Client::new(),
+                policy: DispatchPolicy::new(config),
+                local_in_flight: AtomicUsize::new(0),
+                remote_in_flight: Mutex::new(HashMap::new()),
+                prefixes: config
+                    .prefix_dedup
+                    .then(|| PrefixSender::new(CHECKPOINT_SIZE)),
+                chunk_dedup: config.chunk_dedup,
+                remote_pch: config.remote_pch,
+                pch_affinity: PchAffinity::default(),
             }),
             local: compiler,
         }
@@ -94,49 +130,217 @@ impl<C: Compiler> Compiler for RemoteCompiler<C> {
 }
 
 impl RemoteToolchain {
+    // Select builder for compilation step and its chunking, None means local compilation.
+    fn place(
+        &self,
+        state: &SharedState,
+        task: &CompileStep,
+    ) -> Option<(String, SocketAddr, Option<ChunkParams>)> {
+        // Only preprocessed files can be compiled remotely. Precompiled header is uploaded to
+        // builder, so producers of precompiled headers are compiled locally.
+        let Preprocessed(preprocessed) = &task.input else {
+            return None;
+        };
+        let pch_holders = match &task.pch_usage {
+            PCHUsage::None => Vec::new(),
+            PCHUsage::In(pch) if self.shared.remote_pch => {
+                let meta = state.files.file_hash(&pch.path_abs).ok()?;
+                self.shared.pch_affinity.holders(&pch.path_abs, &meta.hash)
+            }
+            _ => return None,
+        };
+        if task
+            .source
+            .as_ref()
+            .is_some_and(|source| state.overrides.get(source).no_remote)
+        {
+            return None;
+        }
+        // Builder doesn't know phase programs of this toolchain.
+        if self
+            .executable()
+            .is_some_and(|path| state.phases.find(path).is_some())
+        {
+            return None;
+        }
+        let name = self.identifier()?;
+        let builders = self.builders();
+        let placement = {
+            let remote_in_flight = self.shared.remote_in_flight.lock().unwrap();
+            let loads: Vec<BuilderLoad> = builders
+                .iter()
+                .map(|info| BuilderLoad {
+                    info,
+                    in_flight: remote_in_flight.get(&info.endpoint).copied().unwrap_or(0),
+                    holds_pch: pch_holders.contains(&info.endpoint),
+                })
+                .collect();
+            self.shared.policy.place(
+                &name,
+                preprocessed.len() as u64,
+                self.shared.local_in_flight.load(Ordering::Acquire),
+                &loads,
+                state.schedule.random(&task.output_object),
+            )
+        };
+        match placement {
+            Placement::Local => None,
+            Placement::Remote(index) => {
+                let endpoint = &builders[index].endpoint;
+                Some((
+                    endpoint.clone(),
+                    SocketAddr::from_str(endpoint).ok()?,
+                    builders[index].chunking,
+                ))
+            }
+        }
+    }
+
     fn compile_remote(
         &self,
         state: &SharedState,
         task: &CompileStep,
+        endpoint: &str,
+        addr: &SocketAddr,
+        chunking: Option<ChunkParams>,
     ) -> Result<CompileResponse, Error> {
         let name = self
             .identifier()
             .ok_or_else(|| Error::new(ErrorKind::Other, "Can't get toolchain name"))?;
 
-        let addr = self
-            .remote_endpoint(&name)
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Can't find helper for toolchain"))?;
-        if task.pch_usage.is_some() {
+        let base_url = get_base_url(addr);
+
+        let Preprocessed(preprocessed) = &task.input else {
             return Err(Error::new(
                 ErrorKind::Other,
-                "Remote precompiled header generation is not supported",
+                "Only preprocessed files can be compiled remotely",
             ));
-        }
-
-        let base_url = get_base_url(&addr);
-
-        let preprocessed = if let Preprocessed(preprocessed) = &task.input {
-            preprocessed
-        } else {
-            unimplemented!()
         };
 
         // Send compilation request.
-        let request = CompileRequest {
+        let (args, stripped_args) = split_remote_args(self, &task.args);
+        let peer = addr.to_string();
+        let data = preprocessed.to_vec();
+        let chunking = chunking.filter(|_| self.shared.chunk_dedup);
+        let (preprocessed_prefix, preprocessed_data, preprocessed_chunks, mut saved) =
+            self.encode_payload(&base_url, &peer, &data, chunking, false)?;
+        let mut request = CompileRequest {
             toolchain: name,
-            args: task
-                .args
-                .iter()
-                .map(|s| s.to_str().unwrap().to_string())
-                .collect(),
-            preprocessed_data: preprocessed.to_vec(),
+            args,
+            stripped_args,
+            preprocessed_data,
             precompiled_hash: self.upload_precompiled(
                 state,
                 &task.pch_usage.get_in_abs(),
+                endpoint,
                 &base_url,
             )?,
+            preprocessed_prefix,
+            preprocessed_chunks,
         };
-        let request_payload = bincode::serialize(&request).unwrap();
+        let mut result = self.send_task(&base_url, &request);
+        let deduplicated =
+            request.preprocessed_chunks.is_some() || !request.preprocessed_prefix.is_empty();
+        if matches!(&result, Err(e) if e.kind() == ErrorKind::NotFound) && deduplicated {
+            // Builder dropped chunks referenced by key only.
+            info!("Builder {peer} lost preprocessed chunks, sending them again");
+            if let Some(prefixes) = &self.shared.prefixes {
+                prefixes.forget(&peer);
+            }
+            (
+                request.preprocessed_prefix,
+                request.preprocessed_data,
+                request.preprocessed_chunks,
+                saved,
+            ) = self.encode_payload(&base_url, &peer, &data, chunking, true)?;
+            result = self.send_task(&base_url, &request);
+        }
+        let result = result?;
+        if let Some(prefixes) = &self.shared.prefixes {
+            prefixes.confirm(&peer, &request.preprocessed_prefix);
+        }
+        state.statistic.add_prefix_saved(saved);
+        if let Some(chunks) = &request.preprocessed_chunks {
+            state
+                .statistic
+                .add_chunks(preprocessed.len(), chunks.sent_bytes());
+        }
+        if let CompileResponse::Success(ref output) = result {
+            write_output(&task.output_object, output.success(), &output.stdout)?;
+        }
+        state.statistic.inc_remote();
+        Ok(result)
+    }
+
+    // Preprocessed file as prefix chunks, remaining data and content-defined chunks, along with
+    // count of bytes not sent. Full payload has data of every chunk.
+    fn encode_payload(
+        &self,
+        base_url: &reqwest::Url,
+        peer: &str,
+        data: &[u8],
+        chunking: Option<ChunkParams>,
+        full: bool,
+    ) -> Result<EncodedPayload, Error> {
+        Ok(match (chunking, &self.shared.prefixes) {
+            (Some(params), _) => (
+                Vec::new(),
+                Vec::new(),
+                Some(self.chunk_payload(base_url, data, params, full)?),
+                0,
+            ),
+            (None, Some(prefixes)) => {
+                let encoded = if full {
+                    prefixes.encode_full(data)
+                } else {
+                    prefixes.encode(peer, data)
+                };
+                (encoded.prefix, encoded.suffix, None, encoded.saved)
+            }
+            (None, None) => (Vec::new(), data.to_vec(), None, 0),
+        })
+    }
+
+    // Ask builder which chunks of preprocessed file it lacks, only they are sent with the task.
+    fn chunk_payload(
+        &self,
+        base_url: &reqwest::Url,
+        data: &[u8],
+        params: ChunkParams,
+        full: bool,
+    ) -> Result<ChunkPayload, Error> {
+        let stream = ChunkedStream::new(data, params);
+        if full {
+            let all: Vec<usize> = (0..stream.hashes().len()).collect();
+            return stream.payload(&all);
+        }
+        let query = ChunkQuery {
+            hashes: stream.hashes().to_vec(),
+        };
+        let mut resp: reqwest::blocking::Response = self
+            .shared
+            .client
+            .post(base_url.join(RPC_BUILDER_CHUNKS).unwrap())
+            .body(bincode::serialize(&query).unwrap())
+            .send()
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+        if !resp.status().is_success() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Builder rejected chunk query: {}", resp.status()),
+            ));
+        }
+        let missing: Vec<usize> = bincode::deserialize_from(&mut resp)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        stream.payload(&missing)
+    }
+
+    fn send_task(
+        &self,
+        base_url: &reqwest::Url,
+        request: &CompileRequest,
+    ) -> Result<CompileResponse, Error> {
+        let request_payload = bincode::serialize(request).unwrap();
         let mut resp: reqwest::blocking::Response = self
             .shared
             .client
@@ -144,30 +348,33 @@ impl RemoteToolchain {
             .body(request_payload)
             .send()
             .map_err(|e| Error::new(ErrorKind::Other, e))?;
-        // Receive compilation result.
-        let result: CompileResponse = bincode::deserialize_from(&mut resp)
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-        if let CompileResponse::Success(ref output) = result {
-            write_output(
-                &task.output_object,
-                output.success(),
-                output.stdout.as_slice(),
-            )?;
+        if !resp.status().is_success() {
+            // Builder lacks data referenced by request.
+            let kind = if resp.status() == StatusCode::FAILED_DEPENDENCY {
+                ErrorKind::NotFound
+            } else {
+                ErrorKind::Other
+            };
+            return Err(Error::new(
+                kind,
+                format!("Builder rejected task: {}", resp.status()),
+            ));
         }
-        state.statistic.inc_remote();
-        Ok(result)
+        // Receive compilation result.
+        bincode::deserialize_from(&mut resp).map_err(|e| Error::new(ErrorKind::InvalidData, e))
     }
 
     fn upload_precompiled(
         &self,
         state: &SharedState,
         precompiled: &Option<&PathBuf>,
+        endpoint: &str,
         base_url: &reqwest::Url,
     ) -> Result<Option<String>, Error> {
         match precompiled {
             Some(ref path) => {
                 // Get precompiled header file hash
-                let meta = state.cache.file_hash(path)?;
+                let meta = state.files.file_hash(path)?;
                 // Check is precompiled header uploaded
                 // todo: this is workaround for https://github.com/hyperium/hyper/issues/838
                 match self
@@ -182,7 +389,10 @@ impl RemoteToolchain {
                     .map(|response| response.status())
                     .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?
                 {
-                    StatusCode::OK | StatusCode::ACCEPTED => return Ok(Some(meta.hash)),
+                    StatusCode::OK | StatusCode::ACCEPTED => {
+                        self.shared.pch_affinity.record(path, &meta.hash, endpoint);
+                        return Ok(Some(meta.hash));
+                    }
                     _ => {}
                 }
                 let file = File::open(path)?;
@@ -202,7 +412,10 @@ impl RemoteToolchain {
                     .map(|response| response.status())
                     .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?
                 {
-                    StatusCode::OK | StatusCode::ACCEPTED => Ok(Some(meta.hash)),
+                    StatusCode::OK | StatusCode::ACCEPTED => {
+                        self.shared.pch_affinity.record(path, &meta.hash, endpoint);
+                        Ok(Some(meta.hash))
+                    }
                     status => Err(Error::new(
                         ErrorKind::BrokenPipe,
                         format!("Can't upload precompiled header: {status}"),
@@ -240,12 +453,33 @@ impl RemoteToolchain {
             holder.builders.clone()
         }
     }
-    // Resolve toolchain for command execution.
-    fn remote_endpoint(&self, toolchain_name: &str) -> Option<SocketAddr> {
-        let name = toolchain_name.to_string();
-        let all_builders = self.builders();
-        let builder = get_random_builder(&all_builders, |b| b.toolchains.contains(&name))?;
-        SocketAddr::from_str(&builder.endpoint).ok()
+
+    fn compile_local(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
+        self.shared.local_in_flight.fetch_add(1, Ordering::AcqRel);
+        let result = self.local.run_compile(state, task);
+        self.shared.local_in_flight.fetch_sub(1, Ordering::AcqRel);
+        result
+    }
+
+    fn track_remote<T>(&self, endpoint: &str, func: impl FnOnce() -> T) -> T {
+        *self
+            .shared
+            .remote_in_flight
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_string())
+            .or_default() += 1;
+        let result = func();
+        if let Some(count) = self
+            .shared
+            .remote_in_flight
+            .lock()
+            .unwrap()
+            .get_mut(endpoint)
+        {
+            *count -= 1;
+        }
+        result
     }
 }
 
@@ -254,6 +488,14 @@ impl Toolchain for RemoteToolchain {
         self.local.identifier()
     }
 
+    fn task_identifier(&self, task: &CompilationTask) -> Option<String> {
+        self.local.task_identifier(task)
+    }
+
+    fn executable(&self) -> Option<&Path> {
+        self.local.executable()
+    }
+
     // Parse compiler arguments.
     fn create_tasks(
         &self,
@@ -282,20 +524,59 @@ impl Toolchain for RemoteToolchain {
         self.local.create_compile_step(task, preprocessed)
     }
 
+    fn strip_on_remote(&self, arg: &OsStr) -> bool {
+        self.local.strip_on_remote(arg)
+    }
+
+    fn bigobj_flag(&self, args: &[OsString]) -> Option<&'static str> {
+        self.local.bigobj_flag(args)
+    }
+
+    fn is_section_overflow(&self, output: &OutputInfo) -> bool {
+        self.local.is_section_overflow(output)
+    }
+
+    fn warmup_args(&self, source: &Path, object: &Path) -> Option<Vec<OsString>> {
+        self.local.warmup_args(source, object)
+    }
+
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
-        match self.compile_remote(state, &task) {
+        let Some((endpoint, addr, chunking)) = self.place(state, &task) else {
+            return self.compile_local(state, task);
+        };
+        state.monitor.remote(&endpoint);
+        state.etw.remote_dispatch(&endpoint);
+        match self.track_remote(&endpoint, || {
+            self.compile_remote(state, &task, &endpoint, &addr, chunking)
+        }) {
             Ok(response) => match response {
                 CompileResponse::Success(output) => Ok(output),
                 CompileResponse::Err(err) => Err(err.into()),
             },
             Err(e) => {
                 trace!("Fallback to local build: {}", e);
-                self.local.run_compile(state, task)
+                state.monitor.phase(TaskPhase::Compiling);
+                self.compile_local(state, task)
             }
         }
     }
 }
 
+// Split compile arguments into sent to remote builder and stripped ones.
+pub(crate) fn split_remote_args(
+    toolchain: &dyn Toolchain,
+    args: &[OsString],
+) -> (Vec<String>, Vec<String>) {
+    let (stripped, remote): (Vec<&OsString>, Vec<&OsString>) =
+        args.iter().partition(|arg| toolchain.strip_on_remote(arg));
+    let to_strings = |args: Vec<&OsString>| -> Vec<String> {
+        args.iter()
+            .map(|s| s.to_str().unwrap().to_string())
+            .collect()
+    };
+    (to_strings(remote), to_strings(stripped))
+}
+
 fn get_base_url(addr: &SocketAddr) -> reqwest::Url {
     let mut url = reqwest::Url::from_str("http://localhost").unwrap();
     url.set_ip_host(addr.ip()).unwrap();
@@ -303,16 +584,12 @@ fn get_base_url(addr: &SocketAddr) -> reqwest::Url {
     url
 }
 
-fn write_output(path: &Option<PathBuf>, success: bool, output: &[u8]) -> Result<(), Error> {
+fn write_output(path: &Option<PathBuf>, success: bool, output: &TaskOutput) -> Result<(), Error> {
     match path {
         Some(ref path) => {
             if success {
-                let mut f = File::create(path)?;
-                f.write(output).map_err(|e| {
-                    drop(fs::remove_file(path));
-                    e
-                })?;
-                Ok(())
+                write_atomic(path, |f| Ok(output.replay(f)?))
+                    .map_err(|e| Error::new(ErrorKind::Other, e))
             } else {
                 fs::remove_file(path)
             }
@@ -320,15 +597,3 @@ fn write_output(path: &Option<PathBuf>, success: bool, output: &[u8]) -> Result<
         None => Ok(()),
     }
 }
-
-fn get_random_builder<F: Fn(&BuilderInfo) -> bool>(
-    builders: &[BuilderInfo],
-    filter: F,
-) -> Option<&BuilderInfo> {
-    let filtered: Vec<&BuilderInfo> = builders.iter().filter(|b| filter(b)).collect();
-    if filtered.is_empty() {
-        return None;
-    }
-
-    Some(filtered[rand::random::<usize>() % filtered.len()])
-}
@@ -1,11 +1,14 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::io::chunks::ChunkParams;
+
 pub const RPC_BUILDER_UPDATE: &str = "/rpc/v1/builder/update";
 pub const RPC_BUILDER_LIST: &str = "/rpc/v1/builder/list";
 
 pub const RPC_BUILDER_TASK: &str = "/rpc/v1/builder/task";
 pub const RPC_BUILDER_UPLOAD: &str = "/rpc/v1/builder/upload";
+pub const RPC_BUILDER_CHUNKS: &str = "/rpc/v1/builder/chunks";
 
 #[derive(Serialize, Deserialize)]
 pub struct BuilderInfo {
@@ -17,6 +20,12 @@ pub struct BuilderInfo {
     pub version: String,
     // Agent toolchain list
     pub toolchains: Vec<String>,
+    // Count of tasks agent compiles simultaneously
+    pub slots: usize,
+    // Max preprocessed size of accepted task in bytes (0 means unlimited)
+    pub max_payload: u64,
+    // Chunking of preprocessed files, None if builder accepts only whole files
+    pub chunking: Option<ChunkParams>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cluster::common::BuilderInfo;
+use crate::config::Config;
+
+// Builder with this share of busy slots in percent is saturated: consumers of precompiled header
+// held by it are placed as usual instead of waiting for it.
+const AFFINITY_MAX_LOAD: usize = 75;
+
+// Decides whether preprocessed translation unit is compiled locally or on remote builder.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DispatchPolicy {
+    // Smaller translation units are compiled locally: round-trip costs more than compilation.
+    pub min_size: u64,
+    // Translation units at least this large go to builder with the most free slots (0 disables).
+    pub large_size: u64,
+    // Count of tasks that are always compiled locally to keep local cores busy.
+    pub local_reserve: usize,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Placement {
+    Local,
+    // Index of selected builder.
+    Remote(usize),
+}
+
+pub struct BuilderLoad<'a> {
+    pub info: &'a BuilderInfo,
+    // Tasks sent to builder and not completed yet.
+    pub in_flight: usize,
+    // Builder holds precompiled header used by the task.
+    pub holds_pch: bool,
+}
+
+impl BuilderLoad<'_> {
+    fn free_slots(&self) -> usize {
+        self.info.slots.saturating_sub(self.in_flight)
+    }
+
+    fn accepts(&self, toolchain: &str, size: u64) -> bool {
+        self.info.toolchains.iter().any(|name| name == toolchain)
+            && (self.info.max_payload == 0 || size <= self.info.max_payload)
+            && self.free_slots() > 0
+    }
+
+    fn saturated(&self) -> bool {
+        self.in_flight * 100 >= self.info.slots * AFFINITY_MAX_LOAD
+    }
+}
+
+// Builders holding precompiled headers by header path, learned when header is uploaded or
+// found on builder. Consumers prefer these builders to avoid header transfer.
+#[derive(Default)]
+pub struct PchAffinity {
+    holders: Mutex<HashMap<PathBuf, (String, Vec<String>)>>,
+}
+
+impl PchAffinity {
+    // Remember that builder holds precompiled header with given content hash.
+    pub fn record(&self, path: &Path, hash: &str, endpoint: &str) {
+        let mut holders = self.holders.lock().unwrap();
+        let (current, endpoints) = holders
+            .entry(path.to_path_buf())
+            .or_insert_with(|| (hash.to_string(), Vec::new()));
+        if current != hash {
+            // Header is rebuilt, builders hold stale content.
+            *current = hash.to_string();
+            endpoints.clear();
+        }
+        if !endpoints.iter().any(|known| known == endpoint) {
+            endpoints.push(endpoint.to_string());
+        }
+    }
+
+    // Builders holding precompiled header with given content hash.
+    #[must_use]
+    pub fn holders(&self, path: &Path, hash: &str) -> Vec<String> {
+        let mut holders = self.holders.lock().unwrap();
+        match holders.get(path) {
+            Some((current, endpoints)) if current == hash => endpoints.clone(),
+            Some(_) => {
+                holders.remove(path);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl DispatchPolicy {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        DispatchPolicy {
+            min_size: config.remote_min_size_kb * 1024,
+            large_size: config.remote_large_size_kb * 1024,
+            local_reserve: config.remote_local_reserve,
+        }
+    }
+
+    // Select place for translation unit of given size.
+    // `random` is used to spread ordinary translation units between builders.
+    #[must_use]
+    pub fn place(
+        &self,
+        toolchain: &str,
+        size: u64,
+        local_in_flight: usize,
+        builders: &[BuilderLoad],
+        random: usize,
+    ) -> Placement {
+        if size < self.min_size || local_in_flight < self.local_reserve {
+            return Placement::Local;
+        }
+        let candidates: Vec<usize> = builders
+            .iter()
+            .enumerate()
+            .filter(|(_, builder)| builder.accepts(toolchain, size))
+            .map(|(index, _)| index)
+            .collect();
+        if candidates.is_empty() {
+            return Placement::Local;
+        }
+        // Builder holding precompiled header saves its transfer.
+        if let Some(best) = candidates
+            .iter()
+            .rev()
+            .filter(|index| builders[**index].holds_pch && !builders[**index].saturated())
+            .max_by_key(|index| builders[**index].free_slots())
+        {
+            return Placement::Remote(*best);
+        }
+        if self.large_size > 0 && size >= self.large_size {
+            // max_by_key returns last maximum, prefer first one for stable placement.
+            let best = candidates
+                .iter()
+                .rev()
+                .max_by_key(|index| builders[**index].free_slots())
+                .unwrap();
+            return Placement::Remote(*best);
+        }
+        Placement::Remote(candidates[random % candidates.len()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::cluster::common::BuilderInfo;
+    use crate::cluster::dispatch::{BuilderLoad, DispatchPolicy, PchAffinity, Placement};
+
+    const KB: u64 = 1024;
+
+    fn builder(name: &str, slots: usize, max_payload: u64) -> BuilderInfo {
+        BuilderInfo {
+            name: name.to_string(),
+            endpoint: format!("{name}:3000"),
+            version: String::new(),
+            toolchains: vec!["cl".to_string()],
+            slots,
+            max_payload,
+            chunking: None,
+        }
+    }
+
+    fn loads<'a>(builders: &'a [BuilderInfo], in_flight: &[usize]) -> Vec<BuilderLoad<'a>> {
+        builders
+            .iter()
+            .zip(in_flight)
+            .map(|(info, in_flight)| BuilderLoad {
+                info,
+                in_flight: *in_flight,
+                holds_pch: false,
+            })
+            .collect()
+    }
+
+    // Place tasks one by one, each remote placement occupies builder slot.
+    fn simulate(
+        policy: &DispatchPolicy,
+        builders: &[BuilderInfo],
+        local_in_flight: usize,
+        sizes: &[u64],
+    ) -> Vec<Placement> {
+        let mut in_flight = vec![0; builders.len()];
+        let mut local = local_in_flight;
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(random, size)| {
+                let placement =
+                    policy.place("cl", *size, local, &loads(builders, &in_flight), random);
+                match placement {
+                    Placement::Local => local += 1,
+                    Placement::Remote(index) => in_flight[index] += 1,
+                }
+                placement
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_min_size() {
+        let policy = DispatchPolicy {
+            min_size: 16 * KB,
+            ..DispatchPolicy::default()
+        };
+        let builders = [builder("a", 8, 0)];
+        assert_eq!(
+            simulate(&policy, &builders, 0, &[KB, 15 * KB, 16 * KB, 300 * KB]),
+            [
+                Placement::Local,
+                Placement::Local,
+                Placement::Remote(0),
+                Placement::Remote(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_local_reserve() {
+        let policy = DispatchPolicy {
+            local_reserve: 2,
+            ..DispatchPolicy::default()
+        };
+        let builders = [builder("a", 8, 0)];
+        assert_eq!(
+            simulate(&policy, &builders, 0, &[KB; 4]),
+            [
+                Placement::Local,
+                Placement::Local,
+                Placement::Remote(0),
+                Placement::Remote(0)
+            ]
+        );
+        // Reserve is already occupied by running tasks.
+        assert_eq!(
+            simulate(&policy, &builders, 2, &[KB]),
+            [Placement::Remote(0)]
+        );
+    }
+
+    #[test]
+    fn test_large_size_prefers_free_slots() {
+        let policy = DispatchPolicy {
+            large_size: 100 * KB,
+            ..DispatchPolicy::default()
+        };
+        let builders = [builder("small", 2, 0), builder("big", 4, 0)];
+        // Largest units first: they fill the biggest builder until it is as busy as others.
+        assert_eq!(
+            simulate(
+                &policy,
+                &builders,
+                0,
+                &[
+                    900 * KB,
+                    800 * KB,
+                    700 * KB,
+                    600 * KB,
+                    500 * KB,
+                    400 * KB,
+                    300 * KB
+                ]
+            ),
+            [
+                Placement::Remote(1),
+                Placement::Remote(1),
+                Placement::Remote(0),
+                Placement::Remote(1),
+                Placement::Remote(0),
+                Placement::Remote(1),
+                // All slots are busy.
+                Placement::Local,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_small_units_spread() {
+        let policy = DispatchPolicy {
+            large_size: 100 * KB,
+            ..DispatchPolicy::default()
+        };
+        let builders = [builder("a", 4, 0), builder("b", 4, 0)];
+        assert_eq!(
+            simulate(&policy, &builders, 0, &[KB; 4]),
+            [
+                Placement::Remote(0),
+                Placement::Remote(1),
+                Placement::Remote(0),
+                Placement::Remote(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_payload() {
+        let policy = DispatchPolicy::default();
+        let builders = [builder("wan", 8, 64 * KB), builder("lan", 8, 0)];
+        let loads = loads(&builders, &[0, 0]);
+        // Random selection never picks builder that can't accept payload.
+        for random in 0..4 {
+            assert_eq!(
+                policy.place("cl", 128 * KB, 0, &loads, random),
+                Placement::Remote(1)
+            );
+        }
+        assert_eq!(
+            policy.place("cl", 128 * KB, 0, &loads[..1], 0),
+            Placement::Local
+        );
+        assert_eq!(policy.place("clang", KB, 0, &loads, 0), Placement::Local);
+    }
+
+    #[test]
+    fn test_pch_affinity() {
+        let policy = DispatchPolicy::default();
+        let builders = [builder("a", 4, 0), builder("b", 4, 0), builder("c", 4, 0)];
+        let with_pch = |in_flight: &[usize], holders: &[usize]| {
+            let mut loads = loads(&builders, in_flight);
+            for index in holders {
+                loads[*index].holds_pch = true;
+            }
+            loads
+        };
+        // Consumers follow precompiled header whatever random placement is.
+        for random in 0..3 {
+            assert_eq!(
+                policy.place("cl", KB, 0, &with_pch(&[0, 0, 0], &[1]), random),
+                Placement::Remote(1)
+            );
+        }
+        // Less busy holder is preferred.
+        assert_eq!(
+            policy.place("cl", KB, 0, &with_pch(&[0, 2, 1], &[1, 2]), 0),
+            Placement::Remote(2)
+        );
+        // Saturated holder doesn't make consumers wait, they are placed as usual.
+        assert_eq!(
+            policy.place("cl", KB, 0, &with_pch(&[0, 3, 0], &[1]), 2),
+            Placement::Remote(2)
+        );
+        // Other tasks don't care.
+        assert_eq!(
+            policy.place("cl", KB, 0, &with_pch(&[0, 0, 0], &[]), 2),
+            Placement::Remote(2)
+        );
+        // Affinity doesn't override size rules.
+        let policy = DispatchPolicy {
+            min_size: 16 * KB,
+            ..DispatchPolicy::default()
+        };
+        assert_eq!(
+            policy.place("cl", KB, 0, &with_pch(&[0, 0, 0], &[1]), 0),
+            Placement::Local
+        );
+    }
+
+    #[test]
+    fn test_pch_holders() {
+        let affinity = PchAffinity::default();
+        let pch = Path::new("shared.pch");
+        affinity.record(pch, "v1", "a:3000");
+        affinity.record(pch, "v1", "b:3000");
+        affinity.record(pch, "v1", "a:3000");
+        assert_eq!(affinity.holders(pch, "v1"), ["a:3000", "b:3000"]);
+        assert!(affinity.holders(Path::new("other.pch"), "v1").is_empty());
+        // Rebuilt header forgets builders holding old content.
+        assert!(affinity.holders(pch, "v2").is_empty());
+        assert!(affinity.holders(pch, "v1").is_empty());
+        affinity.record(pch, "v1", "a:3000");
+        affinity.record(pch, "v2", "b:3000");
+        assert_eq!(affinity.holders(pch, "v2"), ["b:3000"]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::iter;
+use std::iter::Peekable;
+use std::str::Chars;
+
+trait CharsExt {
+    fn advance_while<P: FnMut(char) -> bool>(&mut self, predicate: P) -> usize;
+}
+
+impl CharsExt for Peekable<Chars<'_>> {
+    fn advance_while<P: FnMut(char) -> bool>(&mut self, mut predicate: P) -> usize {
+        let mut counter = 0;
+        while let Some(c) = self.peek() {
+            if !predicate(*c) {
+                break;
+            }
+            counter += 1;
+            self.next();
+        }
+        counter
+    }
+}
+
+// Parsing command line arguments from singe line by MSVC rules, which are also used for
+// response files of cl.exe on any platform.
+// See also: http://msdn.microsoft.com/en-us/library/17w5ykft.aspx
+pub fn parse(cmd_line: &str) -> crate::Result<Vec<String>> {
+    const BACKSLASH: char = '\\';
+    const QUOTE: char = '"';
+    const TAB: char = '\t';
+    const SPACE: char = ' ';
+    const NEWLINE: char = '\n';
+    const RETURN: char = '\r';
+
+    let mut ret_val = Vec::<String>::new();
+
+    let mut code_units = cmd_line.trim().chars().peekable();
+
+    // Parse the arguments according to these rules:
+    // * All code units are taken literally except space, tab, quote and backslash.
+    // * When not `in_quotes`, space, tab and newline separate arguments. Consecutive separators are
+    // treated as a single separator.
+    // * A space, tab or newline `in_quotes` is taken literally.
+    // * A quote toggles `in_quotes` mode unless it's escaped. An escaped quote is taken literally.
+    // * A quote can be escaped if preceded by an odd number of backslashes.
+    // * If any number of backslashes is immediately followed by a quote then the number of
+    // backslashes is halved (rounding down).
+    // * Backslashes not followed by a quote are all taken literally.
+    // * If `in_quotes` then a quote can also be escaped using another quote
+    // (i.e. two consecutive quotes become one literal quote).
+    // * Unlike the rules above, a single backslash before the quote closing quoted option value,
+    // like `/Fo"Build\My Game\" /c`, is a trailing path separator: cl.exe treats it this way.
+    let mut cur = Vec::new();
+    let mut in_quotes = false;
+    // Quotes are opened inside option like `/Fo"dir"`.
+    let mut option_quotes = false;
+    while let Some(c) = code_units.next() {
+        match c {
+            // If not `in_quotes`, a space or tab ends the argument.
+            SPACE | NEWLINE | RETURN | TAB if !in_quotes => {
+                ret_val.push(String::from_iter(&cur[..]));
+                cur.truncate(0);
+
+                // Skip whitespace.
+                code_units.advance_while(|w| w == SPACE || w == NEWLINE || w == RETURN || w == TAB);
+            }
+            // Backslashes can escape quotes or backslashes but only if consecutive backslashes are followed by a quote.
+            BACKSLASH => {
+                let backslash_count = code_units.advance_while(|w| w == BACKSLASH) + 1;
+                if code_units.peek() == Some(&QUOTE)
+                    && in_quotes
+                    && option_quotes
+                    && backslash_count == 1
+                    && is_value_end(code_units.clone().nth(1))
+                {
+                    cur.push(BACKSLASH);
+                    code_units.next();
+                    in_quotes = false;
+                } else if code_units.peek() == Some(&QUOTE) {
+                    cur.extend(iter::repeat(BACKSLASH).take(backslash_count / 2));
+                    // The quote is escaped if there are an odd number of backslashes.
+                    if backslash_count % 2 == 1 {
+                        code_units.next();
+                        cur.push(QUOTE);
+                    }
+                } else {
+                    // If there is no quote on the end then there is no escaping.
+                    cur.extend(iter::repeat(BACKSLASH).take(backslash_count));
+                }
+            }
+            // If `in_quotes` and not backslash escaped (see above) then a quote either
+            // unsets `in_quote` or is escaped by another quote.
+            QUOTE if in_quotes => match code_units.peek() {
+                // Two consecutive quotes when `in_quotes` produces one literal quote.
+                Some(&QUOTE) => {
+                    cur.push(QUOTE);
+                    code_units.next();
+                }
+                // Otherwise set `in_quotes`.
+                Some(_) => in_quotes = false,
+                // The end of the command line.
+                // Push `cur` even if empty, which we do by breaking while `in_quotes` is still set.
+                None => break,
+            },
+            // If not `in_quotes` and not BACKSLASH escaped (see above) then a quote sets `in_quote`.
+            QUOTE => {
+                in_quotes = true;
+                option_quotes = matches!(cur.first(), Some('/' | '-'));
+            }
+            // Everything else is always taken literally.
+            _ => cur.push(c),
+        }
+    }
+    // Push the final argument, if any.
+    if !cur.is_empty() || in_quotes {
+        ret_val.push(String::from_iter(&cur[..]));
+    }
+    Ok(ret_val)
+}
+
+// Quote is followed by argument separator or the end of command line.
+fn is_value_end(c: Option<char>) -> bool {
+    matches!(c, None | Some(' ' | '\t' | '\n' | '\r'))
+}
+
+#[test]
+fn test_parse_1() {
+    assert_eq!(parse("\"abc\" d e").unwrap(), ["abc", "d", "e"]);
+}
+
+#[test]
+fn test_parse_2() {
+    assert_eq!(parse(" \"abc\" d e ").unwrap(), ["abc", "d", "e"]);
+}
+
+#[test]
+fn test_parse_3() {
+    assert_eq!(
+        parse("\"\" \"abc\" d e \"\"").unwrap(),
+        ["", "abc", "d", "e", ""]
+    );
+}
+
+#[test]
+fn test_parse_4() {
+    assert_eq!(
+        parse("a\\\\b d\"e f\"g h").unwrap(),
+        ["a\\\\b", "de fg", "h"]
+    );
+}
+
+#[test]
+fn test_parse_5() {
+    assert_eq!(parse("a\\\\\\\"b c d").unwrap(), ["a\\\"b", "c", "d"]);
+}
+
+#[test]
+fn test_parse_6() {
+    assert_eq!(
+        parse("a\\\\\\\\\"b c\" d e").unwrap(),
+        ["a\\\\b c", "d", "e"]
+    );
+}
+
+#[test]
+fn test_parse_7() {
+    assert_eq!(
+        parse("C:\\Windows\\System32 d e").unwrap(),
+        ["C:\\Windows\\System32", "d", "e"]
+    );
+}
+
+#[test]
+fn test_parse_8() {
+    assert_eq!(
+        parse("/TEST\"C:\\Windows\\System32\" d e").unwrap(),
+        ["/TESTC:\\Windows\\System32", "d", "e"]
+    );
+}
+
+#[test]
+fn test_parse_9() {
+    assert_eq!(
+        parse("/Fp\"Debug\\HelloWorld.pch\" /Fo\"Debug\\\\\" /Gd").unwrap(),
+        ["/FpDebug\\HelloWorld.pch", "/FoDebug\\", "/Gd"]
+    );
+}
+
+#[test]
+fn test_parse_newline() {
+    assert_eq!(
+        parse("/D \"A=1\n2\"\r\n/D\tB=\"\t\u{1}\"\n a.cpp\n").unwrap(),
+        ["/D", "A=1\n2", "/D", "B=\t\u{1}", "a.cpp"]
+    );
+}
+
+#[test]
+fn test_parse_trailing_backslash() {
+    assert_eq!(
+        parse("/Fo\"Intermediate\\Build\\Win64\\My Game\\\" /c \"a b.cpp\"").unwrap(),
+        ["/FoIntermediate\\Build\\Win64\\My Game\\", "/c", "a b.cpp"]
+    );
+    assert_eq!(parse("/Fo\"out dir\\\"").unwrap(), ["/Foout dir\\"]);
+    // Escaped quotes inside values and outside options are kept.
+    assert_eq!(
+        parse("/D \"VERSION=\\\"1.2\\\"\" \"say \\\" x\"").unwrap(),
+        ["/D", "VERSION=\"1.2\"", "say \" x"]
+    );
+}
+
+#[test]
+fn test_parse_response_file() {
+    assert_eq!(
+        parse("/D\"MSG=\\\"hi there\\\"\"\r\n\"src dir\\a.cpp\" /Fo\"out dir\\\\\"\r\n").unwrap(),
+        ["/DMSG=\"hi there\"", "src dir\\a.cpp", "/Foout dir\\"]
+    );
+}
@@ -7,7 +7,11 @@ pub fn parse(cmd: &str) -> crate::Result<Vec<String>> {
 }
 
 pub fn quote(arg: impl AsRef<OsStr>) -> crate::Result<OsString> {
-    let quoted = shlex::try_quote(arg.as_ref().to_str().unwrap())?;
+    let arg = arg.as_ref();
+    let arg = arg.to_str().ok_or_else(|| {
+        crate::Error::Generic(format!("Unable to quote non-UTF-8 argument: {arg:?}"))
+    })?;
+    let quoted = shlex::try_quote(arg)?;
     Ok(OsString::from(quoted.as_ref()))
 }
 
@@ -75,3 +79,52 @@ fn test_parse_10() {
         ["begin", "some text", "end"]
     );
 }
+
+#[test]
+fn test_parse_newline() {
+    assert_eq!(
+        parse("-D \"A=1\n2\"\n-DB='\t\u{1}'\n a.cpp\n").unwrap(),
+        ["-D", "A=1\n2", "-DB=\t\u{1}", "a.cpp"]
+    );
+}
+
+#[test]
+fn test_quote_round_trip() {
+    let args: Vec<OsString> = ["-DA=1\n2", "-DB=\t", "-DC=\u{1}\"\\", "a.cpp"]
+        .iter()
+        .map(OsString::from)
+        .collect();
+    let line = super::join(&args).unwrap();
+    assert_eq!(
+        parse(line.to_str().unwrap()).unwrap(),
+        ["-DA=1\n2", "-DB=\t", "-DC=\u{1}\"\\", "a.cpp"]
+    );
+}
+
+#[test]
+fn test_quote_cmd_hostile() {
+    // Arguments are passed to compiler as is: percent signs, carets and ampersands are not special.
+    let expected = [
+        "/Fo/out%PATH%/a.obj",
+        "a^b",
+        "a&b",
+        "!x!",
+        "100%%",
+        "/Fo\"x y%\"",
+    ];
+    let args: Vec<OsString> = expected.iter().map(OsString::from).collect();
+    let line = super::join(&args).unwrap();
+    assert_eq!(parse(line.to_str().unwrap()).unwrap(), expected);
+}
+
+#[test]
+fn test_non_utf8() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let arg = OsStr::from_bytes(b"a\xff.cpp").to_os_string();
+    assert!(quote(&arg).is_err());
+    let mut command = std::process::Command::new("cl");
+    assert!(crate::compiler::OsCommandArgs::Raw(arg)
+        .append_to(&mut command)
+        .is_err());
+}
@@ -1,16 +1,18 @@
-use std::cmp::max;
+use std::cell::Cell;
 use std::collections::hash_map;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::{OsStr, OsString};
-use std::io::{stderr, stdout, Write};
+use std::fmt;
+use std::fs::File;
+use std::io::{stderr, stdout, ErrorKind, Read, Write};
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output};
+use std::process::{Command, ExitStatus, Output};
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use ipc::Semaphore;
+use log::{debug, warn};
 use os_str_bytes::OsStrBytes;
 use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
@@ -18,20 +20,127 @@ use sha2::{Digest, Sha256};
 use tempfile::{NamedTempFile, TempDir};
 use thiserror::Error;
 
-use crate::cache::{Cache, FileHasher};
+use crate::annotations::Annotator;
+use crate::bigobj::BigobjRetry;
+use crate::cache::Cache;
 use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
-use crate::config::Config;
+use crate::config::{Config, DiagnosticStyle, ToolRemap};
+use crate::console::ConsoleOutput;
+use crate::determinism::PreprocessCheck;
+use crate::diagnostics::{
+    create_sink, format_diagnostic, DiagnosticSink, Location, Severity, TaskOutcome,
+    CODE_EXIT_STATUS,
+};
+use crate::etw::EtwTracer;
+use crate::hook::PostCompileHook;
+use crate::includes::{
+    EmptyUnitScanner, IncludeCaseTracker, LineDirectiveScanner, MissingHeaderTracker,
+};
 use crate::io::memstream::MemStream;
-use crate::io::statistic::Statistic;
+use crate::io::statcache::{FileHasher, StatCache};
+use crate::io::statistic::{Statistic, Timeline};
+use crate::io::taskoutput::{OutputLimits, TaskOutput};
+use crate::io::temproots::{TempLease, TempRoots};
+use crate::manifest::BuildManifest;
+use crate::outputs::OutputChecker;
+use crate::overrides::DirOverrides;
+use crate::pchreuse::PchReuse;
+use crate::phases::PhasePrograms;
+use crate::prewarm::PrewarmStatistic;
+use crate::provenance::{EntryInfo, Provenance, ProvenanceStore};
+use crate::schedule::Schedule;
+use crate::shadow::ShadowRunner;
+use crate::shim::ToolchainPath;
+use crate::slots::{with_background_priority, SlotArbiter};
+use crate::transform::TransformRules;
 use crate::utils::OsStrExt;
+use crate::vs::codepage::output_codepage;
+use crate::vs::coff;
+use crate::warmup::ToolchainWarmup;
+use crate::watchdog::{TaskMonitor, TaskPhase};
+use crate::worker::UncachedTasks;
 
+// Reason why compiler command can't be split into cached compilation tasks.
 #[derive(Error, Debug)]
-pub enum CompilerError {
-    #[error("can't parse command line arguments: {0}")]
-    InvalidArguments(String),
-    #[error("can't find toolchain for: {0}")]
-    ToolchainNotFound(PathBuf),
+pub enum TaskError {
+    #[error("Found unknown command line arguments: {flags:?}")]
+    UnsupportedFlags { flags: Vec<String> },
+    #[error(
+        "cl : Command line error D8016 : '/{a}' and '/{b}' command-line options are incompatible"
+    )]
+    ConflictingOptions { a: String, b: String },
+    #[error("Found too many {option}: {values}")]
+    DuplicateOption {
+        option: &'static str,
+        values: String,
+    },
+    #[error("Can't find source file path.")]
+    MissingInput,
+    #[error("Input file path does not contain file name: {}", .path.display())]
+    InvalidInput { path: PathBuf },
+    #[error("Cannot specify -o when generating multiple output files")]
+    MultipleInputsUnsupported,
+    #[error("cl : Command line error D8036 : '/Fo{}' not allowed with multiple source files", .path.display())]
+    OutputFileForMultipleInputs { path: PathBuf },
+    #[error("cl : Command line error : multiple source files are compiled to the same object file '{}'", .path.display())]
+    DuplicateOutput { path: PathBuf },
+    #[error("Found link arguments with /c option.")]
+    LinkWithoutLinking,
+    #[error("Can't detect file language by extension: {}", .path.display())]
+    UnknownLanguage { path: PathBuf },
+    #[error("Unknown source language type: {language}")]
+    UnsupportedLanguage { language: String },
+    #[error("cl : Command line error : '/std:{standard}' is not applicable to {language} source file '{}'", .path.display())]
+    StandardMismatch {
+        standard: String,
+        language: String,
+        path: PathBuf,
+    },
+    #[error("cl : Command line error : precompiled header '{}' is created with '/T{producer_language}' from '{}' and can't be used with '/T{language}' by '{}'", .pch.display(), .producer.display(), .path.display())]
+    PchLanguageMismatch {
+        pch: PathBuf,
+        producer: PathBuf,
+        producer_language: String,
+        path: PathBuf,
+        language: String,
+    },
+    #[error("Can't read response file {}: {cause}", .path.display())]
+    ResponseFileError {
+        path: PathBuf,
+        cause: Box<crate::Error>,
+    },
+    #[error("Toolchain not found: {}", .path.display())]
+    ToolchainUnavailable { path: PathBuf },
+    #[error(
+        "Debug information of '/{option}' goes to shared PDB file, see OCTOBUILD_EMBED_DEBUG_INFO"
+    )]
+    SharedPdb { option: String },
+}
+
+impl TaskError {
+    // Reason name for summary of tasks executed without cache.
+    #[must_use]
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TaskError::UnsupportedFlags { .. } => "unsupported flags",
+            TaskError::ConflictingOptions { .. } => "conflicting options",
+            TaskError::DuplicateOption { .. } => "duplicate options",
+            TaskError::MissingInput => "missing input",
+            TaskError::InvalidInput { .. } => "invalid input",
+            TaskError::MultipleInputsUnsupported
+            | TaskError::OutputFileForMultipleInputs { .. }
+            | TaskError::DuplicateOutput { .. } => "multiple inputs",
+            TaskError::LinkWithoutLinking => "link arguments",
+            TaskError::UnknownLanguage { .. } => "unknown language",
+            TaskError::UnsupportedLanguage { .. } => "unsupported language",
+            TaskError::StandardMismatch { .. } => "standard mismatch",
+            TaskError::PchLanguageMismatch { .. } => "precompiled header language",
+            TaskError::ResponseFileError { .. } => "response file",
+            TaskError::ToolchainUnavailable { .. } => "toolchain unavailable",
+            TaskError::SharedPdb { .. } => "shared PDB",
+        }
+    }
 }
 
 // Scope of command line argument.
@@ -72,12 +181,15 @@ pub enum InputKind {
     Source,
     Marker,
     Precompiled,
+    // Object file or library passed to linker.
+    Link,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum OutputKind {
     Object,
     Marker,
+    Executable,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -177,49 +289,172 @@ pub struct CommandInfo {
     pub program: PathBuf,
     pub current_dir: Option<PathBuf>,
     pub env: Arc<CommandEnv>,
+    // Original program path if it was replaced by tool remap rule.
+    pub remapped_from: Option<PathBuf>,
+    // Build configuration label set by frontend, like Debug or Release.
+    pub config: Option<String>,
 }
 
 pub struct SharedState {
-    pub semaphore: Semaphore,
+    pub slots: SlotArbiter,
     pub cache: Cache,
+    // File identity of include files and precompiled headers within the build.
+    pub files: StatCache,
     pub statistic: Statistic,
-    pub temp_dir: TempDir,
+    pub timeline: Timeline,
+    pub includes: IncludeCaseTracker,
+    pub missing_headers: MissingHeaderTracker,
+    pub outputs: OutputChecker,
+    pub output_limits: OutputLimits,
+    pub diagnostics: Box<dyn DiagnosticSink>,
+    pub provenance: ProvenanceStore,
+    pub monitor: TaskMonitor,
+    pub transforms: TransformRules,
+    pub phases: PhasePrograms,
+    pub post_compile: PostCompileHook,
+    pub bigobj: BigobjRetry,
+    pub annotations: Annotator,
+    pub console: ConsoleOutput,
+    pub schedule: Schedule,
+    pub etw: EtwTracer,
+    // Set for preprocess-only run that refreshes cache entries instead of compiling.
+    pub prewarm: Option<PrewarmStatistic>,
+    // Set when native compiler builds the tree and octobuild only runs alongside for comparison.
+    pub shadow: Option<ShadowRunner>,
+    // Set when frontend writes build manifest, tasks recorded in it can be skipped as up-to-date.
+    pub manifest: Option<BuildManifest>,
+    // Compiler commands that can't be cached, by reason.
+    pub uncached: UncachedTasks,
+    // Background probes of toolchains used by the build.
+    pub warmup: ToolchainWarmup,
+    // Settings of source subtrees from octobuild.toml files.
+    pub overrides: DirOverrides,
+    // Process temporary directories, task files are placed near task output.
+    pub temp: TempRoots,
+    // Keep compiler output byte-exact for comparison with native build.
+    pub raw_output: bool,
+    // Compiler runs after sporadic internal compiler error before task fails.
+    pub internal_error_retries: usize,
+    // Localized prefix of cl `/showIncludes` lines, detected by probe compile if not set.
+    pub show_includes_prefix: Option<String>,
+    pub pch_reuse: PchReuse,
+    // Sampled tasks are preprocessed twice to check preprocessor determinism.
+    pub preprocess_check: PreprocessCheck,
+    // Lines removed from output of successful compilation.
+    pub suppress_warnings: Vec<regex::bytes::Regex>,
+    // Codepage of cl diagnostics converted to UTF-8, None if output is kept as is.
+    pub output_codepage: Option<u32>,
     use_response_files: bool,
 }
 
+// Prefix of octobuild temporary directories.
+const TEMP_PREFIX: &str = "octobuild";
+// Temporary directories untouched for this long are considered left by crashed process.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+// Environment variables used by compilers to locate directory for internal temporary files.
+const TEMP_ENV_VARS: [&str; 3] = ["TMP", "TEMP", "TMPDIR"];
+
 #[derive(Default)]
 pub struct CompilerGroup(Vec<Box<dyn Compiler>>);
 
 impl SharedState {
     pub fn new(config: &Config) -> std::io::Result<Self> {
-        let semaphore = Semaphore::new("octobuild-worker", max(config.process_limit, 1_usize))?;
         Ok(SharedState {
-            semaphore,
+            slots: SlotArbiter::new(config),
             cache: Cache::new(config),
+            files: StatCache::default().with_seed(
+                config
+                    .deterministic_schedule
+                    .then_some(config.schedule_seed),
+            ),
             statistic: Statistic::new(),
-            temp_dir: tempfile::Builder::new().prefix("octobuild").tempdir()?,
+            timeline: Timeline::new(),
+            includes: IncludeCaseTracker::new(config.include_case_check),
+            missing_headers: MissingHeaderTracker::new(config.missing_header_check),
+            outputs: OutputChecker::new(config.output_check),
+            output_limits: OutputLimits::new(config),
+            diagnostics: create_sink(config)?,
+            provenance: ProvenanceStore::new(config),
+            monitor: TaskMonitor::new(config),
+            transforms: TransformRules::new(&config.preprocess_transform).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+            })?,
+            phases: PhasePrograms::new(&config.toolchain_phases),
+            post_compile: PostCompileHook::new(&config.post_compile_hook),
+            bigobj: BigobjRetry::new(config.bigobj_retry),
+            annotations: Annotator::new(config),
+            console: ConsoleOutput::new(config),
+            schedule: Schedule::new(config),
+            etw: EtwTracer::new(config),
+            prewarm: None,
+            shadow: config.shadow.then(|| ShadowRunner::new(config)),
+            manifest: None,
+            uncached: UncachedTasks::new(),
+            warmup: ToolchainWarmup::new(config.toolchain_warmup),
+            overrides: DirOverrides::new(config),
+            temp: TempRoots::new(&config.temp_dirs, TEMP_PREFIX, STALE_TEMP_AGE)?,
+            raw_output: config.raw_output,
+            internal_error_retries: config.internal_error_retries,
+            show_includes_prefix: config.show_includes_prefix.clone(),
+            pch_reuse: PchReuse::new(config),
+            preprocess_check: PreprocessCheck::new(config.verify_preprocess),
+            suppress_warnings: SharedState::suppress_patterns(&config.suppress_warnings)?,
+            output_codepage: output_codepage(config.output_codepage),
             use_response_files: config.use_response_files,
         })
     }
 
     pub fn wrap_slow<T, F: FnOnce() -> T>(&self, func: F) -> T {
-        let guard = self.semaphore.access();
+        let guard = self.slots.acquire();
         let result = func();
         drop(guard);
         result
     }
 
+    pub fn suppress_patterns(patterns: &[String]) -> std::io::Result<Vec<regex::bytes::Regex>> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                regex::bytes::Regex::new(pattern).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid warning suppression pattern {pattern:?}: {e}"),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // Point child temporary files to a separate directory, so concurrent tasks don't collide.
+    // Directory is removed when returned value is dropped, so keep it until child exits.
+    pub fn task_temp_dir(
+        &self,
+        command: &mut Command,
+        temp: &TempLease,
+    ) -> std::io::Result<TempDir> {
+        let dir = tempfile::Builder::new()
+            .prefix("task")
+            .tempdir_in(temp.path())?;
+        for name in TEMP_ENV_VARS {
+            command.env(name, dir.path());
+        }
+        Ok(dir)
+    }
+
     pub fn do_response_file(
         &self,
         args: OsCommandArgs,
         command: &mut Command,
+        temp: &TempLease,
     ) -> crate::Result<Option<NamedTempFile>> {
         if self.use_response_files {
             let response_file = tempfile::Builder::new()
                 .suffix(".rsp")
-                .tempfile_in(self.temp_dir.path())?;
+                .tempfile_in(temp.path())?;
             let contents = args.join()?;
-            std::fs::write(response_file.path(), contents.to_raw_bytes())?;
+            let contents = contents.to_raw_bytes();
+            std::fs::write(response_file.path(), &contents)?;
+            temp.add_written(contents.len() as u64);
             command.arg(OsString::from("@").concat(response_file.path().as_os_str()));
             Ok(Some(response_file))
         } else {
@@ -278,7 +513,27 @@ impl CommandInfo {
             program: path,
             current_dir: env::current_dir().ok(),
             env: Arc::new(env::vars().collect()),
+            remapped_from: None,
+            config: None,
+        }
+    }
+
+    // Override configuration label with explicitly specified one.
+    #[must_use]
+    pub fn with_config(mut self, label: Option<&str>) -> Self {
+        if let Some(label) = label {
+            self.config = Some(label.to_string());
+        }
+        self
+    }
+
+    // Apply first matching tool remap rule to program path.
+    #[must_use]
+    pub fn remap(mut self, rules: &[ToolRemap]) -> Self {
+        if let Some(program) = remap_program(rules, &self.program) {
+            self.remapped_from = Some(std::mem::replace(&mut self.program, program));
         }
+        self
     }
 
     pub fn absolutize(&self, path: &Path) -> crate::Result<PathBuf> {
@@ -291,7 +546,12 @@ impl CommandInfo {
 
     #[must_use]
     pub fn to_command(&self) -> Command {
-        let mut command = Command::new(&self.program);
+        self.command_for(&self.program)
+    }
+
+    // Command with environment and directory of this one, but other program.
+    pub fn command_for(&self, program: &Path) -> Command {
+        let mut command = Command::new(program);
         command.env_clear();
         for (key, value) in self.env.iter() {
             command.env(key.clone(), value.clone());
@@ -347,37 +607,163 @@ impl CommandInfo {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// How process has finished.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TaskStatus {
+    Code(i32),
+    // Process was terminated by unhandled exception (NTSTATUS) or by signal.
+    Crashed(u32),
+    Unknown,
+}
+
+impl TaskStatus {
+    // Exit code as reported by process, crash on Windows is reported with NTSTATUS.
+    #[must_use]
+    #[allow(clippy::cast_possible_wrap)]
+    pub fn code(self) -> Option<i32> {
+        match self {
+            TaskStatus::Code(code) => Some(code),
+            TaskStatus::Crashed(code) if cfg!(windows) => Some(code as i32),
+            TaskStatus::Crashed(_) | TaskStatus::Unknown => None,
+        }
+    }
+
+    #[must_use]
+    pub fn success(self) -> bool {
+        self == TaskStatus::Code(0)
+    }
+
+    #[must_use]
+    pub fn is_crashed(self) -> bool {
+        matches!(self, TaskStatus::Crashed(_))
+    }
+
+    // Process was stopped by Ctrl+C or termination request, not by its own fault.
+    #[must_use]
+    pub fn is_interrupted(self) -> bool {
+        #[cfg(unix)]
+        #[allow(clippy::cast_sign_loss)]
+        const CODES: [u32; 3] = [
+            libc::SIGINT as u32,
+            libc::SIGTERM as u32,
+            libc::SIGHUP as u32,
+        ];
+        // STATUS_CONTROL_C_EXIT
+        #[cfg(not(unix))]
+        const CODES: [u32; 1] = [0xC000_013A];
+
+        matches!(self, TaskStatus::Crashed(code) if CODES.contains(&code))
+    }
+
+    // Append crash description to process stderr, compiler usually has no chance to report it.
+    pub fn report_crash(self, stderr: &mut Vec<u8>) {
+        if let TaskStatus::Crashed(_) = self {
+            stderr
+                .extend_from_slice(format!("octobuild: compiler crashed with {self}\n").as_bytes());
+        }
+    }
+}
+
+impl From<ExitStatus> for TaskStatus {
+    #[cfg(unix)]
+    fn from(status: ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+
+        match (status.code(), status.signal()) {
+            (Some(code), _) => TaskStatus::Code(code),
+            #[allow(clippy::cast_sign_loss)]
+            (None, Some(signal)) => TaskStatus::Crashed(signal as u32),
+            (None, None) => TaskStatus::Unknown,
+        }
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::cast_sign_loss)]
+    fn from(status: ExitStatus) -> Self {
+        match status.code() {
+            // Error severity NTSTATUS, like 0xC0000005 for access violation.
+            Some(code) if code as u32 >= 0xC000_0000 => TaskStatus::Crashed(code as u32),
+            Some(code) => TaskStatus::Code(code),
+            None => TaskStatus::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskStatus::Code(code) => write!(f, "exit code: {code}"),
+            TaskStatus::Crashed(code) if cfg!(windows) => write!(f, "0x{code:08X}"),
+            TaskStatus::Crashed(signal) => write!(f, "signal {signal}"),
+            TaskStatus::Unknown => write!(f, "exit code: unknown"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct OutputInfo {
-    pub status: Option<i32>,
-    pub stdout: Vec<u8>,
-    pub stderr: Vec<u8>,
+    pub status: TaskStatus,
+    pub stdout: TaskOutput,
+    pub stderr: TaskOutput,
 }
 
 pub struct BuildTaskResult {
     pub output: crate::Result<OutputInfo>,
+    // Output was taken from cache
+    pub cached: bool,
     pub duration: Duration,
+    pub phases: BTreeMap<TaskPhase, Duration>,
+    // Flag added to compiler arguments by retry after compiler limit failure.
+    pub retry: Option<&'static str>,
+    // Remark about task for build report.
+    pub note: Option<&'static str>,
+}
+
+// Report note of task with no tokens in preprocessed source.
+pub const EMPTY_UNIT_NOTE: &str = "empty translation unit";
+// Report note of task skipped as recorded in build manifest.
+pub const UP_TO_DATE_NOTE: &str = "up-to-date (manifest)";
+
+pub struct CompileResult {
+    pub output: OutputInfo,
+    // Output was taken from cache
+    pub cached: bool,
+    // Flag added to compiler arguments by retry after compiler limit failure.
+    pub retry: Option<&'static str>,
+    // Remark about task for build report.
+    pub note: Option<&'static str>,
 }
 
 impl BuildTaskResult {
-    pub fn print_output(&self) -> crate::Result<()> {
-        match &self.output {
-            Ok(output) => {
-                if !output.success() {
-                    writeln!(
-                        stderr(),
-                        "ERROR: Task failed with exit code: {}",
-                        output
-                            .status
-                            .map_or_else(|| "unknown".to_string(), |v| v.to_string())
-                    )?;
-                }
-                stdout().write_all(&output.stdout)?;
-                stderr().write_all(&output.stderr)?;
-            }
-            Err(e) => {
-                writeln!(stderr(), "ERROR: {e}")?;
+    // Failure message, located at task source file if there is one.
+    #[must_use]
+    pub fn error_message(&self, style: DiagnosticStyle, source: Option<&Path>) -> Option<String> {
+        let (code, message) = match &self.output {
+            Ok(output) if output.success() => return None,
+            Ok(output) => (
+                CODE_EXIT_STATUS,
+                match output.status {
+                    TaskStatus::Crashed(_) => format!("Task crashed with {}", output.status),
+                    status => format!("Task failed with {status}"),
+                },
+            ),
+            Err(e) => (e.diagnostic_code(), e.to_string()),
+        };
+        Some(match source {
+            Some(path) => {
+                format_diagnostic(style, Location::file(path), Severity::Error, code, &message)
             }
+            None => format!("ERROR: {message}"),
+        })
+    }
+
+    pub fn print_output(&self, style: DiagnosticStyle, source: Option<&Path>) -> crate::Result<()> {
+        if let Some(message) = self.error_message(style, source) {
+            writeln!(stderr(), "{message}")?;
+        }
+        if let Ok(output) = &self.output {
+            output.stdout.replay(&mut stdout())?;
+            output.stderr.replay(&mut stderr())?;
         }
         Ok(())
     }
@@ -385,17 +771,60 @@ impl BuildTaskResult {
 
 impl OutputInfo {
     #[must_use]
-    pub fn new(output: Output) -> Self {
+    pub fn new(output: Output, limits: &OutputLimits) -> Self {
+        let status = TaskStatus::from(output.status);
+        let mut stderr = output.stderr;
+        status.report_crash(&mut stderr);
         OutputInfo {
-            status: output.status.code(),
-            stdout: output.stdout,
-            stderr: output.stderr,
+            status,
+            stdout: TaskOutput::new(output.stdout, limits),
+            stderr: TaskOutput::new(stderr, limits),
+        }
+    }
+
+    // Successful result without any output.
+    #[must_use]
+    pub fn empty() -> Self {
+        OutputInfo {
+            status: TaskStatus::Code(0),
+            stdout: TaskOutput::default(),
+            stderr: TaskOutput::default(),
         }
     }
 
     #[must_use]
     pub fn success(&self) -> bool {
-        matches!(self.status, Some(e) if e == 0)
+        self.status.success()
+    }
+
+    // Put command line warnings before compiler diagnostics, like cl does.
+    pub fn with_notes(self, notes: &[String]) -> std::io::Result<Self> {
+        if notes.is_empty() {
+            return Ok(self);
+        }
+        let mut prefix = Vec::new();
+        for note in notes {
+            writeln!(prefix, "{note}")?;
+        }
+        Ok(OutputInfo {
+            stderr: self.stderr.with_prefix(&prefix)?,
+            ..self
+        })
+    }
+
+    // Put `/showIncludes` lines before compiler output, like cl does.
+    pub fn with_includes(self, includes: &[String]) -> std::io::Result<Self> {
+        if includes.is_empty() {
+            return Ok(self);
+        }
+        let mut prefix = Vec::new();
+        for line in includes {
+            writeln!(prefix, "{line}")?;
+        }
+        Ok(OutputInfo {
+            stdout: self.stdout.with_prefix(&prefix)?,
+            ..self
+        })
     }
 }
 
@@ -406,6 +835,18 @@ pub struct PCHArgs {
     pub path_abs: PathBuf,
     // Marker for precompiled header.
     pub marker: Option<OsString>,
+    // Forced include (/FI) of the marker header, preprocessed output names the header by its path.
+    pub forced_marker: Option<OsString>,
+}
+
+impl PCHArgs {
+    // Header looked for in preprocessed output.
+    pub fn filter_marker(&self) -> &Option<OsString> {
+        match self.forced_marker {
+            Some(_) => &self.forced_marker,
+            None => &self.marker,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -462,6 +903,13 @@ pub struct CompilationArgs {
     pub pch_usage: PCHUsage,
     pub deps_file: Option<PathBuf>,
     pub run_second_cpp: bool,
+    // Command line warnings added to every task output, like cl D9025 for overridden options.
+    pub notes: Vec<String>,
+    // Build configuration label for statistics and cache accounting.
+    pub config: Option<String>,
+    // Arguments of native link step for invocation without /c: object files of all tasks
+    // in place of sources and link options.
+    pub link: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug)]
@@ -476,16 +924,53 @@ pub struct CompilationTask {
     pub output_object: PathBuf,
 }
 
+// Identity of compilation task within a build. The same source can be compiled several times
+// with different defines into different objects (unity build permutations), so per-task state
+// must not be keyed by source file alone.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct TaskId {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    // Preprocessor defines and undefines in command line order.
+    pub defines: Vec<String>,
+}
+
+impl CompilationTask {
+    #[must_use]
+    pub fn id(&self) -> TaskId {
+        TaskId {
+            source: self.input_source.clone(),
+            output: self.output_object.clone(),
+            defines: self
+                .shared
+                .args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Arg::Param { name, value, .. } if name == "D" || name == "U" => {
+                        Some(format!("{name}{value}"))
+                    }
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct SourceInput {
     pub path: PathBuf,
     pub current_dir: Option<PathBuf>,
+    // Environment of original command: compiler reads include paths and extra options from it.
+    pub env: Arc<CommandEnv>,
 }
 
+#[derive(Clone)]
 pub enum CompileInput {
     Preprocessed(CompilerOutput),
     Source(SourceInput),
 }
 
+#[derive(Clone)]
 pub struct CompileStep {
     // Compiler arguments.
     pub args: Vec<OsString>,
@@ -494,6 +979,8 @@ pub struct CompileStep {
     pub pch_usage: PCHUsage,
     pub input: CompileInput,
     pub run_second_cpp: bool,
+    // Original source file for diagnostics about temporary compiler input (None on remote builder).
+    pub source: Option<PathBuf>,
 }
 
 impl CompileStep {
@@ -507,15 +994,18 @@ impl CompileStep {
                 Source(SourceInput {
                     path: task.input_source.clone(),
                     current_dir: task.shared.command.current_dir.clone(),
+                    env: task.shared.command.env.clone(),
                 })
             } else {
                 Preprocessed(preprocessed)
             },
             run_second_cpp: task.shared.run_second_cpp,
+            source: Some(task.input_source.clone()),
         }
     }
 }
 
+#[derive(Clone)]
 pub enum CompilerOutput {
     MemSteam(MemStream),
     Vec(Vec<u8>),
@@ -532,6 +1022,17 @@ impl CompilerOutput {
         }
     }
 
+    // Write output to a new file with a single pass over content.
+    pub fn write_to_file(&self, path: &Path) -> crate::Result<usize> {
+        match &self {
+            CompilerOutput::MemSteam(v) => v.write_to_file(path),
+            CompilerOutput::Vec(v) => {
+                std::fs::write(path, v).map_err(|e| crate::Error::from(e).for_write(path, 0))?;
+                Ok(v.len())
+            }
+        }
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         match self {
@@ -548,6 +1049,13 @@ impl CompilerOutput {
         }
     }
 
+    // Preprocessed stream has no tokens, so compiler emits object without code.
+    pub fn is_empty_unit(&self) -> std::io::Result<bool> {
+        let mut scanner = EmptyUnitScanner::default();
+        self.copy(&mut scanner)?;
+        Ok(scanner.is_empty())
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
         match self {
             CompilerOutput::MemSteam(v) => From::from(v),
@@ -557,7 +1065,8 @@ impl CompilerOutput {
 }
 
 pub enum PreprocessResult {
-    Success(CompilerOutput),
+    // Preprocessed source and `/showIncludes` lines to replay on compilation stdout.
+    Success(CompilerOutput, Vec<String>),
     Failed(OutputInfo),
 }
 
@@ -565,6 +1074,22 @@ pub trait Toolchain: Send + Sync {
     // Get toolchain identificator.
     fn identifier(&self) -> Option<String>;
 
+    // Get toolchain identificator for task cache key. Task options can depend on toolchain files
+    // beyond compiler executable, like runtime libraries.
+    fn task_identifier(&self, _task: &CompilationTask) -> Option<String> {
+        self.identifier()
+    }
+
+    // IMAGE_FILE_MACHINE_* of object files produced by toolchain, if known.
+    fn target_machine(&self) -> Option<u16> {
+        None
+    }
+
+    // Real compiler executable, used to match per-toolchain configuration.
+    fn executable(&self) -> Option<&Path> {
+        None
+    }
+
     // Parse compiler arguments.
     fn create_tasks(
         &self,
@@ -587,23 +1112,137 @@ pub trait Toolchain: Send + Sync {
     // Compile preprocessed file.
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo>;
 
+    // Check if compile argument is harmless locally, but must not be sent to remote builder.
+    fn strip_on_remote(&self, _arg: &OsStr) -> bool {
+        false
+    }
+
+    // Flag lifting object file section limit, None if compiler has no such flag
+    // or arguments already have it.
+    fn bigobj_flag(&self, _args: &[OsString]) -> Option<&'static str> {
+        None
+    }
+
+    // Check if compilation failed because object file section limit is exceeded.
+    fn is_section_overflow(&self, _output: &OutputInfo) -> bool {
+        false
+    }
+
+    // Directories searched for include files besides directories of including files, None if
+    // compiler also has implicit ones.
+    fn include_dirs(&self, _task: &CompilationTask) -> Option<Vec<PathBuf>> {
+        None
+    }
+
+    // Arguments compiling source into object file for toolchain warm-up, None if toolchain
+    // has no such probe.
+    fn warmup_args(&self, _source: &Path, _object: &Path) -> Option<Vec<OsString>> {
+        None
+    }
+
     fn compile_task(
         &self,
         state: &SharedState,
         task: &CompilationTask,
-    ) -> crate::Result<OutputInfo> {
+    ) -> crate::Result<CompileResult> {
+        let manifest_key = state
+            .manifest
+            .as_ref()
+            .map(|_| BuildManifest::task_key(task, self.task_identifier(task).as_deref()));
+        if let (Some(manifest), Some(key)) = (&state.manifest, &manifest_key) {
+            if manifest.check(task, key, &state.files) {
+                return Ok(CompileResult {
+                    output: OutputInfo::empty(),
+                    cached: false,
+                    retry: None,
+                    note: Some(UP_TO_DATE_NOTE),
+                });
+            }
+        }
+        if let Some(output) = state.missing_headers.replay(task) {
+            return Ok(CompileResult {
+                output,
+                cached: false,
+                retry: None,
+                note: None,
+            });
+        }
+        state.monitor.phase(TaskPhase::Preprocessing);
         let preprocessed = self.run_preprocess(state, task)?;
+        // Reused preprocessed output has its own check.
+        let reused =
+            state.pch_reuse.is_enabled() && matches!(task.shared.pch_usage, PCHUsage::In(_));
+        if let (PreprocessResult::Success(first, _), false) = (&preprocessed, reused) {
+            if state
+                .preprocess_check
+                .want_check(&state.schedule, &task.input_source)
+            {
+                // Runs are sequential, so the second one regenerates `#import` files in the same
+                // /Fo directory only after the first one has read them.
+                let second = with_background_priority(|| self.run_preprocess(state, task))?;
+                if let PreprocessResult::Success(second, _) = second {
+                    state.preprocess_check.compare(
+                        &task.input_source,
+                        &first.to_vec(),
+                        &second.to_vec(),
+                    );
+                }
+            }
+        }
         match preprocessed {
-            PreprocessResult::Success(preprocessed) => {
-                self.run_compile_cached(state, task, preprocessed)
+            PreprocessResult::Success(preprocessed, includes) => {
+                // Include case check and manifest share one scan of preprocessed file.
+                let inputs = if state.includes.is_enabled() || manifest_key.is_some() {
+                    let mut scanner = LineDirectiveScanner::new();
+                    preprocessed.copy(&mut scanner)?;
+                    Some(scanner.into_paths())
+                } else {
+                    None
+                };
+                if let Some(paths) = &inputs {
+                    state.includes.check(
+                        &task.input_source,
+                        task.shared.command.current_dir.as_deref(),
+                        paths,
+                    )?;
+                }
+                // Source under `#if 0` is compiled and cached as usual, compiler arguments
+                // still make its cache key unique.
+                let empty = preprocessed.is_empty_unit()?;
+                let mut result = self.run_compile_cached(state, task, preprocessed)?;
+                if empty {
+                    result.note = Some(EMPTY_UNIT_NOTE);
+                }
+                if let (Some(manifest), Some(key), Some(inputs)) =
+                    (&state.manifest, &manifest_key, inputs)
+                {
+                    if result.output.success() {
+                        manifest.record(task, key, &state.files, inputs);
+                    }
+                }
+                // Compiler runs on preprocessed file or not at all on cache hit, so include list
+                // comes from preprocessor.
+                result.output = result.output.with_includes(&includes)?;
+                Ok(result)
+            }
+            PreprocessResult::Failed(output) => {
+                let output = OutputInfo {
+                    status: output.status,
+                    // Preprocessor stdout contains the whole preprocessed file.
+                    // We don't want to print all of that to the user.
+                    stdout: TaskOutput::default(),
+                    stderr: output.stderr,
+                };
+                state
+                    .missing_headers
+                    .remember(task, &output, || self.include_dirs(task));
+                Ok(CompileResult {
+                    output,
+                    cached: false,
+                    retry: None,
+                    note: None,
+                })
             }
-            PreprocessResult::Failed(output) => Ok(OutputInfo {
-                status: output.status,
-                // Preprocessor stdout contains the whole preprocessed file.
-                // We don't want to print all of that to the user.
-                stdout: Vec::new(),
-                stderr: output.stderr,
-            }),
         }
     }
 
@@ -612,28 +1251,70 @@ pub trait Toolchain: Send + Sync {
         state: &SharedState,
         task: &CompilationTask,
         preprocessed: CompilerOutput,
-    ) -> crate::Result<OutputInfo> {
+    ) -> crate::Result<CompileResult> {
+        let overrides = state.overrides.lookup(&task.input_source)?;
         let mut hasher = Sha256::new();
         // Get hash from preprocessed data
         hasher.hash_u64(preprocessed.len() as u64);
         preprocessed.copy(&mut hasher)?;
 
-        if let Some(identifier) = self.identifier() {
-            hasher.hash_str(&identifier);
+        let identifier = self.task_identifier(task);
+        if let Some(identifier) = &identifier {
+            hasher.hash_str(identifier);
+        }
+        // Separate preprocessor and compiler programs are a part of toolchain identity.
+        if let Some(path) = self.executable() {
+            if let Some(digest) = state.phases.digest(path, &state.files)? {
+                hasher.hash_str(&digest);
+            }
+        }
+        // Unfiltered output must not be replayed into normal build.
+        if state.raw_output || overrides.native_diagnostics {
+            hasher.hash_str("raw-output");
+        }
+        if let Some(salt) = &overrides.key_salt {
+            hasher.hash_str(salt);
+        }
+        // Cached objects are post-processed by hook.
+        if let Some(digest) = state.post_compile.digest() {
+            hasher.hash_str(&digest);
         }
 
-        let step = self.create_compile_step(task, preprocessed)?;
+        let mut step = self.create_compile_step(task, preprocessed)?;
+        // Transform compiler input after hashing, rule set digest goes to the key instead.
+        if let Preprocessed(preprocessed) = &mut step.input {
+            if let Some(digest) = state.transforms.digest(&task.input_source) {
+                hasher.hash_str(&digest);
+                let data = std::mem::replace(preprocessed, CompilerOutput::Vec(Vec::new()));
+                *preprocessed = state.transforms.apply(&task.input_source, data);
+            }
+        }
 
-        // Hash arguments
-        hasher.hash_u64(step.args.len() as u64);
-        for arg in &step.args {
+        // Hash arguments, except ones ignored for the source subtree.
+        let hashed_args: Vec<&OsString> = step
+            .args
+            .iter()
+            .filter(|arg| !overrides.is_ignored(&arg.to_string_lossy()))
+            .collect();
+        hasher.hash_u64(hashed_args.len() as u64);
+        for arg in hashed_args {
             hasher.hash_os_string(arg)
         }
         // Hash input files
         match &step.pch_usage.get_in_abs() {
             Some(path) => {
                 assert!(path.is_absolute());
-                hasher.hash_str(&state.cache.file_hash(path)?.hash);
+                if let (Some(prewarm), false) = (&state.prewarm, path.exists()) {
+                    // Precompiled header is not built during prewarm.
+                    prewarm.inc_skipped();
+                    return Ok(CompileResult {
+                        output: OutputInfo::empty(),
+                        cached: false,
+                        retry: None,
+                        note: None,
+                    });
+                }
+                hasher.hash_str(&state.files.file_hash(path)?.hash);
             }
             None => {
                 hasher.hash_u64(0);
@@ -654,13 +1335,203 @@ pub trait Toolchain: Send + Sync {
         }
 
         // Try to get files from cache or run
+        let retry_flag = if state.bigobj.is_enabled() {
+            self.bigobj_flag(&step.args)
+        } else {
+            None
+        };
+        // Result of compilation retried with added flag is cached under its own key.
+        let retry_key = retry_flag.map(|flag| {
+            let mut hasher = hasher.clone();
+            hasher.hash_str(flag);
+            hex::encode(hasher.finalize())
+        });
+        let mut key = hex::encode(hasher.finalize());
+        if let Some(prewarm) = &state.prewarm {
+            let refreshed = state.cache.touch(&key)?
+                || retry_key
+                    .as_ref()
+                    .map_or(Ok(false), |key| state.cache.touch(key))?;
+            prewarm.add(refreshed);
+            return Ok(CompileResult {
+                output: OutputInfo::empty(),
+                cached: refreshed,
+                retry: None,
+                note: None,
+            });
+        }
+        let info = EntryInfo {
+            toolchain: identifier,
+            source: task.input_source.clone(),
+            outputs,
+            version: crate::version::full(),
+            produced: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|time| time.as_secs()),
+        };
+        state.outputs.declare(&info.outputs);
+        // Dependency file is written by preprocessor.
+        if let Some(path) = &task.shared.deps_file {
+            state.outputs.declare(std::slice::from_ref(path));
+        }
+        let mut retry = None;
+        // Source that needed the flag in previous builds doesn't have to fail again.
+        if let (Some(flag), Some(retry_key)) = (retry_flag, &retry_key) {
+            if state.cache.touch(retry_key)? {
+                step.args.push(OsString::from(flag));
+                key = retry_key.clone();
+                retry = Some(flag);
+            }
+        }
+        // Compiler run consumes compile step, so keep a copy for retry.
+        let retry_step = match (retry_flag, retry) {
+            (Some(_), None) => Some(step.clone()),
+            _ => None,
+        };
+        let (mut output, mut cached) = compile_cached(self, state, task, &key, &info, step)?;
+        if let (Some(flag), Some(mut step), Some(retry_key)) = (retry_flag, retry_step, retry_key) {
+            // Retry only once: the flag is already in arguments of the second run.
+            if !output.success() && self.is_section_overflow(&output) {
+                step.args.push(OsString::from(flag));
+                key = retry_key;
+                retry = Some(flag);
+                (output, cached) = compile_cached(self, state, task, &key, &info, step)?;
+            }
+        }
+        if output.success() {
+            if retry.is_some() {
+                state.bigobj.remember(&task.input_source);
+            }
+            state.provenance.record(
+                &info.outputs,
+                &Provenance::new(&key, &info, TaskOutcome::new(&output, cached)),
+            );
+        }
+        Ok(CompileResult {
+            output,
+            cached,
+            retry,
+            note: None,
+        })
+    }
+}
+
+// Take compilation result from cache, or run compiler and store its result to cache.
+// Returns compilation output and whether it was taken from cache.
+fn compile_cached<T: Toolchain + ?Sized>(
+    toolchain: &T,
+    state: &SharedState,
+    task: &CompilationTask,
+    key: &str,
+    info: &EntryInfo,
+    step: CompileStep,
+) -> crate::Result<(OutputInfo, bool)> {
+    let compiled = Cell::new(false);
+    state.monitor.phase(TaskPhase::Cache);
+    let compile = || -> crate::Result<OutputInfo> {
+        compiled.set(true);
+        state.monitor.phase(TaskPhase::Compiling);
+        let snapshot = state.outputs.snapshot(
+            &task.input_source,
+            task.shared.command.current_dir.as_deref(),
+            &info.outputs,
+        );
+        let output = toolchain.run_compile(state, step);
+        state.monitor.phase(TaskPhase::Cache);
+        let output = output?;
+        if let (Some(snapshot), true) = (snapshot, output.success()) {
+            state
+                .outputs
+                .verify(&task.input_source, &snapshot, &info.outputs)?;
+        }
+        let output = state.post_compile.run(state, task, key, output)?;
+        state.monitor.phase(TaskPhase::Cache);
+        // Object file of other architecture is not stored to cache.
+        if output.success() {
+            check_target_machine(toolchain, state, task, key, false)?;
+        }
+        Ok(output)
+    };
+    let output = if state.overrides.get(&task.input_source).no_cache
+        || state.preprocess_check.is_divergent(&task.input_source)
+    {
+        compile()
+    } else {
         state.cache.run_file_cached(
             &state.statistic,
-            &hex::encode(hasher.finalize()),
-            outputs,
-            || -> crate::Result<OutputInfo> { self.run_compile(state, step) },
+            task.shared.config.as_deref(),
+            key,
+            &serde_json::to_vec(info)?,
+            info.outputs.clone(),
+            compile,
         )
+    };
+    // Outputs can be read by later tasks, like precompiled header.
+    state.files.invalidate(&info.outputs);
+    if let Some(path) = &task.shared.deps_file {
+        state.files.invalidate(std::slice::from_ref(path));
+    }
+    let output = output?;
+    if output.success() && !compiled.get() {
+        check_target_machine(toolchain, state, task, key, true)?;
     }
+    Ok((output, !compiled.get()))
+}
+
+// Object file of other architecture would fail only at link time, far from the task that
+// produced it. Such cache entry is removed, so the next build compiles the task again.
+// Object file with unreadable header is left to linker.
+fn check_target_machine<T: Toolchain + ?Sized>(
+    toolchain: &T,
+    state: &SharedState,
+    task: &CompilationTask,
+    key: &str,
+    cached: bool,
+) -> crate::Result<()> {
+    let Some(expected) = toolchain.target_machine() else {
+        return Ok(());
+    };
+    let mut header = Vec::with_capacity(coff::MACHINE_HEADER_SIZE);
+    match File::open(&task.output_object) {
+        Ok(file) => file
+            .take(coff::MACHINE_HEADER_SIZE as u64)
+            .read_to_end(&mut header)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let actual = match coff::object_machine(&header) {
+        Ok(Some(actual)) => actual,
+        Ok(None) => {
+            debug!(
+                "Object file {} has no target machine (LTCG), architecture is not checked",
+                task.output_object.display()
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            warn!(
+                "Can't read target machine of object file {}: {e}",
+                task.output_object.display()
+            );
+            return Ok(());
+        }
+    };
+    if coff::is_machine_compatible(expected, actual) {
+        return Ok(());
+    }
+    let origin = if cached {
+        state.cache.remove(key)?;
+        "restored from cache, entry is removed"
+    } else {
+        "compiled, check which compiler the task is routed to"
+    };
+    Err(crate::Error::MachineMismatch {
+        path: task.output_object.clone(),
+        expected: coff::machine_name(expected),
+        actual: coff::machine_name(actual),
+        origin,
+    })
 }
 
 impl CompilerGroup {
@@ -673,6 +1544,12 @@ impl CompilerGroup {
         self.0.push(Box::<C>::default());
         self
     }
+
+    // Add compiler that depends on configuration.
+    pub fn with<C: 'static + Compiler>(mut self, compiler: C) -> Self {
+        self.0.push(Box::new(compiler));
+        self
+    }
 }
 
 impl Compiler for CompilerGroup {
@@ -689,7 +1566,7 @@ impl Compiler for CompilerGroup {
     }
 }
 
-trait Hasher: Digest {
+pub(crate) trait Hasher: Digest {
     fn hash_u64(&mut self, number: u64) {
         let mut n = number;
         let mut buf: [u8; 8] = [0; 8];
@@ -780,7 +1657,12 @@ impl OsCommandArgs {
                 }
                 #[cfg(not(windows))]
                 {
-                    command.args(cmd::native::parse(v.to_str().unwrap())?);
+                    let v = v.to_str().ok_or_else(|| {
+                        crate::Error::Generic(format!(
+                            "Unable to parse non-UTF-8 commandline: {v:?}"
+                        ))
+                    })?;
+                    command.args(cmd::native::parse(v)?);
                 }
             }
         }
@@ -800,9 +1682,11 @@ pub trait Compiler: Send + Sync {
         args: CommandArgs,
         run_second_cpp: bool,
     ) -> crate::Result<Vec<ToolchainCompilationTask>> {
-        let toolchain = self
-            .resolve_toolchain(&command)
-            .ok_or_else(|| crate::Error::ToolchainNotFound(command.program.clone()))?;
+        let toolchain =
+            self.resolve_toolchain(&command)
+                .ok_or_else(|| TaskError::ToolchainUnavailable {
+                    path: command.program.clone(),
+                })?;
 
         let argv = match args {
             CommandArgs::Raw(v) => cmd::native::parse(&v)?,
@@ -821,9 +1705,12 @@ pub trait Compiler: Send + Sync {
     }
 }
 
+// Toolchain is recreated when real compiler binary changes.
+type ToolchainEntry = (Option<(u64, SystemTime)>, Arc<dyn Toolchain>);
+
 #[derive(Default)]
 pub struct ToolchainHolder {
-    toolchains: Arc<RwLock<HashMap<PathBuf, Arc<dyn Toolchain>>>>,
+    toolchains: Arc<RwLock<HashMap<PathBuf, ToolchainEntry>>>,
 }
 
 impl ToolchainHolder {
@@ -834,39 +1721,93 @@ impl ToolchainHolder {
         }
     }
 
-    pub fn resolve<F: FnOnce(PathBuf) -> Arc<dyn Toolchain>>(
+    pub fn resolve<F: FnOnce(ToolchainPath) -> Arc<dyn Toolchain>>(
         &self,
         path: &Path,
         factory: F,
     ) -> Option<Arc<dyn Toolchain>> {
+        let path = ToolchainPath::resolve(path.to_path_buf());
+        let stamp = path.stamp();
         {
             let read_lock = self.toolchains.read().unwrap();
-            if let Some(t) = read_lock.get(path) {
-                return Some(t.clone());
+            if let Some((s, t)) = read_lock.get(&path.spawn) {
+                if *s == stamp {
+                    return Some(t.clone());
+                }
             }
         }
         {
             let mut write_lock = self.toolchains.write().unwrap();
-            Some(
-                write_lock
-                    .entry(path.to_path_buf())
-                    .or_insert_with(|| factory(path.to_path_buf()))
-                    .clone(),
-            )
+            match write_lock.get(&path.spawn) {
+                Some((s, t)) if *s == stamp => Some(t.clone()),
+                _ => {
+                    let toolchain = factory(path.clone());
+                    write_lock.insert(path.spawn, (stamp, toolchain.clone()));
+                    Some(toolchain)
+                }
+            }
         }
     }
 }
 
+// Path is normalized char by char, so prefix of normalized path is normalized prefix of path.
+fn normalize_tool_path(path: &str) -> String {
+    path.chars().map(normalize_tool_char).collect()
+}
+
+fn normalize_tool_char(c: char) -> String {
+    match c {
+        '\\' => "/".to_string(),
+        c if cfg!(windows) => c.to_lowercase().collect(),
+        c => c.to_string(),
+    }
+}
+
+// Byte length of `path` part that normalizes to `prefix`. Lowercase of a char can be
+// several chars long, so both strings are walked together.
+fn normalized_prefix_len(path: &str, prefix: &str) -> Option<usize> {
+    let mut rest = prefix;
+    for (offset, c) in path.char_indices() {
+        if rest.is_empty() {
+            return Some(offset);
+        }
+        rest = rest.strip_prefix(normalize_tool_char(c).as_str())?;
+    }
+    rest.is_empty().then_some(path.len())
+}
+
+#[must_use]
+pub fn remap_program(rules: &[ToolRemap], program: &Path) -> Option<PathBuf> {
+    let program = program.to_str()?;
+    let normalized = normalize_tool_path(program);
+    rules.iter().find_map(|rule| {
+        let from = normalize_tool_path(&rule.from);
+        if from.ends_with('/') {
+            let prefix = normalized_prefix_len(program, &from)?;
+            Some(Path::new(&rule.to).join(&program[prefix..]))
+        } else if normalized == from {
+            Some(PathBuf::from(&rule.to))
+        } else {
+            None
+        }
+    })
+}
+
+// Keep found path as is: compiler is spawned through it, even if it is a symlink or shim.
 fn fn_find_exec(path: PathBuf) -> Option<PathBuf> {
-    fn_find_exec_native(path)?.canonicalize().ok()
+    let path = fn_find_exec_native(path)?;
+    Some(path.absolutize().ok()?.into_owned())
 }
 
 #[cfg(windows)]
 fn fn_find_exec_native(mut path: PathBuf) -> Option<PathBuf> {
+    use crate::shim::is_app_exec_link;
+
     if !path.is_absolute() {
         return None;
     }
-    if path.is_file() {
+    // App execution alias can't be opened as regular file.
+    if path.is_file() || is_app_exec_link(&path) {
         return Some(path);
     }
     if path
@@ -881,7 +1822,7 @@ fn fn_find_exec_native(mut path: PathBuf) -> Option<PathBuf> {
         });
         if let Some(n) = name_with_ext {
             path.set_file_name(n);
-            if path.is_file() {
+            if path.is_file() || is_app_exec_link(&path) {
                 return Some(path);
             }
         }
@@ -902,3 +1843,146 @@ fn fn_find_exec_native(path: PathBuf) -> Option<PathBuf> {
         _ => None,
     }
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::fs;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::{ExitStatus, Output};
+    use std::sync::Arc;
+
+    use crate::compiler::{
+        compile_cached, CommandInfo, CompilationArgs, CompilationTask, CompileInput, CompileStep,
+        CompilerOutput, OutputInfo, PCHUsage, PreprocessResult, SharedState, SourceInput,
+        TaskStatus, Toolchain,
+    };
+    use crate::config::Config;
+    use crate::io::taskoutput::OutputLimits;
+    use crate::provenance::EntryInfo;
+
+    // x64 toolchain producing ARM64 objects.
+    struct MismatchedToolchain;
+
+    impl Toolchain for MismatchedToolchain {
+        fn identifier(&self) -> Option<String> {
+            Some("test".to_string())
+        }
+
+        fn target_machine(&self) -> Option<u16> {
+            Some(0x8664)
+        }
+
+        fn create_tasks(
+            &self,
+            _command: CommandInfo,
+            _args: &[String],
+            _run_second_cpp: bool,
+        ) -> crate::Result<Vec<CompilationTask>> {
+            unreachable!()
+        }
+
+        fn run_preprocess(
+            &self,
+            _state: &SharedState,
+            _task: &CompilationTask,
+        ) -> crate::Result<PreprocessResult> {
+            unreachable!()
+        }
+
+        fn create_compile_step(
+            &self,
+            _task: &CompilationTask,
+            _preprocessed: CompilerOutput,
+        ) -> crate::Result<CompileStep> {
+            unreachable!()
+        }
+
+        fn run_compile(
+            &self,
+            _state: &SharedState,
+            task: CompileStep,
+        ) -> crate::Result<OutputInfo> {
+            let mut header = vec![0; 20];
+            header[..2].copy_from_slice(&0xaa64u16.to_le_bytes());
+            fs::write(task.output_object.unwrap(), header)?;
+            Ok(OutputInfo::empty())
+        }
+    }
+
+    #[test]
+    fn test_machine_mismatch_not_cached() {
+        let temp = tempfile::tempdir().unwrap();
+        let state = SharedState::new(&Config {
+            cache: temp.path().join("cache"),
+            ..Config::default()
+        })
+        .unwrap();
+        let source = temp.path().join("a.cpp");
+        let object = temp.path().join("a.obj");
+        let command = CommandInfo::simple(PathBuf::from("cl.exe"));
+        let task = CompilationTask {
+            shared: Arc::new(CompilationArgs {
+                command,
+                args: Vec::new(),
+                pch_usage: PCHUsage::None,
+                deps_file: None,
+                run_second_cpp: true,
+                notes: Vec::new(),
+                config: None,
+                link: None,
+            }),
+            language: "C++".to_string(),
+            input_source: source.clone(),
+            output_object: object.clone(),
+        };
+        let info = EntryInfo {
+            toolchain: Some("test".to_string()),
+            source: source.clone(),
+            outputs: vec![object.clone()],
+            version: String::new(),
+            produced: None,
+        };
+        let step = CompileStep {
+            args: Vec::new(),
+            output_object: Some(object),
+            pch_usage: PCHUsage::None,
+            input: CompileInput::Source(SourceInput {
+                path: source,
+                current_dir: None,
+                env: task.shared.command.env.clone(),
+            }),
+            run_second_cpp: true,
+            source: None,
+        };
+        let result = compile_cached(&MismatchedToolchain, &state, &task, "0123abcd", &info, step);
+        assert!(matches!(
+            result,
+            Err(crate::Error::MachineMismatch { ref actual, .. }) if actual == "arm64"
+        ));
+        assert!(state.cache.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_crash_status() {
+        assert_eq!(
+            TaskStatus::from(ExitStatus::from_raw(3 << 8)),
+            TaskStatus::Code(3)
+        );
+        let output = OutputInfo::new(
+            Output {
+                status: ExitStatus::from_raw(11),
+                stdout: Vec::new(),
+                stderr: b"a.cpp\n".to_vec(),
+            },
+            &OutputLimits::new(&Config::default()),
+        );
+        assert_eq!(output.status, TaskStatus::Crashed(11));
+        assert!(!output.success());
+        assert_eq!(output.status.code(), None);
+        assert_eq!(
+            output.stderr.to_vec().unwrap(),
+            b"a.cpp\noctobuild: compiler crashed with signal 11\n"
+        );
+    }
+}
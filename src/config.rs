@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::io::Write;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
@@ -5,18 +6,203 @@ use std::sync::OnceLock;
 
 use figment::providers::{Env, Format, Serialized, Yaml};
 use figment::Figment;
+use serde::{Deserialize, Serialize};
 
-#[derive(serde::Serialize, serde::Deserialize)]
+// How graph frontends turn failed tasks into a process exit code.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExitCodeMode {
+    // Exit code of the first failed task.
+    FirstFailure,
+    // Exit code of failed tasks if all of them agree, AGGREGATE_EXIT_CODE otherwise.
+    Aggregate,
+    // Always AGGREGATE_EXIT_CODE, like xgConsole does.
+    XgconsoleCompatible,
+}
+
+impl std::str::FromStr for ExitCodeMode {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-failure" => Ok(ExitCodeMode::FirstFailure),
+            "aggregate" => Ok(ExitCodeMode::Aggregate),
+            "xgconsole-compatible" => Ok(ExitCodeMode::XgconsoleCompatible),
+            _ => Err(crate::Error::Generic(format!(
+                "Unknown exit code mode: {s}"
+            ))),
+        }
+    }
+}
+
+// CI annotation format of compiler warnings and errors printed after every task.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnnotationFormat {
+    Off,
+    // `##vso[task.logissue ...]` logging commands of Azure Pipelines.
+    Azure,
+    // `::warning file=...::` workflow commands of GitHub Actions.
+    Github,
+}
+
+impl std::str::FromStr for AnnotationFormat {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AnnotationFormat::Off),
+            "azure" => Ok(AnnotationFormat::Azure),
+            "github" => Ok(AnnotationFormat::Github),
+            _ => Err(crate::Error::Generic(format!(
+                "Unknown annotation format: {s}"
+            ))),
+        }
+    }
+}
+
+// Shape of diagnostics reported by octobuild itself, so IDE can navigate to file location.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagnosticStyle {
+    // `file(line,column): error CODE: message`, recognized by Visual Studio Output window.
+    Msvc,
+    // `file:line:column: error: message [CODE]`, recognized by gcc-aware tools.
+    Gcc,
+}
+
+// Detection of include files referenced with different letter case.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IncludeCaseCheck {
+    Off,
+    // Warn about collisions at the end of build.
+    Warn,
+    // Also fail tasks which spell include file differently from file on disk.
+    Strict,
+}
+
+// Verification of files written by compiler against task outputs.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputCheck {
+    Off,
+    // Fail tasks with missing outputs, warn about unexpected files at the end of build.
+    Warn,
+    // Also fail tasks which write unexpected files.
+    Strict,
+}
+
+// Replaces compiler executable before toolchain resolution.
+// Rule with `from` ending with path separator replaces path prefix, otherwise whole path must match.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ToolRemap {
+    pub from: String,
+    pub to: String,
+}
+
+// Regex replacement in preprocessed file before compilation, applied line by line.
+// Rule without `source` glob is applied to all files.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PreprocessTransform {
+    pub pattern: String,
+    pub replacement: String,
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+// Program with fixed leading arguments running one phase of compilation.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct PhaseProgram {
+    pub program: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+// Separate preprocessor and compiler programs for toolchain matching `toolchain` glob.
+// Phase without program runs toolchain compiler itself.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ToolchainPhases {
+    pub toolchain: String,
+    #[serde(default)]
+    pub preprocessor: Option<PhaseProgram>,
+    #[serde(default)]
+    pub compiler: Option<PhaseProgram>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Config {
+    pub annotation_limit: usize,
+    pub annotation_root: Option<PathBuf>,
+    pub annotations: AnnotationFormat,
+    pub aux_process_names: Vec<String>,
+    pub aux_process_reap_sec: u64,
+    pub bigobj_retry: bool,
+    pub builder_drain_sec: u64,
+    pub builder_max_payload_kb: u64,
     pub cache: PathBuf,
     pub cache_limit_mb: u64,
     pub cache_compression_level: u32,
+    pub cache_label_weights: BTreeMap<String, u64>,
+    pub chunk_dedup: bool,
+    pub config_label: Option<String>,
+    pub console_build_limit_mb: u64,
+    pub console_task_limit_kb: u64,
     pub coordinator: Option<url::Url>,
     pub coordinator_bind: SocketAddr,
+    pub deterministic_schedule: bool,
+    pub diagnostic_style: DiagnosticStyle,
+    pub diagnostics_file: Option<PathBuf>,
+    pub diagnostics_log: Option<PathBuf>,
+    pub embed_debug_info: bool,
+    pub etw: bool,
+    pub exit_code_mode: ExitCodeMode,
+    pub explain: bool,
     pub helper_bind: SocketAddr,
+    pub include_case_check: IncludeCaseCheck,
+    pub internal_error_retries: usize,
+    pub missing_header_check: bool,
+    pub object_sidecar: bool,
+    pub object_stamp: bool,
+    pub output_check: OutputCheck,
+    pub output_codepage: Option<u32>,
+    pub output_limit_kb: u64,
+    pub output_log_dir: Option<PathBuf>,
+    pub output_log_segment_mb: u64,
+    pub output_log_segments: usize,
+    pub output_spill_kb: u64,
+    pub output_truncation_marker: String,
+    pub overrides_root: Option<PathBuf>,
+    pub pch_reuse: bool,
+    pub pch_reuse_check: usize,
+    pub post_compile_hook: Vec<String>,
+    pub prefix_dedup: bool,
+    pub preprocess_transform: Vec<PreprocessTransform>,
     pub process_limit: usize,
+    pub projfs_restore: bool,
+    pub raw_output: bool,
+    pub remote_large_size_kb: u64,
+    pub remote_local_reserve: usize,
+    pub remote_min_size_kb: u64,
+    pub remote_pch: bool,
     pub run_second_cpp: bool,
+    pub schedule_seed: u64,
+    pub shadow: bool,
+    pub shadow_dir: Option<PathBuf>,
+    pub shadow_limit: usize,
+    pub show_includes_prefix: Option<String>,
+    pub slot_grace_sec: u64,
+    pub slot_limit: Option<usize>,
+    pub split_link: bool,
+    pub suppress_warnings: Vec<String>,
+    pub temp_dirs: Vec<PathBuf>,
+    pub tool_remap: Vec<ToolRemap>,
+    pub toolchain_phases: Vec<ToolchainPhases>,
+    pub toolchain_warmup: bool,
     pub use_response_files: bool,
+    pub verify_preprocess: u32,
+    pub watchdog_interval_sec: u64,
+    pub watchdog_kill_sec: u64,
 }
 
 #[must_use]
@@ -34,15 +220,79 @@ const DEFAULT_USE_RESPONSE_FILES: bool = false;
 impl Default for Config {
     fn default() -> Self {
         Self {
+            annotation_limit: 10,
+            annotation_root: None,
+            annotations: AnnotationFormat::Off,
+            aux_process_names: vec!["mspdbsrv.exe".to_string(), "vctip.exe".to_string()],
+            aux_process_reap_sec: 0,
+            bigobj_retry: false,
+            builder_drain_sec: 60,
+            builder_max_payload_kb: 0,
             cache: project_dirs().cache_dir().into(),
             cache_limit_mb: 64 * 1024,
             cache_compression_level: 1,
+            cache_label_weights: BTreeMap::new(),
+            chunk_dedup: false,
+            config_label: None,
+            console_build_limit_mb: 0,
+            console_task_limit_kb: 0,
             coordinator: None,
             coordinator_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3000)),
+            deterministic_schedule: false,
+            diagnostic_style: DiagnosticStyle::Msvc,
+            diagnostics_file: None,
+            diagnostics_log: None,
+            embed_debug_info: false,
+            etw: false,
+            exit_code_mode: ExitCodeMode::Aggregate,
+            explain: false,
             helper_bind: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)),
+            include_case_check: IncludeCaseCheck::Off,
+            internal_error_retries: 2,
+            missing_header_check: false,
+            object_sidecar: false,
+            object_stamp: false,
+            output_check: OutputCheck::Warn,
+            output_codepage: None,
+            output_limit_kb: 16 * 1024,
+            output_log_dir: None,
+            output_log_segment_mb: 64,
+            output_log_segments: 8,
+            output_spill_kb: 1024,
+            output_truncation_marker: "\n[octobuild: {bytes} bytes of output truncated]\n"
+                .to_string(),
+            overrides_root: None,
+            pch_reuse: false,
+            pch_reuse_check: 20,
+            post_compile_hook: Vec::new(),
+            prefix_dedup: false,
+            preprocess_transform: Vec::new(),
             process_limit: num_cpus::get(),
+            projfs_restore: false,
+            raw_output: false,
+            remote_large_size_kb: 0,
+            remote_local_reserve: 0,
+            remote_min_size_kb: 0,
+            remote_pch: false,
             run_second_cpp: true,
+            schedule_seed: 0,
+            shadow: false,
+            shadow_dir: None,
+            shadow_limit: 1,
+            show_includes_prefix: None,
+            slot_grace_sec: 30,
+            slot_limit: None,
+            split_link: true,
+            // C4628: digraphs not supported with -Ze.
+            suppress_warnings: vec![r"^\S+[^:]*\(\d+\) : warning C4628: ".to_string()],
+            temp_dirs: Vec::new(),
+            tool_remap: Vec::new(),
+            toolchain_phases: Vec::new(),
+            toolchain_warmup: false,
             use_response_files: DEFAULT_USE_RESPONSE_FILES,
+            verify_preprocess: 0,
+            watchdog_interval_sec: 300,
+            watchdog_kill_sec: 0,
         }
     }
 }
@@ -64,8 +314,23 @@ impl Config {
     pub fn print_help(&self, executable: &str, out: &mut impl Write) -> crate::Result<()> {
         writeln!(out)?;
         writeln!(out, "Usage:")?;
-        writeln!(out, "  {} <file>", executable)?;
+        writeln!(
+            out,
+            "  {} [--exit-code-mode=<mode>] [--annotations=azure|github] [--config-label=<label>] [--deterministic-schedule] [--raw-output] [--explain] [--shadow] [--what-if hit=<glob>] [--diagnostics-file=<file>] <file>",
+            executable
+        )?;
         writeln!(out, "  {} /reset", executable)?;
+        writeln!(out, "  {} /whence <output file>", executable)?;
+        writeln!(
+            out,
+            "  {} /purge [--source-glob=<pattern>] [--produced-after=<unix time>]",
+            executable
+        )?;
+        writeln!(out, "  {} /reindex", executable)?;
+        writeln!(out, "  {} /audit-objects <directory>", executable)?;
+        writeln!(out, "  {} /prewarm <file> [--normal-priority]", executable)?;
+        writeln!(out, "  {} /show-output <task id>", executable)?;
+        writeln!(out, "  {} /attach [--from-start]", executable)?;
         writeln!(out,)?;
         writeln!(out, "Octobuild configuration:")?;
         writeln!(
@@ -0,0 +1,566 @@
+use std::fs::{self, File};
+use std::io::{stderr, stdout, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::OutputInfo;
+use crate::config::{Config, DiagnosticStyle};
+use crate::utils::escape_control;
+use crate::worker::BuildResult;
+
+const INDEX_FILE: &str = "index.jsonl";
+const SEGMENT_PREFIX: &str = "output-";
+const SEGMENT_SUFFIX: &str = ".log";
+// Present while build writes to the log, holds id of that build.
+const BUILD_FILE: &str = "build.id";
+// How often attached console looks for new task output.
+const ATTACH_POLL: Duration = Duration::from_millis(200);
+
+// Byte range of task output in log segment.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+struct IndexEntry {
+    task: usize,
+    segment: usize,
+    offset: u64,
+    length: u64,
+    // Task title as in segment header, missing in logs of older versions.
+    #[serde(default)]
+    title: String,
+}
+
+// Full output of every task of the last build, split into segments of limited size.
+// Index maps task id to byte range, so output of any task can be retrieved after the build.
+// Only the newest segments are kept, older ones are removed.
+pub struct TaskLog {
+    dir: PathBuf,
+    segment_size: u64,
+    segments: usize,
+    // Log of previous build is replaced on first write, so commands that don't build keep it.
+    state: Mutex<Option<LogState>>,
+    // Build id written to build file.
+    build: String,
+}
+
+struct LogState {
+    index: File,
+    segment: usize,
+    file: File,
+    size: u64,
+}
+
+fn segment_path(dir: &Path, segment: usize) -> PathBuf {
+    dir.join(format!("{SEGMENT_PREFIX}{segment}{SEGMENT_SUFFIX}"))
+}
+
+impl TaskLog {
+    #[must_use]
+    pub fn new(dir: &Path, segment_size: u64, segments: usize) -> Self {
+        TaskLog {
+            dir: dir.to_path_buf(),
+            segment_size,
+            segments: segments.max(1),
+            state: Mutex::new(None),
+            build: format!(
+                "{} {}",
+                std::process::id(),
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos()
+            ),
+        }
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn create(&self) -> std::io::Result<LogState> {
+        fs::create_dir_all(&self.dir)?;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == INDEX_FILE
+                || (name.starts_with(SEGMENT_PREFIX) && name.ends_with(SEGMENT_SUFFIX))
+            {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        fs::write(self.dir.join(BUILD_FILE), &self.build)?;
+        Ok(LogState {
+            index: File::create(self.dir.join(INDEX_FILE))?,
+            segment: 0,
+            file: File::create(segment_path(&self.dir, 0))?,
+            size: 0,
+        })
+    }
+
+    pub fn write(&self, task: usize, title: &str, parts: &[&[u8]]) -> std::io::Result<()> {
+        let title = escape_control(title);
+        let header = format!("== task {task}: {title}\n");
+        let length: u64 = parts.iter().map(|part| part.len() as u64).sum();
+        let mut guard = self.state.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.create()?);
+        }
+        let state = guard.as_mut().unwrap();
+        // Output larger than segment gets a segment of its own.
+        if state.size > 0 && state.size + header.len() as u64 + length > self.segment_size {
+            state.segment += 1;
+            state.file = File::create(segment_path(&self.dir, state.segment))?;
+            state.size = 0;
+            if let Some(old) = state.segment.checked_sub(self.segments) {
+                drop(fs::remove_file(segment_path(&self.dir, old)));
+            }
+        }
+        state.file.write_all(header.as_bytes())?;
+        let offset = state.size + header.len() as u64;
+        for part in parts {
+            state.file.write_all(part)?;
+        }
+        state.size = offset + length;
+        let mut line = serde_json::to_vec(&IndexEntry {
+            task,
+            segment: state.segment,
+            offset,
+            length,
+            title: title.into_owned(),
+        })?;
+        line.push(b'\n');
+        state.index.write_all(&line)
+    }
+}
+
+impl Drop for TaskLog {
+    // Attached consoles stop following the log when build file is gone.
+    fn drop(&mut self) {
+        let path = self.dir.join(BUILD_FILE);
+        if self.state.get_mut().unwrap().is_some()
+            && fs::read_to_string(&path).is_ok_and(|build| build == self.build)
+        {
+            drop(fs::remove_file(path));
+        }
+    }
+}
+
+// Read full output of task from log of the last build.
+pub fn read_task_output(dir: &Path, task: usize) -> crate::Result<Vec<u8>> {
+    let index = BufReader::new(File::open(dir.join(INDEX_FILE))?);
+    let mut found = None;
+    for line in index.lines() {
+        let entry: IndexEntry = serde_json::from_str(&line?)?;
+        if entry.task == task {
+            found = Some(entry);
+        }
+    }
+    let entry = found.ok_or_else(|| {
+        crate::Error::Generic(format!(
+            "Output of task {task} not found in {}",
+            dir.display()
+        ))
+    })?;
+    read_entry(dir, &entry)
+}
+
+fn read_entry(dir: &Path, entry: &IndexEntry) -> crate::Result<Vec<u8>> {
+    let task = entry.task;
+    let mut file = File::open(segment_path(dir, entry.segment)).map_err(|e| {
+        if e.kind() == ErrorKind::NotFound {
+            crate::Error::Generic(format!(
+                "Output of task {task} was rotated out of {}",
+                dir.display()
+            ))
+        } else {
+            e.into()
+        }
+    })?;
+    file.seek(SeekFrom::Start(entry.offset))?;
+    let mut data = Vec::new();
+    file.take(entry.length).read_to_end(&mut data)?;
+    if data.len() as u64 != entry.length {
+        return Err(crate::Error::Generic(format!(
+            "Output of task {task} is truncated in {}",
+            dir.display()
+        )));
+    }
+    Ok(data)
+}
+
+// Console attached to the log of running build. It only reads the log, so it never slows
+// the build down: output that is not printed yet waits in log segments.
+pub struct LogFollower {
+    dir: PathBuf,
+    build: String,
+    index: File,
+    // Index data after the last complete line.
+    pending: Vec<u8>,
+}
+
+impl LogFollower {
+    // Attach to the current point of the log or to its first task.
+    pub fn open(dir: &Path, from_start: bool) -> crate::Result<Self> {
+        let build = fs::read_to_string(dir.join(BUILD_FILE)).map_err(|_| {
+            crate::Error::Generic(format!(
+                "No running build writes task output to {}",
+                dir.display()
+            ))
+        })?;
+        let mut follower = LogFollower {
+            dir: dir.to_path_buf(),
+            build,
+            index: File::open(dir.join(INDEX_FILE))?,
+            pending: Vec::new(),
+        };
+        if !from_start {
+            follower.take_entries()?;
+        }
+        Ok(follower)
+    }
+
+    fn take_entries(&mut self) -> crate::Result<Vec<IndexEntry>> {
+        self.index.read_to_end(&mut self.pending)?;
+        let complete = self
+            .pending
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |pos| pos + 1);
+        let lines: Vec<u8> = self.pending.drain(..complete).collect();
+        let mut entries = Vec::new();
+        for line in lines.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            entries.push(serde_json::from_slice(line)?);
+        }
+        Ok(entries)
+    }
+
+    // Print output of tasks as they complete, returns when the build is over.
+    pub fn follow(mut self, out: &mut impl Write) -> crate::Result<()> {
+        let build_path = self.dir.join(BUILD_FILE);
+        loop {
+            // Build file is removed after the last write, so the log is read once more after that.
+            let finished = fs::read_to_string(&build_path).ok().as_ref() != Some(&self.build);
+            for entry in self.take_entries()? {
+                writeln!(out, "== task {}: {}", entry.task, entry.title)?;
+                match read_entry(&self.dir, &entry) {
+                    Ok(data) => out.write_all(&data)?,
+                    // Attached console fell behind log rotation.
+                    Err(e) => writeln!(out, "[octobuild: {e}]")?,
+                }
+            }
+            out.flush()?;
+            if finished {
+                return Ok(());
+            }
+            std::thread::sleep(ATTACH_POLL);
+        }
+    }
+}
+
+// Prints task output to console. Enormous builds slow down terminals and overflow CI log views,
+// so output of a single task and of the whole build can be capped. Full output still goes
+// to the cache and, if configured, to the task log.
+pub struct ConsoleOutput {
+    // Printed bytes limit per task, zero means no limit.
+    task_limit: u64,
+    // After this many printed bytes only output of failed tasks is printed, zero means no limit.
+    build_limit: u64,
+    log: Option<TaskLog>,
+    state: Mutex<ConsoleState>,
+}
+
+#[derive(Default)]
+struct ConsoleState {
+    printed: u64,
+    // Successful tasks with output that was not printed because of build limit.
+    suppressed: usize,
+}
+
+// Print data up to budget, cutting at line end if possible. Returns count of printed bytes.
+fn print_capped(out: &mut impl Write, data: &[u8], budget: &mut u64) -> std::io::Result<u64> {
+    let mut size = data
+        .len()
+        .min(usize::try_from(*budget).unwrap_or(usize::MAX));
+    if size < data.len() {
+        if let Some(pos) = data[..size].iter().rposition(|c| *c == b'\n') {
+            size = pos + 1;
+        }
+    }
+    out.write_all(&data[..size])?;
+    *budget -= size as u64;
+    Ok(size as u64)
+}
+
+impl ConsoleOutput {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        ConsoleOutput {
+            task_limit: config.console_task_limit_kb * 1024,
+            build_limit: config.console_build_limit_mb * 1024 * 1024,
+            log: config.output_log_dir.as_ref().map(|dir| {
+                TaskLog::new(
+                    dir,
+                    config.output_log_segment_mb * 1024 * 1024,
+                    config.output_log_segments,
+                )
+            }),
+            state: Mutex::default(),
+        }
+    }
+
+    // Print failure message and output of completed task.
+    pub fn print(&self, result: &BuildResult, style: DiagnosticStyle) -> crate::Result<()> {
+        let message = result.result.error_message(style, result.task.source());
+        self.print_output(
+            &mut stdout(),
+            &mut stderr(),
+            result.id,
+            &result.task.title,
+            message.as_deref(),
+            result.result.output.as_ref().ok(),
+        )
+    }
+
+    fn print_output(
+        &self,
+        out: &mut impl Write,
+        err: &mut impl Write,
+        task: usize,
+        title: &str,
+        message: Option<&str>,
+        output: Option<&OutputInfo>,
+    ) -> crate::Result<()> {
+        if let Some(message) = message {
+            writeln!(err, "{message}")?;
+        }
+        let Some(output) = output else {
+            return Ok(());
+        };
+        let stdout_data = output.stdout.to_vec()?;
+        let stderr_data = output.stderr.to_vec()?;
+        if stdout_data.is_empty() && stderr_data.is_empty() {
+            return Ok(());
+        }
+        if let Some(log) = &self.log {
+            log.write(task, title, &[&stdout_data, &stderr_data])?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if message.is_none() && self.build_limit > 0 && state.printed >= self.build_limit {
+            state.suppressed += 1;
+            return Ok(());
+        }
+        let mut budget = if self.task_limit > 0 {
+            self.task_limit
+        } else {
+            u64::MAX
+        };
+        let printed = print_capped(out, &stdout_data, &mut budget)?
+            + print_capped(err, &stderr_data, &mut budget)?;
+        state.printed += printed;
+        let dropped = (stdout_data.len() + stderr_data.len()) as u64 - printed;
+        if dropped > 0 {
+            match &self.log {
+                Some(log) => writeln!(
+                    err,
+                    "[octobuild: {dropped} bytes of task output not printed, see `xgConsole /show-output {task}` or {}]",
+                    log.dir().display()
+                )?,
+                None => writeln!(err, "[octobuild: {dropped} bytes of task output not printed]")?,
+            }
+        }
+        Ok(())
+    }
+
+    // Print note about output suppressed because of build limit.
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        let state = self.state.lock().unwrap();
+        if state.suppressed == 0 {
+            return Ok(());
+        }
+        write!(
+            out,
+            "Console output limit reached, output of {} successful task(s) was not printed",
+            state.suppressed
+        )?;
+        match &self.log {
+            Some(log) => writeln!(out, ", see {}", log.dir().display())?,
+            None => writeln!(out)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compiler::{OutputInfo, TaskStatus};
+    use crate::config::Config;
+    use crate::console::{
+        read_task_output, segment_path, ConsoleOutput, LogFollower, TaskLog, BUILD_FILE,
+    };
+    use crate::io::taskoutput::TaskOutput;
+
+    // Synthetic compiler output: numbered lines of given total size.
+    fn text(seed: usize, size: usize) -> Vec<u8> {
+        let mut result = Vec::with_capacity(size + 64);
+        let mut line = 0;
+        while result.len() < size {
+            result.extend(format!("task {seed} line {line}: warning C4996\n").as_bytes());
+            line += 1;
+        }
+        result.truncate(size);
+        result
+    }
+
+    fn output(stdout: Vec<u8>, stderr: Vec<u8>) -> OutputInfo {
+        OutputInfo {
+            status: TaskStatus::Code(0),
+            stdout: TaskOutput::from(stdout),
+            stderr: TaskOutput::from(stderr),
+        }
+    }
+
+    #[test]
+    fn test_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = TaskLog::new(dir.path(), 4 * 1024 * 1024, 3);
+        let outputs: Vec<(Vec<u8>, Vec<u8>)> = (0..8)
+            .map(|task| (text(task, 1536 * 1024 + task), text(task + 100, task * 10)))
+            .collect();
+        for (task, (stdout, stderr)) in outputs.iter().enumerate() {
+            log.write(task, &format!("t{task}.cpp"), &[stdout, stderr])
+                .unwrap();
+        }
+        // Two tasks per segment, only three newest segments are kept.
+        assert!(!segment_path(dir.path(), 0).exists());
+        assert!(segment_path(dir.path(), 3).exists());
+        for (task, (stdout, stderr)) in outputs.iter().enumerate().skip(2) {
+            let data = read_task_output(dir.path(), task).unwrap();
+            assert_eq!(data.len(), stdout.len() + stderr.len());
+            assert_eq!(&data[..stdout.len()], stdout.as_slice());
+            assert_eq!(&data[stdout.len()..], stderr.as_slice());
+        }
+        let error = read_task_output(dir.path(), 1).unwrap_err();
+        assert!(error.to_string().contains("rotated out"));
+        let error = read_task_output(dir.path(), 100).unwrap_err();
+        assert!(error.to_string().contains("not found"));
+
+        // Next build replaces the log.
+        let log = TaskLog::new(dir.path(), 4 * 1024 * 1024, 3);
+        log.write(0, "a.cpp", &[b"new\n"]).unwrap();
+        assert_eq!(read_task_output(dir.path(), 0).unwrap(), b"new\n");
+        assert!(read_task_output(dir.path(), 2).is_err());
+        assert!(!segment_path(dir.path(), 3).exists());
+    }
+
+    #[test]
+    fn test_attach() {
+        let dir = tempfile::tempdir().unwrap();
+        let follow = |follower: LogFollower| {
+            let mut out = Vec::new();
+            follower.follow(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        // Nothing to attach to before the build writes output.
+        assert!(LogFollower::open(dir.path(), true).is_err());
+
+        let log = TaskLog::new(dir.path(), 64, 2);
+        log.write(0, "a.cpp", &[b"a\n"]).unwrap();
+        let live = LogFollower::open(dir.path(), false).unwrap();
+        let replay = LogFollower::open(dir.path(), true).unwrap();
+        let concurrent = std::thread::spawn(move || follow(replay));
+        for task in 1..4 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            log.write(task, &format!("t{task}.cpp"), &[b"warning\n", b"note\n"])
+                .unwrap();
+        }
+        // Log of the next build doesn't end this one.
+        let next = TaskLog::new(dir.path(), 64, 2);
+        drop(next);
+        drop(log);
+        assert!(!dir.path().join(BUILD_FILE).exists());
+
+        let tail = "== task 1: t1.cpp\nwarning\nnote\n\
+                    == task 2: t2.cpp\nwarning\nnote\n\
+                    == task 3: t3.cpp\nwarning\nnote\n";
+        assert_eq!(follow(live), tail);
+        assert_eq!(
+            concurrent.join().unwrap(),
+            format!("== task 0: a.cpp\na\n{tail}")
+        );
+
+        // Segments rotated out before they were read are reported.
+        let log = TaskLog::new(dir.path(), 32, 2);
+        log.write(0, "a.cpp", &[b"a\n"]).unwrap();
+        let lagging = LogFollower::open(dir.path(), true).unwrap();
+        for task in 1..4 {
+            log.write(task, &format!("t{task}.cpp"), &[b"warning\n", b"note\n"])
+                .unwrap();
+        }
+        drop(log);
+        let output = follow(lagging);
+        assert!(output
+            .starts_with("== task 0: a.cpp\n[octobuild: Error: Output of task 0 was rotated out"));
+        assert!(output.ends_with("== task 3: t3.cpp\nwarning\nnote\n"));
+    }
+
+    #[test]
+    fn test_console_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let console = ConsoleOutput::new(&Config {
+            console_task_limit_kb: 64,
+            console_build_limit_mb: 1,
+            output_log_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        });
+        let print = |task: usize, message: Option<&str>, output: &OutputInfo| {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            console
+                .print_output(&mut out, &mut err, task, "a.cpp", message, Some(output))
+                .unwrap();
+            (out, String::from_utf8(err).unwrap())
+        };
+
+        // Small output is printed as is.
+        let (out, err) = print(0, None, &output(b"a.cpp\n".to_vec(), Vec::new()));
+        assert_eq!((out.as_slice(), err.as_str()), (b"a.cpp\n".as_slice(), ""));
+
+        // Large output is cut at line end with a pointer to the full text.
+        let large = text(1, 3 * 1024 * 1024);
+        let (out, err) = print(1, None, &output(large.clone(), Vec::new()));
+        assert!(out.len() <= 64 * 1024 && out.len() > 63 * 1024);
+        assert!(large.starts_with(&out));
+        assert_eq!(out.last(), Some(&b'\n'));
+        assert!(err.contains(&format!(
+            "{} bytes of task output not printed, see `xgConsole /show-output 1`",
+            large.len() - out.len()
+        )));
+        assert_eq!(read_task_output(dir.path(), 1).unwrap(), large);
+
+        // After build limit only failed tasks are printed.
+        let medium = text(2, 256 * 1024);
+        let mut suppressed = 0;
+        for task in 2..24 {
+            let (out, _) = print(task, None, &output(medium.clone(), Vec::new()));
+            if out.is_empty() {
+                suppressed += 1;
+            }
+        }
+        assert!(suppressed > 0);
+        let (out, err) = print(24, None, &output(b"a.cpp\n".to_vec(), Vec::new()));
+        assert!(out.is_empty() && err.is_empty());
+        assert_eq!(read_task_output(dir.path(), 24).unwrap(), b"a.cpp\n");
+        let (out, err) = print(25, Some("a.cpp: error"), &output(medium, b"x\n".to_vec()));
+        assert!(!out.is_empty() && out.len() <= 64 * 1024);
+        assert!(err.starts_with("a.cpp: error\n"));
+        let mut summary = Vec::new();
+        console.print_summary(&mut summary).unwrap();
+        assert!(String::from_utf8(summary).unwrap().starts_with(&format!(
+            "Console output limit reached, output of {} successful task(s) was not printed, see ",
+            suppressed + 1
+        )));
+    }
+}
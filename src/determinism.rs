@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::config::DiagnosticStyle;
+use crate::diagnostics::{format_diagnostic, Location, Severity, CODE_PREPROCESS_DIVERGENCE};
+use crate::schedule::Schedule;
+
+// Bytes of preprocessed output shown around the first difference.
+const CONTEXT_SIZE: usize = 40;
+
+// Cache entries are shared between machines only if preprocessor output depends on sources
+// alone. It doesn't with `__COUNTER__` macros affected by /FI order or with `#import` regenerating
+// .tlh files. Sampled tasks are preprocessed twice and diverged ones are compiled without cache.
+pub struct PreprocessCheck {
+    // Share of checked tasks in percent, 0 disables the check.
+    percent: u32,
+    checked: AtomicUsize,
+    divergent: Mutex<BTreeMap<PathBuf, Divergence>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    // Offset of the first differing byte.
+    pub offset: usize,
+    // Output around the offset in the first and the second run.
+    pub first: String,
+    pub second: String,
+}
+
+impl PreprocessCheck {
+    #[must_use]
+    pub fn new(percent: u32) -> Self {
+        PreprocessCheck {
+            percent,
+            checked: AtomicUsize::new(0),
+            divergent: Mutex::default(),
+        }
+    }
+
+    // Decide whether task preprocessing is repeated, seeded schedule makes the sample stable.
+    #[must_use]
+    pub fn want_check(&self, schedule: &Schedule, source: &Path) -> bool {
+        if self.percent == 0 {
+            return false;
+        }
+        let random: f64 = schedule.random(source);
+        random * 100.0 < f64::from(self.percent)
+    }
+
+    // Compare outputs of two preprocessor runs, returns false on divergence.
+    pub fn compare(&self, source: &Path, first: &[u8], second: &[u8]) -> bool {
+        self.checked.fetch_add(1, Ordering::Relaxed);
+        if Sha256::digest(first) == Sha256::digest(second) {
+            return true;
+        }
+        let divergence = find_divergence(first, second);
+        warn!(
+            "Preprocessed output of {} differs between runs at byte {}: {:?} vs {:?}, \
+             compiled without cache",
+            source.display(),
+            divergence.offset,
+            divergence.first,
+            divergence.second
+        );
+        self.divergent
+            .lock()
+            .unwrap()
+            .insert(source.to_path_buf(), divergence);
+        false
+    }
+
+    // Task of diverged source is compiled without cache for the rest of the build.
+    #[must_use]
+    pub fn is_divergent(&self, source: &Path) -> bool {
+        self.percent > 0 && self.divergent.lock().unwrap().contains_key(source)
+    }
+
+    #[must_use]
+    pub fn checked(&self) -> usize {
+        self.checked.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn divergent(&self) -> BTreeMap<PathBuf, Divergence> {
+        self.divergent.lock().unwrap().clone()
+    }
+
+    pub fn print_warnings(
+        &self,
+        out: &mut impl Write,
+        style: DiagnosticStyle,
+    ) -> crate::Result<()> {
+        let divergent = self.divergent();
+        if divergent.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "WARNING: Preprocessor output differs between runs, sources were compiled without cache:"
+        )?;
+        for (source, divergence) in divergent {
+            writeln!(
+                out,
+                "{}",
+                format_diagnostic(
+                    style,
+                    Location::file(&source),
+                    Severity::Warning,
+                    CODE_PREPROCESS_DIVERGENCE,
+                    &format!(
+                        "Preprocessed output differs at byte {}: {:?} vs {:?}",
+                        divergence.offset, divergence.first, divergence.second
+                    )
+                )
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        if self.percent == 0 {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "Preprocessor determinism check: {} task(s) preprocessed twice, {} diverged",
+            self.checked(),
+            self.divergent.lock().unwrap().len()
+        )?;
+        Ok(())
+    }
+}
+
+// Parse share of checked tasks like `5%`.
+pub fn parse_percent(value: &str) -> crate::Result<u32> {
+    value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .parse()
+        .ok()
+        .filter(|percent| *percent <= 100)
+        .ok_or_else(|| crate::Error::Generic(format!("Invalid percentage: {value}")))
+}
+
+// First difference of outputs with surrounding bytes of both of them.
+fn find_divergence(first: &[u8], second: &[u8]) -> Divergence {
+    let offset = first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .unwrap_or(first.len().min(second.len()));
+    let context = |data: &[u8]| {
+        let begin = offset.saturating_sub(CONTEXT_SIZE).min(data.len());
+        let end = (offset + CONTEXT_SIZE).min(data.len());
+        String::from_utf8_lossy(&data[begin..end]).into_owned()
+    };
+    Divergence {
+        offset,
+        first: context(first),
+        second: context(second),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use crate::determinism::{find_divergence, parse_percent, Divergence, PreprocessCheck};
+
+    #[test]
+    fn test_find_divergence() {
+        assert_eq!(
+            find_divergence(b"int a;\nint counter_1;\n", b"int a;\nint counter_2;\n"),
+            Divergence {
+                offset: 19,
+                first: "int a;\nint counter_1;\n".to_string(),
+                second: "int a;\nint counter_2;\n".to_string(),
+            }
+        );
+        // Context is limited, one output can be a prefix of the other.
+        let long = "x".repeat(100);
+        let divergence = find_divergence(long.as_bytes(), format!("{long}tail").as_bytes());
+        assert_eq!(divergence.offset, 100);
+        assert_eq!(divergence.first, "x".repeat(40));
+        assert_eq!(divergence.second, format!("{}tail", "x".repeat(40)));
+    }
+
+    #[test]
+    fn test_parse_percent() {
+        assert_eq!(parse_percent("5%").unwrap(), 5);
+        assert_eq!(parse_percent("100").unwrap(), 100);
+        assert!(parse_percent("101%").is_err());
+        assert!(parse_percent("half").is_err());
+    }
+
+    #[test]
+    fn test_divergent_sources() {
+        let check = PreprocessCheck::new(100);
+        assert!(check.compare(Path::new("a.cpp"), b"int a;\n", b"int a;\n"));
+        assert!(!check.compare(Path::new("b.cpp"), b"int b1;\n", b"int b2;\n"));
+        assert!(!check.is_divergent(Path::new("a.cpp")));
+        assert!(check.is_divergent(Path::new("b.cpp")));
+        assert_eq!(check.checked(), 2);
+
+        let mut out = Vec::new();
+        check.print_summary(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Preprocessor determinism check: 2 task(s) preprocessed twice, 1 diverged\n"
+        );
+        // Nothing is printed if the check is disabled.
+        let mut out = Vec::new();
+        PreprocessCheck::new(0).print_summary(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,683 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::OutputInfo;
+use crate::config::{Config, DiagnosticStyle};
+use crate::io::taskoutput::TaskOutput;
+
+// Codes of diagnostics reported by octobuild itself.
+// Task failed inside octobuild: cache, postprocessing or I/O error.
+pub const CODE_TASK_ERROR: &str = "OB1000";
+// Compiler failed.
+pub const CODE_EXIT_STATUS: &str = "OB1001";
+pub const CODE_INCLUDE_CASE: &str = "OB1002";
+pub const CODE_OUTPUT_MISMATCH: &str = "OB1003";
+pub const CODE_MACHINE_MISMATCH: &str = "OB1004";
+pub const CODE_INCLUDE_CASE_COLLISION: &str = "OB4001";
+pub const CODE_UNEXPECTED_OUTPUT: &str = "OB4002";
+pub const CODE_BIGOBJ_RETRY: &str = "OB4003";
+pub const CODE_PREPROCESS_DIVERGENCE: &str = "OB4004";
+// cl: `Cannot open source file: 'a.cpp': Permission denied`, also used for include files.
+pub const CODE_CANNOT_OPEN: &str = "C1083";
+// Quotes around path in English and localized cl messages.
+const QUOTES: [(char, char); 6] = [
+    ('\'', '\''),
+    ('"', '"'),
+    ('„', '“'),
+    ('“', '”'),
+    ('‘', '’'),
+    ('«', '»'),
+];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+// File position of diagnostic reported by octobuild.
+#[derive(Clone, Copy, Debug)]
+pub struct Location<'a> {
+    pub path: &'a Path,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl<'a> Location<'a> {
+    // Position is unknown: IDE still needs line and column to open the file.
+    #[must_use]
+    pub fn file(path: &'a Path) -> Self {
+        Location {
+            path,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+// Format diagnostic about file location in the shape parsed by IDE.
+// Severity words are never localized: Visual Studio only recognizes English ones.
+#[must_use]
+pub fn format_diagnostic(
+    style: DiagnosticStyle,
+    location: Location,
+    severity: Severity,
+    code: &str,
+    message: &str,
+) -> String {
+    let severity = match severity {
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    };
+    // Line breaks would detach the rest of message from location.
+    let message = message.replace(['\r', '\n'], " ");
+    let path = location.path.display();
+    let (line, column) = (location.line, location.column);
+    match style {
+        DiagnosticStyle::Msvc => format!("{path}({line},{column}): {severity} {code}: {message}"),
+        DiagnosticStyle::Gcc => format!("{path}:{line}:{column}: {severity}: {message} [{code}]"),
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    // Diagnostic code (C4996, -Wunused-variable) or message if there is no code.
+    pub key: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Default, Debug, Eq, PartialEq)]
+pub struct DiagnosticCounts {
+    pub warnings: usize,
+    pub unique_warnings: usize,
+    pub errors: usize,
+    pub unique_errors: usize,
+}
+
+impl DiagnosticCounts {
+    #[must_use]
+    pub fn new(diagnostics: &[Diagnostic]) -> Self {
+        let mut counts = DiagnosticCounts::default();
+        // The same warning at another column of the same line is not unique.
+        let mut seen: HashSet<(&str, Option<u32>, Severity, &str)> = HashSet::new();
+        for diagnostic in diagnostics {
+            let unique = seen.insert((
+                &diagnostic.file,
+                diagnostic.line,
+                diagnostic.severity,
+                &diagnostic.key,
+            ));
+            let (total, uniques) = match diagnostic.severity {
+                Severity::Warning => (&mut counts.warnings, &mut counts.unique_warnings),
+                Severity::Error => (&mut counts.errors, &mut counts.unique_errors),
+            };
+            *total += 1;
+            if unique {
+                *uniques += 1;
+            }
+        }
+        counts
+    }
+
+    #[must_use]
+    pub fn from_output(output: &OutputInfo) -> Self {
+        DiagnosticCounts::new(&parse_output(output))
+    }
+}
+
+// Diagnostics from both output streams of task.
+#[must_use]
+pub fn parse_output(output: &OutputInfo) -> Vec<Diagnostic> {
+    // cl.exe writes diagnostics to stdout, clang to stderr.
+    let text = |output: &TaskOutput| output.to_vec().unwrap_or_default();
+    let success = Some(output.success());
+    let mut diagnostics = parse_text(&text(&output.stdout), success);
+    diagnostics.extend(parse_text(&text(&output.stderr), success));
+    diagnostics
+}
+
+// Paths of files compiler failed to open. Localized messages differ in words and quotes,
+// so only the code and the first quoted text are used.
+#[must_use]
+pub fn cannot_open_paths(diagnostics: &[Diagnostic]) -> Vec<String> {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.key == CODE_CANNOT_OPEN)
+        .filter_map(|diagnostic| quoted_text(&diagnostic.message))
+        .collect()
+}
+
+fn quoted_text(message: &str) -> Option<String> {
+    let (start, close) = message.char_indices().find_map(|(index, c)| {
+        QUOTES
+            .iter()
+            .find(|(open, _)| *open == c)
+            .map(|(open, close)| (index + open.len_utf8(), *close))
+    })?;
+    let rest = &message[start..];
+    Some(rest[..rest.find(close)?].to_string())
+}
+
+// clang: `file:line:col: warning: message [-Wflag]`
+fn re_clang_diagnostic() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"^(?P<file>.+?):(?P<line>\d+):(?:(?P<column>\d+):)?\s*(?P<severity>warning|error|fatal error):\s*(?P<message>.*?)(?:\s*\[(?P<flag>[^\]]+)\])?\s*$").unwrap()
+    })
+}
+
+// cl: `file(line): warning C4996: message`, severity may be localized.
+fn re_cl_diagnostic() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"^\s*(?P<file>.*?)(?:\((?P<line>\d+)(?:,(?P<column>\d+))?\))?\s*:\s*(?P<severity>[^:]*?)\s*\b(?P<code>[A-Z]{1,3}\d{4})\s*:").unwrap()
+    })
+}
+
+#[must_use]
+pub fn parse_diagnostics(output: &[u8]) -> Vec<Diagnostic> {
+    parse_text(output, None)
+}
+
+// Exit status of compiler, when known, tells severity of localized cl diagnostics.
+fn parse_text(output: &[u8], success: Option<bool>) -> Vec<Diagnostic> {
+    String::from_utf8_lossy(output)
+        .lines()
+        .filter_map(|line| parse_diagnostic(line, success))
+        .collect()
+}
+
+fn parse_diagnostic(line: &str, success: Option<bool>) -> Option<Diagnostic> {
+    let line = line.trim_end();
+    if let Some(cap) = re_clang_diagnostic().captures(line) {
+        let severity = match &cap["severity"] {
+            "warning" => Severity::Warning,
+            _ => Severity::Error,
+        };
+        let key = cap
+            .name("flag")
+            .unwrap_or_else(|| cap.name("message").unwrap());
+        return Some(Diagnostic {
+            file: cap["file"].to_string(),
+            line: cap["line"].parse().ok(),
+            column: cap.name("column").and_then(|v| v.as_str().parse().ok()),
+            severity,
+            key: key.as_str().to_string(),
+            message: cap["message"].to_string(),
+        });
+    }
+    let cap = re_cl_diagnostic().captures(line)?;
+    let code = &cap["code"];
+    Some(Diagnostic {
+        file: cap["file"].to_string(),
+        line: cap.name("line").and_then(|v| v.as_str().parse().ok()),
+        column: cap.name("column").and_then(|v| v.as_str().parse().ok()),
+        severity: cl_severity(&cap["severity"], code, success),
+        key: code.to_string(),
+        // Message follows code, pattern only matches the line up to it.
+        message: line[cap.get(0).unwrap().end()..].trim().to_string(),
+    })
+}
+
+// Severity words of cl language packs, "fatal error" and its translations end with them.
+const CL_SEVERITY_WORDS: &[(&str, Severity)] = &[
+    ("warning", Severity::Warning),
+    ("error", Severity::Error),
+    ("warnung", Severity::Warning),
+    ("fehler", Severity::Error),
+    ("avertissement", Severity::Warning),
+    ("erreur", Severity::Error),
+    ("advertencia", Severity::Warning),
+    ("avviso", Severity::Warning),
+    ("errore", Severity::Error),
+    ("aviso", Severity::Warning),
+    ("erro", Severity::Error),
+    ("ostrzeżenie", Severity::Warning),
+    ("błąd", Severity::Error),
+    ("upozornění", Severity::Warning),
+    ("chyba", Severity::Error),
+    ("uyarı", Severity::Warning),
+    ("hata", Severity::Error),
+    ("предупреждение", Severity::Warning),
+    ("ошибка", Severity::Error),
+    ("警告", Severity::Warning),
+    ("エラー", Severity::Error),
+    ("错误", Severity::Error),
+    ("錯誤", Severity::Error),
+    ("경고", Severity::Warning),
+    ("오류", Severity::Error),
+];
+
+fn cl_severity(word: &str, code: &str, success: Option<bool>) -> Severity {
+    let word = word.to_lowercase();
+    if let Some((_, severity)) = CL_SEVERITY_WORDS
+        .iter()
+        .find(|(known, _)| word.ends_with(known))
+    {
+        return *severity;
+    }
+    // Successful compiler run reports no errors.
+    if success == Some(true) {
+        return Severity::Warning;
+    }
+    // Unknown language: C4xxx, C5xxx, LNK4xxx and D9xxx codes are warnings unless promoted by /WX.
+    let digits = code.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    if digits.starts_with('4') || digits.starts_with('5') || code.starts_with("D9") {
+        Severity::Warning
+    } else {
+        Severity::Error
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskOutcome {
+    // Result was taken from cache.
+    Hit,
+    Compiled,
+    Failed,
+}
+
+impl TaskOutcome {
+    #[must_use]
+    pub fn new(output: &OutputInfo, cached: bool) -> Self {
+        if !output.success() {
+            TaskOutcome::Failed
+        } else if cached {
+            TaskOutcome::Hit
+        } else {
+            TaskOutcome::Compiled
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct TaskRecord<'a> {
+    pub task: usize,
+    pub source: &'a Path,
+    pub output: &'a Path,
+    pub outcome: TaskOutcome,
+    pub diagnostics: DiagnosticCounts,
+    // Flag added to compiler arguments by retry after compiler limit failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<&'static str>,
+    // Remark like "empty translation unit".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<&'static str>,
+}
+
+// Receives result of every completed compilation task.
+pub trait DiagnosticSink: Send + Sync {
+    fn record(&self, record: &TaskRecord) -> crate::Result<()>;
+}
+
+pub struct NoopSink;
+
+impl DiagnosticSink for NoopSink {
+    fn record(&self, _: &TaskRecord) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+// Appends every record as a single JSON line.
+pub struct JsonlSink {
+    file: Mutex<File>,
+}
+
+impl JsonlSink {
+    pub fn new(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlSink {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl DiagnosticSink for JsonlSink {
+    fn record(&self, record: &TaskRecord) -> crate::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+pub fn create_sink(config: &Config) -> std::io::Result<Box<dyn DiagnosticSink>> {
+    Ok(match &config.diagnostics_log {
+        Some(path) => Box::new(JsonlSink::new(path)?),
+        None => Box::new(NoopSink),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use crate::compiler::{BuildTaskResult, OutputInfo, TaskStatus};
+    use crate::config::DiagnosticStyle;
+    use crate::diagnostics::{
+        cannot_open_paths, format_diagnostic, parse_diagnostics, parse_output, Diagnostic,
+        DiagnosticCounts, Location, Severity, CODE_UNEXPECTED_OUTPUT,
+    };
+    use crate::io::taskoutput::TaskOutput;
+
+    fn diagnostic(file: &str, line: Option<u32>, severity: Severity, key: &str) -> Diagnostic {
+        Diagnostic {
+            file: file.to_string(),
+            line,
+            column: None,
+            severity,
+            key: key.to_string(),
+            message: String::new(),
+        }
+    }
+
+    // Parsed diagnostics without column and message.
+    fn parse_keys(output: &[u8]) -> Vec<Diagnostic> {
+        parse_diagnostics(output)
+            .into_iter()
+            .map(|diagnostic| Diagnostic {
+                column: None,
+                message: String::new(),
+                ..diagnostic
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_cl() {
+        let output = b"sample.cpp\r
+c:\\work\\sample.cpp(10): warning C4996: 'strcpy': This function may be unsafe.\r
+c:\\work\\sample.cpp(12,5): error C2065: 'x': undeclared identifier\r
+c:\\work\\sample.h(3): note: see declaration of 'foo'\r
+c:\\work\\sample.cpp(20): fatal error C1083: Cannot open include file: 'none.h'\r
+c:\\work\\sample.cpp(21): error C4996: 'strcpy': This function may be unsafe.\r
+cl : Command line warning D9025 : overriding '/W3' with '/W4'\r
+";
+        assert_eq!(
+            parse_keys(output),
+            vec![
+                diagnostic("c:\\work\\sample.cpp", Some(10), Severity::Warning, "C4996"),
+                diagnostic("c:\\work\\sample.cpp", Some(12), Severity::Error, "C2065"),
+                diagnostic("c:\\work\\sample.cpp", Some(20), Severity::Error, "C1083"),
+                diagnostic("c:\\work\\sample.cpp", Some(21), Severity::Error, "C4996"),
+                diagnostic("cl", None, Severity::Warning, "D9025"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cl_localized() {
+        let output = "c:\\work\\sample.cpp(10): Warnung C4996: 'strcpy': unsicher\r
+c:\\work\\sample.cpp(11): avertissement C4244: conversion\r
+c:\\work\\sample.cpp(12): Fehler C2065: 'x': nicht deklarierter Bezeichner\r
+c:\\work\\sample.cpp(13): ошибка C4996: 'strcpy': небезопасно\r
+c:\\work\\sample.cpp(14): 경고 C4244: 변환\r
+c:\\work\\sample.cpp(15): schwerwiegender Fehler C1083: Datei kann nicht geöffnet werden\r
+";
+        assert_eq!(
+            parse_keys(output.as_bytes()),
+            vec![
+                diagnostic("c:\\work\\sample.cpp", Some(10), Severity::Warning, "C4996"),
+                diagnostic("c:\\work\\sample.cpp", Some(11), Severity::Warning, "C4244"),
+                diagnostic("c:\\work\\sample.cpp", Some(12), Severity::Error, "C2065"),
+                diagnostic("c:\\work\\sample.cpp", Some(13), Severity::Error, "C4996"),
+                diagnostic("c:\\work\\sample.cpp", Some(14), Severity::Warning, "C4244"),
+                diagnostic("c:\\work\\sample.cpp", Some(15), Severity::Error, "C1083"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cl_unknown_language() {
+        let text = "c:\\work\\sample.cpp(10): figyelmeztetés C4996: 'strcpy'\r
+c:\\work\\sample.cpp(12): hiba C2065: 'x'\r
+";
+        let output = |code| OutputInfo {
+            status: TaskStatus::Code(code),
+            stdout: TaskOutput::from(text.as_bytes().to_vec()),
+            stderr: TaskOutput::default(),
+        };
+        let severities = |output: &OutputInfo| -> Vec<Severity> {
+            parse_output(output)
+                .iter()
+                .map(|diagnostic| diagnostic.severity)
+                .collect()
+        };
+        // Successful compiler run has only warnings.
+        assert_eq!(
+            severities(&output(0)),
+            [Severity::Warning, Severity::Warning]
+        );
+        // Failed one falls back to diagnostic code.
+        assert_eq!(severities(&output(2)), [Severity::Warning, Severity::Error]);
+    }
+
+    #[test]
+    fn test_cannot_open_paths() {
+        let output = "c1xx: fatal error C1083: Cannot open source file: 'c:\\work\\My Game\\a.cpp': Permission denied\r
+c1xx: fatal error C1083: Datei (Quelle) kann nicht geöffnet werden: „b.cpp“: Permission denied\r
+c1xx: fatal error C1083: Не удается открыть файл источник: \"c.cpp\": Permission denied\r
+c:\\work\\d.cpp(1): error C2065: 'x': undeclared identifier\r
+";
+        assert_eq!(
+            cannot_open_paths(&parse_diagnostics(output.as_bytes())),
+            ["c:\\work\\My Game\\a.cpp", "b.cpp", "c.cpp"]
+        );
+    }
+
+    #[test]
+    fn test_parse_clang() {
+        let output = b"In file included from sample.cpp:1:
+./sample.h:3:9: warning: unused variable 'a' [-Wunused-variable]
+    int a = 0;
+        ^
+sample.cpp:5:1: error: use of undeclared identifier 'x'
+C:\\work\\sample.cpp:7:3: fatal error: 'none.h' file not found
+sample.cpp:2:10: note: previous definition is here
+2 warnings and 2 errors generated.
+";
+        assert_eq!(
+            parse_keys(output),
+            vec![
+                diagnostic(
+                    "./sample.h",
+                    Some(3),
+                    Severity::Warning,
+                    "-Wunused-variable"
+                ),
+                diagnostic(
+                    "sample.cpp",
+                    Some(5),
+                    Severity::Error,
+                    "use of undeclared identifier 'x'"
+                ),
+                diagnostic(
+                    "C:\\work\\sample.cpp",
+                    Some(7),
+                    Severity::Error,
+                    "'none.h' file not found"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_message() {
+        let output = b"c:\\work\\sample.cpp(12,5): error C2065: 'x': undeclared identifier\r
+cl : Command line warning D9025 : overriding '/W3' with '/W4'\r
+./sample.h:3:9: warning: unused variable 'a' [-Wunused-variable]
+sample.cpp:5: error: use of undeclared identifier 'x'
+";
+        let located: Vec<(Option<u32>, Option<u32>, String)> = parse_diagnostics(output)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.message))
+            .collect();
+        assert_eq!(
+            located,
+            [
+                (Some(12), Some(5), "'x': undeclared identifier".to_string()),
+                (None, None, "overriding '/W3' with '/W4'".to_string()),
+                (Some(3), Some(9), "unused variable 'a'".to_string()),
+                (
+                    Some(5),
+                    None,
+                    "use of undeclared identifier 'x'".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counts() {
+        let output = b"a.cpp(1): warning C4996: unsafe
+a.cpp(1): warning C4996: unsafe
+a.cpp(2): warning C4996: unsafe
+a.cpp(3): error C2065: undeclared
+";
+        assert_eq!(
+            DiagnosticCounts::new(&parse_diagnostics(output)),
+            DiagnosticCounts {
+                warnings: 3,
+                unique_warnings: 2,
+                errors: 1,
+                unique_errors: 1,
+            }
+        );
+    }
+
+    fn task_error(output: crate::Result<OutputInfo>, style: DiagnosticStyle) -> Option<String> {
+        let result = BuildTaskResult {
+            output,
+            cached: false,
+            duration: Duration::ZERO,
+            phases: BTreeMap::new(),
+            retry: None,
+            note: None,
+        };
+        result.error_message(style, Some(Path::new("c:/work/a.cpp")))
+    }
+
+    fn failed(code: i32) -> crate::Result<OutputInfo> {
+        Ok(OutputInfo {
+            status: TaskStatus::Code(code),
+            stdout: TaskOutput::default(),
+            stderr: TaskOutput::default(),
+        })
+    }
+
+    // Task result with expected messages in MSVC and GCC styles.
+    type ErrorCase = (
+        fn() -> crate::Result<OutputInfo>,
+        &'static str,
+        &'static str,
+    );
+
+    #[test]
+    fn test_format_task_errors() {
+        let cases: Vec<ErrorCase> = vec![
+            (
+                || failed(2),
+                "c:/work/a.cpp(1,1): error OB1001: Task failed with exit code: 2",
+                "c:/work/a.cpp:1:1: error: Task failed with exit code: 2 [OB1001]",
+            ),
+            (
+                || Err(crate::Error::from("cache entry\nis broken")),
+                "c:/work/a.cpp(1,1): error OB1000: Error: cache entry is broken",
+                "c:/work/a.cpp:1:1: error: Error: cache entry is broken [OB1000]",
+            ),
+            (
+                || {
+                    Err(crate::Error::IncludeCaseMismatch {
+                        spelled: "A.h".to_string(),
+                        actual: "a.h".to_string(),
+                    })
+                },
+                "c:/work/a.cpp(1,1): error OB1002: Include file case mismatch: A.h (on disk: a.h)",
+                "c:/work/a.cpp:1:1: error: Include file case mismatch: A.h (on disk: a.h) [OB1002]",
+            ),
+            (
+                || {
+                    Err(crate::Error::OutputMismatch {
+                        missing: vec![PathBuf::from("a.obj")],
+                        unexpected: Vec::new(),
+                    })
+                },
+                "c:/work/a.cpp(1,1): error OB1003: Compiler outputs don't match task: missing [\"a.obj\"], unexpected []",
+                "c:/work/a.cpp:1:1: error: Compiler outputs don't match task: missing [\"a.obj\"], unexpected [] [OB1003]",
+            ),
+        ];
+        for (output, msvc, gcc) in cases {
+            for (style, expected) in [(DiagnosticStyle::Msvc, msvc), (DiagnosticStyle::Gcc, gcc)] {
+                let message = task_error(output(), style).unwrap();
+                assert_eq!(message, expected);
+                // Both shapes are recognized as diagnostics located at source file.
+                assert_eq!(
+                    parse_keys(message.as_bytes()),
+                    vec![diagnostic(
+                        "c:/work/a.cpp",
+                        Some(1),
+                        Severity::Error,
+                        &expected[expected.find("OB").unwrap()..][..6]
+                    )]
+                );
+            }
+        }
+
+        assert_eq!(task_error(failed(0), DiagnosticStyle::Msvc), None);
+        let result = BuildTaskResult {
+            output: failed(2),
+            cached: false,
+            duration: Duration::ZERO,
+            phases: BTreeMap::new(),
+            retry: None,
+            note: None,
+        };
+        assert_eq!(
+            result.error_message(DiagnosticStyle::Msvc, None).unwrap(),
+            "ERROR: Task failed with exit code: 2"
+        );
+    }
+
+    #[test]
+    fn test_format_warnings() {
+        let location = Location {
+            path: Path::new("c:/work/a.cpp"),
+            line: 12,
+            column: 5,
+        };
+        let message = "Unexpected output file c:/work/a.exe";
+        assert_eq!(
+            format_diagnostic(
+                DiagnosticStyle::Msvc,
+                location,
+                Severity::Warning,
+                CODE_UNEXPECTED_OUTPUT,
+                message
+            ),
+            "c:/work/a.cpp(12,5): warning OB4002: Unexpected output file c:/work/a.exe"
+        );
+        assert_eq!(
+            format_diagnostic(
+                DiagnosticStyle::Gcc,
+                location,
+                Severity::Warning,
+                CODE_UNEXPECTED_OUTPUT,
+                message
+            ),
+            "c:/work/a.cpp:12:5: warning: Unexpected output file c:/work/a.exe [OB4002]"
+        );
+    }
+}
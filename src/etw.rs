@@ -0,0 +1,360 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::compiler::BuildTaskResult;
+use crate::config::Config;
+use crate::io::statistic::Statistic;
+use crate::watchdog::TaskPhase;
+use crate::worker::{BuildAction, BuildTask};
+
+#[cfg(windows)]
+mod windows;
+
+// Provider is manifest-free (TraceLogging): event names and field types are carried by events
+// themselves, so WPA, PerfView or `tracerpt` decode them without registration. Provider id is
+// derived from the name the same way as TraceLogging tools do, so `*Octobuild` also works.
+pub const PROVIDER_NAME: &str = "Octobuild";
+// {c81da523-f089-5219-6257-2ff5ef0021be}
+pub const PROVIDER_ID: (u32, u16, u16, [u8; 8]) = (
+    0xc81d_a523,
+    0xf089,
+    0x5219,
+    [0x62, 0x57, 0x2f, 0xf5, 0xef, 0x00, 0x21, 0xbe],
+);
+
+// Event schemas. They are part of octobuild interface: fields are never removed, renamed or
+// retyped, new fields are only appended. Durations are in milliseconds.
+//
+//   TaskStart (opcode start): Task u64, Title string
+//   TaskStop (opcode stop): Task u64, Title string, Outcome string, DurationMs u64,
+//       PreprocessingMs u64, CompilingMs u64, RemoteMs u64, CacheMs u64, RunningMs u64
+//   RemoteDispatch: Task u64, Endpoint string
+//   Rundown: Tasks u64, HitCount u64, MissCount u64, HitBytes u64, MissBytes u64,
+//       RemoteCount u64, InfraFailureCount u64
+//
+// Outcome is `hit` (taken from cache), `compiled` (cache miss), `command` (native command)
+// or `failed`. Task is index of task in build graph. Rundown is written when build graph
+// completes.
+pub const EVENT_TASK_START: &str = "TaskStart";
+pub const EVENT_TASK_STOP: &str = "TaskStop";
+pub const EVENT_REMOTE_DISPATCH: &str = "RemoteDispatch";
+pub const EVENT_RUNDOWN: &str = "Rundown";
+
+// WINEVENT_OPCODE_INFO, WINEVENT_OPCODE_START, WINEVENT_OPCODE_STOP
+const OPCODE_INFO: u8 = 0;
+const OPCODE_START: u8 = 1;
+const OPCODE_STOP: u8 = 2;
+
+// TraceLogging field types: TlgInUNICODESTRING, TlgInUINT64
+const IN_TYPE_STRING: u8 = 1;
+const IN_TYPE_U64: u8 = 10;
+
+thread_local! {
+    // Task executed by current worker thread, for events written deep inside the task.
+    static CURRENT_TASK: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    U64(u64),
+    Str(&'a str),
+}
+
+#[derive(Debug)]
+pub struct Event<'a> {
+    pub name: &'static str,
+    pub opcode: u8,
+    pub fields: Vec<(&'static str, Value<'a>)>,
+}
+
+impl Event<'_> {
+    // TraceLogging event metadata: size, no tags, event name and name and type of every field.
+    #[must_use]
+    pub fn metadata(&self) -> Vec<u8> {
+        let mut result = vec![0, 0, 0];
+        push_name(&mut result, self.name);
+        for (name, value) in &self.fields {
+            push_name(&mut result, name);
+            result.push(match value {
+                Value::U64(_) => IN_TYPE_U64,
+                Value::Str(_) => IN_TYPE_STRING,
+            });
+        }
+        let size = u16::try_from(result.len()).unwrap_or(u16::MAX);
+        result[..2].copy_from_slice(&size.to_le_bytes());
+        result
+    }
+
+    // Field values in declaration order: little-endian numbers and nul-terminated UTF-16 strings.
+    #[must_use]
+    pub fn payload(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        for (_, value) in &self.fields {
+            match value {
+                Value::U64(value) => result.extend(value.to_le_bytes()),
+                Value::Str(value) => {
+                    for c in value.encode_utf16().chain(Some(0)) {
+                        result.extend(c.to_le_bytes());
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+// TraceLogging provider traits: size and provider name.
+#[must_use]
+pub fn provider_traits() -> Vec<u8> {
+    let mut result = vec![0, 0];
+    push_name(&mut result, PROVIDER_NAME);
+    let size = u16::try_from(result.len()).unwrap_or(u16::MAX);
+    result[..2].copy_from_slice(&size.to_le_bytes());
+    result
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    out.extend(name.as_bytes());
+    out.push(0);
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+// Writes build events to ETW when enabled by config. On other platforms events are dropped.
+pub struct EtwTracer {
+    #[cfg(windows)]
+    provider: Option<windows::Provider>,
+}
+
+impl EtwTracer {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        #[cfg(windows)]
+        let provider = if config.etw {
+            windows::Provider::register()
+                .map_err(|e| log::warn!("Cannot register ETW provider: {e}"))
+                .ok()
+        } else {
+            None
+        };
+        #[cfg(not(windows))]
+        let _ = config;
+        EtwTracer {
+            #[cfg(windows)]
+            provider,
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(windows)]
+        {
+            self.provider
+                .as_ref()
+                .is_some_and(windows::Provider::is_enabled)
+        }
+        #[cfg(not(windows))]
+        {
+            false
+        }
+    }
+
+    fn write(&self, event: &Event) {
+        #[cfg(windows)]
+        if let Some(provider) = &self.provider {
+            provider.write(event);
+        }
+        #[cfg(not(windows))]
+        let _ = event;
+    }
+
+    pub fn task_start(&self, index: usize, task: &BuildTask) {
+        let index = index as u64;
+        CURRENT_TASK.with(|current| current.set(Some(index)));
+        if !self.is_enabled() {
+            return;
+        }
+        self.write(&Event {
+            name: EVENT_TASK_START,
+            opcode: OPCODE_START,
+            fields: vec![
+                ("Task", Value::U64(index)),
+                ("Title", Value::Str(&task.title)),
+            ],
+        });
+    }
+
+    pub fn task_stop(&self, index: usize, task: &BuildTask, result: &BuildTaskResult) {
+        CURRENT_TASK.with(|current| current.set(None));
+        if !self.is_enabled() {
+            return;
+        }
+        let compilation = matches!(task.action, BuildAction::Compilation(..));
+        let outcome = match &result.output {
+            Ok(output) if output.success() => match (compilation, result.cached) {
+                (false, _) => "command",
+                (true, true) => "hit",
+                (true, false) => "compiled",
+            },
+            _ => "failed",
+        };
+        self.write(&task_stop_event(
+            index as u64,
+            &task.title,
+            outcome,
+            result.duration,
+            &result.phases,
+        ));
+    }
+
+    // Compilation step of current task is sent to remote builder.
+    pub fn remote_dispatch(&self, endpoint: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(task) = CURRENT_TASK.with(Cell::get) else {
+            return;
+        };
+        self.write(&Event {
+            name: EVENT_REMOTE_DISPATCH,
+            opcode: OPCODE_INFO,
+            fields: vec![
+                ("Task", Value::U64(task)),
+                ("Endpoint", Value::Str(endpoint)),
+            ],
+        });
+    }
+
+    pub fn rundown(&self, tasks: usize, statistic: &Statistic) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.write(&rundown_event(tasks as u64, statistic));
+    }
+}
+
+fn task_stop_event<'a>(
+    task: u64,
+    title: &'a str,
+    outcome: &'a str,
+    duration: Duration,
+    phases: &BTreeMap<TaskPhase, Duration>,
+) -> Event<'a> {
+    let phase = |phase: TaskPhase| Value::U64(phases.get(&phase).copied().map_or(0, millis));
+    Event {
+        name: EVENT_TASK_STOP,
+        opcode: OPCODE_STOP,
+        fields: vec![
+            ("Task", Value::U64(task)),
+            ("Title", Value::Str(title)),
+            ("Outcome", Value::Str(outcome)),
+            ("DurationMs", Value::U64(millis(duration))),
+            ("PreprocessingMs", phase(TaskPhase::Preprocessing)),
+            ("CompilingMs", phase(TaskPhase::Compiling)),
+            ("RemoteMs", phase(TaskPhase::Remote)),
+            ("CacheMs", phase(TaskPhase::Cache)),
+            ("RunningMs", phase(TaskPhase::Running)),
+        ],
+    }
+}
+
+fn rundown_event(tasks: u64, statistic: &Statistic) -> Event<'static> {
+    let counter = |value: &AtomicUsize| Value::U64(value.load(Ordering::Relaxed) as u64);
+    Event {
+        name: EVENT_RUNDOWN,
+        opcode: OPCODE_INFO,
+        fields: vec![
+            ("Tasks", Value::U64(tasks)),
+            ("HitCount", counter(&statistic.hit_count)),
+            ("MissCount", counter(&statistic.miss_count)),
+            ("HitBytes", counter(&statistic.hit_bytes)),
+            ("MissBytes", counter(&statistic.miss_bytes)),
+            ("RemoteCount", counter(&statistic.remote_count)),
+            ("InfraFailureCount", counter(&statistic.infra_failure_count)),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::etw::{
+        provider_traits, rundown_event, task_stop_event, Event, Value, EVENT_TASK_STOP,
+    };
+    use crate::io::statistic::Statistic;
+    use crate::watchdog::TaskPhase;
+
+    #[test]
+    fn test_event_encoding() {
+        let event = Event {
+            name: "Ev",
+            opcode: 0,
+            fields: vec![("N", Value::U64(0x0102)), ("S", Value::Str("aé"))],
+        };
+        assert_eq!(
+            event.metadata(),
+            [12, 0, 0, b'E', b'v', 0, b'N', 0, 10, b'S', 0, 1]
+        );
+        assert_eq!(
+            event.payload(),
+            [2, 1, 0, 0, 0, 0, 0, 0, b'a', 0, 0xe9, 0, 0, 0]
+        );
+        assert_eq!(
+            provider_traits(),
+            [12, 0, b'O', b'c', b't', b'o', b'b', b'u', b'i', b'l', b'd', 0]
+        );
+    }
+
+    // Schemas are documented and must not change silently.
+    #[test]
+    fn test_event_schemas() {
+        let names =
+            |event: &Event| -> Vec<&str> { event.fields.iter().map(|(name, _)| *name).collect() };
+        let phases = BTreeMap::from([
+            (TaskPhase::Preprocessing, Duration::from_millis(15)),
+            (TaskPhase::Cache, Duration::from_millis(2)),
+        ]);
+        let stop = task_stop_event(3, "a.cpp", "hit", Duration::from_millis(20), &phases);
+        assert_eq!(stop.name, EVENT_TASK_STOP);
+        assert_eq!(
+            names(&stop),
+            [
+                "Task",
+                "Title",
+                "Outcome",
+                "DurationMs",
+                "PreprocessingMs",
+                "CompilingMs",
+                "RemoteMs",
+                "CacheMs",
+                "RunningMs"
+            ]
+        );
+        assert_eq!(stop.fields[4].1, Value::U64(15));
+        assert_eq!(stop.fields[5].1, Value::U64(0));
+
+        let statistic = Statistic::new();
+        statistic.hit_count.store(7, Ordering::Relaxed);
+        let rundown = rundown_event(9, &statistic);
+        assert_eq!(
+            names(&rundown),
+            [
+                "Tasks",
+                "HitCount",
+                "MissCount",
+                "HitBytes",
+                "MissBytes",
+                "RemoteCount",
+                "InfraFailureCount"
+            ]
+        );
+        assert_eq!(rundown.fields[1].1, Value::U64(7));
+    }
+}
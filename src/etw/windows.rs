@@ -0,0 +1,415 @@
+use std::ffi::c_void;
+use std::ptr;
+
+use crate::etw::{provider_traits, Event, PROVIDER_ID};
+
+// WINEVENT_CHANNEL_TRACELOGGING: decoders recognize TraceLogging events by it.
+const CHANNEL_TRACELOGGING: u8 = 11;
+// WINEVENT_LEVEL_INFO
+const LEVEL_INFO: u8 = 4;
+// EventProviderSetTraits
+const EVENT_PROVIDER_SET_TRAITS: u32 = 2;
+// EVENT_DATA_DESCRIPTOR_TYPE_EVENT_METADATA, EVENT_DATA_DESCRIPTOR_TYPE_PROVIDER_METADATA
+const DESCRIPTOR_EVENT_METADATA: u8 = 1;
+const DESCRIPTOR_PROVIDER_METADATA: u8 = 2;
+
+#[repr(C)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+// EVENT_DESCRIPTOR
+#[repr(C)]
+struct EventDescriptor {
+    id: u16,
+    version: u8,
+    channel: u8,
+    level: u8,
+    opcode: u8,
+    task: u16,
+    keyword: u64,
+}
+
+// EVENT_DATA_DESCRIPTOR
+#[repr(C)]
+struct EventDataDescriptor {
+    ptr: u64,
+    size: u32,
+    kind: u8,
+    reserved1: u8,
+    reserved2: u16,
+}
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn EventRegister(
+        provider_id: *const Guid,
+        callback: *const c_void,
+        context: *mut c_void,
+        handle: *mut u64,
+    ) -> u32;
+    fn EventUnregister(handle: u64) -> u32;
+    fn EventSetInformation(handle: u64, class: u32, information: *const c_void, length: u32)
+        -> u32;
+    fn EventProviderEnabled(handle: u64, level: u8, keyword: u64) -> u8;
+    fn EventWriteTransfer(
+        handle: u64,
+        descriptor: *const EventDescriptor,
+        activity_id: *const Guid,
+        related_activity_id: *const Guid,
+        count: u32,
+        data: *const EventDataDescriptor,
+    ) -> u32;
+}
+
+fn provider_id() -> Guid {
+    let (data1, data2, data3, data4) = PROVIDER_ID;
+    Guid {
+        data1,
+        data2,
+        data3,
+        data4,
+    }
+}
+
+fn data_descriptor(data: &[u8], kind: u8) -> EventDataDescriptor {
+    EventDataDescriptor {
+        ptr: data.as_ptr() as u64,
+        size: data.len() as u32,
+        kind,
+        reserved1: 0,
+        reserved2: 0,
+    }
+}
+
+pub struct Provider {
+    handle: u64,
+    traits: Vec<u8>,
+}
+
+impl Provider {
+    pub fn register() -> std::io::Result<Self> {
+        let mut handle = 0;
+        let status =
+            unsafe { EventRegister(&provider_id(), ptr::null(), ptr::null_mut(), &mut handle) };
+        if status != 0 {
+            return Err(std::io::Error::from_raw_os_error(status as i32));
+        }
+        let traits = provider_traits();
+        // Without traits provider name is only known from events, so failure is not fatal.
+        unsafe {
+            EventSetInformation(
+                handle,
+                EVENT_PROVIDER_SET_TRAITS,
+                traits.as_ptr().cast(),
+                traits.len() as u32,
+            );
+        }
+        Ok(Provider { handle, traits })
+    }
+
+    // Some trace session listens to provider: events are not encoded otherwise.
+    pub fn is_enabled(&self) -> bool {
+        unsafe { EventProviderEnabled(self.handle, LEVEL_INFO, 0) != 0 }
+    }
+
+    pub fn write(&self, event: &Event) {
+        let descriptor = EventDescriptor {
+            id: 0,
+            version: 0,
+            channel: CHANNEL_TRACELOGGING,
+            level: LEVEL_INFO,
+            opcode: event.opcode,
+            task: 0,
+            keyword: 0,
+        };
+        let metadata = event.metadata();
+        let payload = event.payload();
+        let data = [
+            data_descriptor(&self.traits, DESCRIPTOR_PROVIDER_METADATA),
+            data_descriptor(&metadata, DESCRIPTOR_EVENT_METADATA),
+            data_descriptor(&payload, 0),
+        ];
+        // Events are dropped when session buffers are full, like with any ETW provider.
+        unsafe {
+            EventWriteTransfer(
+                self.handle,
+                &descriptor,
+                ptr::null(),
+                ptr::null(),
+                data.len() as u32,
+                data.as_ptr(),
+            );
+        }
+    }
+}
+
+impl Drop for Provider {
+    fn drop(&mut self) {
+        unsafe {
+            EventUnregister(self.handle);
+        }
+    }
+}
+
+// Starts real-time trace session, so it needs administrator rights or membership in
+// Performance Log Users group: `cargo test --features etw-smoke-test etw`.
+#[cfg(all(test, feature = "etw-smoke-test"))]
+mod test {
+    use std::ffi::c_void;
+    use std::mem::size_of;
+    use std::ptr;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use crate::etw::windows::{provider_id, Guid, Provider, LEVEL_INFO};
+    use crate::etw::{Event, Value, EVENT_RUNDOWN, EVENT_TASK_START, OPCODE_INFO, OPCODE_START};
+
+    const EVENT_TRACE_REAL_TIME_MODE: u32 = 0x0000_0100;
+    const WNODE_FLAG_TRACED_GUID: u32 = 0x0002_0000;
+    const EVENT_TRACE_CONTROL_STOP: u32 = 1;
+    const EVENT_CONTROL_CODE_ENABLE_PROVIDER: u32 = 1;
+    const PROCESS_TRACE_MODE_REAL_TIME: u32 = 0x0000_0100;
+    const PROCESS_TRACE_MODE_EVENT_RECORD: u32 = 0x1000_0000;
+    const INVALID_PROCESSTRACE_HANDLE: u64 = u64::MAX;
+    // EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL: extended data item with TraceLogging metadata.
+    const EXT_TYPE_EVENT_SCHEMA_TL: u16 = 11;
+
+    // WNODE_HEADER
+    #[repr(C)]
+    struct WnodeHeader {
+        buffer_size: u32,
+        provider_id: u32,
+        historical_context: u64,
+        time_stamp: i64,
+        guid: Guid,
+        client_context: u32,
+        flags: u32,
+    }
+
+    // EVENT_TRACE_PROPERTIES
+    #[repr(C)]
+    struct TraceProperties {
+        wnode: WnodeHeader,
+        buffer_size: u32,
+        minimum_buffers: u32,
+        maximum_buffers: u32,
+        maximum_file_size: u32,
+        log_file_mode: u32,
+        flush_timer: u32,
+        enable_flags: u32,
+        age_limit: i32,
+        number_of_buffers: u32,
+        free_buffers: u32,
+        events_lost: u32,
+        buffers_written: u32,
+        log_buffers_lost: u32,
+        real_time_buffers_lost: u32,
+        logger_thread_id: *mut c_void,
+        log_file_name_offset: u32,
+        logger_name_offset: u32,
+    }
+
+    // Session properties are followed by session name.
+    #[repr(C)]
+    struct SessionProperties {
+        properties: TraceProperties,
+        name: [u16; 256],
+    }
+
+    // EVENT_TRACE_LOGFILEW, current event and log file header are not used.
+    #[repr(C)]
+    struct TraceLogfile {
+        log_file_name: *mut u16,
+        logger_name: *mut u16,
+        current_time: i64,
+        buffers_read: u32,
+        process_trace_mode: u32,
+        current_event: [u64; 11],
+        logfile_header: [u64; 35],
+        buffer_callback: *const c_void,
+        buffer_size: u32,
+        filled: u32,
+        events_lost: u32,
+        event_record_callback: Option<unsafe extern "system" fn(*const EventRecord)>,
+        is_kernel_trace: u32,
+        context: *mut c_void,
+    }
+
+    // EVENT_RECORD with EVENT_HEADER fields flattened.
+    #[repr(C)]
+    struct EventRecord {
+        size: u16,
+        header_type: u16,
+        flags: u16,
+        event_property: u16,
+        thread_id: u32,
+        process_id: u32,
+        time_stamp: i64,
+        provider_id: Guid,
+        descriptor: [u8; 16],
+        processor_time: u64,
+        activity_id: Guid,
+        buffer_context: u32,
+        extended_data_count: u16,
+        user_data_length: u16,
+        extended_data: *const ExtendedDataItem,
+        user_data: *const u8,
+        user_context: *mut c_void,
+    }
+
+    // EVENT_HEADER_EXTENDED_DATA_ITEM
+    #[repr(C)]
+    struct ExtendedDataItem {
+        reserved1: u16,
+        ext_type: u16,
+        linkage: u16,
+        data_size: u16,
+        data_ptr: u64,
+    }
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn StartTraceW(handle: *mut u64, name: *const u16, properties: *mut c_void) -> u32;
+        fn ControlTraceW(handle: u64, name: *const u16, properties: *mut c_void, code: u32) -> u32;
+        fn EnableTraceEx2(
+            handle: u64,
+            provider_id: *const Guid,
+            code: u32,
+            level: u8,
+            match_any: u64,
+            match_all: u64,
+            timeout: u32,
+            parameters: *const c_void,
+        ) -> u32;
+        fn OpenTraceW(logfile: *mut TraceLogfile) -> u64;
+        fn ProcessTrace(handles: *const u64, count: u32, start: *const i64, end: *const i64)
+            -> u32;
+        fn CloseTrace(handle: u64) -> u32;
+    }
+
+    type Captured = Mutex<Vec<(String, u8, u64)>>;
+
+    // Collect name, opcode and first field of events written by octobuild provider.
+    unsafe extern "system" fn on_event(record: *const EventRecord) {
+        let record = &*record;
+        let expected = provider_id();
+        if record.provider_id.data1 != expected.data1 || record.provider_id.data4 != expected.data4
+        {
+            return;
+        }
+        if record.extended_data.is_null() || record.user_data.is_null() {
+            return;
+        }
+        let items =
+            std::slice::from_raw_parts(record.extended_data, record.extended_data_count.into());
+        let Some(schema) = items
+            .iter()
+            .find(|item| item.ext_type == EXT_TYPE_EVENT_SCHEMA_TL)
+        else {
+            return;
+        };
+        let schema =
+            std::slice::from_raw_parts(schema.data_ptr as *const u8, schema.data_size.into());
+        // Size, extension byte and nul-terminated name.
+        let name: Vec<u8> = schema[3..]
+            .iter()
+            .take_while(|c| **c != 0)
+            .copied()
+            .collect();
+        let data = std::slice::from_raw_parts(record.user_data, record.user_data_length.into());
+        let first = data
+            .get(..8)
+            .map_or(0, |bytes| u64::from_le_bytes(bytes.try_into().unwrap()));
+        let captured = &*(record.user_context as *const Captured);
+        captured.lock().unwrap().push((
+            String::from_utf8_lossy(&name).into_owned(),
+            record.descriptor[5],
+            first,
+        ));
+    }
+
+    #[test]
+    fn test_etw_session() {
+        let name: Vec<u16> = format!("octobuild-etw-test-{}", std::process::id())
+            .encode_utf16()
+            .chain(Some(0))
+            .collect();
+        let mut session: Box<SessionProperties> = Box::new(unsafe { std::mem::zeroed() });
+        session.properties.wnode.buffer_size = size_of::<SessionProperties>() as u32;
+        session.properties.wnode.flags = WNODE_FLAG_TRACED_GUID;
+        // Query performance counter timestamps.
+        session.properties.wnode.client_context = 1;
+        session.properties.log_file_mode = EVENT_TRACE_REAL_TIME_MODE;
+        session.properties.logger_name_offset = size_of::<TraceProperties>() as u32;
+        let session_ptr: *mut c_void = ptr::addr_of_mut!(*session).cast();
+
+        let mut handle = 0;
+        let status = unsafe { StartTraceW(&mut handle, name.as_ptr(), session_ptr) };
+        assert_eq!(
+            status, 0,
+            "StartTraceW failed, administrator rights are required"
+        );
+
+        let provider = Provider::register().unwrap();
+        let status = unsafe {
+            EnableTraceEx2(
+                handle,
+                &provider_id(),
+                EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+                LEVEL_INFO,
+                0,
+                0,
+                0,
+                ptr::null(),
+            )
+        };
+        assert_eq!(status, 0);
+        let started = Instant::now();
+        while !provider.is_enabled() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let captured: Box<Captured> = Box::default();
+        let mut logfile: TraceLogfile = unsafe { std::mem::zeroed() };
+        logfile.logger_name = name.as_ptr().cast_mut();
+        logfile.process_trace_mode = PROCESS_TRACE_MODE_REAL_TIME | PROCESS_TRACE_MODE_EVENT_RECORD;
+        logfile.event_record_callback = Some(on_event);
+        logfile.context = ptr::addr_of!(*captured).cast_mut().cast();
+        let trace = unsafe { OpenTraceW(&mut logfile) };
+        assert_ne!(trace, INVALID_PROCESSTRACE_HANDLE);
+        let consumer = std::thread::spawn(move || unsafe {
+            ProcessTrace(&trace, 1, ptr::null(), ptr::null())
+        });
+
+        provider.write(&Event {
+            name: EVENT_TASK_START,
+            opcode: OPCODE_START,
+            fields: vec![("Task", Value::U64(42)), ("Title", Value::Str("a.cpp"))],
+        });
+        provider.write(&Event {
+            name: EVENT_RUNDOWN,
+            opcode: OPCODE_INFO,
+            fields: vec![("Tasks", Value::U64(1))],
+        });
+
+        // Stopping session flushes buffers and ends ProcessTrace.
+        std::thread::sleep(Duration::from_secs(2));
+        unsafe {
+            ControlTraceW(handle, ptr::null(), session_ptr, EVENT_TRACE_CONTROL_STOP);
+        }
+        consumer.join().unwrap();
+        unsafe {
+            CloseTrace(trace);
+        }
+        drop(provider);
+
+        let captured = captured.lock().unwrap();
+        assert!(captured.contains(&(EVENT_TASK_START.to_string(), OPCODE_START, 42)));
+        assert!(captured.contains(&(EVENT_RUNDOWN.to_string(), OPCODE_INFO, 1)));
+    }
+}
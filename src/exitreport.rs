@@ -0,0 +1,365 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{stderr, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::{TaskError, TaskStatus};
+use crate::watchdog::TIMEOUT_EXIT_CODE;
+use crate::worker::BuildResult;
+
+// Variable with report path, read before configuration, so configuration errors are reported too.
+pub const DIAGNOSTICS_FILE_ENV: &str = "OCTOBUILD_DIAGNOSTICS_FILE";
+// Version of report layout, fields are only ever added within the same version.
+pub const REPORT_VERSION: u32 = 1;
+// Exit code of process after panic in main thread.
+const PANIC_EXIT_CODE: i32 = 101;
+
+// Why the build exited with non-zero code, in order of precedence for the top-level category.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitCategory {
+    // Compiler was stopped by Ctrl+C or termination request.
+    Interrupted,
+    // Invalid settings, command line or build graph.
+    Configuration,
+    // Cache, disk, network or toolchain problems unrelated to sources.
+    Infrastructure,
+    // Compiler rejected sources.
+    CompileFailure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ExitError {
+    // Task id, same as in diagnostics log.
+    pub task: Option<usize>,
+    pub path: Option<PathBuf>,
+    // Error kind, like `unsupported flags` reason of task error or `exit code` of compiler.
+    pub variant: String,
+    pub message: String,
+    // Original compiler path of task remapped by `tool_remap` rule.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remapped_from: Option<PathBuf>,
+}
+
+// Report written on non-zero exit for tools wrapping octobuild.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ExitDocument {
+    pub version: u32,
+    pub exit_code: i32,
+    pub category: ExitCategory,
+    // The first error of every category.
+    pub errors: BTreeMap<ExitCategory, ExitError>,
+    pub counts: BTreeMap<ExitCategory, usize>,
+}
+
+// Collects errors of the build and writes them as JSON document on non-zero exit.
+#[derive(Default)]
+pub struct ExitReport {
+    path: Mutex<Option<PathBuf>>,
+    errors: Mutex<Vec<(ExitCategory, ExitError)>>,
+}
+
+impl ExitReport {
+    #[must_use]
+    pub fn new() -> Self {
+        ExitReport {
+            path: Mutex::new(env::var_os(DIAGNOSTICS_FILE_ENV).map(PathBuf::from)),
+            errors: Mutex::default(),
+        }
+    }
+
+    // Report path from configuration or command line replaces one from environment.
+    pub fn set_path(&self, path: Option<&Path>) {
+        if let Some(path) = path {
+            *self.path.lock().unwrap() = Some(path.to_path_buf());
+        }
+    }
+
+    // Run main of console tool and write report if it exits with non-zero code or panics.
+    pub fn run(&self, main: impl FnOnce() -> crate::Result<i32>) -> crate::Result<i32> {
+        match panic::catch_unwind(AssertUnwindSafe(main)) {
+            Ok(Ok(0)) => Ok(0),
+            Ok(Ok(code)) => {
+                self.write_report(code);
+                Ok(code)
+            }
+            Ok(Err(e)) => {
+                self.record_error(&e);
+                self.write_report(1);
+                Err(e)
+            }
+            Err(payload) => {
+                self.push(
+                    ExitCategory::Infrastructure,
+                    ExitError {
+                        task: None,
+                        path: None,
+                        variant: "panic".to_string(),
+                        message: panic_message(payload.as_ref()),
+                        remapped_from: None,
+                    },
+                );
+                self.write_report(PANIC_EXIT_CODE);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    // Record failure of completed task.
+    pub fn record_task(&self, result: &BuildResult) {
+        let path = result.task.source().map(Path::to_path_buf);
+        let (category, variant, message) = match &result.result.output {
+            Ok(output) if output.success() => return,
+            Ok(output) => {
+                let (category, variant) = status_category(output.status);
+                (category, variant.to_string(), output.status.to_string())
+            }
+            Err(e) => {
+                let (category, variant) = error_category(e);
+                (category, variant.to_string(), e.to_string())
+            }
+        };
+        self.push(
+            category,
+            ExitError {
+                task: Some(result.id),
+                path,
+                variant,
+                message,
+                remapped_from: result
+                    .task
+                    .command()
+                    .and_then(|command| command.remapped_from.clone()),
+            },
+        );
+    }
+
+    // Record error that stopped the tool. Failed tasks are recorded by `record_task`.
+    pub fn record_error(&self, error: &crate::Error) {
+        if let crate::Error::BuildFailed(_) = error {
+            return;
+        }
+        let message = error.to_string();
+        // Task error also stops the build.
+        if self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, recorded)| recorded.task.is_some() && recorded.message == message)
+        {
+            return;
+        }
+        let (category, variant) = error_category(error);
+        self.push(
+            category,
+            ExitError {
+                task: None,
+                path: error_path(error).map(Path::to_path_buf),
+                variant: variant.to_string(),
+                message,
+                remapped_from: None,
+            },
+        );
+    }
+
+    fn push(&self, category: ExitCategory, error: ExitError) {
+        self.errors.lock().unwrap().push((category, error));
+    }
+
+    #[must_use]
+    pub fn document(&self, exit_code: i32) -> ExitDocument {
+        let mut errors = BTreeMap::new();
+        let mut counts = BTreeMap::new();
+        for (category, error) in self.errors.lock().unwrap().iter() {
+            errors.entry(*category).or_insert_with(|| error.clone());
+            *counts.entry(*category).or_insert(0) += 1;
+        }
+        ExitDocument {
+            version: REPORT_VERSION,
+            exit_code,
+            // Non-zero exit without recorded errors, like exit code of failed command.
+            category: errors
+                .keys()
+                .next()
+                .copied()
+                .unwrap_or(ExitCategory::CompileFailure),
+            errors,
+            counts,
+        }
+    }
+
+    pub fn write(&self, exit_code: i32) -> crate::Result<()> {
+        let Some(path) = self.path.lock().unwrap().clone() else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(&self.document(exit_code))?;
+        fs::write(&path, data).map_err(|e| crate::Error::FileOpen {
+            path,
+            error: Box::new(e.into()),
+        })
+    }
+
+    // Report is best effort, tool exits with its own error anyway.
+    fn write_report(&self, exit_code: i32) {
+        if let Err(e) = self.write(exit_code) {
+            drop(writeln!(stderr(), "ERROR: Can't write exit report: {e}"));
+        }
+    }
+}
+
+fn status_category(status: TaskStatus) -> (ExitCategory, &'static str) {
+    if status.is_interrupted() {
+        (ExitCategory::Interrupted, "interrupted")
+    } else if status.is_crashed() {
+        (ExitCategory::Infrastructure, "compiler crash")
+    } else if status == TaskStatus::Code(TIMEOUT_EXIT_CODE) {
+        (ExitCategory::Infrastructure, "timeout")
+    } else {
+        (ExitCategory::CompileFailure, "exit code")
+    }
+}
+
+fn error_category(error: &crate::Error) -> (ExitCategory, &'static str) {
+    match error {
+        crate::Error::Compilation { error, .. }
+        | crate::Error::Postprocess { error, .. }
+        | crate::Error::FileOpen { error, .. } => error_category(error),
+        crate::Error::Task(TaskError::ToolchainUnavailable { .. }) => {
+            (ExitCategory::Infrastructure, "toolchain unavailable")
+        }
+        crate::Error::Task(e) => (ExitCategory::Configuration, e.reason()),
+        crate::Error::BuildFailed(_) => (ExitCategory::CompileFailure, "build failed"),
+        crate::Error::IncludeCaseMismatch { .. } => {
+            (ExitCategory::CompileFailure, "include case mismatch")
+        }
+        crate::Error::OutputMismatch { .. } => (ExitCategory::CompileFailure, "output mismatch"),
+        crate::Error::Figment(_) => (ExitCategory::Configuration, "configuration"),
+        crate::Error::NoTaskFiles => (ExitCategory::Configuration, "no task files"),
+        crate::Error::CyclesInBuildGraph => (ExitCategory::Configuration, "build graph cycles"),
+        crate::Error::QuoteError(_) => (ExitCategory::Configuration, "quoting"),
+        crate::Error::Cache(_) => (ExitCategory::Infrastructure, "cache"),
+        crate::Error::DiskFull { .. } => (ExitCategory::Infrastructure, "disk full"),
+        crate::Error::FileLocked { .. } => (ExitCategory::Infrastructure, "file locked"),
+        crate::Error::MachineMismatch { .. } => (ExitCategory::Infrastructure, "machine mismatch"),
+        crate::Error::Reqwest(_) => (ExitCategory::Infrastructure, "remote"),
+        crate::Error::IO(_) => (ExitCategory::Infrastructure, "io"),
+        _ => (ExitCategory::Infrastructure, "internal"),
+    }
+}
+
+fn error_path(error: &crate::Error) -> Option<&Path> {
+    match error {
+        crate::Error::Compilation { path, .. }
+        | crate::Error::Postprocess { path, .. }
+        | crate::Error::FileOpen { path, .. }
+        | crate::Error::DiskFull { path, .. }
+        | crate::Error::FileLocked { path, .. }
+        | crate::Error::MachineMismatch { path, .. } => Some(path),
+        _ => None,
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(ToString::to_string)
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+
+    use crate::compiler::TaskError;
+    use crate::exitreport::{ExitCategory, ExitDocument, ExitReport};
+
+    fn read(path: &PathBuf) -> ExitDocument {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_exit_report() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("exit.json");
+        let report = ExitReport::default();
+        report.set_path(Some(&path));
+
+        // Nothing is written on success.
+        assert_eq!(report.run(|| Ok(0)).unwrap(), 0);
+        assert!(!path.exists());
+
+        // Configuration error.
+        let result = report.run(|| Err(crate::Error::NoTaskFiles));
+        assert!(matches!(result, Err(crate::Error::NoTaskFiles)));
+        let document = read(&path);
+        assert_eq!(document.exit_code, 1);
+        assert_eq!(document.category, ExitCategory::Configuration);
+        assert_eq!(
+            document.errors[&ExitCategory::Configuration].variant,
+            "no task files"
+        );
+
+        // Graph validation error.
+        let report = ExitReport::default();
+        report.set_path(Some(&path));
+        report.record_error(&crate::Error::Task(TaskError::PchLanguageMismatch {
+            pch: PathBuf::from("stdafx.pch"),
+            producer: PathBuf::from("stdafx.cpp"),
+            producer_language: "P".to_string(),
+            path: PathBuf::from("a.c"),
+            language: "C".to_string(),
+        }));
+        report.write(1).unwrap();
+        assert_eq!(
+            read(&path).errors[&ExitCategory::Configuration].variant,
+            "precompiled header language"
+        );
+
+        // Infrastructure error inside task wrapper is classified by its cause.
+        let report = ExitReport::default();
+        report.set_path(Some(&path));
+        report.record_error(&crate::Error::Compilation {
+            path: PathBuf::from("a.cpp"),
+            error: Box::new(crate::Error::Task(TaskError::ToolchainUnavailable {
+                path: PathBuf::from("cl.exe"),
+            })),
+        });
+        report.record_error(&crate::Error::Task(TaskError::MissingInput));
+        report.write(1).unwrap();
+        let document = read(&path);
+        assert_eq!(document.category, ExitCategory::Configuration);
+        let error = &document.errors[&ExitCategory::Infrastructure];
+        assert_eq!(error.variant, "toolchain unavailable");
+        assert_eq!(
+            error.path.as_deref(),
+            Some(PathBuf::from("a.cpp").as_path())
+        );
+        assert_eq!(document.counts[&ExitCategory::Configuration], 1);
+        assert_eq!(document.counts[&ExitCategory::Infrastructure], 1);
+
+        // Panic is recorded and goes on.
+        let report = ExitReport::default();
+        report.set_path(Some(&path));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            report.run(|| panic!("broken invariant"))
+        }));
+        assert!(result.is_err());
+        let document = read(&path);
+        assert_eq!(document.exit_code, 101);
+        let error = &document.errors[&ExitCategory::Infrastructure];
+        assert_eq!(
+            (error.variant.as_str(), error.message.as_str()),
+            ("panic", "broken invariant")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::ffi::OsString;
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+use crate::compiler::{CompilationTask, OutputInfo, SharedState, TaskStatus};
+use crate::watchdog::TaskPhase;
+
+// Placeholders of post-compile hook arguments.
+const OBJECT: &str = "{object}";
+const SOURCE: &str = "{source}";
+const KEY: &str = "{key}";
+
+// User command run for every compiled object before it is stored to cache, like signing or stripping.
+// Cache stores post-processed object, so hook command template is a part of cache key.
+pub struct PostCompileHook {
+    template: Vec<String>,
+}
+
+impl PostCompileHook {
+    #[must_use]
+    pub fn new(template: &[String]) -> Self {
+        PostCompileHook {
+            template: template.to_vec(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.template.is_empty()
+    }
+
+    // Digest of command template, None if there is no hook.
+    #[must_use]
+    pub fn digest(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        for arg in &self.template {
+            hasher.update((arg.len() as u64).to_le_bytes());
+            hasher.update(arg.as_bytes());
+        }
+        Some(hex::encode(hasher.finalize()))
+    }
+
+    // Command line with placeholders replaced by task values.
+    fn args(&self, task: &CompilationTask, key: &str) -> Vec<OsString> {
+        self.template
+            .iter()
+            .map(|arg| {
+                OsString::from(
+                    arg.replace(OBJECT, &task.output_object.to_string_lossy())
+                        .replace(SOURCE, &task.input_source.to_string_lossy())
+                        .replace(KEY, key),
+                )
+            })
+            .collect()
+    }
+
+    // Run hook after successful compilation. Hook runs in task working directory and environment,
+    // its exit code becomes task status and its output is appended to compiler output.
+    pub fn run(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        key: &str,
+        output: OutputInfo,
+    ) -> crate::Result<OutputInfo> {
+        if !output.success() {
+            return Ok(output);
+        }
+        let args = self.args(task, key);
+        let Some((program, args)) = args.split_first() else {
+            return Ok(output);
+        };
+        let command_info = &task.shared.command;
+        let mut command = Command::new(program);
+        command.args(args).env_clear().envs(command_info.env.iter());
+        if let Some(dir) = &command_info.current_dir {
+            command.current_dir(dir);
+        }
+        // Watchdog kills stuck hook the same way as stuck compiler.
+        state.monitor.phase(TaskPhase::Compiling);
+        let hook = state.monitor.output(&mut command).map_err(|e| {
+            crate::Error::Generic(format!(
+                "Can't run post-compile hook {}: {e}",
+                program.to_string_lossy()
+            ))
+        })?;
+        Ok(OutputInfo {
+            status: TaskStatus::from(hook.status),
+            stdout: output.stdout.with_suffix(&hook.stdout)?,
+            stderr: output.stderr.with_suffix(&hook.stderr)?,
+        })
+    }
+}
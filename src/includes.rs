@@ -0,0 +1,830 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use path_absolutize::Absolutize;
+use regex::bytes::Regex;
+
+use crate::compiler::{Arg, CompilationTask, OutputInfo, TaskId};
+use crate::config::{DiagnosticStyle, IncludeCaseCheck};
+use crate::diagnostics::{format_diagnostic, Location, Severity, CODE_INCLUDE_CASE_COLLISION};
+use crate::io::taskoutput::TaskOutput;
+
+// Max count of translation units remembered for every include file spelling.
+const MAX_INCLUDING_UNITS: usize = 3;
+
+// Collects file paths from #line directives of preprocessed stream.
+#[derive(Default)]
+pub struct LineDirectiveScanner {
+    line: Vec<u8>,
+    skip: bool,
+    paths: HashSet<String>,
+}
+
+impl LineDirectiveScanner {
+    #[must_use]
+    pub fn new() -> Self {
+        LineDirectiveScanner::default()
+    }
+
+    #[must_use]
+    pub fn into_paths(mut self) -> HashSet<String> {
+        self.parse_line();
+        self.paths
+    }
+
+    fn parse_line(&mut self) {
+        if !self.skip {
+            if let Some(path) = parse_line_directive(&self.line) {
+                if !is_pseudo_file(path.as_bytes()) {
+                    self.paths.insert(path);
+                }
+            }
+        }
+        self.line.clear();
+        self.skip = false;
+    }
+}
+
+impl Write for LineDirectiveScanner {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let eol = rest.iter().position(|c| *c == b'\n');
+            let segment = &rest[..eol.unwrap_or(rest.len())];
+            if !self.skip {
+                self.line.extend_from_slice(segment);
+                // Don't buffer lines that can't be a directive.
+                match self.line.iter().find(|c| !c.is_ascii_whitespace()) {
+                    None | Some(b'#') => {}
+                    Some(_) => {
+                        self.line.clear();
+                        self.skip = true;
+                    }
+                }
+            }
+            match eol {
+                Some(pos) => {
+                    self.parse_line();
+                    rest = &rest[pos + 1..];
+                }
+                None => {
+                    rest = &[];
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Compiler pseudo-files like `<built-in>` and `<command line>` don't exist on disk.
+#[must_use]
+pub fn is_pseudo_file(path: &[u8]) -> bool {
+    path.len() > 1 && path.starts_with(b"<") && path.ends_with(b">")
+}
+
+// Checks whether preprocessed stream has no tokens, only whitespace and directives like
+// `#line` and `#pragma`. Source with everything under `#if 0` preprocesses to such stream.
+#[derive(Default)]
+pub struct EmptyUnitScanner {
+    directive: bool,
+    tokens: bool,
+}
+
+impl EmptyUnitScanner {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        !self.tokens
+    }
+}
+
+impl Write for EmptyUnitScanner {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for c in buf {
+            if self.tokens {
+                break;
+            }
+            match c {
+                b'\n' => self.directive = false,
+                c if self.directive || c.is_ascii_whitespace() => {}
+                b'#' => self.directive = true,
+                _ => self.tokens = true,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[must_use]
+pub fn is_empty_unit(data: &[u8]) -> bool {
+    let mut scanner = EmptyUnitScanner::default();
+    drop(scanner.write_all(data));
+    scanner.is_empty()
+}
+
+// Parse `#line 1 "path"` (MSVC) and `# 1 "path" 1` (clang) directives.
+fn parse_line_directive(line: &[u8]) -> Option<String> {
+    let line = line
+        .trim_ascii_start()
+        .strip_prefix(b"#")?
+        .trim_ascii_start();
+    let line = line
+        .strip_prefix(b"line")
+        .unwrap_or(line)
+        .trim_ascii_start();
+    let digits = line.iter().take_while(|c| c.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let line = line[digits..].trim_ascii_start().strip_prefix(b"\"")?;
+    let mut path = Vec::with_capacity(line.len());
+    let mut iter = line.iter();
+    while let Some(c) = iter.next() {
+        match c {
+            b'"' => {
+                return Some(String::from_utf8_lossy(&path).into_owned());
+            }
+            b'\\' => path.push(*iter.next()?),
+            c => path.push(*c),
+        }
+    }
+    None
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct IncludeCollision {
+    // All spellings of the same include file with including translation units.
+    pub spellings: BTreeMap<String, Vec<PathBuf>>,
+}
+
+// Tracks include files which are referenced with different letter case across the build.
+pub struct IncludeCaseTracker {
+    mode: IncludeCaseCheck,
+    // Lowercased path -> spelling -> first including translation units.
+    spellings: Mutex<HashMap<String, HashMap<String, Vec<PathBuf>>>>,
+    // Absolute spelled path -> path as it is stored on disk.
+    disk_paths: Mutex<HashMap<PathBuf, Option<String>>>,
+}
+
+impl IncludeCaseTracker {
+    #[must_use]
+    pub fn new(mode: IncludeCaseCheck) -> Self {
+        IncludeCaseTracker {
+            mode,
+            spellings: Mutex::new(HashMap::new()),
+            disk_paths: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.mode != IncludeCaseCheck::Off
+    }
+
+    // Paths come from `#line` directives of preprocessed file, see LineDirectiveScanner.
+    // Relative paths are resolved against current directory of the compiler.
+    pub fn check(
+        &self,
+        source: &Path,
+        cwd: Option<&Path>,
+        paths: &HashSet<String>,
+    ) -> crate::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        if self.mode == IncludeCaseCheck::Strict {
+            for path in paths {
+                self.check_disk_case(path, cwd)?;
+            }
+        }
+        self.record(source, paths.iter().cloned());
+        Ok(())
+    }
+
+    pub fn record(&self, source: &Path, paths: impl IntoIterator<Item = String>) {
+        let mut spellings = self.spellings.lock().unwrap();
+        for path in paths {
+            let units = spellings
+                .entry(path.to_lowercase())
+                .or_default()
+                .entry(path)
+                .or_default();
+            // The same source can be compiled several times with different defines.
+            if units.len() < MAX_INCLUDING_UNITS && !units.iter().any(|unit| unit == source) {
+                units.push(source.to_path_buf());
+            }
+        }
+    }
+
+    // Every path component is compared with canonical path, which has letter case of files
+    // on disk.
+    fn check_disk_case(&self, spelled: &str, cwd: Option<&Path>) -> crate::Result<()> {
+        let path = match cwd {
+            Some(cwd) => Path::new(spelled).absolutize_from(cwd)?,
+            None => Path::new(spelled).absolutize()?,
+        };
+        let disk_path = self
+            .disk_paths
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_insert_with(|| disk_path(&path))
+            .clone();
+        let Some(actual) = disk_path else {
+            return Ok(());
+        };
+        let mismatch = Path::new(&actual)
+            .components()
+            .zip(path.components())
+            .any(|(disk, spelled)| matches!(spelled, Component::Normal(_)) && disk != spelled);
+        if mismatch {
+            return Err(crate::Error::IncludeCaseMismatch {
+                spelled: spelled.to_string(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn collisions(&self) -> Vec<IncludeCollision> {
+        let spellings = self.spellings.lock().unwrap();
+        let sorted: BTreeMap<&String, IncludeCollision> = spellings
+            .iter()
+            .filter(|(_, variants)| variants.len() > 1)
+            .map(|(path, variants)| {
+                (
+                    path,
+                    IncludeCollision {
+                        spellings: variants
+                            .iter()
+                            .map(|(spelling, units)| (spelling.clone(), units.clone()))
+                            .collect(),
+                    },
+                )
+            })
+            .collect();
+        sorted.into_values().collect()
+    }
+
+    pub fn print_warnings(
+        &self,
+        out: &mut impl Write,
+        style: DiagnosticStyle,
+    ) -> crate::Result<()> {
+        let collisions = self.collisions();
+        if collisions.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "WARNING: Found include files referenced with different letter case, cache can't be shared with case-sensitive file systems:"
+        )?;
+        for collision in collisions {
+            for (spelling, units) in &collision.spellings {
+                for unit in units {
+                    let message = format!("Include file spelled as {spelling}");
+                    writeln!(
+                        out,
+                        "{}",
+                        format_diagnostic(
+                            style,
+                            Location::file(unit),
+                            Severity::Warning,
+                            CODE_INCLUDE_CASE_COLLISION,
+                            &message
+                        )
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Get path as it is stored on disk. None if file is not found or canonical path differs in more
+// than letter case, like path through symbolic link or short 8.3 name.
+fn disk_path(path: &Path) -> Option<String> {
+    let canonical = std::fs::canonicalize(path).ok()?;
+    let canonical = strip_verbatim(&canonical.to_string_lossy());
+    let fold = |component: Component| component.as_os_str().to_string_lossy().to_lowercase();
+    Path::new(&canonical)
+        .components()
+        .map(fold)
+        .eq(path.components().map(fold))
+        .then_some(canonical)
+}
+
+// Canonical path on Windows is verbatim (`\\?\C:\...`), unlike paths written by compiler.
+fn strip_verbatim(path: &str) -> String {
+    if let Some(share) = path.strip_prefix(r"\\?\UNC\") {
+        return format!(r"\\{share}");
+    }
+    path.strip_prefix(r"\\?\").unwrap_or(path).to_string()
+}
+
+// Include directory parameters of supported compilers.
+const INCLUDE_DIR_PARAMS: [&str; 4] = ["I", "external:I", "isystem", "iquote"];
+
+// Get include directories given by compiler arguments, in command line order.
+#[must_use]
+pub fn include_dir_args(task: &CompilationTask) -> Vec<PathBuf> {
+    task.shared
+        .args
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Param { name, value, .. } if INCLUDE_DIR_PARAMS.contains(&name.as_str()) => {
+                task.shared.command.absolutize(Path::new(value)).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn re_missing_header() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        // cl: `a.cpp(1): fatal error C1083: Cannot open include file: 'foo.h': No such file or directory`
+        // clang: `a.cpp:1:10: fatal error: 'foo.h' file not found`
+        Regex::new(r"(?m)^(?P<file>[^\r\n]+?)(?:\(\d+(?:,\d+)?\)|:\d+:\d+): fatal error(?: C1083:[^'\r\n]*'(?P<cl>[^'\r\n]+)'|: '(?P<clang>[^'\r\n]+)' file not found)").unwrap()
+    })
+}
+
+// Get include file name and the file including it from "include file not found" compiler error.
+fn parse_missing_header(output: &[u8]) -> Option<(String, String)> {
+    let cap = re_missing_header().captures(output)?;
+    let header = cap.name("cl").or_else(|| cap.name("clang"))?;
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    Some((text(header.as_bytes()), text(cap.name("file")?.as_bytes())))
+}
+
+// Get all locations where compiler may look for include file, None if some are not known.
+fn header_candidates(
+    task: &CompilationTask,
+    header: &str,
+    includer: &str,
+    dirs: Option<Vec<PathBuf>>,
+) -> Option<Vec<PathBuf>> {
+    let header = Path::new(header);
+    if header.is_absolute() {
+        return Some(vec![header.to_path_buf()]);
+    }
+    // cl also searches directories of all files in include stack, which are known only for
+    // include written in source itself.
+    let includer = task.shared.command.absolutize(Path::new(includer)).ok()?;
+    if includer != task.input_source {
+        return None;
+    }
+    let dirs = includer
+        .parent()
+        .map(Path::to_path_buf)
+        .into_iter()
+        .chain(dirs?);
+    Some(dirs.map(|dir| dir.join(header)).collect())
+}
+
+struct MissingHeader {
+    candidates: Vec<PathBuf>,
+    output: OutputInfo,
+}
+
+// Remembers tasks that failed on missing include file to skip preprocessing on retry in the same build.
+pub struct MissingHeaderTracker {
+    enabled: bool,
+    // Task -> missing include file.
+    failures: Mutex<HashMap<TaskId, MissingHeader>>,
+}
+
+impl MissingHeaderTracker {
+    #[must_use]
+    pub fn new(enabled: bool) -> Self {
+        MissingHeaderTracker {
+            enabled,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Get previous failure output if the missing include file still doesn't exist.
+    pub fn replay(&self, task: &CompilationTask) -> Option<OutputInfo> {
+        if !self.enabled {
+            return None;
+        }
+        let id = task.id();
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.get(&id)?;
+        if failure.candidates.iter().any(|path| path.exists()) {
+            failures.remove(&id);
+            return None;
+        }
+        Some(failure.output.clone())
+    }
+
+    // Failure is remembered only if all locations of missing include file are known.
+    pub fn remember(
+        &self,
+        task: &CompilationTask,
+        output: &OutputInfo,
+        include_dirs: impl FnOnce() -> Option<Vec<PathBuf>>,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let text = |output: &TaskOutput| output.to_vec().unwrap_or_default();
+        let Some((header, includer)) = parse_missing_header(&text(&output.stderr))
+            .or_else(|| parse_missing_header(&text(&output.stdout)))
+        else {
+            return;
+        };
+        let Some(candidates) = header_candidates(task, &header, &includer, include_dirs()) else {
+            return;
+        };
+        self.failures.lock().unwrap().insert(
+            task.id(),
+            MissingHeader {
+                candidates,
+                output: output.clone(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::compiler::{
+        CommandInfo, CompilationArgs, CompilationTask, CompileStep, CompilerOutput, OutputInfo,
+        PCHUsage, PreprocessResult, SharedState, TaskStatus, Toolchain,
+    };
+    use crate::config::{Config, IncludeCaseCheck};
+    use crate::includes::{
+        is_empty_unit, EmptyUnitScanner, IncludeCaseTracker, LineDirectiveScanner,
+    };
+    use crate::io::taskoutput::TaskOutput;
+
+    fn scan(chunks: &[&str]) -> HashSet<String> {
+        let mut scanner = LineDirectiveScanner::new();
+        for chunk in chunks {
+            scanner.write_all(chunk.as_bytes()).unwrap();
+        }
+        scanner.into_paths()
+    }
+
+    #[test]
+    fn test_scan_line_directives() {
+        let paths = scan(&[
+            "#line 1 \"c:\\\\work\\\\sample.cpp\"\n",
+            "  #li",
+            "ne 1 \"c:\\\\work\\\\Sample.h\"\nvoid hello();\n",
+            "# 5 \"/home/work/sample.h\" 2\r\n",
+            "int a = 1; #line 2 \"bogus.h\"\n",
+            "#line 3 \"c:\\\\work\\\\sample.cpp\"",
+        ]);
+        let expected: HashSet<String> = [
+            "c:\\work\\sample.cpp",
+            "c:\\work\\Sample.h",
+            "/home/work/sample.h",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn test_scan_pseudo_files() {
+        // clang -E -frewrite-includes output.
+        let paths = scan(&[
+            "# 1 \"sample.cpp\"\n",
+            "# 1 \"<built-in>\" 1\n",
+            "# 1 \"<built-in>\" 3\n",
+            "# 400 \"<built-in>\" 3\n",
+            "# 1 \"<command line>\" 1\n",
+            "# 1 \"<built-in>\" 2\n",
+            "# 1 \"sample.cpp\" 2\n",
+            "#if 0 /* expanded by -frewrite-includes */\n",
+            "#include \"sample.h\"\n",
+            "#endif /* expanded by -frewrite-includes */\n",
+            "# 1 \"./sample.h\" 1\n",
+            "void hello();\n",
+            "# 2 \"sample.cpp\" 2\n",
+            "#line 1 \"<command-line>\"\n",
+        ]);
+        let expected: HashSet<String> = ["sample.cpp", "./sample.h"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(paths, expected);
+        assert!(super::is_pseudo_file(b"<stdin>"));
+        assert!(!super::is_pseudo_file(b"<"));
+        assert!(!super::is_pseudo_file(b"c:/work/<x>/a.h"));
+    }
+
+    #[test]
+    fn test_empty_unit() {
+        assert!(is_empty_unit(b""));
+        assert!(is_empty_unit(b" \r\n\t\n"));
+        assert!(is_empty_unit(
+            b"#line 1 \"a.cpp\"\n\n  #pragma once\n# 3 \"a.cpp\" 2\n"
+        ));
+        assert!(!is_empty_unit(b"#line 1 \"a.cpp\"\nint a;\n"));
+        assert!(!is_empty_unit(b"\n  ;"));
+
+        // Directive ends at line end, even if stream is split on write.
+        let mut scanner = EmptyUnitScanner::default();
+        scanner.write_all(b"#line 1 \"a.cpp\"").unwrap();
+        assert!(scanner.is_empty());
+        scanner.write_all(b"\nvoid").unwrap();
+        assert!(!scanner.is_empty());
+    }
+
+    #[test]
+    fn test_collisions() {
+        let tracker = IncludeCaseTracker::new(IncludeCaseCheck::Warn);
+        let paths = |items: &[&str]| items.iter().map(ToString::to_string).collect::<Vec<_>>();
+        tracker.record(
+            Path::new("a.cpp"),
+            paths(&["a.cpp", "c:/x/Foo.h", "c:/x/bar.h"]),
+        );
+        tracker.record(
+            Path::new("b.cpp"),
+            paths(&["b.cpp", "c:/x/foo.h", "c:/x/bar.h"]),
+        );
+        tracker.record(Path::new("c.cpp"), paths(&["c.cpp", "c:/x/Foo.h"]));
+
+        let collisions = tracker.collisions();
+        assert_eq!(collisions.len(), 1);
+        let spellings: Vec<(&String, &Vec<PathBuf>)> = collisions[0].spellings.iter().collect();
+        assert_eq!(
+            spellings,
+            [
+                (
+                    &"c:/x/Foo.h".to_string(),
+                    &vec![PathBuf::from("a.cpp"), PathBuf::from("c.cpp")]
+                ),
+                (&"c:/x/foo.h".to_string(), &vec![PathBuf::from("b.cpp")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strict_disk_case() {
+        let temp = tempfile::tempdir().unwrap();
+        // Temporary directory itself can be reached through symbolic link or short name.
+        let dir = std::fs::canonicalize(temp.path()).unwrap();
+        let dir = PathBuf::from(super::strip_verbatim(&dir.to_string_lossy()));
+        std::fs::create_dir(dir.join("Include")).unwrap();
+        std::fs::write(dir.join("Include").join("Foo.h"), b"").unwrap();
+        std::fs::write(dir.join("Include").join("\u{c4}.h"), b"").unwrap();
+        let tracker = IncludeCaseTracker::new(IncludeCaseCheck::Strict);
+        let spell = |dir_name: &str, name: &str| {
+            dir.join(dir_name).join(name).to_string_lossy().into_owned()
+        };
+        let actual = spell("Include", "Foo.h");
+        assert!(tracker.check_disk_case(&actual, None).is_ok());
+        // Relative path is resolved against current directory of the compiler.
+        assert!(tracker.check_disk_case("Include/Foo.h", Some(&dir)).is_ok());
+        assert_eq!(
+            tracker.disk_paths.lock().unwrap().get(Path::new(&actual)),
+            Some(&Some(actual.clone()))
+        );
+
+        // Case-sensitive file system has no file with other letter case.
+        #[cfg(target_os = "linux")]
+        assert!(tracker
+            .check_disk_case(&spell("Include", "foo.h"), None)
+            .is_ok());
+        // Directory is spelled differently too.
+        #[cfg(any(windows, target_os = "macos"))]
+        for (bad, cwd, on_disk) in [
+            (spell("Include", "foo.h"), None, actual.clone()),
+            (spell("include", "Foo.h"), None, actual.clone()),
+            (
+                "include/Foo.h".to_string(),
+                Some(dir.as_path()),
+                actual.clone(),
+            ),
+            (
+                spell("Include", "\u{e4}.h"),
+                None,
+                spell("Include", "\u{c4}.h"),
+            ),
+        ] {
+            match tracker.check_disk_case(&bad, cwd) {
+                Err(crate::Error::IncludeCaseMismatch { spelled, actual }) => {
+                    assert_eq!(spelled, bad);
+                    assert_eq!(actual, on_disk);
+                }
+                _ => panic!("Include case mismatch is not detected: {bad}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_missing_header() {
+        let parsed =
+            |header: &str, includer: &str| Some((header.to_string(), includer.to_string()));
+        assert_eq!(
+            super::parse_missing_header(b"sample.cpp\r\nc:\\work\\sample.cpp(1): fatal error C1083: Cannot open include file: 'generated.h': No such file or directory\r\n"),
+            parsed("generated.h", "c:\\work\\sample.cpp")
+        );
+        assert_eq!(
+            super::parse_missing_header(b"sample.cpp:1:10: fatal error: 'gen/generated.h' file not found\n#include \"gen/generated.h\"\n"),
+            parsed("gen/generated.h", "sample.cpp")
+        );
+        assert_eq!(
+            super::parse_missing_header(
+                b"c:\\work\\a.h(3,10): fatal error: 'gen.h' file not found\r\n"
+            ),
+            parsed("gen.h", "c:\\work\\a.h")
+        );
+        // Locked source file is not a missing include.
+        assert_eq!(
+            super::parse_missing_header(
+                b"c1xx: fatal error C1083: Cannot open source file: 'a.cpp': Permission denied\r\n"
+            ),
+            None
+        );
+        assert_eq!(
+            super::parse_missing_header(
+                b"sample.cpp(1): error C2065: 'x': undeclared identifier\r\n"
+            ),
+            None
+        );
+    }
+
+    struct StubToolchain {
+        header: PathBuf,
+        // File with failing include directive.
+        includer: PathBuf,
+        include_dirs: Option<Vec<PathBuf>>,
+        preprocess_count: AtomicUsize,
+    }
+
+    impl Toolchain for StubToolchain {
+        fn identifier(&self) -> Option<String> {
+            Some("stub".to_string())
+        }
+
+        fn create_tasks(
+            &self,
+            _: CommandInfo,
+            _: &[String],
+            _: bool,
+        ) -> crate::Result<Vec<CompilationTask>> {
+            unimplemented!()
+        }
+
+        fn run_preprocess(
+            &self,
+            _: &SharedState,
+            _: &CompilationTask,
+        ) -> crate::Result<PreprocessResult> {
+            self.preprocess_count.fetch_add(1, Ordering::SeqCst);
+            if !self.header.exists() {
+                return Ok(PreprocessResult::Failed(OutputInfo {
+                    status: TaskStatus::Code(2),
+                    stdout: TaskOutput::default(),
+                    stderr: format!(
+                        "{}(1): fatal error C1083: Cannot open include file: 'generated.h': No such file or directory\r\n",
+                        self.includer.display()
+                    )
+                    .into_bytes()
+                    .into(),
+                }));
+            }
+            Ok(PreprocessResult::Success(
+                CompilerOutput::Vec(b"int main() {}".to_vec()),
+                Vec::new(),
+            ))
+        }
+
+        fn create_compile_step(
+            &self,
+            task: &CompilationTask,
+            preprocessed: CompilerOutput,
+        ) -> crate::Result<CompileStep> {
+            Ok(CompileStep::new(task, preprocessed, Vec::new()))
+        }
+
+        fn include_dirs(&self, _: &CompilationTask) -> Option<Vec<PathBuf>> {
+            self.include_dirs.clone()
+        }
+
+        fn run_compile(&self, _: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
+            std::fs::write(task.output_object.unwrap(), b"object")?;
+            Ok(OutputInfo {
+                status: TaskStatus::Code(0),
+                stdout: TaskOutput::default(),
+                stderr: TaskOutput::default(),
+            })
+        }
+    }
+
+    fn missing_header_task(dir: &Path) -> CompilationTask {
+        CompilationTask {
+            shared: Arc::new(CompilationArgs {
+                command: CommandInfo::simple(PathBuf::from("stub")),
+                args: Vec::new(),
+                pch_usage: PCHUsage::None,
+                deps_file: None,
+                run_second_cpp: false,
+                notes: Vec::new(),
+                config: None,
+                link: None,
+            }),
+            language: "P".to_string(),
+            input_source: dir.join("sample.cpp"),
+            output_object: dir.join("sample.obj"),
+        }
+    }
+
+    #[test]
+    fn test_missing_header_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            missing_header_check: true,
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let include = dir.path().join("include");
+        let toolchain = StubToolchain {
+            header: include.join("generated.h"),
+            includer: dir.path().join("sample.cpp"),
+            include_dirs: Some(vec![include.clone()]),
+            preprocess_count: AtomicUsize::new(0),
+        };
+        let task = missing_header_task(dir.path());
+
+        // First attempt runs preprocessor and fails.
+        let first = toolchain.compile_task(&state, &task).unwrap();
+        assert_eq!(first.output.status, TaskStatus::Code(2));
+        assert_eq!(toolchain.preprocess_count.load(Ordering::SeqCst), 1);
+
+        // Header is still missing: replay failure without preprocessing.
+        let second = toolchain.compile_task(&state, &task).unwrap();
+        assert_eq!(second.output.status, TaskStatus::Code(2));
+        assert_eq!(
+            second.output.stderr.to_vec().unwrap(),
+            first.output.stderr.to_vec().unwrap()
+        );
+        assert_eq!(toolchain.preprocess_count.load(Ordering::SeqCst), 1);
+
+        // Header appeared in include directory: compile normally.
+        std::fs::create_dir(&include).unwrap();
+        std::fs::write(&toolchain.header, b"").unwrap();
+        let third = toolchain.compile_task(&state, &task).unwrap();
+        assert!(third.output.success());
+        assert_eq!(toolchain.preprocess_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_missing_header_unknown_locations() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            missing_header_check: true,
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let task = missing_header_task(dir.path());
+        let toolchains = [
+            // Compiler with implicit include directories.
+            StubToolchain {
+                header: dir.path().join("generated.h"),
+                includer: dir.path().join("sample.cpp"),
+                include_dirs: None,
+                preprocess_count: AtomicUsize::new(0),
+            },
+            // Include directive in header, directories of include stack are not known.
+            StubToolchain {
+                header: dir.path().join("generated.h"),
+                includer: dir.path().join("nested").join("a.h"),
+                include_dirs: Some(Vec::new()),
+                preprocess_count: AtomicUsize::new(0),
+            },
+        ];
+        for toolchain in toolchains {
+            for _ in 0..2 {
+                let result = toolchain.compile_task(&state, &task).unwrap();
+                assert_eq!(result.output.status, TaskStatus::Code(2));
+            }
+            // Failure is not replayed.
+            assert_eq!(toolchain.preprocess_count.load(Ordering::SeqCst), 2);
+        }
+    }
+}
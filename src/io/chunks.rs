@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Experimental deduplication of preprocessed streams sent to remote builders by content-defined
+// chunks. Translation units share large runs of the same headers included in different order,
+// so stream is cut where rolling hash of the last bytes matches mask (FastCDC with normalized
+// chunking) and equal runs give equal chunks wherever they are. Client sends chunk hashes first,
+// builder answers which chunks it lacks and only those chunks are sent with the task.
+
+// Builder drops all chunks when they take more memory, clients resend them on demand.
+pub const CHUNK_STORE_LIMIT: usize = 512 * 1024 * 1024;
+
+// Chunk size limits, advertised by builder and used by client for streams sent to it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChunkParams {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ChunkParams {
+    fn default() -> Self {
+        ChunkParams {
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
+// Chunked stream sent with task: hashes of all chunks and data of chunks builder lacks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChunkPayload {
+    pub hashes: Vec<String>,
+    // Data of chunks by index in `hashes`.
+    pub chunks: Vec<(usize, Vec<u8>)>,
+    // Hash of the whole stream, checked after reassembly.
+    pub stream_hash: String,
+}
+
+// Client side: chunk boundaries and hashes of preprocessed stream.
+pub struct ChunkedStream<'a> {
+    data: &'a [u8],
+    ranges: Vec<Range<usize>>,
+    hashes: Vec<String>,
+}
+
+// Builder side: chunks received from clients.
+pub struct ChunkStore {
+    limit: usize,
+    chunks: Mutex<StoreChunks>,
+    // Bytes of streams reassembled from stored chunks and from received ones.
+    reused_bytes: AtomicUsize,
+    received_bytes: AtomicUsize,
+}
+
+#[derive(Default)]
+struct StoreChunks {
+    size: usize,
+    chunks: HashMap<String, Arc<Vec<u8>>>,
+}
+
+// Chunk store accounting, separate from other builder storage.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ChunkUsage {
+    pub chunks: usize,
+    pub size: usize,
+    pub reused_bytes: usize,
+    pub received_bytes: usize,
+}
+
+fn hash_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// Random values for gear hash, fixed for all builds: client and builder must cut the same way.
+fn gear() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        // SplitMix64.
+        let mut state: u64 = 0x6f63_746f_6275_696c;
+        let mut gear = [0; 256];
+        for value in &mut gear {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = z ^ (z >> 31);
+        }
+        gear
+    })
+}
+
+// Mask of `bits` highest bits: they depend on the last 64 bytes of gear hash.
+fn high_mask(bits: u32) -> u64 {
+    !(u64::MAX >> bits)
+}
+
+// Length of the first chunk of data. Before average size the stricter mask is used,
+// after it the looser one, so chunk sizes gather around average.
+fn cut_point(data: &[u8], params: ChunkParams) -> usize {
+    let min_size = params.min_size as usize;
+    if data.len() <= min_size {
+        return data.len();
+    }
+    let max_size = data.len().min(params.max_size as usize);
+    let normal_size = max_size.min(params.avg_size as usize);
+    let bits = params.avg_size.max(2).ilog2();
+    let (mask_small, mask_large) = (high_mask(bits + 1), high_mask(bits - 1));
+    let gear = gear();
+    let mut hash: u64 = 0;
+    for (index, byte) in data.iter().enumerate().take(max_size).skip(min_size) {
+        hash = (hash << 1).wrapping_add(gear[usize::from(*byte)]);
+        let mask = if index < normal_size {
+            mask_small
+        } else {
+            mask_large
+        };
+        if hash & mask == 0 {
+            return index + 1;
+        }
+    }
+    max_size
+}
+
+#[must_use]
+pub fn chunk_ranges(data: &[u8], params: ChunkParams) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = offset + cut_point(&data[offset..], params);
+        ranges.push(offset..end);
+        offset = end;
+    }
+    ranges
+}
+
+impl<'a> ChunkedStream<'a> {
+    #[must_use]
+    pub fn new(data: &'a [u8], params: ChunkParams) -> Self {
+        let ranges = chunk_ranges(data, params);
+        let hashes = ranges
+            .iter()
+            .map(|range| hash_hex(&data[range.clone()]))
+            .collect();
+        ChunkedStream {
+            data,
+            ranges,
+            hashes,
+        }
+    }
+
+    #[must_use]
+    pub fn hashes(&self) -> &[String] {
+        &self.hashes
+    }
+
+    // Payload with data of chunks missing on builder.
+    pub fn payload(&self, missing: &[usize]) -> Result<ChunkPayload, Error> {
+        let chunks = missing
+            .iter()
+            .map(|index| {
+                let range = self.ranges.get(*index).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("Unknown chunk: {index}"))
+                })?;
+                Ok((*index, self.data[range.clone()].to_vec()))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(ChunkPayload {
+            hashes: self.hashes.clone(),
+            chunks,
+            stream_hash: hash_hex(self.data),
+        })
+    }
+}
+
+impl ChunkPayload {
+    // Bytes of chunk data sent with payload.
+    #[must_use]
+    pub fn sent_bytes(&self) -> usize {
+        self.chunks.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+impl ChunkStore {
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        ChunkStore {
+            limit,
+            chunks: Mutex::new(StoreChunks::default()),
+            reused_bytes: AtomicUsize::new(0),
+            received_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    // Indexes of chunks that client must send.
+    #[must_use]
+    pub fn missing(&self, hashes: &[String]) -> Vec<usize> {
+        let store = self.chunks.lock().unwrap();
+        hashes
+            .iter()
+            .enumerate()
+            .filter(|(_, hash)| !store.chunks.contains_key(*hash))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Reassemble original stream. Received chunks are verified before they are stored and
+    // the whole stream after reassembly, so broken chunk can't silently change compiled source.
+    pub fn assemble(&self, payload: ChunkPayload) -> Result<Vec<u8>, Error> {
+        let mut received: HashMap<usize, Arc<Vec<u8>>> = HashMap::new();
+        for (index, data) in payload.chunks {
+            let hash = payload.hashes.get(index).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidData, format!("Unknown chunk: {index}"))
+            })?;
+            if hash_hex(&data) != *hash {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Chunk hash mismatch: {hash}"),
+                ));
+            }
+            received.insert(index, Arc::new(data));
+        }
+        let mut chunks = Vec::with_capacity(payload.hashes.len());
+        {
+            let mut store = self.chunks.lock().unwrap();
+            for (index, hash) in payload.hashes.iter().enumerate() {
+                match received.remove(&index) {
+                    Some(data) => {
+                        self.received_bytes.fetch_add(data.len(), Ordering::Relaxed);
+                        store.insert(hash, data.clone(), self.limit);
+                        chunks.push(data);
+                    }
+                    None => match store.chunks.get(hash) {
+                        Some(data) => {
+                            self.reused_bytes.fetch_add(data.len(), Ordering::Relaxed);
+                            chunks.push(data.clone());
+                        }
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::NotFound,
+                                format!("Chunk not found: {hash}"),
+                            ))
+                        }
+                    },
+                }
+            }
+        }
+        let result: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter().copied())
+            .collect();
+        if hash_hex(&result) != payload.stream_hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Stream hash mismatch: {}", payload.stream_hash),
+            ));
+        }
+        Ok(result)
+    }
+
+    #[must_use]
+    pub fn usage(&self) -> ChunkUsage {
+        let store = self.chunks.lock().unwrap();
+        ChunkUsage {
+            chunks: store.chunks.len(),
+            size: store.size,
+            reused_bytes: self.reused_bytes.load(Ordering::Relaxed),
+            received_bytes: self.received_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl StoreChunks {
+    fn insert(&mut self, hash: &str, data: Arc<Vec<u8>>, limit: usize) {
+        if self.size + data.len() > limit {
+            self.chunks.clear();
+            self.size = 0;
+        }
+        self.size += data.len();
+        if let Some(old) = self.chunks.insert(hash.to_string(), data) {
+            self.size -= old.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use crate::io::chunks::{chunk_ranges, ChunkParams, ChunkStore, ChunkedStream};
+
+    const PARAMS: ChunkParams = ChunkParams {
+        min_size: 64,
+        avg_size: 256,
+        max_size: 1024,
+    };
+
+    // Pseudo-random text, so chunk boundaries are found by content.
+    fn block(seed: u32, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'a' + (state >> 16) as u8 % 26
+            })
+            .collect()
+    }
+
+    // Send stream to builder like remote client does.
+    fn send(store: &ChunkStore, data: &[u8]) -> (Vec<u8>, usize) {
+        let stream = ChunkedStream::new(data, PARAMS);
+        let missing = store.missing(stream.hashes());
+        let payload = stream.payload(&missing).unwrap();
+        let sent = payload.sent_bytes();
+        (store.assemble(payload).unwrap(), sent)
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        let data = block(1, 20000);
+        let ranges = chunk_ranges(&data, PARAMS);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+        assert!(ranges[..ranges.len() - 1]
+            .iter()
+            .all(|range| range.len() >= 64 && range.len() <= 1024));
+        assert!(ranges.len() > 20000 / 1024);
+        assert!(chunk_ranges(&[], PARAMS).is_empty());
+    }
+
+    #[test]
+    fn test_shared_interior() {
+        let (a, b, c) = (block(1, 8000), block(2, 8000), block(3, 300));
+        let store = ChunkStore::new(1 << 20);
+        let first = [a.clone(), b.clone()].concat();
+        let (data, sent) = send(&store, &first);
+        assert_eq!(data, first);
+        assert_eq!(sent, first.len());
+
+        // The same headers in other order and after other text are mostly reused.
+        let second = [c.clone(), b, a].concat();
+        let (data, sent) = send(&store, &second);
+        assert_eq!(data, second);
+        assert!(sent < second.len() / 4, "sent {sent} of {}", second.len());
+        let usage = store.usage();
+        assert_eq!(usage.received_bytes, first.len() + sent);
+        assert_eq!(usage.reused_bytes, second.len() - sent);
+        assert_eq!(usage.size, first.len() + sent);
+    }
+
+    #[test]
+    fn test_missing_chunk() {
+        let data = block(1, 4000);
+        let store = ChunkStore::new(1 << 20);
+        let stream = ChunkedStream::new(&data, PARAMS);
+        // Builder says it has everything, but dropped chunks before the task arrived.
+        let payload = stream.payload(&[]).unwrap();
+        let error = store.assemble(payload).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        assert_eq!(store.missing(stream.hashes()).len(), stream.hashes().len());
+    }
+
+    #[test]
+    fn test_corrupted_chunk() {
+        let data = block(1, 4000);
+        let store = ChunkStore::new(1 << 20);
+        let stream = ChunkedStream::new(&data, PARAMS);
+        let mut payload = stream.payload(&store.missing(stream.hashes())).unwrap();
+        payload.chunks[1].1[0] ^= 1;
+        let error = store.assemble(payload).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // Stream hash covers order of chunks.
+        let mut payload = stream.payload(&store.missing(stream.hashes())).unwrap();
+        payload.stream_hash = stream.payload(&[]).unwrap().hashes[0].clone();
+        let error = store.assemble(payload).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_store_limit() {
+        let (a, b) = (block(1, 3000), block(2, 3000));
+        let store = ChunkStore::new(4000);
+        send(&store, &a);
+        send(&store, &b);
+        assert!(store.usage().size <= 4000);
+        // Chunks of the first stream are dropped to fit the limit and are sent again.
+        let (data, sent) = send(&store, &a);
+        assert_eq!(data, a);
+        assert!(sent > 0);
+    }
+}
@@ -13,6 +13,14 @@ impl<S> Counter<S> {
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
 }
 
 impl<R: Read> Counter<R> {
@@ -1,22 +1,27 @@
 use std::cmp::Ordering;
-use std::collections::BTreeSet;
-use std::ffi::OsString;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::SystemTime;
 
-use crate::compiler::OutputInfo;
+use crate::compiler::{OutputInfo, TaskStatus};
 use crate::config::Config;
 use crate::io::binary::{read_exact, read_u64, read_usize, write_u64, write_usize};
 use crate::io::counter::Counter;
+use crate::io::projection::{Payload, Projector};
 use crate::io::statistic::Statistic;
+use crate::io::taskoutput::{OutputLimits, TaskOutput};
+use crate::io::tempfile::{temp_sibling, write_atomic, LOCK_RETRY};
 use thiserror::Error;
 
-const HEADER: &[u8] = b"OBCF\x00\x03";
+const HEADER: &[u8] = b"OBCF\x00\x06";
 const FOOTER: &[u8] = b"END\x00";
 const SUFFIX: &str = ".lz4";
+// File with format of cache entries in cache root, written by the first process using the cache.
+const LAYOUT_MARKER: &str = "octobuild.cache";
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -33,11 +38,27 @@ pub enum CacheError {
 pub struct FileCache {
     cache_dir: PathBuf,
     cache_limit: u64,
+    // Share of cache size by build configuration label, 1 for labels not listed.
+    label_weights: BTreeMap<String, u64>,
     cache_compression_level: u32,
+    output_limits: OutputLimits,
+    // Cache directory is prepared for writing.
+    initialized: OnceLock<()>,
+    // Restores outputs read on demand instead of copying them.
+    projector: Option<Box<dyn Projector>>,
+}
+
+// Cache entry found on disk.
+pub struct CacheEntry {
+    pub key: String,
+    pub path: PathBuf,
+    pub modified: SystemTime,
 }
 
 struct CacheFile {
     path: PathBuf,
+    // Build configuration label, empty if not known.
+    label: String,
     size: u64,
     accessed: SystemTime,
     modified: SystemTime,
@@ -79,39 +100,142 @@ impl FileCache {
         FileCache {
             cache_dir: config.cache.clone(),
             cache_limit: config.cache_limit_mb * 1024 * 1024,
+            label_weights: config.cache_label_weights.clone(),
             cache_compression_level: config.cache_compression_level,
+            output_limits: OutputLimits::new(config),
+            initialized: OnceLock::new(),
+            projector: None,
         }
     }
 
+    #[must_use]
+    pub fn with_projector(self, projector: Option<Box<dyn Projector>>) -> Self {
+        FileCache { projector, ..self }
+    }
+
+    // Create cache directory and layout marker before the first write. Workers of one process
+    // and other processes may do it concurrently, so every step tolerates a winner.
+    fn init_layout(&self) -> crate::Result<()> {
+        if self.initialized.get().is_some() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.cache_dir)?;
+        let marker = self.cache_dir.join(LAYOUT_MARKER);
+        if !is_layout_marker(&marker) {
+            publish_layout_marker(&marker)?;
+        }
+        _ = self.initialized.set(());
+        Ok(())
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.cache_dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + SUFFIX)
+    }
+
+    // Metadata is opaque for cache and is stored only to be read back by read_metadata.
     pub fn run_cached<F: FnOnce() -> crate::Result<OutputInfo>>(
         &self,
         statistic: &Statistic,
+        label: Option<&str>,
         hash: &str,
+        metadata: &[u8],
         outputs: Vec<PathBuf>,
         worker: F,
     ) -> crate::Result<OutputInfo> {
-        let path = self
-            .cache_dir
-            .join(&hash[0..2])
-            .join(hash[2..].to_string() + SUFFIX);
+        let path = self.entry_path(hash);
         // Try to read data from cache.
-        if let Ok(output) = self.read_cache(statistic, &path, &outputs) {
-            return Ok(output);
+        match self.read_cache(statistic, label, &path, &outputs) {
+            Ok(output) => return Ok(output),
+            // Compilation would fail the same way.
+            Err(e) if e.is_infrastructure() => return Err(e),
+            Err(_) => {}
         }
         // Run task and save result to cache.
         let output = worker()?;
-        self.write_cache(statistic, &path, outputs, &output)?;
+        self.write_cache(statistic, label, &path, metadata, outputs, &output)?;
         Ok(output)
     }
 
+    // Mark entry as recently used, so cleanup keeps it. Returns false if there is no such entry.
+    pub fn touch(&self, hash: &str) -> crate::Result<bool> {
+        match OpenOptions::new().write(true).open(self.entry_path(hash)) {
+            Ok(mut file) => {
+                // Rewrite first byte of LZ4 magic number to update modification time.
+                file.write_all(&[4])?;
+                Ok(true)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Remove cache entry. Removing missing entry is not an error.
+    pub fn remove(&self, hash: &str) -> crate::Result<bool> {
+        match fs::remove_file(self.entry_path(hash)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn entries(&self) -> crate::Result<Vec<CacheEntry>> {
+        let mut result = Vec::new();
+        let dirs = match fs::read_dir(&self.cache_dir) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(result),
+            Err(e) => return Err(e.into()),
+        };
+        for dir in dirs {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            // Entries are stored in directories named by first two key characters.
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let Some(rest) = name.strip_suffix(SUFFIX) else {
+                    continue;
+                };
+                result.push(CacheEntry {
+                    key: prefix.clone() + rest,
+                    path: entry.path(),
+                    modified: entry.metadata()?.modified()?,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn read_metadata(&self, entry: &CacheEntry) -> crate::Result<Vec<u8>> {
+        let mut stream = lz4::Decoder::new(File::open(&entry.path)?)?;
+        if read_exact(&mut stream, HEADER.len())? != HEADER {
+            return Err(CacheError::InvalidHeader(entry.path.clone()).into());
+        }
+        read_blob(&mut stream)?;
+        read_blob(&mut stream)
+    }
+
     pub fn cleanup(&self) -> crate::Result<()> {
+        // Projected outputs read evicted entries.
+        if let Some(projector) = &self.projector {
+            projector.finish();
+        }
         let mut files = BTreeSet::<CacheFile>::new();
 
+        let marker = self.cache_dir.join(LAYOUT_MARKER);
         foreach_cache_file(
             &self.cache_dir,
             &mut (|path: PathBuf, metadata: fs::Metadata| -> crate::Result<()> {
+                if path == marker {
+                    return Ok(());
+                }
                 files.insert(CacheFile {
                     path,
+                    label: String::new(),
                     size: metadata.len(),
                     accessed: metadata.accessed()?,
                     modified: metadata.modified()?,
@@ -120,14 +244,17 @@ impl FileCache {
             }),
         )?;
 
-        let mut cache_size: u64 = 0;
-
+        if files.iter().map(|file| file.size).sum::<u64>() <= self.cache_limit {
+            return Ok(());
+        }
         // Attention, reverse order. We want to keep newer files
-        for item in files.iter().rev() {
-            cache_size += item.size;
-            if cache_size > self.cache_limit {
-                fs::remove_file(&item.path)?;
-            }
+        let mut files: Vec<CacheFile> = files.into_iter().rev().collect();
+        for file in &mut files {
+            file.label = read_label(&file.path).unwrap_or_default();
+        }
+        let weight = |label: &str| self.label_weights.get(label).copied().unwrap_or(1);
+        for index in select_evicted(&files, self.cache_limit, weight) {
+            fs::remove_file(&files[index].path)?;
         }
         Ok(())
     }
@@ -135,6 +262,7 @@ impl FileCache {
     fn read_cache(
         &self,
         statistic: &Statistic,
+        label: Option<&str>,
         path: &PathBuf,
         paths: &[PathBuf],
     ) -> crate::Result<OutputInfo> {
@@ -144,28 +272,38 @@ impl FileCache {
             .open(PathBuf::from(path))?;
         file.write_all(&[4])?;
         file.rewind()?;
-        let mut stream = lz4::Decoder::new(Counter::reader(file))?;
+        // Position in decompressed stream locates projected outputs.
+        let mut stream = Counter::reader(lz4::Decoder::new(Counter::reader(file))?);
         if read_exact(&mut stream, HEADER.len())? != HEADER {
             return Err(CacheError::InvalidHeader(path.clone()).into());
         }
+        // Configuration label and metadata.
+        read_blob(&mut stream)?;
+        read_blob(&mut stream)?;
         if read_usize(&mut stream)? != paths.len() {
             return Err(CacheError::PackedFilesMismatch(path.clone()).into());
         }
-        for path in paths {
-            assert!(path.is_absolute());
-            let mut temp_name = OsString::from("~tmp~");
-            temp_name.push(path.file_name().unwrap());
-            let temp = path.with_file_name(temp_name);
-            drop(fs::remove_file(path));
-            match read_cached_file(&mut stream, &temp).and_then(|_| Ok(fs::rename(&temp, path)?)) {
-                Ok(_) => {}
-                Err(e) => {
-                    drop(fs::remove_file(&temp));
-                    return Err(e);
+        let mut projected = Vec::new();
+        for output in paths {
+            assert!(output.is_absolute());
+            drop(fs::remove_file(output));
+            match &self.projector {
+                Some(projector) if projector.accepts(output) => {
+                    let size = read_u64(&mut stream)?;
+                    let payload = Payload {
+                        entry: path.clone(),
+                        offset: stream.len() as u64,
+                        size,
+                    };
+                    if std::io::copy(&mut (&mut stream).take(size), &mut std::io::sink())? != size {
+                        return Err(crate::Error::Generic("Expected end of stream".to_string()));
+                    }
+                    projected.push((output, payload));
                 }
-            };
+                _ => write_atomic(output, |file| read_cached_file(&mut stream, file))?,
+            }
         }
-        let output = read_output(&mut stream)?;
+        let output = read_output(&mut stream, &self.output_limits)?;
         if read_exact(&mut stream, FOOTER.len())? != FOOTER {
             return Err(CacheError::InvalidFooter(path.clone()).into());
         }
@@ -173,27 +311,44 @@ impl FileCache {
         if stream.read(&mut eof)? != 0 {
             return Err(CacheError::InvalidFooter(path.clone()).into());
         }
-        statistic.add_hit(stream.finish().0.len());
+        statistic.add_hit(label, stream.into_inner().finish().0.len());
+        // Entry is complete, so outputs may read it later.
+        for (output, payload) in projected {
+            let projected = self
+                .projector
+                .as_ref()
+                .is_some_and(|projector| projector.project(output, &payload));
+            if !projected {
+                write_atomic(output, |file| payload.read(0, payload.size, file))?;
+            }
+        }
         Ok(output)
     }
 
     fn write_cache(
         &self,
         statistic: &Statistic,
+        label: Option<&str>,
         path: &Path,
+        metadata: &[u8],
         paths: Vec<PathBuf>,
         output: &OutputInfo,
     ) -> crate::Result<()> {
         if !output.success() {
             return Ok(());
         }
+        self.init_layout()?;
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         let mut stream = lz4::EncoderBuilder::new()
             .level(self.cache_compression_level)
-            .build(Counter::writer(File::create(path)?))?;
+            .build(Counter::writer(
+                LOCK_RETRY.run(path, || File::create(path))?,
+            ))?;
         stream.write_all(HEADER)?;
+        write_blob(&mut stream, label.unwrap_or_default().as_bytes())?;
+        write_blob(&mut stream, metadata)?;
         write_usize(&mut stream, paths.len())?;
         for path in paths {
             assert!(path.is_absolute());
@@ -202,11 +357,87 @@ impl FileCache {
         write_output(&mut stream, output)?;
         stream.write_all(FOOTER)?;
         let (writer, result) = stream.finish();
-        statistic.add_miss(writer.len());
+        statistic.add_miss(label, writer.len());
         Ok(result?)
     }
 }
 
+fn is_layout_marker(path: &Path) -> bool {
+    fs::read(path).is_ok_and(|data| data == HEADER)
+}
+
+// Marker is fully written to temporary file and hard linked to its place, so concurrent
+// writers never see partial content and the loser adopts marker of the winner.
+// Missing or outdated marker is replaced by rename, which is atomic too.
+fn publish_layout_marker(path: &Path) -> crate::Result<()> {
+    let temp = temp_sibling(path);
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&temp)
+        .and_then(|mut file| file.write_all(HEADER))
+        .map_err(crate::Error::from)
+        .and_then(|()| match fs::hard_link(&temp, path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && is_layout_marker(path) => Ok(()),
+            // Outdated marker or file system without hard links.
+            Err(_) => LOCK_RETRY.run_written(path, || fs::rename(&temp, path)),
+        });
+    drop(fs::remove_file(&temp));
+    result
+}
+
+// Select files to remove, files are ordered from the newest one. Every configuration label keeps
+// its newest files within weighted share of the limit, so churn of one configuration doesn't evict
+// others. Space unused by small labels goes to the newest remaining files.
+fn select_evicted(files: &[CacheFile], limit: u64, weight: impl Fn(&str) -> u64) -> Vec<usize> {
+    let weights: BTreeMap<&str, u64> = files
+        .iter()
+        .map(|file| (file.label.as_str(), weight(&file.label)))
+        .collect();
+    let total_weight: u64 = weights.values().sum();
+    let mut used: BTreeMap<&str, u64> = BTreeMap::new();
+    let mut full: BTreeSet<&str> = BTreeSet::new();
+    let mut keep = vec![false; files.len()];
+    for (index, file) in files.iter().enumerate() {
+        let label = file.label.as_str();
+        if full.contains(label) {
+            continue;
+        }
+        let share = (u128::from(limit) * u128::from(weights[label])
+            / u128::from(total_weight.max(1))) as u64;
+        let size = used.entry(label).or_default();
+        if *size + file.size <= share {
+            *size += file.size;
+            keep[index] = true;
+        } else {
+            full.insert(label);
+        }
+    }
+    let mut free = limit - used.values().sum::<u64>();
+    let mut evicted = Vec::new();
+    for (index, file) in files.iter().enumerate() {
+        if keep[index] {
+            continue;
+        }
+        if file.size <= free && evicted.is_empty() {
+            free -= file.size;
+        } else {
+            evicted.push(index);
+        }
+    }
+    evicted
+}
+
+// Read configuration label of cache entry.
+fn read_label(path: &Path) -> crate::Result<String> {
+    let mut stream = lz4::Decoder::new(File::open(path)?)?;
+    if read_exact(&mut stream, HEADER.len())? != HEADER {
+        return Err(CacheError::InvalidHeader(path.to_path_buf()).into());
+    }
+    Ok(String::from_utf8_lossy(&read_blob(&mut stream)?).into_owned())
+}
+
 // TODO: Is it doable without a helper function?
 fn foreach_cache_file<F>(dir: &Path, mut func: F) -> crate::Result<()>
 where
@@ -247,17 +478,32 @@ fn write_cached_file<W: Write>(stream: &mut W, path: PathBuf) -> crate::Result<(
     Ok(())
 }
 
-fn read_cached_file(stream: &mut impl Read, path: &Path) -> crate::Result<()> {
+fn read_cached_file(stream: &mut impl Read, file: &mut Counter<File>) -> crate::Result<()> {
     let size = read_u64(stream)?;
-    let mut file = File::create(path)?;
-    file.set_len(size)?;
-    let written = std::io::copy(&mut stream.take(size), &mut file)?;
+    file.get_ref().set_len(size)?;
+    let written = std::io::copy(&mut stream.take(size), file)?;
     if written != size {
         return Err(crate::Error::Generic("Expected end of stream".to_string()));
     }
     Ok(())
 }
 
+// Copy `size` bytes starting from `offset` of decompressed cache entry.
+pub fn read_payload(
+    path: &Path,
+    offset: u64,
+    size: u64,
+    out: &mut impl Write,
+) -> crate::Result<()> {
+    let mut stream = lz4::Decoder::new(File::open(path)?)?;
+    if std::io::copy(&mut (&mut stream).take(offset), &mut std::io::sink())? != offset
+        || std::io::copy(&mut stream.take(size), out)? != size
+    {
+        return Err(crate::Error::Generic("Expected end of stream".to_string()));
+    }
+    Ok(())
+}
+
 fn write_blob(stream: &mut impl Write, blob: &[u8]) -> crate::Result<()> {
     write_usize(stream, blob.len())?;
     stream.write_all(blob)?;
@@ -270,17 +516,307 @@ fn read_blob(stream: &mut impl Read) -> crate::Result<Vec<u8>> {
 }
 
 fn write_output(stream: &mut impl Write, output: &OutputInfo) -> crate::Result<()> {
-    write_blob(stream, &output.stdout)?;
-    write_blob(stream, &output.stderr)?;
+    output.stdout.write_to(stream)?;
+    output.stderr.write_to(stream)?;
     Ok(())
 }
 
-fn read_output(stream: &mut impl Read) -> crate::Result<OutputInfo> {
-    let stdout = read_blob(stream)?;
-    let stderr = read_blob(stream)?;
+fn read_output(stream: &mut impl Read, limits: &OutputLimits) -> crate::Result<OutputInfo> {
+    let stdout = TaskOutput::read_from(stream, limits)?;
+    let stderr = TaskOutput::read_from(stream, limits)?;
     Ok(OutputInfo {
-        status: Some(0),
+        status: TaskStatus::Code(0),
         stdout,
         stderr,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::time::SystemTime;
+
+    use crate::compiler::{OutputInfo, TaskStatus};
+    use crate::config::Config;
+    use crate::io::filecache::{
+        read_label, select_evicted, CacheFile, FileCache, HEADER, LAYOUT_MARKER,
+    };
+    use crate::io::projection::{Payload, Projector};
+    use crate::io::statistic::Statistic;
+    use crate::io::taskoutput::{OutputLimits, TaskOutput};
+
+    // Files from the newest one.
+    fn files(labels: &[(&str, u64)]) -> Vec<CacheFile> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(index, (label, size))| CacheFile {
+                path: PathBuf::from(format!("{index}")),
+                label: label.to_string(),
+                size: *size,
+                accessed: SystemTime::UNIX_EPOCH,
+                modified: SystemTime::UNIX_EPOCH,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_eviction() {
+        // Debug churn is newer than all Release entries.
+        let churn = files(&[
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Release", 10),
+            ("Release", 10),
+            ("Release", 10),
+        ]);
+        // Plain LRU would keep Debug only.
+        assert_eq!(select_evicted(&churn, 60, |_| 1), [3, 4, 5]);
+        assert_eq!(select_evicted(&churn, 40, |_| 1), [2, 3, 4, 5, 8]);
+        // Release has three times more space.
+        assert_eq!(
+            select_evicted(&churn, 40, |label| if label == "Release" { 3 } else { 1 }),
+            [1, 2, 3, 4, 5]
+        );
+        // Space unused by Release goes to the newest Debug entries.
+        let small = files(&[
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Debug", 10),
+            ("Release", 10),
+            ("Debug", 10),
+        ]);
+        assert_eq!(select_evicted(&small, 40, |_| 1), [3, 5]);
+        // Single label is plain LRU.
+        let single = files(&[("", 10), ("", 30), ("", 10), ("", 10)]);
+        assert_eq!(select_evicted(&single, 45, |_| 1), [2, 3]);
+        assert!(select_evicted(&single, 60, |_| 1).is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_init() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let marker = config.cache.join(LAYOUT_MARKER);
+        for stale in [None, Some(&b""[..]), Some(&b"OBCF\x00\x05"[..])] {
+            drop(std::fs::remove_dir_all(&config.cache));
+            if let Some(content) = stale {
+                std::fs::create_dir_all(&config.cache).unwrap();
+                std::fs::write(&marker, content).unwrap();
+            }
+            // Every thread is a separate cache user, like a separate process.
+            let barrier = Barrier::new(32);
+            std::thread::scope(|scope| {
+                for thread in 0..32 {
+                    let (config, barrier, dir) = (&config, &barrier, dir.path());
+                    scope.spawn(move || {
+                        let cache = FileCache::new(config);
+                        let object = dir.join(format!("{thread}.obj"));
+                        barrier.wait();
+                        cache
+                            .run_cached(
+                                &Statistic::new(),
+                                None,
+                                &format!("{:02x}{thread:062}", thread % 4),
+                                b"{}",
+                                vec![object.clone()],
+                                || {
+                                    std::fs::write(&object, "obj")?;
+                                    Ok(OutputInfo::empty())
+                                },
+                            )
+                            .unwrap();
+                    });
+                }
+            });
+            let mut root: Vec<String> = std::fs::read_dir(&config.cache)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            root.sort();
+            assert_eq!(root, ["00", "01", "02", "03", LAYOUT_MARKER]);
+            assert_eq!(std::fs::read(&marker).unwrap(), HEADER);
+            assert_eq!(FileCache::new(&config).entries().unwrap().len(), 32);
+        }
+    }
+
+    #[test]
+    fn test_label_statistic() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let object = dir.path().join("a.obj");
+        let statistic = Statistic::new();
+        let run = |label: Option<&str>, key: &str| {
+            cache
+                .run_cached(&statistic, label, key, b"{}", vec![object.clone()], || {
+                    std::fs::write(&object, "obj")?;
+                    Ok(OutputInfo::empty())
+                })
+                .unwrap();
+        };
+        run(Some("Debug"), "aa11");
+        run(Some("Debug"), "aa11");
+        run(Some("Release"), "bb22");
+        run(None, "cc33");
+
+        let labels = statistic.labels();
+        let counts: Vec<(Option<&str>, usize, usize)> = labels
+            .iter()
+            .map(|(label, stat)| (label.as_deref(), stat.hit_count, stat.miss_count))
+            .collect();
+        assert_eq!(
+            counts,
+            [(None, 0, 1), (Some("Debug"), 1, 1), (Some("Release"), 0, 1)]
+        );
+        let text = statistic.to_string();
+        assert!(text.contains("\n  Debug: hit 1 of 2 (50 %)"));
+        assert!(text.contains("\n  (no configuration): hit 0 of 1 (0 %)"));
+
+        // Label is stored in cache entry for cleanup.
+        let entries = cache.entries().unwrap();
+        let label = |key: &str| {
+            read_label(&entries.iter().find(|entry| entry.key == key).unwrap().path).unwrap()
+        };
+        assert_eq!(label("aa11"), "Debug");
+        assert_eq!(label("cc33"), "");
+    }
+
+    #[test]
+    fn test_spilled_output_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            output_limit_kb: 4,
+            output_spill_kb: 1,
+            ..Config::default()
+        };
+        let cache = FileCache::new(&config);
+        let object = dir.path().join("a.obj");
+        let statistic = Statistic::new();
+        let warnings = b"a.cpp(1): warning C4996: unsafe\n".repeat(1000);
+        let compiles = Cell::new(0);
+        let run = || {
+            cache
+                .run_cached(
+                    &statistic,
+                    None,
+                    "aa11",
+                    b"{}",
+                    vec![object.clone()],
+                    || {
+                        compiles.set(compiles.get() + 1);
+                        std::fs::write(&object, "obj")?;
+                        Ok(OutputInfo {
+                            status: TaskStatus::Code(0),
+                            stdout: TaskOutput::new(warnings.clone(), &OutputLimits::new(&config)),
+                            stderr: TaskOutput::default(),
+                        })
+                    },
+                )
+                .unwrap()
+        };
+
+        let compiled = run();
+        assert!(compiled.stdout.is_spilled());
+        assert_eq!(compiled.stdout.truncated(), warnings.len() as u64 - 4096);
+        let cached = run();
+        assert_eq!(compiles.get(), 1);
+        assert!(cached.stdout.is_spilled());
+        assert_eq!(cached.stdout.truncated(), compiled.stdout.truncated());
+        assert_eq!(
+            cached.stdout.to_vec().unwrap(),
+            compiled.stdout.to_vec().unwrap()
+        );
+        assert!(cached.stderr.is_empty());
+    }
+
+    // Projects object files by writing stub, or refuses them.
+    struct FakeProjector {
+        succeed: bool,
+        payloads: Arc<Mutex<Vec<Payload>>>,
+    }
+
+    impl Projector for FakeProjector {
+        fn accepts(&self, path: &Path) -> bool {
+            path.extension().is_some_and(|ext| ext == "obj")
+        }
+
+        fn project(&self, path: &Path, payload: &Payload) -> bool {
+            self.payloads.lock().unwrap().push(payload.clone());
+            self.succeed && std::fs::write(path, "stub").is_ok()
+        }
+
+        fn finish(&self) {}
+    }
+
+    #[test]
+    fn test_projected_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let object = dir.path().join("a.obj");
+        let pdb = dir.path().join("a.pdb");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let statistic = Statistic::new();
+        let run = |cache: &FileCache| {
+            cache
+                .run_cached(
+                    &statistic,
+                    None,
+                    "aa11",
+                    b"{}",
+                    vec![object.clone(), pdb.clone()],
+                    || {
+                        std::fs::write(&object, &content)?;
+                        std::fs::write(&pdb, "pdb")?;
+                        Ok(OutputInfo::empty())
+                    },
+                )
+                .unwrap();
+        };
+        run(&FileCache::new(&config));
+
+        for succeed in [true, false] {
+            let payloads = Arc::new(Mutex::new(Vec::new()));
+            let projector = FakeProjector {
+                succeed,
+                payloads: payloads.clone(),
+            };
+            run(&FileCache::new(&config).with_projector(Some(Box::new(projector))));
+            let payloads = payloads.lock().unwrap();
+            assert_eq!(payloads.len(), 1);
+            let payload = &payloads[0];
+            assert_eq!(payload.size, content.len() as u64);
+            let mut bytes = Vec::new();
+            payload.read(0, payload.size, &mut bytes).unwrap();
+            assert_eq!(bytes, content);
+            let mut bytes = Vec::new();
+            payload.read(1000, 10, &mut bytes).unwrap();
+            assert_eq!(bytes, content[1000..1010]);
+            assert!(payload.read(1, payload.size, &mut Vec::new()).is_err());
+
+            // Refused projection is restored by copy.
+            let expected: &[u8] = if succeed { b"stub" } else { &content };
+            assert_eq!(std::fs::read(&object).unwrap(), expected);
+            assert_eq!(std::fs::read(&pdb).unwrap(), b"pdb");
+        }
+        let labels = statistic.labels();
+        assert_eq!((labels[0].1.hit_count, labels[0].1.miss_count), (2, 1));
+    }
+}
@@ -1,15 +1,21 @@
 use std::cmp::min;
 use std::collections::vec_deque;
 use std::collections::VecDeque;
-use std::io::Result;
+use std::fs::File;
+use std::io::{BufWriter, Result};
 pub use std::io::{Read, Write};
 use std::mem::MaybeUninit;
+use std::path::Path;
+
+use crate::io::counter::Counter;
 
 const BLOCK_SIZE: usize = 0x10000 - 0x100;
+// Buffer of file writer, so a single system call writes several blocks.
+const FILE_BUFFER_SIZE: usize = 0x100000;
 
 type Block = [u8; BLOCK_SIZE];
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct MemStream {
     size: usize,
     blocks: VecDeque<Block>,
@@ -68,6 +74,21 @@ impl MemStream {
         Ok(self.size)
     }
 
+    // Write stream content to a new file. File length is set upfront, so file system
+    // allocates it at once instead of growing it on every write.
+    pub fn write_to_file(&self, path: &Path) -> crate::Result<usize> {
+        let file = File::create(path).map_err(|e| crate::Error::from(e).for_write(path, 0))?;
+        let mut writer = BufWriter::with_capacity(FILE_BUFFER_SIZE, Counter::writer(file));
+        let result = writer
+            .get_ref()
+            .get_ref()
+            .set_len(self.size as u64)
+            .and_then(|()| self.copy(&mut writer))
+            .and_then(|_| writer.flush());
+        result.map_err(|e| crate::Error::from(e).for_write(path, writer.get_ref().len()))?;
+        Ok(self.size)
+    }
+
     #[allow(clippy::uninit_assumed_init)]
     #[allow(invalid_value)]
     fn write_data(&mut self, buf: &[u8]) -> usize {
@@ -152,6 +173,7 @@ impl<'a> Read for MemReader<'a> {
 #[cfg(test)]
 mod test {
     use crate::io::memstream::{MemStream, BLOCK_SIZE};
+    use std::fs;
     use std::io::{Read, Write};
 
     fn check_stream(write_size: usize, read_size: usize) {
@@ -171,6 +193,12 @@ mod test {
             assert_eq!(expected.len(), actual.len());
             assert_eq!(expected, actual);
         }
+        {
+            let temp = tempfile::tempdir().unwrap();
+            let path = temp.path().join("stream.i");
+            assert_eq!(writer.write_to_file(&path).unwrap(), expected.len());
+            assert_eq!(expected, fs::read(&path).unwrap());
+        }
         {
             let mut actual = Vec::new();
             let mut reader = writer.reader();
@@ -211,4 +239,16 @@ mod test {
     fn test_simple_7() {
         check_stream(7, 7);
     }
+
+    #[test]
+    fn test_empty() {
+        let stream = MemStream::new();
+        assert_eq!(stream.copy(&mut Vec::new()).unwrap(), 0);
+        // Compiler input of source under `#if 0` can be empty.
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("stream.i");
+        assert_eq!(stream.write_to_file(&path).unwrap(), 0);
+        assert!(fs::read(&path).unwrap().is_empty());
+        assert_eq!(stream.reader().read(&mut [0; 16]).unwrap(), 0);
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Experimental deduplication of preprocessed streams sent to remote builders. Translation
+// units of one project usually start with megabytes of the same headers, so stream is cut
+// at fixed checkpoints and every chunk is keyed by hash of the whole stream up to chunk end:
+// equal keys mean equal prefixes, not only equal chunks. Chunk already sent to builder is
+// referenced by key only. Compilation cache key is still hash of the full stream.
+pub const CHECKPOINT_SIZE: usize = 1024 * 1024;
+
+// Builder drops all chunks when they take more memory, clients resend them on demand.
+pub const STORE_LIMIT: usize = 512 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrefixChunk {
+    pub key: String,
+    // Chunk bytes, None if receiver already has them.
+    pub data: Option<Vec<u8>>,
+}
+
+// Chunks of stream prefix and remaining tail, which is sent as is.
+#[derive(Debug)]
+pub struct EncodedStream {
+    pub prefix: Vec<PrefixChunk>,
+    pub suffix: Vec<u8>,
+    // Bytes not sent because receiver already has them.
+    pub saved: usize,
+}
+
+// Client side: remembers chunks sent to every builder during this build.
+pub struct PrefixSender {
+    checkpoint: usize,
+    sent: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+// Builder side: chunks received from clients.
+pub struct PrefixStore {
+    limit: usize,
+    chunks: Mutex<StoreChunks>,
+}
+
+#[derive(Default)]
+struct StoreChunks {
+    size: usize,
+    chunks: HashMap<String, Arc<Vec<u8>>>,
+}
+
+// Keys of chunks ending at every full checkpoint of data.
+fn checkpoint_keys(data: &[u8], checkpoint: usize) -> Vec<String> {
+    let mut hasher = Sha256::new();
+    data.chunks_exact(checkpoint)
+        .map(|chunk| {
+            hasher.update(chunk);
+            hex::encode(hasher.clone().finalize())
+        })
+        .collect()
+}
+
+impl PrefixSender {
+    #[must_use]
+    pub fn new(checkpoint: usize) -> Self {
+        PrefixSender {
+            checkpoint,
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Chunks peer is known to have are referenced by key only.
+    pub fn encode(&self, peer: &str, data: &[u8]) -> EncodedStream {
+        let sent = self.sent.lock().unwrap();
+        let known = sent.get(peer);
+        self.encode_with(data, |key| known.is_some_and(|known| known.contains(key)))
+    }
+
+    // Stream with data of every chunk, for peer which lost them.
+    pub fn encode_full(&self, data: &[u8]) -> EncodedStream {
+        self.encode_with(data, |_| false)
+    }
+
+    fn encode_with(&self, data: &[u8], known: impl Fn(&str) -> bool) -> EncodedStream {
+        let keys = checkpoint_keys(data, self.checkpoint);
+        let mut saved = 0;
+        let prefix = keys
+            .into_iter()
+            .zip(data.chunks_exact(self.checkpoint))
+            .map(|(key, chunk)| {
+                let data = if known(&key) {
+                    saved += chunk.len();
+                    None
+                } else {
+                    Some(chunk.to_vec())
+                };
+                PrefixChunk { key, data }
+            })
+            .collect();
+        EncodedStream {
+            prefix,
+            suffix: data[data.len() - data.len() % self.checkpoint..].to_vec(),
+            saved,
+        }
+    }
+
+    // Peer accepted request with these chunks, so it has their data.
+    pub fn confirm(&self, peer: &str, prefix: &[PrefixChunk]) {
+        let mut sent = self.sent.lock().unwrap();
+        let sent = sent.entry(peer.to_string()).or_default();
+        for chunk in prefix.iter().filter(|chunk| chunk.data.is_some()) {
+            sent.insert(chunk.key.clone());
+        }
+    }
+
+    // Peer dropped chunks.
+    pub fn forget(&self, peer: &str) {
+        self.sent.lock().unwrap().remove(peer);
+    }
+}
+
+impl PrefixStore {
+    #[must_use]
+    pub fn new(limit: usize) -> Self {
+        PrefixStore {
+            limit,
+            chunks: Mutex::new(StoreChunks::default()),
+        }
+    }
+
+    // Reassemble original stream. Chunk keys are verified against reassembled data, so broken
+    // or stale chunk can't silently change compiled source.
+    pub fn decode(&self, prefix: Vec<PrefixChunk>, suffix: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let mut chunks = Vec::with_capacity(prefix.len());
+        {
+            let mut store = self.chunks.lock().unwrap();
+            for chunk in prefix {
+                match chunk.data {
+                    Some(data) => {
+                        let data = Arc::new(data);
+                        if store.size + data.len() > self.limit {
+                            store.chunks.clear();
+                            store.size = 0;
+                        }
+                        if let Some(old) = store.chunks.insert(chunk.key.clone(), data.clone()) {
+                            store.size -= old.len();
+                        }
+                        store.size += data.len();
+                        chunks.push((chunk.key, data));
+                    }
+                    None => match store.chunks.get(&chunk.key) {
+                        Some(data) => chunks.push((chunk.key, data.clone())),
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::NotFound,
+                                format!("Prefix chunk not found: {}", chunk.key),
+                            ))
+                        }
+                    },
+                }
+            }
+        }
+        let mut hasher = Sha256::new();
+        let mut result = Vec::with_capacity(
+            chunks.iter().map(|(_, data)| data.len()).sum::<usize>() + suffix.len(),
+        );
+        for (key, data) in &chunks {
+            hasher.update(data.as_slice());
+            if hex::encode(hasher.clone().finalize()) != *key {
+                self.remove(key);
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Prefix chunk hash mismatch: {key}"),
+                ));
+            }
+            result.extend_from_slice(data);
+        }
+        result.extend(suffix);
+        Ok(result)
+    }
+
+    fn remove(&self, key: &str) {
+        let mut store = self.chunks.lock().unwrap();
+        if let Some(old) = store.chunks.remove(key) {
+            store.size -= old.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+
+    use crate::io::prefix::{PrefixSender, PrefixStore};
+
+    fn stream(prefix: &[u8], tail: &str) -> Vec<u8> {
+        let mut result = prefix.to_vec();
+        result.extend(tail.as_bytes());
+        result
+    }
+
+    #[test]
+    fn test_shared_prefix() {
+        let common: Vec<u8> = (0..100u8).collect();
+        let a = stream(&common, "int a;");
+        let b = stream(&common, "int b; int c;");
+        let sender = PrefixSender::new(16);
+        let store = PrefixStore::new(1024);
+
+        let encoded = sender.encode("builder", &a);
+        assert_eq!(encoded.prefix.len(), 6);
+        assert!(encoded.prefix.iter().all(|chunk| chunk.data.is_some()));
+        assert_eq!(encoded.saved, 0);
+        sender.confirm("builder", &encoded.prefix);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), a);
+
+        // Chunks of common prefix are sent once, only the new chunk goes with data.
+        let encoded = sender.encode("builder", &b);
+        assert_eq!(encoded.prefix.len(), 7);
+        let sent: Vec<bool> = encoded.prefix.iter().map(|c| c.data.is_some()).collect();
+        assert_eq!(sent, [false, false, false, false, false, false, true]);
+        assert_eq!(encoded.saved, 96);
+        sender.confirm("builder", &encoded.prefix);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), b);
+
+        // Other builder gets everything.
+        let encoded = sender.encode("other", &b);
+        assert_eq!(encoded.saved, 0);
+    }
+
+    #[test]
+    fn test_equal_chunk_at_other_offset() {
+        // Key covers whole prefix, so equal chunk after different bytes is not shared.
+        let a = stream(&[1; 16], "xxxxxxxxxxxxxxxx");
+        let b = stream(&[2; 16], "xxxxxxxxxxxxxxxx");
+        let sender = PrefixSender::new(16);
+        let store = PrefixStore::new(1024);
+        let encoded = sender.encode("builder", &a);
+        sender.confirm("builder", &encoded.prefix);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), a);
+        let encoded = sender.encode("builder", &b);
+        assert_eq!(encoded.saved, 0);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), b);
+    }
+
+    #[test]
+    fn test_unconfirmed_chunks() {
+        let data: Vec<u8> = (0..40u8).collect();
+        let sender = PrefixSender::new(16);
+        // Request failed before builder got chunks, they are sent again.
+        sender.encode("builder", &data);
+        let encoded = sender.encode("builder", &data);
+        assert_eq!(encoded.saved, 0);
+        assert!(encoded.prefix.iter().all(|chunk| chunk.data.is_some()));
+    }
+
+    #[test]
+    fn test_missing_chunk() {
+        let data: Vec<u8> = (0..40u8).collect();
+        let sender = PrefixSender::new(16);
+        let encoded = sender.encode("builder", &data);
+        sender.confirm("builder", &encoded.prefix);
+        // Builder restarted or dropped chunks.
+        let store = PrefixStore::new(1024);
+        let encoded = sender.encode("builder", &data);
+        let error = store.decode(encoded.prefix, encoded.suffix).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        // Retry carries all chunks.
+        let encoded = sender.encode_full(&data);
+        assert_eq!(encoded.saved, 0);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), data);
+
+        sender.forget("builder");
+        let encoded = sender.encode("builder", &data);
+        assert_eq!(encoded.saved, 0);
+    }
+
+    #[test]
+    fn test_corrupted_chunk() {
+        let data: Vec<u8> = (0..40u8).collect();
+        let sender = PrefixSender::new(16);
+        let store = PrefixStore::new(1024);
+        let mut encoded = sender.encode("builder", &data);
+        encoded.prefix[1].data.as_mut().unwrap()[0] ^= 1;
+        let error = store.decode(encoded.prefix, encoded.suffix).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_store_limit() {
+        let a: Vec<u8> = (0..32u8).collect();
+        let b: Vec<u8> = (100..132u8).collect();
+        let sender = PrefixSender::new(16);
+        let store = PrefixStore::new(48);
+        let encoded = sender.encode("builder", &a);
+        sender.confirm("builder", &encoded.prefix);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), a);
+        let encoded = sender.encode("builder", &b);
+        sender.confirm("builder", &encoded.prefix);
+        assert_eq!(store.decode(encoded.prefix, encoded.suffix).unwrap(), b);
+        // Chunks of the first stream are dropped to fit the limit.
+        let encoded = sender.encode("builder", &a);
+        assert!(store.decode(encoded.prefix, encoded.suffix).is_err());
+    }
+}
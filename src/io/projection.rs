@@ -0,0 +1,73 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::config::Config;
+use crate::io::filecache::read_payload;
+
+#[cfg(windows)]
+mod windows;
+
+// Object file stored in cache entry: range of decompressed entry stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Payload {
+    pub entry: PathBuf,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Payload {
+    // Copy `length` bytes of the file starting from `start`.
+    pub fn read(&self, start: u64, length: u64, out: &mut impl Write) -> crate::Result<()> {
+        if start
+            .checked_add(length)
+            .filter(|&end| end <= self.size)
+            .is_none()
+        {
+            return Err(format!(
+                "Range {start}+{length} is out of {} bytes of cached file",
+                self.size
+            )
+            .into());
+        }
+        read_payload(&self.entry, self.offset + start, length, out)
+    }
+}
+
+// Places restored outputs whose content is read from cache entry only when somebody reads them,
+// so objects skipped by linker never touch the disk.
+pub trait Projector: Send + Sync {
+    // Output on this path can be projected, otherwise it is copied.
+    fn accepts(&self, path: &Path) -> bool;
+
+    // Place file backed by payload at path, false if it failed and file must be copied.
+    fn project(&self, path: &Path, payload: &Payload) -> bool;
+
+    // Turn projected outputs into regular files. Called before cache entries may be evicted,
+    // `accepts` returns false after that, so outputs are restored by copy.
+    fn finish(&self);
+}
+
+// Projected restore when it is enabled and available, plain copy otherwise.
+#[must_use]
+pub fn create_projector(config: &Config) -> Option<Box<dyn Projector>> {
+    if !config.projfs_restore {
+        return None;
+    }
+    #[cfg(windows)]
+    {
+        match windows::ProjFs::start(config) {
+            Ok(projfs) => Some(Box::new(projfs)),
+            Err(e) => {
+                warn!("ProjFS restore is not available, cached objects are copied: {e}");
+                None
+            }
+        }
+    }
+    #[cfg(not(windows))]
+    {
+        warn!("ProjFS restore is supported on Windows only, cached objects are copied");
+        None
+    }
+}
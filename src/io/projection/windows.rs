@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{c_void, OsStr, OsString};
+use std::fs::File;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use tempfile::TempDir;
+
+use crate::config::Config;
+use crate::io::projection::{Payload, Projector};
+use crate::io::tempfile::{create_owned_dir, write_atomic};
+
+// Shipped with Windows, present when Client-ProjFS optional feature is enabled.
+const PROJFS_LIBRARY: &str = "ProjectedFSLib.dll";
+// PRJ_PLACEHOLDER_ID_LENGTH
+const PLACEHOLDER_ID_LENGTH: usize = 128;
+// S_OK, HRESULT_FROM_WIN32(ERROR_FILE_NOT_FOUND), E_FAIL
+const S_OK: i32 = 0;
+const HRESULT_FILE_NOT_FOUND: i32 = 0x8007_0002_u32 as i32;
+const E_FAIL: i32 = 0x8000_4005_u32 as i32;
+const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
+// DRIVE_FIXED
+const DRIVE_FIXED: u32 = 3;
+// Hydrated data is written in aligned chunks of this size.
+const WRITE_CHUNK: usize = 1024 * 1024;
+// Seconds between 1601-01-01 (FILETIME epoch) and 1970-01-01.
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+// PRJ_PLACEHOLDER_VERSION_INFO
+#[repr(C)]
+struct VersionInfo {
+    provider_id: [u8; PLACEHOLDER_ID_LENGTH],
+    content_id: [u8; PLACEHOLDER_ID_LENGTH],
+}
+
+// PRJ_FILE_BASIC_INFO
+#[repr(C)]
+struct FileBasicInfo {
+    is_directory: u8,
+    file_size: i64,
+    creation_time: i64,
+    last_access_time: i64,
+    last_write_time: i64,
+    change_time: i64,
+    file_attributes: u32,
+}
+
+// PRJ_PLACEHOLDER_INFO without extended attributes, security descriptor and streams.
+#[repr(C)]
+struct PlaceholderInfo {
+    file_basic_info: FileBasicInfo,
+    ea_entries: u32,
+    security_offset: u32,
+    streams_offset: u32,
+    version_info: VersionInfo,
+    variable_data: [u8; 1],
+}
+
+// PRJ_CALLBACK_DATA
+#[repr(C)]
+struct CallbackData {
+    size: u32,
+    flags: u32,
+    context: isize,
+    command_id: i32,
+    file_id: Guid,
+    data_stream_id: Guid,
+    file_path_name: *const u16,
+    version_info: *const VersionInfo,
+    triggering_process_id: u32,
+    triggering_process_image_file_name: *const u16,
+    instance_context: *const c_void,
+}
+
+type EnumerationCallback = unsafe extern "system" fn(*const CallbackData, *const Guid) -> i32;
+type GetEnumerationCallback =
+    unsafe extern "system" fn(*const CallbackData, *const Guid, *const u16, *mut c_void) -> i32;
+type PlaceholderCallback = unsafe extern "system" fn(*const CallbackData) -> i32;
+type FileDataCallback = unsafe extern "system" fn(*const CallbackData, u64, u32) -> i32;
+
+// PRJ_CALLBACKS, optional callbacks are null.
+#[repr(C)]
+struct Callbacks {
+    start_directory_enumeration: EnumerationCallback,
+    end_directory_enumeration: EnumerationCallback,
+    get_directory_enumeration: GetEnumerationCallback,
+    get_placeholder_info: PlaceholderCallback,
+    get_file_data: FileDataCallback,
+    query_file_name: usize,
+    notification: usize,
+    cancel_command: usize,
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn LoadLibraryW(name: *const u16) -> *mut c_void;
+    fn GetProcAddress(module: *mut c_void, name: *const u8) -> *mut c_void;
+    fn GetVolumePathNameW(path: *const u16, volume: *mut u16, length: u32) -> i32;
+    fn GetDriveTypeW(root: *const u16) -> u32;
+    fn GetVolumeInformationW(
+        root: *const u16,
+        name: *mut u16,
+        name_length: u32,
+        serial: *mut u32,
+        max_component_length: *mut u32,
+        flags: *mut u32,
+        file_system: *mut u16,
+        file_system_length: u32,
+    ) -> i32;
+}
+
+// ProjFS functions, loaded at runtime as the library is missing without the optional feature.
+#[derive(Clone, Copy)]
+struct Api {
+    mark_directory:
+        unsafe extern "system" fn(*const u16, *const u16, *const VersionInfo, *const Guid) -> i32,
+    start_virtualizing: unsafe extern "system" fn(
+        *const u16,
+        *const Callbacks,
+        *const c_void,
+        *const c_void,
+        *mut isize,
+    ) -> i32,
+    stop_virtualizing: unsafe extern "system" fn(isize),
+    write_placeholder:
+        unsafe extern "system" fn(isize, *const u16, *const PlaceholderInfo, u32) -> i32,
+    write_file_data: unsafe extern "system" fn(isize, *const Guid, *const c_void, u64, u32) -> i32,
+    allocate_buffer: unsafe extern "system" fn(isize, usize) -> *mut c_void,
+    free_buffer: unsafe extern "system" fn(*mut c_void),
+}
+
+impl Api {
+    fn load() -> std::io::Result<Self> {
+        let module = unsafe { LoadLibraryW(wide(PROJFS_LIBRARY).as_ptr()) };
+        if module.is_null() {
+            return Err(std::io::Error::other(format!(
+                "can't load {PROJFS_LIBRARY}, enable Client-ProjFS optional feature: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        unsafe {
+            Ok(Api {
+                mark_directory: symbol(module, b"PrjMarkDirectoryAsPlaceholder\0")?,
+                start_virtualizing: symbol(module, b"PrjStartVirtualizing\0")?,
+                stop_virtualizing: symbol(module, b"PrjStopVirtualizing\0")?,
+                write_placeholder: symbol(module, b"PrjWritePlaceholderInfo\0")?,
+                write_file_data: symbol(module, b"PrjWriteFileData\0")?,
+                allocate_buffer: symbol(module, b"PrjAllocateAlignedBuffer\0")?,
+                free_buffer: symbol(module, b"PrjFreeAlignedBuffer\0")?,
+            })
+        }
+    }
+}
+
+// Function pointer of type `T` exported by module.
+unsafe fn symbol<T: Copy>(module: *mut c_void, name: &[u8]) -> std::io::Result<T> {
+    let address = GetProcAddress(module, name.as_ptr());
+    if address.is_null() {
+        return Err(std::io::Error::other(format!(
+            "{PROJFS_LIBRARY} has no {}",
+            String::from_utf8_lossy(&name[..name.len() - 1])
+        )));
+    }
+    Ok(std::mem::transmute_copy(&address))
+}
+
+// State shared with ProjFS callbacks through instance context.
+struct Provider {
+    api: Api,
+    // Payload of placeholders by lowercase file name in virtualization root.
+    files: Mutex<HashMap<String, Payload>>,
+}
+
+// Virtualization root in process temporary directory. Restored output is a symbolic link to
+// placeholder in the root, ProjFS asks provider for placeholder content on its first read.
+// Placeholders can't be read without running provider, so outputs are turned into regular files
+// before virtualization stops.
+pub struct ProjFs {
+    provider: Box<Provider>,
+    // Kept while virtualization runs.
+    #[allow(dead_code)]
+    callbacks: Box<Callbacks>,
+    // Owned directory with virtualization root, removed after virtualization stops.
+    dir: TempDir,
+    // PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT, None after virtualization stops.
+    context: Mutex<Option<isize>>,
+    // Outputs linked to placeholders.
+    links: Mutex<Vec<(PathBuf, PathBuf)>>,
+    counter: AtomicUsize,
+}
+
+impl ProjFs {
+    pub fn start(config: &Config) -> std::io::Result<Self> {
+        let api = Api::load()?;
+        let parent = config
+            .temp_dirs
+            .first()
+            .cloned()
+            .unwrap_or_else(env::temp_dir)
+            .join("octobuild-tmp");
+        let dir = create_owned_dir(&parent, "octobuild-projfs")?;
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root)?;
+        if !is_local_ntfs(&root) {
+            return Err(std::io::Error::other(format!(
+                "{} is not on local NTFS volume",
+                root.display()
+            )));
+        }
+        // Symbolic link creation needs Developer Mode or privilege.
+        let probe = dir.path().join("probe");
+        std::os::windows::fs::symlink_file(root.join("probe"), &probe)?;
+        std::fs::remove_file(&probe)?;
+
+        let id = uuid::Uuid::new_v4();
+        let (data1, data2, data3, data4) = id.as_fields();
+        let instance = Guid {
+            data1,
+            data2,
+            data3,
+            data4: *data4,
+        };
+        let root_name = wide(&root);
+        check(unsafe {
+            (api.mark_directory)(root_name.as_ptr(), ptr::null(), ptr::null(), &instance)
+        })?;
+        let provider = Box::new(Provider {
+            api,
+            files: Mutex::default(),
+        });
+        let callbacks = Box::new(Callbacks {
+            start_directory_enumeration: start_enumeration,
+            end_directory_enumeration: end_enumeration,
+            get_directory_enumeration: get_enumeration,
+            get_placeholder_info,
+            get_file_data,
+            query_file_name: 0,
+            notification: 0,
+            cancel_command: 0,
+        });
+        let mut context = 0;
+        check(unsafe {
+            (api.start_virtualizing)(
+                root_name.as_ptr(),
+                &*callbacks,
+                ptr::from_ref::<Provider>(&provider).cast(),
+                ptr::null(),
+                &mut context,
+            )
+        })?;
+        Ok(ProjFs {
+            provider,
+            callbacks,
+            dir,
+            context: Mutex::new(Some(context)),
+            links: Mutex::default(),
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    fn write_placeholder(&self, context: isize, name: &str, size: u64) -> std::io::Result<()> {
+        let now = filetime(SystemTime::now());
+        let info = PlaceholderInfo {
+            file_basic_info: FileBasicInfo {
+                is_directory: 0,
+                file_size: i64::try_from(size).map_err(std::io::Error::other)?,
+                creation_time: now,
+                last_access_time: now,
+                last_write_time: now,
+                change_time: now,
+                file_attributes: FILE_ATTRIBUTE_ARCHIVE,
+            },
+            ea_entries: 0,
+            security_offset: 0,
+            streams_offset: 0,
+            version_info: VersionInfo {
+                provider_id: [0; PLACEHOLDER_ID_LENGTH],
+                content_id: [0; PLACEHOLDER_ID_LENGTH],
+            },
+            variable_data: [0],
+        };
+        check(unsafe {
+            (self.provider.api.write_placeholder)(
+                context,
+                wide(name).as_ptr(),
+                &info,
+                std::mem::size_of::<PlaceholderInfo>() as u32,
+            )
+        })
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+}
+
+impl Projector for ProjFs {
+    fn accepts(&self, path: &Path) -> bool {
+        self.context.lock().unwrap().is_some() && path.parent().is_some_and(is_local_ntfs)
+    }
+
+    fn project(&self, path: &Path, payload: &Payload) -> bool {
+        let context = self.context.lock().unwrap();
+        let Some(context) = *context else {
+            return false;
+        };
+        let name = format!(
+            "{}-{}",
+            self.counter.fetch_add(1, Ordering::Relaxed),
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let placeholder = self.root().join(&name);
+        let key = name.to_lowercase();
+        self.provider
+            .files
+            .lock()
+            .unwrap()
+            .insert(key.clone(), payload.clone());
+        let result = self
+            .write_placeholder(context, &name, payload.size)
+            .and_then(|()| std::os::windows::fs::symlink_file(&placeholder, path));
+        match result {
+            Ok(()) => {
+                self.links
+                    .lock()
+                    .unwrap()
+                    .push((path.to_path_buf(), placeholder));
+                true
+            }
+            Err(e) => {
+                warn!("Can't project {}, it is copied: {e}", path.display());
+                self.provider.files.lock().unwrap().remove(&key);
+                drop(std::fs::remove_file(&placeholder));
+                false
+            }
+        }
+    }
+
+    fn finish(&self) {
+        // Stops new projections, running ones complete first.
+        let mut context = self.context.lock().unwrap();
+        let Some(active) = context.take() else {
+            return;
+        };
+        for (output, placeholder) in self.links.lock().unwrap().drain(..) {
+            // Output replaced by task or user doesn't point to placeholder anymore.
+            if std::fs::read_link(&output).ok().as_deref() != Some(placeholder.as_path()) {
+                continue;
+            }
+            // Reading placeholder hydrates it, link is replaced by regular file.
+            let result = File::open(&placeholder)
+                .map_err(crate::Error::from)
+                .and_then(|mut source| {
+                    write_atomic(&output, |file| {
+                        std::io::copy(&mut source, file)?;
+                        Ok(())
+                    })
+                });
+            if let Err(e) = result {
+                warn!("Can't turn projected {} into file: {e}", output.display());
+                drop(std::fs::remove_file(&output));
+            }
+        }
+        unsafe { (self.provider.api.stop_virtualizing)(active) };
+        self.provider.files.lock().unwrap().clear();
+    }
+}
+
+impl Drop for ProjFs {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn check(result: i32) -> std::io::Result<()> {
+    if result < 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+fn wide(value: impl AsRef<OsStr>) -> Vec<u16> {
+    value.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+unsafe fn from_wide(value: *const u16) -> OsString {
+    let mut length = 0;
+    while *value.add(length) != 0 {
+        length += 1;
+    }
+    OsString::from_wide(std::slice::from_raw_parts(value, length))
+}
+
+// FILETIME: 100-nanosecond intervals since 1601-01-01.
+fn filetime(time: SystemTime) -> i64 {
+    let since_unix = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let intervals = (since_unix.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000
+        + u64::from(since_unix.subsec_nanos() / 100);
+    i64::try_from(intervals).unwrap_or(i64::MAX)
+}
+
+// ProjFS works only on local NTFS volumes, and links to network share may be resolved by
+// another machine.
+fn is_local_ntfs(path: &Path) -> bool {
+    let mut volume = vec![0u16; 1024];
+    let mut file_system = vec![0u16; 64];
+    unsafe {
+        if GetVolumePathNameW(
+            wide(path).as_ptr(),
+            volume.as_mut_ptr(),
+            volume.len() as u32,
+        ) == 0
+            || GetDriveTypeW(volume.as_ptr()) != DRIVE_FIXED
+            || GetVolumeInformationW(
+                volume.as_ptr(),
+                ptr::null_mut(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                file_system.as_mut_ptr(),
+                file_system.len() as u32,
+            ) == 0
+        {
+            return false;
+        }
+    }
+    let length = file_system.iter().position(|c| *c == 0).unwrap_or(0);
+    String::from_utf16_lossy(&file_system[..length]) == "NTFS"
+}
+
+unsafe fn provider<'a>(data: *const CallbackData) -> &'a Provider {
+    &*((*data).instance_context.cast::<Provider>())
+}
+
+// Only placeholders written by provider exist in the root, enumeration adds nothing to them.
+unsafe extern "system" fn start_enumeration(_: *const CallbackData, _: *const Guid) -> i32 {
+    S_OK
+}
+
+unsafe extern "system" fn end_enumeration(_: *const CallbackData, _: *const Guid) -> i32 {
+    S_OK
+}
+
+unsafe extern "system" fn get_enumeration(
+    _: *const CallbackData,
+    _: *const Guid,
+    _: *const u16,
+    _: *mut c_void,
+) -> i32 {
+    S_OK
+}
+
+unsafe extern "system" fn get_placeholder_info(_: *const CallbackData) -> i32 {
+    HRESULT_FILE_NOT_FOUND
+}
+
+unsafe extern "system" fn get_file_data(
+    data: *const CallbackData,
+    offset: u64,
+    length: u32,
+) -> i32 {
+    let provider = provider(data);
+    let name = from_wide((*data).file_path_name)
+        .to_string_lossy()
+        .to_lowercase();
+    let Some(payload) = provider.files.lock().unwrap().get(&name).cloned() else {
+        return HRESULT_FILE_NOT_FOUND;
+    };
+    let mut content = Vec::with_capacity(length as usize);
+    if let Err(e) = payload.read(offset, u64::from(length), &mut content) {
+        warn!("Can't read projected file {name} from cache: {e}");
+        return E_FAIL;
+    }
+    let context = (*data).context;
+    for (index, chunk) in content.chunks(WRITE_CHUNK).enumerate() {
+        // Data must be written from buffer allocated for the volume sector alignment.
+        let buffer = (provider.api.allocate_buffer)(context, chunk.len());
+        if buffer.is_null() {
+            return E_FAIL;
+        }
+        ptr::copy_nonoverlapping(chunk.as_ptr(), buffer.cast::<u8>(), chunk.len());
+        let result = (provider.api.write_file_data)(
+            context,
+            &(*data).data_stream_id,
+            buffer,
+            offset + (index * WRITE_CHUNK) as u64,
+            chunk.len() as u32,
+        );
+        (provider.api.free_buffer)(buffer);
+        if result < 0 {
+            return result;
+        }
+    }
+    S_OK
+}
@@ -0,0 +1,643 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use crate::schedule::seeded_random;
+use crate::utils::{hash_stream, path_key};
+
+// Shard count, keeps lock contention low for concurrent tasks.
+const SHARDS: usize = 16;
+// Retained entries limit for the whole build.
+pub const DEFAULT_CAPACITY: usize = 32 * 1024;
+// Modification time further in future is caused by clock skew of network share or VM.
+const MTIME_TOLERANCE: Duration = Duration::from_secs(60);
+// Share of reused hashes verified by file content, up to limit per build.
+const SPOT_CHECK_RATE: f64 = 1.0 / 32.0;
+const SPOT_CHECK_LIMIT: usize = 8;
+// Count of files with future modification time named in warning.
+const WORST_OFFENDERS: usize = 5;
+
+#[derive(Clone)]
+pub struct FileHash {
+    pub hash: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+pub trait FileHasher {
+    fn file_hash(&self, path: &Path) -> Result<FileHash, Error>;
+}
+
+type FileStat = (u64, SystemTime);
+
+struct FileEntry {
+    // None if file can't be read.
+    stat: Option<FileStat>,
+    // Content hash is computed on first request only.
+    hash: Arc<OnceLock<Result<String, String>>>,
+    // Hash reused from previous build must match file content.
+    expected: Option<String>,
+    generation: u64,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<PathBuf, FileEntry>,
+}
+
+// File identity cache shared by all tasks of the build. Files are expected to stay unchanged
+// during the build, except for files written by the build itself: they must be invalidated.
+// Between builds hash is reused for files with the same size and modification time.
+pub struct StatCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    // Entries from older generation are validated by size and modification time.
+    generation: AtomicU64,
+    // Access counter for LRU eviction.
+    clock: AtomicU64,
+    guard: MtimeGuard,
+    stats: AtomicUsize,
+    hashes: AtomicUsize,
+}
+
+// Detects file systems where modification time can't be trusted.
+struct MtimeGuard {
+    spot_check_rate: f64,
+    // Spot checks are selected by file path for deterministic schedule.
+    seed: Option<u64>,
+    // File with modification time in future -> skew.
+    skewed: Mutex<HashMap<PathBuf, Duration>>,
+    // File system roots where content changed with the same size and modification time.
+    unreliable: Mutex<HashSet<PathBuf>>,
+    spot_checks: AtomicUsize,
+    warned: AtomicBool,
+}
+
+impl Default for MtimeGuard {
+    fn default() -> Self {
+        MtimeGuard {
+            spot_check_rate: SPOT_CHECK_RATE,
+            seed: None,
+            skewed: Mutex::default(),
+            unreliable: Mutex::default(),
+            spot_checks: AtomicUsize::new(0),
+            warned: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for StatCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl StatCache {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        StatCache {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            shard_capacity: capacity.div_ceil(SHARDS).max(1),
+            generation: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            guard: MtimeGuard::default(),
+            stats: AtomicUsize::new(0),
+            hashes: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.guard.seed = seed;
+        self
+    }
+
+    // Get file size and modification time.
+    pub fn stat(&self, path: &Path) -> Result<(u64, SystemTime), Error> {
+        self.entry(path, |entry| entry.stat)
+            .ok_or_else(|| not_found(path))
+    }
+
+    pub fn exists(&self, path: &Path) -> bool {
+        self.stat(path).is_ok()
+    }
+
+    // Forget files written by the build.
+    pub fn invalidate<P: AsRef<Path>>(&self, paths: &[P]) {
+        for path in paths {
+            let key = path_key(path.as_ref());
+            self.shard(&key).lock().unwrap().entries.remove(&key);
+        }
+    }
+
+    // Check all files again on next request: new build or command with unknown outputs.
+    pub fn refresh(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.guard.spot_checks.store(0, Ordering::Relaxed);
+    }
+
+    // Forget all files.
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().entries.clear();
+        }
+    }
+
+    // Warn once about files with modification time in future.
+    pub fn report_anomalies(&self) {
+        if let Some(message) = self.guard.skew_warning() {
+            warn!("{message}");
+        }
+    }
+
+    // Count of file system stat calls.
+    #[must_use]
+    pub fn stat_count(&self) -> usize {
+        self.stats.load(Ordering::Relaxed)
+    }
+
+    // Count of file content hash computations.
+    #[must_use]
+    pub fn hash_count(&self) -> usize {
+        self.hashes.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &Path) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn entry<T>(&self, path: &Path, func: impl FnOnce(&FileEntry) -> T) -> T {
+        let key = path_key(path);
+        let used = self.clock.fetch_add(1, Ordering::Relaxed);
+        let generation = self.generation.load(Ordering::Relaxed);
+        let mut shard = self.shard(&key).lock().unwrap();
+        if let Some(entry) = shard.entries.get_mut(&key) {
+            entry.used = used;
+            if entry.generation == generation {
+                return func(entry);
+            }
+        }
+        // Stat under shard lock, so concurrent tasks don't repeat it.
+        self.stats.fetch_add(1, Ordering::Relaxed);
+        let stat = fs::metadata(path)
+            .and_then(|metadata| Ok((metadata.len(), metadata.modified()?)))
+            .ok();
+        let (hash, expected) = match shard.entries.remove(&key) {
+            Some(old)
+                if self
+                    .guard
+                    .can_reuse(path, old.stat, stat, SystemTime::now()) =>
+            {
+                match old.hash.get() {
+                    Some(Ok(hash)) if self.guard.want_spot_check(path) => {
+                        (Arc::default(), Some(hash.clone()))
+                    }
+                    _ => (old.hash, None),
+                }
+            }
+            _ => (Arc::default(), None),
+        };
+        if shard.entries.len() >= self.shard_capacity {
+            shard.evict();
+        }
+        let entry = shard.entries.entry(key).or_insert(FileEntry {
+            stat,
+            hash,
+            expected,
+            generation,
+            used,
+        });
+        func(entry)
+    }
+}
+
+impl Shard {
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl FileHasher for StatCache {
+    fn file_hash(&self, path: &Path) -> Result<FileHash, Error> {
+        let (stat, hash, expected) = self.entry(path, |entry| {
+            (entry.stat, entry.hash.clone(), entry.expected.clone())
+        });
+        let (size, modified) = stat.ok_or_else(|| not_found(path))?;
+        // Hash is computed outside of shard lock, concurrent requests wait for the first one.
+        let hash = hash.get_or_init(|| {
+            self.hashes.fetch_add(1, Ordering::Relaxed);
+            let hash = File::open(path)
+                .and_then(|mut file| hash_stream(&mut file))
+                .map_err(|e| e.to_string())?;
+            if let Some(expected) = expected {
+                self.guard.verify(path, &expected, &hash);
+            }
+            Ok(hash)
+        });
+        match hash {
+            Ok(hash) => Ok(FileHash {
+                hash: hash.clone(),
+                size,
+                modified,
+            }),
+            Err(e) => Err(Error::new(ErrorKind::Other, e.clone())),
+        }
+    }
+}
+
+impl MtimeGuard {
+    // Check if file hash from previous build is still valid.
+    fn can_reuse(
+        &self,
+        path: &Path,
+        old: Option<FileStat>,
+        new: Option<FileStat>,
+        now: SystemTime,
+    ) -> bool {
+        let (Some(old), Some(new)) = (old, new) else {
+            return false;
+        };
+        if old != new || self.is_skewed(path, new.1, now) {
+            return false;
+        }
+        let unreliable = self.unreliable.lock().unwrap();
+        unreliable.is_empty() || !unreliable.contains(&filesystem_root(path))
+    }
+
+    // Remember file with modification time in future, its hash is never reused.
+    fn is_skewed(&self, path: &Path, modified: SystemTime, now: SystemTime) -> bool {
+        match modified.duration_since(now) {
+            Ok(skew) if skew > MTIME_TOLERANCE => {
+                self.skewed.lock().unwrap().insert(path.to_path_buf(), skew);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn want_spot_check(&self, path: &Path) -> bool {
+        let random: f64 = match self.seed {
+            Some(seed) => seeded_random(seed, path),
+            None => rand::random(),
+        };
+        random < self.spot_check_rate
+            && self.spot_checks.fetch_add(1, Ordering::Relaxed) < SPOT_CHECK_LIMIT
+    }
+
+    // Compare reused hash with file content, mismatch disables reuse for the whole file system.
+    fn verify(&self, path: &Path, expected: &str, actual: &str) -> bool {
+        if expected == actual {
+            return true;
+        }
+        let root = filesystem_root(path);
+        if self.unreliable.lock().unwrap().insert(root.clone()) {
+            warn!(
+                "File {} changed without size and modification time change, \
+                 modification time is not trusted for files on {}",
+                path.display(),
+                root.display()
+            );
+        }
+        false
+    }
+
+    fn skew_warning(&self) -> Option<String> {
+        let skewed = self.skewed.lock().unwrap();
+        if skewed.is_empty() || self.warned.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        let mut worst: Vec<(&PathBuf, &Duration)> = skewed.iter().collect();
+        worst.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut message = format!(
+            "{} files have modification time in future, check clock of file system host:",
+            skewed.len()
+        );
+        for (path, skew) in worst.into_iter().take(WORST_OFFENDERS) {
+            message.push_str(&format!(
+                "\n  {} (+{}s on {})",
+                path.display(),
+                skew.as_secs(),
+                filesystem_root(path).display()
+            ));
+        }
+        Some(message)
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("Can't read file {}", path.display()),
+    )
+}
+
+// Mount point of file system with the file.
+#[cfg(unix)]
+fn filesystem_root(path: &Path) -> PathBuf {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev()).ok();
+    let Some(dev) = device(path) else {
+        return path.parent().unwrap_or(path).to_path_buf();
+    };
+    path.ancestors()
+        .take_while(|ancestor| device(ancestor) == Some(dev))
+        .last()
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+// Drive or network share of the file.
+#[cfg(windows)]
+fn filesystem_root(path: &Path) -> PathBuf {
+    use std::path::Component;
+
+    path.components()
+        .take_while(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use super::{FileHasher, MtimeGuard, StatCache, SPOT_CHECK_LIMIT};
+
+    fn tree(dir: &std::path::Path, count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| {
+                let path = dir.join(format!("header{i}.h"));
+                fs::write(&path, format!("#define HEADER{i}\n")).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hash_once_per_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = tree(dir.path(), 200);
+        let cache = StatCache::default();
+        // Every task of synthetic build includes every header.
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..6 {
+                        for path in &headers {
+                            cache.file_hash(path).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        // Without cache every task hashes every header: 8 * 6 * 200 computations.
+        assert_eq!(cache.hash_count(), headers.len());
+        assert_eq!(cache.stat_count(), headers.len());
+    }
+
+    #[test]
+    fn test_lazy_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = tree(dir.path(), 10);
+        let cache = StatCache::default();
+        for path in &headers {
+            assert!(cache.exists(path));
+        }
+        assert!(!cache.exists(&dir.path().join("none.h")));
+        assert_eq!(cache.stat_count(), 11);
+        assert_eq!(cache.hash_count(), 0);
+        assert!(cache.file_hash(&dir.path().join("none.h")).is_err());
+        assert_eq!(cache.hash_count(), 0);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = tree(dir.path(), 100);
+        let cache = StatCache::new(32);
+        for path in &headers {
+            cache.file_hash(path).unwrap();
+        }
+        assert!(cache.len() <= 32);
+        // Recently used file is retained.
+        let last = headers.last().unwrap();
+        let hashed = cache.hash_count();
+        cache.file_hash(last).unwrap();
+        assert_eq!(cache.hash_count(), hashed);
+        // Evicted files are hashed again.
+        for path in &headers {
+            cache.file_hash(path).unwrap();
+        }
+        assert!(cache.hash_count() >= hashed + headers.len() - 32);
+    }
+
+    #[test]
+    fn test_invalidate_written_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("generated.h");
+        let cache = StatCache::default();
+        assert!(!cache.exists(&path));
+
+        // File written by the build is unknown until invalidated.
+        fs::write(&path, "int a;\n").unwrap();
+        assert!(!cache.exists(&path));
+        cache.invalidate(&[&path]);
+        let first = cache.file_hash(&path).unwrap();
+
+        // Same size rewrite with possibly the same modification time.
+        fs::write(&path, "int b;\n").unwrap();
+        assert_eq!(cache.file_hash(&path).unwrap().hash, first.hash);
+        cache.invalidate(&[&path]);
+        let second = cache.file_hash(&path).unwrap();
+        assert_ne!(second.hash, first.hash);
+        assert_eq!(second.size, first.size);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.file_hash(&path).unwrap().hash, second.hash);
+        assert_eq!(cache.hash_count(), 3);
+    }
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_future_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("skewed.h");
+        let guard = MtimeGuard::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let stat = |size: u64, skew: u64| Some((size, now + Duration::from_secs(skew)));
+
+        // Small skew is tolerated.
+        assert!(guard.can_reuse(&path, stat(10, 30), stat(10, 30), now));
+        assert!(!guard.can_reuse(&path, stat(10, 30), stat(11, 30), now));
+        assert!(!guard.can_reuse(&path, stat(10, 30), None, now));
+        assert!(guard.skew_warning().is_none());
+
+        assert!(!guard.can_reuse(&path, stat(10, 3600), stat(10, 3600), now));
+        let warning = guard.skew_warning().unwrap();
+        assert!(warning.starts_with("1 files have modification time in future"));
+        assert!(warning.contains(&format!("{} (+3600s on ", path.display())));
+        // Warning is reported once.
+        assert!(guard.skew_warning().is_none());
+    }
+
+    #[test]
+    fn test_worst_offenders() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = MtimeGuard::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for hours in 1..=7 {
+            let path = dir.path().join(format!("{hours}.h"));
+            let stat = Some((1, now + Duration::from_secs(hours * 3600)));
+            assert!(!guard.can_reuse(&path, stat, stat, now));
+        }
+        let warning = guard.skew_warning().unwrap();
+        let lines: Vec<&str> = warning.lines().collect();
+        assert!(lines[0].starts_with("7 files"));
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].contains("7.h (+25200s"));
+        assert!(lines[5].contains("3.h (+10800s"));
+    }
+
+    #[test]
+    fn test_unreliable_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = MtimeGuard::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let stat = Some((10, now));
+        let other = dir.path().join("other.h");
+        assert!(guard.can_reuse(&other, stat, stat, now));
+
+        assert!(guard.verify(&dir.path().join("a.h"), "1", "1"));
+        assert!(guard.can_reuse(&other, stat, stat, now));
+        // Content changed with the same metadata: file system mtime is not trusted anymore.
+        assert!(!guard.verify(&dir.path().join("a.h"), "1", "2"));
+        assert!(!guard.can_reuse(&other, stat, stat, now));
+    }
+
+    #[test]
+    fn test_spot_check_limit() {
+        let guard = MtimeGuard {
+            spot_check_rate: 0.5,
+            ..MtimeGuard::default()
+        };
+        let checks = (0..1000)
+            .filter(|_| guard.want_spot_check(Path::new("a.h")))
+            .count();
+        assert_eq!(checks, SPOT_CHECK_LIMIT);
+    }
+
+    #[test]
+    fn test_seeded_spot_check() {
+        let checked = |seed: u64| -> Vec<usize> {
+            let guard = MtimeGuard {
+                spot_check_rate: 0.1,
+                seed: Some(seed),
+                ..MtimeGuard::default()
+            };
+            (0..100)
+                .filter(|i| guard.want_spot_check(Path::new(&format!("{i}.h"))))
+                .collect()
+        };
+        // Deterministic schedule checks the same files in every run with the same seed.
+        assert_eq!(checked(1), checked(1));
+        assert_ne!(checked(1), checked(2));
+    }
+
+    #[test]
+    fn test_reuse_between_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("header.h");
+        fs::write(&path, "int a;\n").unwrap();
+        let mut cache = StatCache::default();
+        cache.guard.spot_check_rate = 0.0;
+        let first = cache.file_hash(&path).unwrap();
+
+        // Same size and modification time: hash is reused by next build.
+        fs::write(&path, "int b;\n").unwrap();
+        set_modified(&path, first.modified);
+        cache.refresh();
+        assert_eq!(cache.file_hash(&path).unwrap().hash, first.hash);
+        assert_eq!(cache.stat_count(), 2);
+        assert_eq!(cache.hash_count(), 1);
+
+        // Modification time in future is not trusted.
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        set_modified(&path, future);
+        cache.refresh();
+        let second = cache.file_hash(&path).unwrap();
+        assert_ne!(second.hash, first.hash);
+        cache.refresh();
+        assert_eq!(cache.file_hash(&path).unwrap().hash, second.hash);
+        assert_eq!(cache.hash_count(), 3);
+    }
+
+    #[test]
+    fn test_spot_check_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.h");
+        let b = dir.path().join("b.h");
+        fs::write(&a, "int a;\n").unwrap();
+        fs::write(&b, "int b;\n").unwrap();
+        let mut cache = StatCache::default();
+        let old_a = cache.file_hash(&a).unwrap();
+        let old_b = cache.file_hash(&b).unwrap();
+
+        // Rewrite keeping size and modification time, like coarse timestamps of FAT.
+        fs::write(&a, "int c;\n").unwrap();
+        set_modified(&a, old_a.modified);
+        fs::write(&b, "int d;\n").unwrap();
+        set_modified(&b, old_b.modified);
+
+        // Spot check finds changed content.
+        cache.guard.spot_check_rate = 1.0;
+        cache.refresh();
+        assert_ne!(cache.file_hash(&a).unwrap().hash, old_a.hash);
+
+        // Hash is not reused on the same file system anymore.
+        cache.guard.spot_check_rate = 0.0;
+        assert_ne!(cache.file_hash(&b).unwrap().hash, old_b.hash);
+        assert_eq!(cache.hash_count(), 4);
+    }
+}
@@ -1,7 +1,15 @@
 use std::cmp::max;
-
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::io::Write;
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::run::glob_match;
+use crate::utils::escape_control;
+use crate::watchdog::TaskPhase;
 
 #[derive(Default)]
 pub struct Statistic {
@@ -10,6 +18,52 @@ pub struct Statistic {
     pub miss_count: AtomicUsize,
     pub miss_bytes: AtomicUsize,
     pub remote_count: AtomicUsize,
+    pub infra_failure_count: AtomicUsize,
+    // Tasks retried because IDE held their source locked.
+    pub locked_retry_count: AtomicUsize,
+    // Preprocessed bytes not sent to remote builders thanks to prefix deduplication.
+    pub prefix_saved_bytes: AtomicUsize,
+    // Preprocessed bytes of tasks sent to remote builders by chunks and chunk bytes actually sent.
+    pub chunk_total_bytes: AtomicUsize,
+    pub chunk_sent_bytes: AtomicUsize,
+    // Breakdown by build configuration label: different configurations rarely share entries.
+    labels: Mutex<BTreeMap<Option<String>, LabelStatistic>>,
+}
+
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LabelStatistic {
+    pub hit_count: usize,
+    pub hit_bytes: usize,
+    pub miss_count: usize,
+    pub miss_bytes: usize,
+}
+
+// Timing of completed build task.
+#[derive(Clone, Debug)]
+pub struct TaskTiming {
+    // Index of task in build graph.
+    pub index: usize,
+    pub title: String,
+    pub duration: Duration,
+    pub cached: bool,
+    pub phases: BTreeMap<TaskPhase, Duration>,
+    // Indexes of tasks that must complete before this one.
+    pub deps: Vec<usize>,
+}
+
+// Timings of all tasks executed by the build, used to find what gates total build time.
+#[derive(Default)]
+pub struct Timeline {
+    tasks: Mutex<Vec<TaskTiming>>,
+    wall_time: Mutex<Duration>,
+}
+
+// Longest chain of dependent tasks: build can't finish faster even with unlimited workers.
+#[derive(Debug)]
+pub struct CriticalPath {
+    // From the first task to the last one.
+    pub chain: Vec<TaskTiming>,
+    pub length: Duration,
 }
 
 impl fmt::Display for Statistic {
@@ -19,10 +73,11 @@ impl fmt::Display for Statistic {
         let miss_count = self.miss_count.load(Ordering::Relaxed);
         let miss_bytes = self.miss_bytes.load(Ordering::Relaxed);
         let remote_count = self.remote_count.load(Ordering::Relaxed);
+        let infra_failure_count = self.infra_failure_count.load(Ordering::Relaxed);
         let total_count = hit_count + miss_count;
         write!(
             f,
-            "Cache statistic: hit {} of {} ({} %), remote {}, read {}, write {}, total {}",
+            "Cache statistic: hit {} of {} ({} %), remote {}, read {}, write {}, total {}, infrastructure failures {}",
             hit_count,
             total_count,
             hit_count * 100 / max(total_count, 1),
@@ -30,7 +85,44 @@ impl fmt::Display for Statistic {
             hit_bytes,
             miss_bytes,
             hit_bytes + miss_bytes,
-        )
+            infra_failure_count,
+        )?;
+        let locked_retry_count = self.locked_retry_count.load(Ordering::Relaxed);
+        if locked_retry_count > 0 {
+            write!(f, ", locked source retries {locked_retry_count}")?;
+        }
+        let prefix_saved_bytes = self.prefix_saved_bytes.load(Ordering::Relaxed);
+        if prefix_saved_bytes > 0 {
+            write!(f, ", prefix dedup saved {prefix_saved_bytes}")?;
+        }
+        let chunk_total_bytes = self.chunk_total_bytes.load(Ordering::Relaxed);
+        if chunk_total_bytes > 0 {
+            let chunk_sent_bytes = self.chunk_sent_bytes.load(Ordering::Relaxed);
+            write!(
+                f,
+                ", chunk dedup sent {} of {} ({} % saved)",
+                chunk_sent_bytes,
+                chunk_total_bytes,
+                (chunk_total_bytes - chunk_sent_bytes) * 100 / chunk_total_bytes
+            )?;
+        }
+        let labels = self.labels();
+        if labels.len() > 1 {
+            for (label, stat) in labels {
+                let total_count = stat.hit_count + stat.miss_count;
+                write!(
+                    f,
+                    "\n  {}: hit {} of {} ({} %), read {}, write {}",
+                    label.as_deref().unwrap_or("(no configuration)"),
+                    stat.hit_count,
+                    total_count,
+                    stat.hit_count * 100 / max(total_count, 1),
+                    stat.hit_bytes,
+                    stat.miss_bytes,
+                )?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -40,17 +132,285 @@ impl Statistic {
         Statistic::default()
     }
 
-    pub fn add_hit(&self, bytes: usize) {
+    pub fn add_hit(&self, label: Option<&str>, bytes: usize) {
         self.hit_count.fetch_add(1, Ordering::Release);
         self.hit_bytes.fetch_add(bytes, Ordering::Release);
+        let mut labels = self.labels.lock().unwrap();
+        let stat = labels.entry(label.map(str::to_string)).or_default();
+        stat.hit_count += 1;
+        stat.hit_bytes += bytes;
     }
 
-    pub fn add_miss(&self, bytes: usize) {
+    pub fn add_miss(&self, label: Option<&str>, bytes: usize) {
         self.miss_count.fetch_add(1, Ordering::Release);
         self.miss_bytes.fetch_add(bytes, Ordering::Release);
+        let mut labels = self.labels.lock().unwrap();
+        let stat = labels.entry(label.map(str::to_string)).or_default();
+        stat.miss_count += 1;
+        stat.miss_bytes += bytes;
+    }
+
+    // Statistic by build configuration label, tasks without label go first.
+    #[must_use]
+    pub fn labels(&self) -> Vec<(Option<String>, LabelStatistic)> {
+        self.labels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(label, stat)| (label.clone(), *stat))
+            .collect()
     }
 
     pub fn inc_remote(&self) {
         self.remote_count.fetch_add(1, Ordering::Release);
     }
+
+    pub fn inc_infra_failure(&self) {
+        self.infra_failure_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn inc_locked_retry(&self) {
+        self.locked_retry_count.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn add_prefix_saved(&self, bytes: usize) {
+        self.prefix_saved_bytes.fetch_add(bytes, Ordering::Release);
+    }
+
+    pub fn add_chunks(&self, total: usize, sent: usize) {
+        self.chunk_total_bytes.fetch_add(total, Ordering::Release);
+        self.chunk_sent_bytes.fetch_add(sent, Ordering::Release);
+    }
+}
+
+impl Timeline {
+    #[must_use]
+    pub fn new() -> Self {
+        Timeline::default()
+    }
+
+    pub fn record(&self, timing: TaskTiming) {
+        self.tasks.lock().unwrap().push(timing);
+    }
+
+    pub fn add_wall_time(&self, duration: Duration) {
+        *self.wall_time.lock().unwrap() += duration;
+    }
+
+    #[must_use]
+    pub fn tasks(&self) -> Vec<TaskTiming> {
+        self.tasks.lock().unwrap().clone()
+    }
+
+    #[must_use]
+    pub fn wall_time(&self) -> Duration {
+        *self.wall_time.lock().unwrap()
+    }
+
+    // Print critical path of the build. Every `what_if_hit` glob recomputes the path
+    // as if tasks with matching captions were taken from cache.
+    pub fn print_critical_path(
+        &self,
+        out: &mut impl Write,
+        what_if_hit: &[String],
+    ) -> crate::Result<()> {
+        let tasks = self.tasks();
+        if tasks.is_empty() {
+            return Ok(());
+        }
+        let path = critical_path(&tasks, |task| task.duration);
+        writeln!(
+            out,
+            "Critical path: {} of {} tasks, minimal build time {:.1}s, actual {:.1}s",
+            path.chain.len(),
+            tasks.len(),
+            path.length.as_secs_f64(),
+            self.wall_time().as_secs_f64(),
+        )?;
+        for task in &path.chain {
+            let phases: Vec<String> = task
+                .phases
+                .iter()
+                .map(|(phase, duration)| format!("{phase:?} {:.1}s", duration.as_secs_f64()))
+                .collect();
+            writeln!(
+                out,
+                "  {:>7.1}s {}{} [{}]",
+                task.duration.as_secs_f64(),
+                escape_control(&task.title),
+                if task.cached { " (cached)" } else { "" },
+                phases.join(", "),
+            )?;
+        }
+        for pattern in what_if_hit {
+            let hit = hit_duration(&tasks);
+            let what_if = critical_path(&tasks, |task| {
+                if glob_match(pattern, Path::new(&task.title)) {
+                    task.duration.min(hit)
+                } else {
+                    task.duration
+                }
+            });
+            writeln!(
+                out,
+                "What if {pattern} were cache hits: minimal build time {:.1}s, critical path:",
+                what_if.length.as_secs_f64(),
+            )?;
+            for task in &what_if.chain {
+                writeln!(out, "  {}", escape_control(&task.title))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Find the longest chain of dependent tasks, weighted by `duration`.
+// Dependencies on tasks without timing (not executed) are ignored.
+pub fn critical_path(
+    tasks: &[TaskTiming],
+    duration: impl Fn(&TaskTiming) -> Duration,
+) -> CriticalPath {
+    let positions: HashMap<usize, usize> = tasks
+        .iter()
+        .enumerate()
+        .map(|(position, task)| (task.index, position))
+        .collect();
+    let deps: Vec<Vec<usize>> = tasks
+        .iter()
+        .map(|task| {
+            task.deps
+                .iter()
+                .filter_map(|dep| positions.get(dep).copied())
+                .collect()
+        })
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); tasks.len()];
+    let mut pending: Vec<usize> = deps.iter().map(Vec::len).collect();
+    for (position, task_deps) in deps.iter().enumerate() {
+        for &dep in task_deps {
+            dependents[dep].push(position);
+        }
+    }
+
+    // Earliest finish time of every task and its slowest dependency.
+    let mut finish: Vec<Duration> = vec![Duration::ZERO; tasks.len()];
+    let mut prev: Vec<Option<usize>> = vec![None; tasks.len()];
+    let mut queue: Vec<usize> = (0..tasks.len()).filter(|&i| pending[i] == 0).collect();
+    while let Some(position) = queue.pop() {
+        let start = deps[position]
+            .iter()
+            .map(|&dep| (finish[dep], dep))
+            .max()
+            .map_or(Duration::ZERO, |(time, dep)| {
+                prev[position] = Some(dep);
+                time
+            });
+        finish[position] = start + duration(&tasks[position]);
+        for &dependent in &dependents[position] {
+            pending[dependent] -= 1;
+            if pending[dependent] == 0 {
+                queue.push(dependent);
+            }
+        }
+    }
+
+    let mut chain = Vec::new();
+    let mut current = (0..tasks.len()).max_by_key(|&i| finish[i]);
+    let length = current.map_or(Duration::ZERO, |i| finish[i]);
+    while let Some(position) = current {
+        chain.push(tasks[position].clone());
+        current = prev[position];
+    }
+    chain.reverse();
+    CriticalPath { chain, length }
+}
+
+// Expected duration of a cache hit: median of tasks taken from cache in this build.
+fn hit_duration(tasks: &[TaskTiming]) -> Duration {
+    let mut hits: Vec<Duration> = tasks
+        .iter()
+        .filter(|task| task.cached)
+        .map(|task| task.duration)
+        .collect();
+    hits.sort();
+    hits.get(hits.len() / 2).copied().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use crate::io::statistic::{critical_path, TaskTiming, Timeline};
+
+    fn task(index: usize, title: &str, seconds: u64, deps: &[usize]) -> TaskTiming {
+        TaskTiming {
+            index,
+            title: title.to_string(),
+            duration: Duration::from_secs(seconds),
+            cached: false,
+            phases: BTreeMap::new(),
+            deps: deps.to_vec(),
+        }
+    }
+
+    fn titles(tasks: &[TaskTiming]) -> Vec<&str> {
+        tasks.iter().map(|task| task.title.as_str()).collect()
+    }
+
+    #[test]
+    fn test_diamond() {
+        // Link depends on two compilations, both depend on precompiled header.
+        let tasks = vec![
+            task(3, "link", 2, &[1, 2]),
+            task(1, "a.cpp", 5, &[0]),
+            task(0, "pch.cpp", 3, &[]),
+            task(2, "b.cpp", 7, &[0]),
+        ];
+        let path = critical_path(&tasks, |task| task.duration);
+        assert_eq!(titles(&path.chain), vec!["pch.cpp", "b.cpp", "link"]);
+        assert_eq!(path.length, Duration::from_secs(12));
+    }
+
+    #[test]
+    fn test_independent_chains() {
+        // Dependency on task that wasn't executed is ignored.
+        let tasks = vec![
+            task(0, "a.cpp", 4, &[]),
+            task(1, "a.lib", 1, &[0, 7]),
+            task(2, "b.cpp", 2, &[]),
+            task(3, "c.cpp", 4, &[]),
+        ];
+        let path = critical_path(&tasks, |task| task.duration);
+        assert_eq!(titles(&path.chain), vec!["a.cpp", "a.lib"]);
+        assert_eq!(path.length, Duration::from_secs(5));
+
+        let path = critical_path(&[], |task| task.duration);
+        assert!(path.chain.is_empty());
+        assert_eq!(path.length, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_what_if_hit() {
+        let timeline = Timeline::new();
+        timeline.record(task(0, "Engine/a.cpp", 10, &[]));
+        timeline.record(task(1, "Game/b.cpp", 6, &[]));
+        timeline.record(TaskTiming {
+            cached: true,
+            ..task(2, "Game/c.cpp", 1, &[])
+        });
+        timeline.record(task(3, "Game.exe", 2, &[0, 1, 2]));
+        timeline.add_wall_time(Duration::from_secs(13));
+
+        let mut out = Vec::new();
+        timeline
+            .print_critical_path(&mut out, &["Engine/**".to_string()])
+            .unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("2 of 4 tasks, minimal build time 12.0s, actual 13.0s"));
+        assert!(report.contains("Engine/a.cpp"));
+        assert!(report.contains(
+            "What if Engine/** were cache hits: minimal build time 8.0s, critical path:\n  Game/b.cpp\n  Game.exe\n"
+        ));
+    }
 }
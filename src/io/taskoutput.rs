@@ -0,0 +1,329 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use log::warn;
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tempfile::NamedTempFile;
+
+use crate::config::Config;
+use crate::io::binary::{read_u64, write_u64};
+
+// Prefix of temporary files holding spilled task output.
+const SPILL_PREFIX: &str = "octobuild-output";
+// Placeholder in truncation marker replaced by count of dropped bytes.
+const MARKER_BYTES: &str = "{bytes}";
+
+// Size limits of single task output stream.
+#[derive(Clone, Debug)]
+pub struct OutputLimits {
+    // Output above this size is truncated, zero disables truncation.
+    pub max_size: u64,
+    // Output above this size is moved from memory to temporary file.
+    pub spill_size: u64,
+    // Appended to truncated output.
+    pub marker: String,
+}
+
+// Output stream (stdout or stderr) of a task.
+// Small output is kept in memory, large output is spilled to temporary file,
+// so tasks with hundreds of megabytes of warnings don't exhaust memory.
+#[derive(Clone, Default)]
+pub struct TaskOutput {
+    data: Data,
+    // Count of bytes dropped because of size limit.
+    truncated: u64,
+}
+
+#[derive(Clone)]
+enum Data {
+    Inline(Vec<u8>),
+    Spilled { file: Arc<NamedTempFile>, size: u64 },
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        Data::Inline(Vec::new())
+    }
+}
+
+impl OutputLimits {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        OutputLimits {
+            // Raw output must be byte-exact.
+            max_size: if config.raw_output {
+                0
+            } else {
+                config.output_limit_kb * 1024
+            },
+            spill_size: config.output_spill_kb * 1024,
+            marker: config.output_truncation_marker.clone(),
+        }
+    }
+}
+
+impl TaskOutput {
+    // Take captured process output, truncating it to size limit.
+    #[must_use]
+    pub fn new(mut data: Vec<u8>, limits: &OutputLimits) -> Self {
+        let mut truncated = 0;
+        if limits.max_size > 0 && data.len() as u64 > limits.max_size {
+            truncated = data.len() as u64 - limits.max_size;
+            data.truncate(limits.max_size as usize);
+            data.extend_from_slice(
+                limits
+                    .marker
+                    .replace(MARKER_BYTES, &truncated.to_string())
+                    .as_bytes(),
+            );
+            data.shrink_to_fit();
+        }
+        if data.len() as u64 > limits.spill_size {
+            match spill(&mut data.as_slice()) {
+                Ok((file, size)) => {
+                    return TaskOutput {
+                        data: Data::Spilled { file, size },
+                        truncated,
+                    }
+                }
+                Err(e) => warn!("Can't spill task output to temporary file: {e}"),
+            }
+        }
+        TaskOutput {
+            data: Data::Inline(data),
+            truncated,
+        }
+    }
+
+    // Read output stored by `write_to`. Large output is copied to temporary file without loading it into memory.
+    pub fn read_from(stream: &mut impl Read, limits: &OutputLimits) -> std::io::Result<Self> {
+        let truncated = read_u64(stream)?;
+        let size = read_u64(stream)?;
+        let mut stream = stream.take(size);
+        let data = if size > limits.spill_size {
+            let (file, copied) = spill(&mut stream)?;
+            if copied != size {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            Data::Spilled { file, size }
+        } else {
+            let mut data = Vec::with_capacity(size as usize);
+            if stream.read_to_end(&mut data)? as u64 != size {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            Data::Inline(data)
+        };
+        Ok(TaskOutput { data, truncated })
+    }
+
+    pub fn write_to(&self, stream: &mut impl Write) -> std::io::Result<()> {
+        write_u64(stream, self.truncated)?;
+        write_u64(stream, self.len())?;
+        self.replay(stream)
+    }
+
+    // Size of stored output, including truncation marker.
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        match &self.data {
+            Data::Inline(data) => data.len() as u64,
+            Data::Spilled { size, .. } => *size,
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Count of bytes dropped because of size limit.
+    #[must_use]
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    #[must_use]
+    pub fn is_spilled(&self) -> bool {
+        matches!(self.data, Data::Spilled { .. })
+    }
+
+    pub fn reader(&self) -> std::io::Result<Box<dyn Read + '_>> {
+        Ok(match &self.data {
+            Data::Inline(data) => Box::new(data.as_slice()),
+            Data::Spilled { file, .. } => Box::new(file.reopen()?),
+        })
+    }
+
+    // Write stored output to user, truncation marker included.
+    pub fn replay(&self, out: &mut impl Write) -> std::io::Result<()> {
+        std::io::copy(&mut self.reader()?, out)?;
+        Ok(())
+    }
+
+    // Output with text inserted at the beginning. Size limit is not applied to the prefix.
+    pub fn with_prefix(self, prefix: &[u8]) -> std::io::Result<Self> {
+        let mut data = prefix.to_vec();
+        match &self.data {
+            Data::Inline(inline) => data.extend_from_slice(inline),
+            Data::Spilled { .. } => {
+                self.reader()?.read_to_end(&mut data)?;
+            }
+        }
+        Ok(TaskOutput {
+            data: Data::Inline(data),
+            truncated: self.truncated,
+        })
+    }
+
+    // Output with text appended to the end. Size limit is not applied to the suffix.
+    pub fn with_suffix(self, suffix: &[u8]) -> std::io::Result<Self> {
+        if suffix.is_empty() {
+            return Ok(self);
+        }
+        let mut data = self.to_vec()?;
+        data.extend_from_slice(suffix);
+        Ok(TaskOutput {
+            data: Data::Inline(data),
+            truncated: self.truncated,
+        })
+    }
+
+    // Whole output in memory, for parsing. Size is bounded by output limits.
+    pub fn to_vec(&self) -> std::io::Result<Vec<u8>> {
+        match &self.data {
+            Data::Inline(data) => Ok(data.clone()),
+            Data::Spilled { .. } => {
+                let mut data = Vec::with_capacity(self.len() as usize);
+                self.reader()?.read_to_end(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+}
+
+// Output which is small by construction, never truncated or spilled.
+impl From<Vec<u8>> for TaskOutput {
+    fn from(data: Vec<u8>) -> Self {
+        TaskOutput {
+            data: Data::Inline(data),
+            truncated: 0,
+        }
+    }
+}
+
+impl fmt::Debug for TaskOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskOutput")
+            .field("len", &self.len())
+            .field("truncated", &self.truncated)
+            .field("spilled", &self.is_spilled())
+            .finish()
+    }
+}
+
+// Remote protocol sends output inline: builder has already applied its limits.
+impl Serialize for TaskOutput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let data = match &self.data {
+            Data::Inline(data) => Cow::Borrowed(data.as_slice()),
+            Data::Spilled { .. } => Cow::Owned(self.to_vec().map_err(serde::ser::Error::custom)?),
+        };
+        let mut state = serializer.serialize_struct("TaskOutput", 2)?;
+        state.serialize_field("data", data.as_ref())?;
+        state.serialize_field("truncated", &self.truncated)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TaskOutput {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Wire {
+            data: Vec<u8>,
+            truncated: u64,
+        }
+
+        let wire = Wire::deserialize(deserializer)?;
+        Ok(TaskOutput {
+            data: Data::Inline(wire.data),
+            truncated: wire.truncated,
+        })
+    }
+}
+
+fn spill(stream: &mut impl Read) -> std::io::Result<(Arc<NamedTempFile>, u64)> {
+    let mut file = tempfile::Builder::new().prefix(SPILL_PREFIX).tempfile()?;
+    let size = std::io::copy(stream, &mut file)?;
+    file.flush()?;
+    Ok((Arc::new(file), size))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::taskoutput::{OutputLimits, TaskOutput};
+
+    fn limits(max_size: u64, spill_size: u64) -> OutputLimits {
+        OutputLimits {
+            max_size,
+            spill_size,
+            marker: "\n<{bytes} bytes truncated>\n".to_string(),
+        }
+    }
+
+    fn replayed(output: &TaskOutput) -> Vec<u8> {
+        let mut result = Vec::new();
+        output.replay(&mut result).unwrap();
+        result
+    }
+
+    #[test]
+    fn test_spill_threshold() {
+        let limits = limits(0, 4);
+        let small = TaskOutput::new(b"1234".to_vec(), &limits);
+        assert!(!small.is_spilled());
+        assert_eq!(replayed(&small), b"1234");
+
+        let large = TaskOutput::new(b"12345".to_vec(), &limits);
+        assert!(large.is_spilled());
+        assert_eq!(large.len(), 5);
+        assert_eq!(large.truncated(), 0);
+        assert_eq!(replayed(&large), b"12345");
+        // Clones share spilled file.
+        assert_eq!(replayed(&large.clone()), b"12345");
+    }
+
+    #[test]
+    fn test_truncation() {
+        let output = TaskOutput::new(b"warning\nwarning\nwarning\n".to_vec(), &limits(8, 1024));
+        assert_eq!(output.truncated(), 16);
+        assert_eq!(replayed(&output), b"warning\n\n<16 bytes truncated>\n");
+
+        let output = TaskOutput::new(b"warning\n".to_vec(), &limits(8, 1024));
+        assert_eq!(output.truncated(), 0);
+        assert_eq!(replayed(&output), b"warning\n");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let output = TaskOutput::new(vec![b'x'; 100], &limits(64, 16));
+        assert!(output.is_spilled());
+        let mut stored = Vec::new();
+        output.write_to(&mut stored).unwrap();
+
+        let restored = TaskOutput::read_from(&mut stored.as_slice(), &limits(64, 16)).unwrap();
+        assert!(restored.is_spilled());
+        assert_eq!(restored.truncated(), 36);
+        assert_eq!(replayed(&restored), replayed(&output));
+
+        // Reader with larger spill threshold keeps output in memory.
+        let restored = TaskOutput::read_from(&mut stored.as_slice(), &limits(0, 1024)).unwrap();
+        assert!(!restored.is_spilled());
+        assert_eq!(replayed(&restored), replayed(&output));
+
+        // Truncated cache entry.
+        assert!(TaskOutput::read_from(&mut &stored[..stored.len() - 1], &limits(0, 16)).is_err());
+    }
+}
@@ -1,9 +1,21 @@
+use std::ffi::OsString;
 use std::fs;
+use std::fs::File;
 use std::io::Error;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use log::warn;
 use uuid::Uuid;
 
+use crate::io::counter::Counter;
+use crate::slots::is_process_alive;
+
+/// File with PID of the process owning temporary directory.
+pub const OWNER_FILE: &str = "owner.pid";
+
 pub struct TempFile {
     path: Option<PathBuf>,
     disarmed: bool,
@@ -52,3 +64,320 @@ impl Drop for TempFile {
         }
     }
 }
+
+/// Retry policy for files locked by another process.
+///
+/// Antivirus and indexing services open freshly written files for a short time,
+/// so rename or overwrite of such file intermittently fails.
+#[derive(Clone, Copy, Debug)]
+pub struct LockRetry {
+    pub attempts: usize,
+    pub delay: Duration,
+}
+
+/// Waits for about 1.5 seconds in total.
+pub const LOCK_RETRY: LockRetry = LockRetry {
+    attempts: 6,
+    delay: Duration::from_millis(50),
+};
+
+impl LockRetry {
+    /// Run file operation, retrying it with exponential backoff while the file is locked.
+    /// Exhausted retries are reported as infrastructure failure.
+    pub fn run<T, F>(self, path: &Path, op: F) -> crate::Result<T>
+    where
+        F: FnMut() -> std::io::Result<T>,
+    {
+        self.run_with(path, is_file_locked, op)
+    }
+
+    /// Like `run`, but for file just written by this process, see `is_written_file_locked`.
+    pub fn run_written<T, F>(self, path: &Path, op: F) -> crate::Result<T>
+    where
+        F: FnMut() -> std::io::Result<T>,
+    {
+        self.run_with(path, is_written_file_locked, op)
+    }
+
+    fn run_with<T, F>(
+        self,
+        path: &Path,
+        is_locked: impl Fn(&Error) -> bool,
+        mut op: F,
+    ) -> crate::Result<T>
+    where
+        F: FnMut() -> std::io::Result<T>,
+    {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Ok(value) => return Ok(value),
+                Err(error) if is_locked(&error) => {
+                    if attempt >= self.attempts {
+                        return Err(crate::Error::FileLocked {
+                            path: path.to_path_buf(),
+                            attempts: attempt,
+                            error,
+                        });
+                    }
+                    warn!(
+                        "File {} is locked, retry {attempt}/{} in {delay:?}: {error}",
+                        path.display(),
+                        self.attempts - 1
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+/// Check if file operation failed because another process holds the file open.
+#[must_use]
+pub fn is_file_locked(error: &Error) -> bool {
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    const CODES: [i32; 2] = [32, 33];
+    // Unix file systems don't lock files opened by other processes.
+    #[cfg(not(windows))]
+    const CODES: [i32; 0] = [];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| CODES.contains(&code))
+}
+
+/// Check if operation on file just written by this process failed because another process
+/// holds it open. Scanner opening such file also makes its rename fail with
+/// ERROR_ACCESS_DENIED, which means missing permissions for other files.
+#[must_use]
+pub fn is_written_file_locked(error: &Error) -> bool {
+    is_file_locked(error) || (cfg!(windows) && error.raw_os_error() == Some(5))
+}
+
+/// Path for temporary file next to `path`, unique between threads and processes writing it.
+#[must_use]
+pub fn temp_sibling(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut temp_name = OsString::from(format!(
+        "~tmp~{}-{}~",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    temp_name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(temp_name)
+}
+
+/// Write file through temporary file in the same directory and rename it on success,
+/// so failed write never leaves truncated file at `path`.
+pub fn write_atomic<F>(path: &Path, func: F) -> crate::Result<()>
+where
+    F: FnOnce(&mut Counter<File>) -> crate::Result<()>,
+{
+    let temp = temp_sibling(path);
+    let mut file =
+        Counter::writer(File::create(&temp).map_err(|e| crate::Error::from(e).for_write(path, 0))?);
+    let result = func(&mut file);
+    let written = file.len();
+    drop(file);
+    match result.and_then(|()| LOCK_RETRY.run_written(path, || fs::rename(&temp, path))) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            drop(fs::remove_file(&temp));
+            Err(e.for_write(path, written))
+        }
+    }
+}
+
+/// Create temporary directory in `parent` marked with PID of current process.
+pub fn create_owned_dir(parent: &Path, prefix: &str) -> Result<tempfile::TempDir, Error> {
+    fs::create_dir_all(parent)?;
+    let dir = tempfile::Builder::new().prefix(prefix).tempdir_in(parent)?;
+    fs::write(dir.path().join(OWNER_FILE), std::process::id().to_string())?;
+    Ok(dir)
+}
+
+/// Remove directories created by `create_owned_dir` whose owner process is gone and that were
+/// not modified for `max_age`.
+///
+/// Used to clean up temporary directories left by crashed processes. Directories without owner
+/// marker are never removed, so only `parent` owned by octobuild must be passed here.
+pub fn sweep_stale_dirs(parent: &Path, max_age: Duration) -> Result<(), Error> {
+    let now = SystemTime::now();
+    for entry in parent.read_dir()? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_dir() || !is_orphaned(&entry.path()) {
+            continue;
+        }
+        let stale = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .is_some_and(|age| age >= max_age);
+        if stale {
+            drop(fs::remove_dir_all(entry.path()));
+        }
+    }
+    Ok(())
+}
+
+// Directory has owner marker naming a process that is not running anymore.
+fn is_orphaned(dir: &Path) -> bool {
+    fs::read_to_string(dir.join(OWNER_FILE)).is_ok_and(|content| {
+        content
+            .trim()
+            .parse::<u32>()
+            .is_ok_and(|pid| !is_process_alive(pid))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{ErrorKind, Write};
+    use std::path::Path;
+    use std::time::Duration;
+
+    use crate::io::tempfile::{
+        create_owned_dir, sweep_stale_dirs, write_atomic, LockRetry, OWNER_FILE,
+    };
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.obj");
+
+        write_atomic(&path, |file| Ok(file.write_all(b"object")?)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"object");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_atomic_disk_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.obj");
+        fs::write(&path, b"old").unwrap();
+
+        let result = write_atomic(&path, |file| {
+            file.write_all(b"trunc")?;
+            Err(std::io::Error::from(ErrorKind::StorageFull).into())
+        });
+        let error = result.unwrap_err();
+        assert!(error.is_infrastructure());
+        match error {
+            crate::Error::DiskFull {
+                path: failed,
+                written,
+                ..
+            } => {
+                assert_eq!(failed, path);
+                assert_eq!(written, 5);
+            }
+            e => panic!("Disk full error is not detected: {e}"),
+        }
+        // Destination is untouched and temporary file is removed.
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_atomic_other_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sample.obj");
+
+        let result = write_atomic(&path, |_| Err(crate::Error::from("broken stream")));
+        assert!(!result.unwrap_err().is_infrastructure());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_lock_retry() {
+        let policy = LockRetry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+        let path = Path::new("sample.obj");
+        let is_locked = |e: &std::io::Error| e.kind() == ErrorKind::WouldBlock;
+        // File system shim: fails with `failures` lock errors before succeeding.
+        let shim = |failures: usize| {
+            let mut calls = 0;
+            move || {
+                calls += 1;
+                if calls <= failures {
+                    Err(std::io::Error::from(ErrorKind::WouldBlock))
+                } else {
+                    Ok(calls)
+                }
+            }
+        };
+
+        assert_eq!(policy.run_with(path, is_locked, shim(0)).unwrap(), 1);
+        assert_eq!(policy.run_with(path, is_locked, shim(2)).unwrap(), 3);
+
+        let error = policy.run_with(path, is_locked, shim(3)).unwrap_err();
+        assert!(error.is_infrastructure());
+        assert!(matches!(
+            error,
+            crate::Error::FileLocked { attempts: 3, .. }
+        ));
+
+        // Other errors are not retried.
+        let mut calls = 0;
+        let result = policy.run_with(path, is_locked, || -> std::io::Result<()> {
+            calls += 1;
+            Err(std::io::Error::from(ErrorKind::NotFound))
+        });
+        assert!(!result.unwrap_err().is_infrastructure());
+        assert_eq!(calls, 1);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_is_file_locked() {
+        use crate::io::tempfile::{is_file_locked, is_written_file_locked};
+
+        // ERROR_SHARING_VIOLATION
+        assert!(is_file_locked(&std::io::Error::from_raw_os_error(32)));
+        assert!(!is_file_locked(&std::io::Error::from_raw_os_error(2)));
+        // ERROR_ACCESS_DENIED is a lock only for file written by this process.
+        assert!(!is_file_locked(&std::io::Error::from_raw_os_error(5)));
+        assert!(is_written_file_locked(&std::io::Error::from_raw_os_error(
+            5
+        )));
+        assert!(!is_written_file_locked(&std::io::Error::from_raw_os_error(
+            2
+        )));
+    }
+
+    #[test]
+    fn test_sweep_stale_dirs() {
+        let parent = tempfile::tempdir().unwrap();
+        let mut child = std::process::Command::new(env!("CARGO"))
+            .arg("--version")
+            .spawn()
+            .unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        let crashed = parent.path().join("octobuild1234");
+        fs::create_dir_all(crashed.join("task5678")).unwrap();
+        fs::write(crashed.join(OWNER_FILE), dead_pid.to_string()).unwrap();
+        let alive = create_owned_dir(parent.path(), "octobuild").unwrap();
+        // Directory without marker isn't ours.
+        fs::create_dir(parent.path().join("octobuild-src")).unwrap();
+        fs::write(parent.path().join("octobuild.log"), b"").unwrap();
+
+        sweep_stale_dirs(parent.path(), Duration::from_secs(3600)).unwrap();
+        assert!(crashed.exists());
+
+        sweep_stale_dirs(parent.path(), Duration::ZERO).unwrap();
+        assert!(!crashed.exists());
+        assert!(alive.path().exists());
+        assert!(parent.path().join("octobuild-src").exists());
+        assert!(parent.path().join("octobuild.log").exists());
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use crate::io::tempfile::{create_owned_dir, sweep_stale_dirs};
+
+// Concurrent temporary files placed to one root, above which the root is considered saturated.
+const SATURATED_LEASES: usize = 4;
+
+/// Identity of a volume: device id on unix, path prefix like drive letter on windows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VolumeId(pub u64);
+
+type VolumeDetector = Box<dyn Fn(&Path) -> Option<VolumeId> + Send + Sync>;
+
+struct TempRoot {
+    // Configured root and process directory in its `<prefix>-tmp` subdirectory.
+    root: PathBuf,
+    dir: TempDir,
+    volume: Option<VolumeId>,
+    leases: AtomicUsize,
+    written: AtomicU64,
+    // Sequence number of the last saturation, 0 if root was never saturated.
+    saturated: AtomicU64,
+}
+
+/// Temporary directories on several volumes.
+///
+/// Task temporary files go to the root on the same volume as task output, so slow system
+/// temporary drive doesn't take all the traffic. Other tasks take least recently saturated root.
+pub struct TempRoots {
+    roots: Vec<TempRoot>,
+    detect: VolumeDetector,
+    // Volume of output directories, detected once per directory.
+    volumes: Mutex<HashMap<PathBuf, Option<VolumeId>>>,
+    saturations: AtomicU64,
+    rotation: AtomicUsize,
+}
+
+/// Temporary root chosen for a task, held while task files exist.
+pub struct TempLease<'a> {
+    root: &'a TempRoot,
+}
+
+impl TempLease<'_> {
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        self.root.dir.path()
+    }
+
+    pub fn add_written(&self, bytes: u64) {
+        self.root.written.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for TempLease<'_> {
+    fn drop(&mut self) {
+        self.root.leases.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl TempRoots {
+    /// Create process temporary directory with specified prefix in `<prefix>-tmp` directory
+    /// of every root. System temporary directory is used if no roots are specified.
+    ///
+    /// Directories left by crashed processes older than `max_age` are removed, other content
+    /// of the roots is never touched.
+    pub fn new(dirs: &[PathBuf], prefix: &str, max_age: Duration) -> std::io::Result<Self> {
+        TempRoots::with_detector(dirs, prefix, max_age, Box::new(volume_id))
+    }
+
+    fn with_detector(
+        dirs: &[PathBuf],
+        prefix: &str,
+        max_age: Duration,
+        detect: VolumeDetector,
+    ) -> std::io::Result<Self> {
+        let system = [env::temp_dir()];
+        let dirs = if dirs.is_empty() { &system[..] } else { dirs };
+        let roots = dirs
+            .iter()
+            .map(|dir| {
+                let parent = dir.join(format!("{prefix}-tmp"));
+                let owned = create_owned_dir(&parent, prefix)?;
+                drop(sweep_stale_dirs(&parent, max_age));
+                Ok(TempRoot {
+                    root: dir.clone(),
+                    dir: owned,
+                    volume: detect(dir),
+                    leases: AtomicUsize::new(0),
+                    written: AtomicU64::new(0),
+                    saturated: AtomicU64::new(0),
+                })
+            })
+            .collect::<std::io::Result<Vec<TempRoot>>>()?;
+        Ok(TempRoots {
+            roots,
+            detect,
+            volumes: Mutex::new(HashMap::new()),
+            saturations: AtomicU64::new(0),
+            rotation: AtomicUsize::new(0),
+        })
+    }
+
+    /// Process temporary directory in the first root, for files not related to any task output.
+    #[must_use]
+    pub fn primary(&self) -> &Path {
+        self.roots[0].dir.path()
+    }
+
+    /// Choose temporary root for task writing specified output file.
+    pub fn place(&self, output: Option<&Path>) -> TempLease<'_> {
+        let volume = output.and_then(|path| self.output_volume(path));
+        let root = &self.roots[self.select(volume)];
+        if root.leases.fetch_add(1, Ordering::Relaxed) + 1 >= SATURATED_LEASES {
+            let sequence = self.saturations.fetch_add(1, Ordering::Relaxed) + 1;
+            root.saturated.store(sequence, Ordering::Relaxed);
+        }
+        TempLease { root }
+    }
+
+    fn select(&self, volume: Option<VolumeId>) -> usize {
+        if let Some(index) =
+            volume.and_then(|volume| self.roots.iter().position(|r| r.volume == Some(volume)))
+        {
+            return index;
+        }
+        // Rotate starting root, so ties are spread between roots.
+        let count = self.roots.len();
+        let start = self.rotation.fetch_add(1, Ordering::Relaxed);
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .min_by_key(|&index| {
+                let root = &self.roots[index];
+                (
+                    root.saturated.load(Ordering::Relaxed),
+                    root.leases.load(Ordering::Relaxed),
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    // Output directory may not exist yet, so the nearest existing ancestor is used.
+    fn output_volume(&self, output: &Path) -> Option<VolumeId> {
+        let dir = output.parent()?;
+        let mut volumes = self.volumes.lock().unwrap();
+        if let Some(volume) = volumes.get(dir) {
+            return *volume;
+        }
+        let volume = dir.ancestors().find_map(|path| (self.detect)(path));
+        volumes.insert(dir.to_path_buf(), volume);
+        volume
+    }
+
+    /// Print bytes written to every root, if there are several of them.
+    pub fn print_usage(&self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.roots.len() < 2 {
+            return Ok(());
+        }
+        writeln!(out, "Temporary files written:")?;
+        for root in &self.roots {
+            writeln!(
+                out,
+                "  {}: {} bytes",
+                root.root.display(),
+                root.written.load(Ordering::Relaxed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn volume_id(path: &Path) -> Option<VolumeId> {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| VolumeId(metadata.dev()))
+}
+
+#[cfg(windows)]
+fn volume_id(path: &Path) -> Option<VolumeId> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::path::Component;
+
+    // Volume serial number is not available in stable std, drive letter or share is close enough.
+    let path = fs::canonicalize(path).ok()?;
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => {
+            let mut hasher = DefaultHasher::new();
+            prefix
+                .as_os_str()
+                .to_string_lossy()
+                .to_lowercase()
+                .hash(&mut hasher);
+            Some(VolumeId(hasher.finish()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use crate::io::tempfile::OWNER_FILE;
+    use crate::io::temproots::{TempRoots, VolumeId, SATURATED_LEASES};
+
+    // Volume is named by path component: `slow`, `fast` or `other`.
+    fn mock_volume(path: &Path) -> Option<VolumeId> {
+        path.components()
+            .find_map(|c| match c.as_os_str().to_str() {
+                Some("slow") => Some(VolumeId(1)),
+                Some("fast") => Some(VolumeId(2)),
+                Some("other") => Some(VolumeId(3)),
+                _ => None,
+            })
+    }
+
+    fn temp_roots(base: &Path, names: &[&str]) -> TempRoots {
+        let dirs: Vec<PathBuf> = names.iter().map(|name| base.join(name)).collect();
+        TempRoots::with_detector(&dirs, "octobuild", Duration::MAX, Box::new(mock_volume)).unwrap()
+    }
+
+    fn root_name(path: &Path) -> String {
+        path.parent()
+            .and_then(Path::parent)
+            .unwrap()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_same_volume() {
+        let base = tempfile::tempdir().unwrap();
+        let roots = temp_roots(base.path(), &["slow", "fast"]);
+        assert_eq!(
+            roots.primary().parent().unwrap(),
+            base.path().join("slow").join("octobuild-tmp")
+        );
+        assert!(roots.primary().join(OWNER_FILE).exists());
+
+        let output = Path::new("/build/fast/Intermediate/a.obj");
+        for _ in 0..SATURATED_LEASES * 2 {
+            assert_eq!(root_name(roots.place(Some(output)).path()), "fast");
+        }
+        let output = Path::new("/home/slow/a.obj");
+        assert_eq!(root_name(roots.place(Some(output)).path()), "slow");
+    }
+
+    #[test]
+    fn test_foreign_dirs_kept() {
+        // Root may be a volume root with user directories named like ours.
+        let base = tempfile::tempdir().unwrap();
+        let user_dir = base.path().join("octobuild-src");
+        std::fs::create_dir(&user_dir).unwrap();
+        let roots = TempRoots::with_detector(
+            &[base.path().to_path_buf()],
+            "octobuild",
+            Duration::ZERO,
+            Box::new(mock_volume),
+        )
+        .unwrap();
+        drop(roots);
+        assert!(user_dir.exists());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let base = tempfile::tempdir().unwrap();
+        let roots = temp_roots(base.path(), &["slow", "fast"]);
+        // Output on volume without temporary root: roots are used in turn.
+        let output = Path::new("/build/other/a.obj");
+        let first = roots.place(Some(output));
+        let second = roots.place(Some(output));
+        assert_ne!(root_name(first.path()), root_name(second.path()));
+        let third = roots.place(None);
+        let fourth = roots.place(None);
+        assert_ne!(root_name(third.path()), root_name(fourth.path()));
+    }
+
+    #[test]
+    fn test_least_recently_saturated() {
+        let base = tempfile::tempdir().unwrap();
+        let roots = temp_roots(base.path(), &["slow", "fast"]);
+        let place = |volume: &str| {
+            (0..SATURATED_LEASES)
+                .map(|_| roots.place(Some(&Path::new("/build").join(volume).join("a.obj"))))
+                .collect::<Vec<_>>()
+        };
+        // Saturation is remembered after the files are removed.
+        drop(place("fast"));
+        for _ in 0..SATURATED_LEASES {
+            assert_eq!(root_name(roots.place(None).path()), "slow");
+        }
+        drop(place("slow"));
+        for _ in 0..SATURATED_LEASES {
+            assert_eq!(root_name(roots.place(None).path()), "fast");
+        }
+    }
+
+    #[test]
+    fn test_print_usage() {
+        let base = tempfile::tempdir().unwrap();
+        let roots = temp_roots(base.path(), &["slow", "fast"]);
+        roots
+            .place(Some(Path::new("/build/fast/a.obj")))
+            .add_written(1024);
+        let mut out = Vec::new();
+        roots.print_usage(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "Temporary files written:\n  {}: 0 bytes\n  {}: 1024 bytes\n",
+                base.path().join("slow").display(),
+                base.path().join("fast").display()
+            )
+        );
+
+        // Single root is not worth reporting.
+        let mut out = Vec::new();
+        temp_roots(base.path(), &["other"])
+            .print_usage(&mut out)
+            .unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+// How long to wait for a jobserver byte before checking whether implicit token was released.
+const POLL_SLICE: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum JobServerAuth {
+    Fifo(PathBuf),
+    Fds(i32, i32),
+}
+
+// Client of GNU make jobserver: every running compiler holds one token.
+// Process owns one implicit token, which is never passed through jobserver pipe.
+pub struct JobServer {
+    read: File,
+    write: File,
+    implicit: AtomicBool,
+}
+
+pub enum JobToken {
+    Implicit,
+    Byte(u8),
+}
+
+// Parse jobserver address from MAKEFLAGS, the last option wins like in make itself.
+#[must_use]
+pub fn parse_makeflags(flags: &str) -> Option<JobServerAuth> {
+    let mut result = None;
+    for word in flags.split_whitespace() {
+        let Some(value) = word
+            .strip_prefix("--jobserver-auth=")
+            .or_else(|| word.strip_prefix("--jobserver-fds="))
+        else {
+            continue;
+        };
+        result = if let Some(path) = value.strip_prefix("fifo:") {
+            Some(JobServerAuth::Fifo(PathBuf::from(path)))
+        } else {
+            value.split_once(',').and_then(|(read, write)| {
+                Some(JobServerAuth::Fds(read.parse().ok()?, write.parse().ok()?))
+            })
+        };
+    }
+    result
+}
+
+impl JobServer {
+    #[must_use]
+    pub fn from_env() -> Option<Self> {
+        let flags = std::env::var("MAKEFLAGS").ok()?;
+        let auth = parse_makeflags(&flags)?;
+        match JobServer::open(&auth) {
+            Ok(jobserver) => {
+                info!("Using GNU make jobserver: {auth:?}");
+                Some(jobserver)
+            }
+            Err(e) => {
+                // Make closes jobserver descriptors for commands that are not marked as recursive.
+                debug!("Can't open GNU make jobserver {auth:?}: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn open(auth: &JobServerAuth) -> std::io::Result<Self> {
+        let (read, write) = open_auth(auth)?;
+        Ok(JobServer {
+            read,
+            write,
+            implicit: AtomicBool::new(false),
+        })
+    }
+
+    // Take a token, waiting for it at most `timeout`. Never blocks indefinitely,
+    // so misbehaving jobserver client can't deadlock the build.
+    pub fn acquire(&self, timeout: Duration) -> std::io::Result<Option<JobToken>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self
+                .implicit
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(Some(JobToken::Implicit));
+            }
+            let mut buf = [0_u8; 1];
+            match (&self.read).read(&mut buf) {
+                Ok(1) => return Ok(Some(JobToken::Byte(buf[0]))),
+                Ok(_) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "GNU make jobserver is closed",
+                    ))
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            wait_readable(&self.read, POLL_SLICE.min(deadline - now))?;
+        }
+    }
+
+    pub fn release(&self, token: JobToken) {
+        match token {
+            JobToken::Implicit => self.implicit.store(false, Ordering::Release),
+            JobToken::Byte(byte) => {
+                // Byte must be returned, otherwise make reports lost tokens.
+                if let Err(e) = (&self.write).write_all(&[byte]) {
+                    log::error!("Can't return GNU make jobserver token: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_auth(auth: &JobServerAuth) -> std::io::Result<(File, File)> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Open own file description, so non-blocking mode doesn't affect other jobserver clients.
+    let open = |path: &std::path::Path, write: bool| {
+        OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+    };
+    match auth {
+        JobServerAuth::Fifo(path) => Ok((open(path, false)?, open(path, true)?)),
+        JobServerAuth::Fds(read, write) => Ok((
+            open(&PathBuf::from(format!("/dev/fd/{read}")), false)?,
+            open(&PathBuf::from(format!("/dev/fd/{write}")), true)?,
+        )),
+    }
+}
+
+#[cfg(not(unix))]
+fn open_auth(_: &JobServerAuth) -> std::io::Result<(File, File)> {
+    Err(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "GNU make jobserver is supported on Unix only",
+    ))
+}
+
+#[cfg(unix)]
+fn wait_readable(file: &File, timeout: Duration) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+    if unsafe { libc::poll(&mut fd, 1, timeout) } < 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn wait_readable(_: &File, timeout: Duration) -> std::io::Result<()> {
+    std::thread::sleep(timeout);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::jobserver::{parse_makeflags, JobServerAuth};
+
+    #[test]
+    fn test_parse_makeflags() {
+        assert_eq!(parse_makeflags(" -j8"), None);
+        assert_eq!(
+            parse_makeflags("-j --jobserver-auth=fifo:/tmp/GMfifo123"),
+            Some(JobServerAuth::Fifo(PathBuf::from("/tmp/GMfifo123")))
+        );
+        assert_eq!(
+            parse_makeflags("kr -j4 --jobserver-fds=3,4 --jobserver-auth=5,6"),
+            Some(JobServerAuth::Fds(5, 6))
+        );
+        assert_eq!(parse_makeflags("--jobserver-auth=bad"), None);
+    }
+
+    #[cfg(unix)]
+    mod fifo {
+        use std::ffi::CString;
+        use std::fs::{File, OpenOptions};
+        use std::io::{Read, Write};
+        use std::os::unix::ffi::OsStrExt;
+        use std::path::Path;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use crate::jobserver::{parse_makeflags, JobServer, JobToken};
+
+        // Fake make: fifo with `tokens` bytes in it.
+        fn fake_jobserver(dir: &Path, tokens: usize) -> (File, JobServer) {
+            let path = dir.join("fifo");
+            let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+            assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+            let mut make = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            make.write_all(&vec![b'+'; tokens]).unwrap();
+            let flags = format!("-j{} --jobserver-auth=fifo:{}", tokens + 1, path.display());
+            let jobserver = JobServer::open(&parse_makeflags(&flags).unwrap()).unwrap();
+            (make, jobserver)
+        }
+
+        #[test]
+        fn test_acquire_release() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mut make, jobserver) = fake_jobserver(dir.path(), 1);
+            let timeout = Duration::from_millis(200);
+
+            let implicit = jobserver.acquire(timeout).unwrap().unwrap();
+            assert!(matches!(implicit, JobToken::Implicit));
+            let byte = jobserver.acquire(timeout).unwrap().unwrap();
+            assert!(matches!(byte, JobToken::Byte(b'+')));
+
+            let started = Instant::now();
+            assert!(jobserver.acquire(timeout).unwrap().is_none());
+            assert!(started.elapsed() >= timeout);
+
+            // Token goes back to the pipe, where make expects it.
+            jobserver.release(byte);
+            let mut buf = [0_u8; 1];
+            make.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, [b'+']);
+
+            // Implicit token is usable again without jobserver bytes.
+            jobserver.release(implicit);
+            assert!(matches!(
+                jobserver.acquire(timeout).unwrap(),
+                Some(JobToken::Implicit)
+            ));
+        }
+
+        #[test]
+        fn test_wait_for_release() {
+            let dir = tempfile::tempdir().unwrap();
+            let (_make, jobserver) = fake_jobserver(dir.path(), 0);
+            let implicit = jobserver.acquire(Duration::ZERO).unwrap().unwrap();
+            thread::scope(|scope| {
+                let waiter = scope.spawn(|| jobserver.acquire(Duration::from_secs(30)));
+                thread::sleep(Duration::from_millis(50));
+                jobserver.release(implicit);
+                assert!(matches!(
+                    waiter.join().unwrap().unwrap(),
+                    Some(JobToken::Implicit)
+                ));
+            });
+        }
+    }
+}
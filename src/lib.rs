@@ -4,31 +4,60 @@ use std::path::{Path, PathBuf};
 use crate::Error::IO;
 use thiserror::Error;
 
+use crate::compiler::TaskError;
 use crate::io::filecache::CacheError;
+use crate::vs::coff::CoffError;
 use crate::vs::postprocess::PostprocessError;
+use crate::worker::TaskFailure;
 
+pub mod annotations;
+pub mod bigobj;
 pub mod cache;
 
 pub mod cluster {
     pub mod builder;
     pub mod client;
     pub mod common;
+    pub mod dispatch;
 }
 
 pub mod compiler;
 pub mod config;
+pub mod console;
+pub mod determinism;
+pub mod diagnostics;
+pub mod etw;
+pub mod exitreport;
+pub mod hook;
+pub mod includes;
+pub mod jobserver;
 pub mod lazy;
+pub mod manifest;
+pub mod outputs;
+pub mod overrides;
+pub mod pchreuse;
+pub mod phases;
+pub mod prewarm;
+pub mod provenance;
+pub mod reaper;
 pub mod utils;
 pub mod version;
+pub mod warmup;
+pub mod watchdog;
 
 pub mod io {
     pub mod binary;
+    pub mod chunks;
     pub mod counter;
     pub mod filecache;
-    pub mod memcache;
     pub mod memstream;
+    pub mod prefix;
+    pub mod projection;
+    pub mod statcache;
     pub mod statistic;
+    pub mod taskoutput;
     pub mod tempfile;
+    pub mod temproots;
 }
 
 pub mod xg {
@@ -36,6 +65,8 @@ pub mod xg {
 }
 
 pub mod vs {
+    pub mod codepage;
+    pub mod coff;
     pub mod compiler;
     pub mod postprocess;
     pub mod prepare;
@@ -47,20 +78,45 @@ pub mod clang {
 }
 
 pub mod cmd {
+    pub mod msvc;
     pub mod native;
 }
 
+pub mod run;
+pub mod schedule;
+pub mod service;
+pub mod shadow;
+pub mod shim;
 pub mod simple;
+pub mod slots;
+pub mod sourcelock;
+pub mod transform;
 pub mod worker;
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Bincode(#[from] bincode::Error),
+    #[error("Build failed: {} task(s) failed", .0.len())]
+    BuildFailed(Vec<TaskFailure>),
     #[error(transparent)]
     Cache(#[from] CacheError),
+    #[error(transparent)]
+    Coff(#[from] CoffError),
     #[error("Found cycles in build graph")]
     CyclesInBuildGraph,
+    #[error("Not enough disk space to write {path} ({written} bytes written): {error}")]
+    DiskFull {
+        path: PathBuf,
+        written: usize,
+        error: std::io::Error,
+    },
+    #[error("File {path} is locked by another process ({attempts} attempts): {error}")]
+    FileLocked {
+        path: PathBuf,
+        attempts: usize,
+        error: std::io::Error,
+    },
     #[error("Failed to open file {path}: {error}")]
     FileOpen {
         path: PathBuf,
@@ -78,8 +134,24 @@ pub enum Error {
     Generic(String),
     #[error(transparent)]
     IO(std::io::Error),
+    #[error("Include file case mismatch: {spelled} (on disk: {actual})")]
+    IncludeCaseMismatch { spelled: String, actual: String },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Object file {} is built for {actual} instead of {expected} ({origin})", .path.display())]
+    MachineMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+        origin: &'static str,
+    },
     #[error("Build task files not found")]
     NoTaskFiles,
+    #[error("Compiler outputs don't match task: missing {missing:?}, unexpected {unexpected:?}")]
+    OutputMismatch {
+        missing: Vec<PathBuf>,
+        unexpected: Vec<PathBuf>,
+    },
     #[error("Failed to compile {path}: {error}")]
     Compilation {
         path: PathBuf,
@@ -96,8 +168,8 @@ pub enum Error {
     QuoteError(#[from] QuoteError),
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
-    #[error("Toolchain not found: {0}")]
-    ToolchainNotFound(PathBuf),
+    #[error(transparent)]
+    Task(#[from] TaskError),
 }
 
 impl From<std::io::Error> for Error {
@@ -118,7 +190,54 @@ impl From<&str> for Error {
     }
 }
 
+// Check if write failed because of disk space or quota limits.
+fn is_disk_full(error: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: [i32; 2] = [libc::ENOSPC, libc::EDQUOT];
+    // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL, ERROR_DISK_QUOTA_EXCEEDED
+    #[cfg(windows)]
+    const CODES: [i32; 3] = [39, 112, 1295];
+
+    error.kind() == std::io::ErrorKind::StorageFull
+        || error
+            .raw_os_error()
+            .is_some_and(|code| CODES.contains(&code))
+}
+
 impl Error {
+    // Code of diagnostic reported for task that failed with this error.
+    #[must_use]
+    pub fn diagnostic_code(&self) -> &'static str {
+        match self {
+            Error::IncludeCaseMismatch { .. } => diagnostics::CODE_INCLUDE_CASE,
+            Error::OutputMismatch { .. } => diagnostics::CODE_OUTPUT_MISMATCH,
+            Error::MachineMismatch { .. } => diagnostics::CODE_MACHINE_MISMATCH,
+            Error::Compilation { error, .. } | Error::Postprocess { error, .. } => {
+                error.diagnostic_code()
+            }
+            _ => diagnostics::CODE_TASK_ERROR,
+        }
+    }
+
+    // Add destination to disk space errors.
+    #[must_use]
+    pub fn for_write(self, path: &Path, written: usize) -> Self {
+        match self {
+            IO(error) if is_disk_full(&error) => Error::DiskFull {
+                path: path.to_path_buf(),
+                written,
+                error,
+            },
+            e => e,
+        }
+    }
+
+    // Failure is caused by build machine state, not by compiled code, so task can be retried.
+    #[must_use]
+    pub fn is_infrastructure(&self) -> bool {
+        matches!(self, Error::DiskFull { .. } | Error::FileLocked { .. })
+    }
+
     fn send_error<T>(error: crossbeam_channel::SendError<T>) -> Self {
         Error::Generic(error.to_string())
     }
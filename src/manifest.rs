@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::compiler::{CompilationTask, TaskId};
+use crate::io::statcache::{FileHasher, StatCache};
+use crate::io::tempfile::write_atomic;
+
+// Build manifest lets repeated invocation without a build system skip tasks whose inputs and
+// outputs didn't change since the previous run. Inputs are files from #line directives of
+// preprocessed output, so a changed header re-runs only the tasks including it.
+pub struct BuildManifest {
+    path: PathBuf,
+    // Entries of the previous run, empty unless tasks are skipped incrementally.
+    previous: HashMap<TaskId, ManifestEntry>,
+    current: Mutex<BTreeMap<TaskId, ManifestEntry>>,
+    up_to_date: AtomicUsize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    tasks: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    task: TaskId,
+    // Digest of compiler, toolchain identifier and arguments.
+    key: String,
+    // Files with content hashes.
+    inputs: Vec<(PathBuf, String)>,
+    outputs: Vec<(PathBuf, String)>,
+}
+
+impl BuildManifest {
+    // Previous manifest is read only for incremental run, otherwise it is overwritten.
+    pub fn new(path: PathBuf, incremental: bool) -> crate::Result<Self> {
+        let mut previous = HashMap::new();
+        if incremental {
+            match fs::read(&path) {
+                Ok(data) => {
+                    let file: ManifestFile = serde_json::from_slice(&data)?;
+                    for entry in file.tasks {
+                        previous.insert(entry.task.clone(), entry);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(BuildManifest {
+            path,
+            previous,
+            current: Mutex::new(BTreeMap::new()),
+            up_to_date: AtomicUsize::new(0),
+        })
+    }
+
+    // Count of tasks skipped as up-to-date.
+    #[must_use]
+    pub fn up_to_date(&self) -> usize {
+        self.up_to_date.load(Ordering::Relaxed)
+    }
+
+    #[must_use]
+    pub fn task_key(task: &CompilationTask, identifier: Option<&str>) -> String {
+        let mut hasher = Sha256::new();
+        let mut update = |value: &str| {
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value.as_bytes());
+        };
+        update(&task.shared.command.program.to_string_lossy());
+        update(identifier.unwrap_or_default());
+        update(&task.language);
+        update(&format!("{:?}", task.shared.args));
+        hex::encode(hasher.finalize())
+    }
+
+    // Check if task can be skipped: arguments are the same, inputs are unchanged and outputs
+    // are still present with recorded content. Up-to-date entry is kept in the new manifest.
+    pub fn check(&self, task: &CompilationTask, key: &str, files: &StatCache) -> bool {
+        let id = task.id();
+        let Some(entry) = self.previous.get(&id) else {
+            return false;
+        };
+        if entry.key != key
+            || !entry
+                .inputs
+                .iter()
+                .chain(entry.outputs.iter())
+                .all(|(path, hash)| {
+                    files
+                        .file_hash(path)
+                        .map_or(false, |actual| &actual.hash == hash)
+                })
+        {
+            return false;
+        }
+        self.current.lock().unwrap().insert(id, entry.clone());
+        self.up_to_date.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    // Record successfully compiled task with include files from preprocessed output.
+    pub fn record(
+        &self,
+        task: &CompilationTask,
+        key: &str,
+        files: &StatCache,
+        includes: HashSet<String>,
+    ) {
+        match self.create_entry(task, key, files, includes) {
+            Ok(entry) => {
+                self.current
+                    .lock()
+                    .unwrap()
+                    .insert(entry.task.clone(), entry);
+            }
+            Err(e) => warn!(
+                "Can't add {} to build manifest: {e}",
+                task.input_source.display()
+            ),
+        }
+    }
+
+    fn create_entry(
+        &self,
+        task: &CompilationTask,
+        key: &str,
+        files: &StatCache,
+        includes: HashSet<String>,
+    ) -> crate::Result<ManifestEntry> {
+        let resolve = |path: &Path| match &task.shared.command.current_dir {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        };
+        let hashed = |paths: Vec<PathBuf>| -> crate::Result<Vec<(PathBuf, String)>> {
+            let mut result = Vec::new();
+            for path in paths {
+                let hash = files.file_hash(&path)?.hash;
+                result.push((path, hash));
+            }
+            result.sort();
+            result.dedup();
+            Ok(result)
+        };
+        let mut inputs: Vec<PathBuf> = includes
+            .iter()
+            .map(|name| resolve(Path::new(name)))
+            .collect();
+        inputs.push(resolve(&task.input_source));
+        inputs.extend(task.shared.pch_usage.get_in_abs().cloned());
+        let mut outputs = vec![resolve(&task.output_object)];
+        outputs.extend(task.shared.pch_usage.get_out_abs().cloned());
+        Ok(ManifestEntry {
+            task: task.id(),
+            key: key.to_string(),
+            inputs: hashed(inputs)?,
+            outputs: hashed(outputs)?,
+        })
+    }
+
+    // Write entries of all up-to-date and successfully compiled tasks of the run.
+    pub fn save(&self) -> crate::Result<()> {
+        let file = ManifestFile {
+            tasks: self.current.lock().unwrap().values().cloned().collect(),
+        };
+        let data = serde_json::to_vec_pretty(&file)?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&self.path, |file| Ok(file.write_all(&data)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::compiler::{CommandInfo, CompilationArgs, CompilationTask, PCHUsage};
+    use crate::io::statcache::StatCache;
+    use crate::manifest::BuildManifest;
+
+    #[test]
+    fn test_manifest_invalidation() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("manifest.json");
+        let header = temp.path().join("b.h");
+        let task = |name: &str| CompilationTask {
+            shared: Arc::new(CompilationArgs {
+                command: CommandInfo::simple(PathBuf::from("cl.exe")),
+                args: Vec::new(),
+                pch_usage: PCHUsage::None,
+                deps_file: None,
+                run_second_cpp: false,
+                notes: Vec::new(),
+                config: None,
+                link: None,
+            }),
+            language: "P".to_string(),
+            input_source: temp.path().join(format!("{name}.cpp")),
+            output_object: temp.path().join(format!("{name}.obj")),
+        };
+        let (a, c) = (task("a"), task("c"));
+        for name in ["a.cpp", "c.cpp", "a.obj", "c.obj"] {
+            fs::write(temp.path().join(name), name).unwrap();
+        }
+        fs::write(&header, "int b;\n").unwrap();
+        let key = BuildManifest::task_key(&a, None);
+
+        let manifest = BuildManifest::new(path.clone(), true).unwrap();
+        assert!(!manifest.check(&a, &key, &StatCache::new(16)));
+        let includes = HashSet::from([header.display().to_string()]);
+        manifest.record(&a, &key, &StatCache::new(16), includes);
+        manifest.record(&c, &key, &StatCache::new(16), HashSet::new());
+        manifest.save().unwrap();
+
+        let manifest = BuildManifest::new(path.clone(), true).unwrap();
+        assert!(manifest.check(&a, &key, &StatCache::new(16)));
+        assert!(!manifest.check(&a, "other", &StatCache::new(16)));
+        assert_eq!(manifest.up_to_date(), 1);
+
+        // Changed header invalidates only the task including it.
+        fs::write(&header, "int c;\n").unwrap();
+        assert!(!manifest.check(&a, &key, &StatCache::new(16)));
+        assert!(manifest.check(&c, &key, &StatCache::new(16)));
+
+        // Output must keep recorded content.
+        fs::write(temp.path().join("c.obj"), "changed").unwrap();
+        assert!(!manifest.check(&c, &key, &StatCache::new(16)));
+
+        // Manifest isn't read without incremental run.
+        fs::write(temp.path().join("c.obj"), "c.obj").unwrap();
+        let manifest = BuildManifest::new(path, false).unwrap();
+        assert!(!manifest.check(&c, &key, &StatCache::new(16)));
+    }
+}
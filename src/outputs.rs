@@ -0,0 +1,261 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+
+use crate::config::{DiagnosticStyle, OutputCheck};
+use crate::diagnostics::{format_diagnostic, Location, Severity, CODE_UNEXPECTED_OUTPUT};
+use crate::utils::path_key;
+
+// File systems with coarse timestamps (FAT) can report modification time before task start.
+const MTIME_SLACK: Duration = Duration::from_secs(2);
+
+// Compares files written by compiler with outputs declared by tasks.
+pub struct OutputChecker {
+    mode: OutputCheck,
+    // Outputs of all tasks seen so far: concurrent tasks write into the same directories.
+    declared: Mutex<HashSet<PathBuf>>,
+    unexpected: Mutex<BTreeSet<(PathBuf, PathBuf)>>,
+}
+
+// Directory state before compiler run.
+pub struct OutputSnapshot {
+    started: SystemTime,
+    stem: String,
+    files: HashMap<PathBuf, (SystemTime, u64)>,
+    dirs: Vec<PathBuf>,
+}
+
+impl OutputChecker {
+    #[must_use]
+    pub fn new(mode: OutputCheck) -> Self {
+        OutputChecker {
+            mode,
+            declared: Mutex::default(),
+            unexpected: Mutex::default(),
+        }
+    }
+
+    pub fn declare(&self, outputs: &[PathBuf]) {
+        if self.mode == OutputCheck::Off {
+            return;
+        }
+        let mut declared = self.declared.lock().unwrap();
+        declared.extend(outputs.iter().map(|path| path_key(path)));
+    }
+
+    // Remember files that compiler could write instead of declared outputs:
+    // files named after source in output directories and compiler working directory.
+    #[must_use]
+    pub fn snapshot(
+        &self,
+        source: &Path,
+        current_dir: Option<&Path>,
+        outputs: &[PathBuf],
+    ) -> Option<OutputSnapshot> {
+        if self.mode == OutputCheck::Off {
+            return None;
+        }
+        let stem = file_stem(source)?;
+        let mut dirs: Vec<PathBuf> = outputs
+            .iter()
+            .filter_map(|path| path.parent())
+            .chain(current_dir)
+            .map(Path::to_path_buf)
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        let files = scan(&dirs, &stem);
+        Some(OutputSnapshot {
+            started: SystemTime::now(),
+            stem,
+            files,
+            dirs,
+        })
+    }
+
+    // Check compiler results against snapshot. Missing declared output is always an error:
+    // it would break cache store and linking anyway. Unexpected files fail task in strict mode only.
+    pub fn verify(
+        &self,
+        source: &Path,
+        snapshot: &OutputSnapshot,
+        outputs: &[PathBuf],
+    ) -> crate::Result<()> {
+        let window_start = snapshot
+            .started
+            .checked_sub(MTIME_SLACK)
+            .unwrap_or(snapshot.started);
+        let missing: Vec<PathBuf> = outputs
+            .iter()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .map_or(true, |modified| modified < window_start)
+            })
+            .cloned()
+            .collect();
+
+        let unexpected: Vec<PathBuf> = {
+            let declared = self.declared.lock().unwrap();
+            scan(&snapshot.dirs, &snapshot.stem)
+                .into_iter()
+                .filter(|(path, state)| {
+                    snapshot.files.get(path) != Some(state) && !declared.contains(&path_key(path))
+                })
+                .map(|(path, _)| path)
+                .collect()
+        };
+        if !unexpected.is_empty() {
+            let mut recorded = self.unexpected.lock().unwrap();
+            for path in &unexpected {
+                warn!(
+                    "Compiler wrote unexpected file {} for {}",
+                    path.display(),
+                    source.display()
+                );
+                recorded.insert((path.clone(), source.to_path_buf()));
+            }
+        }
+        if !missing.is_empty() || (self.mode == OutputCheck::Strict && !unexpected.is_empty()) {
+            return Err(crate::Error::OutputMismatch {
+                missing,
+                unexpected,
+            });
+        }
+        Ok(())
+    }
+
+    // Unexpected files with source files that produced them.
+    #[must_use]
+    pub fn unexpected(&self) -> Vec<(PathBuf, PathBuf)> {
+        self.unexpected.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn print_warnings(
+        &self,
+        out: &mut impl Write,
+        style: DiagnosticStyle,
+    ) -> crate::Result<()> {
+        let unexpected = self.unexpected();
+        if unexpected.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "WARNING: Compiler wrote files not declared as task outputs:"
+        )?;
+        for (path, source) in unexpected {
+            let message = format!("Unexpected output file {}", path.display());
+            writeln!(
+                out,
+                "{}",
+                format_diagnostic(
+                    style,
+                    Location::file(&source),
+                    Severity::Warning,
+                    CODE_UNEXPECTED_OUTPUT,
+                    &message
+                )
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn file_stem(path: &Path) -> Option<String> {
+    Some(path.file_stem()?.to_string_lossy().to_lowercase())
+}
+
+fn scan(dirs: &[PathBuf], stem: &str) -> HashMap<PathBuf, (SystemTime, u64)> {
+    let mut result = HashMap::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if file_stem(&path).as_deref() != Some(stem) {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            if let Ok(modified) = metadata.modified() {
+                result.insert(path, (modified, metadata.len()));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::config::OutputCheck;
+    use crate::outputs::OutputChecker;
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("a.cpp");
+        let object = dir.path().join("obj").join("a.obj");
+        let other = dir.path().join("obj").join("a.pch");
+        fs::create_dir_all(object.parent().unwrap()).unwrap();
+        fs::write(dir.path().join("a.h"), "").unwrap();
+
+        let checker = OutputChecker::new(OutputCheck::Warn);
+        checker.declare(&[other.clone()]);
+        checker.declare(&[object.clone()]);
+        let outputs = vec![object.clone()];
+        let snapshot = checker
+            .snapshot(&source, Some(dir.path()), &outputs)
+            .unwrap();
+
+        // Declared output is missing.
+        assert!(checker.verify(&source, &snapshot, &outputs).is_err());
+
+        // Outputs declared by other tasks and files named after other sources are not reported.
+        fs::write(&object, "obj").unwrap();
+        fs::write(&other, "obj").unwrap();
+        fs::write(dir.path().join("b.exe"), "").unwrap();
+        checker.verify(&source, &snapshot, &outputs).unwrap();
+        assert!(checker.unexpected().is_empty());
+
+        // Stray file named after source is reported, but doesn't fail task in warn mode.
+        let stray = dir.path().join("a.obj");
+        fs::write(&stray, "obj").unwrap();
+        checker.verify(&source, &snapshot, &outputs).unwrap();
+        assert_eq!(checker.unexpected(), vec![(stray.clone(), source.clone())]);
+
+        let strict = OutputChecker::new(OutputCheck::Strict);
+        strict.declare(&outputs);
+        let snapshot = strict
+            .snapshot(&source, Some(dir.path()), &outputs)
+            .unwrap();
+        fs::write(&object, "obj2").unwrap();
+        // Unchanged stray file existed before compiler run.
+        strict.verify(&source, &snapshot, &outputs).unwrap();
+        fs::write(&stray, "obj2").unwrap();
+        assert!(strict.verify(&source, &snapshot, &outputs).is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let checker = OutputChecker::new(OutputCheck::Off);
+        let outputs = vec![PathBuf::from("/nonexistent/a.obj")];
+        checker.declare(&outputs);
+        assert!(checker
+            .snapshot(&PathBuf::from("a.cpp"), None, &outputs)
+            .is_none());
+    }
+}
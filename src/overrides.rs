@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use figment::providers::{Format, Toml};
+use figment::Figment;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::worker::BuildAction;
+
+// Per-directory settings file, looked up in source directory and its parents.
+pub const OVERRIDES_FILE: &str = "octobuild.toml";
+
+// Settings that subtree of the source tree can override in its octobuild.toml.
+// Only these settings are accepted, unknown keys are reported as errors.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct OverridesFile {
+    no_cache: Option<bool>,
+    no_remote: Option<bool>,
+    ignore_flags: Option<Vec<String>>,
+    key_salt: Option<String>,
+    native_diagnostics: Option<bool>,
+}
+
+// Effective settings of task after merging octobuild.toml files, nearest file wins.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TaskOverrides {
+    // Task is compiled without reading and writing cache.
+    pub no_cache: bool,
+    // Task is always compiled locally.
+    pub no_remote: bool,
+    // Prefixes of compiler arguments that are left out of cache key.
+    pub ignore_flags: Vec<String>,
+    // Text mixed into cache key, changing it invalidates cached results of the subtree.
+    pub key_salt: Option<String>,
+    // Compiler output is kept as is, like with `--raw-output`.
+    pub native_diagnostics: bool,
+    // Applied files, nearest last.
+    pub files: Vec<PathBuf>,
+}
+
+impl TaskOverrides {
+    fn merge(&self, path: PathBuf, file: OverridesFile) -> Self {
+        let mut files = self.files.clone();
+        files.push(path);
+        TaskOverrides {
+            no_cache: file.no_cache.unwrap_or(self.no_cache),
+            no_remote: file.no_remote.unwrap_or(self.no_remote),
+            ignore_flags: file
+                .ignore_flags
+                .unwrap_or_else(|| self.ignore_flags.clone()),
+            key_salt: file.key_salt.or_else(|| self.key_salt.clone()),
+            native_diagnostics: file.native_diagnostics.unwrap_or(self.native_diagnostics),
+            files,
+        }
+    }
+
+    #[must_use]
+    pub fn is_ignored(&self, arg: &str) -> bool {
+        self.ignore_flags
+            .iter()
+            .any(|prefix| arg.starts_with(prefix.as_str()))
+    }
+}
+
+impl fmt::Display for TaskOverrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.files.is_empty() {
+            return write!(f, "no overrides");
+        }
+        write!(
+            f,
+            "no_cache={}, no_remote={}, ignore_flags={:?}, key_salt={:?}, native_diagnostics={} (from ",
+            self.no_cache,
+            self.no_remote,
+            self.ignore_flags,
+            self.key_salt.as_deref().unwrap_or_default(),
+            self.native_diagnostics
+        )?;
+        for (index, path) in self.files.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", path.display())?;
+        }
+        write!(f, ")")
+    }
+}
+
+// Finds octobuild.toml files of task sources. The walk up stops at configured root or at
+// filesystem boundary, results are memoized per directory for the whole build.
+pub struct DirOverrides {
+    root: Option<PathBuf>,
+    // Error text is kept, so every task of the directory fails with it.
+    dirs: Mutex<HashMap<PathBuf, Result<Arc<TaskOverrides>, String>>>,
+    // Count of directories looked into.
+    probes: AtomicUsize,
+}
+
+impl DirOverrides {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        DirOverrides {
+            root: config.overrides_root.clone(),
+            dirs: Mutex::default(),
+            probes: AtomicUsize::new(0),
+        }
+    }
+
+    #[must_use]
+    pub fn probes(&self) -> usize {
+        self.probes.load(Ordering::Relaxed)
+    }
+
+    // Overrides for settings checked after task has started, tasks with invalid overrides
+    // fail before that.
+    #[must_use]
+    pub fn get(&self, source: &Path) -> Arc<TaskOverrides> {
+        self.lookup(source).unwrap_or_default()
+    }
+
+    // Effective overrides of task with given source file.
+    pub fn lookup(&self, source: &Path) -> crate::Result<Arc<TaskOverrides>> {
+        match source.parent() {
+            Some(dir) => Ok(self.resolve(dir)?),
+            None => Ok(Arc::default()),
+        }
+    }
+
+    fn resolve(&self, dir: &Path) -> Result<Arc<TaskOverrides>, String> {
+        if let Some(result) = self.dirs.lock().unwrap().get(dir) {
+            return result.clone();
+        }
+        let base = match dir.parent() {
+            Some(parent) if !self.is_boundary(dir, parent) => self.resolve(parent)?,
+            _ => Arc::default(),
+        };
+        self.probes.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(OVERRIDES_FILE);
+        let result = match fs::read_to_string(&path) {
+            Ok(text) => Figment::from(Toml::string(&text))
+                .extract::<OverridesFile>()
+                .map(|file| Arc::new(base.merge(path.clone(), file)))
+                .map_err(|e| format!("Invalid {}: {e}", path.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(base),
+            Err(e) => Err(format!("Can't read {}: {e}", path.display())),
+        };
+        self.dirs
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), result.clone());
+        result
+    }
+
+    // Directory is the last one looked into, its parent is above the root.
+    fn is_boundary(&self, dir: &Path, parent: &Path) -> bool {
+        if self.root.as_deref() == Some(dir) {
+            return true;
+        }
+        !same_device(dir, parent)
+    }
+
+    // Print effective overrides of compilation actions.
+    pub fn explain(&self, out: &mut impl Write, actions: &[BuildAction]) -> crate::Result<()> {
+        for action in actions {
+            if let BuildAction::Compilation(_, task) = action {
+                let source = task.input_source.display();
+                match self.lookup(&task.input_source) {
+                    Ok(overrides) => writeln!(out, "Explain {source}: {overrides}")?,
+                    Err(e) => writeln!(out, "Explain {source}: {e}")?,
+                }
+                let command = &task.shared.command;
+                if let Some(original) = &command.remapped_from {
+                    writeln!(
+                        out,
+                        "Explain {source}: tool {} remapped from {}",
+                        command.program.display(),
+                        original.display()
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+// Drive root has no parent, so walk never leaves the volume.
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::config::Config;
+    use crate::overrides::DirOverrides;
+
+    #[test]
+    fn test_nearest_file_wins() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().to_path_buf();
+        fs::create_dir_all(root.join("game/engine/core")).unwrap();
+        fs::write(
+            root.join("octobuild.toml"),
+            "no_cache = true\nkey_salt = \"outer\"\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("game/engine/octobuild.toml"),
+            "no_cache = false\nno_remote = true\nignore_flags = [\"/FS\"]\n",
+        )
+        .unwrap();
+        let overrides = DirOverrides::new(&Config {
+            overrides_root: Some(root.clone()),
+            ..Config::default()
+        });
+
+        let top = overrides.lookup(&root.join("game/a.cpp")).unwrap();
+        assert!(top.no_cache);
+        assert!(!top.no_remote);
+        assert_eq!(top.key_salt.as_deref(), Some("outer"));
+        assert_eq!(
+            top.to_string(),
+            format!(
+                "no_cache=true, no_remote=false, ignore_flags=[], key_salt=\"outer\", native_diagnostics=false (from {})",
+                root.join("octobuild.toml").display()
+            )
+        );
+
+        let nested = overrides
+            .lookup(&root.join("game/engine/core/b.cpp"))
+            .unwrap();
+        assert!(!nested.no_cache);
+        assert!(nested.no_remote);
+        assert!(nested.is_ignored("/FS"));
+        assert_eq!(nested.key_salt.as_deref(), Some("outer"));
+        assert_eq!(
+            nested.files,
+            [
+                root.join("octobuild.toml"),
+                root.join("game/engine/octobuild.toml")
+            ]
+        );
+
+        // Every directory is looked into once.
+        assert_eq!(overrides.probes(), 4);
+        overrides.lookup(&root.join("game/c.cpp")).unwrap();
+        overrides
+            .lookup(&root.join("game/engine/core/d.cpp"))
+            .unwrap();
+        assert_eq!(overrides.probes(), 4);
+
+        // Files above the root are not read.
+        let overrides = DirOverrides::new(&Config {
+            overrides_root: Some(root.join("game/engine")),
+            ..Config::default()
+        });
+        let nested = overrides
+            .lookup(&root.join("game/engine/core/b.cpp"))
+            .unwrap();
+        assert_eq!(nested.files, [root.join("game/engine/octobuild.toml")]);
+        assert_eq!(nested.key_salt, None);
+
+        // Unknown settings are rejected.
+        fs::write(root.join("game/octobuild.toml"), "cache_limit_mb = 1\n").unwrap();
+        let overrides = DirOverrides::new(&Config {
+            overrides_root: Some(root.clone()),
+            ..Config::default()
+        });
+        assert!(overrides
+            .lookup(&root.join("game/engine/core/b.cpp"))
+            .is_err());
+    }
+}
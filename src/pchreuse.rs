@@ -0,0 +1,252 @@
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::compiler::CompilationTask;
+use crate::config::Config;
+use crate::includes::LineDirectiveScanner;
+use crate::io::statcache::{FileHasher, StatCache};
+use crate::io::tempfile::write_atomic;
+
+// Cache subdirectory with preprocessed output of tasks using precompiled header.
+const REUSE_DIR: &str = "pch-reuse";
+
+// Tasks using precompiled header preprocess the whole header region only to throw it away.
+// Filtered output is stored per task along with hashes of files it was produced from,
+// precompiled header file stands for everything before the marker.
+pub struct PchReuse {
+    // None if reuse is disabled.
+    dir: Option<PathBuf>,
+    // Every Nth reused output is compared with the normal preprocessor run.
+    check_interval: usize,
+    reused: AtomicUsize,
+    mismatches: AtomicUsize,
+    // Reuse is turned off for the rest of the build after the first mismatch.
+    failed: AtomicBool,
+}
+
+pub enum Reuse {
+    Hit(Vec<u8>),
+    // Reused output must be checked against preprocessor.
+    Check(Vec<u8>),
+    Miss,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReuseEntry {
+    key: String,
+    // Files from #line directives of preprocessed output with content hashes.
+    files: Vec<(PathBuf, String)>,
+    content: Vec<u8>,
+}
+
+impl PchReuse {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        PchReuse {
+            dir: config.pch_reuse.then(|| config.cache.join(REUSE_DIR)),
+            check_interval: config.pch_reuse_check,
+            reused: AtomicUsize::new(0),
+            mismatches: AtomicUsize::new(0),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some() && !self.failed.load(Ordering::Relaxed)
+    }
+
+    // Count of reused outputs that didn't match preprocessor.
+    #[must_use]
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.load(Ordering::Relaxed)
+    }
+
+    // Entry is stored by task, so repeated builds overwrite it instead of piling up.
+    fn entry_path(&self, task: &CompilationTask) -> Option<PathBuf> {
+        let mut hasher = Sha256::new();
+        hasher.update(task.input_source.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(task.output_object.to_string_lossy().as_bytes());
+        let hash = hex::encode(hasher.finalize());
+        Some(self.dir.as_ref()?.join(&hash[0..2]).join(&hash[2..]))
+    }
+
+    pub fn lookup(&self, task: &CompilationTask, key: &str, files: &StatCache) -> Reuse {
+        if !self.is_enabled() {
+            return Reuse::Miss;
+        }
+        let Some(path) = self.entry_path(task) else {
+            return Reuse::Miss;
+        };
+        let entry: ReuseEntry = match fs::read(&path) {
+            Ok(data) => match bincode::deserialize(&data) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Can't read preprocessed output {}: {e}", path.display());
+                    return Reuse::Miss;
+                }
+            },
+            Err(e) => {
+                if e.kind() != ErrorKind::NotFound {
+                    warn!("Can't read preprocessed output {}: {e}", path.display());
+                }
+                return Reuse::Miss;
+            }
+        };
+        if entry.key != key
+            || !entry.files.iter().all(|(path, hash)| {
+                files
+                    .file_hash(path)
+                    .map_or(false, |actual| &actual.hash == hash)
+            })
+        {
+            return Reuse::Miss;
+        }
+        let count = self.reused.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.check_interval > 0 && count % self.check_interval == 0 {
+            Reuse::Check(entry.content)
+        } else {
+            Reuse::Hit(entry.content)
+        }
+    }
+
+    // Store preprocessor output. Reused output checked against it turns reuse off on mismatch:
+    // compilation cache key is computed from preprocessed output, so equal outputs give the same
+    // object file.
+    pub fn update(
+        &self,
+        task: &CompilationTask,
+        key: &str,
+        files: &StatCache,
+        content: &[u8],
+        reused: Option<&[u8]>,
+    ) {
+        if let Some(reused) = reused {
+            if reused == content {
+                return;
+            }
+            self.mismatches.fetch_add(1, Ordering::Relaxed);
+            self.failed.store(true, Ordering::Relaxed);
+            warn!(
+                "Reused preprocessed output of {} doesn't match preprocessor, reuse is disabled",
+                task.input_source.display()
+            );
+            if let Some(path) = self.entry_path(task) {
+                drop(fs::remove_file(path));
+            }
+            return;
+        }
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(path) = self.entry_path(task) else {
+            return;
+        };
+        if let Err(e) = self.write_entry(task, key, files, content, &path) {
+            warn!("Can't write preprocessed output {}: {e}", path.display());
+        }
+    }
+
+    fn write_entry(
+        &self,
+        task: &CompilationTask,
+        key: &str,
+        files: &StatCache,
+        content: &[u8],
+        path: &Path,
+    ) -> crate::Result<()> {
+        let mut scanner = LineDirectiveScanner::new();
+        scanner.write_all(content)?;
+        let mut entry = ReuseEntry {
+            key: key.to_string(),
+            files: Vec::new(),
+            content: content.to_vec(),
+        };
+        for name in scanner.into_paths() {
+            let file = match &task.shared.command.current_dir {
+                Some(dir) => dir.join(&name),
+                None => PathBuf::from(&name),
+            };
+            let hash = files.file_hash(&file)?.hash;
+            entry.files.push((file, hash));
+        }
+        entry.files.sort();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = bincode::serialize(&entry)?;
+        write_atomic(path, |file| Ok(file.write_all(&data)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use crate::compiler::{CommandInfo, CompilationArgs, CompilationTask, PCHUsage};
+    use crate::config::Config;
+    use crate::io::statcache::StatCache;
+    use crate::pchreuse::{PchReuse, Reuse};
+
+    #[test]
+    fn test_reuse_invalidation() {
+        let temp = tempfile::tempdir().unwrap();
+        let header = temp.path().join("b.h");
+        fs::write(&header, "int b;\n").unwrap();
+        let content = format!(
+            "#pragma hdrstop\n#line 1 \"{}\"\nint b;\n",
+            header.display()
+        );
+        let config = Config {
+            cache: temp.path().join("cache"),
+            pch_reuse: true,
+            pch_reuse_check: 2,
+            ..Config::default()
+        };
+        let reuse = PchReuse::new(&config);
+        let task = CompilationTask {
+            shared: Arc::new(CompilationArgs {
+                command: CommandInfo::simple(PathBuf::from("cl.exe")),
+                args: Vec::new(),
+                pch_usage: PCHUsage::None,
+                deps_file: None,
+                run_second_cpp: false,
+                notes: Vec::new(),
+                config: None,
+                link: None,
+            }),
+            language: "P".to_string(),
+            input_source: temp.path().join("a.cpp"),
+            output_object: temp.path().join("a.obj"),
+        };
+        let lookup = |key: &str| match reuse.lookup(&task, key, &StatCache::new(16)) {
+            Reuse::Hit(content) => Some((content, false)),
+            Reuse::Check(content) => Some((content, true)),
+            Reuse::Miss => None,
+        };
+
+        assert!(lookup("key").is_none());
+        reuse.update(&task, "key", &StatCache::new(16), content.as_bytes(), None);
+        assert_eq!(lookup("key"), Some((content.clone().into_bytes(), false)));
+        // Every second reused output is checked.
+        assert_eq!(lookup("key"), Some((content.clone().into_bytes(), true)));
+        assert!(lookup("other").is_none());
+
+        fs::write(&header, "int c;\n").unwrap();
+        assert!(lookup("key").is_none());
+
+        // Mismatch disables reuse.
+        reuse.update(&task, "key", &StatCache::new(16), b"new", Some(b"old"));
+        assert_eq!(reuse.mismatches(), 1);
+        assert!(!reuse.is_enabled());
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::config::{PhaseProgram, ToolchainPhases};
+use crate::io::statcache::{FileHasher, StatCache};
+use crate::run::glob_match;
+
+// Per-toolchain programs for preprocess and compile phases. Some MSVC-compatible toolchains
+// preprocess with a separate front-end or wrapper, while compilation uses the main driver.
+pub struct PhasePrograms {
+    rules: Vec<ToolchainPhases>,
+}
+
+impl PhasePrograms {
+    #[must_use]
+    pub fn new(rules: &[ToolchainPhases]) -> Self {
+        PhasePrograms {
+            rules: rules.to_vec(),
+        }
+    }
+
+    // First rule matching toolchain compiler path.
+    #[must_use]
+    pub fn find(&self, toolchain: &Path) -> Option<&ToolchainPhases> {
+        self.rules
+            .iter()
+            .find(|rule| glob_match(&rule.toolchain, toolchain))
+    }
+
+    // Preprocessor program, None if toolchain compiler itself preprocesses.
+    #[must_use]
+    pub fn preprocessor(&self, toolchain: &Path) -> Option<&PhaseProgram> {
+        self.find(toolchain)?.preprocessor.as_ref()
+    }
+
+    // Compiler program, None if toolchain compiler itself compiles.
+    #[must_use]
+    pub fn compiler(&self, toolchain: &Path) -> Option<&PhaseProgram> {
+        self.find(toolchain)?.compiler.as_ref()
+    }
+
+    // Digest of phase programs and their arguments, None if toolchain has no phase rule.
+    // Output depends on these binaries as much as on compiler, so the digest is a part of
+    // toolchain identity in cache key.
+    pub fn digest(&self, toolchain: &Path, files: &StatCache) -> crate::Result<Option<String>> {
+        let Some(rule) = self.find(toolchain) else {
+            return Ok(None);
+        };
+        let mut hasher = Sha256::new();
+        let mut update = |value: &str| {
+            hasher.update((value.len() as u64).to_le_bytes());
+            hasher.update(value.as_bytes());
+        };
+        for phase in [&rule.preprocessor, &rule.compiler] {
+            let Some(phase) = phase else {
+                update("");
+                continue;
+            };
+            // Program found through PATH is identified by name only.
+            if phase.program.is_absolute() {
+                update(&files.file_hash(&phase.program)?.hash);
+            } else {
+                update(&phase.program.to_string_lossy());
+            }
+            update(&phase.args.len().to_string());
+            for arg in &phase.args {
+                update(arg);
+            }
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::config::{PhaseProgram, ToolchainPhases};
+    use crate::io::statcache::StatCache;
+    use crate::phases::PhasePrograms;
+
+    fn phase(program: &Path, args: &[&str]) -> Option<PhaseProgram> {
+        Some(PhaseProgram {
+            program: program.to_path_buf(),
+            args: args.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    #[test]
+    fn test_find() {
+        let phases = PhasePrograms::new(&[
+            ToolchainPhases {
+                toolchain: "console/**/cl.exe".to_string(),
+                preprocessor: phase(Path::new("front.exe"), &["--msvc"]),
+                compiler: None,
+            },
+            ToolchainPhases {
+                toolchain: "cl.exe".to_string(),
+                preprocessor: None,
+                compiler: None,
+            },
+        ]);
+        let console = Path::new("/sdk/console/bin/cl.exe");
+        assert_eq!(
+            phases.preprocessor(console).unwrap().program,
+            PathBuf::from("front.exe")
+        );
+        assert!(phases.compiler(console).is_none());
+        // First matching rule wins, even without programs.
+        let other = Path::new("/vs/bin/cl.exe");
+        assert_eq!(phases.find(other).unwrap().toolchain, "cl.exe");
+        assert!(phases.preprocessor(other).is_none());
+        assert!(phases.find(Path::new("/vs/bin/clang-cl.exe")).is_none());
+    }
+
+    #[test]
+    fn test_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let front = dir.path().join("front.exe");
+        fs::write(&front, "front").unwrap();
+        let toolchain = Path::new("/sdk/cl.exe");
+        let digest = |front_args: &[&str]| {
+            PhasePrograms::new(&[ToolchainPhases {
+                toolchain: "cl.exe".to_string(),
+                preprocessor: phase(&front, front_args),
+                compiler: None,
+            }])
+            .digest(toolchain, &StatCache::default())
+            .unwrap()
+            .unwrap()
+        };
+        let base = digest(&[]);
+        assert_eq!(digest(&[]), base);
+        assert_ne!(digest(&["--msvc"]), base);
+        // Program content is hashed, not only its path.
+        fs::write(&front, "front v2").unwrap();
+        assert_ne!(digest(&[]), base);
+        assert_eq!(
+            PhasePrograms::new(&[])
+                .digest(toolchain, &StatCache::default())
+                .unwrap(),
+            None
+        );
+    }
+}
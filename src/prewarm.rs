@@ -0,0 +1,44 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Counters of preprocess-only run: it computes cache keys of tasks without compiling them.
+#[derive(Default)]
+pub struct PrewarmStatistic {
+    // Cache entry exists and was marked as recently used.
+    pub refreshed: AtomicUsize,
+    // Nothing in cache for the key, next build compiles the task.
+    pub missing: AtomicUsize,
+    // Key can't be computed without build outputs (precompiled header) or task isn't a compilation.
+    pub skipped: AtomicUsize,
+}
+
+impl PrewarmStatistic {
+    #[must_use]
+    pub fn new() -> Self {
+        PrewarmStatistic::default()
+    }
+
+    pub fn add(&self, refreshed: bool) {
+        if refreshed {
+            self.refreshed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.missing.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn inc_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for PrewarmStatistic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Prewarm statistic: refreshed {} cache entries, missing {}, skipped {} tasks",
+            self.refreshed.load(Ordering::Relaxed),
+            self.missing.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+        )
+    }
+}
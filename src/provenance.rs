@@ -0,0 +1,606 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::diagnostics::TaskOutcome;
+use crate::io::tempfile::write_atomic;
+use crate::run::{glob, glob_match};
+use crate::utils::path_key;
+use crate::vs::coff::{
+    CoffError, CoffObject, IMAGE_SCN_ALIGN_1BYTES, IMAGE_SCN_LNK_INFO, IMAGE_SCN_LNK_REMOVE,
+};
+
+// Suffix of metadata file written next to output file.
+const SIDECAR_SUFFIX: &str = ".octo.json";
+// Cache subdirectory with metadata of output files by their path.
+const INDEX_DIR: &str = "outputs";
+// COFF section with origin of object file. Linker drops it, so it never gets into image.
+pub const STAMP_SECTION: &str = ".octobld";
+const STAMP_FLAGS: u32 = IMAGE_SCN_LNK_INFO | IMAGE_SCN_LNK_REMOVE | IMAGE_SCN_ALIGN_1BYTES;
+
+// Describes where output file came from.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Provenance {
+    pub key: String,
+    pub toolchain: Option<String>,
+    pub source: PathBuf,
+    pub version: String,
+    pub outcome: TaskOutcome,
+}
+
+// Task description stored inside cache entry, allows to rebuild index from cache.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct EntryInfo {
+    pub toolchain: Option<String>,
+    pub source: PathBuf,
+    pub outputs: Vec<PathBuf>,
+    pub version: String,
+    // Unix time of compilation, entry file modification time is updated by every hit.
+    // Missing in entries stored by older versions.
+    #[serde(default)]
+    pub produced: Option<u64>,
+}
+
+impl Provenance {
+    #[must_use]
+    pub fn new(key: &str, info: &EntryInfo, outcome: TaskOutcome) -> Self {
+        Provenance {
+            key: key.to_string(),
+            toolchain: info.toolchain.clone(),
+            source: info.source.clone(),
+            version: info.version.clone(),
+            outcome,
+        }
+    }
+}
+
+// Origin of object file embedded into object itself.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ObjectStamp {
+    // Identifier of octobuild run which produced or restored object.
+    pub build: String,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+#[derive(Debug)]
+pub struct ObjectAudit {
+    pub path: PathBuf,
+    // Objects without stamp are produced by other tools or with stamping disabled.
+    pub stamp: crate::Result<Option<ObjectStamp>>,
+    // Stamp refers to toolchain which is not available anymore.
+    pub toolchain_mismatch: bool,
+}
+
+// Selects cache entries to purge. Entry must match all specified conditions.
+#[derive(Default)]
+pub struct PurgeFilter {
+    // Glob pattern for task source file.
+    pub source_glob: Option<String>,
+    pub produced_after: Option<SystemTime>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProvenanceLocation {
+    Sidecar(PathBuf),
+    Index(PathBuf),
+}
+
+pub struct ProvenanceStore {
+    index_dir: PathBuf,
+    sidecar: bool,
+    // Build identifier when object stamping is enabled.
+    stamp: Option<String>,
+}
+
+#[must_use]
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name: OsString = output.as_os_str().to_os_string();
+    name.push(SIDECAR_SUFFIX);
+    PathBuf::from(name)
+}
+
+impl ProvenanceStore {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        ProvenanceStore {
+            index_dir: config.cache.join(INDEX_DIR),
+            sidecar: config.object_sidecar,
+            // Stamped objects never match native build in shadow mode.
+            stamp: (config.object_stamp && !config.shadow).then(new_build_id),
+        }
+    }
+
+    fn index_path(&self, output: &Path) -> PathBuf {
+        let key = path_key(output);
+        let hash = hex::encode(Sha256::digest(key.to_string_lossy().as_bytes()));
+        self.index_dir
+            .join(&hash[0..2])
+            .join(hash[2..].to_string() + ".json")
+    }
+
+    // Save metadata of produced output files.
+    // Metadata is never a part of task outputs, so it doesn't affect cache and incremental builds.
+    pub fn record(&self, outputs: &[PathBuf], provenance: &Provenance) {
+        for output in outputs {
+            let mut paths = vec![self.index_path(output)];
+            if self.sidecar {
+                paths.push(sidecar_path(output));
+            }
+            for path in paths {
+                if let Err(e) = write_json(&path, provenance) {
+                    warn!("Can't write output metadata {}: {e}", path.display());
+                }
+            }
+            if let Some(build) = &self.stamp {
+                if !is_object(output) {
+                    continue;
+                }
+                let stamp = ObjectStamp {
+                    build: build.clone(),
+                    provenance: provenance.clone(),
+                };
+                if let Err(e) = write_stamp(output, &stamp) {
+                    warn!("Can't stamp object file {}: {e}", output.display());
+                }
+            }
+        }
+    }
+
+    // Get all index records. Unreadable records are skipped, they can be fixed by reindex.
+    fn index_records(&self) -> crate::Result<Vec<(PathBuf, Provenance)>> {
+        let mut result = Vec::new();
+        let dirs = match fs::read_dir(&self.index_dir) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(result),
+            Err(e) => return Err(e.into()),
+        };
+        for dir in dirs {
+            for entry in fs::read_dir(dir?.path())? {
+                let path = entry?.path();
+                match read_json(&path) {
+                    Ok(Some(provenance)) => result.push((path, provenance)),
+                    Ok(None) => {}
+                    Err(e) => warn!("Can't read output metadata {}: {e}", path.display()),
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // Remove matching cache entries along with their index records.
+    // Index may be stale: records of already evicted entries are ignored.
+    pub fn purge(&self, cache: &Cache, filter: &PurgeFilter) -> crate::Result<usize> {
+        let mut records: HashMap<String, Vec<(PathBuf, Provenance)>> = HashMap::new();
+        for (path, provenance) in self.index_records()? {
+            records
+                .entry(provenance.key.clone())
+                .or_default()
+                .push((path, provenance));
+        }
+        let mut removed = 0;
+        for entry in cache.entries()? {
+            let entry_records = records.remove(&entry.key).unwrap_or_default();
+            if let Some(produced_after) = filter.produced_after {
+                let produced = cache
+                    .read_metadata(&entry)
+                    .ok()
+                    .and_then(|metadata| serde_json::from_slice::<EntryInfo>(&metadata).ok())
+                    .and_then(|info| info.produced);
+                // Entry of unknown age is kept.
+                if !produced.is_some_and(|produced| {
+                    UNIX_EPOCH + Duration::from_secs(produced) > produced_after
+                }) {
+                    continue;
+                }
+            }
+            if let Some(pattern) = &filter.source_glob {
+                if !entry_records
+                    .iter()
+                    .any(|(_, provenance)| glob_match(pattern, &provenance.source))
+                {
+                    continue;
+                }
+            }
+            if cache.remove(&entry.key)? {
+                removed += 1;
+            }
+            for (path, _) in entry_records {
+                drop(fs::remove_file(path));
+            }
+        }
+        Ok(removed)
+    }
+
+    // Rebuild index from metadata stored in cache entries.
+    pub fn reindex(&self, cache: &Cache) -> crate::Result<usize> {
+        if let Err(e) = fs::remove_dir_all(&self.index_dir) {
+            if e.kind() != ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        let mut count = 0;
+        for entry in cache.entries()? {
+            let info: EntryInfo = match cache
+                .read_metadata(&entry)
+                .and_then(|metadata| Ok(serde_json::from_slice(&metadata)?))
+            {
+                Ok(info) => info,
+                Err(e) => {
+                    warn!("Can't read cache entry {}: {e}", entry.path.display());
+                    continue;
+                }
+            };
+            let provenance = Provenance::new(&entry.key, &info, TaskOutcome::Compiled);
+            for output in &info.outputs {
+                write_json(&self.index_path(output), &provenance)?;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Find metadata of output file: sidecar file first, cache index otherwise.
+    pub fn find(&self, output: &Path) -> crate::Result<Option<(ProvenanceLocation, Provenance)>> {
+        let sidecar = sidecar_path(output);
+        if let Some(provenance) = read_json(&sidecar)? {
+            return Ok(Some((ProvenanceLocation::Sidecar(sidecar), provenance)));
+        }
+        let index = self.index_path(output);
+        Ok(read_json(&index)?.map(|provenance| (ProvenanceLocation::Index(index), provenance)))
+    }
+}
+
+fn new_build_id() -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{:x}-{:x}", time.as_secs(), process::id())
+}
+
+fn is_object(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("obj"))
+}
+
+// Add or replace stamp section of COFF object file.
+pub fn write_stamp(path: &Path, stamp: &ObjectStamp) -> crate::Result<()> {
+    let data = fs::read(path)?;
+    let content = serde_json::to_vec(stamp)?;
+    let result = CoffObject::parse(&data)?.with_section(STAMP_SECTION, STAMP_FLAGS, &content)?;
+    write_atomic(path, |file| Ok(file.write_all(&result)?))
+}
+
+pub fn read_stamp(path: &Path) -> crate::Result<Option<ObjectStamp>> {
+    let data = fs::read(path)?;
+    let object = match CoffObject::parse(&data) {
+        Ok(object) => object,
+        // Not a COFF object: can't be stamped by octobuild.
+        Err(CoffError::NotObject) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match object.section(STAMP_SECTION) {
+        Some(section) => Ok(Some(serde_json::from_slice(object.section_data(section))?)),
+        None => Ok(None),
+    }
+}
+
+// Check origin of all object files in directory.
+// `toolchains` contains identifiers of currently available toolchains.
+pub fn audit_objects(dir: &Path, toolchains: &HashSet<String>) -> crate::Result<Vec<ObjectAudit>> {
+    let mut result = Vec::new();
+    for path in glob(dir, "**/*")? {
+        if !is_object(&path) || !path.is_file() {
+            continue;
+        }
+        let stamp = read_stamp(&path);
+        let toolchain_mismatch = match &stamp {
+            Ok(Some(stamp)) => stamp
+                .provenance
+                .toolchain
+                .as_ref()
+                .is_some_and(|toolchain| !toolchains.contains(toolchain)),
+            _ => false,
+        };
+        result.push(ObjectAudit {
+            path,
+            stamp,
+            toolchain_mismatch,
+        });
+    }
+    Ok(result)
+}
+
+fn write_json(path: &Path, provenance: &Provenance) -> crate::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let content = serde_json::to_vec_pretty(provenance)?;
+    write_atomic(path, |file| Ok(file.write_all(&content)?))
+}
+
+fn read_json(path: &Path) -> crate::Result<Option<Provenance>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::cache::Cache;
+    use crate::compiler::{OutputInfo, TaskStatus};
+    use crate::config::Config;
+    use crate::diagnostics::TaskOutcome;
+    use crate::io::statistic::Statistic;
+    use crate::io::taskoutput::TaskOutput;
+    use crate::provenance::{
+        sidecar_path, EntryInfo, Provenance, ProvenanceLocation, ProvenanceStore, PurgeFilter,
+    };
+    use crate::utils::cl_path;
+
+    fn provenance(outcome: TaskOutcome) -> Provenance {
+        Provenance {
+            key: "0123456789abcdef".to_string(),
+            toolchain: Some("clang 15".to_string()),
+            source: PathBuf::from("/work/a.cpp"),
+            version: crate::version::full(),
+            outcome,
+        }
+    }
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path(&PathBuf::from("/work/a.cpp.obj")),
+            PathBuf::from("/work/a.cpp.obj.octo.json")
+        );
+    }
+
+    #[test]
+    fn test_find_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ProvenanceStore::new(&Config {
+            cache: dir.path().join("cache"),
+            object_sidecar: true,
+            ..Config::default()
+        });
+        let output = dir.path().join("a.obj");
+
+        assert_eq!(store.find(&output).unwrap(), None);
+        store.record(&[output.clone()], &provenance(TaskOutcome::Compiled));
+        store.record(&[output.clone()], &provenance(TaskOutcome::Hit));
+        assert_eq!(
+            store.find(&output).unwrap(),
+            Some((
+                ProvenanceLocation::Sidecar(sidecar_path(&output)),
+                provenance(TaskOutcome::Hit)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_find_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            object_sidecar: false,
+            ..Config::default()
+        };
+        let store = ProvenanceStore::new(&config);
+        let output = dir.path().join("a.obj");
+
+        store.record(&[output.clone()], &provenance(TaskOutcome::Compiled));
+        assert!(!sidecar_path(&output).exists());
+        let Some((ProvenanceLocation::Index(path), found)) = store.find(&output).unwrap() else {
+            panic!("output metadata must be found in index");
+        };
+        assert!(path.starts_with(&config.cache));
+        assert_eq!(found, provenance(TaskOutcome::Compiled));
+
+        // Output spelled by cl command line with other separators is the same entry.
+        let spelled = cl_path(&format!("{}\\a.obj", dir.path().display()));
+        assert_eq!(store.find(&spelled).unwrap().unwrap().1, found);
+
+        // Index is used when sidecar file was removed.
+        let store = ProvenanceStore::new(&Config {
+            object_sidecar: true,
+            ..config
+        });
+        store.record(&[output.clone()], &provenance(TaskOutcome::Hit));
+        fs::remove_file(sidecar_path(&output)).unwrap();
+        assert!(matches!(
+            store.find(&output).unwrap(),
+            Some((ProvenanceLocation::Index(_), _))
+        ));
+    }
+
+    fn store_entry(cache: &Cache, store: &ProvenanceStore, key: &str, source: &str, output: &Path) {
+        store_produced(cache, store, key, source, output, None);
+    }
+
+    fn store_produced(
+        cache: &Cache,
+        store: &ProvenanceStore,
+        key: &str,
+        source: &str,
+        output: &Path,
+        produced: Option<u64>,
+    ) {
+        fs::write(output, key).unwrap();
+        let info = EntryInfo {
+            toolchain: None,
+            source: PathBuf::from(source),
+            outputs: vec![output.to_path_buf()],
+            version: crate::version::full(),
+            produced,
+        };
+        cache
+            .run_file_cached(
+                &Statistic::new(),
+                None,
+                key,
+                &serde_json::to_vec(&info).unwrap(),
+                info.outputs.clone(),
+                || {
+                    Ok(OutputInfo {
+                        status: TaskStatus::Code(0),
+                        stdout: TaskOutput::default(),
+                        stderr: TaskOutput::default(),
+                    })
+                },
+            )
+            .unwrap();
+        store.record(
+            &info.outputs,
+            &Provenance::new(key, &info, TaskOutcome::Compiled),
+        );
+    }
+
+    fn entry_keys(cache: &Cache) -> Vec<String> {
+        let mut keys: Vec<String> = cache
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_purge_by_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = Cache::new(&config);
+        let store = ProvenanceStore::new(&config);
+        let plugin_obj = dir.path().join("a.obj");
+        let engine_obj = dir.path().join("b.obj");
+        store_entry(
+            &cache,
+            &store,
+            "aa11",
+            "/work/Engine/Plugins/Foo/a.cpp",
+            &plugin_obj,
+        );
+        store_entry(
+            &cache,
+            &store,
+            "bb22",
+            "/work/Engine/Source/b.cpp",
+            &engine_obj,
+        );
+
+        let filter = PurgeFilter {
+            source_glob: Some("Engine/Plugins/**".to_string()),
+            ..PurgeFilter::default()
+        };
+        assert_eq!(store.purge(&cache, &filter).unwrap(), 1);
+        assert_eq!(entry_keys(&cache), ["bb22"]);
+        assert_eq!(store.find(&plugin_obj).unwrap(), None);
+        assert!(store.find(&engine_obj).unwrap().is_some());
+        // Nothing left to purge.
+        assert_eq!(store.purge(&cache, &filter).unwrap(), 0);
+
+        // Stale index record of evicted entry is ignored.
+        assert!(cache.remove("bb22").unwrap());
+        let filter = PurgeFilter {
+            source_glob: Some("Engine/**".to_string()),
+            ..PurgeFilter::default()
+        };
+        assert_eq!(store.purge(&cache, &filter).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_purge_by_production_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = Cache::new(&config);
+        let store = ProvenanceStore::new(&config);
+        let old_obj = dir.path().join("a.obj");
+        store_produced(&cache, &store, "aa11", "/work/a.cpp", &old_obj, Some(1000));
+        store_produced(
+            &cache,
+            &store,
+            "bb22",
+            "/work/b.cpp",
+            &dir.path().join("b.obj"),
+            Some(3000),
+        );
+        store_entry(
+            &cache,
+            &store,
+            "cc33",
+            "/work/c.cpp",
+            &dir.path().join("c.obj"),
+        );
+        // Cache hit rewrites old entry, so its file looks fresh.
+        store_produced(&cache, &store, "aa11", "/work/a.cpp", &old_obj, Some(1000));
+
+        let filter = PurgeFilter {
+            produced_after: Some(UNIX_EPOCH + Duration::from_secs(2000)),
+            ..PurgeFilter::default()
+        };
+        assert_eq!(store.purge(&cache, &filter).unwrap(), 1);
+        assert_eq!(entry_keys(&cache), ["aa11", "cc33"]);
+    }
+
+    #[test]
+    fn test_reindex() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            cache: dir.path().join("cache"),
+            ..Config::default()
+        };
+        let cache = Cache::new(&config);
+        let store = ProvenanceStore::new(&config);
+        let output = dir.path().join("a.obj");
+        store_entry(&cache, &store, "aa11", "/work/a.cpp", &output);
+        store_entry(
+            &cache,
+            &store,
+            "bb22",
+            "/work/b.cpp",
+            &dir.path().join("b.obj"),
+        );
+
+        fs::remove_dir_all(dir.path().join("cache").join(super::INDEX_DIR)).unwrap();
+        assert_eq!(store.find(&output).unwrap(), None);
+
+        assert_eq!(store.reindex(&cache).unwrap(), 2);
+        let Some((ProvenanceLocation::Index(_), found)) = store.find(&output).unwrap() else {
+            panic!("output metadata must be restored from cache");
+        };
+        assert_eq!(found.key, "aa11");
+        assert_eq!(found.source, PathBuf::from("/work/a.cpp"));
+
+        // Rebuilt index works for purge.
+        let filter = PurgeFilter {
+            source_glob: Some("b.cpp".to_string()),
+            ..PurgeFilter::default()
+        };
+        assert_eq!(store.purge(&cache, &filter).unwrap(), 1);
+        assert_eq!(entry_keys(&cache), ["aa11"]);
+    }
+}
@@ -0,0 +1,607 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::config::Config;
+
+// How often reaper checks whether stopped processes exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// cl and link connect to PDB server listening on endpoint from this variable, and start it there.
+const PDB_ENDPOINT_VAR: &str = "_MSPDBSRV_ENDPOINT_";
+
+// Compilers start auxiliary service processes that outlive the build, like mspdbsrv.exe
+// (PDB server shared by cl instances) and vctip.exe (telemetry). They keep intermediate
+// directory open, so opt-in reaper stops them at the end of the build. Only processes
+// started within the build job (job object on Windows, child process groups on Unix) are
+// touched, never auxiliary processes of concurrent builds. PDB server is shared by every cl
+// of the user on the same endpoint, so the build gets its own endpoint.
+pub struct AuxReaper {
+    // Time given to processes to stop on request before they are terminated.
+    grace: Duration,
+    // Executable names of auxiliary processes.
+    names: Vec<String>,
+    // None if reaper is disabled.
+    job: Option<Job>,
+    // PDB server endpoint of build compilers.
+    endpoint: String,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ReapedProcess {
+    pub pid: u32,
+    pub name: String,
+    // Process didn't stop on request within grace period and was terminated.
+    pub forced: bool,
+}
+
+impl AuxReaper {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        AuxReaper {
+            grace: Duration::from_secs(config.aux_process_reap_sec),
+            names: config.aux_process_names.clone(),
+            job: if config.aux_process_reap_sec > 0 {
+                Job::new()
+            } else {
+                None
+            },
+            endpoint: format!(
+                "octobuild-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+        }
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.job.is_some()
+    }
+
+    #[must_use]
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    // Prepare child command of the build. On Windows child starts suspended and resumes in
+    // `assign`, so it can't start auxiliary processes before it joins build job.
+    pub fn prepare(&self, command: &mut Command) {
+        if self.job.is_none() {
+            return;
+        }
+        command.env(PDB_ENDPOINT_VAR, &self.endpoint);
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            use winapi::um::winbase::CREATE_SUSPENDED;
+
+            command.creation_flags(CREATE_SUSPENDED);
+        }
+    }
+
+    // Add child process to build job, processes started by it join the job too.
+    pub fn assign(&self, child: &Child) {
+        if let Some(job) = &self.job {
+            job.assign(child);
+        }
+    }
+
+    // Child process was waited for.
+    pub fn release(&self, pid: u32) {
+        if let Some(job) = &self.job {
+            job.release(pid);
+        }
+    }
+
+    // Stop auxiliary processes left running in build job.
+    pub fn reap(&self) -> Vec<ReapedProcess> {
+        let Some(job) = &self.job else {
+            return Vec::new();
+        };
+        let mut pending: Vec<(u32, String)> = job
+            .members()
+            .into_iter()
+            .filter_map(|pid| {
+                let image = process_image(pid)?;
+                let name = image.file_name()?.to_string_lossy().into_owned();
+                if !self.names.iter().any(|n| n.eq_ignore_ascii_case(&name)) || !job.contains(pid) {
+                    return None;
+                }
+                request_stop(pid, &image, &self.endpoint);
+                Some((pid, name))
+            })
+            .collect();
+        let mut result = Vec::new();
+        let deadline = Instant::now() + self.grace;
+        loop {
+            let members = job.members();
+            pending.retain(|(pid, name)| {
+                if members.contains(pid) {
+                    return true;
+                }
+                result.push(ReapedProcess {
+                    pid: *pid,
+                    name: name.clone(),
+                    forced: false,
+                });
+                false
+            });
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        for (pid, name) in pending {
+            if job.contains(pid) && terminate(pid) {
+                result.push(ReapedProcess {
+                    pid,
+                    name,
+                    forced: true,
+                });
+            } else {
+                warn!("Can't terminate auxiliary process {name} (pid {pid})");
+            }
+        }
+        result
+    }
+
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        for process in self.reap() {
+            if process.forced {
+                writeln!(
+                    out,
+                    "Terminated auxiliary process {} (pid {}) still running {}s after stop request",
+                    process.name,
+                    process.pid,
+                    self.grace.as_secs()
+                )?;
+            } else {
+                writeln!(
+                    out,
+                    "Stopped auxiliary process {} (pid {})",
+                    process.name, process.pid
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Descendants of child processes stay in the process group of the child, unless they
+// create their own session or group.
+#[cfg(unix)]
+struct Job {
+    groups: std::sync::Mutex<std::collections::HashMap<u32, JobGroup>>,
+    // Isolated children stay in the session of the build.
+    session: u32,
+}
+
+// Group id is the process id of its leader, so it can be reused by unrelated process once
+// the group is empty.
+#[cfg(unix)]
+struct JobGroup {
+    // Start time of group leader.
+    start: Option<u64>,
+    // Group leader was not waited for yet.
+    running: bool,
+}
+
+#[cfg(unix)]
+impl Job {
+    fn new() -> Option<Self> {
+        Some(Job {
+            groups: std::sync::Mutex::default(),
+            session: unsafe { libc::getsid(0) } as u32,
+        })
+    }
+
+    // Only child isolated by `TaskMonitor::isolate` leads its own process group, other children
+    // share process group of the build.
+    fn assign(&self, child: &Child) {
+        let pid = child.id();
+        let Ok(id) = libc::pid_t::try_from(pid) else {
+            return;
+        };
+        if unsafe { libc::getpgid(id) } != id {
+            return;
+        }
+        self.groups.lock().unwrap().insert(
+            pid,
+            JobGroup {
+                start: process_stat(pid).map(|stat| stat.start),
+                running: true,
+            },
+        );
+    }
+
+    fn release(&self, pid: u32) {
+        if let Some(group) = self.groups.lock().unwrap().get_mut(&pid) {
+            group.running = false;
+        }
+    }
+
+    fn members(&self) -> Vec<u32> {
+        let mut groups = self.groups.lock().unwrap();
+        let processes = processes();
+        // Group is forgotten once it is empty, or its id was taken by another process.
+        groups.retain(|&id, group| {
+            let reused = processes
+                .iter()
+                .any(|stat| stat.pid == id && group.start.is_some_and(|start| start != stat.start));
+            !reused && (group.running || processes.iter().any(|stat| stat.group == id))
+        });
+        processes
+            .into_iter()
+            .filter(|stat| self.is_member(&groups, stat))
+            .map(|stat| stat.pid)
+            .collect()
+    }
+
+    // Process is checked again right before it is signalled, its id could be reused since
+    // members were listed.
+    fn contains(&self, pid: u32) -> bool {
+        let groups = self.groups.lock().unwrap();
+        process_stat(pid).is_some_and(|stat| self.is_member(&groups, &stat))
+    }
+
+    fn is_member(
+        &self,
+        groups: &std::collections::HashMap<u32, JobGroup>,
+        stat: &ProcessStat,
+    ) -> bool {
+        stat.session == self.session && groups.contains_key(&stat.group)
+    }
+}
+
+#[cfg(unix)]
+struct ProcessStat {
+    pid: u32,
+    group: u32,
+    session: u32,
+    // Clock ticks since boot.
+    start: u64,
+}
+
+// Running process, see proc(5).
+#[cfg(target_os = "linux")]
+fn process_stat(pid: u32) -> Option<ProcessStat> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Command name in parentheses can contain spaces, so fields are parsed after it.
+    let fields: Vec<&str> = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect();
+    // Zombie has already exited.
+    if matches!(*fields.first()?, "Z" | "X") {
+        return None;
+    }
+    Some(ProcessStat {
+        pid,
+        group: fields.get(2)?.parse().ok()?,
+        session: fields.get(3)?.parse().ok()?,
+        start: fields.get(19)?.parse().ok()?,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn processes() -> Vec<ProcessStat> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| process_stat(entry.file_name().to_str()?.parse().ok()?))
+        .collect()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_stat(_pid: u32) -> Option<ProcessStat> {
+    None
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn processes() -> Vec<ProcessStat> {
+    static UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+    UNSUPPORTED.call_once(|| {
+        warn!(
+            "Auxiliary processes are not stopped: process list is not supported on this platform"
+        );
+    });
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn process_image(pid: u32) -> Option<PathBuf> {
+    let name = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some(PathBuf::from(name.trim_end()))
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_image(_pid: u32) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(unix)]
+fn request_stop(pid: u32, _image: &Path, _endpoint: &str) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    unsafe { libc::kill(pid, libc::SIGKILL) == 0 }
+}
+
+// Job object handle is kept as integer to share it between worker threads.
+#[cfg(windows)]
+struct Job(usize);
+
+#[cfg(windows)]
+impl Job {
+    fn new() -> Option<Self> {
+        use winapi::um::jobapi2::CreateJobObjectW;
+
+        let handle = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if handle.is_null() {
+            warn!(
+                "Can't create job object for auxiliary processes: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(Job(handle as usize))
+    }
+
+    // Child was started suspended by `AuxReaper::prepare`, it is resumed even if it can't join.
+    fn assign(&self, child: &Child) {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::jobapi2::AssignProcessToJobObject;
+
+        if unsafe { AssignProcessToJobObject(self.0 as _, child.as_raw_handle() as _) } == 0 {
+            warn!(
+                "Can't assign process {} to job object: {}",
+                child.id(),
+                std::io::Error::last_os_error()
+            );
+        }
+        if !resume_process(child.id()) {
+            warn!(
+                "Can't resume process {}: {}",
+                child.id(),
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    // Processes stay in the job until they exit.
+    fn release(&self, _pid: u32) {}
+
+    fn contains(&self, pid: u32) -> bool {
+        self.members().contains(&pid)
+    }
+
+    fn members(&self) -> Vec<u32> {
+        use winapi::um::jobapi2::QueryInformationJobObject;
+        use winapi::um::winnt::{JobObjectBasicProcessIdList, JOBOBJECT_BASIC_PROCESS_ID_LIST};
+
+        let mut capacity = 256;
+        loop {
+            // List header is followed by process ids.
+            let mut buffer = vec![0usize; capacity + 2];
+            let list = buffer
+                .as_mut_ptr()
+                .cast::<JOBOBJECT_BASIC_PROCESS_ID_LIST>();
+            unsafe {
+                let success = QueryInformationJobObject(
+                    self.0 as _,
+                    JobObjectBasicProcessIdList,
+                    list.cast(),
+                    (buffer.len() * std::mem::size_of::<usize>()) as u32,
+                    std::ptr::null_mut(),
+                ) != 0;
+                let assigned = (*list).NumberOfAssignedProcesses as usize;
+                if !success {
+                    if assigned > capacity {
+                        capacity = assigned * 2;
+                        continue;
+                    }
+                    return Vec::new();
+                }
+                let listed = (*list).NumberOfProcessIdsInList as usize;
+                return std::slice::from_raw_parts((*list).ProcessIdList.as_ptr(), listed)
+                    .iter()
+                    .map(|pid| *pid as u32)
+                    .collect();
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Job {
+    fn drop(&mut self) {
+        use winapi::um::handleapi::CloseHandle;
+
+        unsafe { CloseHandle(self.0 as _) };
+    }
+}
+
+#[cfg(windows)]
+fn process_image(pid: u32) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return None;
+        }
+        let mut buffer = vec![0u16; 32768];
+        let mut size = buffer.len() as u32;
+        let success = QueryFullProcessImageNameW(handle, 0, buffer.as_mut_ptr(), &mut size) != 0;
+        CloseHandle(handle);
+        success.then(|| PathBuf::from(std::ffi::OsString::from_wide(&buffer[..size as usize])))
+    }
+}
+
+// Suspended process has only the main thread.
+#[cfg(windows)]
+fn resume_process(pid: u32) -> bool {
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::processthreadsapi::{OpenThread, ResumeThread};
+    use winapi::um::tlhelp32::{
+        CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD, THREADENTRY32,
+    };
+    use winapi::um::winnt::THREAD_SUSPEND_RESUME;
+
+    let mut resumed = false;
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return false;
+        }
+        let mut entry: THREADENTRY32 = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+        let mut found = Thread32First(snapshot, &mut entry) != 0;
+        while found {
+            if entry.th32OwnerProcessID == pid {
+                let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+                if !thread.is_null() {
+                    resumed |= ResumeThread(thread) != u32::MAX;
+                    CloseHandle(thread);
+                }
+            }
+            found = Thread32Next(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+    }
+    resumed
+}
+
+// mspdbsrv.exe of the build endpoint shuts down on `-stop` request, other processes are left to
+// exit on their own.
+#[cfg(windows)]
+fn request_stop(_pid: u32, image: &Path, endpoint: &str) {
+    use std::process::Stdio;
+
+    let is_pdb_server = image
+        .file_name()
+        .is_some_and(|name| name.eq_ignore_ascii_case("mspdbsrv.exe"));
+    if is_pdb_server {
+        let status = Command::new(image)
+            .arg("-stop")
+            .env(PDB_ENDPOINT_VAR, endpoint)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if let Err(e) = status {
+            warn!("Can't request {} to stop: {e}", image.display());
+        }
+    }
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> bool {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+    use winapi::um::winnt::PROCESS_TERMINATE;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let result = TerminateProcess(handle, 1) != 0;
+        CloseHandle(handle);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::time::Duration;
+
+    use crate::config::Config;
+    use crate::watchdog::TaskMonitor;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reap_job_processes() {
+        let monitor = TaskMonitor::new(&Config {
+            aux_process_reap_sec: 1,
+            aux_process_names: vec!["sleep".to_string()],
+            watchdog_kill_sec: 60,
+            ..Config::default()
+        });
+        // Process of another build with the same name must survive.
+        let mut other = Command::new("sleep").arg("60").spawn().unwrap();
+        // Compiler starts long-lived processes and exits. The second one ignores stop request.
+        let output = monitor
+            .output(Command::new("sh").args([
+                "-c",
+                "sleep 60 >/dev/null 2>&1 & trap '' TERM; sleep 61 >/dev/null 2>&1 &",
+            ]))
+            .unwrap();
+        assert!(output.status.success());
+        // Wait until background processes replace shell with sleep.
+        std::thread::sleep(Duration::from_millis(500));
+
+        let mut reaped = monitor.reaper.reap();
+        reaped.sort_by_key(|process| process.forced);
+        assert_eq!(reaped.len(), 2);
+        assert!(reaped.iter().all(|process| process.name == "sleep"));
+        assert!(!reaped[0].forced);
+        assert!(reaped[1].forced);
+        assert!(monitor.reaper.reap().is_empty());
+        // Group id of waited compiler can be reused by unrelated process.
+        let job = monitor.reaper.job.as_ref().unwrap();
+        assert!(job.groups.lock().unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(other.try_wait().unwrap().is_none());
+        other.kill().unwrap();
+        other.wait().unwrap();
+    }
+
+    // Compilers of the build get PDB server endpoint of their own.
+    #[test]
+    fn test_pdb_endpoint() {
+        let config = Config {
+            aux_process_reap_sec: 1,
+            ..Config::default()
+        };
+        let monitor = TaskMonitor::new(&config);
+        let other = TaskMonitor::new(&config);
+        assert_ne!(monitor.reaper.endpoint(), other.reaper.endpoint());
+
+        #[cfg(unix)]
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.args(["-c", "echo $_MSPDBSRV_ENDPOINT_"]);
+        // Suspended child is resumed once it joins build job.
+        #[cfg(windows)]
+        let mut command = Command::new("cmd");
+        #[cfg(windows)]
+        command.args(["/c", "echo %_MSPDBSRV_ENDPOINT_%"]);
+        let output = monitor.output(&mut command).unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            monitor.reaper.endpoint()
+        );
+    }
+}
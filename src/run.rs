@@ -0,0 +1,330 @@
+use std::cmp::min;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+// Compiler backend used for ad-hoc compilation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RunCompiler {
+    Cl,
+    Clang,
+}
+
+impl std::str::FromStr for RunCompiler {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cl" => Ok(RunCompiler::Cl),
+            "clang" => Ok(RunCompiler::Clang),
+            _ => Err(crate::Error::Generic(format!("Unknown compiler: {s}"))),
+        }
+    }
+}
+
+impl Default for RunCompiler {
+    fn default() -> Self {
+        if cfg!(windows) {
+            RunCompiler::Cl
+        } else {
+            RunCompiler::Clang
+        }
+    }
+}
+
+impl RunCompiler {
+    #[must_use]
+    pub fn executable(self) -> &'static str {
+        match self {
+            RunCompiler::Cl => "cl.exe",
+            RunCompiler::Clang => "clang",
+        }
+    }
+
+    #[must_use]
+    pub fn object_extension(self) -> &'static str {
+        match self {
+            RunCompiler::Cl => "obj",
+            RunCompiler::Clang => "o",
+        }
+    }
+
+    // Get compiler arguments for single source file.
+    #[must_use]
+    pub fn task_args(self, flags: &[String], source: &Path, output: &Path) -> Vec<String> {
+        let mut args = flags.to_vec();
+        match self {
+            RunCompiler::Cl => {
+                args.push(format!("/Fo{}", output.display()));
+            }
+            RunCompiler::Clang => {
+                args.push("-o".to_string());
+                args.push(output.display().to_string());
+            }
+        }
+        args.push(source.display().to_string());
+        args
+    }
+}
+
+// Get object file path for source file: source path relative to root is mirrored into out_dir.
+#[must_use]
+pub fn output_path(root: &Path, out_dir: &Path, source: &Path, extension: &str) -> PathBuf {
+    let relative = source.strip_prefix(root).ok().map_or_else(
+        || PathBuf::from(source.file_name().unwrap_or_default()),
+        Path::to_path_buf,
+    );
+    let mut name = relative.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(extension);
+    out_dir.join(relative.with_file_name(name))
+}
+
+fn has_wildcard(segment: &str) -> bool {
+    segment.contains(['*', '?'])
+}
+
+// Match file name against pattern with `*` and `?` wildcards.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+fn match_segments(pattern: &[&str], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((segment, rest)) => {
+            path.first().map_or(false, |name| {
+                wildcard_match(segment.as_bytes(), name.as_bytes())
+            }) && match_segments(rest, &path[1..])
+        }
+    }
+}
+
+// Check whether path matches pattern. Relative pattern may match any tail of path.
+#[must_use]
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let normalize = |s: &str| {
+        if cfg!(windows) {
+            s.to_lowercase()
+        } else {
+            s.to_string()
+        }
+    };
+    let pattern = normalize(pattern);
+    let mut segments: Vec<&str> = pattern
+        .split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+    if !Path::new(&pattern).is_absolute() {
+        segments.insert(0, "**");
+    }
+    let components: Vec<String> = path
+        .components()
+        .filter_map(|component| match component {
+            Component::Prefix(_) | Component::Normal(_) => {
+                Some(normalize(&component.as_os_str().to_string_lossy()))
+            }
+            _ => None,
+        })
+        .collect();
+    match_segments(&segments, &components)
+}
+
+// Find files matching pattern relative to root. `**` matches any count of directories.
+pub fn glob(root: &Path, pattern: &str) -> crate::Result<Vec<PathBuf>> {
+    // Leading segments without wildcards form base directory.
+    let mut literal = 0;
+    for segment in pattern.split(['/', '\\']) {
+        if has_wildcard(segment) {
+            break;
+        }
+        literal = min(literal + segment.len() + 1, pattern.len());
+    }
+    let base = root.join(&pattern[..literal]);
+    let segments: Vec<&str> = pattern[literal..]
+        .split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+
+    let mut result = Vec::new();
+    let mut visited = HashSet::new();
+    walk(&base, &segments, &mut visited, &mut result);
+    result.sort();
+    result.dedup();
+    Ok(result)
+}
+
+fn walk(
+    path: &Path,
+    segments: &[&str],
+    visited: &mut HashSet<(PathBuf, usize)>,
+    result: &mut Vec<PathBuf>,
+) {
+    let Some((segment, rest)) = segments.split_first() else {
+        if path.is_file() {
+            result.push(path.to_path_buf());
+        }
+        return;
+    };
+    if !has_wildcard(segment) {
+        walk(&path.join(segment), rest, visited, result);
+        return;
+    }
+    if *segment == "**" {
+        // Symlinks can create directory cycles, so remember real directories.
+        let Ok(real) = path.canonicalize() else {
+            return;
+        };
+        if !visited.insert((real, segments.len())) {
+            return;
+        }
+        walk(path, rest, visited, result);
+    }
+    let Ok(entries) = path.read_dir() else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let child = entry.path();
+        if *segment == "**" {
+            if child.is_dir() {
+                walk(&child, segments, visited, result);
+            }
+        } else if wildcard_match(segment.as_bytes(), entry.file_name().as_encoded_bytes()) {
+            walk(&child, rest, visited, result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::run::{glob, glob_match, output_path, RunCompiler};
+
+    fn touch(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+    }
+
+    fn relative(root: &Path, paths: Vec<PathBuf>) -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| {
+                path.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(
+            root,
+            &[
+                "src/a.cpp",
+                "src/b.c",
+                "src/x/c.cpp",
+                "src/x/y/d.cpp",
+                "other/e.cpp",
+            ],
+        );
+
+        assert_eq!(
+            relative(root, glob(root, "src/*.cpp").unwrap()),
+            ["src/a.cpp"]
+        );
+        assert_eq!(
+            relative(root, glob(root, "src/**/*.cpp").unwrap()),
+            ["src/a.cpp", "src/x/c.cpp", "src/x/y/d.cpp"]
+        );
+        assert_eq!(
+            relative(root, glob(root, "**/?.c*").unwrap()),
+            [
+                "other/e.cpp",
+                "src/a.cpp",
+                "src/b.c",
+                "src/x/c.cpp",
+                "src/x/y/d.cpp"
+            ]
+        );
+        assert_eq!(relative(root, glob(root, "src/b.c").unwrap()), ["src/b.c"]);
+        assert!(glob(root, "src/**/*.h").unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_glob_symlink_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        touch(root, &["src/a.cpp"]);
+        std::os::unix::fs::symlink(root.join("src"), root.join("src/loop")).unwrap();
+
+        assert_eq!(
+            relative(root, glob(root, "src/**/*.cpp").unwrap()),
+            ["src/a.cpp"]
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let path = Path::new("/work/Engine/Plugins/Foo/Private/a.cpp");
+        assert!(glob_match("Engine/Plugins/**", path));
+        assert!(glob_match("Plugins/*/Private/*.cpp", path));
+        assert!(glob_match("**/a.cpp", path));
+        assert!(!glob_match("Engine/Source/**", path));
+        assert!(!glob_match("Plugins/*.cpp", path));
+        #[cfg(unix)]
+        {
+            assert!(glob_match("/work/**/*.cpp", path));
+            assert!(!glob_match("/Engine/**", path));
+        }
+    }
+
+    #[test]
+    fn test_output_path() {
+        let root = Path::new("/work");
+        let out_dir = Path::new("/work/out");
+        assert_eq!(
+            output_path(root, out_dir, Path::new("/work/src/a.cpp"), "obj"),
+            PathBuf::from("/work/out/src/a.cpp.obj")
+        );
+        assert_eq!(
+            output_path(root, out_dir, Path::new("/other/b.cpp"), "o"),
+            PathBuf::from("/work/out/b.cpp.o")
+        );
+    }
+
+    #[test]
+    fn test_task_args() {
+        let flags = vec!["/c".to_string(), "/O2".to_string()];
+        assert_eq!(
+            RunCompiler::Cl.task_args(
+                &flags,
+                Path::new("src/a.cpp"),
+                Path::new("out/src/a.cpp.obj")
+            ),
+            ["/c", "/O2", "/Foout/src/a.cpp.obj", "src/a.cpp"]
+        );
+        assert_eq!(
+            RunCompiler::Clang.task_args(
+                &["-c".to_string()],
+                Path::new("src/a.cpp"),
+                Path::new("out/src/a.cpp.o")
+            ),
+            ["-c", "-o", "out/src/a.cpp.o", "src/a.cpp"]
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScheduleEvent {
+    Dispatch { task: usize, worker: usize },
+    Complete { task: usize, worker: usize },
+}
+
+// Deterministic schedule makes benchmark runs comparable: tasks are dispatched in stable order
+// to round-robin workers and results are processed in dispatch order, so the next ready tasks
+// don't depend on thread wakeup races. Random decisions are drawn from RNG seeded by the
+// printed seed and decision key, so they don't depend on the order threads ask for them.
+pub struct Schedule {
+    seed: Option<u64>,
+    events: Mutex<Vec<ScheduleEvent>>,
+}
+
+impl Schedule {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        Schedule {
+            seed: config
+                .deterministic_schedule
+                .then_some(config.schedule_seed),
+            events: Mutex::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_deterministic(&self) -> bool {
+        self.seed.is_some()
+    }
+
+    #[must_use]
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    // Worker for task in deterministic mode, otherwise any idle worker takes it.
+    #[must_use]
+    pub fn worker(&self, task: usize, workers: usize) -> Option<usize> {
+        self.seed.map(|_| task % workers)
+    }
+
+    // Random value for decision identified by key.
+    pub fn random<K: Hash + ?Sized, T>(&self, key: &K) -> T
+    where
+        Standard: Distribution<T>,
+    {
+        match self.seed {
+            Some(seed) => seeded_random(seed, key),
+            None => rand::random(),
+        }
+    }
+
+    // Events are only recorded in deterministic mode: they are compared between runs.
+    pub fn record(&self, event: ScheduleEvent) {
+        if self.is_deterministic() {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[must_use]
+    pub fn events(&self) -> Vec<ScheduleEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+// Random value drawn from RNG seeded by seed and key of decision.
+pub fn seeded_random<K: Hash + ?Sized, T>(seed: u64, key: &K) -> T
+where
+    Standard: Distribution<T>,
+{
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    StdRng::seed_from_u64(seed ^ hasher.finish()).gen()
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.seed {
+            Some(seed) => write!(f, "Deterministic schedule, seed: {seed}"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use crate::schedule::Schedule;
+
+    #[test]
+    fn test_seeded_random() {
+        let schedule = |deterministic: bool, seed: u64| {
+            Schedule::new(&Config {
+                deterministic_schedule: deterministic,
+                schedule_seed: seed,
+                ..Config::default()
+            })
+        };
+        let a = schedule(true, 1);
+        let values: Vec<u64> = (0..8).map(|key| a.random(&key)).collect();
+        // Draw order doesn't matter, only seed and key.
+        let reversed: Vec<u64> = (0..8).rev().map(|key| a.random(&key)).collect();
+        assert_eq!(values, reversed.into_iter().rev().collect::<Vec<_>>());
+        let b: Vec<u64> = (0..8).map(|key| schedule(true, 1).random(&key)).collect();
+        assert_eq!(values, b);
+        let c: Vec<u64> = (0..8).map(|key| schedule(true, 2).random(&key)).collect();
+        assert_ne!(values, c);
+
+        assert_eq!(a.worker(5, 4), Some(1));
+        assert_eq!(schedule(false, 1).worker(5, 4), None);
+        assert_eq!(a.to_string(), "Deterministic schedule, seed: 1");
+        assert_eq!(schedule(false, 1).to_string(), "");
+    }
+}
@@ -0,0 +1,218 @@
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::utils::{format_log_record, init_logger_with};
+
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(windows)]
+mod windows;
+
+// Service is restarted after crash with this delay, failure counter is reset after a day.
+pub const RESTART_DELAY: Duration = Duration::from_secs(60);
+pub const RESET_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+// Time service manager waits for stopped service on top of task drain.
+const STOP_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+    Start,
+    Stop,
+}
+
+impl ServiceCommand {
+    #[must_use]
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "install" => Some(ServiceCommand::Install),
+            "uninstall" => Some(ServiceCommand::Uninstall),
+            "start" => Some(ServiceCommand::Start),
+            "stop" => Some(ServiceCommand::Stop),
+            _ => None,
+        }
+    }
+}
+
+// Service running current executable without arguments, settings are taken from configuration.
+#[derive(Clone, Debug)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub display_name: String,
+    pub description: String,
+    pub executable: PathBuf,
+    // How long stopped service waits for running tasks.
+    pub drain: Duration,
+}
+
+impl ServiceSpec {
+    // Parse `<command> [--name <name>]` arguments of service management tool.
+    pub fn parse_args(
+        mut self,
+        args: impl IntoIterator<Item = OsString>,
+    ) -> crate::Result<Option<(ServiceCommand, ServiceSpec)>> {
+        let mut args = args.into_iter();
+        let Some(command) = args
+            .next()
+            .and_then(|arg| arg.to_str().and_then(ServiceCommand::parse))
+        else {
+            return Ok(None);
+        };
+        while let Some(arg) = args.next() {
+            match (arg.to_str(), args.next()) {
+                (Some("--name"), Some(name)) => {
+                    self.name = name
+                        .into_string()
+                        .map_err(|name| format!("Invalid service name: {name:?}"))?;
+                }
+                _ => return Err(format!("Unexpected argument: {}", arg.to_string_lossy()).into()),
+            }
+        }
+        Ok(Some((command, self)))
+    }
+
+    // Time service manager waits for service stop before killing it.
+    #[must_use]
+    pub fn stop_timeout(&self) -> Duration {
+        self.drain + STOP_MARGIN
+    }
+}
+
+pub fn run_command(command: ServiceCommand, spec: &ServiceSpec) -> crate::Result<()> {
+    #[cfg(windows)]
+    {
+        windows::run_command(command, spec)
+    }
+    #[cfg(target_os = "linux")]
+    {
+        systemd::run_command(command, spec)
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    {
+        let _ = (command, spec);
+        Err("Service management is supported on Windows and Linux only".into())
+    }
+}
+
+// Log of service goes to the log file and to system log: Event Log on Windows, journald through
+// standard output on Linux.
+pub fn init_logger(name: &str) {
+    let output = fern::Dispatch::new();
+    #[cfg(windows)]
+    let output = match windows::EventLog::register(name) {
+        Ok(event_log) => output.chain(
+            fern::Dispatch::new()
+                .level(log::LevelFilter::Warn)
+                .chain(Box::new(event_log) as Box<dyn log::Log>),
+        ),
+        Err(e) => {
+            eprintln!("Can't register Event Log source {name}: {e}");
+            output
+        }
+    };
+    #[cfg(not(windows))]
+    let _ = name;
+    // Set by systemd when standard output is connected to journal.
+    let output = if env::var_os("JOURNAL_STREAM").is_some() {
+        output.chain(
+            fern::Dispatch::new()
+                .format(|out, message, record| {
+                    out.finish(format_args!(
+                        "<{}>{}",
+                        journal_priority(record.level()),
+                        message
+                    ));
+                })
+                .chain(io::stdout()),
+        )
+    } else {
+        output.chain(
+            fern::Dispatch::new()
+                .format(format_log_record)
+                .chain(io::stdout()),
+        )
+    };
+    init_logger_with(output);
+}
+
+// Syslog priority prefix recognized by journald in standard output of service.
+fn journal_priority(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+// Run service manager tool and turn its failure into error with its output.
+fn run_tool(program: &str, args: &[String]) -> crate::Result<()> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| crate::Error::Generic(format!("Can't run {program}: {e}")))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(crate::Error::Generic(format!(
+        "{program} {} failed with {}: {}{}",
+        args.join(" "),
+        output.status,
+        String::from_utf8_lossy(&output.stdout).trim(),
+        String::from_utf8_lossy(&output.stderr).trim()
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::service::{journal_priority, ServiceCommand, ServiceSpec};
+
+    fn spec() -> ServiceSpec {
+        ServiceSpec {
+            name: "octobuild_Builder".to_string(),
+            display_name: "Octobuild Builder".to_string(),
+            description: "Compiles tasks of remote octobuild clients".to_string(),
+            executable: PathBuf::from("/opt/octobuild/octo_builder"),
+            drain: Duration::from_secs(60),
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let (command, parsed) = spec().parse_args(args(&["install"])).unwrap().unwrap();
+        assert_eq!(command, ServiceCommand::Install);
+        assert_eq!(parsed.name, "octobuild_Builder");
+
+        let (command, parsed) = spec()
+            .parse_args(args(&["stop", "--name", "octobuild_test"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(command, ServiceCommand::Stop);
+        assert_eq!(parsed.name, "octobuild_test");
+        assert_eq!(parsed.stop_timeout(), Duration::from_secs(90));
+
+        // Without command the tool runs as service itself.
+        assert!(spec().parse_args(args(&[])).unwrap().is_none());
+        assert!(spec().parse_args(args(&["--verbose"])).unwrap().is_none());
+        assert!(spec().parse_args(args(&["start", "--name"])).is_err());
+    }
+
+    #[test]
+    fn test_journal_priority() {
+        assert_eq!(journal_priority(log::Level::Error), 3);
+        assert_eq!(journal_priority(log::Level::Info), 6);
+    }
+}
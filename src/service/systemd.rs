@@ -0,0 +1,104 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::service::{run_tool, ServiceCommand, ServiceSpec, RESTART_DELAY};
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+
+pub fn run_command(command: ServiceCommand, spec: &ServiceSpec) -> crate::Result<()> {
+    // Unit files and system manager are writable by root only.
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(format!(
+            "Managing systemd service {} requires root privileges, run it with sudo",
+            spec.name
+        )
+        .into());
+    }
+    let unit = format!("{}.service", spec.name);
+    match command {
+        ServiceCommand::Install => {
+            let path = unit_path(spec);
+            fs::write(&path, unit_file(spec)).map_err(|e| crate::Error::FileOpen {
+                path,
+                error: Box::new(e.into()),
+            })?;
+            systemctl(&["daemon-reload"])?;
+            systemctl(&["enable", &unit])
+        }
+        ServiceCommand::Uninstall => {
+            systemctl(&["disable", "--now", &unit])?;
+            fs::remove_file(unit_path(spec))?;
+            systemctl(&["daemon-reload"])
+        }
+        ServiceCommand::Start => systemctl(&["start", &unit]),
+        ServiceCommand::Stop => systemctl(&["stop", &unit]),
+    }
+}
+
+fn systemctl(args: &[&str]) -> crate::Result<()> {
+    run_tool(
+        "systemctl",
+        &args.iter().map(ToString::to_string).collect::<Vec<_>>(),
+    )
+}
+
+fn unit_path(spec: &ServiceSpec) -> PathBuf {
+    PathBuf::from(UNIT_DIR).join(format!("{}.service", spec.name))
+}
+
+// Unit restarting crashed service, stop signal starts task drain.
+// Unlike Windows service, it is restarted after every crash without failure counter.
+#[must_use]
+pub fn unit_file(spec: &ServiceSpec) -> String {
+    let mut unit = String::new();
+    let _ = write!(
+        unit,
+        "[Unit]\n\
+         Description={}\n\
+         After=network-online.target\n\
+         Wants=network-online.target\n\
+         \n\
+         [Service]\n\
+         ExecStart={}\n\
+         Restart=on-failure\n\
+         RestartSec={}\n\
+         KillSignal=SIGTERM\n\
+         TimeoutStopSec={}\n\
+         StandardOutput=journal\n\
+         StandardError=journal\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n",
+        spec.description,
+        spec.executable.display(),
+        RESTART_DELAY.as_secs(),
+        spec.stop_timeout().as_secs(),
+    );
+    unit
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::service::systemd::unit_file;
+    use crate::service::ServiceSpec;
+
+    #[test]
+    fn test_unit_file() {
+        let unit = unit_file(&ServiceSpec {
+            name: "octobuild_Builder".to_string(),
+            display_name: "Octobuild Builder".to_string(),
+            description: "Compiles tasks of remote octobuild clients".to_string(),
+            executable: PathBuf::from("/opt/octobuild/octo_builder"),
+            drain: Duration::from_secs(60),
+        });
+        assert!(unit.contains("Description=Compiles tasks of remote octobuild clients\n"));
+        assert!(unit.contains("\nExecStart=/opt/octobuild/octo_builder\n"));
+        assert!(unit.contains("\nRestart=on-failure\nRestartSec=60\n"));
+        assert!(unit.contains("\nTimeoutStopSec=90\n"));
+        assert!(unit.ends_with("\n[Install]\nWantedBy=multi-user.target\n"));
+    }
+}
@@ -0,0 +1,251 @@
+use std::env;
+use std::ffi::{c_void, OsStr};
+use std::os::windows::ffi::OsStrExt;
+use std::ptr;
+
+use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_ALL_ACCESS};
+use winreg::RegKey;
+
+use crate::service::{run_tool, ServiceCommand, ServiceSpec, RESET_PERIOD, RESTART_DELAY};
+
+// Event sources of Application log.
+const EVENT_LOG_KEY: &str = r"SYSTEM\CurrentControlSet\Services\EventLog\Application";
+// Message file shipped with .NET Framework, it has `%1` message for every event id.
+const EVENT_MESSAGE_FILE: &str = r"Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+const EVENT_ID: u32 = 1000;
+// EVENTLOG_ERROR_TYPE, EVENTLOG_WARNING_TYPE, EVENTLOG_INFORMATION_TYPE
+const EVENT_ERROR: u16 = 1;
+const EVENT_WARNING: u16 = 2;
+const EVENT_INFORMATION: u16 = 4;
+// ERROR_SERVICE_NOT_ACTIVE
+const SERVICE_NOT_ACTIVE: i32 = 1062;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> *mut c_void;
+    fn DeregisterEventSource(handle: *mut c_void) -> i32;
+    fn ReportEventW(
+        handle: *mut c_void,
+        kind: u16,
+        category: u16,
+        event_id: u32,
+        user_sid: *mut c_void,
+        count: u16,
+        data_size: u32,
+        strings: *const *const u16,
+        data: *mut c_void,
+    ) -> i32;
+}
+
+#[link(name = "shell32")]
+extern "system" {
+    fn IsUserAnAdmin() -> i32;
+}
+
+fn wide(value: impl AsRef<OsStr>) -> Vec<u16> {
+    value.as_ref().encode_wide().chain(Some(0)).collect()
+}
+
+pub fn run_command(command: ServiceCommand, spec: &ServiceSpec) -> crate::Result<()> {
+    // Service control manager rejects changes from non-elevated process with access denied.
+    if unsafe { IsUserAnAdmin() } == 0 {
+        return Err(format!(
+            "Managing Windows service {} requires administrator rights, \
+             run it from elevated command prompt",
+            spec.name
+        )
+        .into());
+    }
+    match command {
+        ServiceCommand::Install => {
+            run_tool("sc.exe", &create_args(spec))?;
+            run_tool(
+                "sc.exe",
+                &[
+                    "description".to_string(),
+                    spec.name.clone(),
+                    spec.description.clone(),
+                ],
+            )?;
+            run_tool("sc.exe", &failure_args(spec))?;
+            // Recovery actions also apply to service stopped with error code.
+            run_tool(
+                "sc.exe",
+                &[
+                    "failureflag".to_string(),
+                    spec.name.clone(),
+                    "1".to_string(),
+                ],
+            )?;
+            register_event_source(&spec.name)
+        }
+        ServiceCommand::Uninstall => {
+            // Running service is deleted once it stops.
+            drop(sc_stop(spec));
+            run_tool("sc.exe", &["delete".to_string(), spec.name.clone()])?;
+            RegKey::predef(HKEY_LOCAL_MACHINE)
+                .open_subkey_with_flags(EVENT_LOG_KEY, KEY_ALL_ACCESS)?
+                .delete_subkey_all(&spec.name)
+                .or_else(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })?;
+            Ok(())
+        }
+        ServiceCommand::Start => run_tool("sc.exe", &["start".to_string(), spec.name.clone()]),
+        ServiceCommand::Stop => sc_stop(spec),
+    }
+}
+
+fn sc_stop(spec: &ServiceSpec) -> crate::Result<()> {
+    let status = std::process::Command::new("sc.exe")
+        .args(["stop", &spec.name])
+        .status()?;
+    match status.code() {
+        Some(0 | SERVICE_NOT_ACTIVE) => Ok(()),
+        _ => Err(format!("sc.exe stop {} failed with {status}", spec.name).into()),
+    }
+}
+
+// Service starting with the system, path is quoted as it may contain spaces.
+#[must_use]
+pub fn create_args(spec: &ServiceSpec) -> Vec<String> {
+    vec![
+        "create".to_string(),
+        spec.name.clone(),
+        "binPath=".to_string(),
+        format!("\"{}\"", spec.executable.display()),
+        "start=".to_string(),
+        "auto".to_string(),
+        "DisplayName=".to_string(),
+        spec.display_name.clone(),
+    ]
+}
+
+// Crashed service is restarted after delay, the last action repeats for further failures.
+#[must_use]
+pub fn failure_args(spec: &ServiceSpec) -> Vec<String> {
+    let restart = format!("restart/{}", RESTART_DELAY.as_millis());
+    vec![
+        "failure".to_string(),
+        spec.name.clone(),
+        "reset=".to_string(),
+        RESET_PERIOD.as_secs().to_string(),
+        "actions=".to_string(),
+        [restart.as_str(); 3].join("/"),
+    ]
+}
+
+fn register_event_source(name: &str) -> crate::Result<()> {
+    let (key, _) =
+        RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(format!(r"{EVENT_LOG_KEY}\{name}"))?;
+    let root = env::var("SystemRoot").unwrap_or_else(|_| r"C:\Windows".to_string());
+    key.set_value("EventMessageFile", &format!(r"{root}\{EVENT_MESSAGE_FILE}"))?;
+    key.set_value(
+        "TypesSupported",
+        &u32::from(EVENT_ERROR | EVENT_WARNING | EVENT_INFORMATION),
+    )?;
+    Ok(())
+}
+
+// Writes log records to Application log, source is registered on service install.
+pub struct EventLog {
+    // Event source handle, kept as number to share it between threads.
+    handle: usize,
+}
+
+impl EventLog {
+    pub fn register(name: &str) -> std::io::Result<Self> {
+        let name = wide(name);
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(EventLog {
+            handle: handle as usize,
+        })
+    }
+}
+
+impl log::Log for EventLog {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let kind = match record.level() {
+            log::Level::Error => EVENT_ERROR,
+            log::Level::Warn => EVENT_WARNING,
+            _ => EVENT_INFORMATION,
+        };
+        let message = wide(record.args().to_string());
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.handle as *mut c_void,
+                kind,
+                0,
+                EVENT_ID,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null_mut(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe {
+            DeregisterEventSource(self.handle as *mut c_void);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::service::windows::{create_args, failure_args};
+    use crate::service::ServiceSpec;
+
+    #[test]
+    fn test_sc_args() {
+        let spec = ServiceSpec {
+            name: "octobuild_Builder".to_string(),
+            display_name: "Octobuild Builder".to_string(),
+            description: "Compiles tasks of remote octobuild clients".to_string(),
+            executable: PathBuf::from(r"C:\Program Files\octobuild\octo_builder.exe"),
+            drain: Duration::from_secs(60),
+        };
+        assert_eq!(
+            create_args(&spec),
+            [
+                "create",
+                "octobuild_Builder",
+                "binPath=",
+                r#""C:\Program Files\octobuild\octo_builder.exe""#,
+                "start=",
+                "auto",
+                "DisplayName=",
+                "Octobuild Builder"
+            ]
+        );
+        assert_eq!(
+            failure_args(&spec),
+            [
+                "failure",
+                "octobuild_Builder",
+                "reset=",
+                "86400",
+                "actions=",
+                "restart/60000/restart/60000/restart/60000"
+            ]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::cmd;
+use crate::compiler::{
+    CommandArgs, CommandInfo, CompilationTask, CompileResult, OutputInfo, SharedState, Toolchain,
+};
+use crate::config::Config;
+use crate::slots::with_background_priority;
+
+// Report of every shadowed compilation, one JSON line per task.
+const REPORT_FILE: &str = "report.jsonl";
+// Reproduction metadata in mismatch directory.
+const REPRO_FILE: &str = "repro.json";
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShadowOutcome {
+    // Octobuild produced the same object as native compiler.
+    Match,
+    Mismatch,
+    // Native compiler succeeded, octobuild pipeline failed.
+    Failed,
+    // Task can't be shadowed without touching build tree, or native compiler failed.
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ShadowRecord<'a> {
+    pub source: &'a Path,
+    pub output: &'a Path,
+    pub outcome: ShadowOutcome,
+    pub cached: bool,
+    pub native_ms: u128,
+    pub shadow_ms: u128,
+}
+
+#[derive(Serialize)]
+struct Repro<'a> {
+    program: &'a Path,
+    current_dir: Option<&'a Path>,
+    command_line: String,
+    toolchain: Option<String>,
+    source: &'a Path,
+    output: &'a Path,
+    outcome: ShadowOutcome,
+    native_sha256: Option<String>,
+    shadow_sha256: Option<String>,
+    shadow_error: Option<String>,
+}
+
+// Native compiler command together with octobuild tasks for the same sources.
+pub struct ShadowTasks {
+    pub command: CommandInfo,
+    pub args: CommandArgs,
+    pub tasks: Vec<(Arc<dyn Toolchain>, CompilationTask)>,
+}
+
+// Runs octobuild pipeline next to native compiler and compares produced objects.
+// Shadow compilation writes into temporary directory only, so build tree and
+// task results always come from native compiler.
+pub struct ShadowRunner {
+    dir: PathBuf,
+    limit: usize,
+    running: Mutex<usize>,
+    released: Condvar,
+    report: Mutex<Option<File>>,
+    pub matched: AtomicUsize,
+    pub mismatched: AtomicUsize,
+    pub failed: AtomicUsize,
+    pub skipped: AtomicUsize,
+    native_ms: AtomicU64,
+    shadow_ms: AtomicU64,
+}
+
+struct ShadowRun<'a> {
+    toolchain: &'a dyn Toolchain,
+    task: &'a CompilationTask,
+    // Keeps shadow object until comparison.
+    temp: Option<TempDir>,
+    result: crate::Result<CompileResult>,
+    duration: Duration,
+}
+
+impl ShadowRunner {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        ShadowRunner {
+            dir: config
+                .shadow_dir
+                .clone()
+                .unwrap_or_else(|| config.cache.join("shadow")),
+            limit: config.shadow_limit.max(1),
+            running: Mutex::new(0),
+            released: Condvar::new(),
+            report: Mutex::new(None),
+            matched: AtomicUsize::new(0),
+            mismatched: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            skipped: AtomicUsize::new(0),
+            native_ms: AtomicU64::new(0),
+            shadow_ms: AtomicU64::new(0),
+        }
+    }
+
+    // Directory with report and dumps of mismatched tasks.
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Run native command and shadow compilation concurrently. Result of native command is returned as is.
+    pub fn run(
+        &self,
+        state: &SharedState,
+        shadow: &ShadowTasks,
+        native: impl FnOnce() -> crate::Result<OutputInfo>,
+    ) -> crate::Result<OutputInfo> {
+        // Native compiler writes declared outputs, they are not unexpected for shadow compilation.
+        for (_, task) in &shadow.tasks {
+            state
+                .outputs
+                .declare(std::slice::from_ref(&task.output_object));
+        }
+        std::thread::scope(|scope| {
+            let runs = scope.spawn(|| with_background_priority(|| self.compile(state, shadow)));
+            let started = Instant::now();
+            let output = native();
+            let native_time = started.elapsed();
+            let runs = runs.join().unwrap_or_else(|_| {
+                warn!("Shadow compilation panicked");
+                Vec::new()
+            });
+            let native_ok = matches!(&output, Ok(output) if output.success());
+            for run in runs {
+                if let Err(e) = self.compare(shadow, &run, native_ok, native_time) {
+                    warn!(
+                        "Can't compare shadow output for {}: {e}",
+                        run.task.input_source.display()
+                    );
+                }
+            }
+            output
+        })
+    }
+
+    fn compile<'a>(&self, state: &SharedState, shadow: &'a ShadowTasks) -> Vec<ShadowRun<'a>> {
+        shadow
+            .tasks
+            .iter()
+            .map(|(toolchain, task)| {
+                let started = Instant::now();
+                let (temp, result) = self.compile_task(state, toolchain.as_ref(), task);
+                ShadowRun {
+                    toolchain: toolchain.as_ref(),
+                    task,
+                    temp,
+                    result,
+                    duration: started.elapsed(),
+                }
+            })
+            .collect()
+    }
+
+    fn compile_task(
+        &self,
+        state: &SharedState,
+        toolchain: &dyn Toolchain,
+        task: &CompilationTask,
+    ) -> (Option<TempDir>, crate::Result<CompileResult>) {
+        // Precompiled header and dependency file paths are shared with native compiler.
+        if task.shared.pch_usage.is_out() || task.shared.deps_file.is_some() {
+            return (None, Err(crate::Error::from("task writes shared outputs")));
+        }
+        let temp = match tempfile::Builder::new()
+            .prefix("shadow")
+            .tempdir_in(state.temp.primary())
+        {
+            Ok(temp) => temp,
+            Err(e) => return (None, Err(e.into())),
+        };
+        let shadow_task = CompilationTask {
+            output_object: temp
+                .path()
+                .join(task.output_object.file_name().unwrap_or_default()),
+            ..task.clone()
+        };
+        self.acquire();
+        let result = toolchain.compile_task(state, &shadow_task);
+        self.release();
+        (Some(temp), result)
+    }
+
+    fn acquire(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.released.wait(running).unwrap();
+        }
+        *running += 1;
+    }
+
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.released.notify_one();
+    }
+
+    fn compare(
+        &self,
+        shadow: &ShadowTasks,
+        run: &ShadowRun,
+        native_ok: bool,
+        native_time: Duration,
+    ) -> crate::Result<()> {
+        let task = run.task;
+        let shadow_object = run.temp.as_ref().map(|temp| {
+            temp.path()
+                .join(task.output_object.file_name().unwrap_or_default())
+        });
+        let mut shadow_error = None;
+        let mut cached = false;
+        let outcome = match &run.result {
+            _ if !native_ok => ShadowOutcome::Skipped,
+            Err(e) if run.temp.is_none() => {
+                shadow_error = Some(e.to_string());
+                ShadowOutcome::Skipped
+            }
+            Err(e) => {
+                shadow_error = Some(e.to_string());
+                ShadowOutcome::Failed
+            }
+            Ok(result) if !result.output.success() => {
+                let mut text = result.output.stdout.to_vec().unwrap_or_default();
+                text.extend(result.output.stderr.to_vec().unwrap_or_default());
+                shadow_error = Some(String::from_utf8_lossy(&text).into_owned());
+                ShadowOutcome::Failed
+            }
+            Ok(result) => {
+                cached = result.cached;
+                let native = fs::read(&task.output_object)?;
+                match shadow_object.as_deref().map(fs::read).transpose()? {
+                    Some(data) if data == native => ShadowOutcome::Match,
+                    _ => ShadowOutcome::Mismatch,
+                }
+            }
+        };
+        match outcome {
+            ShadowOutcome::Match => &self.matched,
+            ShadowOutcome::Mismatch => &self.mismatched,
+            ShadowOutcome::Failed => &self.failed,
+            ShadowOutcome::Skipped => &self.skipped,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+        if outcome != ShadowOutcome::Skipped {
+            self.native_ms
+                .fetch_add(native_time.as_millis() as u64, Ordering::Relaxed);
+            self.shadow_ms
+                .fetch_add(run.duration.as_millis() as u64, Ordering::Relaxed);
+        }
+        self.write_report(&ShadowRecord {
+            source: &task.input_source,
+            output: &task.output_object,
+            outcome,
+            cached,
+            native_ms: native_time.as_millis(),
+            shadow_ms: run.duration.as_millis(),
+        })?;
+        if matches!(outcome, ShadowOutcome::Mismatch | ShadowOutcome::Failed) {
+            self.dump(
+                run,
+                &Repro {
+                    program: &shadow.command.program,
+                    current_dir: shadow.command.current_dir.as_deref(),
+                    command_line: command_line(&shadow.args)?,
+                    toolchain: run.toolchain.task_identifier(task),
+                    source: &task.input_source,
+                    output: &task.output_object,
+                    outcome,
+                    native_sha256: file_hash(&task.output_object),
+                    shadow_sha256: shadow_object.as_deref().and_then(file_hash),
+                    shadow_error,
+                },
+                shadow_object.as_deref(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_report(&self, record: &ShadowRecord) -> crate::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut report = self.report.lock().unwrap();
+        if report.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            *report = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(REPORT_FILE))?,
+            );
+        }
+        if let Some(file) = report.as_mut() {
+            file.write_all(&line)?;
+        }
+        Ok(())
+    }
+
+    // Save objects and command of mismatched task for offline analysis.
+    fn dump(
+        &self,
+        run: &ShadowRun,
+        repro: &Repro,
+        shadow_object: Option<&Path>,
+    ) -> crate::Result<()> {
+        let task = run.task;
+        let hash = hex::encode(Sha256::digest(
+            task.output_object.to_string_lossy().as_bytes(),
+        ));
+        let stem = task
+            .input_source
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let dir = self.dir.join(format!("{stem}-{}", &hash[..8]));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(REPRO_FILE), serde_json::to_vec_pretty(repro)?)?;
+        drop(fs::copy(&task.output_object, dir.join("native.obj")));
+        if let Some(path) = shadow_object {
+            drop(fs::copy(path, dir.join("shadow.obj")));
+        }
+        warn!(
+            "Shadow compilation of {} differs from native build, see {}",
+            task.input_source.display(),
+            dir.display()
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for ShadowRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shadow statistic: matched {}, mismatched {}, failed {}, skipped {} tasks, native {:.1}s, octobuild {:.1}s",
+            self.matched.load(Ordering::Relaxed),
+            self.mismatched.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+            self.skipped.load(Ordering::Relaxed),
+            Duration::from_millis(self.native_ms.load(Ordering::Relaxed)).as_secs_f64(),
+            Duration::from_millis(self.shadow_ms.load(Ordering::Relaxed)).as_secs_f64(),
+        )
+    }
+}
+
+fn command_line(args: &CommandArgs) -> crate::Result<String> {
+    Ok(match args {
+        CommandArgs::Raw(line) => line.clone(),
+        CommandArgs::Regular(args) => {
+            let args: Vec<OsString> = args.iter().map(OsString::from).collect();
+            cmd::native::join(&args)?.to_string_lossy().into_owned()
+        }
+    })
+}
+
+fn file_hash(path: &Path) -> Option<String> {
+    fs::read(path)
+        .ok()
+        .map(|data| hex::encode(Sha256::digest(data)))
+}
@@ -0,0 +1,273 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::SystemTime;
+
+// Reparse tag of app execution alias, used by Store-based Visual Studio installs.
+const IO_REPARSE_TAG_APPEXECLINK: u32 = 0x8000_001B;
+// App execution alias data: package id, app user model id, target executable, ...
+const APPEXECLINK_TARGET_INDEX: usize = 2;
+
+// Compiler is spawned through the path used by build, so app execution alias or thin shim
+// (like Scoop `shim.exe` with `<name>.shim` next to it) performs its environment setup.
+// Version probes, invalidation and files bundled with compiler use the real binary instead:
+// shim itself doesn't change when the compiler behind it is updated.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ToolchainPath {
+    pub spawn: PathBuf,
+    pub identity: PathBuf,
+}
+
+impl ToolchainPath {
+    #[must_use]
+    pub fn resolve(spawn: PathBuf) -> Self {
+        let identity = shim_target(&spawn)
+            .or_else(|| app_exec_link_target(&spawn))
+            .unwrap_or_else(|| spawn.clone());
+        ToolchainPath {
+            identity: identity.canonicalize().unwrap_or(identity),
+            spawn,
+        }
+    }
+
+    // Size and modification time of real compiler binary.
+    #[must_use]
+    pub fn stamp(&self) -> Option<(u64, SystemTime)> {
+        let metadata = fs::metadata(&self.identity).ok()?;
+        Some((metadata.len(), metadata.modified().ok()?))
+    }
+
+    // Run version probe through spawn path and retry with real binary if shim fails to forward
+    // it: shim without its environment may print own error or nothing at all.
+    pub fn probe<T>(&self, args: &[&str], parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+        let run = |program: &Path| -> Option<T> {
+            let output = Command::new(program).args(args).output().ok()?;
+            if is_shim_failure(&output) {
+                return None;
+            }
+            parse(&String::from_utf8_lossy(&output.stdout))
+        };
+        run(&self.spawn).or_else(|| {
+            if self.identity == self.spawn {
+                None
+            } else {
+                run(&self.identity)
+            }
+        })
+    }
+}
+
+fn is_shim_failure(output: &Output) -> bool {
+    !output.status.success() || output.stdout.iter().all(u8::is_ascii_whitespace)
+}
+
+// Check that path is app execution alias: it isn't a regular file, but can be executed.
+#[must_use]
+pub fn is_app_exec_link(path: &Path) -> bool {
+    app_exec_link_target(path).is_some()
+}
+
+// Target of Scoop-style shim: `<name>.shim` next to executable with `path = "<target>"` line.
+fn shim_target(path: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(path.with_extension("shim")).ok()?;
+    let target = content.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "path").then(|| value.trim().trim_matches('"'))
+    })?;
+    let target = path.parent()?.join(target);
+    target.is_file().then_some(target)
+}
+
+#[cfg(unix)]
+fn app_exec_link_target(_: &Path) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(windows)]
+fn app_exec_link_target(path: &Path) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::fileapi::{CreateFileW, OPEN_EXISTING};
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::ioapiset::DeviceIoControl;
+    use winapi::um::winbase::{FILE_FLAG_BACKUP_SEMANTICS, FILE_FLAG_OPEN_REPARSE_POINT};
+    use winapi::um::winioctl::FSCTL_GET_REPARSE_POINT;
+    use winapi::um::winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+    // MAXIMUM_REPARSE_DATA_BUFFER_SIZE
+    const BUFFER_SIZE: usize = 16 * 1024;
+
+    let path_raw: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    let mut size: DWORD = 0;
+    unsafe {
+        let handle = CreateFileW(
+            path_raw.as_ptr(),
+            0,
+            FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS,
+            ptr::null_mut(),
+        );
+        if handle == INVALID_HANDLE_VALUE {
+            return None;
+        }
+        let success = DeviceIoControl(
+            handle,
+            FSCTL_GET_REPARSE_POINT,
+            ptr::null_mut(),
+            0,
+            buffer.as_mut_ptr().cast(),
+            BUFFER_SIZE as DWORD,
+            &mut size,
+            ptr::null_mut(),
+        );
+        CloseHandle(handle);
+        if success == 0 {
+            return None;
+        }
+    }
+    parse_app_exec_link(&buffer[..size as usize])
+}
+
+// Parse REPARSE_DATA_BUFFER of app execution alias.
+#[cfg_attr(unix, allow(dead_code))]
+fn parse_app_exec_link(data: &[u8]) -> Option<PathBuf> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    if u32_at(0)? != IO_REPARSE_TAG_APPEXECLINK {
+        return None;
+    }
+    let length = u16::from_le_bytes(data.get(4..6)?.try_into().ok()?) as usize;
+    // Header is followed by version of alias data.
+    let body = data.get(12..8 + length)?;
+    let wide: Vec<u16> = body
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let target = wide.split(|c| *c == 0).nth(APPEXECLINK_TARGET_INDEX)?;
+    if target.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(String::from_utf16(target).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::shim::{parse_app_exec_link, ToolchainPath, IO_REPARSE_TAG_APPEXECLINK};
+
+    fn app_exec_link(strings: &[&str]) -> Vec<u8> {
+        let mut body = 3u32.to_le_bytes().to_vec();
+        for s in strings {
+            for c in s.encode_utf16().chain(Some(0)) {
+                body.extend(c.to_le_bytes());
+            }
+        }
+        let mut data = IO_REPARSE_TAG_APPEXECLINK.to_le_bytes().to_vec();
+        data.extend((body.len() as u16).to_le_bytes());
+        data.extend([0, 0]);
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn test_parse_app_exec_link() {
+        let data = app_exec_link(&[
+            "Microsoft.VisualStudio_8wekyb3d8bbwe",
+            "Microsoft.VisualStudio_8wekyb3d8bbwe!cl",
+            "C:\\Program Files\\WindowsApps\\VC\\bin\\Hostx64\\x64\\cl.exe",
+            "0",
+        ]);
+        assert_eq!(
+            parse_app_exec_link(&data),
+            Some(PathBuf::from(
+                "C:\\Program Files\\WindowsApps\\VC\\bin\\Hostx64\\x64\\cl.exe"
+            ))
+        );
+        // Other reparse points, like symbolic links, are resolved by canonicalize.
+        let mut symlink = data.clone();
+        symlink[..4].copy_from_slice(&0xA000_000Cu32.to_le_bytes());
+        assert_eq!(parse_app_exec_link(&symlink), None);
+        assert_eq!(parse_app_exec_link(&data[..20]), None);
+        assert_eq!(parse_app_exec_link(&app_exec_link(&["a", "b"])), None);
+    }
+
+    #[test]
+    fn test_shim_identity() {
+        let temp = tempfile::tempdir().unwrap();
+        let shims = temp.path().join("shims");
+        let real = temp.path().join("VC/bin");
+        fs::create_dir_all(&shims).unwrap();
+        fs::create_dir_all(&real).unwrap();
+        fs::write(shims.join("cl.exe"), b"shim").unwrap();
+        fs::write(real.join("cl.exe"), b"cl 19.38").unwrap();
+        fs::write(
+            shims.join("cl.shim"),
+            "path = \"../VC/bin/cl.exe\"\nargs = /nologo\n",
+        )
+        .unwrap();
+
+        let path = ToolchainPath::resolve(shims.join("cl.exe"));
+        assert_eq!(path.spawn, shims.join("cl.exe"));
+        assert_eq!(path.identity, real.join("cl.exe").canonicalize().unwrap());
+
+        // Compiler update doesn't touch shim, but changes identity.
+        let stamp = path.stamp().unwrap();
+        fs::write(real.join("cl.exe"), b"cl 19.40.1").unwrap();
+        assert_ne!(path.stamp().unwrap(), stamp);
+
+        // Shim with missing target and plain executable are identified by themselves.
+        fs::write(shims.join("cl.shim"), "path = \"missing.exe\"\n").unwrap();
+        let path = ToolchainPath::resolve(shims.join("cl.exe"));
+        assert_eq!(path.identity, shims.join("cl.exe").canonicalize().unwrap());
+        let path = ToolchainPath::resolve(real.join("cl.exe"));
+        assert_eq!(path.identity, real.join("cl.exe").canonicalize().unwrap());
+    }
+
+    #[cfg(unix)]
+    fn script(path: &Path, body: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_probe_retries_identity() {
+        let temp = tempfile::tempdir().unwrap();
+        let real = temp.path().join("real-clang");
+        let shim = temp.path().join("clang");
+        script(&real, "echo \"clang version 17.0.1\"");
+        fs::write(temp.path().join("clang.shim"), "path = real-clang\n").unwrap();
+        let parse = |output: &str| {
+            output
+                .strip_prefix("clang version ")
+                .map(|version| version.trim().to_string())
+        };
+
+        // Working shim forwards the probe.
+        script(&shim, "echo \"clang version 17.0.1-shim\"");
+        let path = ToolchainPath::resolve(shim.clone());
+        assert_eq!(path.probe(&["--version"], parse).unwrap(), "17.0.1-shim");
+
+        // Shim error and empty banner are retried with real binary.
+        script(&shim, "echo \"shim: cannot find target\" >&2\nexit 1");
+        assert_eq!(path.probe(&["--version"], parse).unwrap(), "17.0.1");
+        script(&shim, "exit 0");
+        assert_eq!(path.probe(&["--version"], parse).unwrap(), "17.0.1");
+
+        // Without shim file there is nothing to retry with.
+        fs::remove_file(temp.path().join("clang.shim")).unwrap();
+        let path = ToolchainPath::resolve(shim);
+        assert_eq!(path.probe(&["--version"], parse), None);
+    }
+}
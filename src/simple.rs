@@ -1,5 +1,5 @@
 use std::env;
-use std::io::{stdout, Write};
+use std::io::{stderr, stdout, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -11,13 +11,13 @@ use crate::cluster::client::RemoteCompiler;
 use crate::compiler::{CommandArgs, CommandInfo, Compiler, CompilerGroup, SharedState};
 use crate::config::Config;
 use crate::vs::compiler::VsCompiler;
-use crate::worker::execute_graph;
-use crate::worker::{BuildAction, BuildGraph, BuildResult, BuildTask};
+use crate::worker::{execute_graph, failure_exit_code};
+use crate::worker::{BuildAction, BuildGraph, BuildTask};
 
 #[must_use]
-pub fn supported_compilers() -> CompilerGroup {
+pub fn supported_compilers(config: &Config) -> CompilerGroup {
     CompilerGroup::new()
-        .add::<VsCompiler>()
+        .with(VsCompiler::new(config))
         .add::<ClangCompiler>()
 }
 
@@ -49,6 +49,9 @@ where
     };
     match compile(&config, &state, exec, compiler) {
         Ok(_) => 0,
+        Err(crate::Error::BuildFailed(failures)) => {
+            failure_exit_code(&failures, config.exit_code_mode)
+        }
         Err(e) => {
             error!("FATAL ERROR: {e}");
             1
@@ -65,30 +68,72 @@ pub fn compile<C>(
 where
     C: Compiler,
 {
-    let command_info = CommandInfo::simple(PathBuf::from(exec));
-    let remote = RemoteCompiler::new(&config.coordinator, compiler);
+    let command_info = CommandInfo::simple(PathBuf::from(exec))
+        .remap(&config.tool_remap)
+        .with_config(config.config_label.as_deref());
+    let remote = RemoteCompiler::new(config, compiler);
     let args = env::args().skip(1).collect();
-    let actions = BuildAction::create_tasks(
+    let create_tasks = if config.shadow {
+        BuildAction::create_shadow_tasks
+    } else {
+        BuildAction::create_tasks
+    };
+    let mut actions = create_tasks(
         &remote,
         command_info,
         CommandArgs::Regular(args),
         exec,
         config.run_second_cpp,
+        config.split_link,
+        &state.uncached,
     );
+    let link = BuildAction::take_link(&mut actions);
 
     let mut build_graph: BuildGraph = Graph::new();
+    let mut compiled = Vec::new();
     for action in actions {
-        build_graph.add_node(Arc::new(BuildTask {
+        compiled.push(build_graph.add_node(Arc::new(BuildTask {
             title: action.title().into_owned(),
             action,
+        })));
+    }
+    if let Some(action) = link {
+        let node = build_graph.add_node(Arc::new(BuildTask {
+            title: exec.to_string(),
+            action,
         }));
+        for index in compiled {
+            build_graph.add_edge(node, index, ());
+        }
     }
-    let result = execute_graph(state, build_graph, config.process_limit, print_task_result);
+    let result = execute_graph(state, build_graph, config.process_limit, |result| {
+        state.console.print(result, config.diagnostic_style)?;
+        state.annotations.annotate(&mut stdout(), result)
+    });
+    state
+        .includes
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .outputs
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .bigobj
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state
+        .preprocess_check
+        .print_warnings(&mut stderr(), config.diagnostic_style)?;
+    state.annotations.print_summary(&mut stdout())?;
+    state.console.print_summary(&mut stdout())?;
     writeln!(stdout(), "{}", state.statistic)?;
+    state.uncached.print_summary(&mut stdout())?;
+    state.preprocess_check.print_summary(&mut stdout())?;
+    if state.schedule.is_deterministic() {
+        writeln!(stdout(), "{}", state.schedule)?;
+    }
+    state.transforms.print_hits(&mut stdout())?;
+    state.temp.print_usage(&mut stdout())?;
+    if let Some(shadow) = &state.shadow {
+        writeln!(stdout(), "{shadow}")?;
+    }
     result
 }
-
-fn print_task_result(result: &BuildResult) -> crate::Result<()> {
-    result.result.print_output()?;
-    Ok(())
-}
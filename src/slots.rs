@@ -0,0 +1,413 @@
+use std::cell::Cell;
+use std::cmp::max;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::jobserver::{JobServer, JobToken};
+
+// How often waiting process retries to take a slot.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Nice value of compiler processes started without a slot.
+#[cfg(unix)]
+const OVERFLOW_NICE: libc::c_int = 10;
+
+thread_local! {
+    // Current thread runs compiler without a slot.
+    static OVERFLOW: Cell<bool> = const { Cell::new(false) };
+    // Current thread runs background work that must not slow down the build.
+    static BACKGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+// Machine-wide pool of compiler slots shared by all octobuild processes.
+// Every slot is a lock file holding PID of its owner, so slots of crashed processes can be reclaimed.
+// PID is followed by unique id of the token, so token releases only its own slot.
+// When running under GNU make jobserver, its tokens are used instead.
+pub struct SlotArbiter {
+    dir: PathBuf,
+    limit: usize,
+    grace: Duration,
+    jobserver: Option<JobServer>,
+}
+
+pub struct SlotToken<'a> {
+    // None if arbiter is disabled or grace period expired.
+    slot: Option<Slot>,
+    job: Option<(&'a JobServer, JobToken)>,
+    overflow: bool,
+}
+
+struct Slot {
+    path: PathBuf,
+    // Content of slot file.
+    owner: String,
+}
+
+impl SlotArbiter {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        SlotArbiter {
+            jobserver: JobServer::from_env(),
+            ..SlotArbiter::with_dir(
+                &std::env::temp_dir().join("octobuild-slots"),
+                config.slot_limit.unwrap_or(max(config.process_limit, 1)),
+                Duration::from_secs(config.slot_grace_sec),
+            )
+        }
+    }
+
+    #[must_use]
+    pub fn with_dir(dir: &Path, limit: usize, grace: Duration) -> Self {
+        SlotArbiter {
+            dir: dir.to_path_buf(),
+            limit,
+            grace,
+            jobserver: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_jobserver(self, jobserver: JobServer) -> Self {
+        SlotArbiter {
+            jobserver: Some(jobserver),
+            ..self
+        }
+    }
+
+    // Wait for a free slot. After grace period task proceeds without a slot at reduced priority,
+    // so a broken peer process never blocks the build.
+    pub fn acquire(&self) -> SlotToken<'_> {
+        if let Some(jobserver) = &self.jobserver {
+            match jobserver.acquire(self.grace) {
+                Ok(Some(token)) => {
+                    return SlotToken {
+                        slot: None,
+                        job: Some((jobserver, token)),
+                        overflow: false,
+                    }
+                }
+                Ok(None) => debug!("No GNU make jobserver token for {:?}", self.grace),
+                Err(e) => warn!("Can't take GNU make jobserver token: {e}"),
+            }
+            return SlotToken::overflow();
+        }
+        if self.limit == 0 {
+            return SlotToken {
+                slot: None,
+                job: None,
+                overflow: false,
+            };
+        }
+        let deadline = Instant::now() + self.grace;
+        loop {
+            match self.try_acquire() {
+                Ok(Some(slot)) => {
+                    return SlotToken {
+                        slot: Some(slot),
+                        job: None,
+                        overflow: false,
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Can't take compiler slot in {}: {e}", self.dir.display());
+                    break;
+                }
+            }
+            if Instant::now() >= deadline {
+                debug!("No free compiler slot for {:?}", self.grace);
+                break;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        SlotToken::overflow()
+    }
+
+    fn try_acquire(&self) -> std::io::Result<Option<Slot>> {
+        fs::create_dir_all(&self.dir)?;
+        // Slot file is created by hard link, so it never exists without owner.
+        let owner = format!("{} {}", std::process::id(), Uuid::new_v4());
+        let temp = self.dir.join(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&temp, &owner)?;
+        let result = self.take_free_slot(&temp);
+        drop(fs::remove_file(&temp));
+        Ok(result?.map(|path| Slot { path, owner }))
+    }
+
+    fn take_free_slot(&self, temp: &Path) -> std::io::Result<Option<PathBuf>> {
+        for index in 0..self.limit {
+            let path = self.dir.join(format!("slot-{index}.lock"));
+            match fs::hard_link(temp, &path) {
+                Ok(()) => return Ok(Some(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            if self.reclaim(&path) && fs::hard_link(temp, &path).is_ok() {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    // Free slot of dead process. Slot is moved away to unique name first, so of concurrent
+    // reclaimers only one gets it. Slot taken by another reclaimer since the check is put back.
+    fn reclaim(&self, path: &Path) -> bool {
+        if !is_stale(path) {
+            return false;
+        }
+        let moved = self.dir.join(format!("{}.stale", Uuid::new_v4()));
+        if fs::rename(path, &moved).is_err() {
+            return false;
+        }
+        let stale = is_stale(&moved);
+        if stale {
+            warn!(
+                "Reclaiming compiler slot of dead process: {}",
+                path.display()
+            );
+        } else if let Err(e) = fs::hard_link(&moved, path) {
+            // Slot was taken again, its owner runs without slot file until it finishes.
+            warn!("Can't put back compiler slot {}: {e}", path.display());
+        }
+        drop(fs::remove_file(&moved));
+        stale
+    }
+}
+
+impl SlotToken<'_> {
+    fn overflow() -> Self {
+        OVERFLOW.with(|overflow| overflow.set(true));
+        SlotToken {
+            slot: None,
+            job: None,
+            overflow: true,
+        }
+    }
+
+    #[must_use]
+    pub fn is_overflow(&self) -> bool {
+        self.overflow
+    }
+}
+
+impl Drop for SlotToken<'_> {
+    fn drop(&mut self) {
+        if self.overflow {
+            OVERFLOW.with(|overflow| overflow.set(false));
+        }
+        if let Some(slot) = self.slot.take() {
+            // Slot file could be replaced while it was moved away by concurrent reclaimer.
+            if fs::read_to_string(&slot.path).is_ok_and(|owner| owner == slot.owner) {
+                drop(fs::remove_file(slot.path));
+            }
+        }
+        if let Some((jobserver, token)) = self.job.take() {
+            jobserver.release(token);
+        }
+    }
+}
+
+fn is_stale(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(content) => !content
+            .split_whitespace()
+            .next()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(is_process_alive),
+        // Slot was released while we were looking at it.
+        Err(_) => false,
+    }
+}
+
+// Lower priority of compiler process started by current thread without a slot.
+pub fn adjust_priority(child: &Child) {
+    if OVERFLOW.with(Cell::get) || BACKGROUND.with(Cell::get) {
+        lower_priority(child.id());
+    }
+}
+
+// Run function with compiler processes started by current thread at reduced priority.
+pub fn with_background_priority<T>(func: impl FnOnce() -> T) -> T {
+    let previous = BACKGROUND.with(|background| background.replace(true));
+    let result = func();
+    BACKGROUND.with(|background| background.set(previous));
+    result
+}
+
+// Lower priority of current process, processes started after that inherit it.
+pub fn lower_own_priority() {
+    lower_priority(std::process::id());
+}
+
+#[cfg(unix)]
+pub fn is_process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    let exists = unsafe { libc::kill(pid, 0) == 0 };
+    // EPERM means that process exists, but belongs to another user.
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+pub fn is_process_alive(pid: u32) -> bool {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{GetExitCodeProcess, OpenProcess};
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    // Exit code of running process.
+    const STILL_ACTIVE: u32 = 259;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let mut code = 0;
+        let alive = GetExitCodeProcess(handle, &mut code) == 0 || code == STILL_ACTIVE;
+        CloseHandle(handle);
+        alive
+    }
+}
+
+#[cfg(unix)]
+fn lower_priority(pid: u32) {
+    unsafe {
+        libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, OVERFLOW_NICE);
+    }
+}
+
+#[cfg(windows)]
+fn lower_priority(pid: u32) {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{OpenProcess, SetPriorityClass};
+    use winapi::um::winbase::BELOW_NORMAL_PRIORITY_CLASS;
+    use winapi::um::winnt::PROCESS_SET_INFORMATION;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_SET_INFORMATION, 0, pid);
+        if !handle.is_null() {
+            SetPriorityClass(handle, BELOW_NORMAL_PRIORITY_CLASS);
+            CloseHandle(handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::slots::SlotArbiter;
+
+    #[test]
+    fn test_concurrency_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two schedulers that share slot directory behave like two octobuild processes.
+        let schedulers = [
+            Arc::new(SlotArbiter::with_dir(
+                dir.path(),
+                2,
+                Duration::from_secs(60),
+            )),
+            Arc::new(SlotArbiter::with_dir(
+                dir.path(),
+                2,
+                Duration::from_secs(60),
+            )),
+        ];
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let arbiter = schedulers[i % 2].clone();
+                let running = running.clone();
+                let peak = peak.clone();
+                thread::spawn(move || {
+                    for _ in 0..3 {
+                        let token = arbiter.acquire();
+                        assert!(!token.is_overflow());
+                        let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(current, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(10));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        drop(token);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let arbiter = SlotArbiter::with_dir(dir.path(), 1, Duration::ZERO);
+        let token = arbiter.acquire();
+        assert!(!token.is_overflow());
+        assert!(arbiter.acquire().is_overflow());
+        drop(token);
+        assert!(!arbiter.acquire().is_overflow());
+
+        let disabled = SlotArbiter::with_dir(dir.path(), 0, Duration::ZERO);
+        assert!(!disabled.acquire().is_overflow());
+    }
+
+    #[test]
+    fn test_reclaim_dead_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut child = Command::new(env!("CARGO"))
+            .arg("--version")
+            .spawn()
+            .unwrap();
+        let dead_pid = child.id();
+        child.wait().unwrap();
+        fs::write(dir.path().join("slot-0.lock"), dead_pid.to_string()).unwrap();
+
+        let arbiter = SlotArbiter::with_dir(dir.path(), 1, Duration::ZERO);
+        let token = arbiter.acquire();
+        assert!(!token.is_overflow());
+        let owner = fs::read_to_string(dir.path().join("slot-0.lock")).unwrap();
+        assert!(owner.starts_with(&format!("{} ", std::process::id())));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        drop(token);
+
+        // Slot of live process is never reclaimed.
+        fs::write(
+            dir.path().join("slot-0.lock"),
+            std::process::id().to_string(),
+        )
+        .unwrap();
+        assert!(arbiter.acquire().is_overflow());
+        assert!(!arbiter.reclaim(&dir.path().join("slot-0.lock")));
+    }
+
+    #[test]
+    fn test_release_own_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slot-0.lock");
+        let arbiter = SlotArbiter::with_dir(dir.path(), 1, Duration::ZERO);
+        let token = arbiter.acquire();
+        assert!(!token.is_overflow());
+        // Slot was given to another token while this one was running.
+        let other = format!("{} other", std::process::id());
+        fs::write(&path, &other).unwrap();
+        drop(token);
+        assert_eq!(fs::read_to_string(&path).unwrap(), other);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::compiler::OutputInfo;
+use crate::diagnostics::{cannot_open_paths, parse_output};
+
+// Visual Studio and code generators briefly hold exclusive locks on sources while saving them,
+// compiler racing the save fails with C1083 although the source exists. Such task is retried
+// with growing delay a few times.
+pub const LOCK_RETRY_COUNT: u32 = 3;
+pub const LOCK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Check if compiler failed to open task source because it was locked.
+#[must_use]
+pub fn is_locked_source(source: &Path, output: &OutputInfo) -> bool {
+    !output.success()
+        && cannot_open_paths(&parse_output(output))
+            .iter()
+            .any(|path| is_same_source(source, path))
+        && is_transient_open_failure(source)
+}
+
+// Path in diagnostic is spelled like in command line, so it may be relative.
+fn is_same_source(source: &Path, path: &str) -> bool {
+    let normalize = |path: &str| path.replace('\\', "/").to_ascii_lowercase();
+    let source = normalize(&source.to_string_lossy());
+    let path = normalize(path);
+    source == path || source.ends_with(&format!("/{}", path.trim_start_matches("./")))
+}
+
+// Source that opens now was locked only for a moment. Missing source is never retried.
+fn is_transient_open_failure(source: &Path) -> bool {
+    match File::open(source) {
+        Ok(_) => true,
+        Err(e) => is_sharing_violation(&e),
+    }
+}
+
+fn is_sharing_violation(error: &std::io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    const CODES: [i32; 2] = [32, 33];
+    #[cfg(not(windows))]
+    const CODES: [i32; 0] = [];
+
+    error
+        .raw_os_error()
+        .is_some_and(|code| CODES.contains(&code))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::compiler::{OutputInfo, TaskStatus};
+    use crate::io::taskoutput::TaskOutput;
+    use crate::sourcelock::{is_locked_source, is_same_source};
+
+    fn failure(stdout: &str) -> OutputInfo {
+        OutputInfo {
+            status: TaskStatus::Code(2),
+            stdout: TaskOutput::from(stdout.as_bytes().to_vec()),
+            stderr: TaskOutput::default(),
+        }
+    }
+
+    #[test]
+    fn test_locked_source() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("a.cpp");
+        let output = failure(&format!(
+            "c1xx: fatal error C1083: Cannot open source file: '{}': Permission denied\r\n",
+            source.display()
+        ));
+        // Source doesn't exist, compiler is right.
+        assert!(!is_locked_source(&source, &output));
+        // Lock is already released.
+        fs::write(&source, "int a;\n").unwrap();
+        assert!(is_locked_source(&source, &output));
+        // Another file can't be opened.
+        assert!(!is_locked_source(&temp.path().join("b.cpp"), &output));
+        assert!(!is_locked_source(
+            &source,
+            &failure("a.cpp(1): fatal error C1083: Cannot open include file: 'b.h': No such file or directory\r\n")
+        ));
+    }
+
+    #[test]
+    fn test_same_source() {
+        assert!(is_same_source(
+            std::path::Path::new("/work/My Game/Src/a.cpp"),
+            "src\\a.cpp"
+        ));
+        assert!(is_same_source(
+            std::path::Path::new("/work/a.cpp"),
+            "./a.cpp"
+        ));
+        assert!(!is_same_source(
+            std::path::Path::new("/work/ba.cpp"),
+            "a.cpp"
+        ));
+    }
+
+    // Lock is held by a helper thread like by IDE saving the file.
+    #[cfg(windows)]
+    #[test]
+    fn test_sharing_violation() {
+        use std::os::windows::fs::OpenOptionsExt;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("a.cpp");
+        fs::write(&source, "int a;\n").unwrap();
+        let output = failure(&format!(
+            "c1xx: fatal error C1083: Cannot open source file: '{}': Permission denied\r\n",
+            source.display()
+        ));
+        let (locked, wait) = mpsc::channel();
+        let path = source.clone();
+        let helper = thread::spawn(move || {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .share_mode(0)
+                .open(path)
+                .unwrap();
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+            drop(file);
+        });
+        wait.recv().unwrap();
+        assert!(super::is_sharing_violation(
+            &fs::File::open(&source).unwrap_err()
+        ));
+        assert!(is_locked_source(&source, &output));
+        helper.join().unwrap();
+        assert!(fs::File::open(&source).is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use regex::bytes::{Regex, RegexBuilder};
+use sha2::{Digest, Sha256};
+
+use crate::compiler::CompilerOutput;
+use crate::config::PreprocessTransform;
+use crate::run::glob_match;
+
+// Every rule is applied to every line of every preprocessed file, so keep rule set small.
+const MAX_RULES: usize = 16;
+// Limit of compiled pattern size in bytes: prevents patterns with huge automata.
+const MAX_PATTERN_SIZE: usize = 64 * 1024;
+
+struct Rule {
+    config: PreprocessTransform,
+    regex: Regex,
+    hits: AtomicUsize,
+}
+
+// Replacements in preprocessed stream to work around compiler bugs.
+// Rules are applied to compiler input only, after cache key hashing.
+pub struct TransformRules {
+    rules: Vec<Rule>,
+}
+
+impl TransformRules {
+    pub fn new(rules: &[PreprocessTransform]) -> crate::Result<Self> {
+        if rules.len() > MAX_RULES {
+            return Err(crate::Error::Generic(format!(
+                "Too many preprocess transform rules: {} (max {MAX_RULES})",
+                rules.len()
+            )));
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let regex = RegexBuilder::new(&rule.pattern)
+                    .size_limit(MAX_PATTERN_SIZE)
+                    .build()
+                    .map_err(|e| {
+                        crate::Error::Generic(format!(
+                            "Invalid preprocess transform pattern {:?}: {e}",
+                            rule.pattern
+                        ))
+                    })?;
+                Ok(Rule {
+                    config: rule.clone(),
+                    regex,
+                    hits: AtomicUsize::new(0),
+                })
+            })
+            .collect::<crate::Result<Vec<Rule>>>()?;
+        Ok(TransformRules { rules })
+    }
+
+    fn active<'a>(&'a self, source: &'a Path) -> impl Iterator<Item = &'a Rule> + 'a {
+        self.rules.iter().filter(move |rule| {
+            rule.config
+                .source
+                .as_ref()
+                .map_or(true, |pattern| glob_match(pattern, source))
+        })
+    }
+
+    // Digest of rules applied to source file, None if there are no such rules.
+    // Compiled bytes differ from hashed ones, so the digest must be a part of cache key.
+    #[must_use]
+    pub fn digest(&self, source: &Path) -> Option<String> {
+        let mut hasher = Sha256::new();
+        let mut count = 0;
+        for rule in self.active(source) {
+            for value in [&rule.config.pattern, &rule.config.replacement] {
+                hasher.update((value.len() as u64).to_le_bytes());
+                hasher.update(value.as_bytes());
+            }
+            count += 1;
+        }
+        (count > 0).then(|| hex::encode(hasher.finalize()))
+    }
+
+    // Apply rules line by line: patterns never match across line boundaries.
+    #[must_use]
+    pub fn apply(&self, source: &Path, preprocessed: CompilerOutput) -> CompilerOutput {
+        let rules: Vec<&Rule> = self.active(source).collect();
+        if rules.is_empty() {
+            return preprocessed;
+        }
+        let data = preprocessed.to_vec();
+        let mut result = Vec::with_capacity(data.len());
+        let mut changed = false;
+        for line in data.split_inclusive(|c| *c == b'\n') {
+            // Keep end of line out of reach of `$` and `\s` patterns.
+            let body = line
+                .strip_suffix(b"\n")
+                .map_or(line, |body| body.strip_suffix(b"\r").unwrap_or(body));
+            let eol = &line[body.len()..];
+            let mut line = body.to_vec();
+            for rule in &rules {
+                let hits = rule.regex.find_iter(&line).count();
+                if hits == 0 {
+                    continue;
+                }
+                rule.hits.fetch_add(hits, Ordering::Relaxed);
+                line = rule
+                    .regex
+                    .replace_all(&line, rule.config.replacement.as_bytes())
+                    .into_owned();
+                changed = true;
+            }
+            result.extend_from_slice(&line);
+            result.extend_from_slice(eol);
+        }
+        if changed {
+            CompilerOutput::Vec(result)
+        } else {
+            preprocessed
+        }
+    }
+
+    pub fn print_hits(&self, out: &mut impl Write) -> crate::Result<()> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        writeln!(out, "Preprocess transform rules:")?;
+        for rule in &self.rules {
+            writeln!(
+                out,
+                "  {:?}: {} replacement(s)",
+                rule.config.pattern,
+                rule.hits.load(Ordering::Relaxed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+
+    use crate::compiler::CompilerOutput;
+    use crate::config::PreprocessTransform;
+    use crate::transform::{TransformRules, MAX_RULES};
+
+    fn rule(pattern: &str, replacement: &str, source: Option<&str>) -> PreprocessTransform {
+        PreprocessTransform {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            source: source.map(ToString::to_string),
+        }
+    }
+
+    fn apply(rules: &TransformRules, source: &str, content: &str) -> String {
+        let output = rules.apply(
+            Path::new(source),
+            CompilerOutput::Vec(content.as_bytes().to_vec()),
+        );
+        String::from_utf8(output.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = TransformRules::new(&[
+            rule(r"__builtin_ice\((\w+)\)", "($1)", None),
+            rule("^#pragma bad.*$", "", Some("Engine/Plugins/**")),
+        ])
+        .unwrap();
+        let content =
+            "#line 1 \"a.cpp\"\nint a = __builtin_ice(x) + __builtin_ice(y);\n#pragma bad\n";
+        assert_eq!(
+            apply(&rules, "/work/Engine/Source/a.cpp", content),
+            "#line 1 \"a.cpp\"\nint a = (x) + (y);\n#pragma bad\n"
+        );
+        assert_eq!(
+            apply(&rules, "/work/Engine/Plugins/a.cpp", content),
+            "#line 1 \"a.cpp\"\nint a = (x) + (y);\n\n"
+        );
+        assert_eq!(rules.rules[0].hits.load(Ordering::Relaxed), 4);
+        assert_eq!(rules.rules[1].hits.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_digest() {
+        let rules = TransformRules::new(&[rule("a", "b", Some("*.c"))]).unwrap();
+        assert_eq!(rules.digest(Path::new("/work/a.cpp")), None);
+        let digest = rules.digest(Path::new("/work/a.c")).unwrap();
+
+        // Any change of applied rules changes digest.
+        let other = TransformRules::new(&[rule("a", "c", Some("*.c"))]).unwrap();
+        assert_ne!(other.digest(Path::new("/work/a.c")).unwrap(), digest);
+        assert_eq!(
+            TransformRules::new(&[]).unwrap().digest(Path::new("a.c")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(TransformRules::new(&[rule("(unclosed", "", None)]).is_err());
+        assert!(TransformRules::new(&[rule(r"\w{1000}{1000}", "", None)]).is_err());
+        let rules: Vec<PreprocessTransform> =
+            (0..=MAX_RULES).map(|_| rule("a", "b", None)).collect();
+        assert!(TransformRules::new(&rules).is_err());
+        assert!(TransformRules::new(&rules[1..]).is_ok());
+    }
+}
@@ -1,12 +1,13 @@
 use local_encoding_ng::{Encoder, Encoding};
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
 use std::io;
 use std::io::{Error, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR_STR};
 use std::time::Instant;
 use std::{env, fs};
 
-use crate::cmd;
+use crate::compiler::TaskError;
 use sha2::{Digest, Sha256};
 
 pub fn hash_stream<R: Read>(reader: &mut R) -> Result<String, Error> {
@@ -15,18 +16,25 @@ pub fn hash_stream<R: Read>(reader: &mut R) -> Result<String, Error> {
     Ok(hex::encode(hasher.finalize()))
 }
 
+// Response files including each other deeper than this are rejected to stop recursion.
+const RESPONSE_FILE_DEPTH: usize = 16;
+
+// Replace @file arguments with arguments read from response file, split by compiler rules.
 pub fn expand_response_files(
     base: &Option<PathBuf>,
     args: &[String],
+    parse: fn(&str) -> crate::Result<Vec<String>>,
 ) -> crate::Result<Vec<String>> {
     let mut result = Vec::<String>::new();
-    expand_response_files_r(base, args, &mut result)?;
+    expand_response_files_r(base, args, parse, 0, &mut result)?;
     Ok(result)
 }
 
-pub fn expand_response_files_r(
+fn expand_response_files_r(
     base: &Option<PathBuf>,
     args: &[String],
+    parse: fn(&str) -> crate::Result<Vec<String>>,
+    depth: usize,
     into: &mut Vec<String>,
 ) -> crate::Result<()> {
     for item in args {
@@ -49,9 +57,23 @@ pub fn expand_response_files_r(
             Some(p) => p.join(&item[1..]),
             None => PathBuf::from(&item[1..]),
         };
-        let data = fs::read(path)?;
-        let text = decode_string(&data)?;
-        expand_response_files_r(base, &cmd::native::parse(&text)?, into)?;
+        if depth >= RESPONSE_FILE_DEPTH {
+            return Err(TaskError::ResponseFileError {
+                path,
+                cause: Box::new(crate::Error::from(format!(
+                    "response files are nested deeper than {RESPONSE_FILE_DEPTH} levels"
+                ))),
+            }
+            .into());
+        }
+        let text = fs::read(&path)
+            .map_err(crate::Error::from)
+            .and_then(|data| decode_string(&data))
+            .map_err(|e| TaskError::ResponseFileError {
+                path,
+                cause: Box::new(e),
+            })?;
+        expand_response_files_r(base, &parse(&text)?, parse, depth + 1, into)?;
     }
 
     Ok(())
@@ -64,6 +86,9 @@ fn decode_string(data: &[u8]) -> crate::Result<String> {
         Ok(decode_utf16(&data[2..], |a, b| (a << 8) + b)?)
     } else if data.starts_with(&[0xFF, 0xFE]) {
         Ok(decode_utf16(&data[2..], |a, b| (b << 8) + a)?)
+    } else if data.len() % 2 == 0 && data.get(1) == Some(&0) && data[0] != 0 {
+        // UTF-16LE without BOM, response file starts with ASCII character.
+        Ok(decode_utf16(data, |a, b| (b << 8) + a)?)
     } else {
         Ok(Encoding::ANSI.to_string(data)?)
     }
@@ -83,27 +108,43 @@ fn decode_utf16<F: Fn(u16, u16) -> u16>(data: &[u8], endian: F) -> crate::Result
 }
 
 pub fn init_logger() {
+    init_logger_with(
+        fern::Dispatch::new()
+            .format(format_log_record)
+            .chain(io::stdout()),
+    );
+}
+
+// Log to the file next to executable and to given output.
+pub fn init_logger_with(output: fern::Dispatch) {
     let log_file = env::current_exe().unwrap().with_extension("log");
 
-    // Create a basic logger configuration
     fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{:?} [{}] {}",
-                Instant::now(),
-                record.level(),
-                message
-            ));
-        })
-        // Output to stdout and the log file in the temporary directory we made above to test
-        .chain(io::stdout())
-        .chain(fern::log_file(log_file).unwrap())
+        .chain(
+            fern::Dispatch::new()
+                .format(format_log_record)
+                .chain(fern::log_file(log_file).unwrap()),
+        )
+        .chain(output)
         // Only log messages Info and above
         .level(log::LevelFilter::Info)
         .apply()
         .expect("Failed to initialize logging");
 }
 
+pub fn format_log_record(
+    out: fern::FormatCallback,
+    message: &std::fmt::Arguments,
+    record: &log::Record,
+) {
+    out.finish(format_args!(
+        "{:?} [{}] {}",
+        Instant::now(),
+        record.level(),
+        message
+    ));
+}
+
 pub enum ParamValue<T> {
     None,
     Single(T),
@@ -121,6 +162,40 @@ impl OsStrExt for OsString {
     }
 }
 
+// Make control characters visible, so text from task files can't break log and console lines.
+#[must_use]
+pub fn escape_control(text: &str) -> Cow<str> {
+    if !text.chars().any(char::is_control) {
+        return Cow::Borrowed(text);
+    }
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            result.extend(c.escape_debug());
+        } else {
+            result.push(c);
+        }
+    }
+    Cow::Owned(result)
+}
+
+// Path from cl command line. cl accepts both `/` and `\` separators, so they are unified to
+// native one. Argument itself keeps original spelling for commands run with it.
+#[must_use]
+pub fn cl_path(value: &str) -> PathBuf {
+    PathBuf::from(value.replace(['/', '\\'], MAIN_SEPARATOR_STR))
+}
+
+// Key of path in lookup tables: Windows paths are case-insensitive and can mix separators.
+#[must_use]
+pub fn path_key(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(path.to_string_lossy().to_lowercase().replace('/', "\\"))
+    } else {
+        path.to_path_buf()
+    }
+}
+
 pub fn find_param<T, R, F: Fn(&T) -> Option<R>>(args: &[T], filter: F) -> ParamValue<R> {
     let mut found: Vec<R> = args.iter().filter_map(filter).collect();
     match found.len() {
@@ -153,9 +228,32 @@ fn test_decode_string() {
         &decode_string(b"\xFF\xFEt\x00e\x00s\x00t\x00 \x00\x40\x04\x43\x04\x41\x04").unwrap(),
         "test рус"
     );
+    // UTF-16LE without BOM
+    assert_eq!(&decode_string(b"/\x00c\x00 \x00\x40\x04").unwrap(), "/c р");
     // UTF-16BE
     assert_eq!(
         &decode_string(b"\xFE\xFF\x00t\x00e\x00s\x00t\x00 \x04\x40\x04\x43\x04\x41").unwrap(),
         "test рус"
     );
 }
+
+#[test]
+fn test_cl_path() {
+    let native = ["build", "obj", "foo.obj"].iter().collect::<PathBuf>();
+    assert_eq!(cl_path("build/obj/foo.obj"), native);
+    assert_eq!(cl_path("build\\obj\\foo.obj"), native);
+    assert_eq!(cl_path("build/obj\\foo.obj"), native);
+    assert_eq!(
+        path_key(&cl_path("build/obj/foo.obj")),
+        path_key(&cl_path("build\\obj/foo.obj"))
+    );
+}
+
+#[test]
+fn test_escape_control() {
+    assert!(matches!(escape_control("a.cpp"), Cow::Borrowed("a.cpp")));
+    assert_eq!(
+        escape_control("/DA=\"1\n2\"\t/DB\u{1}\u{7f}"),
+        "/DA=\"1\\n2\"\\t/DB\\u{1}\\u{7f}"
+    );
+}
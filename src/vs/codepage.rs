@@ -0,0 +1,106 @@
+// cl writes diagnostics in console output codepage, like CP866 or CP1251 on Russian Windows.
+// Output is converted to UTF-8 before it is filtered and stored, so cached output replays
+// the same way on every machine.
+
+// Output in UTF-8 doesn't need conversion.
+const CP_UTF8: u32 = 65001;
+
+// Codepage of compiler output, None if output is kept as is.
+#[must_use]
+pub fn output_codepage(config: Option<u32>) -> Option<u32> {
+    config
+        .or_else(detect)
+        .filter(|codepage| *codepage != CP_UTF8)
+}
+
+#[cfg(windows)]
+fn detect() -> Option<u32> {
+    use winapi::um::consoleapi::GetConsoleOutputCP;
+    use winapi::um::winnls::GetOEMCP;
+
+    // Process without console has no output codepage, console programs fall back to OEM one.
+    match unsafe { GetConsoleOutputCP() } {
+        0 => Some(unsafe { GetOEMCP() }),
+        codepage => Some(codepage),
+    }
+}
+
+#[cfg(not(windows))]
+fn detect() -> Option<u32> {
+    None
+}
+
+// ASCII output is the same in every codepage. Output that can't be converted is kept as is.
+#[must_use]
+pub fn to_utf8(data: Vec<u8>, codepage: Option<u32>) -> Vec<u8> {
+    match codepage {
+        Some(codepage) if !data.is_ascii() => match decode(&data, codepage) {
+            Some(text) => text.into_bytes(),
+            None => data,
+        },
+        _ => data,
+    }
+}
+
+#[cfg(windows)]
+fn decode(data: &[u8], codepage: u32) -> Option<String> {
+    use std::ptr;
+    use winapi::um::stringapiset::MultiByteToWideChar;
+
+    let len = i32::try_from(data.len()).ok()?;
+    let size =
+        unsafe { MultiByteToWideChar(codepage, 0, data.as_ptr().cast(), len, ptr::null_mut(), 0) };
+    let mut wide = vec![0u16; usize::try_from(size).ok().filter(|size| *size > 0)?];
+    let written = unsafe {
+        MultiByteToWideChar(
+            codepage,
+            0,
+            data.as_ptr().cast(),
+            len,
+            wide.as_mut_ptr(),
+            size,
+        )
+    };
+    wide.truncate(
+        usize::try_from(written)
+            .ok()
+            .filter(|written| *written > 0)?,
+    );
+    Some(String::from_utf16_lossy(&wide))
+}
+
+#[cfg(not(windows))]
+fn decode(_data: &[u8], _codepage: u32) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vs::codepage::{output_codepage, to_utf8};
+
+    #[test]
+    fn test_to_utf8_ascii() {
+        let ascii = b"foo.c(3): warning C4100: 'x': unreferenced formal parameter\r\n".to_vec();
+        assert_eq!(to_utf8(ascii.clone(), Some(1251)), ascii);
+        assert_eq!(to_utf8(b"\xEF\xF0".to_vec(), None), b"\xEF\xF0");
+        assert_eq!(output_codepage(Some(65001)), None);
+        assert_eq!(output_codepage(Some(866)), Some(866));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_to_utf8_cp1251() {
+        let cp1251 = b"foo.c(3): \xEF\xF0\xE5\xE4\xF3\xEF\xF0\xE5\xE6\xE4\xE5\xED\xE8\xE5 C4100: x: \
+            \xED\xE5\xE8\xF1\xEF\xEE\xEB\xFC\xE7\xF3\xE5\xEC\xFB\xE9 \xEF\xE0\xF0\xE0\xEC\xE5\xF2\xF0\r\n";
+        let expected = "foo.c(3): предупреждение C4100: x: неиспользуемый параметр\r\n";
+        assert_eq!(
+            String::from_utf8(to_utf8(cp1251.to_vec(), Some(1251))).unwrap(),
+            expected
+        );
+        // The same bytes mean different text in CP866.
+        assert_ne!(
+            String::from_utf8(to_utf8(cp1251.to_vec(), Some(866))).unwrap(),
+            expected
+        );
+    }
+}
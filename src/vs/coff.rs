@@ -0,0 +1,526 @@
+// Minimal COFF object file reader/writer: just enough to read section table and append a section.
+// Objects with unexpected layout are rejected instead of being rewritten.
+use std::ops::Range;
+
+use thiserror::Error;
+
+// Section contains comments or other information.
+pub const IMAGE_SCN_LNK_INFO: u32 = 0x0000_0200;
+// Section will not become part of the image.
+pub const IMAGE_SCN_LNK_REMOVE: u32 = 0x0000_0800;
+pub const IMAGE_SCN_ALIGN_1BYTES: u32 = 0x0010_0000;
+// Section contains extended relocations.
+const IMAGE_SCN_LNK_NRELOC_OVFL: u32 = 0x0100_0000;
+
+const FILE_HEADER_SIZE: usize = 20;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 18;
+const RELOCATION_SIZE: usize = 10;
+
+// IMAGE_FILE_MACHINE_* values of supported object files.
+const MACHINES: [u16; 5] = [0x014c, 0x01c4, 0x8664, 0xaa64, 0xa641];
+// Machine names as used by cl target directories and banner.
+const MACHINE_NAMES: [(u16, &str); 5] = [
+    (0x014c, "x86"),
+    (0x01c4, "arm"),
+    (0x8664, "x64"),
+    (0xaa64, "arm64"),
+    (0xa641, "arm64ec"),
+];
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+const IMAGE_FILE_MACHINE_ARM64EC: u16 = 0xa641;
+
+// Anonymous object header starts with IMAGE_FILE_MACHINE_UNKNOWN and 0xFFFF.
+const ANON_OBJECT_SIGNATURE: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+// ClassID of `/bigobj` object header {D1BAA1C7-BAEE-4BA9-AF20-FAF66AA4DCB8}.
+const BIGOBJ_CLASS_ID: [u8; 16] = [
+    0xc7, 0xa1, 0xba, 0xd1, 0xee, 0xba, 0xa9, 0x4b, 0xaf, 0x20, 0xfa, 0xf6, 0x6a, 0xa4, 0xdc, 0xb8,
+];
+// Length of object file prefix needed by `object_machine`.
+pub const MACHINE_HEADER_SIZE: usize = 28;
+
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum CoffError {
+    #[error("not a COFF object file")]
+    NotObject,
+    #[error("unsupported COFF object layout: {0}")]
+    Unsupported(&'static str),
+    #[error("COFF {0} is out of file bounds")]
+    OutOfBounds(&'static str),
+    #[error("invalid COFF section name: {0}")]
+    InvalidName(String),
+    #[error("COFF section {0} already exists")]
+    SectionExists(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub characteristics: u32,
+    // Offset of header in file.
+    header: usize,
+    raw_data: Range<usize>,
+    relocations: usize,
+}
+
+impl Section {
+    #[must_use]
+    pub fn has_flags(&self, flags: u32) -> bool {
+        self.characteristics & flags == flags
+    }
+}
+
+pub struct CoffObject<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// Target machine from object file header. Anonymous objects other than `/bigobj` ones,
+// like `/GL` intermediate code, have no target machine.
+pub fn object_machine(header: &[u8]) -> Result<Option<u16>, CoffError> {
+    if header.len() < ANON_OBJECT_SIGNATURE.len() {
+        return Err(CoffError::NotObject);
+    }
+    if header[..4] != ANON_OBJECT_SIGNATURE {
+        let machine = read_u16(header, 0);
+        return if MACHINES.contains(&machine) {
+            Ok(Some(machine))
+        } else {
+            Err(CoffError::NotObject)
+        };
+    }
+    if header.len() < MACHINE_HEADER_SIZE
+        || read_u16(header, 4) < 2
+        || header[12..28] != BIGOBJ_CLASS_ID
+    {
+        return Ok(None);
+    }
+    Ok(Some(read_u16(header, 6)))
+}
+
+#[must_use]
+pub fn machine_by_name(name: &str) -> Option<u16> {
+    MACHINE_NAMES
+        .iter()
+        .find(|(_, known)| known.eq_ignore_ascii_case(name))
+        .map(|(machine, _)| *machine)
+}
+
+#[must_use]
+pub fn machine_name(machine: u16) -> String {
+    MACHINE_NAMES
+        .iter()
+        .find(|(known, _)| *known == machine)
+        .map_or_else(
+            || format!("0x{machine:04x}"),
+            |(_, name)| (*name).to_string(),
+        )
+}
+
+// ARM64 compiler also produces ARM64EC objects with `/arm64EC`.
+#[must_use]
+pub fn is_machine_compatible(expected: u16, actual: u16) -> bool {
+    expected == actual
+        || (expected == IMAGE_FILE_MACHINE_ARM64 && actual == IMAGE_FILE_MACHINE_ARM64EC)
+}
+
+// Check that range of file is after headers and inside file.
+fn check_range(
+    data: &[u8],
+    headers_end: usize,
+    offset: usize,
+    size: usize,
+    what: &'static str,
+) -> Result<Range<usize>, CoffError> {
+    if size == 0 {
+        return Ok(0..0);
+    }
+    let end = offset
+        .checked_add(size)
+        .ok_or(CoffError::OutOfBounds(what))?;
+    if offset < headers_end || end > data.len() {
+        return Err(CoffError::OutOfBounds(what));
+    }
+    Ok(offset..end)
+}
+
+impl<'a> CoffObject<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, CoffError> {
+        if data.len() < FILE_HEADER_SIZE || !MACHINES.contains(&read_u16(data, 0)) {
+            // Also rejects /bigobj and /GL objects: they start with IMAGE_FILE_MACHINE_UNKNOWN.
+            return Err(CoffError::NotObject);
+        }
+        if read_u16(data, 16) != 0 {
+            return Err(CoffError::Unsupported("optional header"));
+        }
+        let count = read_u16(data, 2) as usize;
+        let headers_end = FILE_HEADER_SIZE + count * SECTION_HEADER_SIZE;
+        if headers_end > data.len() {
+            return Err(CoffError::OutOfBounds("section table"));
+        }
+        let symbols = read_u32(data, 8) as usize;
+        let symbol_count = read_u32(data, 12) as usize;
+        if symbols != 0 {
+            let table = check_range(
+                data,
+                headers_end,
+                symbols,
+                symbol_count * SYMBOL_SIZE + 4,
+                "symbol table",
+            )?;
+            let strings = read_u32(data, table.end - 4) as usize;
+            check_range(
+                data,
+                headers_end,
+                table.end - 4,
+                strings.max(4),
+                "string table",
+            )?;
+        }
+
+        let mut sections = Vec::with_capacity(count);
+        for index in 0..count {
+            let header = FILE_HEADER_SIZE + index * SECTION_HEADER_SIZE;
+            let characteristics = read_u32(data, header + 36);
+            if characteristics & IMAGE_SCN_LNK_NRELOC_OVFL != 0 {
+                return Err(CoffError::Unsupported("extended relocations"));
+            }
+            if read_u32(data, header + 28) != 0 {
+                return Err(CoffError::Unsupported("line numbers"));
+            }
+            let raw_size = read_u32(data, header + 16) as usize;
+            let raw_pointer = read_u32(data, header + 20) as usize;
+            // Uninitialized data has size but no content in file.
+            let raw_data = if raw_pointer == 0 {
+                0..0
+            } else {
+                check_range(data, headers_end, raw_pointer, raw_size, "section data")?
+            };
+            let relocations = read_u32(data, header + 24) as usize;
+            let relocation_count = read_u16(data, header + 32) as usize;
+            if relocations != 0 {
+                check_range(
+                    data,
+                    headers_end,
+                    relocations,
+                    relocation_count * RELOCATION_SIZE,
+                    "relocations",
+                )?;
+            }
+            sections.push(Section {
+                name: section_name(data, symbols, symbol_count, header)?,
+                characteristics,
+                header,
+                raw_data,
+                relocations,
+            });
+        }
+        Ok(CoffObject { data, sections })
+    }
+
+    #[must_use]
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    #[must_use]
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    #[must_use]
+    pub fn section_data(&self, section: &Section) -> &'a [u8] {
+        &self.data[section.raw_data.clone()]
+    }
+
+    // Create copy of object with section added or replaced.
+    // Only sections written by this function can be replaced: they are always last in file.
+    pub fn with_section(
+        &self,
+        name: &str,
+        characteristics: u32,
+        content: &[u8],
+    ) -> Result<Vec<u8>, CoffError> {
+        if name.is_empty() || name.len() > 8 || name.starts_with('/') {
+            return Err(CoffError::InvalidName(name.to_string()));
+        }
+        if let Some(section) = self.section(name) {
+            return self.replace_section(section, characteristics, content);
+        }
+
+        // Section header is inserted right after section table, so everything after it is shifted.
+        let count = self.sections.len();
+        if count >= 0xFFFE {
+            return Err(CoffError::Unsupported("too many sections"));
+        }
+        let headers_end = FILE_HEADER_SIZE + count * SECTION_HEADER_SIZE;
+        let shift = |data: &mut [u8], offset: usize| {
+            let value = read_u32(data, offset);
+            if value != 0 {
+                write_u32(data, offset, value + SECTION_HEADER_SIZE as u32);
+            }
+        };
+        let size = self.data.len() + SECTION_HEADER_SIZE + content.len();
+        if u32::try_from(size).is_err() {
+            return Err(CoffError::Unsupported("file too large"));
+        }
+
+        let mut result = Vec::with_capacity(size);
+        result.extend_from_slice(&self.data[..headers_end]);
+        result[2..4].copy_from_slice(&(count as u16 + 1).to_le_bytes());
+        shift(&mut result, 8);
+        for section in &self.sections {
+            shift(&mut result, section.header + 20);
+            shift(&mut result, section.header + 24);
+        }
+
+        let mut header = [0; SECTION_HEADER_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        write_u32(&mut header, 16, content.len() as u32);
+        write_u32(
+            &mut header,
+            20,
+            (self.data.len() + SECTION_HEADER_SIZE) as u32,
+        );
+        write_u32(&mut header, 36, characteristics);
+        result.extend_from_slice(&header);
+        result.extend_from_slice(&self.data[headers_end..]);
+        result.extend_from_slice(content);
+        Ok(result)
+    }
+
+    fn replace_section(
+        &self,
+        section: &Section,
+        characteristics: u32,
+        content: &[u8],
+    ) -> Result<Vec<u8>, CoffError> {
+        let is_last = self
+            .sections
+            .last()
+            .is_some_and(|last| last.header == section.header);
+        if !is_last
+            || section.raw_data.end != self.data.len()
+            || section.raw_data.is_empty()
+            || section.relocations != 0
+        {
+            return Err(CoffError::SectionExists(section.name.clone()));
+        }
+        let mut result = Vec::with_capacity(section.raw_data.start + content.len());
+        result.extend_from_slice(&self.data[..section.raw_data.start]);
+        write_u32(&mut result, section.header + 16, content.len() as u32);
+        write_u32(&mut result, section.header + 36, characteristics);
+        result.extend_from_slice(content);
+        Ok(result)
+    }
+}
+
+// Long section names are stored as `/<offset>` reference to string table.
+fn section_name(
+    data: &[u8],
+    symbols: usize,
+    symbol_count: usize,
+    header: usize,
+) -> Result<String, CoffError> {
+    let raw = &data[header..header + 8];
+    let raw = &raw[..raw.iter().position(|c| *c == 0).unwrap_or(raw.len())];
+    let name = String::from_utf8_lossy(raw).into_owned();
+    let Some(offset) = name.strip_prefix('/') else {
+        return Ok(name);
+    };
+    let offset: usize = offset
+        .parse()
+        .map_err(|_| CoffError::InvalidName(name.clone()))?;
+    if symbols == 0 {
+        return Err(CoffError::OutOfBounds("section name"));
+    }
+    let strings = symbols + symbol_count * SYMBOL_SIZE;
+    let start = strings + offset;
+    let end = strings + read_u32(data, strings) as usize;
+    if offset < 4 || start >= end {
+        return Err(CoffError::OutOfBounds("section name"));
+    }
+    let value = &data[start..end];
+    let value = &value[..value.iter().position(|c| *c == 0).unwrap_or(value.len())];
+    Ok(String::from_utf8_lossy(value).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::vs::coff::{
+        is_machine_compatible, machine_by_name, machine_name, object_machine, CoffError,
+        CoffObject, BIGOBJ_CLASS_ID, IMAGE_SCN_ALIGN_1BYTES, IMAGE_SCN_LNK_INFO,
+        IMAGE_SCN_LNK_REMOVE,
+    };
+
+    // Build object with `.text` section, one relocation, one symbol and long section name.
+    fn sample() -> Vec<u8> {
+        let text = b"\xC3\x90\x90\x90";
+        let mut data = Vec::new();
+        // File header: AMD64, 2 sections, symbol table at 108 with one symbol.
+        data.extend_from_slice(&0x8664u16.to_le_bytes());
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&114u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        // .text: data at 100, relocation at 104.
+        data.extend_from_slice(b".text\0\0\0");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        data.extend_from_slice(&100u32.to_le_bytes());
+        data.extend_from_slice(&104u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        data.extend_from_slice(&0x6030_0020u32.to_le_bytes());
+        // .bss with long name from string table.
+        data.extend_from_slice(b"/4\0\0\0\0\0\0");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&0xC030_0080u32.to_le_bytes());
+        assert_eq!(data.len(), 100);
+        data.extend_from_slice(text);
+        // Relocation.
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 4, 0]);
+        assert_eq!(data.len(), 114);
+        // Symbol.
+        data.extend_from_slice(b"main\0\0\0\0");
+        data.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x20, 0, 2, 0]);
+        // String table.
+        data.extend_from_slice(&19u32.to_le_bytes());
+        data.extend_from_slice(b".bss$long_name\0");
+        data
+    }
+
+    const FLAGS: u32 = IMAGE_SCN_LNK_INFO | IMAGE_SCN_LNK_REMOVE | IMAGE_SCN_ALIGN_1BYTES;
+
+    #[test]
+    fn test_parse() {
+        let data = sample();
+        let object = CoffObject::parse(&data).unwrap();
+        let names: Vec<&str> = object.sections().iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, [".text", ".bss$long_name"]);
+        let text = object.section(".text").unwrap();
+        assert_eq!(object.section_data(text), b"\xC3\x90\x90\x90");
+        assert!(object
+            .section_data(object.section(".bss$long_name").unwrap())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_append_section() {
+        let data = sample();
+        let result = CoffObject::parse(&data)
+            .unwrap()
+            .with_section(".octobld", FLAGS, b"stamp")
+            .unwrap();
+        let object = CoffObject::parse(&result).unwrap();
+        assert_eq!(object.sections().len(), 3);
+        // Existing content is still reachable after shift.
+        let text = object.section(".text").unwrap();
+        assert_eq!(object.section_data(text), b"\xC3\x90\x90\x90");
+        assert!(object.section(".bss$long_name").is_some());
+        let stamp = object.section(".octobld").unwrap();
+        assert!(stamp.has_flags(IMAGE_SCN_LNK_REMOVE));
+        assert_eq!(object.section_data(stamp), b"stamp");
+        assert_eq!(&result[154..172], &data[114..132]);
+
+        // Section is replaced in place.
+        let result = object.with_section(".octobld", FLAGS, b"other").unwrap();
+        let object = CoffObject::parse(&result).unwrap();
+        assert_eq!(object.sections().len(), 3);
+        assert_eq!(
+            object.section_data(object.section(".octobld").unwrap()),
+            b"other"
+        );
+        assert_eq!(result.len(), data.len() + 40 + 5);
+    }
+
+    #[test]
+    fn test_reject() {
+        let data = sample();
+        // Not an object file.
+        assert!(matches!(
+            CoffObject::parse(b"FAKEOBJ 0123"),
+            Err(CoffError::NotObject)
+        ));
+        // Truncated string table.
+        assert!(matches!(
+            CoffObject::parse(&data[..data.len() - 1]),
+            Err(CoffError::OutOfBounds(_))
+        ));
+        // Section data points inside section table.
+        let mut broken = data.clone();
+        broken[20 + 20] = 50;
+        assert!(matches!(
+            CoffObject::parse(&broken),
+            Err(CoffError::OutOfBounds(_))
+        ));
+        // Existing section which is not last can't be replaced.
+        let object = CoffObject::parse(&data).unwrap();
+        assert_eq!(
+            object.with_section(".text", FLAGS, b"x").err(),
+            Some(CoffError::SectionExists(".text".to_string()))
+        );
+        assert!(matches!(
+            object.with_section(".too_long_name", FLAGS, b"x"),
+            Err(CoffError::InvalidName(_))
+        ));
+    }
+
+    // Anonymous object header: signature, version, machine, timestamp and ClassID.
+    fn anonymous(version: u16, machine: u16, class_id: [u8; 16]) -> Vec<u8> {
+        let mut data = vec![0x00, 0x00, 0xff, 0xff];
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&machine.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&class_id);
+        data.extend_from_slice(&[0; 16]);
+        data
+    }
+
+    #[test]
+    fn test_object_machine() {
+        let mut data = sample();
+        assert_eq!(object_machine(&data), Ok(Some(0x8664)));
+        data[..2].copy_from_slice(&0x014cu16.to_le_bytes());
+        assert_eq!(object_machine(&data[..20]), Ok(Some(0x014c)));
+        data[..2].copy_from_slice(&0xaa64u16.to_le_bytes());
+        assert_eq!(object_machine(&data), Ok(Some(0xaa64)));
+        // `/bigobj` object.
+        assert_eq!(
+            object_machine(&anonymous(2, 0x8664, BIGOBJ_CLASS_ID)),
+            Ok(Some(0x8664))
+        );
+        // `/GL` object with intermediate code.
+        assert_eq!(object_machine(&anonymous(1, 0x014c, [0x38; 16])), Ok(None));
+        assert_eq!(object_machine(&anonymous(2, 0, [0x38; 16])), Ok(None));
+        assert_eq!(object_machine(b"FAKEOBJ 0123"), Err(CoffError::NotObject));
+        assert_eq!(object_machine(b"\x4c"), Err(CoffError::NotObject));
+    }
+
+    #[test]
+    fn test_machine_names() {
+        assert_eq!(machine_by_name("x64"), Some(0x8664));
+        assert_eq!(machine_by_name("ARM64"), Some(0xaa64));
+        assert_eq!(machine_by_name("ia64"), None);
+        assert_eq!(machine_name(0x014c), "x86");
+        assert_eq!(machine_name(0x0200), "0x0200");
+        assert!(is_machine_compatible(0xaa64, 0xa641));
+        assert!(!is_machine_compatible(0x8664, 0x014c));
+    }
+}
@@ -1,53 +1,323 @@
-use crate::cmd;
 use crate::compiler::CompileInput::{Preprocessed, Source};
 use crate::compiler::{
-    Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, OsCommandArgs,
-    OutputInfo, PCHUsage, ParamForm, PreprocessResult, Scope, SharedState, Toolchain,
-    ToolchainHolder,
+    Arg, CommandInfo, CompilationTask, CompileStep, Compiler, CompilerOutput, Hasher,
+    OsCommandArgs, OutputInfo, PCHArgs, PCHUsage, ParamForm, PreprocessResult, Scope, SharedState,
+    TaskStatus, Toolchain, ToolchainHolder,
 };
+use crate::config::Config;
+use crate::diagnostics::parse_diagnostics;
+use crate::includes::{include_dir_args, is_empty_unit};
 use crate::io::memstream::MemStream;
+use crate::io::statcache::FileHasher;
+use crate::io::taskoutput::TaskOutput;
 use crate::io::tempfile::TempFile;
 use crate::lazy::Lazy;
+use crate::pchreuse::Reuse;
+use crate::shim::ToolchainPath;
 use crate::utils::OsStrExt;
+use crate::vs::codepage::to_utf8;
+use crate::vs::coff;
 use crate::vs::postprocess;
-use cmd::native::quote;
 use regex::bytes::{NoExpand, Regex};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 use std::sync::{Arc, OnceLock};
 use std::{env, fs};
 
+// Arguments that can hang or write local-only files on remote builder:
+// Windows Error Reporting, ETW timing session and /analyze log paths.
+const REMOTE_STRIP_PREFIXES: [&str; 3] = ["/errorReport", "/Bt", "/analyze:log"];
+
+// Fatal error C1128: number of sections exceeded object file format limit.
+const SECTION_OVERFLOW_CODE: &str = "C1128";
+const BIGOBJ_FLAG: &str = "/bigobj";
+
+// Sporadic failures that usually pass on the next run: C1001 internal compiler error,
+// C1060 compiler is out of heap space and C1076 compiler limit: internal heap limit reached.
+const INTERNAL_ERROR_CODES: [&str; 3] = ["C1001", "C1060", "C1076"];
+
+// Prefix of `/showIncludes` lines printed by English cl.
+const SHOW_INCLUDES_PREFIX: &str = "Note: including file:";
+const SHOW_INCLUDES_PROBE: &str = "octobuild_probe.h";
+
 #[derive(Default)]
 pub struct VsCompiler {
     toolchains: ToolchainHolder,
+    embed_debug_info: bool,
+}
+
+impl VsCompiler {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        VsCompiler {
+            toolchains: ToolchainHolder::new(),
+            embed_debug_info: config.embed_debug_info,
+        }
+    }
 }
 
 struct VsToolchain {
-    path: PathBuf,
+    path: ToolchainPath,
+    // clang-cl accepts cl arguments, but is identified and preprocesses like clang.
+    clang_cl: bool,
+    // Compile /Zi and /ZI tasks with /Z7 instead of running them without cache.
+    embed_debug_info: bool,
     identifier: Lazy<Option<String>>,
+    openmp_runtime: Lazy<Option<String>>,
+    show_includes_prefix: Lazy<Option<String>>,
 }
 
 impl VsToolchain {
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(path: ToolchainPath) -> Self {
         VsToolchain {
+            clang_cl: is_clang_cl(&path.spawn),
+            embed_debug_info: false,
             path,
             identifier: Lazy::default(),
+            openmp_runtime: Lazy::default(),
+            show_includes_prefix: Lazy::default(),
+        }
+    }
+
+    // Key of reusable preprocessor output: everything before precompiled header marker is
+    // represented by precompiled header file, files after it are checked on lookup.
+    fn reuse_key(
+        &self,
+        state: &SharedState,
+        task: &CompilationTask,
+        args: &[OsString],
+        pch: &PCHArgs,
+    ) -> crate::Result<Option<String>> {
+        // Dependency file is written by preprocessor, so it has to run.
+        if !state.pch_reuse.is_enabled()
+            || task.shared.run_second_cpp
+            || task.shared.deps_file.is_some()
+            || !pch.path_abs.exists()
+        {
+            return Ok(None);
+        }
+        let mut hasher = Sha256::new();
+        // Toolchain without version info is left out of the key, like in compilation key.
+        if let Some(identifier) = self.identifier() {
+            hasher.hash_str(&identifier);
+        }
+        if let Some(digest) = state.phases.digest(&self.path.identity, &state.files)? {
+            hasher.hash_str(&digest);
+        }
+        hasher.hash_str(&state.files.file_hash(&pch.path_abs)?.hash);
+        hasher.hash_os_string(pch.marker.as_deref().unwrap_or_default());
+        hasher.hash_u64(args.len() as u64);
+        for arg in args {
+            hasher.hash_os_string(arg);
+        }
+        // cl reads additional options and include directories from environment.
+        for name in ["CL", "_CL_", "INCLUDE"] {
+            hasher.hash_str(task.shared.command.env.get(name).unwrap_or_default());
+        }
+        Ok(Some(hex::encode(hasher.finalize())))
+    }
+
+    // Objects compiled with /openmp:llvm are linked with LLVM OpenMP runtime bundled with
+    // the compiler and mislink with objects built against other runtime version.
+    fn with_openmp_runtime(&self, identifier: String, task: &CompilationTask) -> String {
+        let llvm_openmp = task.shared.args.iter().any(|arg| match arg {
+            Arg::Flag { name, .. } => name == "openmp:llvm" || name == "openmp:experimental",
+            _ => false,
+        });
+        if !llvm_openmp {
+            return identifier;
+        }
+        match self
+            .openmp_runtime
+            .get(|| probe_openmp_runtime(&self.path.identity))
+        {
+            Some(runtime) => format!("{identifier} {runtime}"),
+            None => identifier,
+        }
+    }
+
+    fn preprocess_args(&self, task: &CompilationTask) -> crate::Result<Vec<OsString>> {
+        let mut args = vec![
+            OsString::from("/nologo"),
+            OsString::from("/T".to_string()).concat(&task.language),
+            OsString::from("/E"),
+        ];
+        if self.clang_cl {
+            // clang-cl ignores /we4002. Like with clang toolchain, only includes are expanded.
+            args.push(OsString::from("/clang:-frewrite-includes"));
+        } else {
+            args.push(OsString::from("/we4002")); // C4002: too many actual parameters for macro 'identifier'
+        }
+        args.push(OsString::from("/Fo").concat(&task.output_object)); // /Fo option also set output path for #import directive
+        args.push(OsString::from(&task.input_source));
+        collect_args(
+            &task.shared.args,
+            Scope::Preprocessor,
+            false,
+            false,
+            &mut args,
+        );
+        Ok(args)
+    }
+
+    // Compilers for different host and target architectures live in different directories,
+    // but can have the same version and produce incompatible objects.
+    fn with_architecture(&self, identifier: String) -> String {
+        let (host, path_target) = path_architecture(&self.path.identity);
+        let target = probe_target(&self.path).or(path_target);
+        let mut identifier = identifier;
+        if let Some(host) = host {
+            identifier = format!("{identifier} host {host}");
         }
+        if let Some(target) = target {
+            identifier = format!("{identifier} target {target}");
+        }
+        identifier
     }
 }
 
+fn is_clang_cl(program: &Path) -> bool {
+    program
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("clang-cl"))
+}
+
+// `clang-cl --version` prints version and target triple:
+// `clang version 17.0.3` and `Target: x86_64-pc-windows-msvc`.
+fn parse_clang_cl_version(stdout: &str) -> Option<String> {
+    let version = stdout
+        .lines()
+        .find_map(|line| line.split_once("clang version "))
+        .and_then(|(_, rest)| rest.split_whitespace().next())?;
+    let target = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Target:"))
+        .map(str::trim)
+        .filter(|target| !target.is_empty())?;
+    Some(format!("{version} {target}"))
+}
+
+// Host and target architecture from `HostX64/x86/cl.exe` layout of Visual Studio 2017+.
+fn path_architecture(path: &Path) -> (Option<String>, Option<String>) {
+    let target = path.parent();
+    let host = target
+        .and_then(Path::parent)
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+        .and_then(|name| name.strip_prefix("host").map(ToString::to_string));
+    let target = host
+        .as_ref()
+        .and(target)
+        .and_then(Path::file_name)
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase);
+    (host, target)
+}
+
+// cl without arguments prints banner to stderr:
+// `Microsoft (R) C/C++ Optimizing Compiler Version 19.38.33133 for x64`.
+fn probe_target(path: &ToolchainPath) -> Option<String> {
+    let run = |program: &Path| -> Option<String> {
+        let output = Command::new(program).output().ok()?;
+        parse_banner_target(&String::from_utf8_lossy(&output.stderr))
+    };
+    run(&path.spawn).or_else(|| {
+        if path.identity == path.spawn {
+            None
+        } else {
+            run(&path.identity)
+        }
+    })
+}
+
+fn identifier_machine(identifier: &str) -> Option<u16> {
+    let (_, target) = identifier.rsplit_once(" target ")?;
+    coff::machine_by_name(target)
+}
+
+fn parse_banner_target(banner: &str) -> Option<String> {
+    let line = banner.lines().find(|line| !line.trim().is_empty())?;
+    let (_, target) = line.trim_end().rsplit_once(" for ")?;
+    (!target.is_empty() && !target.contains(' ')).then(|| target.to_lowercase())
+}
+
+// Find LLVM OpenMP runtime next to compiler executable and identify it by name and content.
+fn probe_openmp_runtime(path: &Path) -> Option<String> {
+    let mut names: Vec<String> = fs::read_dir(path.parent()?)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            let name = name.to_lowercase();
+            name.starts_with("libomp") && name.ends_with(".dll")
+        })
+        .collect();
+    names.sort();
+    let name = names.into_iter().next()?;
+    let data = fs::read(path.with_file_name(&name)).ok()?;
+    Some(format!(
+        "{name} {}",
+        &hex::encode(Sha256::digest(data))[..16]
+    ))
+}
+
+// cl prints `/showIncludes` lines with localized prefix, like `Hinweis: Einlesen der Datei:`.
+// Preprocess source that includes known header to find it.
+fn probe_show_includes_prefix(
+    mut command: Command,
+    dir: &Path,
+    codepage: Option<u32>,
+) -> Option<String> {
+    fs::write(dir.join(SHOW_INCLUDES_PROBE), "").ok()?;
+    let source = dir.join("octobuild_probe.c");
+    fs::write(&source, format!("#include \"{SHOW_INCLUDES_PROBE}\"\n")).ok()?;
+    let output = command
+        .args(["/nologo", "/showIncludes", "/E"])
+        .arg(&source)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    parse_show_includes_prefix(&String::from_utf8_lossy(&to_utf8(output.stderr, codepage)))
+}
+
+// Header path follows prefix after spaces, Windows paths can't contain `: `.
+fn parse_show_includes_prefix(stderr: &str) -> Option<String> {
+    stderr
+        .lines()
+        .filter(|line| line.trim_end().ends_with(SHOW_INCLUDES_PROBE))
+        .find_map(|line| line.rsplit_once(": "))
+        .map(|(prefix, _)| format!("{}:", prefix.trim_end()))
+}
+
+// cl prints `/showIncludes` lines to stderr among preprocessor warnings.
+fn parse_show_includes(stderr: &[u8], prefix: &str) -> Vec<String> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .filter(|line| line.starts_with(prefix))
+        .map(ToString::to_string)
+        .collect()
+}
+
 impl Compiler for VsCompiler {
     fn resolve_toolchain(&self, command: &CommandInfo) -> Option<Arc<dyn Toolchain>> {
         let filename_lowercase = command.program.file_name()?.to_str()?.to_lowercase();
-        if filename_lowercase != "cl.exe" && filename_lowercase != "cl" {
+        if filename_lowercase != "cl.exe"
+            && filename_lowercase != "cl"
+            && !is_clang_cl(&command.program)
+        {
             return None;
         }
         let executable = command.find_executable()?;
-        self.toolchains
-            .resolve(&executable, |path| Arc::new(VsToolchain::new(path)))
+        self.toolchains.resolve(&executable, |path| {
+            Arc::new(VsToolchain {
+                embed_debug_info: self.embed_debug_info,
+                ..VsToolchain::new(path)
+            })
+        })
     }
 
     #[cfg(unix)]
@@ -97,7 +367,12 @@ impl Compiler for VsCompiler {
             .map(|path| -> Vec<PathBuf> { CL_BIN.iter().map(|bin| path.join(bin)).collect() })
             .flat_map(|paths| paths.into_iter())
             .filter(|cl| cl.exists())
-            .map(|cl| -> Arc<dyn Toolchain> { Arc::new(VsToolchain::new(cl)) })
+            .map(|cl| -> Arc<dyn Toolchain> {
+                Arc::new(VsToolchain {
+                    embed_debug_info: self.embed_debug_info,
+                    ..VsToolchain::new(ToolchainPath::resolve(cl))
+                })
+            })
             .filter(|toolchain| toolchain.identifier().is_some())
             .collect()
     }
@@ -105,19 +380,33 @@ impl Compiler for VsCompiler {
 
 fn run_postprocess(
     output: Output,
+    includes: Vec<String>,
     path: &Path,
     marker: &Option<OsString>,
     keep_headers: bool,
 ) -> crate::Result<PreprocessResult> {
+    // Source under `#if 0` has no precompiled header include to look for.
+    if is_empty_unit(&output.stdout) {
+        return Ok(PreprocessResult::Success(
+            CompilerOutput::Vec(output.stdout),
+            includes,
+        ));
+    }
     let mut content = MemStream::new();
-    postprocess::filter_preprocessed(
+    let warnings = postprocess::filter_preprocessed(
         &mut Cursor::new(output.stdout),
         &mut content,
         marker,
         keep_headers,
     )
     .map_err(|e| crate::Error::postprocess(path, e))?;
-    Ok(PreprocessResult::Success(CompilerOutput::MemSteam(content)))
+    for warning in warnings {
+        log::warn!("{}: {warning}", path.display());
+    }
+    Ok(PreprocessResult::Success(
+        CompilerOutput::MemSteam(content),
+        includes,
+    ))
 }
 fn collect_args(
     args: &[Arg],
@@ -125,7 +414,7 @@ fn collect_args(
     run_second_cpp: bool,
     output_precompiled: bool,
     into: &mut Vec<OsString>,
-) -> crate::Result<()> {
+) {
     if output_precompiled {
         into.push(OsString::from("/Yc"));
     }
@@ -152,18 +441,17 @@ fn collect_args(
                     match form {
                         ParamForm::Separate => {
                             into.push(OsString::from(prefix).concat(flag));
-                            // TODO: Why quote?
-                            into.push(quote(value)?);
+                            into.push(OsString::from(value));
                         }
                         ParamForm::Smushed => {
-                            into.push(OsString::from(prefix).concat(flag).concat(quote(value)?));
+                            into.push(OsString::from(prefix).concat(flag).concat(value));
                         }
                         ParamForm::Combined => {
                             into.push(
                                 OsString::from(prefix)
                                     .concat(flag)
                                     .concat("=")
-                                    .concat(quote(value)?),
+                                    .concat(value),
                             );
                         }
                     }
@@ -172,13 +460,36 @@ fn collect_args(
             Arg::Input { .. } | Arg::Output { .. } => {}
         };
     }
-
-    Ok(())
 }
 
 impl Toolchain for VsToolchain {
     fn identifier(&self) -> Option<String> {
-        self.identifier.get(|| vs_identifier(&self.path))
+        if self.clang_cl {
+            return self.identifier.get(|| {
+                self.path
+                    .probe(&["--version"], parse_clang_cl_version)
+                    .map(|version| format!("clang-cl {version}"))
+            });
+        }
+        self.identifier.get(|| {
+            vs_identifier(&self.path.identity).map(|identifier| self.with_architecture(identifier))
+        })
+    }
+
+    fn task_identifier(&self, task: &CompilationTask) -> Option<String> {
+        Some(self.with_openmp_runtime(self.identifier()?, task))
+    }
+
+    // clang-cl target depends on task arguments (`--target`, `-m32`), so only cl is checked.
+    fn target_machine(&self) -> Option<u16> {
+        if self.clang_cl {
+            return None;
+        }
+        identifier_machine(&self.identifier()?)
+    }
+
+    fn executable(&self) -> Option<&Path> {
+        Some(&self.path.identity)
     }
 
     fn create_tasks(
@@ -187,7 +498,13 @@ impl Toolchain for VsToolchain {
         args: &[String],
         run_second_cpp: bool,
     ) -> crate::Result<Vec<CompilationTask>> {
-        super::prepare::create_tasks(command, args, run_second_cpp)
+        // clang-cl keeps debug information in object file for /Zi too.
+        super::prepare::create_tasks(
+            command,
+            args,
+            run_second_cpp,
+            self.embed_debug_info || self.clang_cl,
+        )
     }
 
     fn run_preprocess(
@@ -195,55 +512,120 @@ impl Toolchain for VsToolchain {
         state: &SharedState,
         task: &CompilationTask,
     ) -> crate::Result<PreprocessResult> {
-        let mut args = vec![
-            OsString::from("/nologo"),
-            OsString::from("/T".to_string()).concat(&task.language),
-            OsString::from("/E"),
-            OsString::from("/we4002"), // C4002: too many actual parameters for macro 'identifier'
-            OsString::from("/Fo").concat(quote(&task.output_object)?), // /Fo option also set output path for #import directive
-            quote(&task.input_source)?,
-        ];
-        collect_args(
-            &task.shared.args,
-            Scope::Preprocessor,
-            false,
-            false,
-            &mut args,
-        )?;
+        let mut args = self.preprocess_args(task)?;
+        let show_includes = task.shared.args.iter().any(|arg| match arg {
+            Arg::Flag { name, .. } => name == "showIncludes",
+            _ => false,
+        });
+        if show_includes {
+            args.push(OsString::from("/showIncludes"));
+        }
+        // Included files are printed by preprocessor run, so it can't be skipped.
+        let reuse_key = match &task.shared.pch_usage {
+            PCHUsage::In(v) if !show_includes => self.reuse_key(state, task, &args, v)?,
+            _ => None,
+        };
+        let mut reused = None;
+        if let Some(key) = &reuse_key {
+            match state.pch_reuse.lookup(task, key, &state.files) {
+                Reuse::Hit(content) => {
+                    return Ok(PreprocessResult::Success(
+                        CompilerOutput::Vec(content),
+                        Vec::new(),
+                    ));
+                }
+                Reuse::Check(content) => reused = Some(content),
+                Reuse::Miss => {}
+            }
+        }
 
-        let mut command = task.shared.command.to_command();
+        let mut command = match state.phases.preprocessor(&self.path.identity) {
+            Some(phase) => {
+                let mut command = task.shared.command.command_for(&phase.program);
+                command.args(&phase.args);
+                command
+            }
+            None => task.shared.command.to_command(),
+        };
+        let temp = state.temp.place(Some(&task.output_object));
+        let task_temp = state.task_temp_dir(&mut command, &temp)?;
+        let show_includes_prefix = show_includes.then(|| {
+            state.show_includes_prefix.clone().unwrap_or_else(|| {
+                self.show_includes_prefix
+                    .get(|| {
+                        let probe = match state.phases.preprocessor(&self.path.identity) {
+                            Some(phase) => task.shared.command.command_for(&phase.program),
+                            None => task.shared.command.to_command(),
+                        };
+                        probe_show_includes_prefix(probe, task_temp.path(), state.output_codepage)
+                    })
+                    .unwrap_or_else(|| SHOW_INCLUDES_PREFIX.to_string())
+            })
+        });
         let response_file =
-            state.do_response_file(OsCommandArgs::Raw(args.join(" ".as_ref())), &mut command)?;
-        let output = state.wrap_slow(|| -> crate::Result<Output> {
-            let output = command.output()?;
+            state.do_response_file(OsCommandArgs::Regular(args.clone()), &mut command, &temp)?;
+        let mut output = state.wrap_slow(|| -> crate::Result<Output> {
+            let output = state.monitor.output(&mut command)?;
             drop(response_file);
+            drop(task_temp);
             Ok(output)
         })?;
+        // Preprocessed source is in stdout, only diagnostics are converted.
+        if !(state.raw_output || state.overrides.get(&task.input_source).native_diagnostics) {
+            output.stderr = to_utf8(output.stderr, state.output_codepage);
+        }
 
         if output.status.success() {
+            let includes = match &show_includes_prefix {
+                Some(prefix) => parse_show_includes(&output.stderr, prefix),
+                None => Vec::new(),
+            };
             if task.shared.run_second_cpp {
-                Ok(PreprocessResult::Success(CompilerOutput::Vec(
-                    output.stdout,
-                )))
+                Ok(PreprocessResult::Success(
+                    CompilerOutput::Vec(output.stdout),
+                    includes,
+                ))
             } else {
                 match &task.shared.pch_usage {
-                    PCHUsage::None => Ok(PreprocessResult::Success(CompilerOutput::Vec(
-                        output.stdout,
-                    ))),
+                    PCHUsage::None => Ok(PreprocessResult::Success(
+                        CompilerOutput::Vec(output.stdout),
+                        includes,
+                    )),
                     PCHUsage::In(v) => {
-                        run_postprocess(output, &task.input_source, &v.marker, false)
-                    }
-                    PCHUsage::Out(v) => {
-                        run_postprocess(output, &task.input_source, &v.marker, true)
+                        let result = run_postprocess(
+                            output,
+                            includes,
+                            &task.input_source,
+                            v.filter_marker(),
+                            false,
+                        )?;
+                        if let (Some(key), PreprocessResult::Success(preprocessed, _)) =
+                            (&reuse_key, &result)
+                        {
+                            state.pch_reuse.update(
+                                task,
+                                key,
+                                &state.files,
+                                &preprocessed.to_vec(),
+                                reused.as_deref(),
+                            );
+                        }
+                        Ok(result)
                     }
+                    PCHUsage::Out(v) => run_postprocess(
+                        output,
+                        includes,
+                        &task.input_source,
+                        v.filter_marker(),
+                        true,
+                    ),
                 }
             }
         } else {
-            Ok(PreprocessResult::Failed(OutputInfo {
-                status: output.status.code(),
-                stdout: output.stdout,
-                stderr: output.stderr,
-            }))
+            Ok(PreprocessResult::Failed(OutputInfo::new(
+                output,
+                &state.output_limits,
+            )))
         }
     }
 
@@ -263,55 +645,64 @@ impl Toolchain for VsToolchain {
             task.shared.run_second_cpp,
             task.shared.pch_usage.is_out(),
             &mut args,
-        )?;
+        );
         Ok(CompileStep::new(task, preprocessed, args))
     }
 
     fn run_compile(&self, state: &SharedState, task: CompileStep) -> crate::Result<OutputInfo> {
+        let raw_output = state.raw_output
+            || task
+                .source
+                .as_ref()
+                .is_some_and(|source| state.overrides.get(source).native_diagnostics);
+        let temp = state.temp.place(task.output_object.as_deref());
         let (output_path, temp_output) = match task.output_object {
             Some(v) => (v, None),
             None => {
                 let output_temp = tempfile::Builder::new()
                     .suffix(".o")
-                    .tempfile_in(state.temp_dir.path())?;
+                    .tempfile_in(temp.path())?;
                 (output_temp.path().to_path_buf(), Some(output_temp))
             }
         };
 
         let mut args = task.args.clone();
         args.push(OsString::from("/c"));
-        args.push(OsString::from("/Fo").concat(quote(output_path)?));
+        args.push(OsString::from("/Fo").concat(&output_path));
 
+        // Compiler may run in temporary directory, so precompiled header path must be absolute.
         match &task.pch_usage {
             PCHUsage::None => {}
             PCHUsage::In(v) => {
-                if let Some(pch_marker) = &v.marker {
-                    args.push(OsString::from("/Yu").concat(quote(pch_marker)?));
-                } else {
-                    args.push(OsString::from("/Yu"));
-                }
-                args.push(OsString::from("/Fp").concat(quote(&v.path)?));
+                // Preprocessed input has #pragma hdrstop in place of the header include.
+                args.push(OsString::from("/Yu"));
+                args.push(OsString::from("/Fp").concat(&v.path_abs));
             }
             PCHUsage::Out(v) => {
-                args.push(OsString::from("/Fp").concat(quote(&v.path)?));
+                args.push(OsString::from("/Fp").concat(&v.path_abs));
             }
         }
 
-        let (input_path, temp_input, current_dir_override) = match &task.input {
+        let (input_path, temp_input, current_dir_override, source_env) = match &task.input {
             Preprocessed(preprocessed) => {
-                let input_temp = TempFile::new_in(state.temp_dir.path(), ".i");
-                preprocessed.copy(&mut File::create(input_temp.path())?)?;
-                (input_temp.path().to_path_buf(), Some(input_temp), None)
-            }
-            Source(source) => {
-                if let Some(dir) = &source.current_dir {
-                    (source.path.clone(), None, Some(dir.as_path()))
-                } else {
-                    (source.path.clone(), None, None)
-                }
+                let input_temp = TempFile::new_in(temp.path(), ".i");
+                let written = preprocessed.write_to_file(input_temp.path())?;
+                temp.add_written(written as u64);
+                (
+                    input_temp.path().to_path_buf(),
+                    Some(input_temp),
+                    None,
+                    None,
+                )
             }
+            Source(source) => (
+                source.path.clone(),
+                None,
+                source.current_dir.as_deref(),
+                Some(&source.env),
+            ),
         };
-        args.push(quote(&input_path)?);
+        args.push(OsString::from(&input_path));
 
         // Run compiler.
 
@@ -321,43 +712,189 @@ impl Toolchain for VsToolchain {
             .and_then(OsStr::to_str)
             .map(str::as_bytes)
             .unwrap_or(b"");
+        // Diagnostics about temporary preprocessed file must point to original source.
+        let rename = match (&temp_input, &task.source) {
+            (Some(temp_input), Some(source)) => Some(Rename::new(temp_input.path(), source)),
+            _ => None,
+        };
 
         // Execute.
-        let output = state.wrap_slow(|| -> crate::Result<Output> {
-            let mut command = Command::new(&self.path);
+        let phase = state.phases.compiler(&self.path.identity);
+        let run = || -> crate::Result<Output> {
+            let mut command = Command::new(
+                phase.map_or(self.path.spawn.as_path(), |phase| phase.program.as_path()),
+            );
 
             command
                 .env_clear()
-                .current_dir(current_dir_override.unwrap_or_else(|| state.temp_dir.path()));
+                .current_dir(current_dir_override.unwrap_or_else(|| temp.path()));
 
-            // Copy required environment variables.
-            // todo: #15 Need to make correct PATH variable for cl.exe manually
-            for (name, value) in ["SystemDrive", "SystemRoot", "TEMP", "TMP", "PATH"]
-                .iter()
-                .filter_map(|name| env::var(name).ok().map(|value| (name, value)))
-            {
-                command.env(name, value);
+            match source_env {
+                // Source is compiled like original command: INCLUDE, CL and _CL_ apply.
+                Some(source_env) => {
+                    command.envs(source_env.iter());
+                }
+                // Preprocessed file doesn't depend on environment of original command.
+                // todo: #15 Need to make correct PATH variable for cl.exe manually
+                None => {
+                    for (name, value) in ["SystemDrive", "SystemRoot", "PATH"]
+                        .iter()
+                        .filter_map(|name| env::var(name).ok().map(|value| (name, value)))
+                    {
+                        command.env(name, value);
+                    }
+                }
+            }
+            if let Some(phase) = phase {
+                command.args(&phase.args);
             }
+            let task_temp = state.task_temp_dir(&mut command, &temp)?;
 
-            let response_file = state
-                .do_response_file(OsCommandArgs::Raw(args.join(" ".as_ref())), &mut command)?;
-            let output = command.output()?;
-            drop(temp_input);
+            let response_file = state.do_response_file(
+                OsCommandArgs::Regular(args.clone()),
+                &mut command,
+                &temp,
+            )?;
+            let output = state.monitor.output(&mut command)?;
             drop(response_file);
+            drop(task_temp);
+            Ok(output)
+        };
+        let output = state.wrap_slow(|| -> crate::Result<Output> {
+            // Compiler input file is kept until retries are done.
+            let mut output = run()?;
+            let mut retries = 0;
+            while retries < state.internal_error_retries
+                && (is_internal_error(output.status.code(), &output.stdout)
+                    || is_internal_error(output.status.code(), &output.stderr))
+            {
+                retries += 1;
+                output = run()?;
+            }
+            drop(temp_input);
+            if retries > 0 {
+                log::warn!(
+                    "{}: compilation retried {retries} time(s) after internal compiler error",
+                    input_path.display()
+                );
+                output.stderr.extend_from_slice(
+                    format!(
+                        "octobuild: compilation retried {retries} time(s) after internal compiler error\n"
+                    )
+                    .as_bytes(),
+                );
+            }
             Ok(output)
         })?;
 
-        let content = match temp_output {
-            Some(v) => fs::read(v.path())?,
-            None => output.stdout,
+        let status = TaskStatus::from(output.status);
+        let filter = |content: Vec<u8>| {
+            if raw_output {
+                content
+            } else {
+                prepare_output(
+                    input_marker,
+                    content,
+                    status.success(),
+                    &state.suppress_warnings,
+                    rename.as_ref(),
+                )
+            }
+        };
+        // Command line warnings, like D9025, are written to stderr.
+        let mut stderr = if raw_output {
+            output.stderr
+        } else {
+            let mut stderr = to_utf8(output.stderr, state.output_codepage);
+            if let Some(rename) = &rename {
+                stderr = rename.apply(stderr);
+            }
+            if status.success() {
+                stderr = suppress_lines(stderr, &state.suppress_warnings);
+            }
+            stderr
+        };
+        status.report_crash(&mut stderr);
+        let stdout = match temp_output {
+            // Object file for remote client must not be truncated.
+            Some(v) => TaskOutput::from(filter(fs::read(v.path())?)),
+            None => {
+                let stdout = if raw_output {
+                    output.stdout
+                } else {
+                    to_utf8(output.stdout, state.output_codepage)
+                };
+                TaskOutput::new(filter(stdout), &state.output_limits)
+            }
         };
 
         Ok(OutputInfo {
-            status: output.status.code(),
-            stdout: prepare_output(input_marker, content, output.status.success()),
-            stderr: output.stderr,
+            status,
+            stdout,
+            stderr: TaskOutput::new(stderr, &state.output_limits),
         })
     }
+
+    fn strip_on_remote(&self, arg: &OsStr) -> bool {
+        let arg = arg.to_string_lossy();
+        REMOTE_STRIP_PREFIXES
+            .iter()
+            .any(|prefix| arg.starts_with(prefix))
+    }
+
+    fn bigobj_flag(&self, args: &[OsString]) -> Option<&'static str> {
+        // Command line parser normalizes -bigobj to /bigobj.
+        (!args.iter().any(|arg| arg == BIGOBJ_FLAG)).then_some(BIGOBJ_FLAG)
+    }
+
+    fn is_section_overflow(&self, output: &OutputInfo) -> bool {
+        // cl.exe writes diagnostics to stdout.
+        let text = output.stdout.to_vec().unwrap_or_default();
+        parse_diagnostics(&text)
+            .iter()
+            .any(|diagnostic| diagnostic.key == SECTION_OVERFLOW_CODE)
+    }
+
+    fn include_dirs(&self, task: &CompilationTask) -> Option<Vec<PathBuf>> {
+        // clang-cl also has built-in headers of its resource directory.
+        if self.clang_cl {
+            return None;
+        }
+        let mut dirs = include_dir_args(task);
+        let ignore_env = task
+            .shared
+            .args
+            .iter()
+            .any(|arg| matches!(arg, Arg::Flag { name, .. } if name == "X"));
+        if !ignore_env {
+            let env = task.shared.command.env.get("INCLUDE").unwrap_or_default();
+            dirs.extend(
+                env.split(';')
+                    .filter(|dir| !dir.is_empty())
+                    .filter_map(|dir| task.shared.command.absolutize(Path::new(dir)).ok()),
+            );
+        }
+        Some(dirs)
+    }
+
+    fn warmup_args(&self, source: &Path, object: &Path) -> Option<Vec<OsString>> {
+        let mut fo = OsString::from("/Fo");
+        fo.push(object);
+        Some(vec![
+            OsString::from("/nologo"),
+            OsString::from("/c"),
+            OsString::from(source),
+            fo,
+        ])
+    }
+}
+
+// Check if compiler failed with error that is worth retrying with the same input.
+fn is_internal_error(code: Option<i32>, output: &[u8]) -> bool {
+    code != Some(0)
+        && parse_diagnostics(output)
+            .iter()
+            .any(|diagnostic| INTERNAL_ERROR_CODES.contains(&diagnostic.key.as_str()))
 }
 
 #[cfg(unix)]
@@ -459,6 +996,7 @@ fn vs_identifier(path: &Path) -> Option<String> {
 #[cfg(windows)]
 fn read_executable_id(path: &Path) -> crate::Result<String> {
     use byteorder::{LittleEndian, ReadBytesExt};
+    use std::fs::File;
     use std::io::{Read, Seek, SeekFrom};
 
     let mut header: Vec<u8> = Vec::with_capacity(0x54);
@@ -487,7 +1025,38 @@ fn read_executable_id(path: &Path) -> crate::Result<String> {
     Ok(format!("{pe_time_date_stamp:X}{pe_size_of_image:x}"))
 }
 
-fn prepare_output(line: &[u8], mut buffer: Vec<u8>, success: bool) -> Vec<u8> {
+// Replaces path of temporary compiler input with original source path.
+struct Rename {
+    from: Regex,
+    to: Vec<u8>,
+}
+
+impl Rename {
+    fn new(from: &Path, to: &Path) -> Self {
+        // Windows paths are case insensitive and cl may print them with either separator.
+        let from = regex::escape(&from.to_string_lossy()).replace(r"\\", r"[\\/]");
+        Rename {
+            from: Regex::new(&format!("(?i){from}")).unwrap(),
+            to: to.to_string_lossy().into_owned().into_bytes(),
+        }
+    }
+
+    fn apply(&self, buffer: Vec<u8>) -> Vec<u8> {
+        let replaced = match self.from.replace_all(&buffer, NoExpand(&self.to)) {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(replaced) => Some(replaced),
+        };
+        replaced.unwrap_or(buffer)
+    }
+}
+
+fn prepare_output(
+    line: &[u8],
+    mut buffer: Vec<u8>,
+    success: bool,
+    suppress: &[Regex],
+    rename: Option<&Rename>,
+) -> Vec<u8> {
     // Remove strage file name from output
     let mut begin =
         if (line.len() < buffer.len()) && buffer.starts_with(line) && is_eol(buffer[line.len()]) {
@@ -499,15 +1068,31 @@ fn prepare_output(line: &[u8], mut buffer: Vec<u8>, success: bool) -> Vec<u8> {
         begin += 1;
     }
     buffer = buffer.split_off(begin);
+    if let Some(rename) = rename {
+        buffer = rename.apply(buffer);
+    }
     if success {
         // Remove some redundant lines
-        static RE: OnceLock<Regex> = OnceLock::new();
-        buffer = RE
-            .get_or_init(|| Regex::new(r"(?m)^\S+[^:]*\(\d+\) : warning C4628: .*$\n?").unwrap())
-            .replace_all(&buffer, NoExpand(b""))
-            .to_vec();
+        buffer = suppress_lines(buffer, suppress);
+    }
+    buffer
+}
+
+// Remove lines matching any of patterns, line ending is not a part of matched text.
+fn suppress_lines(buffer: Vec<u8>, patterns: &[Regex]) -> Vec<u8> {
+    if patterns.is_empty() {
+        return buffer;
     }
     buffer
+        .split_inclusive(|c| *c == b'\n')
+        .filter(|line| {
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            !patterns.iter().any(|pattern| pattern.is_match(text))
+        })
+        .flatten()
+        .copied()
+        .collect()
 }
 
 fn is_eol(c: u8) -> bool {
@@ -516,13 +1101,515 @@ fn is_eol(c: u8) -> bool {
 
 #[cfg(test)]
 mod test {
+    use std::ffi::OsString;
     use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use regex::bytes::Regex;
+
+    use crate::cluster::client::split_remote_args;
+    use crate::cmd;
+    use crate::compiler::{CommandEnv, CommandInfo, CompilerOutput, SharedState, Toolchain};
+    use crate::config::Config;
+    use crate::shim::ToolchainPath;
+    use crate::vs::compiler::VsToolchain;
+
+    #[test]
+    fn test_strip_on_remote() {
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let args: Vec<String> =
+            "/c /TP /O2 /Bt+ /analyze /analyze:logsample.sarif /errorReport:queue sample.cpp"
+                .split(' ')
+                .map(ToString::to_string)
+                .collect();
+        let tasks = toolchain
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap();
+        let step = toolchain
+            .create_compile_step(&tasks[0], CompilerOutput::Vec(Vec::new()))
+            .unwrap();
+
+        // Local compilation keeps all flags except ignored /errorReport.
+        let local: Vec<OsString> = [
+            "/nologo",
+            "/TP",
+            "/O2",
+            "/Bt+",
+            "/analyze",
+            "/analyze:logsample.sarif",
+        ]
+        .iter()
+        .map(OsString::from)
+        .collect();
+        assert_eq!(step.args, local);
+
+        let (remote, stripped) = split_remote_args(&toolchain, &step.args);
+        assert_eq!(remote, ["/nologo", "/TP", "/O2", "/analyze"]);
+        assert_eq!(stripped, ["/Bt+", "/analyze:logsample.sarif"]);
+    }
+
+    #[test]
+    fn test_clang_cl_args() {
+        let args: Vec<String> = "/c /TP /O2 /DX=1 /Iinclude /Fosample.obj sample.cpp"
+            .split(' ')
+            .map(ToString::to_string)
+            .collect();
+        let cl = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let clang_cl = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("clang-cl.exe")));
+        assert!(!cl.clang_cl);
+        assert!(clang_cl.clang_cl);
+        let task = &cl
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap()[0];
+
+        // Preprocessor differs only in flag specific to compiler.
+        let cl_args = cl.preprocess_args(task).unwrap();
+        let clang_cl_args = clang_cl.preprocess_args(task).unwrap();
+        assert_eq!(&cl_args[..3], ["/nologo", "/TP", "/E"]);
+        assert_eq!(cl_args[3], "/we4002");
+        assert_eq!(clang_cl_args[3], "/clang:-frewrite-includes");
+        assert_eq!(cl_args[4..], clang_cl_args[4..]);
+        assert!(clang_cl_args.contains(&OsString::from("/Iinclude")));
+
+        // Preprocessed file is compiled with the same arguments.
+        let step = |toolchain: &VsToolchain| {
+            toolchain
+                .create_compile_step(task, CompilerOutput::Vec(Vec::new()))
+                .unwrap()
+                .args
+        };
+        assert_eq!(step(&cl), step(&clang_cl));
+    }
+
+    #[test]
+    fn test_clang_cl_version() {
+        assert_eq!(
+            super::parse_clang_cl_version(
+                "clang version 17.0.3\nTarget: x86_64-pc-windows-msvc\nThread model: posix\nInstalledDir: C:\\Program Files\\LLVM\\bin\n"
+            ),
+            Some("17.0.3 x86_64-pc-windows-msvc".to_string())
+        );
+        assert_eq!(
+            super::parse_clang_cl_version("clang version 16.0.0\n"),
+            None
+        );
+        assert!(super::is_clang_cl(Path::new("C:/LLVM/bin/CLANG-CL.EXE")));
+        assert!(!super::is_clang_cl(Path::new("C:/LLVM/bin/clang.exe")));
+    }
+
+    fn compile_args(args: &str, run_second_cpp: bool) -> Vec<OsString> {
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let args: Vec<String> = args.split(' ').map(ToString::to_string).collect();
+        let tasks = toolchain
+            .create_tasks(
+                CommandInfo::simple(PathBuf::from("cl.exe")),
+                &args,
+                run_second_cpp,
+            )
+            .unwrap();
+        toolchain
+            .create_compile_step(&tasks[0], CompilerOutput::Vec(Vec::new()))
+            .unwrap()
+            .args
+    }
+
+    #[test]
+    fn test_include_dirs() {
+        let cwd = std::env::current_dir().unwrap();
+        let include_dirs = |program: &str, args: &str| {
+            let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from(program)));
+            let mut env = CommandEnv::new();
+            env.insert("INCLUDE", format!("{};;sdk", cwd.join("crt").display()));
+            let command = CommandInfo {
+                env: std::sync::Arc::new(env),
+                ..CommandInfo::simple(PathBuf::from(program))
+            };
+            let args: Vec<String> = args.split(' ').map(ToString::to_string).collect();
+            let tasks = toolchain.create_tasks(command, &args, false).unwrap();
+            toolchain.include_dirs(&tasks[0])
+        };
+        // Command line directories are searched before INCLUDE variable.
+        assert_eq!(
+            include_dirs("cl.exe", "/c /Iinc /external:I ext a.cpp").unwrap(),
+            [
+                cwd.join("inc"),
+                cwd.join("ext"),
+                cwd.join("crt"),
+                cwd.join("sdk")
+            ]
+        );
+        assert_eq!(
+            include_dirs("cl.exe", "/c /X /Iinc a.cpp").unwrap(),
+            [cwd.join("inc")]
+        );
+        assert_eq!(include_dirs("clang-cl.exe", "/c /Iinc a.cpp"), None);
+    }
+
+    #[test]
+    fn test_exception_and_await_args() {
+        // Code generation flags are passed to compilation of preprocessed file.
+        assert_eq!(
+            compile_args("/c /TP /EHa /await /std:c++17 sample.cpp", false),
+            ["/nologo", "/TP", "/EHa", "/await", "/std:c++17"]
+        );
+        assert_eq!(
+            compile_args("/c /TP /await:strict /EHsc sample.cpp", true),
+            ["/nologo", "/TP", "/await:strict", "/EHsc"]
+        );
+        // Compilation arguments are part of cache key, so exception models never share entries.
+        let models: Vec<Vec<OsString>> = ["/EHs", "/EHa", "/EHsc"]
+            .iter()
+            .map(|flag| compile_args(&format!("/c /TP {flag} sample.cpp"), false))
+            .collect();
+        assert_ne!(models[0], models[1]);
+        assert_ne!(models[0], models[2]);
+        assert_ne!(models[1], models[2]);
+
+        // Exception model defines _CPPUNWIND, so preprocessor gets it too.
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let args: Vec<String> = ["/c", "/TP", "/EHa", "sample.cpp"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let tasks = toolchain
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap();
+        let preprocess = toolchain.preprocess_args(&tasks[0]).unwrap();
+        assert!(preprocess.contains(&OsString::from("/EHa")));
+    }
+
+    #[test]
+    fn test_conformance_args() {
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let flags = "/std:c++17 /Zc:__cplusplus /Zc:wchar_t- /permissive- /await /Zp8";
+        let args: Vec<String> = format!("/c /TP {flags} sample.cpp")
+            .split(' ')
+            .map(ToString::to_string)
+            .collect();
+        let tasks = toolchain
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap();
+        // Conformance options are passed to both preprocessor and compiler.
+        let preprocess = toolchain.preprocess_args(&tasks[0]).unwrap();
+        for flag in flags.split(' ') {
+            assert!(preprocess.contains(&OsString::from(flag)), "{flag}");
+        }
+        assert_eq!(
+            compile_args(&format!("/c /TP {flags} sample.cpp"), false)[2..],
+            flags.split(' ').map(OsString::from).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            compile_args("/c /TP /permissive sample.cpp", false),
+            ["/nologo", "/TP", "/permissive"]
+        );
+        // Compilation arguments are part of cache key, so standards never share entries.
+        assert_ne!(
+            compile_args("/c /TP /std:c++14 sample.cpp", false),
+            compile_args("/c /TP /std:c++17 sample.cpp", false)
+        );
+    }
+
+    #[test]
+    fn test_quoted_args() {
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(PathBuf::from("cl.exe")));
+        let args = cmd::msvc::parse(
+            r#"/c /TP /Fo"Intermediate\Build\Win64\My Game\" /D "VERSION=\"1.2\"" /I "C:\Program Files\SDK\include" a.cpp"#,
+        )
+        .unwrap();
+        let tasks = toolchain
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap();
+        assert!(tasks[0]
+            .output_object
+            .ends_with("Intermediate/Build/Win64/My Game/a.obj"));
+
+        // Values are kept unquoted, command line is quoted when it is written.
+        let preprocess = toolchain.preprocess_args(&tasks[0]).unwrap();
+        let contains = |pair: [&str; 2]| preprocess.windows(2).any(|window| window == pair);
+        assert!(contains(["/D", r#"VERSION="1.2""#]));
+        assert!(contains(["/I", r"C:\Program Files\SDK\include"]));
+        #[cfg(windows)]
+        assert_eq!(
+            cmd::msvc::parse(cmd::native::join(&preprocess).unwrap().to_str().unwrap()).unwrap(),
+            preprocess
+                .iter()
+                .map(|arg| arg.to_str().unwrap())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_parallel_codegen_args() {
+        assert_eq!(
+            compile_args("/c /TP /openmp /Qpar sample.cpp", false),
+            ["/nologo", "/TP", "/openmp", "/Qpar"]
+        );
+        assert_eq!(
+            compile_args("/c /TP /openmp:llvm sample.cpp", false),
+            ["/nologo", "/TP", "/openmp:llvm"]
+        );
+        // Every flag changes code generation, so it must change cache key.
+        let flags: Vec<Vec<OsString>> = ["", "/openmp", "/openmp:llvm", "/Qpar"]
+            .iter()
+            .map(|flag| compile_args(&format!("/c /TP {flag} sample.cpp"), false))
+            .collect();
+        for (i, a) in flags.iter().enumerate() {
+            for b in &flags[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_openmp_runtime_identifier() {
+        let temp = tempfile::tempdir().unwrap();
+        let toolchain = |dir: &str, runtime: &[u8]| {
+            let dir = temp.path().join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("cl.exe"), b"cl").unwrap();
+            std::fs::write(dir.join("libomp140.x86_64.dll"), runtime).unwrap();
+            VsToolchain::new(ToolchainPath::resolve(dir.join("cl.exe")))
+        };
+        let task = |toolchain: &VsToolchain, flag: &str| {
+            let args: Vec<String> = ["/c", "/TP", flag, "sample.cpp"]
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            toolchain
+                .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+                .unwrap()
+                .remove(0)
+        };
+        let old = toolchain("old", b"runtime 1");
+        let new = toolchain("new", b"runtime 2");
+
+        // Only LLVM OpenMP runtime is bundled with compiler.
+        let openmp = task(&old, "/openmp");
+        assert_eq!(old.with_openmp_runtime("cl".into(), &openmp), "cl");
+
+        let old_id = old.with_openmp_runtime("cl".into(), &task(&old, "/openmp:llvm"));
+        let new_id = new.with_openmp_runtime("cl".into(), &task(&new, "/openmp:llvm"));
+        assert!(old_id.starts_with("cl libomp140.x86_64.dll "));
+        assert!(new_id.starts_with("cl libomp140.x86_64.dll "));
+        assert_ne!(old_id, new_id);
+
+        // Probe result is memoized per toolchain.
+        std::fs::write(temp.path().join("old/libomp140.x86_64.dll"), b"runtime 2").unwrap();
+        let llvm = task(&old, "/openmp:llvm");
+        assert_eq!(old.with_openmp_runtime("cl".into(), &llvm), old_id);
+    }
+
+    #[test]
+    fn test_shim_runtime_identifier() {
+        let temp = tempfile::tempdir().unwrap();
+        let shims = temp.path().join("shims");
+        let real = temp.path().join("VC");
+        std::fs::create_dir_all(&shims).unwrap();
+        std::fs::create_dir_all(&real).unwrap();
+        std::fs::write(shims.join("cl.exe"), b"shim").unwrap();
+        std::fs::write(shims.join("cl.shim"), "path = \"../VC/cl.exe\"\n").unwrap();
+        std::fs::write(real.join("cl.exe"), b"cl").unwrap();
+        std::fs::write(real.join("libomp140.x86_64.dll"), b"runtime").unwrap();
+
+        // Compiler is spawned through shim, but runtime is bundled with real binary.
+        let toolchain = VsToolchain::new(ToolchainPath::resolve(shims.join("cl.exe")));
+        assert_eq!(toolchain.path.spawn, shims.join("cl.exe"));
+        let args: Vec<String> = ["/c", "/TP", "/openmp:llvm", "sample.cpp"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        let task = toolchain
+            .create_tasks(CommandInfo::simple(PathBuf::from("cl.exe")), &args, false)
+            .unwrap()
+            .remove(0);
+        assert!(toolchain
+            .with_openmp_runtime("cl".into(), &task)
+            .starts_with("cl libomp140.x86_64.dll "));
+    }
+
+    #[test]
+    fn test_toolchain_invalidation() {
+        use std::sync::Arc;
+        use std::time::{Duration, SystemTime};
+
+        use crate::compiler::Compiler;
+        use crate::vs::compiler::VsCompiler;
+
+        let temp = tempfile::tempdir().unwrap();
+        let cl = temp.path().join("cl.exe");
+        std::fs::write(&cl, b"cl 19.38").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&cl, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let set_modified = |modified: SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(&cl)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let compiler = VsCompiler::default();
+        let resolve = || {
+            compiler
+                .resolve_toolchain(&CommandInfo::simple(cl.clone()))
+                .unwrap()
+        };
+
+        let first = resolve();
+        assert!(Arc::ptr_eq(&first, &resolve()));
+
+        // Compiler updated in place with the same size: toolchain is rebuilt.
+        let modified = std::fs::metadata(&cl).unwrap().modified().unwrap();
+        set_modified(modified + Duration::from_secs(10));
+        let second = resolve();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&second, &resolve()));
+
+        // Size change is noticed even if modification time is restored.
+        std::fs::write(&cl, b"cl 19.40.1").unwrap();
+        set_modified(modified + Duration::from_secs(10));
+        let third = resolve();
+        assert!(!Arc::ptr_eq(&second, &third));
+    }
+
+    #[test]
+    fn test_parse_banner_target() {
+        let parse = super::parse_banner_target;
+        assert_eq!(
+            parse("Microsoft (R) C/C++ Optimizing Compiler Version 19.38.33133 for x64\r\n"),
+            Some("x64".to_string())
+        );
+        assert_eq!(
+            parse("\nMicrosoft (R) C/C++ Optimizing Compiler Version 19.38.33133 for ARM64\n"),
+            Some("arm64".to_string())
+        );
+        assert_eq!(parse("cl : Command line error D8003"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn test_identifier_machine() {
+        let machine = super::identifier_machine;
+        assert_eq!(machine("19.38.33133.0 host x64 target x86"), Some(0x014c));
+        assert_eq!(machine("19.38.33133.0 target arm64"), Some(0xaa64));
+        assert_eq!(machine("19.38.33133.0 host x64"), None);
+        assert_eq!(machine("19.38.33133.0 target ia64"), None);
+    }
+
+    #[test]
+    fn test_is_internal_error() {
+        let check = |code, output: &str| super::is_internal_error(code, output.as_bytes());
+        assert!(check(
+            Some(2),
+            "a.cpp\r\na.cpp(12): fatal error C1001: Internal compiler error.\r\n"
+        ));
+        assert!(check(
+            Some(2),
+            "a.cpp(1): fatal error C1060: compiler is out of heap space\n"
+        ));
+        assert!(check(
+            None,
+            "cl : fatal error C1076: compiler limit: internal heap limit reached\n"
+        ));
+        assert!(!check(
+            Some(2),
+            "a.cpp(1): error C2065: 'x': undeclared identifier\n"
+        ));
+        assert!(!check(
+            Some(0),
+            "a.cpp(12): fatal error C1001: Internal compiler error.\n"
+        ));
+        assert!(!check(Some(2), ""));
+    }
+
+    #[test]
+    fn test_show_includes() {
+        let probe = "octobuild_probe.c\r\nHinweis: Einlesen der Datei:  C:\\Temp\\task\\octobuild_probe.h\r\n";
+        assert_eq!(
+            super::parse_show_includes_prefix(probe).as_deref(),
+            Some("Hinweis: Einlesen der Datei:")
+        );
+        assert_eq!(
+            super::parse_show_includes_prefix("octobuild_probe.c\r\n"),
+            None
+        );
+
+        let stderr = b"Note: including file: C:\\src\\a.h\r\n\
+            a.cpp(3): warning C4005: 'X': macro redefinition\r\n\
+            Note: including file:  C:\\src\\b.h\r\n";
+        assert_eq!(
+            super::parse_show_includes(stderr, "Note: including file:"),
+            [
+                "Note: including file: C:\\src\\a.h",
+                "Note: including file:  C:\\src\\b.h"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_architecture_identifier() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let toolchain = |dir: &str, banner: &str| {
+            let dir = temp.path().join(dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let cl = dir.join("cl.exe");
+            std::fs::write(&cl, format!("#!/bin/sh\necho \"{banner}\" >&2\n")).unwrap();
+            std::fs::set_permissions(&cl, std::fs::Permissions::from_mode(0o755)).unwrap();
+            VsToolchain::new(ToolchainPath::resolve(cl))
+        };
+        let banner = "Microsoft (R) C/C++ Optimizing Compiler Version 19.38.33133 for";
+        let x64 = toolchain("HostX64/x64", &format!("{banner} x64"));
+        let x86 = toolchain("HostX64/x86", &format!("{banner} x86"));
+        let arm64 = toolchain("HostX86/arm64", &format!("{banner} ARM64"));
+
+        // The same compiler version for different targets never shares cache entries.
+        let version = "cl 19.38.33133 6543A1B2c0000";
+        assert_eq!(
+            x64.with_architecture(version.into()),
+            format!("{version} host x64 target x64")
+        );
+        assert_eq!(
+            x86.with_architecture(version.into()),
+            format!("{version} host x64 target x86")
+        );
+        assert_eq!(
+            arm64.with_architecture(version.into()),
+            format!("{version} host x86 target arm64")
+        );
+
+        // Target is taken from directory layout if banner doesn't name it.
+        let silent = toolchain("HostX86/x86", "Microsoft (R) C/C++ Optimizing Compiler");
+        assert_eq!(
+            silent.with_architecture(version.into()),
+            format!("{version} host x86 target x86")
+        );
+        // Older layouts have neither.
+        let legacy = toolchain("VC/bin", "");
+        assert_eq!(legacy.with_architecture(version.into()), version);
+    }
 
     fn check_prepare_output(original: &str, expected: &str, line: &str, success: bool) {
+        let suppress = SharedState::suppress_patterns(&Config::default().suppress_warnings);
+        check_prepare_output_with(original, expected, line, success, &suppress.unwrap());
+    }
+
+    fn check_prepare_output_with(
+        original: &str,
+        expected: &str,
+        line: &str,
+        success: bool,
+        suppress: &[Regex],
+    ) {
         let mut stream: Vec<u8> = Vec::new();
         stream.write_all(original.as_bytes()).unwrap();
 
-        let result = super::prepare_output(line.as_bytes(), stream, success);
+        let result = super::prepare_output(line.as_bytes(), stream, success, suppress, None);
         assert_eq!(String::from_utf8_lossy(&result), expected);
     }
 
@@ -571,4 +1658,86 @@ foo.c(43) : warning C4433: foo bar
             false,
         );
     }
+
+    #[test]
+    fn test_prepare_output_suppress_empty() {
+        check_prepare_output_with(
+            "BLABLABLA\r\nfoo.c(42) : warning C4628: foo bar\r\n",
+            "foo.c(42) : warning C4628: foo bar\r\n",
+            "BLABLABLA",
+            true,
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_prepare_output_suppress_multiple() {
+        let suppress = SharedState::suppress_patterns(&[
+            r"^\S+[^:]*\(\d+\) : warning C4628: ".to_string(),
+            r": warning C4599: ".to_string(),
+            r"^cl : Command line warning D9025 : ".to_string(),
+        ])
+        .unwrap();
+        check_prepare_output_with(
+            "BLABLABLA\r\n\
+            foo.c(41) : warning C4411: foo bar\r\n\
+            foo.c(42) : warning C4628: foo bar\r\n\
+            foo.c(43) : warning C4599: '/Ibar': command line argument number 3 does not match precompiled header\r\n\
+            cl : Command line warning D9025 : overriding '/O2' with '/Od'\r\n\
+            foo.c(44) : warning C4433: foo bar",
+            "foo.c(41) : warning C4411: foo bar\r\nfoo.c(44) : warning C4433: foo bar",
+            "BLABLABLA",
+            true,
+            &suppress,
+        );
+        assert!(SharedState::suppress_patterns(&["(".to_string()]).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_prepare_output_cp1251() {
+        // C4628 warning in CP1251 between two other lines.
+        let cp1251 = b"foo.c(3): \xEF\xF0\xE5\xE4\xF3\xEF\xF0\xE5\xE6\xE4\xE5\xED\xE8\xE5 C4100: x\r\n\
+            foo.c(4) : \xEF\xF0\xE5\xE4\xF3\xEF\xF0\xE5\xE6\xE4\xE5\xED\xE8\xE5 C4628: \xE4\xE8\xE3\xF0\xE0\xF4\xFB\r\n\
+            foo.c(5): \xEE\xF8\xE8\xE1\xEA\xE0 C2065: y\r\n";
+        let output = crate::vs::codepage::to_utf8(cp1251.to_vec(), Some(1251));
+        let suppress = SharedState::suppress_patterns(&[r"^\S+\(\d+\) : \w+ C4628: ".to_string()]);
+        check_prepare_output_with(
+            &String::from_utf8(output).unwrap(),
+            "foo.c(3): предупреждение C4100: x\r\nfoo.c(5): ошибка C2065: y\r\n",
+            "BLABLABLA",
+            true,
+            &suppress.unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_prepare_output_rename() {
+        let rename = super::Rename::new(
+            Path::new(r"C:\temp\octobuild\abc123.i"),
+            Path::new(r"D:\work\foo.c"),
+        );
+        let result = super::prepare_output(
+            b"abc123.i",
+            b"abc123.i\r\n\
+            C:\\temp\\octobuild\\abc123.i(42): warning C4100: 'x': unreferenced formal parameter\r\n\
+            c:/TEMP/octobuild/abc123.i(57): error C2065: 'y': undeclared identifier\r\n\
+            D:\\work\\foo.h(3): note: see declaration in C:\\temp\\octobuild\\abc123.i(12)\r\n"
+                .to_vec(),
+            false,
+            &[],
+            Some(&rename),
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "D:\\work\\foo.c(42): warning C4100: 'x': unreferenced formal parameter\r\n\
+            D:\\work\\foo.c(57): error C2065: 'y': undeclared identifier\r\n\
+            D:\\work\\foo.h(3): note: see declaration in D:\\work\\foo.c(12)\r\n"
+        );
+        // Output without temporary path is kept as is.
+        assert_eq!(
+            rename.apply(b"foo.c(1): warning".to_vec()),
+            b"foo.c(1): warning"
+        );
+    }
 }
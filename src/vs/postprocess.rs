@@ -6,6 +6,8 @@ use std::slice;
 
 use thiserror::Error;
 
+use crate::includes::is_pseudo_file;
+
 #[derive(Error, Clone, Debug)]
 pub enum PostprocessError {
     #[error("unexpected end of line in literal")]
@@ -16,20 +18,30 @@ pub enum PostprocessError {
     LiteralTooLong,
     #[error("unexpected end of escape sequence")]
     EscapeEof,
-    #[error("can't find precompiled header marker: {0:?}")]
-    MarkerNotFound(OsString),
+    #[error(
+        "can't find precompiled header marker {marker:?}, first files in preprocessed output: {}",
+        .files.join(" -> ")
+    )]
+    MarkerNotFound {
+        marker: OsString,
+        files: Vec<String>,
+    },
     #[error("token too long")]
     TokenTooLong,
 }
 
 const BUF_SIZE: usize = 0x10000;
+// File transitions reported when marker is not found.
+const MAX_TRANSITIONS: usize = 8;
 
+// Returns warnings about suspicious input, like `#pragma once` in main file: it usually means
+// generated source that confuses precompiled header boundary.
 pub fn filter_preprocessed(
     reader: &mut impl Read,
     writer: &mut impl Write,
     marker: &Option<OsString>,
     keep_headers: bool,
-) -> crate::Result<()> {
+) -> crate::Result<Vec<String>> {
     let mut state = ScannerState {
         buf_data: [0; BUF_SIZE],
         ptr_copy: ptr::null(),
@@ -44,6 +56,10 @@ pub fn filter_preprocessed(
         utf8: false,
         header_found: false,
         entry_file: None,
+        current_file: None,
+        line: 1,
+        transitions: Vec::new(),
+        warnings: Vec::new(),
         done: false,
     };
 
@@ -70,10 +86,17 @@ pub fn filter_preprocessed(
             }
             state.parse_line()?;
             if state.done {
-                return state.copy_to_end();
+                state.copy_to_end()?;
+                return Ok(state.warnings);
             }
         }
-        Err(PostprocessError::MarkerNotFound(marker.clone().unwrap()).into())
+        Err(PostprocessError::MarkerNotFound {
+            marker: marker
+                .clone()
+                .unwrap_or_else(|| OsString::from("#pragma hdrstop")),
+            files: state.transitions,
+        }
+        .into())
     }
 }
 
@@ -96,6 +119,11 @@ where
     utf8: bool,
     header_found: bool,
     entry_file: Option<Vec<u8>>,
+    // File and line of the current position according to #line directives.
+    current_file: Option<Vec<u8>>,
+    line: u64,
+    transitions: Vec<String>,
+    warnings: Vec<String>,
     done: bool,
 }
 
@@ -206,6 +234,7 @@ where
             ) as *const u8;
             if !end.is_null() {
                 self.ptr_read = end.offset(1);
+                self.line += 1;
                 return Ok(());
             }
             self.ptr_read = self.ptr_end;
@@ -228,6 +257,7 @@ where
                     last = *end.offset(-1);
                 }
                 self.ptr_read = end.offset(1);
+                self.line += 1;
                 if last == b'\r' {
                     return Ok(b"\r\n");
                 }
@@ -250,8 +280,15 @@ where
         self.parse_spaces()?;
         let mut token = [0; 0x10];
         match self.parse_token(&mut token)? {
-            b"line" => self.parse_directive_line(),
+            b"line" => {
+                let mut line_token = [0; 0x10];
+                self.parse_spaces()?;
+                let line = self.parse_token(&mut line_token)?;
+                self.parse_directive_line(line)
+            }
             b"pragma" => self.parse_directive_pragma(),
+            // clang line marker: `# 1 "path" 2`.
+            line if line.first().is_some_and(u8::is_ascii_digit) => self.parse_directive_line(line),
             _ => {
                 self.next_line()?;
                 Ok(())
@@ -259,15 +296,26 @@ where
         }
     }
 
-    unsafe fn parse_directive_line(&mut self) -> Result<(), Error> {
-        let mut line_token = [0; 0x10];
+    unsafe fn parse_directive_line(&mut self, line: &[u8]) -> Result<(), Error> {
         let mut file_token = [0; 0x400];
         let mut file_raw = [0; 0x400];
         self.parse_spaces()?;
-        let line = self.parse_token(&mut line_token)?;
-        self.parse_spaces()?;
         let (file, raw) = self.parse_path(&mut file_token, &mut file_raw)?;
         let eol = self.next_line_eol()?;
+        if is_pseudo_file(file) {
+            return Ok(());
+        }
+        self.line = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.parse().ok())
+            .unwrap_or(self.line);
+        if self.current_file.as_deref() != Some(file) {
+            if self.transitions.len() < MAX_TRANSITIONS {
+                self.transitions
+                    .push(String::from_utf8_lossy(file).into_owned());
+            }
+            self.current_file = Some(Vec::from(file));
+        }
         self.entry_file = match self.entry_file.take() {
             Some(path) => {
                 if self.header_found && (path == file) {
@@ -304,6 +352,10 @@ where
                 }
                 self.done = true;
             }
+            b"once" => {
+                self.check_pragma_once();
+                self.next_line()?;
+            }
             _ => {
                 self.next_line()?;
             }
@@ -311,6 +363,24 @@ where
         Ok(())
     }
 
+    // Before the marker only headers are expected to have `#pragma once`.
+    fn check_pragma_once(&mut self) {
+        if self.marker.is_none() || self.header_found {
+            return;
+        }
+        if self.current_file.is_some() && self.current_file != self.entry_file {
+            return;
+        }
+        let file = self
+            .current_file
+            .as_deref()
+            .map_or("<unknown>".into(), String::from_utf8_lossy);
+        self.warnings.push(format!(
+            "{file}({}): #pragma once outside of header before precompiled header marker",
+            self.line
+        ));
+    }
+
     unsafe fn parse_escape(&mut self) -> Result<u8, Error> {
         self.next();
         match self.peek()? {
@@ -353,8 +423,12 @@ where
         loop {
             while self.ptr_read != self.ptr_end {
                 match *self.ptr_read {
+                    b'\n' => {
+                        self.line += 1;
+                        self.next();
+                    }
                     // non-nl-white-space ::= a blank, tab, or formfeed character
-                    b' ' | b'\t' | b'\x0C' | b'\n' | b'\r' => {
+                    b' ' | b'\t' | b'\x0C' | b'\r' => {
                         self.next();
                     }
                     _ => {
@@ -555,6 +629,64 @@ int main(int argc, char **argv) {
             r#"#pragma hdrstop
 #line 2 "sample.cpp"
 
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+            Some(OsString::from("sample header.h")),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_filter_precompiled_pseudo_files() {
+        check_filter(
+            r#"#line 1 "<built-in>"
+#line 1 "sample.cpp"
+#line 1 "<command line>"
+#line 1 "e:/work/octobuild/test_cl/sample header.h"
+void hello();
+#line 1 "<built-in>"
+#line 2 "sample.cpp"
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+            r#"#pragma hdrstop
+#line 2 "sample.cpp"
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+            Some(OsString::from("sample header.h")),
+            false,
+        );
+    }
+
+    #[test]
+    fn test_filter_precompiled_line_markers() {
+        // clang-cl /clang:-frewrite-includes output.
+        check_filter(
+            r#"# 1 "sample.cpp"
+# 1 "<built-in>" 1
+# 1 "sample.cpp" 2
+#if 0 /* expanded by -frewrite-includes */
+#include "sample header.h"
+#endif /* expanded by -frewrite-includes */
+# 1 "./sample header.h" 1
+#pragma once
+void hello();
+# 2 "sample.cpp" 2
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+            r#"#pragma hdrstop
+#line 2 "sample.cpp"
+
 int main(int argc, char **argv) {
 	return 0;
 }
@@ -682,4 +814,102 @@ int main(int argc, char **argv) {
             true,
         );
     }
+
+    #[test]
+    fn test_filter_precompiled_subdir() {
+        // /Yu header with directory, written with either separator.
+        for marker in ["Engine/Public/EnginePch.h", "Engine\\Public\\EnginePch.h"] {
+            check_filter(
+                r#"#line 1 "sample.cpp"
+#line 1 "e:\\work\\engine\\public\\enginepch.h"
+# pragma once
+void hello();
+#line 2 "sample.cpp"
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+                r#"#pragma hdrstop
+#line 2 "sample.cpp"
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+                Some(OsString::from(marker)),
+                false,
+            );
+        }
+    }
+
+    fn filter_warnings(original: &str, marker: &str) -> crate::Result<Vec<String>> {
+        let mut writer: Vec<u8> = Vec::new();
+        super::filter_preprocessed(
+            &mut Cursor::new(original.as_bytes().to_vec()),
+            &mut writer,
+            &Some(OsString::from(marker)),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_filter_pragma_once_in_source() {
+        // Generated source with its own `#pragma once` before precompiled header.
+        let warnings = filter_warnings(
+            r#"#line 1 "sample.cpp"
+
+#pragma once
+#line 1 "e:/work/octobuild/test_cl/sample header.h"
+#pragma once
+void hello();
+#line 4 "sample.cpp"
+#pragma once
+"#,
+            "sample header.h",
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            ["sample.cpp(2): #pragma once outside of header before precompiled header marker"]
+        );
+
+        // Position is unknown before the first #line directive.
+        let warnings = filter_warnings(
+            r#"#pragma once
+#line 1 "sample.cpp"
+#line 1 "sample header.h"
+#line 2 "sample.cpp"
+"#,
+            "sample header.h",
+        )
+        .unwrap();
+        assert_eq!(
+            warnings,
+            ["<unknown>(1): #pragma once outside of header before precompiled header marker"]
+        );
+    }
+
+    #[test]
+    fn test_filter_marker_not_found() {
+        let error = filter_warnings(
+            r#"#pragma once
+#line 1 "sample.cpp"
+#line 1 "<built-in>"
+#line 1 "e:/work/octobuild/test_cl/other.h"
+void hello();
+#line 2 "sample.cpp"
+
+int main(int argc, char **argv) {
+	return 0;
+}
+"#,
+            "sample header.h",
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "can't find precompiled header marker \"sample header.h\", first files in preprocessed output: sample.cpp -> e:/work/octobuild/test_cl/other.h -> sample.cpp"
+        );
+    }
 }
@@ -1,49 +1,91 @@
+use std::collections::HashSet;
 use std::ffi::OsString;
+use std::io::{stderr, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use crate::cmd;
 use crate::compiler::{
     Arg, CommandInfo, CompilationArgs, CompilationTask, InputKind, OutputKind, PCHArgs, PCHUsage,
-    ParamForm, Scope,
+    ParamForm, Scope, TaskError,
 };
-use crate::utils::{expand_response_files, find_param, ParamValue};
+use crate::utils::{cl_path, expand_response_files, find_param, ParamValue};
+
+// Option pairs rejected by cl with D8016.
+const INCOMPATIBLE: [(&[&str], &[&str]); 2] = [
+    (&["Yc"], &["Yu"]),
+    (
+        &["RTC1", "RTCs", "RTCu", "RTCsu", "RTCus"],
+        &["O1", "O2", "Ox"],
+    ),
+];
+
+// Mutually exclusive options: the last one wins, cl warns about the others with D9025.
+const EXCLUSIVE: [&[&str]; 5] = [
+    &["MD", "MDd", "MT", "MTd"],
+    &["Z7", "Zi", "ZI"],
+    &["Od", "O1", "O2"],
+    &["GR", "GR-"],
+    &["Gy", "Gy-"],
+];
+
+// Compiler options passed to linker by cl: debug information options add /DEBUG.
+const LINK_FLAGS: [&str; 4] = ["nologo", "Z7", "Zi", "ZI"];
+
+// Debug information options that make cl write to PDB file named by /Fd, which is shared by
+// tasks through mspdbsrv and can't be cached.
+const PDB_FLAGS: [&str; 2] = ["Zi", "ZI"];
+
+// Minimal rebuild warning is printed once per process, so once per build.
+static MINIMAL_REBUILD_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Inputs that cl passes to linker.
+const LINK_INPUTS: [&str; 3] = ["obj", "lib", "res"];
 
 pub fn create_tasks(
     command: CommandInfo,
     args: &[String],
     run_second_cpp: bool,
+    embed_debug_info: bool,
 ) -> crate::Result<Vec<CompilationTask>> {
-    let expanded_args = expand_response_files(&command.current_dir, args)?;
+    let expanded_args = expand_response_files(&command.current_dir, args, cmd::msvc::parse)?;
+    // Arguments after /link are passed to linker as is.
+    let (expanded_args, link_options) = match expanded_args
+        .iter()
+        .position(|arg| arg == "/link" || arg == "-link")
+    {
+        Some(index) => (&expanded_args[..index], Some(&expanded_args[index + 1..])),
+        None => (&expanded_args[..], None),
+    };
 
-    let parsed_args = parse_arguments(expanded_args.iter())?;
-    // Source file name.
-    let mut input_sources = Vec::<PathBuf>::new();
-    for input in parsed_args.iter().filter_map(|arg| match arg {
-        Arg::Input { kind, file, .. } if *kind == InputKind::Source => Some(PathBuf::from(file)),
-        _ => None,
-    }) {
-        input_sources.push(command.absolutize(&input)?);
+    let mut parsed_args = parse_arguments(expanded_args.iter())?;
+    warn_minimal_rebuild(&parsed_args);
+    let notes = resolve_conflicts(&mut parsed_args)?;
+    replace_pdb_flag(&mut parsed_args, embed_debug_info)?;
+    // Source file names with language forced by /Tc or /Tp.
+    let mut input_sources = Vec::<(PathBuf, Option<&str>)>::new();
+    for (input, forced) in parsed_args.iter().filter_map(source_input) {
+        input_sources.push((command.absolutize(&input)?, forced));
     }
     if input_sources.is_empty() {
-        return Err(crate::Error::from(
-            "Can't find source file path.".to_string(),
-        ));
+        return Err(TaskError::MissingInput.into());
     }
     // Precompiled header file name.
     let precompiled_file = match find_param(&parsed_args, |arg: &Arg| -> Option<PathBuf> {
         match arg {
-            Arg::Input { kind, file, .. } if *kind == InputKind::Precompiled => {
-                Some(PathBuf::from(file))
-            }
+            Arg::Input { kind, file, .. } if *kind == InputKind::Precompiled => Some(cl_path(file)),
             _ => None,
         }
     }) {
         ParamValue::None => None,
         ParamValue::Single(v) => Some(v),
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many precompiled header files: {v:?}"
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "precompiled header files",
+                values: format!("{v:?}"),
+            }
+            .into());
         }
     };
     // Precompiled header file name.
@@ -58,12 +100,26 @@ pub fn create_tasks(
             _ => None,
         }
     });
+    // Headers forced by /FI in command line order, UBT includes precompiled header this way.
+    let forced_includes: Vec<&str> = parsed_args
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Param { name, value, .. } if name == "FI" => Some(value.as_str()),
+            _ => None,
+        })
+        .collect();
     let pch_usage: PCHUsage = match &pch_param {
         ParamValue::None => crate::Result::<PCHUsage>::Ok(PCHUsage::None),
         ParamValue::Single((input, path)) => {
+            // Like cl, default name comes from the header, or from the source without it.
             let precompiled_path = match precompiled_file {
                 Some(v) => v,
-                None => PathBuf::from(path).with_extension("pch"),
+                None if path.is_empty() => input_sources[0]
+                    .0
+                    .file_name()
+                    .map(|name| PathBuf::from(name).with_extension("pch"))
+                    .unwrap_or_default(),
+                None => cl_path(path).with_extension("pch"),
             };
             let precompiled_path_abs = command.absolutize(&precompiled_path)?;
             let pch_marker = if path.is_empty() {
@@ -71,44 +127,67 @@ pub fn create_tasks(
             } else {
                 Some(OsString::from(path))
             };
+            // Forced include of other header can't stop precompiled part.
+            let forced_marker = forced_includes
+                .iter()
+                .find(|header| !path.is_empty() && is_same_header(header, path))
+                .map(|header| OsString::from(header_components(header).join("/")));
             if *input {
                 Ok(PCHUsage::In(PCHArgs {
                     path: precompiled_path,
                     path_abs: precompiled_path_abs,
                     marker: pch_marker,
+                    forced_marker,
                 }))
             } else {
                 Ok(PCHUsage::Out(PCHArgs {
                     path: precompiled_path,
                     path_abs: precompiled_path_abs,
                     marker: pch_marker,
+                    forced_marker,
                 }))
             }
         }
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many precompiled header markers: {:?}",
-                v.iter().map(|item| item.1.clone()).collect::<PathBuf>()
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "precompiled header markers",
+                values: format!(
+                    "{:?}",
+                    v.iter().map(|item| item.1.clone()).collect::<PathBuf>()
+                ),
+            }
+            .into());
         }
     }?;
 
     // Output object file name.
-    let output_param = find_param(&parsed_args, |arg: &Arg| -> Option<PathBuf> {
+    let output_param = find_param(&parsed_args, |arg: &Arg| -> Option<String> {
         match arg {
-            Arg::Output { kind, file, .. } if *kind == OutputKind::Object => {
-                Some(PathBuf::from(file))
-            }
+            Arg::Output { kind, file, .. } if *kind == OutputKind::Object => Some(file.clone()),
             _ => None,
         }
     });
-    let output_object: Option<PathBuf> = match output_param {
+    let output_object: Option<OutputObject> = match output_param {
         ParamValue::None => None,
-        ParamValue::Single(v) => Some(command.absolutize(&v)?),
+        ParamValue::Single(v) => {
+            // Trailing separator names directory for objects, even if it doesn't exist yet.
+            let trimmed = v.trim_end_matches(['/', '\\']);
+            let path = command.absolutize(&cl_path(trimmed))?;
+            let is_dir = trimmed.len() != v.len() || path.is_dir();
+            if !is_dir && input_sources.len() > 1 {
+                return Err(TaskError::OutputFileForMultipleInputs {
+                    path: PathBuf::from(v),
+                }
+                .into());
+            }
+            Some(OutputObject { path, is_dir })
+        }
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many output object files: {v:?}"
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "output object files",
+                values: format!("{v:?}"),
+            }
+            .into());
         }
     };
     // Language
@@ -121,40 +200,309 @@ pub fn create_tasks(
         ParamValue::None => None,
         ParamValue::Single(v) => Some(v),
         ParamValue::Many(v) => {
-            return Err(crate::Error::from(format!(
-                "Found too many output object files: {v:?}"
-            )));
+            return Err(TaskError::DuplicateOption {
+                option: "language options",
+                values: format!("{v:?}"),
+            }
+            .into());
         }
     };
+    let output_objects = input_sources
+        .iter()
+        .map(|(input_source, _)| get_output_object(&command, input_source, &output_object))
+        .collect::<crate::Result<Vec<PathBuf>>>()?;
+    // Sources with the same name from different directories would overwrite each other's object.
+    let mut unique = HashSet::new();
+    if let Some(path) = output_objects.iter().find(|path| !unique.insert(*path)) {
+        return Err(TaskError::DuplicateOutput { path: path.clone() }.into());
+    }
+    // Without /c cl links compiled objects: compilation is split into cached tasks
+    // followed by native link step.
+    let link = if parsed_args
+        .iter()
+        .any(|arg| matches!(arg, Arg::Flag { name, .. } if name == "c"))
+    {
+        if link_options.is_some() || parsed_args.iter().any(is_link_arg) {
+            return Err(TaskError::LinkWithoutLinking.into());
+        }
+        None
+    } else {
+        Some(link_args(&parsed_args, &output_objects, link_options))
+    };
+    let config = command
+        .config
+        .clone()
+        .or_else(|| infer_config(&parsed_args));
     let shared = Arc::new(CompilationArgs {
         args: parsed_args,
         pch_usage,
         command,
         deps_file: None,
         run_second_cpp,
+        notes,
+        config,
+        link,
     });
     input_sources
         .into_iter()
-        .map(|input_source| {
-            let language = language
-                .as_ref()
-                .map_or_else(|| detect_language(&input_source), |lang| Some(lang.clone()))
-                .ok_or_else(|| {
-                    format!(
-                        "Can't detect file language by extension: {}",
-                        input_source.to_string_lossy()
-                    )
+        .zip(output_objects)
+        .map(|((input_source, forced), output_object)| {
+            let language = forced
+                .map(str::to_string)
+                .or_else(|| language.clone())
+                .or_else(|| detect_language(&input_source))
+                .ok_or_else(|| TaskError::UnknownLanguage {
+                    path: input_source.clone(),
                 })?;
+            check_standard(&language, &input_source, &shared.args)?;
             Ok(CompilationTask {
                 shared: shared.clone(),
                 language,
-                output_object: get_output_object(&input_source, &output_object)?,
+                output_object,
                 input_source,
             })
         })
         .collect()
 }
 
+// Source file of compilation: plain input or /Tc and /Tp option that also forces its language.
+fn source_input(arg: &Arg) -> Option<(PathBuf, Option<&'static str>)> {
+    match arg {
+        Arg::Input {
+            kind: InputKind::Source,
+            file,
+        } => Some((cl_path(file), None)),
+        Arg::Param { name, value, .. } if name == "Tc" => Some((cl_path(value), Some("C"))),
+        Arg::Param { name, value, .. } if name == "Tp" => Some((cl_path(value), Some("P"))),
+        _ => None,
+    }
+}
+
+// Header path components with unified separators and without `.` and `..`.
+fn header_components(path: &str) -> Vec<&str> {
+    path.split(['/', '\\'])
+        .filter(|part| !matches!(*part, "" | "." | ".."))
+        .collect()
+}
+
+// Paths name the same header when one of them is the tail of the other.
+fn is_same_header(forced: &str, marker: &str) -> bool {
+    let forced = header_components(forced);
+    let marker = header_components(marker);
+    let len = forced.len().min(marker.len());
+    len > 0
+        && forced[forced.len() - len..]
+            .iter()
+            .zip(&marker[marker.len() - len..])
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn is_link_arg(arg: &Arg) -> bool {
+    matches!(
+        arg,
+        Arg::Input {
+            kind: InputKind::Link,
+            ..
+        } | Arg::Output {
+            kind: OutputKind::Executable,
+            ..
+        }
+    )
+}
+
+// Arguments of cl invocation that links objects of compile-and-link command. Sources are
+// replaced by their objects in place, so executable is named after the same first file.
+fn link_args(args: &[Arg], objects: &[PathBuf], link_options: Option<&[String]>) -> Vec<String> {
+    let mut objects = objects.iter();
+    let mut result = Vec::new();
+    for arg in args {
+        match arg {
+            arg if source_input(arg).is_some() => result.extend(
+                objects
+                    .next()
+                    .map(|path| path.to_string_lossy().into_owned()),
+            ),
+            Arg::Input {
+                kind: InputKind::Link,
+                file,
+            } => result.push(file.clone()),
+            Arg::Output {
+                kind: OutputKind::Executable,
+                name,
+                file,
+            } => result.push(format!("/{name}{file}")),
+            Arg::Flag { name, .. } if LINK_FLAGS.contains(&name.as_str()) => {
+                result.push(format!("/{name}"));
+            }
+            _ => {}
+        }
+    }
+    if let Some(options) = link_options {
+        result.push("/link".to_string());
+        result.extend(options.iter().cloned());
+    }
+    result
+}
+
+// Option name as cl reports it in command line diagnostics.
+// Guess build configuration from runtime library and optimization options.
+// Debug runtime or disabled optimization means Debug, otherwise optimization means Release.
+fn infer_config(args: &[Arg]) -> Option<String> {
+    let mut debug_runtime = None;
+    let mut optimized = None;
+    for arg in args {
+        if let Arg::Flag { name, .. } = arg {
+            match name.as_str() {
+                "MDd" | "MTd" => debug_runtime = Some(true),
+                "MD" | "MT" => debug_runtime = Some(false),
+                "Od" => optimized = Some(false),
+                "O1" | "O2" | "Ox" => optimized = Some(true),
+                _ => {}
+            }
+        }
+    }
+    match (debug_runtime, optimized) {
+        (Some(true), _) | (_, Some(false)) => Some("Debug".to_string()),
+        (_, Some(true)) => Some("Release".to_string()),
+        _ => None,
+    }
+}
+
+fn option_name(arg: &Arg) -> Option<&str> {
+    match arg {
+        Arg::Flag { name, .. } => Some(name),
+        Arg::Input {
+            kind: InputKind::Marker,
+            ..
+        } => Some("Yu"),
+        Arg::Output {
+            kind: OutputKind::Marker,
+            ..
+        } => Some("Yc"),
+        _ => None,
+    }
+}
+
+// Reproduce cl handling of conflicting options before the task is split into preprocessor
+// and compiler runs: hard conflicts fail the task, overridden options are removed,
+// and returned D9025 warnings are added to the task output on every run, cached or not.
+fn resolve_conflicts(args: &mut Vec<Arg>) -> Result<Vec<String>, TaskError> {
+    let names: Vec<Option<&str>> = args.iter().map(option_name).collect();
+    for (first, second) in INCOMPATIBLE {
+        let position = |group: &[&str]| {
+            names
+                .iter()
+                .position(|name| name.is_some_and(|name| group.contains(&name)))
+        };
+        if let (Some(a), Some(b)) = (position(first), position(second)) {
+            let (a, b) = (a.min(b), a.max(b));
+            return Err(TaskError::ConflictingOptions {
+                a: names[a].unwrap_or_default().to_string(),
+                b: names[b].unwrap_or_default().to_string(),
+            });
+        }
+    }
+
+    let mut notes = Vec::new();
+    let mut overridden = vec![false; args.len()];
+    for group in EXCLUSIVE {
+        let mut last: Option<usize> = None;
+        for (index, name) in names.iter().enumerate() {
+            let Some(name) = name.filter(|name| group.contains(name)) else {
+                continue;
+            };
+            if let Some(prev) = last {
+                let prev_name = names[prev].unwrap_or_default();
+                if prev_name != name {
+                    notes.push(format!(
+                        "cl : Command line warning D9025 : overriding '/{prev_name}' with '/{name}'"
+                    ));
+                }
+                overridden[prev] = true;
+            }
+            last = Some(index);
+        }
+    }
+    let mut overridden = overridden.into_iter();
+    args.retain(|_| !overridden.next().unwrap_or_default());
+    Ok(notes)
+}
+
+// /Gm keeps dependencies of source in .idb file, which doesn't work with separate preprocessing
+// and with tasks compiled in parallel, so it is removed instead of passed to cl.
+fn warn_minimal_rebuild(args: &[Arg]) {
+    let found = args
+        .iter()
+        .any(|arg| matches!(arg, Arg::Flag { name, .. } if name == "Gm"));
+    if found && !MINIMAL_REBUILD_WARNED.swap(true, Ordering::Relaxed) {
+        let _ = writeln!(
+            stderr(),
+            "octobuild: warning: '/Gm' (minimal rebuild) is not supported and is removed from cl commands"
+        );
+    }
+}
+
+// Debug information goes to object file with /Z7, so object depends on preprocessed source only.
+// /Fd is ignored anyway. Precompiled header created with /Z7 also keeps its debug information
+// in object file, so its users must be compiled with /Z7 too.
+fn replace_pdb_flag(args: &mut [Arg], embed_debug_info: bool) -> Result<(), TaskError> {
+    for arg in args {
+        if let Arg::Flag { name, .. } = arg {
+            if PDB_FLAGS.contains(&name.as_str()) {
+                if !embed_debug_info {
+                    return Err(TaskError::SharedPdb {
+                        option: name.clone(),
+                    });
+                }
+                *arg = Arg::flag(Scope::Shared, "/", "Z7");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Language of /std: option value: C++ standards are `c++14`..`c++latest`, C ones are `c11`..`clatest`.
+fn standard_language(value: &str) -> &'static str {
+    if value.starts_with("c++") {
+        "P"
+    } else {
+        "C"
+    }
+}
+
+fn language_name(language: &str) -> &str {
+    match language {
+        "P" => "C++",
+        other => other,
+    }
+}
+
+// cl applies /std: options only to sources of their own language, so C source compiled with
+// C++ standard alone is a misconfigured project rather than something to forward silently.
+fn check_standard(language: &str, input_source: &Path, args: &[Arg]) -> Result<(), TaskError> {
+    let standards: Vec<&str> = args
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Flag { name, .. } => name.strip_prefix("std:"),
+            _ => None,
+        })
+        .collect();
+    match standards.first() {
+        Some(standard)
+            if !standards
+                .iter()
+                .any(|value| standard_language(value) == language) =>
+        {
+            Err(TaskError::StandardMismatch {
+                standard: standard.to_string(),
+                language: language_name(language).to_string(),
+                path: input_source.to_path_buf(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
 fn detect_language(path: &Path) -> Option<String> {
     let ext = path.extension()?.to_str()?;
     if ext.eq_ignore_ascii_case("cpp") || ext.eq_ignore_ascii_case("cc") {
@@ -166,36 +514,39 @@ fn detect_language(path: &Path) -> Option<String> {
     }
 }
 
+// Object file option: single file or directory for objects of all sources.
+struct OutputObject {
+    path: PathBuf,
+    is_dir: bool,
+}
+
 fn get_output_object(
+    command: &CommandInfo,
     input_source: &Path,
-    output_object: &Option<PathBuf>,
+    output_object: &Option<OutputObject>,
 ) -> crate::Result<PathBuf> {
-    let result = output_object.as_ref().map_or_else(
-        || {
-            assert!(input_source.is_absolute());
-            Ok(input_source.with_extension("obj"))
-        },
-        |path| {
-            assert!(path.is_absolute());
-            if path.is_dir() {
-                input_source
-                    .file_name()
-                    .map(|name| path.join(name).with_extension("obj"))
-                    .ok_or_else(|| {
-                        crate::Error::Generic(format!(
-                            "Input file path does not contain file name: {}",
-                            input_source.to_string_lossy()
-                        ))
-                    })
-            } else {
-                Ok(path.clone())
-            }
-        },
-    )?;
-    Ok(result)
+    let object_name = || {
+        input_source
+            .file_name()
+            .map(|name| Path::new(name).with_extension("obj"))
+            .ok_or_else(|| {
+                crate::Error::from(TaskError::InvalidInput {
+                    path: input_source.to_path_buf(),
+                })
+            })
+    };
+    assert!(input_source.is_absolute());
+    match output_object {
+        // cl writes object file to current directory, not next to source.
+        None => command.absolutize(&object_name()?),
+        Some(OutputObject { path, is_dir: true }) => Ok(path.join(object_name()?)),
+        Some(OutputObject { path, .. }) => Ok(path.clone()),
+    }
 }
 
-fn parse_arguments<S: AsRef<str>, I: Iterator<Item = S>>(mut iter: I) -> Result<Vec<Arg>, String> {
+fn parse_arguments<S: AsRef<str>, I: Iterator<Item = S>>(
+    mut iter: I,
+) -> Result<Vec<Arg>, TaskError> {
     let mut result: Vec<Arg> = Vec::new();
     let mut errors: Vec<String> = Vec::new();
     while let Some(parse_result) = parse_argument(&mut iter) {
@@ -209,7 +560,7 @@ fn parse_arguments<S: AsRef<str>, I: Iterator<Item = S>>(mut iter: I) -> Result<
         }
     }
     if !errors.is_empty() {
-        return Err(format!("Found unknown command line arguments: {errors:?}"));
+        return Err(TaskError::UnsupportedFlags { flags: errors });
     }
     Ok(result)
 }
@@ -219,14 +570,22 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
     iter: &mut I,
 ) -> Option<Result<Arg, String>> {
     iter.next().map(|arg| {
+        // Standard input can't be preprocessed separately, native compiler handles it.
+        if arg.as_ref() == "-" {
+            return Err(arg.as_ref().to_string());
+        }
+        // Options are stored without prefix and passed to compiler with slash, so `-DFOO` and
+        // `/DFOO` are the same. Like cl, relative file names starting with dash are options.
         if has_param_prefix(arg.as_ref()) {
             let flag = &arg.as_ref()[1..];
-            match is_spaceable_param(flag) {
+            let option = match is_spaceable_param(flag) {
                 Some((key, scope)) => {
                     if flag == key {
                         match iter.next() {
                             Some(value) => {
-                                if has_param_prefix(value.as_ref()) {
+                                if has_param_prefix(value.as_ref())
+                                    && !is_absolute_path(value.as_ref())
+                                {
                                     Err(arg.as_ref().to_string())
                                 } else {
                                     Ok(Arg::param_ext(
@@ -252,16 +611,31 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                 }
                 None => match flag {
                     "c" | "nologo" => Ok(Arg::flag(Scope::Ignore, "/", flag)),
+                    // Included files are printed by preprocessor, see run_preprocess.
+                    "showIncludes" => Ok(Arg::flag(Scope::Ignore, "/", flag)),
+                    // Minimal rebuild, see warn_minimal_rebuild. /Gm- is passed as is.
+                    "Gm" => Ok(Arg::flag(Scope::Ignore, "/", flag)),
 
-                    "bigobj" => Ok(Arg::flag(Scope::Compiler, "/", flag)),
+                    "bigobj" | "Bt" | "Bt+" | "Qpar" => Ok(Arg::flag(Scope::Compiler, "/", flag)),
+
+                    // Conformance options change predefined macros, like _MSVC_LANG or
+                    // _RESUMABLE_FUNCTIONS_SUPPORTED, so preprocessor needs them too.
+                    "await" | "permissive" | "permissive-" => {
+                        Ok(Arg::flag(Scope::Shared, "/", flag))
+                    }
+
+                    // OpenMP also defines _OPENMP macro, so preprocessor needs it too.
+                    "openmp" | "openmp:llvm" | "openmp:experimental" => {
+                        Ok(Arg::flag(Scope::Shared, "/", flag))
+                    }
 
                     "FC"
                     | "d2vzeroupper"
                     | "d2vzeroupper-"
                     | "fastfail"
                     | "utf-8"
-                    | "permissive-"
-                    | "experimental:deterministic" => Ok(Arg::flag(Scope::Shared, "/", flag)),
+                    | "experimental:deterministic"
+                    | "await:strict" => Ok(Arg::flag(Scope::Shared, "/", flag)),
 
                     "X" => Ok(Arg::flag(Scope::Preprocessor, "/", flag)),
 
@@ -278,14 +652,15 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                     s if s.starts_with('Z') => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("d2Zi+") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("std:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
-                    s if s.starts_with("MP") => Ok(Arg::flag(Scope::Compiler, "/", flag)),
+                    // octobuild runs tasks in parallel itself.
+                    s if s.starts_with("MP") => Ok(Arg::flag(Scope::Ignore, "/", flag)),
                     s if s.starts_with("fsanitize=") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("MD") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("MT") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("EH") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("fp:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("arch:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
-                    s if s.starts_with("errorReport:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
+                    s if s.starts_with("errorReport:") => Ok(Arg::flag(Scope::Ignore, "/", flag)),
                     s if s.starts_with("source-charset:") => {
                         Ok(Arg::flag(Scope::Shared, "/", flag))
                     }
@@ -293,29 +668,54 @@ fn parse_argument<S: AsRef<str>, I: Iterator<Item = S>>(
                         Ok(Arg::flag(Scope::Shared, "/", flag))
                     }
                     s if s.starts_with("external:W") => Ok(Arg::flag(Scope::Shared, "/", flag)),
+                    s if s.starts_with("external:templates") => {
+                        Ok(Arg::flag(Scope::Shared, "/", flag))
+                    }
                     s if s.starts_with("favor:") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     s if s.starts_with("Fo") => Ok(Arg::output(OutputKind::Object, "Fo", &s[2..])),
+                    s if s.starts_with("Fe") => {
+                        let (name, file) = match s[2..].strip_prefix(':') {
+                            Some(file) => ("Fe:", file),
+                            None => ("Fe", &s[2..]),
+                        };
+                        if file.is_empty() {
+                            Err(arg.as_ref().to_string())
+                        } else {
+                            Ok(Arg::output(OutputKind::Executable, name, file))
+                        }
+                    }
                     s if s.starts_with("Fp") => Ok(Arg::input(InputKind::Precompiled, &s[2..])),
+                    // PDB file name, debug information of cached tasks is kept in object file.
+                    s if s.starts_with("Fd") => Ok(Arg::flag(Scope::Ignore, "/", flag)),
                     s if s.starts_with("Yc") => Ok(Arg::output(OutputKind::Marker, "Yc", &s[2..])),
                     s if s.starts_with("Yu") => Ok(Arg::input(InputKind::Marker, &s[2..])),
                     s if s.starts_with("Yl") => Ok(Arg::flag(Scope::Shared, "/", flag)),
-                    s if s.starts_with("FI") => Ok(Arg::param_ext(
-                        Scope::Preprocessor,
-                        "/",
-                        "FI",
-                        &s[2..],
-                        ParamForm::Smushed,
-                    )),
                     s if s.starts_with("analyze") => Ok(Arg::flag(Scope::Shared, "/", flag)),
                     _ => Err(arg.as_ref().to_string()),
                 },
+            };
+            // Unsupported option may be absolute unix path of input file, like ones passed to
+            // clang-cl on Linux.
+            if option.is_ok() || !is_input_path(arg.as_ref()) {
+                return option;
             }
-        } else {
-            Ok(Arg::Input {
-                kind: InputKind::Source,
-                file: arg.as_ref().to_string(),
-            })
         }
+        let is_link_input = Path::new(arg.as_ref())
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                LINK_INPUTS
+                    .iter()
+                    .any(|link| ext.eq_ignore_ascii_case(link))
+            });
+        Ok(Arg::input(
+            if is_link_input {
+                InputKind::Link
+            } else {
+                InputKind::Source
+            },
+            arg.as_ref(),
+        ))
     })
 }
 
@@ -325,7 +725,13 @@ fn is_spaceable_param(flag: &str) -> Option<(&str, Scope)> {
             return Some((prefix, Scope::Shared));
         }
     }
-    for prefix in ["external:I", "I", "sourceDependencies"] {
+    // Language of the source is passed to preprocessor and compiler as /TC or /TP.
+    for prefix in ["Tc", "Tp"] {
+        if flag.starts_with(prefix) {
+            return Some((prefix, Scope::Ignore));
+        }
+    }
+    for prefix in ["external:I", "FI", "I", "sourceDependencies"] {
         if flag.starts_with(prefix) {
             return Some((prefix, Scope::Preprocessor));
         }
@@ -342,11 +748,21 @@ fn has_param_prefix(arg: &str) -> bool {
     arg.starts_with('/') || arg.starts_with('-')
 }
 
+// Absolute unix path, like ones passed to clang-cl on Linux, also starts with slash.
+fn is_absolute_path(arg: &str) -> bool {
+    arg.starts_with('/') && Path::new(arg).is_absolute()
+}
+
+// Existing file that looks like an option.
+fn is_input_path(arg: &str) -> bool {
+    is_absolute_path(arg) && Path::new(arg).is_file()
+}
+
 #[test]
 fn test_parse_argument() {
     let args: Vec<String> =
         "/TP /c /Yusample.h /Fpsample.h.pch /Fosample.cpp.o /DTEST /D TEST2 /arch:AVX /fsanitize=address \
-         sample.cpp"
+         /errorReport:queue /Bt+ /EHsc /EHa /await /await:strict /openmp /openmp:llvm /Qpar sample.cpp"
             .split(' ')
             .map(|x| x.to_string())
             .collect();
@@ -362,7 +778,631 @@ fn test_parse_argument() {
             Arg::param_ext(Scope::Shared, "/", "D", "TEST2", ParamForm::Separate),
             Arg::flag(Scope::Shared, "/", "arch:AVX"),
             Arg::flag(Scope::Shared, "/", "fsanitize=address"),
+            Arg::flag(Scope::Ignore, "/", "errorReport:queue"),
+            Arg::flag(Scope::Compiler, "/", "Bt+"),
+            Arg::flag(Scope::Shared, "/", "EHsc"),
+            Arg::flag(Scope::Shared, "/", "EHa"),
+            Arg::flag(Scope::Shared, "/", "await"),
+            Arg::flag(Scope::Shared, "/", "await:strict"),
+            Arg::flag(Scope::Shared, "/", "openmp"),
+            Arg::flag(Scope::Shared, "/", "openmp:llvm"),
+            Arg::flag(Scope::Compiler, "/", "Qpar"),
             Arg::input(InputKind::Source, "sample.cpp")
         ]
     )
 }
+
+#[test]
+fn test_incompatible_options() {
+    let resolve = |line: &str| {
+        let mut args = parse_arguments(line.split(' ')).unwrap();
+        resolve_conflicts(&mut args)
+    };
+    assert_eq!(
+        resolve("/c /Ycsample.h /Yusample.h sample.cpp")
+            .unwrap_err()
+            .to_string(),
+        "cl : Command line error D8016 : '/Yc' and '/Yu' command-line options are incompatible"
+    );
+    assert_eq!(
+        resolve("/c /O2 /RTC1 sample.cpp").unwrap_err().to_string(),
+        "cl : Command line error D8016 : '/O2' and '/RTC1' command-line options are incompatible"
+    );
+    assert_eq!(
+        resolve("/c /RTCs /Ox sample.cpp").unwrap_err().to_string(),
+        "cl : Command line error D8016 : '/RTCs' and '/Ox' command-line options are incompatible"
+    );
+    assert!(resolve("/c /RTCc /O2 /Yusample.h sample.cpp")
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_overridden_options() {
+    let resolve = |line: &str| {
+        let mut args = parse_arguments(line.split(' ')).unwrap();
+        let notes = resolve_conflicts(&mut args).unwrap();
+        let names: Vec<String> = args
+            .iter()
+            .filter_map(option_name)
+            .map(str::to_string)
+            .collect();
+        (names, notes)
+    };
+    let warning = |from: &str, to: &str| {
+        format!("cl : Command line warning D9025 : overriding '/{from}' with '/{to}'")
+    };
+
+    assert_eq!(
+        resolve("/c /MD /Zi /MT sample.cpp"),
+        (
+            vec!["c".to_string(), "Zi".to_string(), "MT".to_string()],
+            vec![warning("MD", "MT")]
+        )
+    );
+    assert_eq!(
+        resolve("/Zi /Z7 /ZI /MDd"),
+        (
+            vec!["ZI".to_string(), "MDd".to_string()],
+            vec![warning("Zi", "Z7"), warning("Z7", "ZI")]
+        )
+    );
+    assert_eq!(
+        resolve("/Od /O2 /GR- /GR /Gy /Gy-"),
+        (
+            vec!["O2".to_string(), "GR".to_string(), "Gy-".to_string()],
+            vec![
+                warning("Od", "O2"),
+                warning("GR-", "GR"),
+                warning("Gy", "Gy-")
+            ]
+        )
+    );
+    // Repeated option is not reported.
+    assert_eq!(resolve("/MD /MD"), (vec!["MD".to_string()], Vec::new()));
+}
+
+#[test]
+fn test_infer_config() {
+    let infer = |line: &str| infer_config(&parse_arguments(line.split(' ')).unwrap());
+    assert_eq!(infer("/c /MDd /Od sample.cpp").as_deref(), Some("Debug"));
+    assert_eq!(infer("/c /MD /O2 sample.cpp").as_deref(), Some("Release"));
+    assert_eq!(infer("/c /MT /Ox sample.cpp").as_deref(), Some("Release"));
+    // Debug runtime wins over optimization.
+    assert_eq!(infer("/c /MTd /O2 sample.cpp").as_deref(), Some("Debug"));
+    // Release runtime without optimization, like Unreal DebugGame modules.
+    assert_eq!(infer("/c /MD /Od sample.cpp").as_deref(), Some("Debug"));
+    assert_eq!(infer("/c /O1 sample.cpp").as_deref(), Some("Release"));
+    // Runtime library alone doesn't tell about optimization.
+    assert_eq!(infer("/c /MD sample.cpp"), None);
+    assert_eq!(infer("/c /Zi sample.cpp"), None);
+    // Last option wins like in cl.
+    assert_eq!(infer("/c /Od /O2 sample.cpp").as_deref(), Some("Release"));
+
+    // Explicit label overrides inferred one.
+    let args: Vec<String> = ["/c", "/MDd", "/Od", "sample.cpp"]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let command = CommandInfo::simple(PathBuf::from("cl.exe"));
+    let tasks = create_tasks(command.clone(), &args, false, false).unwrap();
+    assert_eq!(tasks[0].shared.config.as_deref(), Some("Debug"));
+    let tasks = create_tasks(command.with_config(Some("Editor")), &args, false, false).unwrap();
+    assert_eq!(tasks[0].shared.config.as_deref(), Some("Editor"));
+}
+
+#[test]
+fn test_compile_and_link() {
+    let create = |line: &str| {
+        let args: Vec<String> = line.split(' ').map(ToString::to_string).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            false,
+            false,
+        )
+    };
+    let cwd = std::env::current_dir().unwrap();
+    let object = |name: &str| cwd.join(name).to_string_lossy().into_owned();
+
+    // Objects of sources without /Fo are placed to current directory.
+    let tasks =
+        create("/nologo a.cpp /Z7 /O2 sub/b.c x.lib /Fe:app.exe /link /SUBSYSTEM:CONSOLE /DEBUG")
+            .unwrap();
+    let objects: Vec<&Path> = tasks
+        .iter()
+        .map(|task| task.output_object.as_path())
+        .collect();
+    assert_eq!(objects, [cwd.join("a.obj"), cwd.join("b.obj")]);
+    assert_eq!(
+        tasks[0].shared.link.as_deref().unwrap(),
+        [
+            "/nologo".to_string(),
+            object("a.obj"),
+            "/Z7".to_string(),
+            object("b.obj"),
+            "x.lib".to_string(),
+            "/Fe:app.exe".to_string(),
+            "/link".to_string(),
+            "/SUBSYSTEM:CONSOLE".to_string(),
+            "/DEBUG".to_string(),
+        ]
+    );
+
+    // Explicit object name and smushed /Fe.
+    let tasks = create("/Foout/a.o a.cpp /Feapp.exe").unwrap();
+    assert_eq!(tasks[0].output_object, cwd.join("out/a.o"));
+    assert_eq!(
+        tasks[0].shared.link.as_deref().unwrap(),
+        [object("out/a.o"), "/Feapp.exe".to_string()]
+    );
+
+    // Compilation only.
+    assert_eq!(create("/c a.cpp").unwrap()[0].shared.link, None);
+    assert!(create("/c a.cpp x.lib").is_err());
+    assert!(create("/c a.cpp /link /DEBUG").is_err());
+    // Separate executable name is not supported.
+    assert!(create("a.cpp /Fe: app.exe").is_err());
+    // Nothing to compile.
+    assert!(create("a.obj /Fe:app.exe").is_err());
+
+    // /MP sources are split into separate tasks, objects go to /Fo directory.
+    let tasks = create("/c /MP4 /Foout\\ a.cpp sub/b.c").unwrap();
+    let objects: Vec<&Path> = tasks
+        .iter()
+        .map(|task| task.output_object.as_path())
+        .collect();
+    assert_eq!(objects, [cwd.join("out/a.obj"), cwd.join("out/b.obj")]);
+    assert!(tasks[0]
+        .shared
+        .args
+        .contains(&Arg::flag(Scope::Ignore, "/", "MP4")));
+    // Single object file can't hold objects of multiple sources.
+    assert_eq!(
+        create("/c /MP /Foout/a.obj a.cpp b.cpp")
+            .unwrap_err()
+            .to_string(),
+        "cl : Command line error D8036 : '/Foout/a.obj' not allowed with multiple source files"
+    );
+    assert_eq!(
+        create("/c /MP /Foout/a.obj a.cpp").unwrap()[0].output_object,
+        cwd.join("out/a.obj")
+    );
+
+    // Batched sources without /MP share arguments, so cache keys match single source commands.
+    let tasks = create("/c /Foout/ a.cpp sub/b.c").unwrap();
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(tasks[1].output_object, cwd.join("out/b.obj"));
+    assert_eq!(tasks[1].language, "C");
+    assert!(Arc::ptr_eq(&tasks[0].shared, &tasks[1].shared));
+    // Sources with the same name can't be compiled to the same directory.
+    assert_eq!(
+        create("/c /Foout/ a.cpp sub/a.cpp")
+            .unwrap_err()
+            .to_string(),
+        format!(
+            "cl : Command line error : multiple source files are compiled to the same object file '{}'",
+            cwd.join("out/a.obj").display()
+        )
+    );
+    assert!(create("/c a.cpp sub/a.c").is_err());
+
+    // Separators are unified like in cl, argument keeps original spelling.
+    let tasks = create("/c /Foout\\obj/a.obj sub\\a.cpp").unwrap();
+    assert_eq!(
+        tasks[0].output_object,
+        cwd.join("out").join("obj").join("a.obj")
+    );
+    assert_eq!(tasks[0].input_source, cwd.join("sub").join("a.cpp"));
+    assert!(tasks[0].shared.args.contains(&Arg::output(
+        OutputKind::Object,
+        "Fo",
+        "out\\obj/a.obj"
+    )));
+    assert_eq!(
+        create("/c /Foout/obj/a.obj sub/a.cpp").unwrap()[0].output_object,
+        tasks[0].output_object
+    );
+    assert!(matches!(
+        create("/c /Foout\\ sub/a.cpp sub\\a.cpp").unwrap_err(),
+        crate::Error::Task(TaskError::DuplicateOutput { .. })
+    ));
+}
+
+#[test]
+fn test_debug_info() {
+    let create = |line: &str, embed_debug_info: bool| {
+        let args: Vec<String> = line.split(' ').map(ToString::to_string).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            false,
+            embed_debug_info,
+        )
+    };
+    // Flags passed to compiler.
+    let flags = |line: &str| -> Vec<String> {
+        create(line, true).unwrap()[0]
+            .shared
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                Arg::Flag { scope, name, .. } if *scope != Scope::Ignore => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // PDB file is shared by tasks, so such commands are executed without cache by default.
+    assert!(matches!(
+        create("/c /Zi a.cpp", false).unwrap_err(),
+        crate::Error::Task(TaskError::SharedPdb { option }) if option == "Zi"
+    ));
+    assert_eq!(
+        create("/c /ZI /Fdout\\ a.cpp", false)
+            .unwrap_err()
+            .to_string(),
+        "Debug information of '/ZI' goes to shared PDB file, see OCTOBUILD_EMBED_DEBUG_INFO"
+    );
+    // The last debug information option wins, like in cl.
+    assert!(create("/c /Zi /Z7 /Fdout/a.pdb a.cpp", false).is_ok());
+    assert!(create("/c /Z7 /Zi a.cpp", false).is_err());
+
+    // Debug information goes to object file, /Fd is not passed to compiler.
+    assert_eq!(flags("/c /Zi /Fdout\\ /O2 a.cpp"), ["Z7", "O2"]);
+    assert_eq!(flags("/c /Z7 /ZI /Fdout/a.pdb a.cpp"), ["Z7"]);
+    assert_eq!(flags("/c /Ycstdafx.h /Zi /Fdout/ stdafx.cpp"), ["Z7"]);
+    // Linker still gets /DEBUG from /Z7.
+    let tasks = create("/Zi /Fdout/ a.cpp /Fe:app.exe", true).unwrap();
+    assert!(tasks[0]
+        .shared
+        .link
+        .as_deref()
+        .unwrap()
+        .contains(&"/Z7".to_string()));
+}
+
+#[test]
+fn test_source_language() {
+    let create = |line: &str| {
+        let args: Vec<String> = line.split(' ').map(ToString::to_string).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            false,
+            false,
+        )
+    };
+    let languages = |line: &str| -> Vec<String> {
+        create(line)
+            .unwrap()
+            .into_iter()
+            .map(|task| task.language)
+            .collect()
+    };
+
+    assert_eq!(languages("/c a.cpp b.c"), ["P", "C"]);
+    assert_eq!(languages("/c /TP a.cpp b.c"), ["P", "P"]);
+    // /Tc and /Tp force language of single source, with or without space.
+    assert_eq!(languages("/c /Tca.inl /Tp b.inl c.c"), ["C", "P", "C"]);
+    let cwd = std::env::current_dir().unwrap();
+    let tasks = create("/c /Tpmain.inl").unwrap();
+    assert_eq!(tasks[0].input_source, cwd.join("main.inl"));
+    assert_eq!(tasks[0].output_object, cwd.join("main.obj"));
+
+    // /std: option must match the source language.
+    assert_eq!(languages("/c /std:c++20 a.cpp"), ["P"]);
+    assert_eq!(languages("/c /std:c17 a.c"), ["C"]);
+    assert_eq!(
+        create("/c /std:c++20 a.c").unwrap_err().to_string(),
+        format!(
+            "cl : Command line error : '/std:c++20' is not applicable to C source file '{}'",
+            cwd.join("a.c").display()
+        )
+    );
+    assert!(create("/c /std:c11 a.cpp").is_err());
+    assert!(create("/c /TC /std:c++latest a.cpp").is_err());
+    // Both standards are given for mixed sources.
+    assert_eq!(languages("/c /std:c++17 /std:c11 a.cpp b.c"), ["P", "C"]);
+}
+
+#[test]
+fn test_response_files() {
+    let temp = tempfile::tempdir().unwrap();
+    let command = CommandInfo {
+        current_dir: Some(temp.path().to_path_buf()),
+        ..CommandInfo::simple(PathBuf::from("cl.exe"))
+    };
+    let create = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        create_tasks(command.clone(), &args, false, false)
+    };
+    // UBT writes UTF-16 response files, nested one is UTF-8 with BOM.
+    let mut data = vec![0xFF, 0xFE];
+    data.extend(
+        "/c /nologo\r\n/D\"MSG=\\\"hi there\\\"\"\r\n@\"nested args.rsp\"\r\n\"src dir/a.cpp\"\r\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes),
+    );
+    std::fs::write(temp.path().join("a.cpp.obj.response"), data).unwrap();
+    std::fs::write(
+        temp.path().join("nested args.rsp"),
+        "\u{feff}/Fo\"out dir/\"\n/O2\n",
+    )
+    .unwrap();
+
+    let tasks = create(&["@a.cpp.obj.response"]).unwrap();
+    let expected = create(&[
+        "/c",
+        "/nologo",
+        "/DMSG=\"hi there\"",
+        "/Foout dir/",
+        "/O2",
+        "src dir/a.cpp",
+    ])
+    .unwrap();
+    // Response file names don't get to arguments, so cache key doesn't depend on them.
+    assert_eq!(tasks[0].shared.args, expected[0].shared.args);
+    assert_eq!(tasks[0].input_source, temp.path().join("src dir/a.cpp"));
+    assert_eq!(tasks[0].output_object, temp.path().join("out dir/a.obj"));
+
+    // Response file including itself.
+    std::fs::write(temp.path().join("loop.rsp"), "/c @loop.rsp").unwrap();
+    let error = create(&["@loop.rsp", "a.cpp"]).unwrap_err().to_string();
+    assert!(error.ends_with("response files are nested deeper than 16 levels"));
+}
+
+#[test]
+fn test_minimal_rebuild() {
+    let create = |line: &str| {
+        let args: Vec<String> = line.split(' ').map(ToString::to_string).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            false,
+            false,
+        )
+        .unwrap()
+        .remove(0)
+    };
+    // Arguments of preprocessor, compiler and second compiler run.
+    let passed = |line: &str| -> Vec<Vec<String>> {
+        let task = create(line);
+        [
+            (Scope::Preprocessor, false),
+            (Scope::Compiler, false),
+            (Scope::Compiler, true),
+        ]
+        .into_iter()
+        .map(|(target, run_second_cpp)| {
+            task.shared
+                .args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Arg::Flag { scope, name, .. }
+                        if scope.matches(target, run_second_cpp, false) =>
+                    {
+                        Some(name.clone())
+                    }
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect()
+    };
+
+    let with = passed("/c /Gm /O2 /EHsc a.cpp");
+    assert!(with.iter().all(|args| !args.contains(&"Gm".to_string())));
+    // Cache key is the same as without the option.
+    assert_eq!(with, passed("/c /O2 /EHsc a.cpp"));
+    assert!(passed("/c /Gm- a.cpp")
+        .iter()
+        .all(|args| args.contains(&"Gm-".to_string())));
+}
+
+#[test]
+fn test_option_prefixes() {
+    let parse = |line: &str| parse_arguments(line.split(' ')).unwrap();
+    // Prefix of option isn't kept, so cache key doesn't depend on it.
+    let slash = parse("/c /O2 /DFOO /D BAR /I inc /Fooutput.obj /EHsc a.cpp");
+    assert_eq!(
+        parse("-c /O2 -DFOO -D BAR /I inc -Fooutput.obj -EHsc a.cpp"),
+        slash
+    );
+    assert_eq!(
+        parse("-c -O2 -DFOO -D BAR -I inc -Fooutput.obj -EHsc a.cpp"),
+        slash
+    );
+    // Standard input is left to native compiler.
+    assert!(matches!(
+        parse_arguments(["-c", "-"].iter()),
+        Err(TaskError::UnsupportedFlags { flags }) if flags == ["-"]
+    ));
+    // Relative file name starting with dash is an option, like for cl.
+    assert!(parse_arguments(["-c", "-a.cpp"].iter()).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_absolute_unix_paths() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = temp.path().join("a.cpp");
+    std::fs::write(&source, "").unwrap();
+    let source = source.to_str().unwrap();
+    let include = temp.path().to_str().unwrap();
+    assert_eq!(
+        parse_arguments(["-c", "/I", include, source].iter()).unwrap(),
+        [
+            Arg::flag(Scope::Ignore, "/", "c"),
+            Arg::param_ext(Scope::Preprocessor, "/", "I", include, ParamForm::Separate),
+            Arg::input(InputKind::Source, source),
+        ]
+    );
+    // Missing file is still an unsupported option.
+    assert!(parse_arguments(["/c", "/nonexistent/a.cpp"].iter()).is_err());
+}
+
+#[test]
+fn test_external_headers() {
+    let parse = |line: &str| parse_arguments(line.split(' ')).unwrap();
+    assert_eq!(
+        parse("/external:I third_party /external:Ilibs /external:W0 /external:templates- a.cpp"),
+        [
+            Arg::param_ext(
+                Scope::Preprocessor,
+                "/",
+                "external:I",
+                "third_party",
+                ParamForm::Separate
+            ),
+            Arg::param_ext(
+                Scope::Preprocessor,
+                "/",
+                "external:I",
+                "libs",
+                ParamForm::Smushed
+            ),
+            Arg::flag(Scope::Shared, "/", "external:W0"),
+            Arg::flag(Scope::Shared, "/", "external:templates-"),
+            Arg::input(InputKind::Source, "a.cpp"),
+        ]
+    );
+    assert_eq!(
+        parse("-external:W4 -external:templates"),
+        [
+            Arg::flag(Scope::Shared, "/", "external:W4"),
+            Arg::flag(Scope::Shared, "/", "external:templates"),
+        ]
+    );
+}
+
+#[test]
+fn test_forced_include_marker() {
+    use std::io::Cursor;
+
+    use crate::vs::postprocess::filter_preprocessed;
+
+    let create = |line: &str| {
+        let args: Vec<String> = line.split(' ').map(ToString::to_string).collect();
+        create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &args,
+            false,
+            false,
+        )
+        .unwrap()
+        .remove(0)
+    };
+    let pch_args = |task: &CompilationTask| match &task.shared.pch_usage {
+        PCHUsage::In(v) => v.clone(),
+        _ => panic!("precompiled header is not used"),
+    };
+    // Precompiled header is included only by /FI, source mentions it only via #line.
+    let preprocessed = r#"#line 1 "d:\\build\\game\\a.cpp"
+#line 1 "d:\\build\\shared\\sharedpch.h"
+#pragma once
+void hello();
+#line 1 "d:\\build\\game\\a.cpp"
+#line 1 "d:\\build\\game\\definitions.h"
+#define GAME 1
+#line 1 "d:\\build\\game\\a.cpp"
+int main() { return 0; }
+"#;
+    let filter = |marker: &Option<OsString>| -> crate::Result<String> {
+        let mut output = Vec::new();
+        filter_preprocessed(&mut Cursor::new(preprocessed), &mut output, marker, false)?;
+        Ok(String::from_utf8(output).unwrap())
+    };
+
+    let task = create(
+        r"/c /FID:\Build\Shared\SharedPCH.h /FI definitions.h /Yu..\Shared\SharedPCH.h /Fpshared.pch a.cpp",
+    );
+    // Forced includes keep their order.
+    let forced: Vec<&str> = task
+        .shared
+        .args
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Param { name, value, .. } if name == "FI" => Some(value.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(forced, [r"D:\Build\Shared\SharedPCH.h", "definitions.h"]);
+    let pch = pch_args(&task);
+    assert_eq!(pch.marker, Some(OsString::from(r"..\Shared\SharedPCH.h")));
+    assert_eq!(
+        pch.filter_marker(),
+        &Some(OsString::from("D:/Build/Shared/SharedPCH.h"))
+    );
+    // /Yu value alone doesn't match path in #line.
+    assert!(filter(&pch.marker).is_err());
+    assert_eq!(
+        filter(pch.filter_marker()).unwrap(),
+        "#pragma hdrstop\n#line 1 \"d:\\\\build\\\\game\\\\a.cpp\"\n#line 1 \"d:\\\\build\\\\game\\\\definitions.h\"\n#define GAME 1\n#line 1 \"d:\\\\build\\\\game\\\\a.cpp\"\nint main() { return 0; }\n"
+    );
+
+    // Forced include of other header isn't a marker.
+    let pch = pch_args(&create(
+        r"/c /FIdefinitions.h /Yu..\Shared\SharedPCH.h /Fpshared.pch a.cpp",
+    ));
+    assert_eq!(pch.forced_marker, None);
+    assert_eq!(pch.filter_marker(), &pch.marker);
+    // Without /Yu header /FI doesn't stop precompiled part either.
+    let pch = pch_args(&create(r"/c /FISharedPCH.h /Yu /Fpshared.pch a.cpp"));
+    assert_eq!(pch.filter_marker(), &None);
+}
+
+#[test]
+fn test_precompiled_header_names() {
+    let pch = |line: &str| {
+        let task = create_tasks(
+            CommandInfo::simple(PathBuf::from("cl.exe")),
+            &cmd::msvc::parse(line).unwrap(),
+            false,
+            false,
+        )
+        .unwrap()
+        .remove(0);
+        match &task.shared.pch_usage {
+            PCHUsage::In(v) => (true, v.marker.clone(), v.path.clone()),
+            PCHUsage::Out(v) => (false, v.marker.clone(), v.path.clone()),
+            PCHUsage::None => panic!("precompiled header is not used"),
+        }
+    };
+    let marker = |value: &str| Some(OsString::from(value));
+
+    // Without /Fp precompiled header is named after the header, next to it.
+    assert_eq!(
+        pch("/c /Ycpch.h pch.cpp"),
+        (false, marker("pch.h"), PathBuf::from("pch.pch"))
+    );
+    assert_eq!(
+        pch(r#"/c /Yu"Engine/Public/EnginePch.h" a.cpp"#),
+        (
+            true,
+            marker("Engine/Public/EnginePch.h"),
+            cl_path("Engine/Public/EnginePch.pch")
+        )
+    );
+    assert_eq!(
+        pch(r"/c /YuEngine\Public\EnginePch.h a.cpp"),
+        (
+            true,
+            marker(r"Engine\Public\EnginePch.h"),
+            cl_path("Engine/Public/EnginePch.pch")
+        )
+    );
+    assert_eq!(
+        pch(r#"/c "/YcMy Game\Game Pch.h" "/FpMy Game\game.pch" a.cpp"#),
+        (
+            false,
+            marker(r"My Game\Game Pch.h"),
+            cl_path("My Game/game.pch")
+        )
+    );
+    // Without header name precompiled header is named after the source.
+    assert_eq!(
+        pch(r"/c /Yc src\stdafx.cpp"),
+        (false, None, PathBuf::from("stdafx.pch"))
+    );
+}
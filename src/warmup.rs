@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::compiler::{CommandInfo, Toolchain};
+use crate::worker::BuildAction;
+
+// How often running probe checks whether warm-up is cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// First compilation with a toolchain pays for antivirus scan of compiler binaries, startup of
+// compiler services like mspdbsrv.exe and toolchain identifier probe. Warm-up compiles empty
+// source once per toolchain in background as soon as the toolchain is known, so it overlaps
+// build planning instead of the first real task. Probes never fail the build.
+pub struct ToolchainWarmup {
+    enabled: bool,
+    state: Arc<WarmupState>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct WarmupState {
+    cancelled: AtomicBool,
+    // Compiler programs with started probes.
+    started: Mutex<HashSet<PathBuf>>,
+    probes: Mutex<Vec<WarmupProbe>>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WarmupOutcome {
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug)]
+pub struct WarmupProbe {
+    pub program: PathBuf,
+    pub duration: Duration,
+    pub outcome: WarmupOutcome,
+}
+
+impl ToolchainWarmup {
+    #[must_use]
+    pub fn new(enabled: bool) -> Self {
+        ToolchainWarmup {
+            enabled,
+            state: Arc::default(),
+            threads: Mutex::default(),
+        }
+    }
+
+    // Start probe of toolchain in background, unless its compiler is already probed.
+    pub fn start(&self, toolchain: &Arc<dyn Toolchain>, command: &CommandInfo) {
+        if !self.enabled || self.state.cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+        if !self
+            .state
+            .started
+            .lock()
+            .unwrap()
+            .insert(command.program.clone())
+        {
+            return;
+        }
+        let toolchain = toolchain.clone();
+        let command = command.clone();
+        let state = self.state.clone();
+        let thread = std::thread::spawn(move || {
+            let start = Instant::now();
+            let outcome = match run_probe(toolchain.as_ref(), &command, &state.cancelled) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    warn!(
+                        "Toolchain warm-up of {} failed: {e}",
+                        command.program.display()
+                    );
+                    WarmupOutcome::Failed
+                }
+            };
+            let probe = WarmupProbe {
+                program: command.program,
+                duration: start.elapsed(),
+                outcome,
+            };
+            info!("Toolchain warm-up: {probe:?}");
+            state.probes.lock().unwrap().push(probe);
+        });
+        self.threads.lock().unwrap().push(thread);
+    }
+
+    // Start probes of toolchains of compilation actions.
+    pub fn start_actions(&self, actions: &[BuildAction]) {
+        for action in actions {
+            if let BuildAction::Compilation(toolchain, task) = action {
+                self.start(toolchain, &task.shared.command);
+            }
+        }
+    }
+
+    // Stop running probes and don't start new ones.
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    // Wait for started probes to complete.
+    pub fn finish(&self) -> Vec<WarmupProbe> {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            drop(thread.join());
+        }
+        self.state.probes.lock().unwrap().clone()
+    }
+
+    // Cancel probes that are still running and print time spent by them.
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        self.cancel();
+        for probe in self.finish() {
+            let outcome = match probe.outcome {
+                WarmupOutcome::Done => "done",
+                WarmupOutcome::Failed => "failed",
+                WarmupOutcome::Cancelled => "cancelled",
+            };
+            writeln!(
+                out,
+                "Toolchain warm-up of {} {outcome} in {:.3}s",
+                probe.program.display(),
+                probe.duration.as_secs_f64()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn run_probe(
+    toolchain: &dyn Toolchain,
+    command: &CommandInfo,
+    cancelled: &AtomicBool,
+) -> crate::Result<WarmupOutcome> {
+    // Identifier is remembered by toolchain, so real tasks don't wait for it.
+    toolchain.identifier();
+    let dir = tempfile::Builder::new().prefix("warmup").tempdir()?;
+    let source = dir.path().join("warmup.cpp");
+    fs::write(&source, "")?;
+    let Some(args) = toolchain.warmup_args(&source, &dir.path().join("warmup.obj")) else {
+        return Ok(WarmupOutcome::Done);
+    };
+    if cancelled.load(Ordering::Relaxed) {
+        return Ok(WarmupOutcome::Cancelled);
+    }
+    let mut child = command
+        .to_command()
+        .args(args)
+        .current_dir(dir.path())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(if status.success() {
+                WarmupOutcome::Done
+            } else {
+                WarmupOutcome::Failed
+            });
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            drop(child.kill());
+            child.wait()?;
+            return Ok(WarmupOutcome::Cancelled);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,644 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::reaper::AuxReaper;
+use crate::utils::escape_control;
+
+// How often watchdog checks for stalls.
+pub const WATCHDOG_TICK: Duration = Duration::from_secs(1);
+
+// Exit code of child process killed for running too long, same as of GNU `timeout`.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+thread_local! {
+    // Task executed by current worker thread.
+    static CURRENT_TASK: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskPhase {
+    Started,
+    Preprocessing,
+    Compiling,
+    Remote,
+    // Reading or writing cache entry.
+    Cache,
+    Running,
+}
+
+struct ChildProcess {
+    pid: u32,
+    command: String,
+    started: Instant,
+    killed: bool,
+}
+
+struct ActiveTask {
+    title: String,
+    phase: TaskPhase,
+    started: Instant,
+    phase_started: Instant,
+    // Time spent in finished phases.
+    phase_times: BTreeMap<TaskPhase, Duration>,
+    remote: Option<String>,
+    child: Option<ChildProcess>,
+}
+
+struct MonitorState {
+    tasks: BTreeMap<usize, ActiveTask>,
+    last_transition: Instant,
+}
+
+// State of running tasks, used to report build stalls.
+pub struct TaskMonitor {
+    // Report stall after this time without task state transitions (zero disables watchdog).
+    pub interval: Duration,
+    // Kill child processes running longer than this (zero disables).
+    pub kill_after: Duration,
+    // Auxiliary processes started by children are stopped at the end of the build.
+    pub reaper: AuxReaper,
+    next_id: AtomicUsize,
+    queued: AtomicUsize,
+    state: Mutex<MonitorState>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TaskSnapshot {
+    pub title: String,
+    pub phase: TaskPhase,
+    pub elapsed_sec: u64,
+    pub pid: Option<u32>,
+    pub command: Option<String>,
+    pub remote: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Snapshot {
+    pub stalled_sec: u64,
+    // Tasks ready to run but not started by worker yet.
+    pub queued: usize,
+    pub phases: BTreeMap<TaskPhase, usize>,
+    pub tasks: Vec<TaskSnapshot>,
+}
+
+pub struct TaskGuard<'a> {
+    monitor: &'a TaskMonitor,
+    id: usize,
+}
+
+pub struct ChildGuard<'a> {
+    monitor: &'a TaskMonitor,
+    pid: u32,
+}
+
+impl TaskMonitor {
+    #[must_use]
+    pub fn new(config: &Config) -> Self {
+        TaskMonitor {
+            interval: Duration::from_secs(config.watchdog_interval_sec),
+            kill_after: Duration::from_secs(config.watchdog_kill_sec),
+            reaper: AuxReaper::new(config),
+            next_id: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            state: Mutex::new(MonitorState {
+                tasks: BTreeMap::new(),
+                last_transition: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn enqueue(&self) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+    }
+
+    // Register task executed by current thread until guard is dropped.
+    pub fn begin(&self, title: &str) -> TaskGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::AcqRel);
+        _ = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |v| v.checked_sub(1));
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.tasks.insert(
+            id,
+            ActiveTask {
+                title: title.to_string(),
+                phase: TaskPhase::Started,
+                started: now,
+                phase_started: now,
+                phase_times: BTreeMap::new(),
+                remote: None,
+                child: None,
+            },
+        );
+        state.last_transition = now;
+        CURRENT_TASK.with(|current| current.set(Some(id)));
+        TaskGuard { monitor: self, id }
+    }
+
+    fn current<T>(&self, func: impl FnOnce(&ActiveTask) -> T) -> Option<T> {
+        let id = CURRENT_TASK.with(Cell::get)?;
+        self.state.lock().unwrap().tasks.get(&id).map(func)
+    }
+
+    fn update(&self, func: impl FnOnce(&mut ActiveTask)) {
+        let Some(id) = CURRENT_TASK.with(Cell::get) else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        if let Some(task) = state.tasks.get_mut(&id) {
+            func(task);
+            state.last_transition = Instant::now();
+        }
+    }
+
+    // Change phase of task executed by current thread.
+    pub fn phase(&self, phase: TaskPhase) {
+        self.update(|task| {
+            task.set_phase(phase);
+            task.remote = None;
+        });
+    }
+
+    pub fn remote(&self, endpoint: &str) {
+        self.update(|task| {
+            task.set_phase(TaskPhase::Remote);
+            task.remote = Some(endpoint.to_string());
+        });
+    }
+
+    // Run child in its own process group, so watchdog can kill the whole process tree.
+    // Otherwise grandchild holding output pipes keeps task waiting after the child is killed.
+    // Such child is out of terminal foreground group, so it doesn't get Ctrl+C.
+    // Child must be registered by `track_child` right after spawn.
+    pub fn isolate(&self, command: &mut Command) {
+        self.reaper.prepare(command);
+        #[cfg(unix)]
+        if !self.kill_after.is_zero() {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+    }
+
+    // Register child process of current task until guard is dropped.
+    pub fn track_child(&self, command: &Command, child: &Child) -> ChildGuard<'_> {
+        crate::slots::adjust_priority(child);
+        self.reaper.assign(child);
+        self.update(|task| {
+            task.child = Some(ChildProcess {
+                pid: child.id(),
+                command: format!("{command:?}"),
+                started: Instant::now(),
+                killed: false,
+            });
+        });
+        ChildGuard {
+            monitor: self,
+            pid: child.id(),
+        }
+    }
+
+    // Same as `Command::output`, but child process is visible to watchdog.
+    pub fn output(&self, command: &mut Command) -> std::io::Result<Output> {
+        self.isolate(command);
+        let child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let guard = self.track_child(command, &child);
+        Ok(guard.finish(child.wait_with_output()?))
+    }
+
+    #[must_use]
+    pub fn stalled_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.state.lock().unwrap().last_transition)
+    }
+
+    #[must_use]
+    pub fn snapshot(&self, now: Instant) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        let mut phases = BTreeMap::new();
+        let tasks = state
+            .tasks
+            .values()
+            .map(|task| {
+                *phases.entry(task.phase).or_default() += 1;
+                TaskSnapshot {
+                    title: task.title.clone(),
+                    phase: task.phase,
+                    elapsed_sec: now.saturating_duration_since(task.started).as_secs(),
+                    pid: task.child.as_ref().map(|child| child.pid),
+                    command: task.child.as_ref().map(|child| child.command.clone()),
+                    remote: task.remote.clone(),
+                }
+            })
+            .collect();
+        Snapshot {
+            stalled_sec: now
+                .saturating_duration_since(state.last_transition)
+                .as_secs(),
+            queued: self.queued.load(Ordering::Acquire),
+            phases,
+            tasks,
+        }
+    }
+
+    // Kill child processes running longer than `kill_after`. Returns killed process ids.
+    pub fn kill_stuck(&self, now: Instant) -> Vec<u32> {
+        let mut killed = Vec::new();
+        if self.kill_after.is_zero() {
+            return killed;
+        }
+        let mut state = self.state.lock().unwrap();
+        for task in state.tasks.values_mut() {
+            let Some(child) = &mut task.child else {
+                continue;
+            };
+            if child.killed || now.saturating_duration_since(child.started) < self.kill_after {
+                continue;
+            }
+            child.killed = true;
+            if kill_process_tree(child.pid) {
+                error!(
+                    "Task {}: killed process {} running for more than {}s: {}",
+                    escape_control(&task.title),
+                    child.pid,
+                    self.kill_after.as_secs(),
+                    child.command
+                );
+                killed.push(child.pid);
+            } else {
+                warn!(
+                    "Task {}: can't kill process {}",
+                    escape_control(&task.title),
+                    child.pid
+                );
+            }
+        }
+        killed
+    }
+}
+
+impl ActiveTask {
+    fn set_phase(&mut self, phase: TaskPhase) {
+        let now = Instant::now();
+        *self.phase_times.entry(self.phase).or_default() +=
+            now.saturating_duration_since(self.phase_started);
+        self.phase = phase;
+        self.phase_started = now;
+    }
+}
+
+impl TaskGuard<'_> {
+    // Time spent by task in every phase so far.
+    #[must_use]
+    pub fn phase_times(&self) -> BTreeMap<TaskPhase, Duration> {
+        let state = self.monitor.state.lock().unwrap();
+        let Some(task) = state.tasks.get(&self.id) else {
+            return BTreeMap::new();
+        };
+        let mut result = task.phase_times.clone();
+        *result.entry(task.phase).or_default() += task.phase_started.elapsed();
+        result
+    }
+}
+
+impl Drop for TaskGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.monitor.state.lock().unwrap();
+        state.tasks.remove(&self.id);
+        state.last_transition = Instant::now();
+        CURRENT_TASK.with(|current| current.set(None));
+    }
+}
+
+impl ChildGuard<'_> {
+    // Mark output of child killed by watchdog, so the task fails with clear reason
+    // instead of random exit code of interrupted compiler.
+    #[must_use]
+    pub fn finish(self, mut output: Output) -> Output {
+        let killed = self.monitor.current(|task| {
+            task.child
+                .as_ref()
+                .filter(|child| child.killed)
+                .map(|child| (child.command.clone(), child.started.elapsed()))
+        });
+        if let Some(Some((command, elapsed))) = killed {
+            output.status = timeout_status();
+            output.stderr.extend_from_slice(
+                format!(
+                    "octobuild: killed after running for {}s: {command}\n",
+                    elapsed.as_secs()
+                )
+                .as_bytes(),
+            );
+        }
+        output
+    }
+}
+
+impl Drop for ChildGuard<'_> {
+    fn drop(&mut self) {
+        self.monitor.update(|task| task.child = None);
+        self.monitor.reaper.release(self.pid);
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "No task state changes for {}s, {} task(s) queued, {} task(s) active:",
+            self.stalled_sec,
+            self.queued,
+            self.tasks.len()
+        )?;
+        for (phase, count) in &self.phases {
+            writeln!(f, "  {phase:?}: {count}")?;
+        }
+        for task in &self.tasks {
+            write!(
+                f,
+                "  {} [{:?}, {}s]",
+                escape_control(&task.title),
+                task.phase,
+                task.elapsed_sec
+            )?;
+            if let Some(remote) = &task.remote {
+                write!(f, " builder {remote}")?;
+            }
+            if let (Some(pid), Some(command)) = (task.pid, &task.command) {
+                write!(f, " pid {pid}: {command}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Decides when to report stall. Reports are repeated at doubling intervals while stall lasts.
+pub struct Watchdog {
+    next_report: Duration,
+    last_stall: Duration,
+}
+
+impl Watchdog {
+    #[must_use]
+    pub fn new(monitor: &TaskMonitor) -> Self {
+        Watchdog {
+            next_report: monitor.interval,
+            last_stall: Duration::ZERO,
+        }
+    }
+
+    pub fn check(&mut self, monitor: &TaskMonitor, now: Instant) -> Option<Snapshot> {
+        monitor.kill_stuck(now);
+        let stalled = monitor.stalled_for(now);
+        if stalled < self.last_stall {
+            // Some task made progress since last check.
+            self.next_report = monitor.interval;
+        }
+        self.last_stall = stalled;
+        if monitor.interval.is_zero() || stalled < self.next_report {
+            return None;
+        }
+        self.next_report = stalled * 2;
+        Some(monitor.snapshot(now))
+    }
+
+    // Check monitor until stop channel is closed.
+    pub fn run(mut self, monitor: &TaskMonitor, stop: &crossbeam_channel::Receiver<()>) {
+        while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+            stop.recv_timeout(WATCHDOG_TICK)
+        {
+            if let Some(snapshot) = self.check(monitor, Instant::now()) {
+                warn!("{snapshot}");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn timeout_status() -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(TIMEOUT_EXIT_CODE << 8)
+}
+
+#[cfg(windows)]
+fn timeout_status() -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(TIMEOUT_EXIT_CODE as u32)
+}
+
+// Kill process group of isolated child, or the child alone if it is not a group leader.
+#[cfg(unix)]
+fn kill_process_tree(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    unsafe { libc::kill(-pid, libc::SIGKILL) == 0 || libc::kill(pid, libc::SIGKILL) == 0 }
+}
+
+// Windows has no process groups for console tools, so descendants are found by parent id.
+#[cfg(windows)]
+fn kill_process_tree(pid: u32) -> bool {
+    let descendants = process_descendants(pid);
+    let result = kill_process(pid);
+    for pid in descendants {
+        kill_process(pid);
+    }
+    result
+}
+
+#[cfg(windows)]
+fn process_descendants(root: u32) -> Vec<u32> {
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::tlhelp32::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+        TH32CS_SNAPPROCESS,
+    };
+
+    let mut processes = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return Vec::new();
+        }
+        let mut entry: PROCESSENTRY32W = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+        let mut found = Process32FirstW(snapshot, &mut entry) != 0;
+        while found {
+            processes.push((entry.th32ProcessID, entry.th32ParentProcessID));
+            found = Process32NextW(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+    }
+    let mut result = Vec::new();
+    let mut parents = vec![root];
+    while let Some(parent) = parents.pop() {
+        for &(pid, ppid) in &processes {
+            if ppid == parent && pid != parent && pid != root && !result.contains(&pid) {
+                result.push(pid);
+                parents.push(pid);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(windows)]
+fn kill_process(pid: u32) -> bool {
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+    use winapi::um::winnt::PROCESS_TERMINATE;
+
+    unsafe {
+        let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+        if handle.is_null() {
+            return false;
+        }
+        let result = TerminateProcess(handle, TIMEOUT_EXIT_CODE as u32) != 0;
+        CloseHandle(handle);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    use crate::config::Config;
+    use crate::watchdog::{TaskMonitor, TaskPhase, Watchdog, TIMEOUT_EXIT_CODE};
+
+    fn monitor(interval: u64, kill: u64) -> TaskMonitor {
+        TaskMonitor::new(&Config {
+            watchdog_interval_sec: interval,
+            watchdog_kill_sec: kill,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    fn test_stalled_task_snapshot() {
+        let monitor = monitor(10, 0);
+        let mut watchdog = Watchdog::new(&monitor);
+        monitor.enqueue();
+        monitor.enqueue();
+        monitor.enqueue();
+        let guard = monitor.begin("a.cpp");
+        monitor.phase(TaskPhase::Compiling);
+        // Synthetic child process which never exits.
+        let command = Command::new("cl.exe");
+        {
+            let mut state = monitor.state.lock().unwrap();
+            let task = state.tasks.values_mut().next().unwrap();
+            task.child = Some(super::ChildProcess {
+                pid: 1234,
+                command: format!("{command:?}"),
+                started: Instant::now(),
+                killed: false,
+            });
+        }
+        let start = Instant::now();
+
+        assert!(watchdog.check(&monitor, start).is_none());
+        let snapshot = watchdog
+            .check(&monitor, start + Duration::from_secs(11))
+            .unwrap();
+        assert_eq!(snapshot.queued, 2);
+        assert_eq!(snapshot.tasks.len(), 1);
+        let task = &snapshot.tasks[0];
+        assert_eq!(task.title, "a.cpp");
+        assert_eq!(task.phase, TaskPhase::Compiling);
+        assert_eq!(task.pid, Some(1234));
+        assert_eq!(task.command.as_deref(), Some("\"cl.exe\""));
+        assert_eq!(snapshot.phases.get(&TaskPhase::Compiling), Some(&1));
+        let text = snapshot.to_string();
+        assert!(text.contains("2 task(s) queued, 1 task(s) active"));
+        assert!(text.contains("a.cpp [Compiling, 11s] pid 1234: \"cl.exe\""));
+
+        // Reports are repeated at increasing intervals.
+        assert!(watchdog
+            .check(&monitor, start + Duration::from_secs(15))
+            .is_none());
+        assert!(watchdog
+            .check(&monitor, start + Duration::from_secs(23))
+            .is_some());
+        assert!(watchdog
+            .check(&monitor, start + Duration::from_secs(40))
+            .is_none());
+        assert!(watchdog
+            .check(&monitor, start + Duration::from_secs(47))
+            .is_some());
+
+        // Progress resets interval.
+        monitor.phase(TaskPhase::Cache);
+        let start = Instant::now();
+        assert!(watchdog.check(&monitor, start).is_none());
+        assert!(watchdog
+            .check(&monitor, start + Duration::from_secs(10))
+            .is_some());
+
+        drop(guard);
+        let snapshot = monitor.snapshot(Instant::now());
+        assert!(snapshot.tasks.is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let monitor = monitor(0, 0);
+        let mut watchdog = Watchdog::new(&monitor);
+        let _guard = monitor.begin("a.cpp");
+        assert!(watchdog
+            .check(&monitor, Instant::now() + Duration::from_secs(3600))
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_kill_stuck_child() {
+        let monitor = monitor(0, 5);
+        std::thread::scope(|scope| {
+            let worker = scope.spawn(|| {
+                let _guard = monitor.begin("sleep");
+                // Grandchild keeps output pipes open unless the whole process tree is killed.
+                monitor.output(Command::new("sh").args(["-c", "sleep 60 & wait"]))
+            });
+            // Wait until child is started.
+            let start = Instant::now();
+            while monitor
+                .snapshot(Instant::now())
+                .tasks
+                .first()
+                .and_then(|task| task.pid)
+                .is_none()
+            {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let now = Instant::now();
+            assert!(monitor.kill_stuck(now).is_empty());
+            assert_eq!(monitor.kill_stuck(now + Duration::from_secs(6)).len(), 1);
+            // Child is killed only once.
+            assert!(monitor.kill_stuck(now + Duration::from_secs(7)).is_empty());
+            let output = worker.join().unwrap().unwrap();
+            assert!(now.elapsed() < Duration::from_secs(30));
+            assert_eq!(output.status.code(), Some(TIMEOUT_EXIT_CODE));
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.starts_with("octobuild: killed after running for "));
+            assert!(stderr.contains("\"sh\""));
+        });
+    }
+}
@@ -1,45 +1,216 @@
-use log::error;
+use log::{error, info, warn};
 use std::borrow::Cow;
-use std::cmp::{max, min};
+use std::cmp::{max, min, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+use std::fmt;
 use std::io::Write;
-use std::sync::Arc;
-use std::time::Instant;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use petgraph::graph::NodeIndex;
 use petgraph::{EdgeDirection, Graph};
 
 use crate::compiler::{
     BuildTaskResult, CommandArgs, CommandInfo, CompilationTask, Compiler, OutputInfo, SharedState,
-    Toolchain,
+    TaskError, Toolchain,
 };
+use crate::config::ExitCodeMode;
+use crate::diagnostics::{DiagnosticCounts, TaskOutcome, TaskRecord};
+use crate::io::statistic::TaskTiming;
+use crate::schedule::ScheduleEvent;
+use crate::shadow::ShadowTasks;
+use crate::sourcelock::{is_locked_source, LOCK_RETRY_COUNT, LOCK_RETRY_DELAY};
+use crate::utils::escape_control;
+use crate::watchdog::{TaskPhase, Watchdog};
+
+// Attempts to run task failed because of build machine state (e.g. full disk).
+const INFRA_RETRY_COUNT: usize = 3;
+// Pause before retry to let disk space be freed.
+const INFRA_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// Exit code used when failed tasks can't be represented by a single task exit code.
+pub const AGGREGATE_EXIT_CODE: i32 = 1;
 
 pub type BuildGraph = Graph<Arc<BuildTask>, ()>;
 
+// Compiler commands executed natively because they can't be split into cached tasks,
+// counted by reason.
+#[derive(Default)]
+pub struct UncachedTasks {
+    reasons: Mutex<BTreeMap<&'static str, usize>>,
+}
+
+impl UncachedTasks {
+    #[must_use]
+    pub fn new() -> Self {
+        UncachedTasks::default()
+    }
+
+    pub fn record(&self, error: &crate::Error) {
+        let reason = match error {
+            crate::Error::Task(error) => error.reason(),
+            _ => "other errors",
+        };
+        *self.reasons.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    #[must_use]
+    pub fn reasons(&self) -> BTreeMap<&'static str, usize> {
+        self.reasons.lock().unwrap().clone()
+    }
+
+    pub fn print_summary(&self, out: &mut impl Write) -> crate::Result<()> {
+        let reasons = self.reasons();
+        if reasons.is_empty() {
+            return Ok(());
+        }
+        let total: usize = reasons.values().sum();
+        let reasons: Vec<String> = reasons
+            .iter()
+            .map(|(reason, count)| format!("{reason}: {count}"))
+            .collect();
+        writeln!(
+            out,
+            "Commands executed without cache: {total} ({})",
+            reasons.join(", ")
+        )?;
+        Ok(())
+    }
+}
+
 pub struct BuildTask {
     pub title: String,
     pub action: BuildAction,
 }
 
 impl BuildTask {
+    // Source file of compilation task.
+    #[must_use]
+    pub fn source(&self) -> Option<&Path> {
+        match &self.action {
+            BuildAction::Compilation(_, task) => Some(&task.input_source),
+            BuildAction::Shadow(shadow) => shadow
+                .tasks
+                .first()
+                .map(|(_, task)| task.input_source.as_path()),
+            BuildAction::Empty | BuildAction::Exec(..) => None,
+        }
+    }
+
+    // Compiler command of the task.
+    #[must_use]
+    pub fn command(&self) -> Option<&CommandInfo> {
+        match &self.action {
+            BuildAction::Compilation(_, task) => Some(&task.shared.command),
+            BuildAction::Exec(command, _) => Some(command),
+            BuildAction::Shadow(shadow) => Some(&shadow.command),
+            BuildAction::Empty => None,
+        }
+    }
+
+    // Compilation tasks of the action, shadow action compiles several of them.
+    fn compilation_tasks(&self) -> Vec<&CompilationTask> {
+        match &self.action {
+            BuildAction::Compilation(_, task) => vec![task],
+            BuildAction::Shadow(shadow) => shadow.tasks.iter().map(|(_, task)| task).collect(),
+            BuildAction::Empty | BuildAction::Exec(..) => Vec::new(),
+        }
+    }
+
     fn execute(&self, state: &SharedState) -> BuildTaskResult {
+        let guard = state.monitor.begin(&self.title);
+        let start_time = Instant::now();
+        let mut attempt = 1;
+        let mut locked_attempt = 0;
+        loop {
+            let mut result = self.execute_once(state);
+            if let Err(e) = &result.output {
+                if e.is_infrastructure() {
+                    state.statistic.inc_infra_failure();
+                    if attempt < INFRA_RETRY_COUNT {
+                        warn!(
+                            "Task {} failed, retry in {}s: {e}",
+                            self.title,
+                            INFRA_RETRY_DELAY.as_secs()
+                        );
+                        std::thread::sleep(INFRA_RETRY_DELAY);
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            }
+            if let Ok(output) = &result.output {
+                // Crash doesn't depend on sources, so the task gets another chance.
+                if output.status.is_crashed()
+                    && !output.status.is_interrupted()
+                    && attempt < INFRA_RETRY_COUNT
+                {
+                    warn!("Task {} crashed with {}, retry", self.title, output.status);
+                    attempt += 1;
+                    continue;
+                }
+            }
+            // Source saved by IDE at the moment is readable again soon.
+            if let (Ok(output), Some(source)) = (&result.output, self.source()) {
+                if locked_attempt < LOCK_RETRY_COUNT && is_locked_source(source, output) {
+                    state.statistic.inc_locked_retry();
+                    locked_attempt += 1;
+                    let delay = LOCK_RETRY_DELAY * locked_attempt;
+                    warn!(
+                        "Task {} failed to open locked source {}, retry in {}ms",
+                        self.title,
+                        source.display(),
+                        delay.as_millis()
+                    );
+                    std::thread::sleep(delay);
+                    continue;
+                }
+            }
+            result.duration = Instant::now().duration_since(start_time);
+            result.phases = guard.phase_times();
+            return result;
+        }
+    }
+
+    fn execute_once(&self, state: &SharedState) -> BuildTaskResult {
         let start_time = Instant::now();
+        let mut cached = false;
+        let mut retry = None;
+        let mut note = None;
         let output = match &self.action {
-            BuildAction::Empty => Ok(OutputInfo {
-                status: Some(0),
-                stderr: Vec::new(),
-                stdout: Vec::new(),
-            }),
-            BuildAction::Exec(command_info, args) => state.wrap_slow(|| {
-                let mut command = command_info.to_command();
-                args.append_to(&mut command)?;
-                let output = command.output()?;
-                Ok(OutputInfo::new(output))
-            }),
-            BuildAction::Compilation(toolchain, task) => toolchain.compile_task(state, task),
+            BuildAction::Empty => Ok(OutputInfo::empty()),
+            BuildAction::Exec(..) | BuildAction::Shadow(..) if state.prewarm.is_some() => {
+                // Prewarm only preprocesses, so linker and custom commands are not run.
+                if let Some(prewarm) = &state.prewarm {
+                    prewarm.inc_skipped();
+                }
+                Ok(OutputInfo::empty())
+            }
+            BuildAction::Exec(command_info, args) => run_command(state, command_info, args),
+            BuildAction::Shadow(shadow) => {
+                let native = || run_command(state, &shadow.command, &shadow.args);
+                match &state.shadow {
+                    Some(runner) => runner.run(state, shadow, native),
+                    None => native(),
+                }
+            }
+            BuildAction::Compilation(toolchain, task) => {
+                toolchain.compile_task(state, task).and_then(|result| {
+                    cached = result.cached;
+                    retry = result.retry;
+                    note = result.note;
+                    Ok(result.output.with_notes(&task.shared.notes)?)
+                })
+            }
         };
         BuildTaskResult {
             output,
+            cached,
             duration: Instant::now().duration_since(start_time),
+            phases: BTreeMap::new(),
+            retry,
+            note,
         }
     }
 }
@@ -48,9 +219,32 @@ pub enum BuildAction {
     Empty,
     Exec(CommandInfo, CommandArgs),
     Compilation(Arc<dyn Toolchain>, CompilationTask),
+    // Native command with octobuild compilation of the same sources for comparison.
+    Shadow(ShadowTasks),
+}
+
+fn run_command(
+    state: &SharedState,
+    command_info: &CommandInfo,
+    args: &CommandArgs,
+) -> crate::Result<OutputInfo> {
+    state.wrap_slow(|| {
+        state.monitor.phase(TaskPhase::Running);
+        let mut command = command_info.to_command();
+        let temp = state.temp.place(None);
+        let task_temp = state.task_temp_dir(&mut command, &temp)?;
+        args.append_to(&mut command)?;
+        let output = state.monitor.output(&mut command);
+        drop(task_temp);
+        // Command outputs are unknown, it could generate headers for next tasks.
+        state.files.refresh();
+        Ok(OutputInfo::new(output?, &state.output_limits))
+    })
 }
 
 pub struct BuildResult<'a> {
+    // Task id, same as in diagnostics log
+    pub id: usize,
     // Completed task
     pub task: &'a BuildTask,
     // Worker number
@@ -63,6 +257,41 @@ pub struct BuildResult<'a> {
     pub total: usize,
 }
 
+#[derive(Debug, Clone)]
+pub struct TaskFailure {
+    // Failed task title
+    pub title: String,
+    // Task exit code
+    pub status: Option<i32>,
+}
+
+impl fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(code) => write!(f, "{} (exit code: {code})", self.title),
+            None => write!(f, "{} (exit code: unknown)", self.title),
+        }
+    }
+}
+
+#[must_use]
+pub fn failure_exit_code(failures: &[TaskFailure], mode: ExitCodeMode) -> i32 {
+    match mode {
+        ExitCodeMode::FirstFailure => failures
+            .first()
+            .and_then(|failure| failure.status)
+            .unwrap_or(AGGREGATE_EXIT_CODE),
+        ExitCodeMode::Aggregate => {
+            let mut statuses = failures.iter().map(|failure| failure.status);
+            match statuses.next().flatten() {
+                Some(code) if statuses.all(|status| status == Some(code)) => code,
+                _ => AGGREGATE_EXIT_CODE,
+            }
+        }
+        ExitCodeMode::XgconsoleCompatible => AGGREGATE_EXIT_CODE,
+    }
+}
+
 struct ResultMessage {
     index: NodeIndex,
     task: Arc<BuildTask>,
@@ -79,6 +308,7 @@ impl<'a> BuildResult<'a> {
     fn new(message: &'a ResultMessage, completed: &mut usize, total: usize) -> Self {
         *completed += 1;
         BuildResult {
+            id: message.index.index(),
             worker: message.worker,
             task: &message.task,
             result: &message.result,
@@ -89,49 +319,108 @@ impl<'a> BuildResult<'a> {
 
     pub fn print(self) -> crate::Result<()> {
         if let Ok(ref output) = self.result.output {
-            std::io::stdout().write_all(&output.stdout)?;
-            std::io::stderr().write_all(&output.stderr)?;
+            output.stdout.replay(&mut std::io::stdout())?;
+            output.stderr.replay(&mut std::io::stderr())?;
         }
         Ok(())
     }
 }
 
 impl BuildAction {
+    // Compile-and-link invocation ends with native link step, see `take_link`. Without
+    // `split_link` such invocation is executed natively.
     pub fn create_tasks<C: Compiler>(
         compiler: &C,
         command: CommandInfo,
         args: CommandArgs,
         title: &str,
         run_second_cpp: bool,
+        split_link: bool,
+        uncached: &UncachedTasks,
     ) -> Vec<BuildAction> {
-        let actions: Vec<BuildAction> = compiler
+        if let Some(original) = &command.remapped_from {
+            info!(
+                "Task {}: tool {} remapped to {}",
+                escape_control(title),
+                original.display(),
+                command.program.display()
+            );
+        }
+        let tasks = compiler
             .create_tasks(command.clone(), args.clone(), run_second_cpp)
-            .map(|tasks| {
-                tasks
-                    .into_iter()
-                    .map(|task| BuildAction::Compilation(task.toolchain, task.task))
-                    .collect()
-            })
             .unwrap_or_else(|e| {
                 match e {
-                    crate::Error::ToolchainNotFound(_) => {}
+                    // Not a compiler command.
+                    crate::Error::Task(TaskError::ToolchainUnavailable { .. }) => {}
                     e => {
-                        error!("Cannot cache task {title}: {e}");
+                        error!("Cannot cache task {}: {e}", escape_control(title));
+                        uncached.record(&e);
                     }
                 }
                 Vec::new()
             });
-        if actions.is_empty() {
+        let link = tasks.first().and_then(|task| task.task.shared.link.clone());
+        if tasks.is_empty() || (link.is_some() && !split_link) {
+            return vec![BuildAction::Exec(command, args)];
+        }
+        let link = link.map(|link| BuildAction::Exec(command, CommandArgs::Regular(link)));
+        tasks
+            .into_iter()
+            .map(|task| BuildAction::Compilation(task.toolchain, task.task))
+            .chain(link)
+            .collect()
+    }
+
+    // Take native link step that must run after all compilation tasks of the command.
+    pub fn take_link(actions: &mut Vec<BuildAction>) -> Option<BuildAction> {
+        match actions.as_slice() {
+            [BuildAction::Compilation(..), .., BuildAction::Exec(..)] => actions.pop(),
+            _ => None,
+        }
+    }
+
+    // Single native command, compilation tasks of the command are run by octobuild only for comparison.
+    pub fn create_shadow_tasks<C: Compiler>(
+        compiler: &C,
+        command: CommandInfo,
+        args: CommandArgs,
+        title: &str,
+        run_second_cpp: bool,
+        split_link: bool,
+        uncached: &UncachedTasks,
+    ) -> Vec<BuildAction> {
+        let tasks: Vec<(Arc<dyn Toolchain>, CompilationTask)> = BuildAction::create_tasks(
+            compiler,
+            command.clone(),
+            args.clone(),
+            title,
+            run_second_cpp,
+            split_link,
+            uncached,
+        )
+        .into_iter()
+        .filter_map(|action| match action {
+            BuildAction::Compilation(toolchain, task) => Some((toolchain, task)),
+            _ => None,
+        })
+        .collect();
+        if tasks.is_empty() {
             return vec![BuildAction::Exec(command, args)];
         }
-        actions
+        vec![BuildAction::Shadow(ShadowTasks {
+            command,
+            args,
+            tasks,
+        })]
     }
 
     #[must_use]
     pub fn title(&self) -> Cow<str> {
         match &self {
             BuildAction::Empty => Cow::Borrowed(""),
-            BuildAction::Exec(_, args) => Cow::Owned(format!("{args:?}")),
+            BuildAction::Exec(_, args) | BuildAction::Shadow(ShadowTasks { args, .. }) => {
+                Cow::Owned(format!("{args:?}"))
+            }
             BuildAction::Compilation(_, task) => {
                 Cow::Borrowed(task.input_source.to_str().unwrap_or("<stdin>"))
             }
@@ -168,45 +457,203 @@ pub fn validate_graph<N, E>(graph: Graph<N, E>) -> crate::Result<Graph<N, E>> {
     Err(crate::Error::CyclesInBuildGraph)
 }
 
+// Precompiled header built from C++ source can't be used by C source and vice versa, cl fails
+// such compilation with obscure errors. Producer and consumer are separate tasks, so they are
+// checked together once the whole graph is known.
+pub fn validate_pch_languages(graph: &BuildGraph) -> crate::Result<()> {
+    let tasks: Vec<&CompilationTask> = graph
+        .raw_nodes()
+        .iter()
+        .flat_map(|node| node.weight.compilation_tasks())
+        .collect();
+    let producers: HashMap<&Path, &CompilationTask> = tasks
+        .iter()
+        .filter_map(|task| Some((task.shared.pch_usage.get_out_abs()?.as_path(), *task)))
+        .collect();
+    for task in &tasks {
+        let Some((pch, producer)) = task
+            .shared
+            .pch_usage
+            .get_in_abs()
+            .and_then(|pch| Some((pch, producers.get(pch.as_path())?)))
+        else {
+            continue;
+        };
+        if producer.language != task.language {
+            return Err(TaskError::PchLanguageMismatch {
+                pch: pch.clone(),
+                producer: producer.input_source.clone(),
+                producer_language: producer.language.clone(),
+                path: task.input_source.clone(),
+                language: task.language.clone(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// Pass completed compilation task to diagnostic sink.
+fn record_diagnostics(state: &SharedState, message: &ResultMessage) {
+    if let (BuildAction::Compilation(_, task), Ok(output)) =
+        (&message.task.action, &message.result.output)
+    {
+        let record = TaskRecord {
+            task: message.index.index(),
+            source: &task.input_source,
+            output: &task.output_object,
+            outcome: TaskOutcome::new(output, message.result.cached),
+            diagnostics: DiagnosticCounts::from_output(output),
+            retry: message.result.retry,
+            note: message.result.note,
+        };
+        if let Err(e) = state.diagnostics.record(&record) {
+            error!(
+                "Cannot record diagnostics of task {}: {e}",
+                message.task.title
+            );
+        }
+    }
+}
+
+fn record_timing(state: &SharedState, graph: &BuildGraph, message: &ResultMessage) {
+    state.timeline.record(TaskTiming {
+        index: message.index.index(),
+        title: message.task.title.clone(),
+        duration: message.result.duration,
+        cached: message.result.cached,
+        phases: message.result.phases.clone(),
+        deps: graph
+            .neighbors_directed(message.index, EdgeDirection::Outgoing)
+            .map(|dep| dep.index())
+            .collect(),
+    });
+}
+
+// Ready tasks are dispatched in index order. Results are processed in order of task completion
+// or, for deterministic schedule, in dispatch order: result arrived ahead of earlier dispatched
+// task waits for it.
+struct TaskQueue {
+    // Single queue shared by all workers or queue per worker for deterministic schedule.
+    tx_task: Vec<crossbeam_channel::Sender<TaskMessage>>,
+    deterministic: bool,
+    ready: BinaryHeap<Reverse<NodeIndex>>,
+    dispatched: VecDeque<NodeIndex>,
+    arrived: HashMap<NodeIndex, ResultMessage>,
+    results: VecDeque<ResultMessage>,
+}
+
+impl TaskQueue {
+    fn new(tx_task: Vec<crossbeam_channel::Sender<TaskMessage>>, deterministic: bool) -> Self {
+        TaskQueue {
+            tx_task,
+            deterministic,
+            ready: BinaryHeap::new(),
+            dispatched: VecDeque::new(),
+            arrived: HashMap::new(),
+            results: VecDeque::new(),
+        }
+    }
+
+    fn dispatch(&mut self, state: &SharedState, graph: &BuildGraph) -> crate::Result<()> {
+        while let Some(Reverse(index)) = self.ready.pop() {
+            let worker = state
+                .schedule
+                .worker(index.index(), self.tx_task.len())
+                .unwrap_or(0);
+            if self.deterministic {
+                self.dispatched.push_back(index);
+            }
+            state.schedule.record(ScheduleEvent::Dispatch {
+                task: index.index(),
+                worker,
+            });
+            state.monitor.enqueue();
+            self.tx_task[worker]
+                .send(TaskMessage {
+                    index,
+                    task: graph.node_weight(index).unwrap().clone(),
+                })
+                .map_err(crate::Error::send_error)?;
+        }
+        Ok(())
+    }
+
+    fn push_result(&mut self, message: ResultMessage) {
+        if !self.deterministic {
+            self.results.push_back(message);
+            return;
+        }
+        self.arrived.insert(message.index, message);
+        while let Some(message) = self
+            .dispatched
+            .front()
+            .and_then(|index| self.arrived.remove(index))
+        {
+            self.dispatched.pop_front();
+            self.results.push_back(message);
+        }
+    }
+
+    fn pop_result(&mut self) -> Option<ResultMessage> {
+        self.results.pop_front()
+    }
+
+    // Stop dispatching and return results left unprocessed after build failure.
+    fn close(self) -> Vec<ResultMessage> {
+        let mut arrived: Vec<ResultMessage> = self.arrived.into_values().collect();
+        arrived.sort_by_key(|message| message.index);
+        self.results.into_iter().chain(arrived).collect()
+    }
+}
+
 fn execute_until_failed<F>(
+    state: &SharedState,
     graph: &BuildGraph,
-    tx_task: &crossbeam_channel::Sender<TaskMessage>,
+    queue: &mut TaskQueue,
     rx_result: &crossbeam_channel::Receiver<ResultMessage>,
     count: &mut usize,
+    failures: &mut Vec<TaskFailure>,
     update_progress: F,
 ) -> crate::Result<()>
 where
     F: Fn(&BuildResult) -> crate::Result<()>,
 {
     let mut completed: Vec<bool> = vec![false; graph.node_count()];
-    for index in graph.externals(EdgeDirection::Outgoing) {
-        tx_task
-            .send(TaskMessage {
-                index,
-                task: graph.node_weight(index).unwrap().clone(),
-            })
-            .map_err(crate::Error::send_error)?;
-    }
+    queue
+        .ready
+        .extend(graph.externals(EdgeDirection::Outgoing).map(Reverse));
+    queue.dispatch(state, graph)?;
 
     for message in rx_result {
-        assert!(!completed[message.index.index()]);
-
-        update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
-        let output = message.result.output?;
-        if !output.success() {
-            return Err(crate::Error::from("Build failed".to_string()));
-        }
-        completed[message.index.index()] = true;
-
-        for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
-            if is_ready(graph, &completed, source) {
-                tx_task
-                    .send(TaskMessage {
-                        index: source,
-                        task: graph.node_weight(source).unwrap().clone(),
-                    })
-                    .map_err(crate::Error::send_error)?;
+        queue.push_result(message);
+        while let Some(message) = queue.pop_result() {
+            assert!(!completed[message.index.index()]);
+
+            update_progress(&BuildResult::new(&message, count, graph.node_count()))?;
+            record_diagnostics(state, &message);
+            record_timing(state, graph, &message);
+            state.schedule.record(ScheduleEvent::Complete {
+                task: message.index.index(),
+                worker: message.worker,
+            });
+            let output = message.result.output?;
+            if !output.success() {
+                failures.push(TaskFailure {
+                    title: message.task.title.clone(),
+                    status: output.status.code(),
+                });
+                return Ok(());
+            }
+            completed[message.index.index()] = true;
+
+            for source in graph.neighbors_directed(message.index, EdgeDirection::Incoming) {
+                if is_ready(graph, &completed, source) {
+                    queue.ready.push(Reverse(source));
+                }
             }
+            // Dispatch after every result, so dispatch order doesn't depend on result batching.
+            queue.dispatch(state, graph)?;
         }
 
         if *count == completed.len() {
@@ -240,20 +687,32 @@ where
     if graph.node_count() == 0 {
         return Ok(());
     }
+    // Files could change between builds.
+    state.files.refresh();
 
+    let started = Instant::now();
     let (tx_result, rx_result) = crossbeam_channel::unbounded::<ResultMessage>();
-    let (tx_task, rx_task) = crossbeam_channel::unbounded::<TaskMessage>();
     let num_cpus = max(1, min(process_limit, graph.node_count()));
+    let deterministic = state.schedule.is_deterministic();
+    // Deterministic schedule assigns tasks to workers, otherwise idle worker takes next task.
+    let (tx_task, rx_task): (Vec<_>, Vec<_>) = (0..if deterministic { num_cpus } else { 1 })
+        .map(|_| crossbeam_channel::unbounded::<TaskMessage>())
+        .unzip();
     std::thread::scope(|scope| {
         for worker_id in 0..num_cpus {
-            let local_rx_task = rx_task.clone();
+            let local_rx_task = rx_task[worker_id % rx_task.len()].clone();
             let local_tx_result = tx_result.clone();
             scope.spawn(move || {
                 while let Ok(message) = local_rx_task.recv() {
+                    state.etw.task_start(message.index.index(), &message.task);
+                    let result = message.task.execute(state);
+                    state
+                        .etw
+                        .task_stop(message.index.index(), &message.task, &result);
                     match local_tx_result.send(ResultMessage {
                         index: message.index,
                         worker: worker_id,
-                        result: message.task.execute(state),
+                        result,
                         task: message.task,
                     }) {
                         Ok(_) => {}
@@ -265,18 +724,53 @@ where
             });
         }
         drop(tx_result);
+        // Report stalls until all tasks are completed.
+        let (tx_stop, rx_stop) = crossbeam_channel::bounded::<()>(0);
+        scope.spawn(move || Watchdog::new(&state.monitor).run(&state.monitor, &rx_stop));
         // Run all tasks.
         let mut count: usize = 0;
-        let result =
-            execute_until_failed(&graph, &tx_task, &rx_result, &mut count, &update_progress);
+        let mut failures: Vec<TaskFailure> = Vec::new();
+        let mut queue = TaskQueue::new(tx_task, deterministic);
+        let result = execute_until_failed(
+            state,
+            &graph,
+            &mut queue,
+            &rx_result,
+            &mut count,
+            &mut failures,
+            &update_progress,
+        );
         // Cleanup task queue.
-        drop(tx_task);
+        let remaining = queue.close();
         drop(rx_task);
-        // Wait for in progress task completion.
-        for message in rx_result {
+        // Report results held for dispatch order and wait for in progress task completion.
+        for message in remaining.into_iter().chain(rx_result) {
             update_progress(&BuildResult::new(&message, &mut count, graph.node_count()))?;
+            record_diagnostics(state, &message);
+            record_timing(state, &graph, &message);
+            state.schedule.record(ScheduleEvent::Complete {
+                task: message.index.index(),
+                worker: message.worker,
+            });
+            if let Ok(output) = &message.result.output {
+                if !output.success() {
+                    failures.push(TaskFailure {
+                        title: message.task.title.clone(),
+                        status: output.status.code(),
+                    });
+                }
+            }
+        }
+        drop(tx_stop);
+        state.timeline.add_wall_time(started.elapsed());
+        state.etw.rundown(count, &state.statistic);
+        state.files.report_anomalies();
+        result?;
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(crate::Error::BuildFailed(failures))
         }
-        result
     })
 }
 
@@ -285,8 +779,12 @@ mod test {
     use std::sync::{Arc, Mutex};
 
     use crate::compiler::SharedState;
-    use crate::config::Config;
-    use crate::worker::{execute_graph, BuildAction, BuildGraph, BuildTask};
+    use crate::config::{Config, ExitCodeMode};
+    use crate::schedule::ScheduleEvent;
+    use crate::worker::{
+        execute_graph, failure_exit_code, BuildAction, BuildGraph, BuildTask, TaskFailure,
+        AGGREGATE_EXIT_CODE,
+    };
 
     #[test]
     fn test_execute_graph_empty() {
@@ -347,4 +845,152 @@ mod test {
         let actual: Vec<String> = result.lock().unwrap().clone();
         assert_eq!(actual, vec!["task 1".to_string(), "task 2".to_string()]);
     }
+
+    #[test]
+    fn test_execute_graph_deterministic() {
+        let config = Config {
+            deterministic_schedule: true,
+            schedule_seed: 42,
+            ..Config::default()
+        };
+        let run = || {
+            let state = SharedState::new(&config).unwrap();
+            let mut graph = BuildGraph::new();
+            let nodes: Vec<_> = (0..40)
+                .map(|i| {
+                    graph.add_node(Arc::new(BuildTask {
+                        title: format!("task {i}"),
+                        action: BuildAction::Empty,
+                    }))
+                })
+                .collect();
+            for (i, node) in nodes.iter().enumerate().skip(1) {
+                if i % 3 == 0 {
+                    graph.add_edge(*node, nodes[i / 2], ());
+                }
+                if i % 5 == 0 {
+                    graph.add_edge(*node, nodes[i - 1], ());
+                }
+            }
+            let progress = Mutex::new(Vec::new());
+            execute_graph(&state, graph, 4, |r| {
+                progress
+                    .lock()
+                    .unwrap()
+                    .push((r.task.title.clone(), r.worker));
+                Ok(())
+            })
+            .unwrap();
+            (state.schedule.events(), progress.into_inner().unwrap())
+        };
+        let (events, progress) = run();
+        assert_eq!(events.len(), 80);
+        assert_eq!(progress.len(), 40);
+        // Tasks without dependencies are dispatched first, in index order and round-robin.
+        assert_eq!(
+            events[..3],
+            [
+                ScheduleEvent::Dispatch { task: 0, worker: 0 },
+                ScheduleEvent::Dispatch { task: 1, worker: 1 },
+                ScheduleEvent::Dispatch { task: 2, worker: 2 },
+            ]
+        );
+        for _ in 0..10 {
+            assert_eq!(run(), (events.clone(), progress.clone()));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_execute_graph_task_temp_dir() {
+        use crate::compiler::{CommandArgs, CommandInfo};
+        use std::path::PathBuf;
+
+        let state = SharedState::new(&Config::default()).unwrap();
+
+        let mut graph = BuildGraph::new();
+        for title in ["task 1", "task 2"] {
+            graph.add_node(Arc::new(BuildTask {
+                title: title.to_string(),
+                action: BuildAction::Exec(
+                    CommandInfo::simple(PathBuf::from("sh")),
+                    CommandArgs::Regular(vec![
+                        "-c".to_string(),
+                        "echo \"$TMP\"; sleep 0.2".to_string(),
+                    ]),
+                ),
+            }));
+        }
+
+        let result = Mutex::new(Vec::new());
+        execute_graph(&state, graph, 2, |r| {
+            let output = r.result.output.as_ref().unwrap();
+            let tmp = String::from_utf8_lossy(&output.stdout.to_vec().unwrap())
+                .trim()
+                .to_string();
+            result.lock().unwrap().push(PathBuf::from(tmp));
+            Ok(())
+        })
+        .unwrap();
+
+        let actual: Vec<PathBuf> = result.lock().unwrap().clone();
+        assert_eq!(actual.len(), 2);
+        assert_ne!(actual[0], actual[1]);
+        for tmp in actual {
+            assert!(tmp.starts_with(state.temp.primary()));
+            assert!(!tmp.exists());
+        }
+    }
+
+    fn failure(title: &str, status: Option<i32>) -> TaskFailure {
+        TaskFailure {
+            title: title.to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_exit_code_single_category() {
+        let failures = vec![failure("task 1", Some(2)), failure("task 2", Some(2))];
+        assert_eq!(failure_exit_code(&failures, ExitCodeMode::FirstFailure), 2);
+        assert_eq!(failure_exit_code(&failures, ExitCodeMode::Aggregate), 2);
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::XgconsoleCompatible),
+            AGGREGATE_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn test_exit_code_mixed_failures() {
+        let failures = vec![
+            failure("task 1", Some(-1073741819)),
+            failure("task 2", Some(2)),
+            failure("task 3", None),
+        ];
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::FirstFailure),
+            -1073741819
+        );
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::Aggregate),
+            AGGREGATE_EXIT_CODE
+        );
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::XgconsoleCompatible),
+            AGGREGATE_EXIT_CODE
+        );
+    }
+
+    #[test]
+    fn test_exit_code_unknown_status() {
+        let failures = vec![failure("task 1", None), failure("task 2", Some(2))];
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::FirstFailure),
+            AGGREGATE_EXIT_CODE
+        );
+        assert_eq!(
+            failure_exit_code(&failures, ExitCodeMode::Aggregate),
+            AGGREGATE_EXIT_CODE
+        );
+    }
 }
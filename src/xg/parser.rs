@@ -1,6 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fmt::{Display, Formatter};
 use std::io::{Error, ErrorKind, Read};
+use std::ops::Range;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -9,6 +12,8 @@ use thiserror::Error;
 
 use crate::compiler::{CommandEnv, CommandInfo};
 
+use log::{debug, warn};
+
 use petgraph::graph::{Graph, NodeIndex};
 use xml::reader::EventReader;
 use xml::reader::XmlEvent;
@@ -36,6 +41,94 @@ enum XgParseError {
     InvalidStreamFormat,
     #[error("xml reading error: {0}")]
     XmlError(xml::reader::Error),
+    #[error("unsupported encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("invalid {0} text")]
+    InvalidEncoding(&'static str),
+}
+
+// Attribute and element spellings of different UBT versions.
+const ALIASES: [(&str, &str); 2] = [("Parameters", "Params"), ("WorkingDirectory", "WorkingDir")];
+
+// Attributes octobuild knows about, but doesn't use.
+const UNUSED_ATTRS: [(&str, &[&str]); 3] = [
+    ("BuildSet", &["FormatVersion"]),
+    (
+        "Tool",
+        &[
+            "AllowIntercept",
+            "AllowRemote",
+            "AllowRestartOnLocal",
+            "AutoRecover",
+            "AutoReserveMemory",
+            "GroupPrefix",
+            "OutputFileMasks",
+            "SkipIfProjectFailed",
+            "VCCompiler",
+        ],
+    ),
+    (
+        "Task",
+        &["SkipIfProjectFailed", "SourceFile", "StopOnErrors"],
+    ),
+];
+
+/// Elements and attributes of task file ignored by parser, with occurrence count.
+#[derive(Debug, Default)]
+pub struct SchemaDrift {
+    ignored: BTreeMap<String, usize>,
+}
+
+impl SchemaDrift {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.ignored.is_empty()
+    }
+
+    pub fn ignored(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.ignored
+            .iter()
+            .map(|(item, count)| (item.as_str(), *count))
+    }
+
+    fn ignore(&mut self, item: String) {
+        match self.ignored.entry(item) {
+            Entry::Vacant(entry) => {
+                debug!("Ignoring unknown task file item: {}", entry.key());
+                entry.insert(1);
+            }
+            Entry::Occupied(mut entry) => {
+                *entry.get_mut() += 1;
+            }
+        }
+    }
+
+    fn ignore_element(&mut self, parent: &str, name: &str) {
+        self.ignore(format!("{parent}/{name}"));
+    }
+
+    // Record attributes left after parser took everything it needs.
+    fn ignore_attrs(&mut self, element: &str, attrs: HashMap<String, String>) {
+        let unused = UNUSED_ATTRS
+            .iter()
+            .find(|(name, _)| *name == element)
+            .map_or(&[][..], |(_, attrs)| *attrs);
+        for attr in attrs.into_keys() {
+            if !unused.contains(&attr.as_str()) {
+                self.ignore(format!("{element}@{attr}"));
+            }
+        }
+    }
+}
+
+impl Display for SchemaDrift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ignored unknown task file items:")?;
+        for (item, count) in self.ignored() {
+            write!(f, " {item} ({count})")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -44,8 +137,21 @@ struct XgEnvironment {
     tools: HashMap<String, XgTool>,
 }
 
+// Project name words recognized as build configuration.
+const CONFIG_NAMES: [&str; 8] = [
+    "Debug",
+    "DebugGame",
+    "Development",
+    "Shipping",
+    "Test",
+    "Release",
+    "RelWithDebInfo",
+    "MinSizeRel",
+];
+
 #[derive(Debug)]
 struct XgProject {
+    name: Option<String>,
     env: String,
     tasks: HashMap<String, XgTask>,
 }
@@ -65,22 +171,46 @@ struct XgTool {
     output: Option<String>,
 }
 
-pub fn parse<R: Read>(graph: &mut XgGraph, reader: R) -> Result<(), Error> {
-    let mut parser = EventReader::new(reader);
+/// Parse task file to graph.
+///
+/// Unknown elements and attributes are skipped and reported with single warning.
+pub fn parse<R: Read>(graph: &mut XgGraph, mut reader: R) -> Result<SchemaDrift, Error> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let text = decode(&bytes)?;
+    let mut drift = SchemaDrift::default();
+    let mut parser = EventReader::new(text.as_bytes());
     loop {
-        if let XmlEvent::StartElement { name, .. } = next_xml_event(&mut parser)? {
-            return match &name.local_name[..] {
-                "BuildSet" => parse_build_set(graph, &mut parser),
-                _ => Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    XgParseError::InvalidStreamFormat,
-                )),
-            };
+        if let XmlEvent::StartElement {
+            name, attributes, ..
+        } = next_xml_event(&mut parser)?
+        {
+            match &name.local_name[..] {
+                "BuildSet" => {
+                    drift.ignore_attrs("BuildSet", map_attributes(attributes));
+                    parse_build_set(graph, &mut parser, &mut drift)?;
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        XgParseError::InvalidStreamFormat,
+                    ));
+                }
+            }
+            break;
         }
     }
+    if !drift.is_empty() {
+        warn!("{drift}");
+    }
+    Ok(drift)
 }
 
-fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) -> Result<(), Error> {
+fn parse_build_set<R: Read>(
+    graph: &mut XgGraph,
+    events: &mut EventReader<R>,
+    drift: &mut SchemaDrift,
+) -> Result<(), Error> {
     let mut envs: HashMap<String, XgEnvironment> = HashMap::new();
     let mut projects: Vec<XgProject> = Vec::new();
     loop {
@@ -89,16 +219,21 @@ fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) ->
                 name, attributes, ..
             } => match &name.local_name[..] {
                 "Environments" => {
-                    parse_environments(events, &mut envs)?;
+                    parse_environments(events, &mut envs, drift)?;
                 }
                 "Project" => {
                     let mut attrs = map_attributes(attributes);
+                    let name = attrs.remove("Name");
+                    let env = take_attr(&mut attrs, "Env")?;
+                    drift.ignore_attrs("Project", attrs);
                     projects.push(XgProject {
-                        env: take_attr(&mut attrs, "Env")?,
-                        tasks: parse_tasks(events)?,
+                        name,
+                        env,
+                        tasks: parse_tasks(events, drift)?,
                     });
                 }
-                _ => {
+                other => {
+                    drift.ignore_element("BuildSet", other);
                     parse_skip(events, ())?;
                 }
             },
@@ -114,6 +249,7 @@ fn parse_build_set<R: Read>(graph: &mut XgGraph, events: &mut EventReader<R>) ->
 fn parse_environments<R: Read>(
     events: &mut EventReader<R>,
     envs: &mut HashMap<String, XgEnvironment>,
+    drift: &mut SchemaDrift,
 ) -> Result<(), Error> {
     loop {
         match next_xml_event(events)? {
@@ -123,9 +259,11 @@ fn parse_environments<R: Read>(
                 "Environment" => {
                     let mut attrs = map_attributes(attributes);
                     let name = take_attr(&mut attrs, "Name")?;
-                    envs.insert(name, parse_environment(events)?);
+                    drift.ignore_attrs("Environment", attrs);
+                    envs.insert(name, parse_environment(events, drift)?);
                 }
-                _ => {
+                other => {
+                    drift.ignore_element("Environments", other);
                     parse_skip(events, ())?;
                 }
             },
@@ -137,16 +275,22 @@ fn parse_environments<R: Read>(
     }
 }
 
-fn parse_environment<R: Read>(events: &mut EventReader<R>) -> Result<XgEnvironment, Error> {
+fn parse_environment<R: Read>(
+    events: &mut EventReader<R>,
+    drift: &mut SchemaDrift,
+) -> Result<XgEnvironment, Error> {
     let mut variables = env::vars().collect();
     let mut tools = HashMap::new();
     loop {
         match next_xml_event(events)? {
             XmlEvent::StartElement { name, .. } => {
                 match &name.local_name[..] {
-                    "Variables" => parse_variables(events, &mut variables)?,
-                    "Tools" => parse_tools(events, &mut tools)?,
-                    _ => parse_skip(events, ())?,
+                    "Variables" => parse_variables(events, &mut variables, drift)?,
+                    "Tools" => parse_tools(events, &mut tools, drift)?,
+                    other => {
+                        drift.ignore_element("Environment", other);
+                        parse_skip(events, ())?;
+                    }
                 };
             }
             XmlEvent::EndElement { .. } => {
@@ -164,6 +308,7 @@ fn parse_environment<R: Read>(events: &mut EventReader<R>) -> Result<XgEnvironme
 fn parse_variables<R: Read>(
     events: &mut EventReader<R>,
     variables: &mut CommandEnv,
+    drift: &mut SchemaDrift,
 ) -> Result<(), Error> {
     loop {
         match next_xml_event(events)? {
@@ -174,9 +319,13 @@ fn parse_variables<R: Read>(
                     let mut attrs = map_attributes(attributes);
                     let name = take_attr(&mut attrs, "Name")?;
                     let value = take_attr(&mut attrs, "Value")?;
+                    drift.ignore_attrs("Variable", attrs);
                     variables.insert(name, value);
+                    parse_unknown(events, drift, "Variable")?;
+                } else {
+                    drift.ignore_element("Variables", &name.local_name);
+                    parse_skip(events, ())?;
                 }
-                parse_skip(events, ())?;
             }
             XmlEvent::EndElement { .. } => {
                 return Ok(());
@@ -189,6 +338,7 @@ fn parse_variables<R: Read>(
 fn parse_tools<R: Read>(
     events: &mut EventReader<R>,
     tools: &mut HashMap<String, XgTool>,
+    drift: &mut SchemaDrift,
 ) -> Result<(), Error> {
     loop {
         match next_xml_event(events)? {
@@ -199,16 +349,18 @@ fn parse_tools<R: Read>(
                     let mut attrs = map_attributes(attributes);
                     let name = take_attr(&mut attrs, "Name")?;
                     let exec = take_attr(&mut attrs, "Path")?;
-                    tools.insert(
-                        name,
-                        XgTool {
-                            exec: PathBuf::from(&exec),
-                            output: attrs.remove("OutputPrefix"),
-                            args: Rc::new(attrs.remove("Params").unwrap_or_default()),
-                        },
-                    );
+                    let params = parse_tool_params(events, drift)?;
+                    let tool = XgTool {
+                        exec: PathBuf::from(&exec),
+                        output: attrs.remove("OutputPrefix"),
+                        args: Rc::new(attrs.remove("Params").or(params).unwrap_or_default()),
+                    };
+                    drift.ignore_attrs("Tool", attrs);
+                    tools.insert(name, tool);
+                } else {
+                    drift.ignore_element("Tools", &name.local_name);
+                    parse_skip(events, ())?;
                 }
-                parse_skip(events, ())?;
             }
             XmlEvent::EndElement { .. } => {
                 return Ok(());
@@ -218,7 +370,53 @@ fn parse_tools<R: Read>(
     }
 }
 
-fn parse_tasks<R: Read>(events: &mut EventReader<R>) -> Result<HashMap<String, XgTask>, Error> {
+// Params can be written as child element, usually as CDATA section with literal newlines.
+fn parse_tool_params<R: Read>(
+    events: &mut EventReader<R>,
+    drift: &mut SchemaDrift,
+) -> Result<Option<String>, Error> {
+    let mut params = None;
+    loop {
+        match next_xml_event(events)? {
+            XmlEvent::StartElement { name, .. } => {
+                if canonical_name(&name.local_name) == "Params" {
+                    params = Some(parse_text(events)?);
+                } else {
+                    drift.ignore_element("Tool", &name.local_name);
+                    parse_skip(events, ())?;
+                }
+            }
+            XmlEvent::EndElement { .. } => {
+                return Ok(params);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Element text exactly as written: whitespace and CDATA content are kept.
+fn parse_text<R: Read>(events: &mut EventReader<R>) -> Result<String, Error> {
+    let mut text = String::new();
+    loop {
+        match next_xml_event(events)? {
+            XmlEvent::Characters(value) | XmlEvent::CData(value) | XmlEvent::Whitespace(value) => {
+                text.push_str(&value);
+            }
+            XmlEvent::StartElement { .. } => {
+                parse_skip(events, ())?;
+            }
+            XmlEvent::EndElement { .. } => {
+                return Ok(text);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_tasks<R: Read>(
+    events: &mut EventReader<R>,
+    drift: &mut SchemaDrift,
+) -> Result<HashMap<String, XgTask>, Error> {
     let mut tasks = HashMap::new();
     loop {
         match next_xml_event(events)? {
@@ -236,17 +434,19 @@ fn parse_tasks<R: Read>(events: &mut EventReader<R>) -> Result<HashMap<String, X
                         _ => HashSet::new(),
                     };
 
-                    tasks.insert(
-                        name.clone(),
-                        XgTask {
-                            title: attrs.remove("Caption"),
-                            tool,
-                            working_dir: PathBuf::from(&working_dir),
-                            depends_on: depends_on.into_iter().collect::<Vec<String>>(),
-                        },
-                    );
+                    let task = XgTask {
+                        title: attrs.remove("Caption"),
+                        tool,
+                        working_dir: PathBuf::from(&working_dir),
+                        depends_on: depends_on.into_iter().collect::<Vec<String>>(),
+                    };
+                    drift.ignore_attrs("Task", attrs);
+                    tasks.insert(name, task);
+                    parse_unknown(events, drift, "Task")?;
+                } else {
+                    drift.ignore_element("Project", &name.local_name);
+                    parse_skip(events, ())?;
                 }
-                parse_skip(events, ())?;
             }
             XmlEvent::EndElement { .. } => {
                 return Ok(tasks);
@@ -256,6 +456,26 @@ fn parse_tasks<R: Read>(events: &mut EventReader<R>) -> Result<HashMap<String, X
     }
 }
 
+// Skip content of element, which is not expected to have children.
+fn parse_unknown<R: Read>(
+    events: &mut EventReader<R>,
+    drift: &mut SchemaDrift,
+    parent: &str,
+) -> Result<(), Error> {
+    loop {
+        match next_xml_event(events)? {
+            XmlEvent::StartElement { name, .. } => {
+                drift.ignore_element(parent, &name.local_name);
+                parse_skip(events, ())?;
+            }
+            XmlEvent::EndElement { .. } => {
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
 fn parse_skip<R: Read, T>(events: &mut EventReader<R>, result: T) -> Result<T, Error> {
     let mut depth: isize = 0;
     loop {
@@ -305,6 +525,7 @@ fn graph_project(
 ) -> Result<(), Error> {
     let mut nodes: Vec<NodeIndex> = Vec::new();
     let mut task_refs: HashMap<&str, NodeIndex> = HashMap::new();
+    let config = project.name.as_deref().and_then(project_config);
     for (id, task) in &project.tasks {
         let tool = env.tools.get(&task.tool).ok_or_else(|| {
             Error::new(
@@ -323,6 +544,8 @@ fn graph_project(
                 current_dir: Some(task.working_dir.clone()),
                 // Environment variables
                 env: env.variables.clone(),
+                remapped_from: None,
+                config: config.clone(),
             },
             raw_args: tool.args.clone(),
         });
@@ -344,11 +567,128 @@ fn graph_project(
     Ok(())
 }
 
+// Find build configuration word in project name, like "Game Win64 Shipping".
+fn project_config(name: &str) -> Option<String> {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .find_map(|word| {
+            CONFIG_NAMES
+                .iter()
+                .find(|config| config.eq_ignore_ascii_case(word))
+        })
+        .map(ToString::to_string)
+}
+
+fn canonical_name(name: &str) -> &str {
+    ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, canonical)| canonical)
+}
+
+// Attribute names are canonical, canonical spelling wins over alias.
 fn map_attributes(attributes: Vec<xml::attribute::OwnedAttribute>) -> HashMap<String, String> {
-    attributes
-        .into_iter()
-        .map(|v| (v.name.local_name, v.value))
-        .collect()
+    let mut attrs = HashMap::new();
+    for attr in attributes {
+        let name = attr.name.local_name;
+        match canonical_name(&name) {
+            canonical if canonical == name => {
+                attrs.insert(name, attr.value);
+            }
+            canonical => {
+                attrs.entry(canonical.to_string()).or_insert(attr.value);
+            }
+        }
+    }
+    attrs
+}
+
+// Decode task file text: encoding is detected by BOM, zero bytes of UTF-16 or XML declaration.
+fn decode(bytes: &[u8]) -> Result<String, Error> {
+    let text = match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => decode_utf8(rest)?,
+        [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes)?,
+        [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes)?,
+        [b'<', 0, ..] => decode_utf16(bytes, u16::from_le_bytes)?,
+        [0, b'<', ..] => decode_utf16(bytes, u16::from_be_bytes)?,
+        _ => {
+            let prefix = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]);
+            let encoding = declared_encoding(&prefix).map(|(_, v)| v.to_ascii_lowercase());
+            match encoding.as_deref() {
+                // Declaration of UTF-16 without zero bytes comes from text saved via StringWriter.
+                None | Some("utf-8" | "utf8" | "us-ascii" | "utf-16" | "unicode") => {
+                    decode_utf8(bytes)?
+                }
+                Some("iso-8859-1" | "latin1") => bytes.iter().map(|&b| char::from(b)).collect(),
+                Some("windows-1252" | "cp1252") => bytes.iter().map(|&b| cp1252(b)).collect(),
+                Some(other) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        XgParseError::UnsupportedEncoding(other.to_string()),
+                    ));
+                }
+            }
+        }
+    };
+    // Text is already decoded, so declared encoding must not be applied by XML reader again.
+    Ok(match declared_encoding(&text) {
+        Some((range, _)) => format!("{}{}", &text[..range.start], &text[range.end..]),
+        None => text,
+    })
+}
+
+fn decode_utf8(bytes: &[u8]) -> Result<String, Error> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            XgParseError::InvalidEncoding("UTF-8"),
+        )
+    })
+}
+
+fn decode_utf16(bytes: &[u8], code: fn([u8; 2]) -> u16) -> Result<String, Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            XgParseError::InvalidEncoding("UTF-16"),
+        )
+    };
+    let chunks = bytes.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return Err(invalid());
+    }
+    char::decode_utf16(chunks.map(|c| code([c[0], c[1]])))
+        .collect::<Result<String, _>>()
+        .map_err(|_| invalid())
+}
+
+fn cp1252(byte: u8) -> char {
+    const HIGH: [char; 32] = [
+        '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}',
+        '\u{8F}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}',
+        '\u{2014}', '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}',
+        '\u{178}',
+    ];
+    match byte {
+        0x80..=0x9F => HIGH[usize::from(byte - 0x80)],
+        _ => char::from(byte),
+    }
+}
+
+// Find encoding pseudo-attribute of XML declaration: its range in text and value.
+fn declared_encoding(text: &str) -> Option<(Range<usize>, &str)> {
+    let decl = &text[..text.find("?>")?];
+    if !decl.starts_with("<?xml") {
+        return None;
+    }
+    let start = decl.find("encoding")?;
+    let rest = decl[start + "encoding".len()..].trim_start();
+    let rest = rest.strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let value = &rest[1..];
+    let len = value.find(quote)?;
+    let end = decl.len() - value.len() + len + 1;
+    Some((start..end, &value[..len]))
 }
 
 fn take_attr(attrs: &mut HashMap<String, String>, attr: &'static str) -> Result<String, Error> {
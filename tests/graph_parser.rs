@@ -1,7 +1,8 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::BufReader;
 use std::path::PathBuf;
 
+use octobuild::xg::parser::XgGraph;
 use petgraph::Graph;
 
 #[test]
@@ -11,5 +12,230 @@ fn test_parse_smoke() {
         .unwrap()
         .join(PathBuf::from("graph-parser.xml"));
     let reader = BufReader::new(File::open(f).unwrap());
-    octobuild::xg::parser::parse(&mut Graph::new(), reader).unwrap();
+    let drift = octobuild::xg::parser::parse(&mut Graph::new(), reader).unwrap();
+    assert!(drift.is_empty());
+}
+
+// Task per line: title, program, working directory, configuration, arguments and dependencies.
+fn dump_graph(graph: &XgGraph) -> String {
+    let mut lines: Vec<String> = graph
+        .node_indices()
+        .map(|index| {
+            let node = &graph[index];
+            let mut deps: Vec<&str> = graph
+                .neighbors(index)
+                .map(|dep| graph[dep].title.as_str())
+                .collect();
+            deps.sort_unstable();
+            format!(
+                "{} | {} | {} | {} | {} | [{}]",
+                node.title,
+                node.command.program.display(),
+                node.command.current_dir.as_ref().unwrap().display(),
+                node.command.config.as_deref().unwrap_or("-"),
+                node.raw_args,
+                deps.join(", ")
+            )
+        })
+        .collect();
+    lines.sort();
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+fn parse_golden(name: &str) -> Vec<(String, usize)> {
+    let dir = PathBuf::from(file!()).parent().unwrap().join("xg");
+    let file = File::open(dir.join(format!("{name}.xml"))).unwrap();
+    let mut graph = Graph::new();
+    let drift = octobuild::xg::parser::parse(&mut graph, BufReader::new(file)).unwrap();
+    let golden = fs::read_to_string(dir.join(format!("{name}.golden"))).unwrap();
+    assert_eq!(dump_graph(&graph), golden.replace("\r\n", "\n"));
+    drift
+        .ignored()
+        .map(|(item, count)| (item.to_string(), count))
+        .collect()
+}
+
+#[test]
+fn test_parse_golden_ue4_27() {
+    // UTF-8 with BOM.
+    assert_eq!(parse_golden("ue4.27"), vec![]);
+}
+
+#[test]
+fn test_parse_golden_ue5_1() {
+    // UTF-16 with BOM.
+    assert_eq!(parse_golden("ue5.1"), vec![]);
+}
+
+#[test]
+fn test_parse_golden_ue5_3() {
+    // UTF-8 with UTF-16 declaration, Parameters spelling and unknown items.
+    assert_eq!(
+        parse_golden("ue5.3"),
+        vec![
+            ("BuildSet/Metadata".to_string(), 1),
+            ("Tool@MemoryHint".to_string(), 3),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_encoding() {
+    let xml = |encoding: &str, caption: &str| {
+        format!(
+            r#"<?xml version="1.0" encoding="{encoding}"?>
+<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Tools>
+        <Tool Name="Tool0" Path="cl.exe" Params="/c a.cpp" />
+      </Tools>
+    </Environment>
+  </Environments>
+  <Project Name="Default" Env="Default">
+    <Task Caption="{caption}" Name="Action0" Tool="Tool0" WorkingDir="." />
+  </Project>
+</BuildSet>"#
+        )
+    };
+    let title = |bytes: Vec<u8>| {
+        let mut graph = Graph::new();
+        octobuild::xg::parser::parse(&mut graph, &bytes[..]).unwrap();
+        graph.node_weights().next().unwrap().title.clone()
+    };
+    let utf16be: Vec<u8> = xml("utf-16", "Сборка")
+        .encode_utf16()
+        .flat_map(u16::to_be_bytes)
+        .collect();
+    assert_eq!(title(utf16be), "Сборка");
+    let cp1252: Vec<u8> = xml("windows-1252", "\u{80}\u{E9}")
+        .chars()
+        .map(|c| c as u8)
+        .collect();
+    assert_eq!(title(cp1252), "€é");
+    let latin1: Vec<u8> = xml("ISO-8859-1", "é").chars().map(|c| c as u8).collect();
+    assert_eq!(title(latin1), "é");
+    let koi8 = xml("koi8-r", "a").into_bytes();
+    assert!(octobuild::xg::parser::parse(&mut Graph::new(), &koi8[..]).is_err());
+}
+
+#[test]
+fn test_parse_attribute_alias() {
+    let xml = r#"<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Tools>
+        <Tool Name="Tool0" Path="cl.exe" Parameters="/c a.cpp" />
+        <Tool Name="Tool1" Path="cl.exe" Params="/c b.cpp" Parameters="/c c.cpp" />
+        <Tool Name="Tool2" Path="cl.exe"><Parameters>/c d.cpp</Parameters></Tool>
+      </Tools>
+    </Environment>
+  </Environments>
+  <Project Name="Default" Env="Default">
+    <Task Caption="a" Name="Action0" Tool="Tool0" WorkingDirectory="." Hint="x" />
+    <Task Caption="b" Name="Action1" Tool="Tool1" WorkingDir="." />
+    <Task Caption="d" Name="Action2" Tool="Tool2" WorkingDir="."><Hint /></Task>
+  </Project>
+</BuildSet>"#;
+    let mut graph = Graph::new();
+    let drift = octobuild::xg::parser::parse(&mut graph, xml.as_bytes()).unwrap();
+    let args = |title: &str| {
+        graph
+            .node_weights()
+            .find(|node| node.title == title)
+            .unwrap()
+            .raw_args
+            .to_string()
+    };
+    assert_eq!(args("a"), "/c a.cpp");
+    assert_eq!(args("b"), "/c b.cpp");
+    assert_eq!(args("d"), "/c d.cpp");
+    assert_eq!(
+        drift.ignored().collect::<Vec<_>>(),
+        vec![("Task/Hint", 1), ("Task@Hint", 1)]
+    );
+    assert_eq!(
+        drift.to_string(),
+        "Ignored unknown task file items: Task/Hint (1) Task@Hint (1)"
+    );
+}
+
+#[test]
+fn test_parse_params_cdata() {
+    let params =
+        "/c /D \"MESSAGE=line1\nline2\"\t/D TAB=\"a\tb\"\n/D CTRL=\"\u{7f}\u{80}\" a.cpp\n";
+    let xml = format!(
+        r#"<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Tools>
+        <Tool Name="Tool0" Path="cl.exe"><Params><![CDATA[{params}]]></Params></Tool>
+        <Tool Name="Tool1" Path="link.exe" Params="/OUT:a.exe" />
+      </Tools>
+    </Environment>
+  </Environments>
+  <Project Name="Default" Env="Default">
+    <Task Caption="a.cpp" Name="Action0" Tool="Tool0" WorkingDir="." />
+    <Task Caption="a.exe" Name="Action1" Tool="Tool1" WorkingDir="." DependsOn="Action0" />
+  </Project>
+</BuildSet>"#
+    );
+    let mut graph = Graph::new();
+    octobuild::xg::parser::parse(&mut graph, xml.as_bytes()).unwrap();
+    let args = |title: &str| {
+        graph
+            .node_weights()
+            .find(|node| node.title == title)
+            .unwrap()
+            .raw_args
+            .to_string()
+    };
+    assert_eq!(args("a.cpp"), params);
+    assert_eq!(args("a.exe"), "/OUT:a.exe");
+}
+
+#[test]
+fn test_parse_project_config() {
+    let project = |name: &str| {
+        format!(
+            r#"<Project Name="{name}" Env="Default">
+    <Task Caption="{name}" Name="Action0" Tool="Tool0" WorkingDir="." />
+  </Project>"#
+        )
+    };
+    let xml = format!(
+        r#"<BuildSet FormatVersion="1">
+  <Environments>
+    <Environment Name="Default">
+      <Tools>
+        <Tool Name="Tool0" Path="cl.exe" Params="/c a.cpp" />
+      </Tools>
+    </Environment>
+  </Environments>
+  {}
+  {}
+  {}
+  {}
+</BuildSet>"#,
+        project("Default"),
+        project("Game Win64 Shipping"),
+        project("Editor|debug|x64"),
+        project("UnitTests"),
+    );
+    let mut graph = Graph::new();
+    octobuild::xg::parser::parse(&mut graph, xml.as_bytes()).unwrap();
+    let config = |title: &str| {
+        graph
+            .node_weights()
+            .find(|node| node.title == title)
+            .unwrap()
+            .command
+            .config
+            .clone()
+    };
+    assert_eq!(config("Default"), None);
+    assert_eq!(config("Game Win64 Shipping").as_deref(), Some("Shipping"));
+    assert_eq!(config("Editor|debug|x64").as_deref(), Some("Debug"));
+    // Only whole words are recognized.
+    assert_eq!(config("UnitTests"), None);
 }
@@ -0,0 +1,1993 @@
+// Drives the whole build pipeline against fake cl.exe from tests/support/fake_cl.rs,
+// so it works on machines without Visual Studio.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use octobuild::compiler::{CommandArgs, CommandInfo, SharedState, TaskError};
+use octobuild::config::{
+    AnnotationFormat, Config, ExitCodeMode, OutputCheck, PhaseProgram, PreprocessTransform,
+    ToolRemap, ToolchainPhases,
+};
+use octobuild::diagnostics::TaskOutcome;
+use octobuild::exitreport::{ExitCategory, ExitReport};
+use octobuild::manifest::BuildManifest;
+use octobuild::prewarm::PrewarmStatistic;
+use octobuild::provenance::{
+    audit_objects, read_stamp, sidecar_path, ProvenanceLocation, STAMP_SECTION,
+};
+use octobuild::vs::coff::{CoffObject, IMAGE_SCN_LNK_REMOVE};
+use octobuild::vs::compiler::VsCompiler;
+use octobuild::warmup::WarmupOutcome;
+use octobuild::worker::{
+    execute_graph, failure_exit_code, validate_pch_languages, BuildAction, BuildGraph, BuildTask,
+    UncachedTasks, AGGREGATE_EXIT_CODE,
+};
+
+struct Fixture {
+    dir: tempfile::TempDir,
+    // Variables added to environment of recorded commands.
+    env: RefCell<Vec<(String, String)>>,
+}
+
+struct BuildOutput {
+    result: octobuild::Result<()>,
+    stdout: String,
+    // CI annotations printed for completed tasks.
+    annotations: String,
+    hits: usize,
+    misses: usize,
+    // Failures recorded like by console tools.
+    report: ExitReport,
+}
+
+impl Fixture {
+    fn new(files: &[(&str, &str)]) -> Self {
+        let fixture = Fixture {
+            dir: tempfile::tempdir().unwrap(),
+            env: RefCell::default(),
+        };
+        fs::create_dir_all(fixture.bin()).unwrap();
+        fs::copy(env!("CARGO_BIN_EXE_fake_cl"), fixture.cl()).unwrap();
+        for (name, content) in files {
+            fixture.write(name, content);
+        }
+        fixture
+    }
+
+    fn bin(&self) -> PathBuf {
+        self.dir.path().join("bin")
+    }
+
+    fn cl(&self) -> PathBuf {
+        self.bin().join("cl.exe")
+    }
+
+    fn src(&self) -> PathBuf {
+        self.dir.path().join("src")
+    }
+
+    fn write(&self, name: &str, content: &str) {
+        let path = self.src().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn control(&self, content: &str) {
+        fs::write(self.bin().join("fake_cl.conf"), content).unwrap();
+    }
+
+    // Fake compiler invocations since last call.
+    fn take_log(&self) -> Vec<String> {
+        let path = self.bin().join("fake_cl.log");
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        drop(fs::remove_file(path));
+        content.lines().map(str::to_string).collect()
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            cache: self.dir.path().join("cache"),
+            process_limit: 1,
+            ..Config::default()
+        }
+    }
+
+    fn build(&self, config: &Config, commands: &[&[&str]]) -> BuildOutput {
+        let state = SharedState::new(config).unwrap();
+        self.build_with(&state, config, commands)
+    }
+
+    fn build_with(
+        &self,
+        state: &SharedState,
+        config: &Config,
+        commands: &[&[&str]],
+    ) -> BuildOutput {
+        self.execute(state, config, commands, true)
+    }
+
+    // Build commands without dependencies between them, so they run concurrently.
+    fn build_parallel(&self, config: &Config, commands: &[&[&str]]) -> BuildOutput {
+        let state = SharedState::new(config).unwrap();
+        self.execute(&state, config, commands, false)
+    }
+
+    fn execute(
+        &self,
+        state: &SharedState,
+        config: &Config,
+        commands: &[&[&str]],
+        chained: bool,
+    ) -> BuildOutput {
+        let mut graph = BuildGraph::new();
+        let mut prev = None;
+        for args in commands {
+            let command = CommandInfo {
+                program: self.cl(),
+                current_dir: Some(self.src()),
+                env: Arc::new(env::vars().chain(self.env.borrow().clone()).collect()),
+                remapped_from: None,
+                config: None,
+            }
+            .remap(&config.tool_remap);
+            let mut actions = BuildAction::create_tasks(
+                &VsCompiler::default(),
+                command,
+                CommandArgs::Regular(args.iter().map(|arg| arg.to_string()).collect()),
+                args.last().unwrap(),
+                config.run_second_cpp,
+                config.split_link,
+                &state.uncached,
+            );
+            state.warmup.start_actions(&actions);
+            let link = BuildAction::take_link(&mut actions);
+            let mut compiled = Vec::new();
+            for action in actions {
+                assert!(matches!(action, BuildAction::Compilation(..)));
+                let node = graph.add_node(Arc::new(BuildTask {
+                    title: args.last().unwrap().to_string(),
+                    action,
+                }));
+                // Keep command order to make precompiled header available for its users.
+                if let (Some(prev), true) = (prev, chained) {
+                    graph.add_edge(node, prev, ());
+                }
+                prev = Some(node);
+                compiled.push(node);
+            }
+            if let Some(action) = link {
+                let node = graph.add_node(Arc::new(BuildTask {
+                    title: args.last().unwrap().to_string(),
+                    action,
+                }));
+                for index in compiled {
+                    graph.add_edge(node, index, ());
+                }
+                prev = Some(node);
+            }
+        }
+        let stdout = Mutex::new(String::new());
+        let annotations = Mutex::new(Vec::new());
+        let report = ExitReport::default();
+        let result = execute_graph(state, graph, config.process_limit, |r| {
+            report.record_task(r);
+            if let Ok(output) = &r.result.output {
+                stdout
+                    .lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&output.stdout.to_vec().unwrap()));
+            }
+            state
+                .annotations
+                .annotate(&mut *annotations.lock().unwrap(), r)
+        });
+        if let Err(e) = &result {
+            report.record_error(e);
+        }
+        BuildOutput {
+            result,
+            stdout: stdout.into_inner().unwrap(),
+            annotations: String::from_utf8(annotations.into_inner().unwrap()).unwrap(),
+            hits: state.statistic.hit_count.load(Ordering::Relaxed),
+            misses: state.statistic.miss_count.load(Ordering::Relaxed),
+            report,
+        }
+    }
+
+    fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.src().join(name)).unwrap()
+    }
+
+    // Content of `.text` section of object file written by fake compiler.
+    fn object_text(&self, name: &str) -> String {
+        let data = fs::read(self.src().join(name)).unwrap();
+        let object = CoffObject::parse(&data).unwrap();
+        let text = object.section_data(object.section(".text").unwrap());
+        String::from_utf8(text.to_vec()).unwrap()
+    }
+}
+
+#[test]
+fn test_compile_and_cache_hit() {
+    for run_second_cpp in [false, true] {
+        let fixture = Fixture::new(&[
+            ("a.cpp", "#include \"a.h\"\nint main() { return A; }\n"),
+            ("a.h", "#define A 0\n"),
+        ]);
+        let config = Config {
+            run_second_cpp,
+            ..fixture.config()
+        };
+        let command: &[&str] = &["/c", "/nologo", "/Foa.obj", "a.cpp"];
+
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_eq!((output.hits, output.misses), (0, 1));
+        assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+        let object = fixture.object_text("a.obj");
+        assert!(object.starts_with("FAKEOBJ "));
+
+        // Object file is restored from cache without running compiler.
+        fs::remove_file(fixture.src().join("a.obj")).unwrap();
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_eq!((output.hits, output.misses), (1, 0));
+        assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+        assert_eq!(fixture.object_text("a.obj"), object);
+
+        // Header change invalidates cache.
+        fixture.write("a.h", "#define A 1\n");
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_eq!((output.hits, output.misses), (0, 1));
+        assert_ne!(fixture.object_text("a.obj"), object);
+    }
+}
+
+#[test]
+fn test_command_environment() {
+    for run_second_cpp in [false, true] {
+        let fixture = Fixture::new(&[
+            ("a.cpp", "#include <sdk.h>\nint main() { return SDK; }\n"),
+            ("sdk/sdk.h", "#define SDK 0\n"),
+        ]);
+        let config = Config {
+            run_second_cpp,
+            ..fixture.config()
+        };
+        let command: &[&str] = &["/c", "/nologo", "/Foa.obj", "a.cpp"];
+
+        // Header is found only through INCLUDE of the recorded command, not of octobuild.
+        let output = fixture.build(&config, &[command]);
+        assert!(output.result.is_err());
+        assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+
+        fixture.env.borrow_mut().push((
+            "INCLUDE".to_string(),
+            fixture.src().join("sdk").to_string_lossy().into_owned(),
+        ));
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+        // Object compiled from source also sees the header.
+        fixture.write("sdk/sdk.h", "#define SDK 1\n");
+        let object = fixture.object_text("a.obj");
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_ne!(fixture.object_text("a.obj"), object);
+    }
+}
+
+#[test]
+fn test_toolchain_phases() {
+    for run_second_cpp in [false, true] {
+        let fixture = Fixture::new(&[("a.cpp", "int main() { return 0; }\n")]);
+        // Every copy of fake compiler logs invocations to its own directory.
+        let phase_program = |name: &str, args: &[&str]| {
+            let dir = fixture.dir.path().join(name);
+            fs::create_dir_all(&dir).unwrap();
+            let program = dir.join("fake_cl.exe");
+            fs::copy(env!("CARGO_BIN_EXE_fake_cl"), &program).unwrap();
+            PhaseProgram {
+                program,
+                args: args.iter().map(ToString::to_string).collect(),
+            }
+        };
+        let take_log = |name: &str| {
+            let path = fixture.dir.path().join(name).join("fake_cl.log");
+            let content = fs::read_to_string(&path).unwrap_or_default();
+            drop(fs::remove_file(path));
+            content.lines().map(str::to_string).collect::<Vec<_>>()
+        };
+        let phases = |compiler_args: &[&str]| ToolchainPhases {
+            toolchain: "bin/cl.exe".to_string(),
+            preprocessor: Some(phase_program("front", &["/nologo"])),
+            compiler: Some(phase_program("driver", compiler_args)),
+        };
+        let config = Config {
+            run_second_cpp,
+            toolchain_phases: vec![phases(&[])],
+            ..fixture.config()
+        };
+        let command: &[&str] = &["/c", "/nologo", "/Foa.obj", "a.cpp"];
+
+        let output = fixture.build(&config, &[command]);
+        output.result.unwrap();
+        assert_eq!((output.hits, output.misses), (0, 1));
+        assert_eq!(take_log("front"), ["preprocess a.cpp"]);
+        assert_eq!(take_log("driver"), ["compile a.cpp"]);
+        assert!(fixture.take_log().is_empty());
+
+        let output = fixture.build(&config, &[command]);
+        assert_eq!((output.hits, output.misses), (1, 0));
+
+        // Arguments of phase program are a part of toolchain identity.
+        let config = Config {
+            toolchain_phases: vec![phases(&["/bigobj"])],
+            ..config
+        };
+        let output = fixture.build(&config, &[command]);
+        assert_eq!((output.hits, output.misses), (0, 1));
+        assert_eq!(take_log("front"), ["preprocess a.cpp", "preprocess a.cpp"]);
+        assert_eq!(take_log("driver"), ["compile a.cpp"]);
+
+        // Without phase rule the toolchain compiler runs both phases.
+        let config = Config {
+            toolchain_phases: Vec::new(),
+            ..config
+        };
+        let output = fixture.build(&config, &[command]);
+        assert_eq!((output.hits, output.misses), (0, 1));
+        assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+        assert!(take_log("front").is_empty());
+    }
+}
+
+#[test]
+fn test_compile_and_link() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "int main() { return 0; }\n"),
+        ("lib/b.cpp", "int b() { return 0; }\n"),
+    ]);
+    let config = fixture.config();
+    let command: &[&str] = &["/nologo", "a.cpp", "lib/b.cpp", "/Fe:app.exe"];
+
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (0, 2));
+    assert_eq!(
+        fixture.take_log(),
+        [
+            "preprocess a.cpp",
+            "compile a.cpp",
+            "preprocess b.cpp",
+            "compile b.cpp",
+            "link a.obj b.obj"
+        ]
+    );
+    // Objects are written to current directory like cl does.
+    assert!(fixture.src().join("b.obj").is_file());
+    assert!(!fixture.src().join("lib/b.obj").exists());
+    assert!(fixture.read("app.exe").starts_with("FAKEEXE\na.obj "));
+
+    // Compilation is taken from cache, link step always runs.
+    fs::remove_file(fixture.src().join("app.exe")).unwrap();
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (2, 0));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "preprocess b.cpp", "link a.obj b.obj"]
+    );
+    assert!(fixture.src().join("app.exe").is_file());
+
+    // Without /Fe executable is named after the first source.
+    let output = fixture.build(&config, &[&["/nologo", "a.cpp", "lib/b.cpp"]]);
+    output.result.unwrap();
+    assert!(fixture.src().join("a.exe").is_file());
+    fixture.take_log();
+
+    // Failed compilation fails the command without link step.
+    fixture.control("b.cpp exit 2\n");
+    fixture.write("lib/b.cpp", "int b() { return 1; }\n");
+    let output = fixture.build(&config, &[command]);
+    assert!(output.result.is_err());
+    assert!(!fixture
+        .take_log()
+        .iter()
+        .any(|line| line.starts_with("link")));
+
+    // Without split the whole invocation is executed natively.
+    let command = CommandInfo {
+        program: fixture.cl(),
+        current_dir: Some(fixture.src()),
+        env: Arc::new(env::vars().collect()),
+        remapped_from: None,
+        config: None,
+    };
+    let uncached = UncachedTasks::new();
+    let create = |args: &[&str]| {
+        BuildAction::create_tasks(
+            &VsCompiler::default(),
+            command.clone(),
+            CommandArgs::Regular(args.iter().map(|arg| arg.to_string()).collect()),
+            "app.exe",
+            config.run_second_cpp,
+            false,
+            &uncached,
+        )
+    };
+    let actions = create(&["a.cpp", "/Fe:app.exe"]);
+    assert!(matches!(actions[..], [BuildAction::Exec(..)]));
+    assert!(uncached.reasons().is_empty());
+
+    // Commands that can't be cached are counted by reason.
+    let actions = create(&["/c", "a.cpp", "x.lib"]);
+    assert!(matches!(actions[..], [BuildAction::Exec(..)]));
+    create(&["/c", "/Ycstdafx.h", "/Yustdafx.h", "a.cpp"]);
+    create(&["/c", "/Tcb.inl", "/Ycstdafx.h", "/Yustdafx.h"]);
+    assert_eq!(
+        uncached.reasons(),
+        BTreeMap::from([("conflicting options", 2), ("link arguments", 1)])
+    );
+}
+
+#[test]
+fn test_source_permutations() {
+    let fixture = Fixture::new(&[("a.cpp", "int variant = VARIANT;\n")]);
+    let log = fixture.dir.path().join("diagnostics.jsonl");
+    let config = Config {
+        diagnostics_log: Some(log.clone()),
+        ..fixture.config()
+    };
+    // Unity build permutations: one source, different defines and objects.
+    let commands: &[&[&str]] = &[
+        &["/c", "/DVARIANT=1", "/Foa1.obj", "a.cpp"],
+        &["/c", "/DVARIANT=2", "/Foa2.obj", "a.cpp"],
+        &["/c", "/DVARIANT=3", "/Foa3.obj", "a.cpp"],
+    ];
+    let objects = ["a1.obj", "a2.obj", "a3.obj"];
+
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (0, 3));
+    assert_eq!(state.cache.entries().unwrap().len(), 3);
+    let texts: Vec<String> = objects
+        .iter()
+        .map(|name| fixture.object_text(name))
+        .collect();
+    assert_eq!(texts.iter().collect::<HashSet<_>>().len(), 3);
+
+    // Every task is reported with its own object.
+    let outputs: HashSet<PathBuf> = fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["outcome"], "compiled");
+            assert_eq!(
+                PathBuf::from(record["source"].as_str().unwrap()),
+                fixture.src().join("a.cpp")
+            );
+            PathBuf::from(record["output"].as_str().unwrap())
+        })
+        .collect();
+    let expected: HashSet<PathBuf> = objects
+        .iter()
+        .map(|name| fixture.src().join(name))
+        .collect();
+    assert_eq!(outputs, expected);
+
+    // Every permutation is restored from its own cache entry.
+    for name in objects {
+        fs::remove_file(fixture.src().join(name)).unwrap();
+    }
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (3, 0));
+    for (name, text) in objects.iter().zip(&texts) {
+        assert_eq!(&fixture.object_text(name), text);
+    }
+}
+
+#[test]
+fn test_warnings_and_exit_code() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture.control("a.cpp warning C4996: deprecated\nb.cpp exit 3\n");
+    let config = fixture.config();
+
+    let output = fixture.build(
+        &config,
+        &[&["/c", "/Foa.obj", "a.cpp"], &["/c", "/Fob.obj", "b.cpp"]],
+    );
+    match output.result {
+        Err(octobuild::Error::BuildFailed(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].title, "b.cpp");
+            assert_eq!(failures[0].status, Some(3));
+        }
+        other => panic!("unexpected build result: {other:?}"),
+    }
+    assert!(output
+        .stdout
+        .contains("a.cpp(1): warning C4996: deprecated"));
+    assert!(output.stdout.contains("b.cpp(1): error C2999"));
+    // Compiler echoes source file name, octobuild must hide it.
+    assert!(!output.stdout.lines().any(|line| line == "a.cpp"));
+    assert!(fixture.src().join("a.obj").is_file());
+    assert!(!fixture.src().join("b.obj").is_file());
+}
+
+#[test]
+fn test_missing_include() {
+    let fixture = Fixture::new(&[("a.cpp", "#include \"none.h\"\n")]);
+    let config = Config {
+        missing_header_check: true,
+        ..fixture.config()
+    };
+    let state = SharedState::new(&config).unwrap();
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+
+    let output = fixture.build_with(&state, &config, &[command]);
+    assert!(matches!(
+        output.result,
+        Err(octobuild::Error::BuildFailed(_))
+    ));
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+
+    // Failure is replayed until header appears.
+    let output = fixture.build_with(&state, &config, &[command]);
+    assert!(matches!(
+        output.result,
+        Err(octobuild::Error::BuildFailed(_))
+    ));
+    assert!(fixture.take_log().is_empty());
+
+    fixture.write("none.h", "\n");
+    let output = fixture.build_with(&state, &config, &[command]);
+    output.result.unwrap();
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+}
+
+#[test]
+fn test_precompiled_header() {
+    let fixture = Fixture::new(&[
+        ("stdafx.h", "int shared();\n"),
+        ("stdafx.cpp", "#include \"stdafx.h\"\n"),
+        (
+            "a.cpp",
+            "#include \"stdafx.h\"\nint a() { return shared(); }\n",
+        ),
+    ]);
+    let config = Config {
+        run_second_cpp: false,
+        ..fixture.config()
+    };
+    let commands: &[&[&str]] = &[
+        &[
+            "/c",
+            "/Ycstdafx.h",
+            "/Fpstdafx.pch",
+            "/Fostdafx.obj",
+            "stdafx.cpp",
+        ],
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "/Foa.obj", "a.cpp"],
+    ];
+
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert!(fixture.read("stdafx.pch").starts_with("FAKEPCH "));
+    assert!(fixture.object_text("a.obj").starts_with("FAKEOBJ "));
+
+    // Both object and precompiled header are restored from cache.
+    fixture.take_log();
+    for name in ["stdafx.pch", "stdafx.obj", "a.obj"] {
+        fs::remove_file(fixture.src().join(name)).unwrap();
+    }
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!(output.hits, 2);
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess stdafx.cpp", "preprocess a.cpp"]
+    );
+    assert!(fixture.src().join("stdafx.pch").is_file());
+}
+
+#[test]
+fn test_empty_translation_unit() {
+    let fixture = Fixture::new(&[
+        ("stdafx.h", "int shared();\n"),
+        ("stdafx.cpp", "#include \"stdafx.h\"\n"),
+        (
+            "a.cpp",
+            "#if 0\n#include \"stdafx.h\"\nint a() { return shared(); }\n#endif\n",
+        ),
+    ]);
+    let log = fixture.dir.path().join("diagnostics.jsonl");
+    let config = Config {
+        run_second_cpp: false,
+        diagnostics_log: Some(log.clone()),
+        ..fixture.config()
+    };
+    let commands: &[&[&str]] = &[
+        &[
+            "/c",
+            "/Ycstdafx.h",
+            "/Fpstdafx.pch",
+            "/Fostdafx.obj",
+            "stdafx.cpp",
+        ],
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "/Foa.obj", "a.cpp"],
+    ];
+    let take_records = || -> Vec<serde_json::Value> {
+        let content = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    };
+
+    // Missing precompiled header include is fine for source without tokens.
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (0, 2));
+    let object = fixture.object_text("a.obj");
+    let records = take_records();
+    assert_eq!(records[0]["note"], serde_json::Value::Null);
+    assert_eq!(records[1]["outcome"], "compiled");
+    assert_eq!(records[1]["note"], "empty translation unit");
+
+    // Empty translation unit is cached like any other.
+    fs::remove_file(fixture.src().join("a.obj")).unwrap();
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (2, 0));
+    assert_eq!(fixture.object_text("a.obj"), object);
+    let records = take_records();
+    assert_eq!(records[1]["outcome"], "hit");
+    assert_eq!(records[1]["note"], "empty translation unit");
+}
+
+#[test]
+fn test_precompiled_header_reuse() {
+    let fixture = Fixture::new(&[
+        ("stdafx.h", "int shared();\n"),
+        ("stdafx.cpp", "#include \"stdafx.h\"\n"),
+        (
+            "a.cpp",
+            "#include \"stdafx.h\"\n#include \"b.h\"\nint a() { return shared(); }\n",
+        ),
+        ("inc/b.h", "int b;\n"),
+    ]);
+    let config = Config {
+        run_second_cpp: false,
+        pch_reuse: true,
+        pch_reuse_check: 0,
+        ..fixture.config()
+    };
+    let commands: &[&[&str]] = &[
+        &[
+            "/c",
+            "/Ycstdafx.h",
+            "/Fpstdafx.pch",
+            "/Fostdafx.obj",
+            "stdafx.cpp",
+        ],
+        &[
+            "/c",
+            "/Yustdafx.h",
+            "/Fpstdafx.pch",
+            "/Iinc",
+            "/Foa.obj",
+            "a.cpp",
+        ],
+    ];
+
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    let object = fixture.object_text("a.obj");
+
+    // Preprocessor doesn't run for task using precompiled header.
+    fixture.take_log();
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!(output.hits, 2);
+    assert_eq!(fixture.take_log(), ["preprocess stdafx.cpp"]);
+    assert_eq!(fixture.object_text("a.obj"), object);
+
+    // New header shadows included one without changing any tracked file: self-check catches
+    // stale output and the task is built from preprocessor output.
+    fixture.write("b.h", "int b2;\n");
+    let config = Config {
+        pch_reuse_check: 1,
+        ..config
+    };
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, commands);
+    output.result.unwrap();
+    assert_eq!(state.pch_reuse.mismatches(), 1);
+    assert!(!state.pch_reuse.is_enabled());
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess stdafx.cpp", "preprocess a.cpp", "compile a.cpp"]
+    );
+    assert_ne!(fixture.object_text("a.obj"), object);
+}
+
+#[test]
+fn test_rewritten_precompiled_header() {
+    let fixture = Fixture::new(&[
+        ("stdafx.h", "int shared();\n"),
+        ("first.cpp", "#include \"stdafx.h\"\nint first;\n"),
+        ("second.cpp", "#include \"stdafx.h\"\nint second;\n"),
+        (
+            "a.cpp",
+            "#include \"stdafx.h\"\nint a() { return shared(); }\n",
+        ),
+    ]);
+    let config = Config {
+        run_second_cpp: false,
+        ..fixture.config()
+    };
+    // Precompiled header is hashed by first user and rewritten later in the same build.
+    let commands: &[&[&str]] = &[
+        &[
+            "/c",
+            "/Ycstdafx.h",
+            "/Fpstdafx.pch",
+            "/Fofirst.obj",
+            "first.cpp",
+        ],
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "/Foa1.obj", "a.cpp"],
+        &[
+            "/c",
+            "/Ycstdafx.h",
+            "/Fpstdafx.pch",
+            "/Fosecond.obj",
+            "second.cpp",
+        ],
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "/Foa2.obj", "a.cpp"],
+    ];
+
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!(output.hits, 0);
+    assert_eq!(
+        fixture
+            .take_log()
+            .iter()
+            .filter(|line| line.as_str() == "compile a.cpp")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn test_wrapper_mode() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture.control("b.cpp exit 3\n");
+    let octo_cl = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_octo_cl"))
+            .args(["/c", "/nologo"])
+            .args(args)
+            .current_dir(fixture.src())
+            .env("PATH", fixture.bin())
+            .env("OCTOBUILD_CACHE", fixture.dir.path().join("cache"))
+            .output()
+            .unwrap()
+    };
+
+    let output = octo_cl(&["a.cpp"]);
+    assert!(output.status.success());
+    assert!(fixture.object_text("a.obj").starts_with("FAKEOBJ "));
+
+    let output = octo_cl(&["b.cpp"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!fixture.src().join("b.obj").is_file());
+
+    // Command that can't be cached runs as is, with reason in the summary.
+    fs::remove_file(fixture.src().join("a.obj")).unwrap();
+    fixture.take_log();
+    let output = octo_cl(&["/Fox.obj", "/Foa.obj", "a.cpp"]);
+    assert!(output.status.success());
+    assert_eq!(fixture.take_log(), ["compile a.cpp"]);
+    assert!(fixture.object_text("a.obj").starts_with("FAKEOBJ "));
+    assert!(String::from_utf8_lossy(&output.stdout)
+        .contains("Commands executed without cache: 1 (duplicate options: 1)"));
+}
+
+#[test]
+fn test_provenance() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let config = Config {
+        object_sidecar: true,
+        ..fixture.config()
+    };
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let object = fixture.src().join("a.obj");
+
+    fixture.build(&config, &[command]).result.unwrap();
+    let state = SharedState::new(&config).unwrap();
+    let (location, provenance) = state.provenance.find(&object).unwrap().unwrap();
+    assert_eq!(location, ProvenanceLocation::Sidecar(sidecar_path(&object)));
+    assert_eq!(provenance.outcome, TaskOutcome::Compiled);
+    assert_eq!(provenance.source, fixture.src().join("a.cpp"));
+
+    // Sidecar file is not an output of the task, so it is not restored from cache.
+    fs::remove_file(&object).unwrap();
+    fs::remove_file(sidecar_path(&object)).unwrap();
+    fixture
+        .build(
+            &Config {
+                object_sidecar: false,
+                ..fixture.config()
+            },
+            &[command],
+        )
+        .result
+        .unwrap();
+    assert!(!sidecar_path(&object).exists());
+    let (location, provenance) = state.provenance.find(&object).unwrap().unwrap();
+    assert!(matches!(location, ProvenanceLocation::Index(_)));
+    assert_eq!(provenance.outcome, TaskOutcome::Hit);
+}
+
+#[test]
+fn test_object_stamp() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let config = Config {
+        object_stamp: true,
+        ..fixture.config()
+    };
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let object = fixture.src().join("a.obj");
+
+    fixture.build(&config, &[command]).result.unwrap();
+    let data = fs::read(&object).unwrap();
+    let coff = CoffObject::parse(&data).unwrap();
+    assert!(coff
+        .section(STAMP_SECTION)
+        .unwrap()
+        .has_flags(IMAGE_SCN_LNK_REMOVE));
+    let stamp = read_stamp(&object).unwrap().unwrap();
+    assert_eq!(stamp.provenance.outcome, TaskOutcome::Compiled);
+    assert_eq!(stamp.provenance.source, fixture.src().join("a.cpp"));
+    let text = fixture.object_text("a.obj");
+
+    // Cache keeps object without stamp, restored object is stamped by current build.
+    fs::remove_file(&object).unwrap();
+    fixture.build(&config, &[command]).result.unwrap();
+    let restored = read_stamp(&object).unwrap().unwrap();
+    assert_eq!(restored.provenance.outcome, TaskOutcome::Hit);
+    assert_eq!(restored.provenance.key, stamp.provenance.key);
+    assert_eq!(fixture.object_text("a.obj"), text);
+
+    // Object of other tool.
+    fixture.write("b.obj", "not an object\n");
+    let toolchains: HashSet<String> = stamp.provenance.toolchain.iter().cloned().collect();
+    let summary = |toolchains: &HashSet<String>| -> Vec<(String, Option<TaskOutcome>, bool)> {
+        audit_objects(&fixture.src(), toolchains)
+            .unwrap()
+            .into_iter()
+            .map(|audit| {
+                (
+                    audit
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    audit.stamp.unwrap().map(|stamp| stamp.provenance.outcome),
+                    audit.toolchain_mismatch,
+                )
+            })
+            .collect()
+    };
+    assert_eq!(
+        summary(&toolchains),
+        [
+            ("a.obj".to_string(), Some(TaskOutcome::Hit), false),
+            ("b.obj".to_string(), None, false)
+        ]
+    );
+    // Toolchain which produced object is not available anymore.
+    if stamp.provenance.toolchain.is_some() {
+        assert_eq!(
+            summary(&HashSet::new()),
+            [
+                ("a.obj".to_string(), Some(TaskOutcome::Hit), true),
+                ("b.obj".to_string(), None, false)
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_preprocess_transform() {
+    let fixture = Fixture::new(&[("a.cpp", "int bad;\n")]);
+    let config = Config {
+        run_second_cpp: false,
+        ..fixture.config()
+    };
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let object = fixture.src().join("a.obj");
+    let build = |config: &Config| {
+        let state = SharedState::new(config).unwrap();
+        fixture
+            .build_with(&state, config, &[command])
+            .result
+            .unwrap();
+        let (_, provenance) = state.provenance.find(&object).unwrap().unwrap();
+        let mut hits = Vec::new();
+        state.transforms.print_hits(&mut hits).unwrap();
+        (
+            provenance.key,
+            fixture.object_text("a.obj"),
+            String::from_utf8(hits).unwrap(),
+        )
+    };
+
+    let (bad_key, bad_text, _) = build(&config);
+    fixture.write("a.cpp", "int good;\n");
+    let (good_key, good_text, _) = build(&config);
+    assert_ne!(bad_text, good_text);
+
+    // Compiler gets transformed input, cache key is salted by rule set.
+    fixture.write("a.cpp", "int bad;\n");
+    let config = Config {
+        preprocess_transform: vec![PreprocessTransform {
+            pattern: r"\bbad\b".to_string(),
+            replacement: "good".to_string(),
+            source: Some("*.cpp".to_string()),
+        }],
+        ..config
+    };
+    let (key, text, hits) = build(&config);
+    assert_eq!(text, good_text);
+    assert_ne!(key, bad_key);
+    assert_ne!(key, good_key);
+    assert!(hits.contains("\"\\\\bbad\\\\b\": 1 replacement(s)"));
+}
+
+#[test]
+fn test_post_compile_hook() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let object = fixture.src().join("a.obj");
+    let hook = |args: &[&str]| {
+        let mut post_compile_hook = vec![fixture.cl().to_string_lossy().into_owned()];
+        post_compile_hook.extend(args.iter().map(ToString::to_string));
+        Config {
+            post_compile_hook,
+            ..fixture.config()
+        }
+    };
+    let build = |config: &Config| {
+        let state = SharedState::new(config).unwrap();
+        let output = fixture.build_with(&state, config, &[command]);
+        output.result.unwrap();
+        let (_, provenance) = state.provenance.find(&object).unwrap().unwrap();
+        (provenance.key, output.hits, output.stdout)
+    };
+
+    let (plain_key, _, _) = build(&fixture.config());
+    fixture.take_log();
+
+    // Hook runs after compilation, its output is appended to task output.
+    let config = hook(&["--append", "SIGNED", "{object}"]);
+    let (key, hits, stdout) = build(&config);
+    assert_ne!(key, plain_key);
+    assert_eq!(hits, 0);
+    assert!(stdout.contains("SIGNED"));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "compile a.cpp", "hook a.obj"]
+    );
+    assert!(fs::read(&object).unwrap().ends_with(b"SIGNED"));
+
+    // Cached object is already post-processed, hook doesn't run again.
+    fs::remove_file(&object).unwrap();
+    let (cached_key, hits, _) = build(&config);
+    assert_eq!((cached_key, hits), (key.clone(), 1));
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+    let data = fs::read(&object).unwrap();
+    assert!(data.ends_with(b"SIGNED") && !data.ends_with(b"SIGNEDSIGNED"));
+
+    // Changed hook invalidates cache.
+    let (stripped_key, hits, _) = build(&hook(&["--append", "STRIPPED", "{object}"]));
+    assert_ne!(stripped_key, key);
+    assert_eq!(hits, 0);
+
+    // Hook failure fails the task, result is not cached.
+    let config = hook(&["--append", "SIGNED", "{object}.missing/x"]);
+    for _ in 0..2 {
+        fixture.take_log();
+        let state = SharedState::new(&config).unwrap();
+        let output = fixture.build_with(&state, &config, &[command]);
+        assert!(output.result.is_err());
+        assert_eq!(output.hits, 0);
+        assert_eq!(
+            fixture.take_log(),
+            ["preprocess a.cpp", "compile a.cpp", "hook x"]
+        );
+    }
+}
+
+#[test]
+fn test_cmd_hostile_paths() {
+    // Characters expanded or interpreted by cmd.exe must reach compiler and hook unchanged.
+    let dir = "out%PATH%^&!x";
+    let source = format!("{dir}/a.cpp");
+    let header = format!("{dir}/a%1.h");
+    let fixture = Fixture::new(&[
+        (source.as_str(), "#include \"a%1.h\"\nint a;\n"),
+        (header.as_str(), "int h;\n"),
+    ]);
+    let output_arg = format!("/Fo{dir}/a.obj");
+    let command: &[&str] = &["/c", &output_arg, &source];
+    let object = fixture.src().join(dir).join("a.obj");
+    let config = Config {
+        post_compile_hook: vec![
+            fixture.cl().to_string_lossy().into_owned(),
+            "--append".to_string(),
+            "100%!".to_string(),
+            "{object}".to_string(),
+        ],
+        ..fixture.config()
+    };
+
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!(output.misses, 1);
+    assert!(output.stdout.contains("100%!"));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "compile a.cpp", "hook a.obj"]
+    );
+    assert!(fs::read(&object).unwrap().ends_with(b"100%!"));
+
+    // Cache entry is restored and header with percent sign in path is tracked.
+    fs::remove_file(&object).unwrap();
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!(output.hits, 1);
+    fixture.write(&header, "int h2;\n");
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!(output.misses, 1);
+}
+
+#[test]
+fn test_internal_error_retry() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    fixture.control("a.cpp internal-error 2\n");
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+
+    // Error persisting after all retries fails the task.
+    let config = Config {
+        internal_error_retries: 1,
+        ..fixture.config()
+    };
+    let output = fixture.build(&config, &[command]);
+    assert!(output.result.is_err());
+    assert!(output.stdout.contains("fatal error C1001"));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "compile a.cpp", "compile a.cpp"]
+    );
+
+    // Output of the last attempt is kept.
+    let output = fixture.build(&fixture.config(), &[command]);
+    output.result.unwrap();
+    assert!(!output.stdout.contains("C1001"));
+    assert_eq!((output.hits, output.misses), (0, 1));
+    assert_eq!(
+        fixture.take_log(),
+        [
+            "preprocess a.cpp",
+            "compile a.cpp",
+            "compile a.cpp",
+            "compile a.cpp"
+        ]
+    );
+    assert!(fixture.object_text("a.obj").starts_with("FAKEOBJ "));
+}
+
+#[test]
+fn test_mixed_failures_exit_code() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "int a;\n"),
+        ("b.cpp", "int b;\n"),
+        ("c.cpp", "int c;\n"),
+        ("d.cpp", "int d;\n"),
+    ]);
+    // Failures are reported in completion order.
+    fixture.control(
+        "a.cpp error C2065\n\
+         b.cpp crash 10\nb.cpp delay 500\n\
+         c.cpp exit 3\nc.cpp delay 1500\n\
+         d.cpp exit 3\nd.cpp delay 1500\n",
+    );
+    let commands: &[&[&str]] = &[
+        &["/c", "/Foa.obj", "a.cpp"],
+        &["/c", "/Fob.obj", "b.cpp"],
+        &["/c", "/Foc.obj", "c.cpp"],
+        &["/c", "/Fod.obj", "d.cpp"],
+    ];
+    // Exit code like returned by `xgConsole`.
+    let exit_code = |commands: &[&[&str]], mode: ExitCodeMode| {
+        let config = Config {
+            exit_code_mode: mode,
+            process_limit: 4,
+            ..fixture.config()
+        };
+        match fixture.build_parallel(&config, commands).result {
+            Err(octobuild::Error::BuildFailed(failures)) => {
+                failure_exit_code(&failures, config.exit_code_mode)
+            }
+            other => panic!("unexpected build result: {other:?}"),
+        }
+    };
+
+    assert_eq!(exit_code(commands, ExitCodeMode::FirstFailure), 2);
+    assert_eq!(
+        exit_code(commands, ExitCodeMode::Aggregate),
+        AGGREGATE_EXIT_CODE
+    );
+    assert_eq!(
+        exit_code(commands, ExitCodeMode::XgconsoleCompatible),
+        AGGREGATE_EXIT_CODE
+    );
+    // The same failure twice keeps its exit code.
+    assert_eq!(exit_code(&commands[2..], ExitCodeMode::Aggregate), 3);
+    assert_eq!(
+        exit_code(&commands[2..], ExitCodeMode::XgconsoleCompatible),
+        AGGREGATE_EXIT_CODE
+    );
+}
+
+#[test]
+fn test_crash_retry() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+
+    // Crashed compiler gets another chance.
+    fixture.control("a.cpp crash 1\n");
+    let output = fixture.build(&fixture.config(), &[command]);
+    output.result.unwrap();
+    assert_eq!(
+        fixture.take_log(),
+        [
+            "preprocess a.cpp",
+            "compile a.cpp",
+            "preprocess a.cpp",
+            "compile a.cpp"
+        ]
+    );
+
+    // Crash persisting after all retries fails the task and isn't cached.
+    fixture.control("a.cpp crash 10\n");
+    fixture.write("a.cpp", "int b;\n");
+    let output = fixture.build(&fixture.config(), &[command]);
+    match output.result {
+        Err(octobuild::Error::BuildFailed(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].status.is_some(), cfg!(windows));
+        }
+        other => panic!("unexpected result: {other:?}"),
+    }
+    assert_eq!(fixture.take_log().len(), 6);
+
+    fixture.control("");
+    let output = fixture.build(&fixture.config(), &[command]);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (0, 1));
+}
+
+#[test]
+fn test_locked_source_retry() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let config = fixture.config();
+
+    // Source locked by IDE for a moment is compiled on retry.
+    fixture.control("a.cpp locked 1\n");
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[command]);
+    output.result.unwrap();
+    assert!(!output.stdout.contains("C1083"));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "preprocess a.cpp", "compile a.cpp"]
+    );
+    assert_eq!(
+        state.statistic.locked_retry_count.load(Ordering::Relaxed),
+        1
+    );
+
+    // Lock persisting after all retries fails the task.
+    fixture.control("a.cpp locked 10\n");
+    fixture.write("a.cpp", "int b;\n");
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[command]);
+    assert!(output.result.is_err());
+    assert_eq!(fixture.take_log().len(), 4);
+    assert_eq!(
+        state.statistic.locked_retry_count.load(Ordering::Relaxed),
+        3
+    );
+
+    // Other errors are not retried.
+    fixture.control("a.cpp exit 2\n");
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[command]);
+    assert!(output.result.is_err());
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+    assert_eq!(
+        state.statistic.locked_retry_count.load(Ordering::Relaxed),
+        0
+    );
+}
+
+#[test]
+fn test_tool_remap() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    // Build still refers to old toolchain directory.
+    let toolchain = fixture.dir.path().join("vs2022");
+    fs::create_dir_all(&toolchain).unwrap();
+    fs::rename(fixture.cl(), toolchain.join("cl.exe")).unwrap();
+    fs::write(toolchain.join("fake_cl.conf"), "b.cpp exit 3\n").unwrap();
+    let config = Config {
+        tool_remap: vec![ToolRemap {
+            from: format!("{}{}", fixture.bin().display(), std::path::MAIN_SEPARATOR),
+            to: toolchain.display().to_string(),
+        }],
+        ..fixture.config()
+    };
+
+    let output = fixture.build(
+        &config,
+        &[&["/c", "/Foa.obj", "a.cpp"], &["/c", "/Fob.obj", "b.cpp"]],
+    );
+    assert!(output.result.is_err());
+    assert!(fixture.src().join("a.obj").exists());
+    let document = output.report.document(3);
+    let error = &document.errors[&ExitCategory::CompileFailure];
+    assert_eq!(error.path, Some(fixture.src().join("b.cpp")));
+    assert_eq!(error.remapped_from, Some(fixture.cl()));
+
+    // Explain output shows both paths.
+    let state = SharedState::new(&config).unwrap();
+    let command = CommandInfo {
+        program: fixture.cl(),
+        current_dir: Some(fixture.src()),
+        env: Arc::new(env::vars().collect()),
+        remapped_from: None,
+        config: None,
+    }
+    .remap(&config.tool_remap);
+    let actions = BuildAction::create_tasks(
+        &VsCompiler::default(),
+        command,
+        CommandArgs::Regular(vec!["/c".to_string(), "a.cpp".to_string()]),
+        "a.cpp",
+        config.run_second_cpp,
+        config.split_link,
+        &state.uncached,
+    );
+    let mut explain = Vec::new();
+    state.overrides.explain(&mut explain, &actions).unwrap();
+    let explain = String::from_utf8(explain).unwrap();
+    assert!(
+        explain.contains(&format!(
+            "tool {} remapped from {}",
+            toolchain.join("cl.exe").display(),
+            fixture.cl().display()
+        )),
+        "{explain}"
+    );
+}
+
+#[test]
+fn test_exit_report() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let source = fixture.src().join("a.cpp");
+
+    fixture.control("a.cpp exit 3\n");
+    let document = fixture
+        .build(&fixture.config(), &[command])
+        .report
+        .document(3);
+    assert_eq!(document.category, ExitCategory::CompileFailure);
+    let error = &document.errors[&ExitCategory::CompileFailure];
+    assert_eq!(error.task, Some(0));
+    assert_eq!(error.path.as_ref(), Some(&source));
+    assert_eq!(error.variant, "exit code");
+    assert_eq!(error.message, "exit code: 3");
+
+    // Interrupted compiler is not retried.
+    fixture.take_log();
+    fixture.control("a.cpp interrupt\n");
+    let document = fixture
+        .build(&fixture.config(), &[command])
+        .report
+        .document(1);
+    assert_eq!(document.category, ExitCategory::Interrupted);
+    assert_eq!(document.counts[&ExitCategory::Interrupted], 1);
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+
+    fixture.control("a.cpp crash 10\n");
+    let document = fixture
+        .build(&fixture.config(), &[command])
+        .report
+        .document(1);
+    assert_eq!(document.category, ExitCategory::Infrastructure);
+    assert_eq!(
+        document.errors[&ExitCategory::Infrastructure].variant,
+        "compiler crash"
+    );
+
+    // Document is read by other tools as JSON.
+    let path = fixture.dir.path().join("exit.json");
+    let report = fixture.build(&fixture.config(), &[command]).report;
+    report.set_path(Some(&path));
+    report.write(1).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(json["category"], "infrastructure");
+    assert_eq!(json["counts"]["infrastructure"], 1);
+    assert_eq!(json["errors"]["infrastructure"]["task"], 0);
+}
+
+#[test]
+fn test_show_includes() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "#include \"a.h\"\nint a;\n"),
+        ("a.h", "#include \"b.h\"\n"),
+        ("b.h", "int b;\n"),
+    ]);
+    let command: &[&str] = &["/c", "/showIncludes", "/Foa.obj", "a.cpp"];
+    let expected = |prefix: &str| {
+        format!(
+            "{prefix} {}\n{prefix}  {}\n",
+            fixture.src().join("a.h").display(),
+            fixture.src().join("b.h").display()
+        )
+    };
+
+    // Included files are printed on both cache miss and cache hit.
+    let config = fixture.config();
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert!(output.stdout.contains(&expected("Note: including file:")));
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (1, 0));
+    assert!(output.stdout.contains(&expected("Note: including file:")));
+
+    // Localized prefix is detected by probe compile.
+    fixture.control("* include-prefix Hinweis: Einlesen der Datei:\n");
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert!(output
+        .stdout
+        .contains(&expected("Hinweis: Einlesen der Datei:")));
+    assert!(!output.stdout.contains("octobuild_probe"));
+
+    // Prefix from configuration disables the probe.
+    let config = Config {
+        show_includes_prefix: Some("Hinweis: Einlesen der Datei:".to_string()),
+        ..fixture.config()
+    };
+    fixture.take_log();
+    let output = fixture.build(&config, &[command]);
+    output.result.unwrap();
+    assert!(output
+        .stdout
+        .contains(&expected("Hinweis: Einlesen der Datei:")));
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+}
+
+#[test]
+fn test_bigobj_retry() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture.control("a.cpp bigobj\n");
+    let log = fixture.dir.path().join("diagnostics.jsonl");
+    let config = Config {
+        bigobj_retry: true,
+        diagnostics_log: Some(log.clone()),
+        ..fixture.config()
+    };
+    let command: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let object = fixture.src().join("a.obj");
+    let last_record = || -> serde_json::Value {
+        let content = fs::read_to_string(&log).unwrap();
+        serde_json::from_str(content.lines().last().unwrap()).unwrap()
+    };
+
+    // Without the policy C1128 fails the build.
+    let output = fixture.build(&fixture.config(), &[command]);
+    assert!(output.result.is_err());
+    assert!(output.stdout.contains("fatal error C1128"));
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+
+    // Failed compilation is retried with /bigobj, failure output is dropped.
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[command]);
+    output.result.unwrap();
+    assert!(!output.stdout.contains("C1128"));
+    assert_eq!((output.hits, output.misses), (0, 1));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "compile a.cpp", "compile a.cpp"]
+    );
+    let text = fixture.object_text("a.obj");
+    let (_, provenance) = state.provenance.find(&object).unwrap().unwrap();
+    let key = provenance.key;
+    assert_eq!(state.cache.entries().unwrap().len(), 1);
+    assert_eq!(last_record()["retry"], "/bigobj");
+    assert_eq!(last_record()["outcome"], "compiled");
+
+    // Retried source is reported with suggestion to set the flag in the project.
+    assert_eq!(state.bigobj.retried(), [fixture.src().join("a.cpp")]);
+    let mut warnings = Vec::new();
+    state
+        .bigobj
+        .print_warnings(&mut warnings, config.diagnostic_style)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(warnings).unwrap(),
+        format!(
+            "WARNING: Sources were compiled with /bigobj after C1128 failure, add the flag to the project:\n\
+             {}(1,1): warning OB4003: Number of sections exceeded object file format limit, compile with /bigobj\n",
+            fixture.src().join("a.cpp").display()
+        )
+    );
+
+    // Next build takes retried result from cache without failing first.
+    fs::remove_file(&object).unwrap();
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[command]);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (1, 0));
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp"]);
+    assert_eq!(fixture.object_text("a.obj"), text);
+    let (_, provenance) = state.provenance.find(&object).unwrap().unwrap();
+    assert_eq!(provenance.key, key);
+    assert_eq!(last_record()["retry"], "/bigobj");
+    assert_eq!(last_record()["outcome"], "hit");
+    assert_eq!(state.bigobj.retried().len(), 1);
+
+    // Object compiled without the flag is cached under a different key.
+    fixture.control("");
+    let plain = fixture.config();
+    let state = SharedState::new(&plain).unwrap();
+    let output = fixture.build_with(&state, &plain, &[command]);
+    output.result.unwrap();
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+    let (_, provenance) = state.provenance.find(&object).unwrap().unwrap();
+    assert_ne!(provenance.key, key);
+    assert_ne!(fixture.object_text("a.obj"), text);
+
+    // Compilation is retried only once.
+    fixture.control("b.cpp warning C1128: number of sections exceeded\nb.cpp exit 2\n");
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[&["/c", "/Fob.obj", "b.cpp"]]);
+    assert!(output.result.is_err());
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess b.cpp", "compile b.cpp", "compile b.cpp"]
+    );
+    assert!(state.bigobj.retried().is_empty());
+}
+
+#[test]
+fn test_prewarm() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "int a;\n"),
+        ("b.cpp", "int b;\n"),
+        ("stdafx.h", "int pch;\n"),
+        ("stdafx.cpp", "#include \"stdafx.h\"\n"),
+        ("c.cpp", "#include \"stdafx.h\"\nint c;\n"),
+    ]);
+    let config = Config {
+        run_second_cpp: false,
+        ..fixture.config()
+    };
+    let a: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let b: &[&str] = &["/c", "/Fob.obj", "b.cpp"];
+    let pch: &[&str] = &[
+        "/c",
+        "/Ycstdafx.h",
+        "/Fpstdafx.pch",
+        "/Fostdafx.obj",
+        "stdafx.cpp",
+    ];
+    let c: &[&str] = &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "/Foc.obj", "c.cpp"];
+    fixture.build(&config, &[a]).result.unwrap();
+    fixture.take_log();
+    fs::remove_file(fixture.src().join("a.obj")).unwrap();
+
+    let mut state = SharedState::new(&config).unwrap();
+    state.prewarm = Some(PrewarmStatistic::new());
+    let output = fixture.build_with(&state, &config, &[a, b, pch, c]);
+    output.result.unwrap();
+
+    // Only preprocessor runs: nothing is compiled, extracted or stored in cache.
+    let log = fixture.take_log();
+    assert!(log.iter().all(|line| line.starts_with("preprocess ")));
+    assert!(log.contains(&"preprocess a.cpp".to_string()));
+    assert!(log.contains(&"preprocess b.cpp".to_string()));
+    assert_eq!((output.hits, output.misses), (0, 0));
+    assert!(!fixture.src().join("a.obj").exists());
+    assert!(!fixture.src().join("stdafx.pch").exists());
+
+    let prewarm = state.prewarm.as_ref().unwrap();
+    assert_eq!(prewarm.refreshed.load(Ordering::Relaxed), 1);
+    assert_eq!(prewarm.missing.load(Ordering::Relaxed), 2);
+    // Users of precompiled header need it to compute cache key.
+    assert_eq!(prewarm.skipped.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_toolchain_warmup() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    let config = Config {
+        toolchain_warmup: true,
+        ..fixture.config()
+    };
+    let commands: &[&[&str]] = &[&["/c", "a.cpp"], &["/c", "b.cpp"]];
+    let warmups = |log: &[String]| log.iter().filter(|l| *l == "compile warmup.cpp").count();
+
+    // Planning and build don't wait for slow probe, which is cancelled at the end.
+    fixture.control("warmup.cpp delay 60000\n");
+    let state = SharedState::new(&config).unwrap();
+    let start = Instant::now();
+    fixture
+        .build_with(&state, &config, commands)
+        .result
+        .unwrap();
+    let mut summary = Vec::new();
+    state.warmup.print_summary(&mut summary).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(30));
+    let summary = String::from_utf8(summary).unwrap();
+    assert!(summary.starts_with("Toolchain warm-up of "));
+    assert!(summary.contains(" cancelled in "));
+    assert_eq!(warmups(&fixture.take_log()), 0);
+
+    // Probe runs once per toolchain.
+    fixture.control("warmup.cpp delay 500\n");
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, commands);
+    output.result.unwrap();
+    let probes = state.warmup.finish();
+    assert_eq!(probes.len(), 1);
+    assert_eq!(probes[0].program, fixture.cl());
+    assert_eq!(probes[0].outcome, WarmupOutcome::Done);
+    assert!(probes[0].duration >= Duration::from_millis(500));
+    assert_eq!(warmups(&fixture.take_log()), 1);
+}
+
+#[test]
+fn test_dir_overrides() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("gen/b.cpp", "int b;\n")]);
+    let config = Config {
+        overrides_root: Some(fixture.src()),
+        ..fixture.config()
+    };
+    let commands: &[&[&str]] = &[
+        &["/c", "/Foa.obj", "a.cpp"],
+        &["/c", "/Fob.obj", "gen/b.cpp"],
+    ];
+
+    // Generated subtree opts out of caching.
+    fixture.write("gen/octobuild.toml", "no_cache = true\n");
+    fixture.build(&config, commands).result.unwrap();
+    fixture.take_log();
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (1, 0));
+    assert_eq!(
+        fixture.take_log(),
+        ["preprocess a.cpp", "preprocess b.cpp", "compile b.cpp"]
+    );
+
+    // Salt changes cache key of the subtree only.
+    fixture.write("gen/octobuild.toml", "key_salt = \"v2\"\n");
+    let output = fixture.build(&config, commands);
+    output.result.unwrap();
+    assert_eq!((output.hits, output.misses), (1, 1));
+    let output = fixture.build(&config, commands);
+    assert_eq!((output.hits, output.misses), (2, 0));
+
+    // Invalid file fails tasks of the subtree.
+    fixture.write("gen/octobuild.toml", "cache = \"elsewhere\"\n");
+    assert!(fixture.build(&config, commands).result.is_err());
+}
+
+#[test]
+fn test_build_manifest() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "#include \"a.h\"\nint a;\n"),
+        ("b.cpp", "int b;\n"),
+        ("a.h", "int h;\n"),
+    ]);
+    let log = fixture.dir.path().join("diagnostics.jsonl");
+    let config = Config {
+        run_second_cpp: false,
+        diagnostics_log: Some(log.clone()),
+        ..fixture.config()
+    };
+    let a: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let b: &[&str] = &["/c", "/Fob.obj", "b.cpp"];
+    let manifest = fixture.dir.path().join("manifest.json");
+    let build = || {
+        let mut state = SharedState::new(&config).unwrap();
+        state.manifest = Some(BuildManifest::new(manifest.clone(), true).unwrap());
+        fixture.build_with(&state, &config, &[a, b]).result.unwrap();
+        let manifest = state.manifest.as_ref().unwrap();
+        manifest.save().unwrap();
+        manifest.up_to_date()
+    };
+
+    assert_eq!(build(), 0);
+    assert_eq!(
+        fixture.take_log(),
+        [
+            "preprocess a.cpp",
+            "compile a.cpp",
+            "preprocess b.cpp",
+            "compile b.cpp"
+        ]
+    );
+
+    // Up-to-date tasks don't even run preprocessor.
+    fs::remove_file(&log).unwrap();
+    assert_eq!(build(), 2);
+    assert!(fixture.take_log().is_empty());
+    // Report tells skipped tasks apart from compiled ones.
+    let content = fs::read_to_string(&log).unwrap();
+    fs::remove_file(&log).unwrap();
+    for line in content.lines() {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(record["note"], "up-to-date (manifest)");
+    }
+    assert_eq!(content.lines().count(), 2);
+
+    // Header change re-runs only the task including it.
+    fixture.write("a.h", "int g;\n");
+    assert_eq!(build(), 1);
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+
+    // Removed output is produced again, from cache.
+    fs::remove_file(fixture.src().join("b.obj")).unwrap();
+    assert_eq!(build(), 1);
+    assert_eq!(fixture.take_log(), ["preprocess b.cpp"]);
+    assert!(fixture.src().join("b.obj").exists());
+}
+
+#[test]
+fn test_output_check() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture.control("a.cpp extra a.exe\nb.cpp no-output\n");
+    let a: &[&str] = &["/c", "/Foout/a.obj", "a.cpp"];
+    fs::create_dir_all(fixture.src().join("out")).unwrap();
+
+    // Stray file is reported, task succeeds.
+    let config = fixture.config();
+    let state = SharedState::new(&config).unwrap();
+    fixture.build_with(&state, &config, &[a]).result.unwrap();
+    assert_eq!(
+        state.outputs.unexpected(),
+        [(fixture.src().join("a.exe"), fixture.src().join("a.cpp"))]
+    );
+
+    // Missing object fails task with explanation instead of cache store error.
+    let output = fixture.build(&config, &[&["/c", "/Fob.obj", "b.cpp"]]);
+    assert!(output.result.is_err());
+    assert_eq!(output.misses, 0);
+
+    // Strict mode fails task that writes unexpected files.
+    let strict = Config {
+        output_check: OutputCheck::Strict,
+        ..fixture.config()
+    };
+    fixture.write("a.cpp", "int a2;\n");
+    fs::remove_file(fixture.src().join("a.exe")).unwrap();
+    assert!(fixture.build(&strict, &[a]).result.is_err());
+}
+
+#[test]
+fn test_annotations() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture
+        .control("a.cpp warning C4996: 'strcpy': unsafe\nb.cpp warning C4996: 'strcpy': unsafe\n");
+    let a: &[&str] = &["/c", "a.cpp"];
+    let b: &[&str] = &["/c", "b.cpp"];
+    let config = Config {
+        annotations: AnnotationFormat::Github,
+        annotation_root: Some(fixture.src()),
+        ..fixture.config()
+    };
+    let expected = "::warning file=a.cpp,line=1,title=C4996::'strcpy': unsafe\n\
+                    ::warning file=b.cpp,line=1,title=C4996::'strcpy': unsafe\n";
+    let cold = fixture.build(&config, &[a, b]);
+    cold.result.unwrap();
+    assert_eq!(cold.annotations, expected);
+
+    // Warnings of cached compilation are annotated too.
+    let warm = fixture.build(&config, &[a, b]);
+    assert_eq!(warm.hits, 2);
+    assert_eq!(warm.annotations, expected);
+
+    let off = fixture.build(&fixture.config(), &[a, b]);
+    assert_eq!(off.annotations, "");
+}
+
+#[test]
+fn test_raw_output() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n")]);
+    fixture.control("a.cpp warning C4996: 'strcpy': unsafe\n");
+    let a: &[&str] = &["/c", "a.cpp"];
+    let native = Command::new(fixture.cl())
+        .args(a)
+        .current_dir(fixture.src())
+        .output()
+        .unwrap();
+    let native = String::from_utf8(native.stdout).unwrap();
+    assert!(native.starts_with("a.cpp\n"));
+
+    let raw = Config {
+        raw_output: true,
+        ..fixture.config()
+    };
+    let cold = fixture.build(&raw, &[a]);
+    cold.result.unwrap();
+    assert_eq!(cold.stdout, native);
+    let warm = fixture.build(&raw, &[a]);
+    assert_eq!(warm.hits, 1);
+    assert_eq!(warm.stdout, native);
+
+    // Raw cache entry is not replayed into normal build, which strips echoed file name.
+    let normal = fixture.build(&fixture.config(), &[a]);
+    assert_eq!((normal.hits, normal.misses), (0, 1));
+    assert_eq!(normal.stdout, native.strip_prefix("a.cpp\n").unwrap());
+}
+
+#[test]
+fn test_shadow() {
+    let fixture = Fixture::new(&[
+        ("a.cpp", "int a;\n"),
+        ("b.cpp", "int b;\n"),
+        ("c.cpp", "int c;\n"),
+    ]);
+    // Nondeterministic compilation never matches, failed native compilation isn't compared.
+    fixture.control("b.cpp random\nc.cpp exit 3\n");
+    let config = Config {
+        shadow: true,
+        shadow_dir: Some(fixture.dir.path().join("shadow")),
+        ..fixture.config()
+    };
+    let state = SharedState::new(&config).unwrap();
+    let mut graph = BuildGraph::new();
+    for name in ["a", "b", "c"] {
+        // Absolute paths make native and octobuild compiler see the same input.
+        let object = format!("/Fo{}", fixture.src().join(format!("{name}.obj")).display());
+        let source = fixture.src().join(format!("{name}.cpp"));
+        // Absolute unix path looks like cl option, so tasks are created from relative one.
+        let args = vec!["/c".to_string(), object.clone(), format!("{name}.cpp")];
+        let native = vec!["/c".to_string(), object, source.display().to_string()];
+        let command = CommandInfo {
+            program: fixture.cl(),
+            current_dir: Some(fixture.src()),
+            env: Arc::new(env::vars().collect()),
+            remapped_from: None,
+            config: None,
+        };
+        for action in BuildAction::create_shadow_tasks(
+            &VsCompiler::default(),
+            command,
+            CommandArgs::Regular(args),
+            name,
+            config.run_second_cpp,
+            config.split_link,
+            &state.uncached,
+        ) {
+            let BuildAction::Shadow(mut shadow) = action else {
+                panic!("expected shadow action");
+            };
+            shadow.args = CommandArgs::Regular(native.clone());
+            graph.add_node(Arc::new(BuildTask {
+                title: name.to_string(),
+                action: BuildAction::Shadow(shadow),
+            }));
+        }
+    }
+    let result = execute_graph(&state, graph, config.process_limit, |_| Ok(()));
+
+    // Exit code of native compiler is kept.
+    match result {
+        Err(octobuild::Error::BuildFailed(failures)) => {
+            assert_eq!(failures.len(), 1);
+            assert_eq!(failures[0].status, Some(3));
+        }
+        other => panic!("unexpected build result: {other:?}"),
+    }
+    let shadow = state.shadow.as_ref().unwrap();
+    assert_eq!(shadow.matched.load(Ordering::Relaxed), 1);
+    assert_eq!(shadow.mismatched.load(Ordering::Relaxed), 1);
+    assert_eq!(shadow.failed.load(Ordering::Relaxed), 0);
+    assert_eq!(shadow.skipped.load(Ordering::Relaxed), 1);
+
+    // Only native compiler writes to build tree.
+    let mut files: Vec<String> = fs::read_dir(fixture.src())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["a.cpp", "a.obj", "b.cpp", "b.obj", "c.cpp"]);
+
+    let report = fs::read_to_string(shadow.dir().join("report.jsonl")).unwrap();
+    assert_eq!(report.lines().count(), 3);
+    assert!(report.contains("\"outcome\":\"mismatch\""));
+    let dumps: Vec<PathBuf> = fs::read_dir(shadow.dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    assert_eq!(dumps.len(), 1);
+    let dump = &dumps[0];
+    assert!(dump
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("b-"));
+    let repro = fs::read_to_string(dump.join("repro.json")).unwrap();
+    assert!(repro.contains("b.cpp"));
+    assert_ne!(
+        fs::read(dump.join("native.obj")).unwrap(),
+        fs::read(dump.join("shadow.obj")).unwrap()
+    );
+}
+
+#[test]
+fn test_pch_language_mismatch() {
+    let fixture = Fixture::new(&[]);
+    let graph = |commands: &[&[&str]]| {
+        let mut graph = BuildGraph::new();
+        for args in commands {
+            let command = CommandInfo {
+                program: fixture.cl(),
+                current_dir: Some(fixture.src()),
+                env: Arc::new(env::vars().collect()),
+                remapped_from: None,
+                config: None,
+            };
+            for action in BuildAction::create_tasks(
+                &VsCompiler::default(),
+                command,
+                CommandArgs::Regular(args.iter().map(|arg| arg.to_string()).collect()),
+                args.last().unwrap(),
+                false,
+                false,
+                &UncachedTasks::new(),
+            ) {
+                assert!(matches!(action, BuildAction::Compilation(..)));
+                graph.add_node(Arc::new(BuildTask {
+                    title: args.last().unwrap().to_string(),
+                    action,
+                }));
+            }
+        }
+        graph
+    };
+    let producer: &[&str] = &["/c", "/Ycstdafx.h", "/Fpstdafx.pch", "stdafx.cpp"];
+
+    validate_pch_languages(&graph(&[
+        producer,
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "a.cpp"],
+        &["/c", "b.c"],
+    ]))
+    .unwrap();
+    // Precompiled header of C++ source is used by C source.
+    let error = validate_pch_languages(&graph(&[
+        producer,
+        &["/c", "/Yustdafx.h", "/Fpstdafx.pch", "b.c"],
+    ]))
+    .unwrap_err();
+    assert!(
+        matches!(
+            &error,
+            octobuild::Error::Task(TaskError::PchLanguageMismatch {
+                pch,
+                producer,
+                producer_language,
+                path,
+                language,
+            }) if pch.ends_with("stdafx.pch")
+                && producer.ends_with("stdafx.cpp")
+                && producer_language == "P"
+                && path.ends_with("b.c")
+                && language == "C"
+        ),
+        "{error:?}"
+    );
+    // Language forced by option is checked too.
+    assert!(matches!(
+        validate_pch_languages(&graph(&[
+            producer,
+            &["/c", "/TC", "/Yustdafx.h", "/Fpstdafx.pch", "a.cpp"],
+        ])),
+        Err(octobuild::Error::Task(
+            TaskError::PchLanguageMismatch { .. }
+        ))
+    ));
+    // Precompiled header built outside of the graph is not checked.
+    validate_pch_languages(&graph(&[
+        producer,
+        &["/c", "/Yuother.h", "/Fpother.pch", "b.c"],
+    ]))
+    .unwrap();
+}
+
+#[test]
+fn test_verify_preprocess() {
+    let fixture = Fixture::new(&[("a.cpp", "int a;\n"), ("b.cpp", "int b;\n")]);
+    fixture.control("a.cpp counter\n");
+    let config = Config {
+        verify_preprocess: 100,
+        ..fixture.config()
+    };
+    let a: &[&str] = &["/c", "/Foa.obj", "a.cpp"];
+    let b: &[&str] = &["/c", "/Fob.obj", "b.cpp"];
+
+    let state = SharedState::new(&config).unwrap();
+    let output = fixture.build_with(&state, &config, &[a, b]);
+    output.result.unwrap();
+    assert_eq!(
+        fixture.take_log(),
+        [
+            "preprocess a.cpp",
+            "preprocess a.cpp",
+            "compile a.cpp",
+            "preprocess b.cpp",
+            "preprocess b.cpp",
+            "compile b.cpp"
+        ]
+    );
+    // Diverged task is compiled without cache.
+    assert_eq!((output.hits, output.misses), (0, 1));
+    assert_eq!(state.preprocess_check.checked(), 2);
+    let divergent = state.preprocess_check.divergent();
+    let divergence = &divergent[&fixture.src().join("a.cpp")];
+    assert!(divergence.first.ends_with("int counter_1;\n"));
+    assert!(divergence.second.ends_with("int counter_2;\n"));
+    assert_eq!(divergent.len(), 1);
+    let mut warnings = Vec::new();
+    state
+        .preprocess_check
+        .print_warnings(&mut warnings, config.diagnostic_style)
+        .unwrap();
+    assert!(String::from_utf8(warnings).unwrap().contains(&format!(
+        "warning OB4004: Preprocessed output differs at byte {}",
+        divergence.offset
+    )));
+
+    // Deterministic task is still cached.
+    let output = fixture.build(&config, &[b]);
+    assert_eq!((output.hits, output.misses), (1, 0));
+    // Without the check preprocessor runs once.
+    fixture.take_log();
+    fixture.build(&fixture.config(), &[a]).result.unwrap();
+    assert_eq!(fixture.take_log(), ["preprocess a.cpp", "compile a.cpp"]);
+}
@@ -0,0 +1,55 @@
+// Restores cached object through ProjFS placeholder, so it needs "Windows Projected File System"
+// optional feature enabled: `cargo test --features projfs-smoke-test --test projection`.
+#![cfg(all(windows, feature = "projfs-smoke-test"))]
+
+use std::fs;
+
+use octobuild::compiler::OutputInfo;
+use octobuild::config::Config;
+use octobuild::io::filecache::FileCache;
+use octobuild::io::projection::create_projector;
+use octobuild::io::statistic::Statistic;
+
+#[test]
+fn test_projected_object_hydration() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        cache: dir.path().join("cache"),
+        temp_dirs: vec![dir.path().join("temp")],
+        projfs_restore: true,
+        ..Config::default()
+    };
+    let projector = create_projector(&config);
+    assert!(projector.is_some(), "ProjFS is not available");
+    let cache = FileCache::new(&config).with_projector(projector);
+    let object = dir.path().join("a.obj");
+    let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    let statistic = Statistic::new();
+    let run = || {
+        cache
+            .run_cached(
+                &statistic,
+                None,
+                "aa11",
+                b"{}",
+                vec![object.clone()],
+                || {
+                    fs::write(&object, &content)?;
+                    Ok(OutputInfo::empty())
+                },
+            )
+            .unwrap();
+    };
+    run();
+    run();
+
+    // Restored output is link to placeholder, hydrated by plain read.
+    assert!(fs::symlink_metadata(&object).unwrap().is_symlink());
+    assert_eq!(fs::read(&object).unwrap(), content);
+
+    // Cleanup materializes outputs before entries may be evicted.
+    cache.cleanup().unwrap();
+    assert!(!fs::symlink_metadata(&object).unwrap().is_symlink());
+    fs::remove_dir_all(&config.cache).unwrap();
+    assert_eq!(fs::read(&object).unwrap(), content);
+}
@@ -0,0 +1,64 @@
+// Installs builder as Windows service under throwaway name, so it needs administrator rights:
+// `cargo test --features service-smoke-test --test service`.
+#![cfg(all(windows, feature = "service-smoke-test"))]
+
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SERVICE_NAME: &str = "octobuild_smoke_test";
+
+fn builder(command: &str) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_octo_builder"))
+        .args([command, "--name", SERVICE_NAME])
+        .status()
+        .unwrap()
+        .success()
+}
+
+// Text of `sc query`, or None if service doesn't exist.
+fn query() -> Option<String> {
+    let output = Command::new("sc.exe")
+        .args(["query", SERVICE_NAME])
+        .output()
+        .unwrap();
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn wait_state(state: &str) {
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !query().is_some_and(|output| output.contains(state)) {
+        assert!(Instant::now() < deadline, "Service is not {state}");
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+#[test]
+fn test_service_lifecycle() {
+    // Leftover of failed run.
+    builder("uninstall");
+
+    assert!(builder("install"));
+    let config = Command::new("sc.exe")
+        .args(["qfailure", SERVICE_NAME])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&config.stdout).contains("RESTART -- Delay = 60000"));
+
+    assert!(builder("start"));
+    wait_state("RUNNING");
+    assert!(builder("stop"));
+    wait_state("STOPPED");
+    // Stopping stopped service is fine.
+    assert!(builder("stop"));
+
+    assert!(builder("uninstall"));
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while query().is_some() {
+        assert!(Instant::now() < deadline, "Service is not deleted");
+        thread::sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,588 @@
+// Minimal cl.exe replacement for tests on machines without Visual Studio.
+//
+// Supported options:
+//  * /E - write preprocessed source with #line directives to stdout;
+//  * /c - write COFF object file with `.text` section derived from input hash to /Fo path;
+//  * neither /E nor /c - link object files and libraries into executable at /Fe path;
+//  * /Yc, /Yu - write or require precompiled header at /Fp path;
+//  * /I - include directory, `INCLUDE` environment variable lists more of them;
+//  * /D - macro, substituted in source lines as a whole word;
+//  * /bigobj - changes object file content;
+//  * /showIncludes - print included files to stderr;
+//  * `#if 0` ... `#endif` in source - lines in between are left out, other conditions aren't supported;
+//  * @file - response file.
+//
+// Every invocation is appended to `fake_cl.log` next to executable.
+// Per-source behaviour is configured by `fake_cl.conf` next to executable:
+//
+//   <source file name> warning <code> <message>
+//   <source file name> exit <code>
+//   <source file name> error <code>          - fail with compiler error <code>
+//   <source file name> extra <file name>   - also write file to current directory
+//   <source file name> no-output           - don't write object file
+//   <source file name> random              - write different object file on every run
+//   <source file name> bigobj              - fail with C1128 unless /bigobj is set
+//   <source file name> internal-error <n>  - fail with C1001 while compiled less than n times
+//   <source file name> crash <n>           - crash while compiled less than n times
+//   <source file name> interrupt           - terminate like on Ctrl+C
+//   <source file name> counter             - preprocessed output differs on every run
+//   <source file name> delay <ms>          - sleep before processing, like cold start of compiler
+//   <source file name> locked <n>          - fail with C1083 while preprocessed less than n times
+//   * include-prefix <text>                - localized prefix of /showIncludes lines
+//
+// Also serves as post-compile hook: `--append <text> <file>` appends text to file and prints it.
+use std::env;
+use std::fs;
+use std::io::{stderr, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use sha2::{Digest, Sha256};
+
+#[derive(Default)]
+struct Options {
+    preprocess: bool,
+    compile: bool,
+    pch_create: bool,
+    pch_use: bool,
+    bigobj: bool,
+    // Prefix of /showIncludes lines.
+    show_includes: Option<String>,
+    output: Option<PathBuf>,
+    executable: Option<PathBuf>,
+    pch: Option<PathBuf>,
+    includes: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    inputs: Vec<PathBuf>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let [flag, text, path] = &args[..] {
+        if flag == "--append" {
+            process::exit(match append(text, Path::new(path)) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("fake_cl: {e}");
+                    2
+                }
+            });
+        }
+    }
+    let args = expand_args(args.into_iter());
+    let options = parse_options(&args);
+    let code = match run(&options) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("fake_cl: {e}");
+            2
+        }
+    };
+    process::exit(code);
+}
+
+fn expand_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut result = Vec::new();
+    for arg in args {
+        match arg.strip_prefix('@') {
+            Some(path) => result.extend(split_args(&fs::read_to_string(path).unwrap())),
+            None => result.push(arg),
+        }
+    }
+    result
+}
+
+// Split response file content, honouring double quotes.
+fn split_args(content: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut has_arg = false;
+    for c in content.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if has_arg {
+                    result.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        result.push(current);
+    }
+    result
+}
+
+fn parse_options(args: &[String]) -> Options {
+    let mut options = Options::default();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // Absolute unix paths also start with slash.
+        let flag = if Path::new(arg).exists() {
+            None
+        } else {
+            arg.strip_prefix('/').or_else(|| arg.strip_prefix('-'))
+        };
+        let Some(flag) = flag else {
+            options.inputs.push(PathBuf::from(arg.trim_matches('"')));
+            continue;
+        };
+        match flag {
+            "E" => options.preprocess = true,
+            "c" => options.compile = true,
+            "bigobj" => options.bigobj = true,
+            "showIncludes" => {
+                options.show_includes = Some(
+                    control("*")
+                        .include_prefix
+                        .unwrap_or_else(|| "Note: including file:".to_string()),
+                );
+            }
+            "I" => options.includes.extend(iter.next().map(PathBuf::from)),
+            "D" => options
+                .defines
+                .extend(iter.next().map(String::as_str).map(define)),
+            "link" => break,
+            s if s.starts_with("Fo") => options.output = Some(unquote(&s[2..])),
+            s if s.starts_with("Fe") => {
+                options.executable = Some(unquote(s[2..].trim_start_matches(':')));
+            }
+            s if s.starts_with("Fp") => options.pch = Some(unquote(&s[2..])),
+            s if s.starts_with("Yc") => options.pch_create = true,
+            s if s.starts_with("Yu") => options.pch_use = true,
+            s if s.starts_with('I') => options.includes.push(unquote(&s[1..])),
+            s if s.starts_with('D') => options.defines.push(define(&s[1..])),
+            _ => {}
+        }
+    }
+    // Like cl.exe, directories from INCLUDE are searched after /I ones.
+    if let Ok(value) = env::var("INCLUDE") {
+        options.includes.extend(
+            value
+                .split(';')
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        );
+    }
+    options
+}
+
+fn define(value: &str) -> (String, String) {
+    let value = value.trim_matches('"');
+    match value.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (value.to_string(), "1".to_string()),
+    }
+}
+
+// Replace defined identifiers, there are no function-like macros.
+fn substitute(line: &str, defines: &[(String, String)]) -> String {
+    let mut result = String::new();
+    let mut word = String::new();
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        let name = std::mem::take(&mut word);
+        match defines.iter().rev().find(|(define, _)| *define == name) {
+            Some((_, value)) => result.push_str(value),
+            None => result.push_str(&name),
+        }
+        result.push(c);
+    }
+    result.pop();
+    result
+}
+
+fn unquote(value: &str) -> PathBuf {
+    PathBuf::from(value.trim_matches('"'))
+}
+
+fn exe_dir() -> PathBuf {
+    env::current_exe().unwrap().parent().unwrap().to_path_buf()
+}
+
+fn append(text: &str, path: &Path) -> std::io::Result<()> {
+    log("hook", &file_name(path))?;
+    let mut file = fs::OpenOptions::new().append(true).open(path)?;
+    file.write_all(text.as_bytes())?;
+    writeln!(stdout(), "{text}")
+}
+
+fn log(action: &str, source: &str) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(exe_dir().join("fake_cl.log"))?;
+    writeln!(file, "{action} {source}")
+}
+
+// Count of logged runs of action for source file, including current one.
+fn run_count(action: &str, source: &str) -> std::io::Result<usize> {
+    let log = fs::read_to_string(exe_dir().join("fake_cl.log"))?;
+    let line = format!("{action} {source}");
+    Ok(log.lines().filter(|l| *l == line).count())
+}
+
+#[derive(Default)]
+struct Control {
+    warnings: Vec<String>,
+    code: i32,
+    extra: Vec<String>,
+    error: Option<String>,
+    no_output: bool,
+    random: bool,
+    bigobj: bool,
+    internal_errors: usize,
+    crashes: usize,
+    interrupt: bool,
+    counter: bool,
+    include_prefix: Option<String>,
+    delay_ms: u64,
+    locked: usize,
+}
+
+// Get configured behaviour for source file.
+fn control(source: &str) -> Control {
+    let mut control = Control::default();
+    let content = fs::read_to_string(exe_dir().join("fake_cl.conf")).unwrap_or_default();
+    for line in content.lines() {
+        let mut parts = line.splitn(3, ' ');
+        if parts.next() != Some(source) {
+            continue;
+        }
+        match (parts.next(), parts.next()) {
+            (Some("warning"), Some(message)) => control.warnings.push(message.to_string()),
+            (Some("exit"), Some(value)) => control.code = value.trim().parse().unwrap(),
+            (Some("error"), Some(value)) => control.error = Some(value.trim().to_string()),
+            (Some("extra"), Some(name)) => control.extra.push(name.trim().to_string()),
+            (Some("no-output"), None) => control.no_output = true,
+            (Some("random"), None) => control.random = true,
+            (Some("bigobj"), None) => control.bigobj = true,
+            (Some("internal-error"), Some(value)) => {
+                control.internal_errors = value.trim().parse().unwrap();
+            }
+            (Some("crash"), Some(value)) => control.crashes = value.trim().parse().unwrap(),
+            (Some("interrupt"), _) => control.interrupt = true,
+            (Some("counter"), None) => control.counter = true,
+            (Some("delay"), Some(value)) => control.delay_ms = value.trim().parse().unwrap(),
+            (Some("locked"), Some(value)) => control.locked = value.trim().parse().unwrap(),
+            (Some("include-prefix"), Some(value)) => {
+                control.include_prefix = Some(value.trim().to_string());
+            }
+            _ => {}
+        }
+    }
+    control
+}
+
+// Terminate like compiler with access violation.
+#[cfg(unix)]
+fn crash() -> ! {
+    process::abort()
+}
+
+#[cfg(windows)]
+fn crash() -> ! {
+    process::exit(0xC000_0005_u32 as i32)
+}
+
+#[cfg(unix)]
+fn interrupt() -> ! {
+    unsafe { libc::raise(libc::SIGINT) };
+    unreachable!()
+}
+
+#[cfg(windows)]
+fn interrupt() -> ! {
+    process::exit(0xC000_013A_u32 as i32)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+fn run(options: &Options) -> std::io::Result<i32> {
+    if !options.preprocess && !options.compile {
+        return link(options);
+    }
+    let input = match options.inputs.as_slice() {
+        [input] => input,
+        inputs => {
+            writeln!(stderr(), "fake_cl: expected single input, got {inputs:?}")?;
+            return Ok(2);
+        }
+    };
+    let delay_ms = control(&file_name(input)).delay_ms;
+    if delay_ms > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(delay_ms));
+    }
+    if options.preprocess {
+        return preprocess(options, input);
+    }
+    compile(options, input)
+}
+
+fn link(options: &Options) -> std::io::Result<i32> {
+    let Some(first) = options.inputs.first() else {
+        writeln!(stderr(), "fake_cl: no input files")?;
+        return Ok(2);
+    };
+    let names: Vec<String> = options.inputs.iter().map(|path| file_name(path)).collect();
+    log("link", &names.join(" "))?;
+    let mut content = String::from("FAKEEXE\n");
+    for input in &options.inputs {
+        if input
+            .extension()
+            .map_or(true, |ext| ext != "obj" && ext != "lib")
+        {
+            writeln!(stderr(), "fake_cl: can't link {}", input.display())?;
+            return Ok(2);
+        }
+        let Ok(data) = fs::read(input) else {
+            writeln!(
+                stdout(),
+                "LINK : fatal error LNK1181: cannot open input file '{}'",
+                input.display()
+            )?;
+            return Ok(2);
+        };
+        content.push_str(&format!(
+            "{} {}\n",
+            file_name(input),
+            hex::encode(Sha256::digest(data))
+        ));
+    }
+    // Executable is named after the first input by default.
+    let executable = options
+        .executable
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(first.file_name().unwrap()).with_extension("exe"));
+    fs::write(executable, content)?;
+    Ok(0)
+}
+
+fn preprocess(options: &Options, input: &Path) -> std::io::Result<i32> {
+    log("preprocess", &file_name(input))?;
+    // cl.exe prints source file name to stderr in preprocessor mode.
+    writeln!(stderr(), "{}", file_name(input))?;
+    // Like source held open by IDE saving it.
+    if run_count("preprocess", &file_name(input))? <= control(&file_name(input)).locked {
+        writeln!(
+            stderr(),
+            "c1xx: fatal error C1083: Cannot open source file: '{}': Permission denied",
+            input.display()
+        )?;
+        return Ok(2);
+    }
+    let mut output = String::new();
+    if let Err(message) = expand(options, input, 0, &mut output) {
+        writeln!(stderr(), "{message}")?;
+        return Ok(2);
+    }
+    let source = file_name(input);
+    if control(&source).counter {
+        // Like __COUNTER__ shifted by headers that differ between runs.
+        output.push_str(&format!(
+            "int counter_{};\n",
+            run_count("preprocess", &source)?
+        ));
+    }
+    stdout().write_all(output.as_bytes())?;
+    Ok(0)
+}
+
+fn line_directive(line: usize, path: &Path) -> String {
+    format!(
+        "#line {line} \"{}\"\n",
+        path.to_string_lossy().replace('\\', "/")
+    )
+}
+
+fn expand(options: &Options, path: &Path, depth: usize, output: &mut String) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    output.push_str(&line_directive(1, path));
+    let mut disabled = false;
+    for (index, line) in content.lines().enumerate() {
+        // Like cl.exe, keep blank lines in place of disabled ones.
+        if disabled || line.trim() == "#if 0" {
+            disabled = line.trim() != "#endif";
+            output.push('\n');
+            continue;
+        }
+        let Some(name) = line
+            .trim()
+            .strip_prefix("#include")
+            .map(|rest| rest.trim().trim_matches(['"', '<', '>']))
+        else {
+            output.push_str(&substitute(line, &options.defines));
+            output.push('\n');
+            continue;
+        };
+        let dirs = path
+            .parent()
+            .into_iter()
+            .chain(options.includes.iter().map(PathBuf::as_path));
+        let Some(header) = dirs.map(|dir| dir.join(name)).find(|p| p.is_file()) else {
+            return Err(format!(
+                "{}({}): fatal error C1083: Cannot open include file: '{name}': No such file or directory",
+                path.display(),
+                index + 1
+            ));
+        };
+        if depth > 16 {
+            return Err(format!("{}: include nesting too deep", header.display()));
+        }
+        if let Some(prefix) = &options.show_includes {
+            // Nested headers are indented by one more space.
+            eprintln!(
+                "{prefix}{:width$}{}",
+                "",
+                header.display(),
+                width = depth + 1
+            );
+        }
+        expand(options, &header, depth + 1, output)?;
+        output.push_str(&line_directive(index + 2, path));
+    }
+    Ok(())
+}
+
+fn compile(options: &Options, input: &Path) -> std::io::Result<i32> {
+    let mut content = fs::read_to_string(input)?;
+    if !content.contains("#line ") {
+        // Compilation from source, expand includes to make object depend on them.
+        content.clear();
+        if let Err(message) = expand(options, input, 0, &mut content) {
+            writeln!(stdout(), "{message}")?;
+            return Ok(2);
+        }
+    }
+    // Preprocessed input remembers original source in the first #line directive.
+    let source = content
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("#line "))
+        .and_then(|rest| rest.split('"').nth(1))
+        .map_or_else(|| file_name(input), |path| file_name(Path::new(path)));
+    log("compile", &source)?;
+    // cl.exe prints source file name to stdout in compile mode.
+    writeln!(stdout(), "{}", file_name(input))?;
+
+    if options.pch_use {
+        let pch = pch_path(options, input);
+        if !pch.is_file() {
+            writeln!(
+                stdout(),
+                "{source}: fatal error C1083: Cannot open precompiled header file: '{}': No such file or directory",
+                pch.display()
+            )?;
+            return Ok(2);
+        }
+    }
+
+    let control = control(&source);
+    for warning in &control.warnings {
+        writeln!(stdout(), "{source}(1): warning {warning}")?;
+    }
+    if control.bigobj && !options.bigobj {
+        writeln!(
+            stdout(),
+            "{source}(1): fatal error C1128: number of sections exceeded object file format limit: compile with /bigobj"
+        )?;
+        return Ok(2);
+    }
+    if run_count("compile", &source)? <= control.crashes {
+        crash();
+    }
+    if control.interrupt {
+        interrupt();
+    }
+    if run_count("compile", &source)? <= control.internal_errors {
+        writeln!(
+            stdout(),
+            "{source}(1): fatal error C1001: Internal compiler error."
+        )?;
+        return Ok(2);
+    }
+    if let Some(code) = &control.error {
+        writeln!(stdout(), "{source}(1): error {code}: fake error")?;
+        return Ok(2);
+    }
+    if control.code != 0 {
+        writeln!(stdout(), "{source}(1): error C2999: fake error")?;
+        return Ok(control.code);
+    }
+    for name in &control.extra {
+        fs::write(name, "FAKEEXTRA\n")?;
+    }
+
+    if options.bigobj {
+        content.push_str("bigobj\n");
+    }
+    if control.random {
+        // Like __TIME__ or unstable code generation of a real compiler.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        content.push_str(&format!("{nanos} {}\n", process::id()));
+    }
+    let hash = hex::encode(Sha256::digest(content.as_bytes()));
+    let output = options
+        .output
+        .clone()
+        .unwrap_or_else(|| input.with_extension("obj"));
+    if !control.no_output {
+        fs::write(output, coff_object(format!("FAKEOBJ {hash}\n").as_bytes()))?;
+    }
+    if options.pch_create {
+        fs::write(pch_path(options, input), format!("FAKEPCH {hash}\n"))?;
+    }
+    Ok(0)
+}
+
+// Build AMD64 COFF object with single `.text` section and empty symbol table.
+fn coff_object(text: &[u8]) -> Vec<u8> {
+    let text_offset: u32 = 20 + 40;
+    let symbols = text_offset + text.len() as u32;
+    let mut data = Vec::new();
+    data.extend_from_slice(&0x8664u16.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&symbols.to_le_bytes());
+    data.extend_from_slice(&0u32.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+    data.extend_from_slice(&0u16.to_le_bytes());
+
+    data.extend_from_slice(b".text\0\0\0");
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    data.extend_from_slice(&text_offset.to_le_bytes());
+    data.extend_from_slice(&[0; 12]);
+    // IMAGE_SCN_CNT_CODE | IMAGE_SCN_ALIGN_16BYTES | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ
+    data.extend_from_slice(&0x6050_0020u32.to_le_bytes());
+
+    data.extend_from_slice(text);
+    // String table size.
+    data.extend_from_slice(&4u32.to_le_bytes());
+    data
+}
+
+fn pch_path(options: &Options, input: &Path) -> PathBuf {
+    options
+        .pch
+        .clone()
+        .unwrap_or_else(|| input.with_extension("pch"))
+}